@@ -0,0 +1,163 @@
+//! Reading a response from the tunnel and splitting it into body chunks for the
+//! visitor, as the server's proxy does, against the copying approach it replaced and
+//! a fixed read buffer the chunks are split from.
+//!
+//! Run with `cargo bench --bench proxy`. The crate is a binary, so the modules the hot
+//! path is built from are compiled in here directly.
+
+use bytes::{Buf, Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use futures::io::AsyncReadExt;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/http_head.rs"]
+mod http_head;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/server/buffers.rs"]
+mod buffers;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/chunked.rs"]
+mod chunked;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/server/framing.rs"]
+mod framing;
+
+use buffers::{read_into, ProxyBuffers};
+use framing::BodyFraming;
+use http_head::{parse_response, HeadScanner};
+
+fn response(body_len: usize) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nCache-Control: no-store\r\n\
+         Date: Sat, 17 Oct 2026 12:00:00 GMT\r\nContent-Length: {}\r\n\r\n",
+        body_len
+    )
+    .into_bytes();
+    response.resize(response.len() + body_len, b'x');
+    response
+}
+
+/// The current path: the head scanned from where the last read left off, and body
+/// chunks split off the read buffer, each read going into the room the last left
+fn split(buffers: &ProxyBuffers, mut tunnel: &[u8]) -> usize {
+    block_on(async {
+        let mut received = buffers.read_buffer();
+        let mut scanner = HeadScanner::default();
+        let head_len = loop {
+            read_into(&mut tunnel, &mut received).await.unwrap();
+            if let Some(len) = scanner.scan(&received).unwrap() {
+                break len;
+            }
+        };
+        let head = parse_response(&received[..head_len]).unwrap();
+        received.advance(head_len);
+
+        let mut framing = BodyFraming::for_response(head.status, false, head.headers.content_length().unwrap(), false);
+        let mut sent = 0;
+        loop {
+            while let Some(chunk) = framing.next_chunk(&mut received).unwrap() {
+                sent += black_box(chunk).len();
+            }
+            if framing.is_complete() || read_into(&mut tunnel, &mut received).await.unwrap() == 0 {
+                break;
+            }
+        }
+        sent
+    })
+}
+
+/// Splitting chunks off a buffer that's given a fresh 8KB, zeroed, for every read
+fn fixed(mut tunnel: &[u8]) -> usize {
+    let read = |received: &mut BytesMut, tunnel: &mut &[u8]| {
+        let len = received.len();
+        received.resize(len + 8192, 0);
+        let n = std::io::Read::read(tunnel, &mut received[len..]).unwrap();
+        received.truncate(len + n);
+        n
+    };
+    let mut received = BytesMut::new();
+    let mut scanner = HeadScanner::default();
+    let head_len = loop {
+        read(&mut received, &mut tunnel);
+        if let Some(len) = scanner.scan(&received).unwrap() {
+            break len;
+        }
+    };
+    let head = parse_response(&received[..head_len]).unwrap();
+    received.advance(head_len);
+
+    let mut framing = BodyFraming::for_response(head.status, false, head.headers.content_length().unwrap(), false);
+    let mut sent = 0;
+    loop {
+        while let Some(chunk) = framing.next_chunk(&mut received).unwrap() {
+            sent += black_box(chunk).len();
+        }
+        if framing.is_complete() || read(&mut received, &mut tunnel) == 0 {
+            break;
+        }
+    }
+    sent
+}
+
+/// The path before buffers were reused: a fresh head buffer rescanned on every read,
+/// and every body chunk copied out of a stack buffer
+fn copying(mut tunnel: &[u8]) -> usize {
+    block_on(async {
+        let mut header_buf = Vec::new();
+        let mut buf = [0u8; 4096];
+        let head_end = loop {
+            let n = tunnel.read(&mut buf).await.unwrap();
+            header_buf.extend_from_slice(&buf[..n]);
+            if let Some(pos) = header_buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let head = parse_response(&header_buf[..head_end]).unwrap();
+        let mut remaining = head.headers.content_length().unwrap().unwrap() as usize;
+        let mut received = header_buf[head_end..].to_vec();
+
+        let mut buf = [0u8; 8192];
+        let mut sent = 0;
+        loop {
+            let n = remaining.min(received.len());
+            remaining -= n;
+            sent += black_box(Bytes::copy_from_slice(&received[..n])).len();
+            if remaining == 0 {
+                break;
+            }
+            let n = tunnel.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.clear();
+            received.extend_from_slice(&buf[..n]);
+        }
+        sent
+    })
+}
+
+fn bench_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response");
+    let buffers = ProxyBuffers::default();
+    for (name, body_len) in [("1KB", 1024), ("1MB", 1024 * 1024)] {
+        let response = response(body_len);
+        group.throughput(Throughput::Bytes(response.len() as u64));
+        group.bench_with_input(BenchmarkId::new("copying", name), &response, |b, response| {
+            b.iter(|| assert_eq!(copying(response), body_len))
+        });
+        group.bench_with_input(BenchmarkId::new("fixed", name), &response, |b, response| {
+            b.iter(|| assert_eq!(fixed(response), body_len))
+        });
+        group.bench_with_input(BenchmarkId::new("split", name), &response, |b, response| {
+            b.iter(|| assert_eq!(split(&buffers, response), body_len))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_response);
+criterion_main!(benches);
@@ -0,0 +1,537 @@
+use anyhow::Context;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error as _;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::client_config::{ClientConfig, TlsOptions};
+use crate::server::Config;
+
+/// Retries after the first attempt for connect errors and 5xx responses
+const MAX_RETRIES: u32 = 2;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve the server and admin token from flags, the server config or the client config
+pub fn resolve_credentials(
+    server: Option<String>,
+    token: Option<String>,
+    config_path: &str,
+) -> anyhow::Result<(String, String)> {
+    // Try to load from server config first, then fall back to client config
+    let (server, token) = match (server, token) {
+        (Some(s), Some(t)) => (s, t),
+        (server_opt, token_opt) => {
+            // Try server config first
+            if let Ok(config) = Config::load(config_path, false) {
+                let server = server_opt.unwrap_or_else(|| format!("https://{}", config.server.domain));
+
+                let token = match token_opt {
+                    Some(t) => t,
+                    None => {
+                        // Find an admin token from config
+                        config
+                            .tokens
+                            .iter()
+                            .find(|(_, t)| t.admin)
+                            .map(|(token, _)| token.clone())
+                            .context("No --token provided and no admin token found in server config")?
+                    }
+                };
+
+                (server, token)
+            } else {
+                // Fall back to client config
+                let client_config = ClientConfig::load()?
+                    .context("No --server/--token provided and no config found")?;
+
+                let server = server_opt.unwrap_or(client_config.server);
+                let token = token_opt.unwrap_or(client_config.token);
+
+                (server, token)
+            }
+        }
+    };
+
+    Ok((server, token))
+}
+
+/// TLS options for `server`: those saved with its profile in the client config, if
+/// it has one, overridden by the ones given as flags
+pub fn resolve_tls(server: &str, flags: TlsOptions) -> anyhow::Result<TlsOptions> {
+    let saved = ClientConfig::load()?
+        .and_then(|config| {
+            config
+                .all_profiles()
+                .into_iter()
+                .find(|(_, profile)| base_url(&profile.server) == base_url(server))
+        })
+        .map(|(_, profile)| profile.tls)
+        .unwrap_or_default();
+    Ok(saved.merged(flags))
+}
+
+/// `server` as `scheme://host[:port]`, for the stored URLs that have no scheme too
+fn base_url(server: &str) -> String {
+    if server.starts_with("https://") || server.starts_with("http://") {
+        server.trim_end_matches('/').to_string()
+    } else {
+        // Legacy: no scheme, default to https
+        format!("https://{}", server.trim_end_matches('/'))
+    }
+}
+
+/// Apply `tls` to `builder`. Proxies are left to reqwest, which takes them from
+/// `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and `NO_PROXY`.
+fn configure_tls(mut builder: reqwest::ClientBuilder, tls: &TlsOptions) -> Result<reqwest::ClientBuilder, AdminError> {
+    let read = |what: &str, path: &Path| {
+        std::fs::read(path).map_err(|e| AdminError::Other(format!("Failed to read {} {}: {}", what, path.display(), e)))
+    };
+
+    if let Some(ref path) = tls.ca_cert {
+        let certs = reqwest::Certificate::from_pem_bundle(&read("CA certificate", path)?)
+            .map_err(|e| AdminError::Other(format!("Invalid CA certificate {}: {}", path.display(), e)))?;
+        if certs.is_empty() {
+            return Err(AdminError::Other(format!("No PEM certificates in {}", path.display())));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let pem = [read("client certificate", cert)?, b"\n".to_vec(), read("client key", key)?].concat();
+            let identity = reqwest::Identity::from_pem(&pem).map_err(|e| {
+                AdminError::Other(format!(
+                    "Invalid client certificate {} or key {} (both must be PEM): {}",
+                    cert.display(),
+                    key.display(),
+                    e
+                ))
+            })?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err(AdminError::Other("A client certificate needs both client_cert and client_key".to_string())),
+    }
+
+    if tls.skips_verify() {
+        eprintln!("Warning: not verifying the server's certificate (insecure_skip_verify)");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder)
+}
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("DNS lookup failed for {host}: {message}")]
+    Dns { host: String, message: String },
+    #[error("Failed to connect to {host}: {message}")]
+    Connect { host: String, message: String },
+    #[error("TLS error talking to {host}: {message}")]
+    Tls { host: String, message: String },
+    #[error("Request to {host} timed out")]
+    Timeout { host: String },
+    #[error("Invalid or non-admin token")]
+    Unauthorized,
+    #[error("Admin API not enabled on server")]
+    NotFound,
+    /// The server refused the request, saying why
+    #[error("{0}")]
+    Rejected(String),
+    #[error("Server returned error: {0}")]
+    Status(StatusCode),
+    #[error("Failed to parse server response: {0}")]
+    InvalidResponse(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl AdminError {
+    /// Whether retrying the request might succeed
+    fn is_transient(&self) -> bool {
+        match self {
+            AdminError::Connect { .. } => true,
+            AdminError::Status(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+/// HTTP client shared by the admin-facing CLI commands (status, disconnect, ...),
+/// so they all apply the same timeout, retry policy and error reporting
+pub struct AdminClient {
+    http: reqwest::Client,
+    base_url: String,
+    host: String,
+    token: String,
+    /// For each request, except streams
+    timeout: Duration,
+    retry_base_delay: Duration,
+}
+
+impl AdminClient {
+    /// A client for `server`'s admin API, trusting a private CA, presenting a client
+    /// certificate or skipping certificate checks as `tls` says
+    pub fn new(server: &str, token: &str, timeout: Duration, tls: &TlsOptions) -> Result<Self, AdminError> {
+        // Use the scheme from the stored server URL
+        let base_url = base_url(server);
+        let host = url::Url::parse(&base_url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()))
+            .unwrap_or_else(|| server.to_string());
+
+        let http = configure_tls(reqwest::Client::builder().connect_timeout(timeout), tls)?
+            .build()
+            .map_err(|e| AdminError::Other(format!("Failed to create HTTP client: {}", e)))?;
+
+        Ok(Self {
+            http,
+            base_url,
+            host,
+            token: token.to_string(),
+            timeout,
+            retry_base_delay: RETRY_BASE_DELAY,
+        })
+    }
+
+    /// GET an admin endpoint and parse the JSON body
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, AdminError> {
+        parse(self.send(Method::GET, path, None).await?).await
+    }
+
+    /// POST a JSON body to an admin endpoint and parse the JSON reply
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, AdminError> {
+        let body = serde_json::to_vec(body).map_err(|e| AdminError::Other(e.to_string()))?;
+        parse(self.send(Method::POST, path, Some(body)).await?).await
+    }
+
+    /// PUT a JSON body to an admin endpoint and parse the JSON reply
+    pub async fn put_json<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, AdminError> {
+        let body = serde_json::to_vec(body).map_err(|e| AdminError::Other(e.to_string()))?;
+        parse(self.send(Method::PUT, path, Some(body)).await?).await
+    }
+
+    /// DELETE an admin resource
+    pub async fn delete(&self, path: &str) -> Result<(), AdminError> {
+        self.send(Method::DELETE, path, None).await.map(|_| ())
+    }
+
+    /// DELETE an admin resource and parse the JSON reply
+    pub async fn delete_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, AdminError> {
+        parse(self.send(Method::DELETE, path, None).await?).await
+    }
+
+    /// GET an admin endpoint, leaving the body (read within the timeout) to the caller
+    pub async fn get(&self, path: &str) -> Result<reqwest::Response, AdminError> {
+        self.send(Method::GET, path, None).await
+    }
+
+    /// GET an admin endpoint whose body may stay open, such as followed logs. Only
+    /// connecting is bounded by the timeout.
+    pub async fn get_stream(&self, path: &str) -> Result<reqwest::Response, AdminError> {
+        self.send_with(Method::GET, path, None, None).await
+    }
+
+    /// Send a request, retrying connect errors and 5xx responses with backoff. A POST
+    /// is only retried if it never reached the server, so it can't take effect twice.
+    async fn send(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> Result<reqwest::Response, AdminError> {
+        self.send_with(method, path, body, Some(self.timeout)).await
+    }
+
+    async fn send_with(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Response, AdminError> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut attempt = 0;
+        loop {
+            let result = self.send_once(method.clone(), &url, body.clone(), timeout).await;
+            match result {
+                Err(e)
+                    if e.is_transient()
+                        && (method.is_idempotent() || matches!(e, AdminError::Connect { .. }))
+                        && attempt < MAX_RETRIES =>
+                {
+                    let delay = self.retry_base_delay * 2u32.pow(attempt);
+                    attempt += 1;
+                    debug!("{} {} failed ({}), retrying in {:?}", method, url, e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Response, AdminError> {
+        let mut request = self
+            .http
+            .request(method, url)
+            .header("Authorization", format!("Bearer {}", self.token));
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body);
+        }
+        let response = request.send().await.map_err(|e| self.classify(e))?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED => Err(AdminError::Unauthorized),
+            StatusCode::NOT_FOUND => Err(AdminError::NotFound),
+            status @ (StatusCode::BAD_REQUEST | StatusCode::CONFLICT) => {
+                // The admin API says what was wrong in {"error": "..."}
+                let message = response
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|body| body["error"].as_str().map(str::to_string))
+                    .unwrap_or_else(|| status.to_string());
+                Err(AdminError::Rejected(message))
+            }
+            status if !status.is_success() => Err(AdminError::Status(status)),
+            _ => Ok(response),
+        }
+    }
+
+    fn classify(&self, err: reqwest::Error) -> AdminError {
+        let host = self.host.clone();
+        if err.is_timeout() {
+            return AdminError::Timeout { host };
+        }
+
+        // reqwest doesn't expose DNS/TLS failures as distinct kinds, so look at the cause chain
+        let mut chain = Vec::new();
+        let mut source = err.source();
+        while let Some(cause) = source {
+            chain.push(cause.to_string());
+            source = cause.source();
+        }
+        let message = chain.last().cloned().unwrap_or_else(|| err.to_string());
+        let details = chain.join(": ").to_lowercase();
+
+        if details.contains("dns error") || details.contains("failed to lookup address") {
+            AdminError::Dns { host, message }
+        } else if details.contains("certificate") || details.contains("tls") || details.contains("handshake") {
+            AdminError::Tls { host, message }
+        } else if err.is_connect() {
+            AdminError::Connect { host, message }
+        } else {
+            AdminError::Other(err.to_string())
+        }
+    }
+}
+
+async fn parse<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, AdminError> {
+    response
+        .json()
+        .await
+        .map_err(|e| AdminError::InvalidResponse(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::StatusCode as AxumStatus,
+        routing::{any, get},
+        Json, Router,
+    };
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn client(server: &str) -> AdminClient {
+        let mut client = AdminClient::new(server, "tk_admin", Duration::from_secs(5), &TlsOptions::default()).unwrap();
+        client.retry_base_delay = Duration::from_millis(10);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let server = mock_server(Router::new().route(
+            "/_admin/version",
+            get(move || {
+                let counter = counter.clone();
+                async move {
+                    if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                        Err(AxumStatus::BAD_GATEWAY)
+                    } else {
+                        Ok(Json(serde_json::json!({ "version": "0.1.0" })))
+                    }
+                }
+            }),
+        ))
+        .await;
+
+        let body: serde_json::Value = client(&server).get_json("/_admin/version").await.unwrap();
+        assert_eq!(body["version"], "0.1.0");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_and_classifies_errors() {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let server = mock_server(
+            Router::new()
+                .route(
+                    "/unavailable",
+                    any(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async { AxumStatus::SERVICE_UNAVAILABLE }
+                    }),
+                )
+                .route("/unauthorized", any(|| async { AxumStatus::UNAUTHORIZED })),
+        )
+        .await;
+        let client = client(&server);
+
+        let err = client.delete("/unavailable").await.unwrap_err();
+        assert!(matches!(err, AdminError::Status(s) if s == StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(hits.load(Ordering::SeqCst), MAX_RETRIES + 1);
+
+        // Client errors aren't retried
+        let err = client.delete("/unauthorized").await.unwrap_err();
+        assert!(matches!(err, AdminError::Unauthorized));
+
+        // Nothing listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let err = self::client(&closed).delete("/").await.unwrap_err();
+        assert!(matches!(err, AdminError::Connect { .. }), "{:?}", err);
+        assert!(err.to_string().starts_with("Failed to connect to 127.0.0.1"));
+    }
+
+    /// A private CA's PEM files in a temporary directory: `ca.pem`, and a certificate
+    /// and key for the server (`server.pem`, `server.key`) and an admin (`client.pem`,
+    /// `client.key`)
+    fn private_ca() -> std::path::PathBuf {
+        use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+
+        let dir = std::env::temp_dir().join(format!("loophole-admin-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        // Each certificate needs a subject of its own, or OpenSSL takes them all to be
+        // self-signed
+        params.distinguished_name.push(DnType::CommonName, "loophole test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (name, san, usage) in [
+            ("server", "127.0.0.1", ExtendedKeyUsagePurpose::ServerAuth),
+            ("client", "admin", ExtendedKeyUsagePurpose::ClientAuth),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        dir
+    }
+
+    /// An admin API over HTTPS with the CA's server certificate, refusing clients
+    /// without a certificate from the CA
+    async fn mtls_server(dir: &Path) -> String {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(dir.join("ca.pem")).unwrap()).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![CertificateDer::from_pem_file(dir.join("server.pem")).unwrap()],
+                PrivateKeyDer::from_pem_file(dir.join("server.key")).unwrap(),
+            )
+            .unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/_admin/version", get(|| async { Json(serde_json::json!({ "version": "0.1.0" })) }));
+        let server = axum_server::from_tcp_rustls(listener, axum_server::tls_rustls::RustlsConfig::from_config(Arc::new(config)));
+        tokio::spawn(server.serve(app.into_make_service()));
+        format!("https://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_tls_options() {
+        let dir = private_ca();
+        let server = mtls_server(&dir).await;
+        let version = |tls: TlsOptions| {
+            let server = server.clone();
+            async move {
+                let client = AdminClient::new(&server, "tk_admin", Duration::from_secs(5), &tls)?;
+                client.get_json::<serde_json::Value>("/_admin/version").await
+            }
+        };
+        let ca_cert = Some(dir.join("ca.pem"));
+        let client_cert = TlsOptions {
+            client_cert: Some(dir.join("client.pem")),
+            client_key: Some(dir.join("client.key")),
+            ..TlsOptions::default()
+        };
+
+        // The server's certificate isn't trusted without the CA
+        let err = version(client_cert.clone()).await.unwrap_err();
+        assert!(matches!(err, AdminError::Tls { .. }), "{:?}", err);
+
+        // The server wants a client certificate
+        let err = version(TlsOptions { ca_cert: ca_cert.clone(), ..TlsOptions::default() }).await.unwrap_err();
+        assert!(matches!(err, AdminError::Tls { .. }), "{:?}", err);
+
+        let body = version(TlsOptions { ca_cert: ca_cert.clone(), ..client_cert.clone() }).await.unwrap();
+        assert_eq!(body["version"], "0.1.0");
+        let body = version(TlsOptions { insecure_skip_verify: Some(true), ..client_cert.clone() }).await.unwrap();
+        assert_eq!(body["version"], "0.1.0");
+
+        // Unusable options are reported before connecting
+        let err = version(TlsOptions { client_key: None, ..client_cert.clone() }).await.unwrap_err();
+        assert!(err.to_string().contains("client_key"), "{}", err);
+        let err = version(TlsOptions { ca_cert: Some(dir.join("missing.pem")), ..TlsOptions::default() }).await.unwrap_err();
+        assert!(err.to_string().contains("missing.pem"), "{}", err);
+        let err = version(TlsOptions { ca_cert: Some(dir.join("client.key")), ..TlsOptions::default() }).await.unwrap_err();
+        assert!(err.to_string().contains("No PEM certificates"), "{}", err);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(base_url("tunnel.example.com/"), "https://tunnel.example.com");
+        assert_eq!(base_url("http://localhost:8080/"), "http://localhost:8080");
+    }
+}
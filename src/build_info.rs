@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Build metadata embedded by build.rs, used to pin the exact build in bug reports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_sha: Option<String>,
+    pub build_date: Option<String>,
+    pub target: Option<String>,
+    pub rustc_version: Option<String>,
+    pub features: Vec<String>,
+}
+
+/// Treat the empty strings build.rs emits for unavailable values as missing
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl BuildInfo {
+    /// Build info for the running binary
+    pub fn current() -> Self {
+        Self::from_parts(
+            env!("CARGO_PKG_VERSION"),
+            env!("LOOPHOLE_GIT_SHA"),
+            env!("LOOPHOLE_BUILD_DATE"),
+            env!("LOOPHOLE_TARGET"),
+            env!("LOOPHOLE_RUSTC_VERSION"),
+            env!("LOOPHOLE_FEATURES"),
+        )
+    }
+
+    fn from_parts(
+        version: &str,
+        git_sha: &str,
+        build_date: &str,
+        target: &str,
+        rustc_version: &str,
+        features: &str,
+    ) -> Self {
+        Self {
+            version: version.to_string(),
+            git_sha: non_empty(git_sha),
+            build_date: non_empty(build_date),
+            target: non_empty(target),
+            rustc_version: non_empty(rustc_version),
+            features: features
+                .split(',')
+                .filter_map(non_empty)
+                .collect(),
+        }
+    }
+
+    /// Multi-line description for `loophole --version --verbose`
+    pub fn verbose(&self) -> String {
+        let unknown = || "unknown".to_string();
+        let features = if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join(", ")
+        };
+        format!(
+            "loophole {}\ngit sha:    {}\nbuild date: {}\ntarget:     {}\nrustc:      {}\nfeatures:   {}",
+            self.version,
+            self.git_sha.clone().unwrap_or_else(unknown),
+            self.build_date.clone().unwrap_or_else(unknown),
+            self.target.clone().unwrap_or_else(unknown),
+            self.rustc_version.clone().unwrap_or_else(unknown),
+            features,
+        )
+    }
+}
+
+/// Short form, e.g. `0.1.0 (1a2b3c4d5e6f 2026-10-17)`
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version)?;
+        match (&self.git_sha, &self.build_date) {
+            (Some(sha), Some(date)) => write!(f, " ({} {})", sha, date),
+            (Some(sha), None) => write!(f, " ({})", sha),
+            (None, Some(date)) => write!(f, " ({})", date),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info_serialization() {
+        let info = BuildInfo::from_parts(
+            "0.1.0",
+            "1a2b3c4d5e6f",
+            "2026-10-17",
+            "x86_64-unknown-linux-gnu",
+            "rustc 1.85.0",
+            "foo,bar",
+        );
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["version"], "0.1.0");
+        assert_eq!(json["git_sha"], "1a2b3c4d5e6f");
+        assert_eq!(json["features"], serde_json::json!(["foo", "bar"]));
+
+        let parsed: BuildInfo = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, info);
+        assert_eq!(info.to_string(), "0.1.0 (1a2b3c4d5e6f 2026-10-17)");
+    }
+
+    #[test]
+    fn test_missing_git_info() {
+        // What build.rs emits when building from a tarball without git
+        let info = BuildInfo::from_parts("0.1.0", "", "2026-10-17", "", "", "");
+        assert_eq!(info.git_sha, None);
+        assert!(info.features.is_empty());
+        assert_eq!(info.to_string(), "0.1.0 (2026-10-17)");
+        assert!(info.verbose().contains("git sha:    unknown"));
+        assert_eq!(serde_json::to_value(&info).unwrap()["git_sha"], serde_json::Value::Null);
+
+        // The embedded info for this build is always usable
+        assert_eq!(BuildInfo::current().version, env!("CARGO_PKG_VERSION"));
+    }
+}
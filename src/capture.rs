@@ -0,0 +1,262 @@
+//! What request and response data may be kept when traffic is captured for display
+//! or export (the client's inspector and HAR files, the server's per-tunnel log).
+//!
+//! Bodies are cut off at a byte cap so uploads don't fill memory, binary bodies are
+//! recorded by size only, and the values of sensitive headers are replaced with
+//! `[redacted]` before anything is stored. Every capture feature goes through a
+//! [`CapturePolicy`] rather than copying raw bytes; the client's replay buffer, which
+//! has to resend requests as they were, takes only its body cap.
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+
+/// Bodies are kept up to this many bytes by default
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Headers whose values are never stored, unless a policy adds more
+pub const DEFAULT_REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Stored in place of a redacted header value
+pub const REDACTED: &str = "[redacted]";
+
+/// Media types (besides `text/*` and `+json`/`+xml` suffixes) whose bodies are text
+const TEXT_TYPES: &[&str] = &[
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/ecmascript",
+    "application/x-www-form-urlencoded",
+    "application/graphql",
+    "application/x-ndjson",
+    "image/svg+xml",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturePolicy {
+    max_body_bytes: usize,
+    /// Lower case
+    redacted_headers: Vec<String>,
+}
+
+impl Default for CapturePolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BODY_BYTES)
+    }
+}
+
+impl CapturePolicy {
+    /// Keep bodies up to `max_body_bytes`, redacting the default headers
+    pub fn new(max_body_bytes: usize) -> Self {
+        Self {
+            max_body_bytes,
+            redacted_headers: DEFAULT_REDACTED_HEADERS.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Also redact these headers (from config or flags), matched case-insensitively
+    pub fn redact_headers<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for name in names {
+            let name = name.as_ref().trim().to_ascii_lowercase();
+            if !name.is_empty() && !self.redacted_headers.contains(&name) {
+                self.redacted_headers.push(name);
+            }
+        }
+        self
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    pub fn is_redacted(&self, name: &str) -> bool {
+        self.redacted_headers.iter().any(|redacted| redacted.eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The value to store for header `name`
+    pub fn header_value<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.is_redacted(name) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    /// Headers as they may be stored, in their original order
+    pub fn headers<'a>(&self, headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<(String, String)> {
+        headers
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), self.header_value(name, value).to_string()))
+            .collect()
+    }
+
+    /// What to store of a body. `body` may be just the first part of it, as long as
+    /// `total_len` is the full length, so callers needn't buffer whole uploads.
+    pub fn body(&self, content_type: Option<&str>, body: &[u8], total_len: usize) -> CapturedBody {
+        if total_len == 0 {
+            return CapturedBody::Empty;
+        }
+        if !content_type.is_none_or(is_text_type) {
+            return CapturedBody::Binary { len: total_len };
+        }
+
+        let kept = &body[..body.len().min(self.max_body_bytes)];
+        let text = match std::str::from_utf8(kept) {
+            Ok(text) => text,
+            // Cut off mid-character by the cap (or by the caller)
+            Err(e) if e.error_len().is_none() && kept.len() < total_len => {
+                std::str::from_utf8(&kept[..e.valid_up_to()]).expect("valid up to here")
+            }
+            Err(_) => return CapturedBody::Binary { len: total_len },
+        };
+        if text.contains('\0') {
+            return CapturedBody::Binary { len: total_len };
+        }
+        CapturedBody::Text {
+            text: text.to_string(),
+            truncated_bytes: total_len - text.len(),
+        }
+    }
+}
+
+/// Whether a Content-Type names a text format; parameters such as charset are ignored
+fn is_text_type(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || TEXT_TYPES.contains(&media_type.as_str())
+}
+
+/// A body as stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CapturedBody {
+    Empty,
+    /// The start of a text body, and how many bytes were cut off the end
+    Text { text: String, truncated_bytes: usize },
+    /// A binary body, recorded by size only
+    Binary { len: usize },
+}
+
+impl CapturedBody {
+    /// The full length of the original body
+    pub fn len(&self) -> usize {
+        match self {
+            CapturedBody::Empty => 0,
+            CapturedBody::Text { text, truncated_bytes } => text.len() + truncated_bytes,
+            CapturedBody::Binary { len } => *len,
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, CapturedBody::Text { truncated_bytes, .. } if *truncated_bytes > 0)
+    }
+}
+
+/// As `{"size", "truncated", "binary", "text"}`, with `text` absent for binary bodies
+impl Serialize for CapturedBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut body = serializer.serialize_struct("CapturedBody", 4)?;
+        body.serialize_field("size", &self.len())?;
+        body.serialize_field("truncated", &self.is_truncated())?;
+        body.serialize_field("binary", &matches!(self, CapturedBody::Binary { .. }))?;
+        match self {
+            CapturedBody::Empty => body.serialize_field("text", "")?,
+            CapturedBody::Text { text, .. } => body.serialize_field("text", text)?,
+            CapturedBody::Binary { .. } => body.skip_field("text")?,
+        }
+        body.end()
+    }
+}
+
+/// The body for display, with a marker where anything was left out
+impl fmt::Display for CapturedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapturedBody::Empty => Ok(()),
+            CapturedBody::Text { text, truncated_bytes: 0 } => f.write_str(text),
+            CapturedBody::Text { text, truncated_bytes } => {
+                write!(f, "{}\n[truncated: {} more bytes]", text, truncated_bytes)
+            }
+            CapturedBody::Binary { len } => write!(f, "[binary body: {} bytes]", len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncation() {
+        let policy = CapturePolicy::new(5);
+        let body = policy.body(Some("text/plain"), b"hello world", 11);
+        assert_eq!(body, CapturedBody::Text { text: "hello".into(), truncated_bytes: 6 });
+        assert!(body.is_truncated());
+        assert_eq!(body.len(), 11);
+        assert_eq!(body.to_string(), "hello\n[truncated: 6 more bytes]");
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            r#"{"size":11,"truncated":true,"binary":false,"text":"hello"}"#
+        );
+
+        // Only a prefix was passed in, but the full length is known
+        let body = policy.body(None, b"hel", 1_000_000);
+        assert_eq!(body, CapturedBody::Text { text: "hel".into(), truncated_bytes: 999_997 });
+
+        let body = policy.body(Some("application/json"), b"{}", 2);
+        assert!(!body.is_truncated());
+        assert_eq!(body.to_string(), "{}");
+        assert_eq!(policy.body(Some("text/plain"), b"", 0), CapturedBody::Empty);
+    }
+
+    #[test]
+    fn test_truncation_keeps_whole_characters() {
+        // "héllo": the cap falls inside the two-byte é
+        let policy = CapturePolicy::new(2);
+        let body = policy.body(Some("text/plain; charset=utf-8"), "héllo".as_bytes(), 6);
+        assert_eq!(body, CapturedBody::Text { text: "h".into(), truncated_bytes: 5 });
+    }
+
+    #[test]
+    fn test_binary_bodies() {
+        let policy = CapturePolicy::default();
+        // By content type, whatever the bytes
+        assert_eq!(policy.body(Some("image/png"), b"plain ascii", 11), CapturedBody::Binary { len: 11 });
+        assert_eq!(policy.body(Some("application/octet-stream"), b"x", 1), CapturedBody::Binary { len: 1 });
+        // By sniffing, when the type is text or missing
+        assert_eq!(policy.body(None, &[0xff, 0xfe, 0x00], 3), CapturedBody::Binary { len: 3 });
+        assert_eq!(policy.body(Some("text/plain"), b"a\0b", 3), CapturedBody::Binary { len: 3 });
+        assert_eq!(CapturedBody::Binary { len: 3 }.to_string(), "[binary body: 3 bytes]");
+        assert_eq!(
+            serde_json::to_string(&CapturedBody::Binary { len: 3 }).unwrap(),
+            r#"{"size":3,"truncated":false,"binary":true}"#
+        );
+
+        for text_type in ["text/html", "application/problem+json", "application/atom+xml", "Application/JSON"] {
+            assert!(matches!(policy.body(Some(text_type), b"ok", 2), CapturedBody::Text { .. }), "{}", text_type);
+        }
+    }
+
+    #[test]
+    fn test_redaction_is_case_insensitive() {
+        let policy = CapturePolicy::default().redact_headers(["X-Session-Token", " "]);
+        let headers = policy.headers([
+            ("Authorization", "Bearer secret"),
+            ("COOKIE", "a=b"),
+            ("set-cookie", "c=d"),
+            ("X-Api-Key", "k"),
+            ("x-session-token", "t"),
+            ("Content-Type", "text/plain"),
+        ]);
+        let values: Vec<&str> = headers.iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(values, [REDACTED, REDACTED, REDACTED, REDACTED, REDACTED, "text/plain"]);
+        // Names are stored as they were sent
+        assert_eq!(headers[1].0, "COOKIE");
+        assert!(!policy.is_redacted("content-type"));
+        assert!(!policy.is_redacted(""));
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::admin_client::{self, AdminClient, AdminError};
+use crate::client_config::TlsOptions;
+use crate::expose::credentials;
+
+#[derive(Debug, Serialize)]
+struct RegistrationCheck<'a> {
+    subdomain: &'a str,
+}
+
+/// Whether the server would register the subdomain, and if not, why
+#[derive(Debug, Serialize, Deserialize)]
+struct Verdict {
+    subdomain: String,
+    available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// Kept as a string, so codes added by newer servers still print
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Ask the server whether the token could register `subdomain` now, without
+/// registering it. Fails if it couldn't, so CI can stop before starting a tunnel.
+pub async fn run(
+    subdomain: String,
+    server: Option<String>,
+    token: Option<String>,
+    json: bool,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    let (server, token) = credentials(server, token)?;
+    let client = AdminClient::new(&server, &token, timeout, &admin_client::resolve_tls(&server, tls)?)?;
+    let verdict = check(&client, &subdomain).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&verdict)?);
+    } else if verdict.available {
+        println!(
+            "{} {} is available{}",
+            "✓".green(),
+            verdict.subdomain.green(),
+            verdict.url.as_deref().map(|url| format!(" at {}", url)).unwrap_or_default()
+        );
+    }
+    if !verdict.available {
+        anyhow::bail!(
+            "{} isn't available: {}",
+            verdict.subdomain,
+            verdict.message.as_deref().unwrap_or("no reason given")
+        );
+    }
+    Ok(())
+}
+
+async fn check(client: &AdminClient, subdomain: &str) -> Result<Verdict> {
+    match client.post_json("/_check/registration", &RegistrationCheck { subdomain }).await {
+        Ok(verdict) => Ok(verdict),
+        Err(AdminError::NotFound) => anyhow::bail!("The server doesn't support registration checks; it may need upgrading"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::post, Json, Router};
+
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let server = mock_server(Router::new().route(
+            "/_check/registration",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                Json(match body["subdomain"].as_str() {
+                    Some("free") => serde_json::json!({
+                        "subdomain": "free",
+                        "available": true,
+                        "url": "https://free.tunnel.example.com"
+                    }),
+                    _ => serde_json::json!({
+                        "subdomain": "taken",
+                        "available": false,
+                        "code": "subdomain_taken",
+                        "message": "Subdomain 'taken' is already in use"
+                    }),
+                })
+            }),
+        ))
+        .await;
+        let client = AdminClient::new(&server, "tk_alice", Duration::from_secs(5), &TlsOptions::default()).unwrap();
+
+        let verdict = check(&client, "free").await.unwrap();
+        assert!(verdict.available);
+        assert_eq!(verdict.url.as_deref(), Some("https://free.tunnel.example.com"));
+
+        let verdict = check(&client, "taken").await.unwrap();
+        assert!(!verdict.available);
+        assert_eq!(verdict.code.as_deref(), Some("subdomain_taken"));
+        assert_eq!(verdict.message.as_deref(), Some("Subdomain 'taken' is already in use"));
+    }
+
+    #[tokio::test]
+    async fn test_older_server() {
+        let server = mock_server(Router::new().fallback(|| async { (StatusCode::NOT_FOUND, "Tunnel not found") })).await;
+        let client = AdminClient::new(&server, "tk_alice", Duration::from_secs(5), &TlsOptions::default()).unwrap();
+        let err = check(&client, "free").await.unwrap_err();
+        assert!(err.to_string().contains("doesn't support registration checks"), "{}", err);
+    }
+}
@@ -0,0 +1,138 @@
+//! Decoding `Transfer-Encoding: chunked` bodies as they arrive, for the server's proxy
+//! handing visitors plain bodies and the client's inspector keeping what it captures.
+
+use bytes::{Buf, Bytes, BytesMut};
+
+/// Longest chunk-size or trailer line accepted
+const MAX_CHUNK_LINE: usize = 8192;
+
+/// Incremental decoder for chunked transfer coding, fed the body as it arrives
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    /// Partial size or trailer line carried over between reads
+    line: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64),
+    /// The CRLF after a chunk's data
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+impl ChunkedDecoder {
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    /// The next run of chunk data at the front of `input`, split off it without
+    /// copying, after consuming any size lines and CRLFs before it
+    pub fn next_chunk(&mut self, input: &mut BytesMut) -> std::io::Result<Option<Bytes>> {
+        while !input.is_empty() && !self.is_done() {
+            if let ChunkState::Data(remaining) = self.state {
+                let n = remaining.min(input.len() as u64) as usize;
+                self.state = match remaining - n as u64 {
+                    0 => ChunkState::DataEnd,
+                    left => ChunkState::Data(left),
+                };
+                return Ok(Some(input.split_to(n).freeze()));
+            }
+
+            let Some(line) = self.take_line(input)? else {
+                break;
+            };
+            self.state = match self.state {
+                ChunkState::Size => {
+                    let size = std::str::from_utf8(&line)
+                        .ok()
+                        .map(|l| l.split(';').next().unwrap_or("").trim())
+                        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| invalid_chunk("invalid chunk size"))?;
+                    if size == 0 {
+                        ChunkState::Trailer
+                    } else {
+                        ChunkState::Data(size)
+                    }
+                }
+                ChunkState::DataEnd if line.is_empty() => ChunkState::Size,
+                ChunkState::DataEnd => return Err(invalid_chunk("chunk data longer than its size")),
+                ChunkState::Trailer if line.is_empty() => ChunkState::Done,
+                state => state,
+            };
+        }
+        if self.is_done() {
+            input.clear();
+        }
+        Ok(None)
+    }
+
+    /// The next line without its line ending, or None if it hasn't fully arrived
+    fn take_line(&mut self, input: &mut BytesMut) -> std::io::Result<Option<Vec<u8>>> {
+        let Some(pos) = input.iter().position(|&b| b == b'\n') else {
+            self.line.extend_from_slice(input);
+            input.clear();
+            if self.line.len() > MAX_CHUNK_LINE {
+                return Err(invalid_chunk("chunk line too long"));
+            }
+            return Ok(None);
+        };
+        self.line.extend_from_slice(&input[..pos]);
+        input.advance(pos + 1);
+        let mut line = std::mem::take(&mut self.line);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+}
+
+fn invalid_chunk(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything `decoder` makes of `encoded`, fed in pieces of `size` bytes as reads
+    /// from the stream may split it
+    fn decode(decoder: &mut ChunkedDecoder, encoded: &[u8], size: usize) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut received = BytesMut::new();
+        for piece in encoded.chunks(size) {
+            received.extend_from_slice(piece);
+            while let Some(chunk) = decoder.next_chunk(&mut received)? {
+                out.extend_from_slice(&chunk);
+            }
+            assert!(received.is_empty(), "left {:?}", received);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_chunked_decoder() {
+        let encoded = b"5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+
+        for split in 0..encoded.len() {
+            let mut decoder = ChunkedDecoder::default();
+            let out = decode(&mut decoder, encoded, split.max(1)).unwrap();
+            assert!(decoder.is_done(), "split at {}", split);
+            assert_eq!(out, b"hello, world", "split at {}", split);
+        }
+
+        // Cut off partway through a chunk
+        let mut decoder = ChunkedDecoder::default();
+        assert_eq!(decode(&mut decoder, b"a\r\n0123", 64).unwrap(), b"0123");
+        assert!(!decoder.is_done());
+
+        assert!(decode(&mut ChunkedDecoder::default(), b"zz\r\n", 64).is_err());
+        assert!(decode(&mut ChunkedDecoder::default(), b"2\r\nabc\r\n", 64).is_err());
+        let long_line = [b"1;".as_slice(), &[b'x'; MAX_CHUNK_LINE + 1]].concat();
+        assert!(decode(&mut ChunkedDecoder::default(), &long_line, 64).is_err());
+    }
+}
@@ -1,15 +1,89 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::idn;
+use crate::units;
 
 const CONFIG_VERSION: u32 = 1;
 
+/// Held (with an advisory lock) while the config is read, changed and written back
+const LOCK_FILE: &str = "config.lock";
+
+/// Name used for the top-level server/token when listing profiles
+pub const DEFAULT_PROFILE: &str = "default";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub version: u32,
     pub server: String,
     pub token: String,
+    /// How the admin commands reach `server` over TLS
+    #[serde(default, skip_serializing_if = "TlsOptions::is_empty")]
+    pub tls: TlsOptions,
+    /// Additional named servers, saved with `loophole login --profile <name>`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub server: String,
+    pub token: String,
+    #[serde(default, skip_serializing_if = "TlsOptions::is_empty")]
+    pub tls: TlsOptions,
+}
+
+/// TLS settings for the admin commands (status, logs, tokens, ...), for servers
+/// with a certificate from a private CA or that ask for a client certificate. Saved
+/// as `[tls]` for the default server and `[profiles.<name>.tls]` for the others, or
+/// given as flags, which take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    /// PEM file of CA certificates to trust on top of the system's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate to present, with its PEM key in `client_key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// Accept any server certificate, e.g. a self-signed one while testing. `None`
+    /// when neither set nor unset, so a flag can turn off what a profile turns on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insecure_skip_verify: Option<bool>,
+}
+
+impl TlsOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn skips_verify(&self) -> bool {
+        self.insecure_skip_verify == Some(true)
+    }
+
+    /// These options, with the ones `overrides` sets taking their place. The client
+    /// certificate and key are replaced together.
+    pub fn merged(self, overrides: TlsOptions) -> Self {
+        let (client_cert, client_key) = if overrides.client_cert.is_some() || overrides.client_key.is_some() {
+            (overrides.client_cert, overrides.client_key)
+        } else {
+            (self.client_cert, self.client_key)
+        };
+        Self {
+            ca_cert: overrides.ca_cert.or(self.ca_cert),
+            client_cert,
+            client_key,
+            insecure_skip_verify: overrides.insecure_skip_verify.or(self.insecure_skip_verify),
+        }
+    }
 }
 
 fn config_dir() -> PathBuf {
@@ -28,19 +102,36 @@ impl ClientConfig {
             version: CONFIG_VERSION,
             server,
             token,
+            tls: TlsOptions::default(),
+            profiles: BTreeMap::new(),
         }
     }
 
+    /// The default server followed by every named profile
+    pub fn all_profiles(&self) -> Vec<(String, Profile)> {
+        let default = Profile {
+            server: self.server.clone(),
+            token: self.token.clone(),
+            tls: self.tls.clone(),
+        };
+        std::iter::once((DEFAULT_PROFILE.to_string(), default))
+            .chain(self.profiles.iter().map(|(name, p)| (name.clone(), p.clone())))
+            .collect()
+    }
+
     pub fn load() -> Result<Option<Self>> {
-        let path = config_path();
+        Self::load_from(&config_path())
+    }
+
+    fn load_from(path: &Path) -> Result<Option<Self>> {
         if !path.exists() {
             return Ok(None);
         }
 
-        let content = fs::read_to_string(&path)
+        let content = fs::read_to_string(path)
             .context(format!("Failed to read config from {}", path.display()))?;
 
-        let config: ClientConfig = toml::from_str(&content)
+        let config = Self::parse(&content)
             .context(format!("Failed to parse config from {}", path.display()))?;
 
         if config.version != CONFIG_VERSION {
@@ -54,17 +145,418 @@ impl ClientConfig {
         Ok(Some(config))
     }
 
-    pub fn save(&self) -> Result<PathBuf> {
-        let dir = config_dir();
-        fs::create_dir_all(&dir)
-            .context(format!("Failed to create config directory {}", dir.display()))?;
+    fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Change the saved config (None if there isn't one yet) under the config lock,
+    /// so concurrent logins don't lose each other's changes
+    pub fn update(change: impl FnOnce(Option<Self>) -> Self) -> Result<PathBuf> {
+        Self::update_at(&config_path(), change)
+    }
+
+    fn update_at(path: &Path, change: impl FnOnce(Option<Self>) -> Self) -> Result<PathBuf> {
+        let dir = path.parent().context("Config path has no directory")?;
+        create_config_dir(dir)?;
+        let _lock = ConfigLock::acquire(dir)?;
+        let config = change(Self::load_from(path)?);
+        config.save_to(path)?;
+        Ok(path.to_path_buf())
+    }
 
-        let path = config_path();
+    /// Write the config atomically: readers see the old file or the new one, never a
+    /// mix, and a crash part-way leaves the old one in place
+    fn save_to(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().context("Config path has no directory")?;
+        create_config_dir(dir)?;
         let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
 
-        fs::write(&path, content)
-            .context(format!("Failed to write config to {}", path.display()))?;
+        // In the same directory, so the rename can't cross filesystems
+        let temp = dir.join(format!(".config.toml.{}.tmp", uuid::Uuid::new_v4()));
+        let written = write_private(&temp, content.as_bytes()).and_then(|()| fs::rename(&temp, path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(e).context(format!("Failed to write config to {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Several tunnels to run together with `loophole start`, one `[tunnels.<name>]`
+/// table each. Kept beside a project rather than in the login config, so it holds no
+/// server or token.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelsFile {
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, TunnelSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelSpec {
+    /// Random if not set. Internationalized names are kept as punycode, the form sent
+    /// to the server.
+    #[serde(default, deserialize_with = "deserialize_subdomain")]
+    pub subdomain: Option<String>,
+    /// Other subdomains to reach the tunnel on, as punycode like `subdomain`
+    #[serde(default, deserialize_with = "deserialize_aliases")]
+    pub aliases: Vec<String>,
+    pub port: u16,
+    #[serde(default = "default_tunnel_host")]
+    pub host: String,
+    /// Host header for local requests
+    pub local_host: Option<String>,
+    #[serde(
+        rename = "forward_timeout",
+        default = "default_forward_timeout_secs",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub forward_timeout_secs: u64,
+}
+
+fn deserialize_subdomain<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let subdomain = String::deserialize(deserializer)?;
+    idn::to_ascii(&subdomain).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_aliases<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let aliases = Vec::<String>::deserialize(deserializer)?;
+    aliases
+        .iter()
+        .map(|alias| idn::to_ascii(alias).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn default_tunnel_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_forward_timeout_secs() -> u64 {
+    30
+}
+
+impl TunnelSpec {
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host.parse().expect("validated when loaded"), self.port)
+    }
+
+    pub fn forward_timeout(&self) -> Duration {
+        Duration::from_secs(self.forward_timeout_secs)
+    }
+}
+
+impl TunnelsFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).context(format!("Failed to read tunnels from {}", path.display()))?;
+        Self::parse(&content).context(format!("Invalid tunnels file {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: Self = toml::from_str(content)?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.tunnels.is_empty() {
+            anyhow::bail!("No tunnels defined; add a [tunnels.<name>] table for each one");
+        }
+        let mut subdomains = BTreeMap::new();
+        for (name, spec) in &self.tunnels {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                anyhow::bail!("Invalid tunnel name '{}': use letters, digits, '-' and '_'", name);
+            }
+            if spec.port == 0 {
+                anyhow::bail!("tunnels.{}: port must be between 1 and 65535", name);
+            }
+            if spec.host.parse::<IpAddr>().is_err() {
+                anyhow::bail!("tunnels.{}: host must be an IP address, got '{}'", name, spec.host);
+            }
+            if spec.forward_timeout_secs == 0 {
+                anyhow::bail!("tunnels.{}: forward_timeout must be greater than zero", name);
+            }
+            // Aliases can't be shared either, with each other or with subdomains
+            for subdomain in spec.subdomain.iter().chain(&spec.aliases) {
+                match subdomains.insert(subdomain.to_ascii_lowercase(), name) {
+                    Some(other) if other == name => anyhow::bail!("tunnels.{} asks for subdomain '{}' twice", name, subdomain),
+                    Some(other) => {
+                        anyhow::bail!("tunnels.{} and tunnels.{} both ask for subdomain '{}'", other, name, subdomain)
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the config directory, following a symlink to a directory that doesn't
+/// exist yet (as dotfile managers leave them) instead of failing with "File exists"
+fn create_config_dir(dir: &Path) -> Result<()> {
+    let is_dangling_symlink = dir.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) && !dir.exists();
+    let target = if is_dangling_symlink {
+        let target = fs::read_link(dir).context(format!("Failed to read symlink {}", dir.display()))?;
+        // Relative targets are relative to the symlink's own directory
+        dir.parent().map_or(target.clone(), |parent| parent.join(&target))
+    } else {
+        dir.to_path_buf()
+    };
+    fs::create_dir_all(&target).context(format!("Failed to create config directory {}", target.display()))
+}
+
+/// Write a file only its owner can read, since it holds tokens
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// An exclusive advisory lock on the config directory, released when dropped. The
+/// config file itself is replaced on every save, so the lock lives in its own file.
+struct ConfigLock {
+    _file: fs::File,
+}
+
+impl ConfigLock {
+    fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path).context(format!("Failed to open {}", path.display()))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            // SAFETY: the descriptor is open for as long as `file` is
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error()).context(format!("Failed to lock {}", path.display()));
+            }
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("loophole-client-{}", uuid::Uuid::new_v4()));
+        (dir.join("config.toml"), dir)
+    }
+
+    fn profile(n: usize) -> Profile {
+        Profile {
+            server: format!("https://{}.example.com", n),
+            token: format!("tk_{}", "x".repeat(n * 50)),
+            tls: TlsOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_concurrent_saves_leave_a_whole_file() {
+        let (path, dir) = temp_config();
+        let configs: Vec<ClientConfig> = (0..8)
+            .map(|n| {
+                let mut config = ClientConfig::new(format!("https://{}.example.com", n), format!("tk_{}", n));
+                // Different lengths, so an interleaved write would show
+                config.profiles = (0..n * 3).map(|i| (format!("p{}", i), profile(i))).collect();
+                config
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for config in &configs {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        config.save_to(&path).unwrap();
+                    }
+                });
+            }
+            // Readers never see a partial file
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    if let Some(config) = ClientConfig::load_from(&path).unwrap() {
+                        assert!(configs.iter().any(|c| c.server == config.server && c.profiles == config.profiles));
+                    }
+                }
+            });
+        });
+
+        let saved = ClientConfig::load_from(&path).unwrap().unwrap();
+        assert!(configs.iter().any(|c| c.server == saved.server && c.profiles == saved.profiles));
+        // No temp files left behind
+        let leftovers: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_updates_keep_every_change() {
+        let (path, dir) = temp_config();
+        std::thread::scope(|scope| {
+            for n in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    ClientConfig::update_at(path, |config| {
+                        let mut config = config.unwrap_or_else(|| ClientConfig::new("https://a.example.com".into(), "tk_a".into()));
+                        config.profiles.insert(format!("p{}", n), profile(n));
+                        config
+                    })
+                    .unwrap();
+                });
+            }
+        });
+
+        let saved = ClientConfig::load_from(&path).unwrap().unwrap();
+        assert_eq!(saved.profiles.len(), 8, "an update was lost: {:?}", saved.profiles.keys());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_permissions_and_dangling_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The config directory is a symlink to a directory that doesn't exist yet
+        let (_, root) = temp_config();
+        fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink("real-config", root.join("loophole")).unwrap();
+        let path = root.join("loophole").join("config.toml");
+        assert!(ClientConfig::load_from(&path).unwrap().is_none());
+
+        ClientConfig::new("https://a.example.com".into(), "tk_a".into()).save_to(&path).unwrap();
+        assert!(root.join("real-config/config.toml").is_file());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_tunnels_file() {
+        let file = TunnelsFile::parse(
+            r#"
+[tunnels.api]
+subdomain = "my-api"
+aliases = ["api-v2", "münchen-api"]
+port = 8080
+forward_timeout = "2m"
+
+[tunnels.web]
+port = 3000
+host = "::1"
+local_host = "web.localhost"
+"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = file.tunnels.keys().map(String::as_str).collect();
+        assert_eq!(names, ["api", "web"]);
+        let api = &file.tunnels["api"];
+        assert_eq!(api.subdomain.as_deref(), Some("my-api"));
+        assert_eq!(api.aliases, ["api-v2", "xn--mnchen-api-9db"]);
+        assert_eq!(api.local_addr(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(api.forward_timeout(), Duration::from_secs(120));
+        let web = &file.tunnels["web"];
+        assert_eq!(web.subdomain, None);
+        assert!(web.aliases.is_empty());
+        assert_eq!(web.local_addr(), "[::1]:3000".parse().unwrap());
+        assert_eq!(web.local_host.as_deref(), Some("web.localhost"));
+        assert_eq!(web.forward_timeout(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_tunnels_file_validation() {
+        let error = |content: &str| format!("{:#}", TunnelsFile::parse(content).unwrap_err());
+
+        assert!(error("").contains("No tunnels defined"));
+        assert!(error("[tunnels.api]\nsubdomain = \"api\"\n").contains("missing field `port`"));
+        assert!(error("[tunnels.api]\nport = 80\nlocalhost = \"x\"\n").contains("unknown field `localhost`"));
+        assert!(error("[tunnels.api]\nport = 0\n").contains("tunnels.api: port"));
+        assert!(error("[tunnels.api]\nport = 80\nhost = \"localhost\"\n").contains("host must be an IP address"));
+        assert!(error("[tunnels.api]\nport = 80\nforward_timeout = 0\n").contains("greater than zero"));
+        assert!(error("[tunnels.\"a b\"]\nport = 80\n").contains("Invalid tunnel name 'a b'"));
+        assert!(error("[tunnels.a]\nport = 80\nsubdomain = \"app\"\n[tunnels.b]\nport = 81\nsubdomain = \"App\"\n")
+            .contains("tunnels.a and tunnels.b both ask for subdomain 'App'"));
+        assert!(error("[tunnels.a]\nport = 80\nsubdomain = \"app\"\n[tunnels.b]\nport = 81\naliases = [\"app\"]\n")
+            .contains("tunnels.a and tunnels.b both ask for subdomain 'app'"));
+        assert!(error("[tunnels.a]\nport = 80\nsubdomain = \"app\"\naliases = [\"app\"]\n")
+            .contains("tunnels.a asks for subdomain 'app' twice"));
+        // The same name, in Unicode and as punycode
+        assert!(error("[tunnels.a]\nport = 80\nsubdomain = \"münchen\"\n[tunnels.b]\nport = 81\nsubdomain = \"xn--mnchen-3ya\"\n")
+            .contains("both ask for subdomain 'xn--mnchen-3ya'"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        // Configs written before profiles existed still load
+        let config = ClientConfig::parse(
+            "version = 1\nserver = \"https://a.example.com\"\ntoken = \"tk_a\"\n",
+        )
+        .unwrap();
+        assert!(config.profiles.is_empty());
+        assert!(!toml::to_string(&config).unwrap().contains("profiles"));
+
+        let mut config = config;
+        config.profiles.insert(
+            "staging".to_string(),
+            Profile {
+                server: "https://b.example.com".to_string(),
+                token: "tk_b".to_string(),
+                tls: TlsOptions::default(),
+            },
+        );
+        let reparsed = ClientConfig::parse(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        let names: Vec<String> = reparsed.all_profiles().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["default", "staging"]);
+        assert_eq!(reparsed.profiles["staging"].token, "tk_b");
+    }
+
+    #[test]
+    fn test_tls_options() {
+        let config = ClientConfig::parse(
+            "version = 1\nserver = \"https://a.example.com\"\ntoken = \"tk_a\"\n\n\
+             [tls]\nca_cert = \"/etc/loophole/ca.pem\"\n\n\
+             [profiles.internal]\nserver = \"https://b.example.com\"\ntoken = \"tk_b\"\n\n\
+             [profiles.internal.tls]\nclient_cert = \"/etc/loophole/client.pem\"\nclient_key = \"/etc/loophole/client.key\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.tls.ca_cert, Some(PathBuf::from("/etc/loophole/ca.pem")));
+        let profiles = config.all_profiles();
+        assert_eq!(profiles[0].1.tls, config.tls);
+        assert_eq!(profiles[1].1.tls.client_key, Some(PathBuf::from("/etc/loophole/client.key")));
+        assert_eq!(ClientConfig::parse(&toml::to_string_pretty(&config).unwrap()).unwrap().profiles, config.profiles);
+        assert!(ClientConfig::parse("version = 1\nserver = \"a\"\ntoken = \"b\"\n[tls]\ncacert = \"ca.pem\"\n").is_err());
+
+        // Flags replace what's saved, the client certificate and key together
+        let saved = profiles[1].1.tls.clone();
+        let flags = TlsOptions {
+            ca_cert: Some(PathBuf::from("ca.pem")),
+            ..TlsOptions::default()
+        };
+        let merged = saved.clone().merged(flags.clone());
+        assert_eq!((merged.ca_cert, merged.client_cert), (flags.ca_cert, saved.client_cert.clone()));
+        let flags = TlsOptions {
+            client_cert: Some(PathBuf::from("other.pem")),
+            client_key: Some(PathBuf::from("other.key")),
+            ..TlsOptions::default()
+        };
+        assert_eq!(saved.clone().merged(flags.clone()), flags);
 
-        Ok(path)
+        // A flag can turn off skipping verification that a profile turns on
+        let saved = TlsOptions { insecure_skip_verify: Some(true), ..saved };
+        assert!(saved.clone().merged(TlsOptions::default()).skips_verify());
+        let flags = TlsOptions { insecure_skip_verify: Some(false), ..TlsOptions::default() };
+        assert!(!saved.merged(flags).skips_verify());
     }
 }
@@ -0,0 +1,80 @@
+//! Checking the system clock against a server's `Date` header. ACME orders and TLS
+//! validation both fail in confusing ways when the clock is badly off, so the server
+//! checks against its ACME directory and the client against the tunnel server.
+
+use std::time::{Duration, SystemTime};
+
+use crate::units;
+
+/// Skew beyond this is reported
+pub const MAX_SKEW: Duration = Duration::from_secs(2 * 60);
+
+/// A server's `Date` header, and when it arrived by the local clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerDate {
+    pub date: SystemTime,
+    pub received_at: SystemTime,
+}
+
+impl ServerDate {
+    /// None if `header` isn't an HTTP date
+    pub fn parse(header: &str, received_at: SystemTime) -> Option<Self> {
+        let date = httpdate::parse_http_date(header.trim()).ok()?;
+        Some(Self { date, received_at })
+    }
+
+    /// A warning naming the skew if the local clock is more than [`MAX_SKEW`] off the
+    /// clock of `server`, e.g. "the tunnel server"
+    pub fn skew_warning(&self, server: &str) -> Option<String> {
+        let (skew, direction) = match self.received_at.duration_since(self.date) {
+            Ok(skew) => (skew, "ahead of"),
+            Err(e) => (e.duration(), "behind"),
+        };
+        if skew <= MAX_SKEW {
+            return None;
+        }
+        // The header only has whole seconds
+        let skew = units::format_duration(Duration::from_secs(skew.as_secs()));
+        Some(format!(
+            "The system clock is {} {} {}'s. Certificates may be rejected and TLS may fail; check that NTP is running (e.g. timedatectl status)",
+            skew, direction, server
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn received(offset_secs: i64) -> SystemTime {
+        let date = httpdate::parse_http_date(DATE).unwrap();
+        match offset_secs {
+            secs if secs >= 0 => date + Duration::from_secs(secs as u64),
+            secs => date - Duration::from_secs(secs.unsigned_abs()),
+        }
+    }
+
+    #[test]
+    fn test_skew_warning() {
+        let warning = |offset| ServerDate::parse(DATE, received(offset)).unwrap().skew_warning("the ACME server");
+
+        assert_eq!(warning(0), None);
+        assert_eq!(warning(119), None);
+        assert_eq!(warning(-120), None);
+
+        let ahead = warning(5 * 60 + 3).unwrap();
+        assert!(ahead.starts_with("The system clock is 5m3s ahead of the ACME server's."), "{}", ahead);
+        assert!(ahead.contains("NTP"), "{}", ahead);
+        let behind = warning(-2 * 3600).unwrap();
+        assert!(behind.starts_with("The system clock is 2h behind the ACME server's."), "{}", behind);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(ServerDate::parse(&format!(" {} ", DATE), SystemTime::now()).is_some());
+        assert!(ServerDate::parse("yesterday", SystemTime::now()).is_none());
+        assert!(ServerDate::parse("", SystemTime::now()).is_none());
+    }
+}
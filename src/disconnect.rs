@@ -0,0 +1,26 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::time::Duration;
+
+use crate::admin_client::{self, AdminClient};
+use crate::client_config::TlsOptions;
+
+/// Force disconnect a tunnel via the admin API
+pub async fn run(
+    subdomain: String,
+    server: Option<String>,
+    token: Option<String>,
+    config_path: String,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    let (server, token) = admin_client::resolve_credentials(server, token, &config_path)?;
+    let client = AdminClient::new(&server, &token, timeout, &admin_client::resolve_tls(&server, tls)?)?;
+
+    client
+        .delete(&format!("/_admin/tunnels/{}", subdomain))
+        .await?;
+
+    println!("{} Disconnected tunnel {}", "✓".green(), subdomain.green());
+    Ok(())
+}
@@ -1,7 +1,18 @@
 use anyhow::{Context, Result};
 use futures::{SinkExt, StreamExt};
-use crate::proto::{ClientMessage, ErrorCode, ServerMessage};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use ipnet::IpNet;
+use std::time::SystemTime;
+use crate::build_info::BuildInfo;
+use crate::clock::ServerDate;
+use crate::proto::{ClientMessage, ErrorCode, Protocol, ServerMessage, TunnelLimits, TunnelMode};
+use crate::schedule::Window;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::{client_async_tls_with_config, tungstenite::Message};
+
+use super::dial::Dialer;
+use super::tunnel::websocket_config;
 use tracing::{debug, error, info};
 
 pub struct TunnelClient {
@@ -9,35 +20,108 @@ pub struct TunnelClient {
     pub token: String,
     pub subdomain: String,
     pub control_path: String,
+    pub dialer: Dialer,
+    pub protocol: Protocol,
+    /// Server port to ask for, for TCP tunnels
+    pub remote_port: Option<u16>,
+    /// Declared in the tunnel's manifest
+    pub service_name: Option<String>,
+    pub service_version: Option<String>,
+    pub publish_manifest: bool,
+    /// Lets `loophole connect` users reach a TCP tunnel without its token
+    pub share_key: Option<String>,
+    /// When the server should pause the tunnel for maintenance
+    pub pause_schedule: Option<Window>,
+    /// `user:password` visitors must present
+    pub basic_auth: Option<String>,
+    /// Networks visitors must come from; anyone may connect when empty
+    pub allow_ips: Vec<IpNet>,
+    /// How visitors reach an HTTP tunnel
+    pub mode: TunnelMode,
+    /// Other subdomains visitors reach the tunnel on
+    pub aliases: Vec<String>,
 }
 
 impl TunnelClient {
-    pub fn new(server: String, token: String, subdomain: String) -> Self {
+    pub fn new(server: String, token: String, subdomain: String, dialer: Dialer) -> Self {
         Self {
             server,
             token,
             subdomain,
             control_path: "/_tunnel/connect".to_string(),
+            dialer,
+            protocol: Protocol::Http,
+            remote_port: None,
+            service_name: None,
+            service_version: None,
+            publish_manifest: false,
+            share_key: None,
+            pause_schedule: None,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            mode: TunnelMode::Subdomain,
+            aliases: Vec::new(),
         }
     }
 
+    /// Register a TCP tunnel instead, on `remote_port` if given
+    pub fn tcp(mut self, remote_port: Option<u16>) -> Self {
+        self.protocol = Protocol::Tcp;
+        self.remote_port = remote_port;
+        self
+    }
+
+    /// Let connectors presenting `share_key` reach the TCP tunnel
+    pub fn share(mut self, share_key: Option<String>) -> Self {
+        self.share_key = share_key;
+        self
+    }
+
+    /// Have the server pause the tunnel for maintenance during `schedule`'s windows
+    pub fn pause_schedule(mut self, schedule: Option<Window>) -> Self {
+        self.pause_schedule = schedule;
+        self
+    }
+
+    /// Have the server ask visitors for `credentials` (`user:password`)
+    pub fn basic_auth(mut self, credentials: Option<String>) -> Self {
+        self.basic_auth = credentials;
+        self
+    }
+
+    /// Have the server refuse visitors from outside `allow_ips`
+    pub fn allow_ips(mut self, allow_ips: Vec<IpNet>) -> Self {
+        self.allow_ips = allow_ips;
+        self
+    }
+
+    /// Have the server serve the tunnel under `/t/<subdomain>/` on its own domain
+    pub fn path_mode(mut self, path_mode: bool) -> Self {
+        self.mode = if path_mode { TunnelMode::Path } else { TunnelMode::Subdomain };
+        self
+    }
+
+    /// Have the server route these subdomains to the tunnel too
+    pub fn aliases(mut self, aliases: Vec<String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Have the server publish the tunnel's manifest, with the service's name and version if given
+    pub fn manifest(mut self, publish: bool, service_name: Option<String>, service_version: Option<String>) -> Self {
+        self.publish_manifest = publish;
+        self.service_name = service_name;
+        self.service_version = service_version;
+        self
+    }
+
     pub async fn connect(&self) -> Result<TunnelConnection> {
-        // Convert HTTP(S) URL to WS(S) URL
-        let ws_url = if self.server.starts_with("https://") {
-            self.server.replace("https://", "wss://")
-        } else if self.server.starts_with("http://") {
-            self.server.replace("http://", "ws://")
-        } else {
-            // Legacy: no scheme provided, default to wss://
-            format!("wss://{}", self.server)
-        };
-        let ws_url = format!("{}{}", ws_url, self.control_path);
-        
-        info!("Connecting to {}", ws_url);
-        
-        let (ws_stream, _) = connect_async(&ws_url)
-            .await
-            .context("Failed to connect to server")?;
+        let (ws_stream, upgrade) = open_websocket(&self.dialer, &self.server, &self.control_path, None).await?;
+        let server_date = upgrade
+            .headers()
+            .get(tokio_tungstenite::tungstenite::http::header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| ServerDate::parse(date, SystemTime::now()));
 
         debug!("WebSocket connection established");
 
@@ -47,9 +131,22 @@ impl TunnelClient {
         let register_msg = ClientMessage::Register {
             token: self.token.clone(),
             subdomain: self.subdomain.clone(),
+            protocol: self.protocol,
+            remote_port: self.remote_port,
+            service_name: self.service_name.clone(),
+            service_version: self.service_version.clone(),
+            publish_manifest: self.publish_manifest,
+            client_version: Some(BuildInfo::current().to_string()),
+            share_key: self.share_key.clone(),
+            pause_schedule: self.pause_schedule.as_ref().map(ToString::to_string),
+            basic_auth: self.basic_auth.clone(),
+            allow_ips: self.allow_ips.iter().map(ToString::to_string).collect(),
+            mode: self.mode,
+            echo_streams: self.protocol == Protocol::Http,
+            aliases: self.aliases.clone(),
         };
         let json = register_msg.to_json()?;
-        write.send(Message::Text(json.into())).await?;
+        write.send(Message::Text(json)).await?;
         debug!("Sent registration request");
 
         // Wait for response
@@ -66,25 +163,39 @@ impl TunnelClient {
 
         let server_msg = ServerMessage::from_json(&response_text)?;
         match server_msg {
-            ServerMessage::Registered { subdomain, url } => {
+            ServerMessage::Registered { subdomain, url, server_version, limits, motd, alias_urls } => {
                 info!("Tunnel registered!");
                 info!("Subdomain: {}", subdomain);
                 info!("URL: {}", url);
+                if let Some(version) = server_version {
+                    debug!("Server version: {}", version);
+                }
+                // Servers that predate TCP tunnels ignore the protocol and register HTTP
+                if self.protocol == Protocol::Tcp && !url.starts_with("tcp://") {
+                    anyhow::bail!("TCP tunnels unavailable: the server doesn't support them (got {})", url);
+                }
                 Ok(TunnelConnection {
                     write,
                     read,
                     subdomain,
                     url,
                     cert_ready: None, // Will be determined by CertificateStatus message
+                    server_date,
+                    limits,
+                    motd,
+                    alias_urls,
                 })
             }
             ServerMessage::Error { code, message } => {
                 error!("Registration failed: {:?} - {}", code, message);
                 match code {
                     ErrorCode::InvalidToken => anyhow::bail!("Invalid token"),
-                    ErrorCode::SubdomainTaken => anyhow::bail!("Subdomain already taken"),
+                    ErrorCode::SubdomainTaken => anyhow::bail!("Subdomain already taken: {}", message),
                     ErrorCode::SubdomainInvalid => anyhow::bail!("Invalid subdomain: {}", message),
-                    ErrorCode::TunnelLimitReached => anyhow::bail!("Tunnel limit reached"),
+                    ErrorCode::TunnelLimitReached => anyhow::bail!("Tunnel limit reached: {}", message),
+                    ErrorCode::TcpUnavailable => anyhow::bail!("TCP tunnels unavailable: {}", message),
+                    ErrorCode::PortUnavailable => anyhow::bail!("Port unavailable: {}", message),
+                    ErrorCode::InvalidOption => anyhow::bail!("{}", message),
                     ErrorCode::InternalError => anyhow::bail!("Server error: {}", message),
                 }
             }
@@ -108,10 +219,8 @@ impl TunnelClient {
         
         match result {
             Ok(Some(Ok(Message::Text(text)))) => {
-                if let Ok(msg) = ServerMessage::from_json(&text) {
-                    if let ServerMessage::CertificateStatus { ready } = msg {
-                        return Some(ready);
-                    }
+                if let Ok(ServerMessage::CertificateStatus { ready }) = ServerMessage::from_json(&text) {
+                    return Some(ready);
                 }
                 None
             }
@@ -133,13 +242,11 @@ impl TunnelClient {
             loop {
                 match read.next().await {
                     Some(Ok(Message::Text(text))) => {
-                        if let Ok(msg) = ServerMessage::from_json(&text) {
-                            if let ServerMessage::CertificateStatus { ready } = msg {
-                                if ready {
-                                    return true;
-                                }
-                                // Not ready yet, keep waiting
+                        if let Ok(ServerMessage::CertificateStatus { ready }) = ServerMessage::from_json(&text) {
+                            if ready {
+                                return true;
                             }
+                            // Not ready yet, keep waiting
                         }
                     }
                     Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {
@@ -157,6 +264,43 @@ impl TunnelClient {
     }
 }
 
+pub type WsStream = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Open a WebSocket to `path` on `server`, dialing with `dialer`, presenting `bearer`
+/// (a token or share key) if given. Shared by tunnels and connectors.
+pub async fn open_websocket(dialer: &Dialer, server: &str, path: &str, bearer: Option<&str>) -> Result<(WsStream, Response)> {
+    // Convert HTTP(S) URL to WS(S) URL
+    let ws_url = if server.starts_with("https://") {
+        server.replace("https://", "wss://")
+    } else if server.starts_with("http://") {
+        server.replace("http://", "ws://")
+    } else {
+        // Legacy: no scheme provided, default to wss://
+        format!("wss://{}", server)
+    };
+    let ws_url = format!("{}{}", ws_url, path);
+
+    info!("Connecting to {}", ws_url);
+
+    // Dial the TCP connection ourselves (address racing, bind options), then layer TLS/WS on it
+    let url = url::Url::parse(&ws_url).context("Invalid server URL")?;
+    let host = url.host_str().context("Server URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let stream = dialer
+        .connect(host.trim_start_matches('[').trim_end_matches(']'), port)
+        .await
+        .context("Failed to connect to server")?;
+
+    let mut request = ws_url.as_str().into_client_request().context("Invalid server URL")?;
+    if let Some(bearer) = bearer {
+        let value = HeaderValue::from_str(&format!("Bearer {}", bearer)).context("Invalid token")?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    client_async_tls_with_config(request, stream, Some(websocket_config()), None)
+        .await
+        .context("Failed to connect to server")
+}
+
 #[allow(dead_code)]
 pub struct TunnelConnection {
     pub write: futures::stream::SplitSink<
@@ -173,4 +317,52 @@ pub struct TunnelConnection {
     pub subdomain: String,
     pub url: String,
     pub cert_ready: Option<bool>,
+    /// From the upgrade response, for checking the system clock
+    pub server_date: Option<ServerDate>,
+    /// What the server holds visitors to; None from older servers
+    pub limits: Option<TunnelLimits>,
+    /// The server's message of the day
+    pub motd: Option<String>,
+    /// Where visitors also reach the tunnel, on its aliases
+    pub alias_urls: Vec<String>,
+}
+
+/// The limits the server announced, for showing at startup, e.g. `20 requests/s,
+/// 10MB request bodies`; None when there are none to show
+pub fn describe_limits(limits: &TunnelLimits) -> Option<String> {
+    let mut parts = Vec::new();
+    if let Some(rps) = limits.max_rps {
+        parts.push(format!("{} requests/s", rps));
+    }
+    if let Some(concurrent) = limits.max_concurrent {
+        parts.push(format!("{} requests at once", concurrent));
+    }
+    if let Some(bytes) = limits.max_body_bytes {
+        parts.push(format!("{} request bodies", crate::units::format_bytes(bytes)));
+    }
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_limits() {
+        let limits = TunnelLimits {
+            max_rps: Some(20),
+            max_concurrent: Some(8),
+            max_body_bytes: Some(10 * 1024 * 1024),
+        };
+        assert_eq!(
+            describe_limits(&limits).as_deref(),
+            Some("20 requests/s, 8 requests at once, 10MB request bodies")
+        );
+        let limits = TunnelLimits {
+            max_body_bytes: Some(1000),
+            ..TunnelLimits::default()
+        };
+        assert_eq!(describe_limits(&limits).as_deref(), Some("1000B request bodies"));
+        assert_eq!(describe_limits(&TunnelLimits::default()), None);
+    }
 }
@@ -0,0 +1,91 @@
+//! Commands entered at the terminal while a tunnel runs: `r` replays a recent
+//! request (`--replay-buffer`) and `c` continues past `--stop-at`
+
+use colored::Colorize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use super::forwarder::RequestLog;
+use super::replay::{self, ReplayBuffer};
+use super::summary::SessionStats;
+use super::tunnel::LocalService;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    /// Replay the Nth most recent request
+    Replay(usize),
+    /// Forward again after stopping at `--stop-at`
+    Continue,
+}
+
+impl Command {
+    /// None for lines that aren't commands, which are ignored
+    fn parse(line: &str) -> Option<Result<Self, &'static str>> {
+        let mut words = line.split_whitespace();
+        match words.next()? {
+            "r" => Some(match words.next().map(str::parse::<usize>) {
+                None => Ok(Command::Replay(1)),
+                Some(Ok(nth)) if nth > 0 => Ok(Command::Replay(nth)),
+                Some(_) => Err("Usage: r, or r N to replay the Nth most recent request"),
+            }),
+            "c" => Some(Ok(Command::Continue)),
+            _ => None,
+        }
+    }
+}
+
+/// What `r` needs to replay requests
+pub struct Replayer {
+    pub buffer: Arc<ReplayBuffer>,
+    pub local: LocalService,
+    pub timeout: Duration,
+    pub log: RequestLog,
+}
+
+/// Run commands as they're entered. Returns when stdin closes.
+pub async fn read(replayer: Option<Replayer>, stats: Arc<SessionStats>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match Command::parse(&line) {
+            None => {}
+            Some(Err(usage)) => eprintln!("{} {}", "!".yellow(), usage),
+            Some(Ok(Command::Replay(nth))) => {
+                let Some(replayer) = &replayer else {
+                    eprintln!("{} Nothing to replay without --replay-buffer", "!".yellow());
+                    continue;
+                };
+                let Some(request) = replayer.buffer.get(nth) else {
+                    eprintln!("{} No request to replay", "!".yellow());
+                    continue;
+                };
+                let request_line = request.split(|&byte| byte == b'\r').next().unwrap_or_default();
+                println!("{} Replaying {}", "↻".cyan(), String::from_utf8_lossy(request_line));
+                // Asked for, so shown even with --quiet
+                let mut log = replayer.log;
+                log.quiet = false;
+                replay::replay(&request, &replayer.local, replayer.timeout, log).await;
+            }
+            Some(Ok(Command::Continue)) => match stats.resume() {
+                true => println!("{} Forwarding again; --stop-at no longer applies this session", "✓".green()),
+                false => eprintln!("{} The tunnel isn't stopped", "!".yellow()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("r"), Some(Ok(Command::Replay(1))));
+        assert_eq!(Command::parse("  r 3 "), Some(Ok(Command::Replay(3))));
+        assert!(matches!(Command::parse("r 0"), Some(Err(_))));
+        assert!(matches!(Command::parse("r last"), Some(Err(_))));
+        assert_eq!(Command::parse("c"), Some(Ok(Command::Continue)));
+        assert_eq!(Command::parse(""), None);
+        assert_eq!(Command::parse("hello"), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! `loophole connect`: the visitor's end of a TCP tunnel, for networks that only let
+//! HTTP(S) out. It listens on a local port and carries each connection to the tunnel
+//! over a WebSocket of its own, on the same port as the tunnels' control connections.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{debug, Level};
+use tracing_subscriber::FmtSubscriber;
+
+use super::client::open_websocket;
+use super::{credentials, Dialer};
+use crate::client_config::ClientConfig;
+use crate::proto::compat::ClientCompat;
+use crate::proto::transport::CONNECT_PATH;
+use crate::units;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn connect(
+    server: Option<String>,
+    token: Option<String>,
+    key: Option<String>,
+    subdomain: String,
+    local_port: u16,
+    bind: IpAddr,
+    dialer: Dialer,
+    log_level: Level,
+) -> Result<()> {
+    // A share key stands in for the token, so only the server needs to be known
+    let (server, key) = match (key, server) {
+        (Some(key), Some(server)) => (server, key),
+        (Some(key), None) => {
+            let config = ClientConfig::load()?
+                .ok_or_else(|| anyhow::anyhow!("No server to connect to. Provide --server, or run 'loophole login' first."))?;
+            (config.server, key)
+        }
+        (None, server) => credentials(server, token)?,
+    };
+    dialer.validate()?;
+
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let listener = TcpListener::bind((bind, local_port))
+        .await
+        .with_context(|| format!("Failed to listen on {}", SocketAddr::new(bind, local_port)))?;
+    println!(
+        "{} Forwarding {} to TCP tunnel {} on {}",
+        "✓".green(),
+        listener.local_addr()?.to_string().cyan(),
+        subdomain.cyan(),
+        server.green()
+    );
+    println!();
+
+    Arc::new(Connector::new(server, subdomain, key, dialer)).serve(listener).await
+}
+
+/// Where connections are carried to, and how to get in
+pub struct Connector {
+    server: String,
+    subdomain: String,
+    /// The tunnel's token or share key
+    key: String,
+    dialer: Dialer,
+}
+
+impl Connector {
+    pub fn new(server: String, subdomain: String, key: String, dialer: Dialer) -> Self {
+        Self { server, subdomain, key, dialer }
+    }
+
+    /// Carry every connection to `listener` to the tunnel, until accepting fails
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (local, peer) = listener.accept().await.context("Failed to accept a connection")?;
+            tokio::spawn(self.clone().carry(local, peer));
+        }
+    }
+
+    async fn carry(self: Arc<Self>, mut local: TcpStream, peer: SocketAddr) {
+        let path = format!("{}/{}", CONNECT_PATH, self.subdomain);
+        let ws = match open_websocket(&self.dialer, &self.server, &path, Some(&self.key)).await {
+            Ok((ws, _)) => ws,
+            Err(e) => {
+                eprintln!("{} Connection from {} failed: {}", "✗".red(), peer, self.explain(&e));
+                return;
+            }
+        };
+        println!("{} Connection from {}", "→".cyan(), peer);
+
+        let mut remote = ClientCompat::new(ws).compat();
+        match tokio::io::copy_bidirectional(&mut local, &mut remote).await {
+            Ok((sent, received)) => println!(
+                "{} Connection from {} closed ({} sent, {} received)",
+                "←".dimmed(),
+                peer,
+                units::format_bytes(sent),
+                units::format_bytes(received)
+            ),
+            Err(e) => debug!("Connection from {} ended: {}", peer, e),
+        }
+    }
+
+    /// Say what to do about the refusals the server explains with a status
+    fn explain(&self, e: &anyhow::Error) -> String {
+        let status = e.chain().find_map(|cause| match cause.downcast_ref::<tungstenite::Error>() {
+            Some(tungstenite::Error::Http(response)) => Some(response.status().as_u16()),
+            _ => None,
+        });
+        match status {
+            Some(401) => format!(
+                "Not authorized, or there's no TCP tunnel '{}': use the tunnel's token, or the share key printed when it was exposed with --share",
+                self.subdomain
+            ),
+            // Servers without connectors say 404 for every path they don't know
+            Some(404) => format!(
+                "No TCP tunnel '{}' on {} (or the server doesn't support loophole connect)",
+                self.subdomain, self.server
+            ),
+            _ => format!("{:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ws::{Message, WebSocketUpgrade};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A server whose only tunnel, "mydb", echoes in upper case for the key "sk_right"
+    async fn mock_server() -> String {
+        let router = Router::new().route(
+            &format!("{}/mydb", CONNECT_PATH),
+            get(|headers: HeaderMap, ws: WebSocketUpgrade| async move {
+                if headers.get("authorization").and_then(|h| h.to_str().ok()) != Some("Bearer sk_right") {
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                ws.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(Message::Binary(data))) = socket.next().await {
+                        let _ = socket.send(Message::Binary(data.to_ascii_uppercase())).await;
+                    }
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn start_connector(server: &str, subdomain: &str, key: &str) -> (Arc<Connector>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connector = Arc::new(Connector::new(server.to_string(), subdomain.to_string(), key.to_string(), Dialer::default()));
+        tokio::spawn(connector.clone().serve(listener));
+        (connector, addr)
+    }
+
+    #[tokio::test]
+    async fn test_carries_connections_over_websocket() {
+        let server = mock_server().await;
+        let (_, addr) = start_connector(&server, "mydb", "sk_right").await;
+
+        // Two connections at once, each with a WebSocket of its own
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"select 1;").await.unwrap();
+        second.write_all(b"select 2;").await.unwrap();
+        let mut reply = [0u8; 9];
+        first.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"SELECT 1;");
+        second.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"SELECT 2;");
+    }
+
+    #[tokio::test]
+    async fn test_refusals_are_explained() {
+        let server = mock_server().await;
+        let (connector, _) = start_connector(&server, "mydb", "sk_wrong").await;
+        let path = format!("{}/mydb", CONNECT_PATH);
+        let e = open_websocket(&connector.dialer, &server, &path, Some("sk_wrong")).await.err().unwrap();
+        assert!(connector.explain(&e).starts_with("Not authorized"), "{}", connector.explain(&e));
+
+        let (connector, _) = start_connector(&server, "other", "sk_right").await;
+        let path = format!("{}/other", CONNECT_PATH);
+        let e = open_websocket(&connector.dialer, &server, &path, Some("sk_right")).await.err().unwrap();
+        assert!(connector.explain(&e).starts_with("No TCP tunnel 'other'"), "{}", connector.explain(&e));
+
+        // The connection is closed, rather than left hanging
+        let (_, addr) = start_connector(&server, "other", "sk_right").await;
+        let mut visitor = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(visitor.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::{Context, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+/// Delay before starting the next connection attempt while earlier ones are pending
+/// (RFC 8305 recommends 250ms)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens the TCP connection to the tunnel server. Built once from the command line
+/// and reused for every reconnect.
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    /// Local address to bind outbound connections to (`--bind-interface`)
+    pub bind_ip: Option<IpAddr>,
+    /// Network device to bind outbound connections to (`--bind-device`, Linux only)
+    pub bind_device: Option<String>,
+}
+
+impl Dialer {
+    /// Check the bind options can be applied, so misconfiguration fails at startup
+    /// rather than on every reconnect attempt
+    pub fn validate(&self) -> Result<()> {
+        match (self.bind_ip, &self.bind_device) {
+            (Some(ip), _) => self
+                .build_socket(SocketAddr::new(ip, 0))
+                .map(drop)
+                .with_context(|| format!("Cannot bind to --bind-interface {}", ip)),
+            (None, Some(_)) => self
+                .build_socket(SocketAddr::from(([0, 0, 0, 0], 0)))
+                .map(drop),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Resolve `host:port` and connect, racing the resolved addresses
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let resolved: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("Failed to resolve {}", host))?
+            .collect();
+        if resolved.is_empty() {
+            anyhow::bail!("No addresses found for {}", host);
+        }
+        let addrs = interleave_families(self.select_addrs(resolved)?);
+        self.race(addrs, ATTEMPT_DELAY).await
+    }
+
+    /// Happy Eyeballs (RFC 8305): start attempts in order, a new one every `delay`
+    /// or as soon as the previous one fails, and keep whichever connects first.
+    /// A broken IPv6 path then costs `delay` instead of a full connect timeout.
+    async fn race(&self, addrs: Vec<SocketAddr>, delay: Duration) -> Result<TcpStream> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => attempts.push(self.attempt(addr)),
+                    None => break,
+                }
+            }
+
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => match result {
+                    Ok(stream) => {
+                        debug!("Connected to {}", addr);
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        debug!("Failed to connect to {}: {:#}", addr, e);
+                        last_err = Some(e);
+                        // Fall through to the next address immediately
+                        if let Some(next) = pending.next() {
+                            attempts.push(self.attempt(next));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(delay), if pending.len() > 0 => {
+                    if let Some(next) = pending.next() {
+                        attempts.push(self.attempt(next));
+                    }
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No addresses to connect to")))
+    }
+
+    async fn attempt(&self, addr: SocketAddr) -> (SocketAddr, Result<TcpStream>) {
+        debug!("Dialing {}", addr);
+        (addr, self.connect_addr(addr).await)
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let socket = self.build_socket(addr)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr))
+    }
+
+    /// Keep only addresses reachable from the bound address family
+    fn select_addrs(&self, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>> {
+        let Some(bind_ip) = self.bind_ip else {
+            return Ok(addrs);
+        };
+        let selected: Vec<SocketAddr> = addrs
+            .iter()
+            .copied()
+            .filter(|a| a.is_ipv4() == bind_ip.is_ipv4())
+            .collect();
+        if selected.is_empty() {
+            anyhow::bail!(
+                "Server has no {} address reachable from --bind-interface {} (resolved: {})",
+                if bind_ip.is_ipv4() { "IPv4" } else { "IPv6" },
+                bind_ip,
+                addrs
+                    .iter()
+                    .map(|a| a.ip().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(selected)
+    }
+
+    /// Create a non-blocking TCP socket for `addr` with the bind options applied
+    fn build_socket(&self, addr: SocketAddr) -> Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+            .context("Failed to create socket")?;
+        socket.set_nonblocking(true)?;
+
+        if let Some(ref device) = self.bind_device {
+            bind_device(&socket, device)?;
+        }
+
+        if let Some(ip) = self.bind_ip {
+            socket
+                .bind(&SocketAddr::new(ip, 0).into())
+                .with_context(|| format!("Failed to bind to {}", ip))?;
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Alternate address families, starting with whichever the resolver listed first
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6);
+
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+    result
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> Result<()> {
+    socket
+        .bind_device(Some(device.as_bytes()))
+        .with_context(|| format!("Failed to bind to device {} (requires CAP_NET_RAW or root)", device))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _device: &str) -> Result<()> {
+    anyhow::bail!("--bind-device is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_select_addrs_by_bind_family() {
+        let resolved = vec![addr("[::1]:443"), addr("127.0.0.1:443")];
+
+        let any = Dialer::default();
+        assert_eq!(any.select_addrs(resolved.clone()).unwrap(), resolved);
+
+        let v4 = Dialer {
+            bind_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(v4.select_addrs(resolved.clone()).unwrap(), vec![addr("127.0.0.1:443")]);
+
+        let v6 = Dialer {
+            bind_ip: Some("::1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(v6.select_addrs(resolved).unwrap(), vec![addr("[::1]:443")]);
+
+        let err = v6.select_addrs(vec![addr("127.0.0.1:443")]).unwrap_err();
+        assert!(err.to_string().contains("no IPv6 address"), "{}", err);
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs = vec![
+            addr("[2001:db8::1]:443"),
+            addr("[2001:db8::2]:443"),
+            addr("[2001:db8::3]:443"),
+            addr("192.0.2.1:443"),
+        ];
+        assert_eq!(
+            interleave_families(addrs),
+            vec![
+                addr("[2001:db8::1]:443"),
+                addr("192.0.2.1:443"),
+                addr("[2001:db8::2]:443"),
+                addr("[2001:db8::3]:443"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_race_falls_back_past_unroutable_ipv6() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // 100::/64 is the discard-only prefix (RFC 6666): the attempt either fails or hangs
+        let addrs = vec![addr(&format!("[100::1]:{}", port)), addr(&format!("127.0.0.1:{}", port))];
+
+        let started = std::time::Instant::now();
+        let stream = Dialer::default()
+            .race(addrs, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_race_reports_last_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+
+        let err = Dialer::default().race(vec![closed], ATTEMPT_DELAY).await.unwrap_err();
+        assert!(err.to_string().contains(&closed.to_string()), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_connect_binds_local_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let options = Dialer {
+            bind_ip: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        options.validate().unwrap();
+        let stream = options.connect("127.0.0.1", port).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), options.bind_ip.unwrap());
+    }
+
+    #[test]
+    fn test_validate_rejects_foreign_address() {
+        // TEST-NET-1 is never assigned to a local interface
+        let options = Dialer {
+            bind_ip: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let err = options.validate().unwrap_err();
+        assert!(format!("{:#}", err).contains("--bind-interface 192.0.2.1"), "{:#}", err);
+    }
+}
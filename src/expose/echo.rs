@@ -0,0 +1,112 @@
+//! Answering the server's round-trip probes. They arrive on an HTTP tunnel's streams
+//! like requests do, told apart by their first byte ([`ECHO_STREAM`]), and everything
+//! after it is echoed straight back.
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::proto::transport::ECHO_STREAM;
+
+/// A stream with its first byte already read, which is given back before the rest
+pub struct Peeked<S> {
+    first: Option<u8>,
+    inner: S,
+}
+
+impl<S> Peeked<S> {
+    /// `inner` with nothing read from it
+    pub fn new(inner: S) -> Self {
+        Self { first: None, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Peeked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if let (Some(first), false) = (self.first, buf.is_empty()) {
+            buf[0] = first;
+            self.first = None;
+            return Poll::Ready(Ok(1));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Peeked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Read the first byte of a stream from the server. An echo stream is answered in a
+/// task of its own, as it lasts as long as the tunnel, and None returned; anything
+/// else comes back with its first byte in place. None too if the stream ends first.
+pub async fn dispatch<S>(mut stream: S) -> Option<Peeked<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut first = [0u8; 1];
+    match stream.read(&mut first).await {
+        Ok(1) if first[0] == ECHO_STREAM => {
+            tokio::spawn(echo(stream));
+            None
+        }
+        Ok(1) => Some(Peeked {
+            first: Some(first[0]),
+            inner: stream,
+        }),
+        _ => None,
+    }
+}
+
+/// Send back whatever arrives, as soon as it does, until the server closes the stream
+async fn echo<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+    let mut buf = [0u8; 64];
+    while let Ok(n) = stream.read(&mut buf).await {
+        if n == 0 || stream.write_all(&buf[..n]).await.is_err() || stream.flush().await.is_err() {
+            break;
+        }
+    }
+    let _ = stream.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn test_requests_keep_their_first_byte() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = server.compat();
+        server.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        server.close().await.unwrap();
+
+        let mut stream = dispatch(client.compat()).await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_echo_streams_answered() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = server.compat();
+        server.write_all(&[ECHO_STREAM]).await.unwrap();
+        assert!(dispatch(client.compat()).await.is_none());
+
+        server.write_all(&7u64.to_be_bytes()).await.unwrap();
+        let mut echoed = [0u8; 8];
+        server.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(u64::from_be_bytes(echoed), 7);
+    }
+}
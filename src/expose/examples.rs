@@ -0,0 +1,111 @@
+use colored::Colorize;
+
+/// Webhook providers `--print-examples` has configuration hints for. To add one, add a
+/// variant and its templates in `Provider::templates`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Provider {
+    Stripe,
+    Github,
+}
+
+/// An example with `{url}` standing in for the tunnel URL
+struct Template {
+    title: &'static str,
+    lines: &'static [&'static str],
+}
+
+const GENERIC: &[Template] = &[
+    Template {
+        title: "Send a request",
+        lines: &["curl -i {url}/"],
+    },
+    Template {
+        title: "POST some JSON",
+        lines: &[r#"curl -i -X POST {url}/ -H 'Content-Type: application/json' -d '{"hello":"world"}'"#],
+    },
+];
+
+impl Provider {
+    fn templates(self) -> &'static [Template] {
+        match self {
+            Provider::Stripe => &[Template {
+                title: "Stripe webhooks",
+                lines: &[
+                    "Dashboard → Developers → Webhooks → Add endpoint",
+                    "Endpoint URL: {url}/webhooks/stripe",
+                    "Then send a test event: stripe trigger payment_intent.succeeded",
+                ],
+            }],
+            Provider::Github => &[Template {
+                title: "GitHub webhooks",
+                lines: &[
+                    "Repository → Settings → Webhooks → Add webhook",
+                    "Payload URL: {url}/webhooks/github",
+                    "Content type: application/json",
+                    "Then use Recent Deliveries → Redeliver to resend an event",
+                ],
+            }],
+        }
+    }
+}
+
+/// A rendered example
+#[derive(Debug, PartialEq, Eq)]
+pub struct Example {
+    pub title: &'static str,
+    pub lines: Vec<String>,
+}
+
+/// The generic examples followed by each selected provider's, for `url`
+pub fn render(url: &str, providers: &[Provider]) -> Vec<Example> {
+    let url = url.trim_end_matches('/');
+    GENERIC
+        .iter()
+        .chain(providers.iter().flat_map(|p| p.templates()))
+        .map(|template| Example {
+            title: template.title,
+            lines: template.lines.iter().map(|line| line.replace("{url}", url)).collect(),
+        })
+        .collect()
+}
+
+pub fn print(url: &str, providers: &[Provider]) {
+    println!("{}", "Examples:".bold());
+    for example in render(url, providers) {
+        println!("  {}", example.title.dimmed());
+        for line in &example.lines {
+            println!("    {}", line);
+        }
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_generic() {
+        let examples = render("https://myapp.tunnel.example.com/", &[]);
+        assert_eq!(examples.len(), GENERIC.len());
+        assert_eq!(examples[0].lines, vec!["curl -i https://myapp.tunnel.example.com/"]);
+        assert_eq!(
+            examples[1].lines,
+            vec![r#"curl -i -X POST https://myapp.tunnel.example.com/ -H 'Content-Type: application/json' -d '{"hello":"world"}'"#]
+        );
+    }
+
+    #[test]
+    fn test_render_providers_in_order() {
+        let examples = render("http://myapp.localhost:8080", &[Provider::Github, Provider::Stripe]);
+        let titles: Vec<_> = examples.iter().map(|e| e.title).collect();
+        assert_eq!(titles, vec!["Send a request", "POST some JSON", "GitHub webhooks", "Stripe webhooks"]);
+        assert!(examples[2]
+            .lines
+            .contains(&"Payload URL: http://myapp.localhost:8080/webhooks/github".to_string()));
+        assert!(examples
+            .iter()
+            .flat_map(|e| &e.lines)
+            .all(|line| !line.contains("{url}")));
+    }
+}
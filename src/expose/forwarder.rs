@@ -1,13 +1,163 @@
 use colored::Colorize;
 use futures::io::{AsyncReadExt as FuturesAsyncReadExt, AsyncWriteExt as FuturesAsyncWriteExt};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::debug;
 
+use super::inspector::Inspector;
+use super::local_tls::LocalTls;
+use super::replay::{ReplayBuffer, RequestTap};
+use crate::capture::CapturePolicy;
+use crate::http_head::{self, parse_request, parse_response, HeadError, HeadScanner};
+use crate::units::format_size;
+
+const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// Extra fields for request log lines (`--log-detail`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogDetail {
+    /// Response body size
+    Size,
+    /// Response Content-Type
+    Type,
+}
+
+/// What to print for each forwarded request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestLog {
+    pub quiet: bool,
+    size: bool,
+    content_type: bool,
+    /// Starts every line, to tell tunnels apart when several share the terminal
+    prefix: &'static str,
+}
+
+impl RequestLog {
+    pub fn new(quiet: bool, details: &[LogDetail]) -> Self {
+        Self {
+            quiet,
+            size: details.contains(&LogDetail::Size),
+            content_type: details.contains(&LogDetail::Type),
+            prefix: "",
+        }
+    }
+
+    pub fn prefixed(self, prefix: &'static str) -> Self {
+        Self { prefix, ..self }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        self.prefix
+    }
+
+    /// The requested details, e.g. `1.5KB text/html`
+    fn details(&self, body_bytes: usize, content_type: Option<&str>) -> Option<String> {
+        let mut parts = Vec::new();
+        if self.size {
+            parts.push(format_size(body_bytes as u64));
+        }
+        if self.content_type {
+            // Parameters such as charset are rarely what you're looking for
+            let media_type = content_type.and_then(|t| t.split(';').next()).map(str::trim);
+            parts.push(media_type.filter(|t| !t.is_empty()).unwrap_or("-").to_string());
+        }
+        (!parts.is_empty()).then(|| parts.join(" "))
+    }
+
+    /// Print the line for a completed request, e.g. `← GET /index.html (200) 3ms`
+    pub fn request(&self, method: &str, path: &str, status: u16, elapsed: Duration, body_bytes: usize, content_type: Option<&str>) {
+        if self.quiet {
+            return;
+        }
+        let status_display = format!("{}", status);
+        let status_colored = match status {
+            200..=299 => status_display.green(),
+            300..=399 => status_display.cyan(),
+            400..=499 => status_display.yellow(),
+            _ => status_display.red(),
+        };
+
+        let details = self
+            .details(body_bytes, content_type)
+            .map(|details| format!(" {}", details.dimmed()))
+            .unwrap_or_default();
+        println!(
+            "{}{} {} {} ({}) {}{}",
+            self.prefix,
+            "←".cyan(),
+            method.yellow(),
+            path,
+            status_colored,
+            format!("{}ms", elapsed.as_millis()).dimmed(),
+            details
+        );
+    }
+}
+
+/// What to keep of forwarded requests, besides their log lines
+#[derive(Clone, Default)]
+pub struct Recording {
+    /// Exchanges for `--inspect`
+    pub inspector: Option<Arc<Inspector>>,
+    /// Raw requests for `--replay-buffer`
+    pub replay: Option<Arc<ReplayBuffer>>,
+    /// What the inspector and the replay buffer may keep
+    pub policy: CapturePolicy,
+}
+
+/// A connection to the local service, over TLS or not
+trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalStream for T {}
+
+/// Connect to the local service, returning the 502 body to send if that fails
+async fn connect_local(local_addr: SocketAddr, tls: Option<&LocalTls>, timeout: Duration) -> Result<Box<dyn LocalStream>, String> {
+    let stream = TcpStream::connect(local_addr).await.map_err(|e| {
+        debug!("Failed to connect to local server: {}", e);
+        "Cannot connect to backend".to_string()
+    })?;
+    let Some(tls) = tls else {
+        return Ok(Box::new(stream));
+    };
+    match tokio::time::timeout(timeout, tls.connect(stream)).await {
+        Ok(Ok(stream)) => Ok(Box::new(stream)),
+        Ok(Err(e)) => Err(format!("TLS handshake with backend failed: {}", e)),
+        Err(_) => Err("TLS handshake with backend timed out".to_string()),
+    }
+}
+
+fn error_response(status: &str, message: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}", status, message.len(), message).into_bytes()
+}
+
+fn bad_gateway(message: &str) -> Vec<u8> {
+    error_response("502 Bad Gateway", message)
+}
+
+/// The response to a request head that can't be read, rather than a bare 502 from
+/// the server when the stream closes
+fn unreadable_request(e: HeadError) -> Vec<u8> {
+    let status = match e {
+        HeadError::LineTooLong => "414 URI Too Long",
+        HeadError::TooLarge | HeadError::TooManyHeaders => "431 Request Header Fields Too Large",
+        HeadError::Invalid => "400 Bad Request",
+    };
+    error_response(status, &e.to_string())
+}
+
 /// Handle a tunnel stream by connecting to local server and proxying bidirectionally
-pub async fn handle_tunnel_stream<S>(mut tunnel_stream: S, local_addr: SocketAddr, local_host: Option<String>, _timeout: Duration, quiet: bool)
+pub async fn handle_tunnel_stream<S>(
+    mut tunnel_stream: S,
+    local_addr: SocketAddr,
+    local_host: Option<String>,
+    local_tls: Option<LocalTls>,
+    recording: Recording,
+    timeout: Duration,
+    log: RequestLog,
+)
 where
     S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin + Send + 'static,
 {
@@ -16,9 +166,9 @@ where
     // Read request headers from tunnel to get method/path for logging
     let mut header_buf = Vec::new();
     let mut buf = [0u8; 4096];
-    let header_end;
-    
-    loop {
+    // The request line has its own limit, so a long URL doesn't crowd out the headers
+    let mut scanner = HeadScanner::for_requests();
+    let head = loop {
         match tunnel_stream.read(&mut buf).await {
             Ok(0) => {
                 debug!("Tunnel stream closed before headers");
@@ -26,13 +176,10 @@ where
             }
             Ok(n) => {
                 header_buf.extend_from_slice(&buf[..n]);
-                if let Some(pos) = find_header_end(&header_buf) {
-                    header_end = pos;
-                    break;
-                }
-                if header_buf.len() > 65536 {
-                    eprintln!("{} Request headers too large", "✗".red());
-                    return;
+                match scanner.scan(&header_buf) {
+                    Ok(Some(len)) => break parse_request(&header_buf[..len]).map(|request| (len, request)),
+                    Ok(None) => {}
+                    Err(e) => break Err(e),
                 }
             }
             Err(e) => {
@@ -40,57 +187,82 @@ where
                 return;
             }
         }
-    }
+    };
+    let (head_len, request) = match head {
+        Ok(head) => head,
+        Err(e) => {
+            eprintln!("{}{} Invalid request headers: {}", log.prefix, "✗".red(), e);
+            let _ = tunnel_stream.write_all(&unreadable_request(e)).await;
+            let _ = tunnel_stream.close().await;
+            return;
+        }
+    };
+
+    // Capture the request as the visitor sent it, before any Host rewrite
+    let mut capture = recording.inspector.as_ref().map(|inspector| inspector.begin(&request));
+    let mut request_tap = capture.as_ref().map(|capture| {
+        let mut tap = capture.request_body();
+        tap.feed(&header_buf[head_len..]);
+        tap
+    });
+    let mut raw_request = recording
+        .replay
+        .as_ref()
+        .map(|_| RequestTap::new(&header_buf, &request, &recording.policy));
 
-    // Parse request line for logging
-    let request_line = std::str::from_utf8(&header_buf[..header_end])
-        .ok()
-        .and_then(|s| s.lines().next())
-        .map(|s| s.to_string());
-    
     // Optionally rewrite Host header
     let request_data = if let Some(ref host) = local_host {
-        rewrite_host_header(&header_buf, host)
+        let mut rewritten = request.clone();
+        rewritten.headers.set("Host", host);
+        let mut data = rewritten.to_bytes();
+        data.extend_from_slice(&header_buf[head_len..]);
+        data
     } else {
         header_buf
     };
 
     // Connect to local server
-    let local_stream = match TcpStream::connect(local_addr).await {
+    let local_stream = match connect_local(local_addr, local_tls.as_ref(), timeout).await {
         Ok(s) => s,
-        Err(e) => {
+        Err(message) => {
             let elapsed = start_time.elapsed();
-            if !quiet {
-                if let Some(ref req_line) = request_line {
-                    let parts: Vec<&str> = req_line.split_whitespace().collect();
-                    let method = parts.first().unwrap_or(&"");
-                    let path = parts.get(1).unwrap_or(&"");
-                    eprintln!(
-                        "{} {} {} {} {}",
-                        "←".cyan(),
-                        method.yellow(),
-                        path,
-                        "502 Bad Gateway".red(),
-                        format!("{}ms", elapsed.as_millis()).dimmed()
-                    );
-                }
+            if !log.quiet {
+                eprintln!(
+                    "{}{} {} {} {} {} {}",
+                    log.prefix,
+                    "←".cyan(),
+                    request.method.yellow(),
+                    request.target,
+                    "502 Bad Gateway".red(),
+                    format!("{}ms", elapsed.as_millis()).dimmed(),
+                    message.dimmed()
+                );
+            }
+            if let (Some(inspector), Some(mut capture)) = (&recording.inspector, capture) {
+                let response_tap = capture.error_response(502, &message);
+                inspector.record(capture, request_tap, Some(response_tap));
+            }
+            // Kept if it arrived whole, so it can be replayed once the service is back
+            if let (Some(buffer), Some(raw_request)) = (&recording.replay, raw_request) {
+                buffer.push(raw_request);
             }
             // Send error response back through tunnel
-            let error_response = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 26\r\n\r\nCannot connect to backend";
-            let _ = tunnel_stream.write_all(error_response).await;
+            let _ = tunnel_stream.write_all(&bad_gateway(&message)).await;
             let _ = tunnel_stream.close().await;
-            debug!("Failed to connect to local server: {}", e);
             return;
         }
     };
 
-    let (mut local_read, mut local_write) = local_stream.into_split();
+    let (mut local_read, mut local_write) = tokio::io::split(local_stream);
     
     // Write buffered request data to local server
     if let Err(e) = local_write.write_all(&request_data).await {
         debug!("Failed to write to local server: {}", e);
-        let error_response = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 24\r\n\r\nFailed to send request";
-        let _ = tunnel_stream.write_all(error_response).await;
+        if let (Some(inspector), Some(mut capture)) = (&recording.inspector, capture) {
+            let response_tap = capture.error_response(502, "Failed to send request");
+            inspector.record(capture, request_tap, Some(response_tap));
+        }
+        let _ = tunnel_stream.write_all(&bad_gateway("Failed to send request")).await;
         let _ = tunnel_stream.close().await;
         return;
     }
@@ -98,13 +270,21 @@ where
     // Split the tunnel stream into read and write halves
     let (mut tunnel_read, mut tunnel_write) = tunnel_stream.split();
 
-    // Bidirectional copy between tunnel and local server
+    // Bidirectional copy between tunnel and local server. The local connection stays open
+    // until the server closes the stream, which for an upgraded request (WebSocket) is
+    // when the visitor disconnects.
     let tunnel_to_local = async move {
         let mut buf = [0u8; 8192];
         loop {
             match tunnel_read.read(&mut buf).await {
                 Ok(0) => break,
                 Ok(n) => {
+                    if let Some(tap) = request_tap.as_mut() {
+                        tap.feed(&buf[..n]);
+                    }
+                    if let Some(raw_request) = raw_request.as_mut() {
+                        raw_request.feed(&buf[..n]);
+                    }
                     if local_write.write_all(&buf[..n]).await.is_err() {
                         break;
                     }
@@ -113,100 +293,638 @@ where
             }
         }
         let _ = local_write.shutdown().await;
+        (request_tap, raw_request)
     };
 
+    let is_head = request.method == "HEAD";
+
     let local_to_tunnel = async move {
         let mut buf = [0u8; 8192];
-        let mut first_read = true;
         let mut status_code: Option<u16> = None;
+        let mut content_type: Option<String> = None;
         let mut total_bytes = 0usize;
-        
-        loop {
+        let mut head_len = 0;
+        let mut response_tap = None;
+
+        // Read the response head first: the body must cross the tunnel with explicit
+        // framing, so close-delimited bodies are re-chunked
+        let mut head = Vec::new();
+        let mut rechunk = false;
+        // Nothing follows a HEAD response, a 204 or a 304, whatever its Content-Length
+        // says, and a keep-alive local server won't close the connection after it
+        let mut bodiless = false;
+        let mut scanner = HeadScanner::default();
+        'head: loop {
             match local_read.read(&mut buf).await {
-                Ok(0) => break,
+                Ok(0) | Err(_) => break,
                 Ok(n) => {
                     total_bytes += n;
-                    
-                    // Parse status from first chunk
-                    if first_read {
-                        first_read = false;
-                        if let Ok(s) = std::str::from_utf8(&buf[..n.min(100)]) {
-                            if let Some(line) = s.lines().next() {
-                                let parts: Vec<&str> = line.split_whitespace().collect();
-                                status_code = parts.get(1).and_then(|s| s.parse().ok());
+                    head.extend_from_slice(&buf[..n]);
+                    loop {
+                        let len = match scanner.scan(&head) {
+                            Ok(Some(len)) => len,
+                            Ok(None) => break,
+                            // Not something we can frame; the server will reject it
+                            Err(_) => break 'head,
+                        };
+                        let response = ResponseHead::parse(&head[..len]);
+                        // 100 Continue and the like pass through straight away, as the
+                        // visitor may be waiting for one before sending the body
+                        if response.is_interim() {
+                            let interim: Vec<u8> = head.drain(..len).collect();
+                            head_len += interim.len();
+                            if tunnel_write.write_all(&interim).await.is_err() {
+                                break 'head;
                             }
+                            continue;
+                        }
+                        head_len += len;
+                        status_code = response.status();
+                        content_type = response.content_type().map(str::to_string);
+                        bodiless = response.is_bodiless(is_head);
+                        if let Some(capture) = capture.as_mut() {
+                            let mut tap = capture.response(response.0.as_ref());
+                            tap.feed(&head[len..]);
+                            response_tap = Some(tap);
+                        }
+                        if response.is_close_delimited(is_head) {
+                            rechunk = true;
+                            let body = head.split_off(len);
+                            // Before the blank line, after the last field's line ending
+                            head.truncate(len - if head.ends_with(b"\r\n") { 2 } else { 1 });
+                            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+                            head.extend_from_slice(&encode_chunk(&body));
+                        } else if bodiless {
+                            head.truncate(len);
                         }
+                        break 'head;
                     }
-                    
-                    if tunnel_write.write_all(&buf[..n]).await.is_err() {
-                        break;
+                }
+            }
+        }
+        let mut open = tunnel_write.write_all(&head).await.is_ok() && !head.is_empty() && !bodiless;
+
+        while open {
+            match local_read.read(&mut buf).await {
+                Ok(0) => {
+                    if rechunk {
+                        let _ = tunnel_write.write_all(LAST_CHUNK).await;
+                    }
+                    break;
+                }
+                Ok(n) => {
+                    total_bytes += n;
+                    if let Some(tap) = response_tap.as_mut() {
+                        tap.feed(&buf[..n]);
                     }
+                    let written = if rechunk {
+                        tunnel_write.write_all(&encode_chunk(&buf[..n])).await
+                    } else {
+                        tunnel_write.write_all(&buf[..n]).await
+                    };
+                    open = written.is_ok();
                 }
+                // Leave a re-chunked body unterminated so the server sees it was cut short
                 Err(_) => break,
             }
         }
         // Flush to ensure all data is sent before we finish
         let _ = tunnel_write.flush().await;
         let _ = tunnel_write.close().await;
-        
-        (status_code, total_bytes)
+
+        (status_code, content_type, total_bytes.saturating_sub(head_len), capture, response_tap)
     };
 
-    let (_, (status_code, _total_bytes)) = tokio::join!(tunnel_to_local, local_to_tunnel);
+    let ((request_tap, raw_request), (status_code, content_type, body_bytes, capture, response_tap)) =
+        tokio::join!(tunnel_to_local, local_to_tunnel);
+    if let (Some(inspector), Some(capture)) = (&recording.inspector, capture) {
+        inspector.record(capture, request_tap, response_tap);
+    }
+    if let (Some(buffer), Some(raw_request)) = (&recording.replay, raw_request) {
+        buffer.push(raw_request);
+    }
     
     // Log the completed request
-    let elapsed = start_time.elapsed();
-    if !quiet {
-        if let Some(ref req_line) = request_line {
-            let parts: Vec<&str> = req_line.split_whitespace().collect();
-            let method = parts.first().unwrap_or(&"");
-            let path = parts.get(1).unwrap_or(&"");
-            
-            let status = status_code.unwrap_or(0);
-            let status_display = format!("{}", status);
-            let status_colored = match status {
-                200..=299 => status_display.green(),
-                300..=399 => status_display.cyan(),
-                400..=499 => status_display.yellow(),
-                _ => status_display.red(),
+    log.request(&request.method, &request.target, status_code.unwrap_or(0), start_time.elapsed(), body_bytes, content_type.as_deref());
+}
+
+/// Answer a tunnel stream without reaching the local service, while `--stop-at` has
+/// stopped the tunnel: HTTP visitors get a 503, TCP connections are closed
+pub async fn handle_stopped_stream<S>(mut tunnel_stream: S, http: bool)
+where
+    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin + Send + 'static,
+{
+    if http {
+        let message = "Tunnel stopped: its bandwidth limit was reached";
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            message.len(),
+            message
+        );
+        let _ = tunnel_stream.write_all(response.as_bytes()).await;
+    }
+    let _ = tunnel_stream.close().await;
+}
+
+/// Handle a TCP tunnel stream by connecting to the local service and copying bytes
+/// both ways until either side closes
+pub async fn handle_tcp_stream<S>(tunnel_stream: S, local_addr: SocketAddr, connect_timeout: Duration, log: RequestLog)
+where
+    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let start_time = Instant::now();
+    let mut local_stream = match tokio::time::timeout(connect_timeout, TcpStream::connect(local_addr)).await {
+        Ok(Ok(stream)) => stream,
+        result => {
+            let reason = match result {
+                Ok(Err(e)) => e.to_string(),
+                _ => "timed out".to_string(),
             };
-            
-            println!(
-                "{} {} {} ({}) {}",
-                "←".cyan(),
-                method.yellow(),
-                path,
-                status_colored,
-                format!("{}ms", elapsed.as_millis()).dimmed()
-            );
+            if !log.quiet {
+                eprintln!("{}{} TCP connection to {} failed: {}", log.prefix, "✗".red(), local_addr, reason);
+            }
+            // Dropping the stream closes the visitor's connection
+            return;
+        }
+    };
+
+    let mut tunnel_stream = tokio_util::compat::FuturesAsyncReadCompatExt::compat(tunnel_stream);
+    match tokio::io::copy_bidirectional(&mut tunnel_stream, &mut local_stream).await {
+        Ok((received, sent)) => {
+            if !log.quiet {
+                println!(
+                    "{}{} {} {} in, {} out {}",
+                    log.prefix,
+                    "←".cyan(),
+                    "TCP".yellow(),
+                    format_size(received),
+                    format_size(sent),
+                    format!("{}ms", start_time.elapsed().as_millis()).dimmed()
+                );
+            }
         }
+        Err(e) => debug!("TCP connection to {} ended: {}", local_addr, e),
     }
 }
 
-fn find_header_end(data: &[u8]) -> Option<usize> {
-    for i in 0..data.len().saturating_sub(3) {
-        if &data[i..i + 4] == b"\r\n\r\n" {
-            return Some(i);
+/// What the forwarder needs from a local server's response head. Heads that don't
+/// parse have none, and are passed on as they are.
+#[derive(Debug, Default)]
+struct ResponseHead(Option<http_head::ResponseHead>);
+
+impl ResponseHead {
+    fn parse(head: &[u8]) -> Self {
+        Self(parse_response(head).ok())
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.0.as_ref().map(|head| head.status)
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.0.as_ref()?.headers.get_str("content-type")
+    }
+
+    /// Whether a body may follow the head
+    fn has_body(&self, is_head: bool) -> bool {
+        match self.status() {
+            Some(status) => !is_head && status >= 200 && status != 204 && status != 304,
+            None => false,
         }
     }
-    None
+
+    /// A 1xx other than 101, which the real response follows
+    fn is_interim(&self) -> bool {
+        self.0.as_ref().is_some_and(http_head::ResponseHead::is_interim)
+    }
+
+    /// A final response that's complete with its head: to HEAD, or a 204 or 304
+    fn is_bodiless(&self, is_head: bool) -> bool {
+        matches!(self.status(), Some(200..)) && !self.has_body(is_head)
+    }
+
+    /// Whether the body ends only when the local server closes the connection (e.g. an
+    /// HTTP/1.0-style response), which the server can't tell apart from a dropped tunnel
+    fn is_close_delimited(&self, is_head: bool) -> bool {
+        self.0.as_ref().is_some_and(|head| {
+            self.has_body(is_head) && !head.headers.contains("content-length") && !head.headers.is_chunked()
+        })
+    }
 }
 
-fn rewrite_host_header(request: &[u8], new_host: &str) -> Vec<u8> {
-    let request_str = match std::str::from_utf8(request) {
-        Ok(s) => s,
-        Err(_) => return request.to_vec(),
-    };
+fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        // An empty chunk would end the body
+        return Vec::new();
+    }
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CapturePolicy, CapturedBody};
+    use crate::expose::inspector::Exchange;
+
+    #[test]
+    fn test_close_delimited_detection() {
+        let head = |raw: &str| ResponseHead::parse(format!("{raw}\r\n\r\n").as_bytes());
+
+        assert!(head("HTTP/1.0 200 OK\r\nContent-Type: text/plain").is_close_delimited(false));
+        assert!(head("HTTP/1.1 404 Not Found\r\nConnection: close").is_close_delimited(false));
+        assert!(!head("HTTP/1.1 200 OK\r\ncontent-length: 5").is_close_delimited(false));
+        assert!(!head("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked").is_close_delimited(false));
+
+        // No body to frame
+        assert!(!head("HTTP/1.1 200 OK").is_close_delimited(true));
+        assert!(!head("HTTP/1.1 204 No Content").is_close_delimited(false));
+        assert!(!head("HTTP/1.1 304 Not Modified").is_close_delimited(false));
+        assert!(!head("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket").is_close_delimited(false));
+        assert!(!head("garbage").is_close_delimited(false));
+    }
+
+    #[test]
+    fn test_bodiless_and_interim_detection() {
+        let head = |raw: &str| ResponseHead::parse(format!("{raw}\r\n\r\n").as_bytes());
+
+        assert!(head("HTTP/1.1 200 OK\r\nContent-Length: 1234").is_bodiless(true));
+        assert!(head("HTTP/1.1 204 No Content").is_bodiless(false));
+        assert!(head("HTTP/1.1 304 Not Modified\r\nETag: \"v1\"").is_bodiless(false));
+        assert!(!head("HTTP/1.1 200 OK\r\nContent-Length: 1234").is_bodiless(false));
+        assert!(!head("HTTP/1.1 100 Continue").is_bodiless(false));
+        assert!(!head("HTTP/1.1 101 Switching Protocols").is_bodiless(false));
+        assert!(!head("garbage").is_bodiless(true));
+
+        assert!(head("HTTP/1.1 100 Continue").is_interim());
+        assert!(head("HTTP/1.1 103 Early Hints\r\nLink: </app.css>").is_interim());
+        assert!(!head("HTTP/1.1 101 Switching Protocols").is_interim());
+        assert!(!head("HTTP/1.1 200 OK").is_interim());
+    }
+
+    #[tokio::test]
+    async fn test_bodiless_response_ends_without_backend_closing() {
+        use tokio::io::AsyncWriteExt as _;
+
+        // Keeps the connection open after each response, as keep-alive servers do
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while crate::http_head::request_head_len(&head).is_none() {
+                        let mut buf = [0u8; 1024];
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let reply: &[u8] = if head.starts_with(b"HEAD ") {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n"
+                    } else {
+                        b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n"
+                    };
+                    socket.write_all(reply).await.unwrap();
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+
+        let forward = |request: &'static [u8]| {
+            tokio::time::timeout(Duration::from_secs(5), forward(request, local_addr, None, Recording::default()))
+        };
+        let response = forward(b"HEAD / HTTP/1.1\r\nHost: x\r\n\r\n").await.expect("HEAD response hung");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let response = forward(b"GET / HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"v1\"\r\n\r\n")
+            .await
+            .expect("304 response hung");
+        assert_eq!(response, "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n");
+    }
+
+    #[test]
+    fn test_response_head_content_type() {
+        let head = ResponseHead::parse(b"HTTP/1.1 200 OK\r\ncontent-type:  text/html; charset=utf-8 \r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(head.status(), Some(200));
+        assert_eq!(head.content_type(), Some("text/html; charset=utf-8"));
+        assert_eq!(ResponseHead::parse(b"HTTP/1.1 204 No Content\r\n\r\n").content_type(), None);
+    }
+
+    #[test]
+    fn test_log_details() {
+        let log = |details: &[LogDetail]| RequestLog::new(false, details);
+
+        assert_eq!(log(&[]).details(100, Some("text/html")), None);
+        assert_eq!(log(&[LogDetail::Size]).details(100, Some("text/html")).unwrap(), "100B");
+        assert_eq!(
+            log(&[LogDetail::Type, LogDetail::Size]).details(1536, Some("text/html; charset=utf-8")).unwrap(),
+            "1.5KB text/html"
+        );
+        assert_eq!(log(&[LogDetail::Type]).details(0, None).unwrap(), "-");
+    }
+
+    #[tokio::test]
+    async fn test_response_head_split_across_reads() {
+        use tokio::io::AsyncWriteExt as _;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        // A local server that trickles its response head out a few bytes at a time
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let response = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"ok\":true}";
+            for piece in response.chunks(3) {
+                socket.write_all(piece).await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        });
+
+        let (tunnel, server_side) = tokio::io::duplex(4096);
+        tokio::spawn(handle_tunnel_stream(
+            tunnel.compat(),
+            local_addr,
+            None,
+            None,
+            Recording::default(),
+            Duration::from_secs(5),
+            RequestLog::new(true, &[]),
+        ));
+        let (mut read, mut write) = tokio::io::split(server_side);
+        write.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        read.read_to_end(&mut response).await.unwrap();
+
+        // The head was found despite arriving in pieces, so the body was re-chunked
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n"), "{:?}", response);
+        assert!(response.ends_with("0\r\n\r\n"), "{:?}", response);
+    }
+
+    /// Send `request` through `handle_tunnel_stream` and return the raw response
+    async fn forward(request: &[u8], local_addr: SocketAddr, local_tls: Option<LocalTls>, recording: Recording) -> String {
+        use tokio::io::AsyncWriteExt as _;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let (tunnel, server_side) = tokio::io::duplex(4096);
+        tokio::spawn(handle_tunnel_stream(
+            tunnel.compat(),
+            local_addr,
+            Some("localhost".to_string()),
+            local_tls,
+            recording,
+            Duration::from_secs(5),
+            RequestLog::new(true, &[]),
+        ));
+        let (mut read, mut write) = tokio::io::split(server_side);
+        write.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        read.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    /// An axum server on a self-signed certificate for `localhost`
+    async fn https_backend() -> SocketAddr {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let provider = std::sync::Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into(),
+            )
+            .unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello over tls" }));
+        let server = axum_server::from_tcp_rustls(
+            listener,
+            axum_server::tls_rustls::RustlsConfig::from_config(std::sync::Arc::new(config)),
+        );
+        tokio::spawn(server.serve(app.into_make_service()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_https_backend_with_self_signed_certificate() {
+        let local_addr = https_backend().await;
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        let insecure = LocalTls::new("localhost", true).unwrap();
+        let response = forward(request, local_addr, Some(insecure), Recording::default()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.ends_with("hello over tls"), "{:?}", response);
+
+        // Verified, the self-signed certificate is refused with a 502 that says why
+        let verified = LocalTls::new("localhost", false).unwrap();
+        let response = forward(request, local_addr, Some(verified), Recording::default()).await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{:?}", response);
+        assert!(response.contains("TLS handshake with backend failed: invalid peer certificate"), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_backend_gets_framed_502() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let inspector = Arc::new(Inspector::new(CapturePolicy::default(), 10));
+        let response = forward(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", local_addr, None, recording(&inspector)).await;
+        assert_eq!(response, "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 25\r\n\r\nCannot connect to backend");
+
+        let exchange = &recorded(&inspector).await[0];
+        assert_eq!(exchange.status, Some(502));
+        assert_eq!(exchange.response_body.to_string(), "Cannot connect to backend");
+    }
+
+    #[tokio::test]
+    async fn test_long_request_lines() {
+        use tokio::io::AsyncWriteExt as _;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        // The local service sends back the request line it got
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    while crate::http_head::request_head_len(&head).is_none() {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let line = &head[..head.windows(2).position(|w| w == b"\r\n").unwrap()];
+                    let response = [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", line.len()).as_bytes(), line].concat();
+                    let _ = stream.write_all(&response).await;
+                });
+            }
+        });
+        let send = |target: String| async move {
+            let (tunnel, server_side) = tokio::io::duplex(4096);
+            tokio::spawn(handle_tunnel_stream(
+                tunnel.compat(),
+                local_addr,
+                None,
+                None,
+                Recording::default(),
+                Duration::from_secs(5),
+                RequestLog::new(true, &[]),
+            ));
+            let (mut read, mut write) = tokio::io::split(server_side);
+            // The rest of a refused request may go unread
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+            tokio::spawn(async move { write.write_all(request.as_bytes()).await });
+            let mut response = Vec::new();
+            read.read_to_end(&mut response).await.unwrap();
+            String::from_utf8(response).unwrap()
+        };
 
-    let mut result = String::new();
-    for line in request_str.lines() {
-        if line.to_lowercase().starts_with("host:") {
-            result.push_str(&format!("Host: {}\r\n", new_host));
-        } else {
-            result.push_str(line);
-            result.push_str("\r\n");
+        // Forwarded byte for byte, with percent-encoding and doubled slashes as they were
+        let target = format!("/a//b/%2F%7e?cb={}&x=%20+%2B", "%41z".repeat(8 * 1024));
+        let response = send(target.clone()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:.100}", response);
+        assert!(response.ends_with(&format!("GET {} HTTP/1.1", target)));
+
+        let response = send(format!("/?q={}", "a".repeat(crate::http_head::MAX_REQUEST_LINE_BYTES))).await;
+        assert_eq!(response, "HTTP/1.1 414 URI Too Long\r\nContent-Length: 29\r\n\r\nrequest line longer than 64KB");
+    }
+
+    fn recording(inspector: &Arc<Inspector>) -> Recording {
+        Recording {
+            inspector: Some(inspector.clone()),
+            replay: None,
+            policy: CapturePolicy::default(),
         }
     }
 
-    result.into_bytes()
+    /// What `inspector` holds once the forwarder has recorded an exchange
+    async fn recorded(inspector: &Inspector) -> Vec<Exchange> {
+        for _ in 0..100 {
+            let exchanges = inspector.exchanges();
+            if !exchanges.is_empty() {
+                return exchanges;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nothing was recorded");
+    }
+
+    #[tokio::test]
+    async fn test_inspector_captures_exchange() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/upload",
+            axum::routing::post(|body: String| async move {
+                // Streamed, so the response is chunked
+                let chunks = vec![Ok::<_, std::io::Error>(format!("got {} bytes", body.len())), Ok(", thanks".to_string())];
+                ([("content-type", "text/plain"), ("set-cookie", "session=abc")], axum::body::Body::from_stream(futures::stream::iter(chunks)))
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let inspector = Arc::new(Inspector::new(CapturePolicy::new(16).redact_headers(["X-Secret"]), 10));
+        let body = "a body longer than the sixteen byte cap";
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer t\r\nX-Secret: s\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let response = forward(request.as_bytes(), local_addr, None, recording(&inspector)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+
+        let exchange = &recorded(&inspector).await[0];
+        assert_eq!((exchange.method.as_str(), exchange.path.as_str(), exchange.status), ("POST", "/upload", Some(200)));
+        // As the visitor sent it, before the Host rewrite
+        assert_eq!(exchange.request_headers[0], ("Host".to_string(), "x".to_string()));
+        assert_eq!(exchange.request_headers[1].1, "[redacted]");
+        assert_eq!(exchange.request_headers[2].1, "[redacted]");
+        assert_eq!(exchange.request_body, CapturedBody::Text { text: "a body longer th".into(), truncated_bytes: 23 });
+        assert!(exchange.response_headers.contains(&("set-cookie".to_string(), "[redacted]".to_string())));
+        // Decoded from the chunked framing
+        assert_eq!(exchange.response_body, CapturedBody::Text { text: "got 39 bytes, th".into(), truncated_bytes: 4 });
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_reaches_local_service() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/echo", axum::routing::post(|body: String| async move { body }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let request = b"POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                        5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let response = forward(request, local_addr, None, Recording::default()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nhello, world"), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn test_body_sent_after_100_continue() {
+        use tokio::io::AsyncWriteExt as _;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        // Only reads the body once it has asked for it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while crate::http_head::request_head_len(&head).is_none() {
+                let mut buf = [0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+            let mut body = [0u8; 5];
+            socket.read_exact(&mut body).await.unwrap();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n{}", String::from_utf8_lossy(&body));
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let (tunnel, server_side) = tokio::io::duplex(4096);
+        tokio::spawn(handle_tunnel_stream(
+            tunnel.compat(),
+            local_addr,
+            None,
+            None,
+            Recording::default(),
+            Duration::from_secs(5),
+            RequestLog::new(true, &[]),
+        ));
+        let (mut read, mut write) = tokio::io::split(server_side);
+        write
+            .write_all(b"PUT /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")
+            .await
+            .unwrap();
+
+        // The request stays open while the visitor waits to be asked for the body
+        let mut interim = [0u8; 25];
+        tokio::time::timeout(Duration::from_secs(5), read.read_exact(&mut interim)).await.unwrap().unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        write.write_all(b"hello").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), read.read_to_end(&mut response)).await.unwrap().unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{:?}", response);
+    }
+
+    #[test]
+    fn test_encode_chunk() {
+        assert_eq!(encode_chunk(b"hello world, again"), b"12\r\nhello world, again\r\n");
+        assert!(encode_chunk(b"").is_empty());
+    }
 }
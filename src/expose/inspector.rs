@@ -0,0 +1,308 @@
+//! The local request inspector (`expose --inspect`): the last requests through the
+//! tunnel, with their responses, served as JSON and a small HTML page
+
+use axum::extract::State;
+use axum::response::{Html, IntoResponse, Json};
+use axum::routing::get;
+use axum::Router;
+use bytes::BytesMut;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::{CapturePolicy, CapturedBody};
+use crate::chunked::ChunkedDecoder;
+use crate::http_head::{Headers, RequestHead, ResponseHead};
+
+/// Exchanges kept in memory; older ones are dropped
+pub const CAPACITY: usize = 100;
+
+const PAGE: &str = include_str!("inspector.html");
+
+/// A request and the response it got, as stored
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    pub id: u64,
+    /// Unix time in milliseconds
+    pub started_at_ms: u64,
+    pub duration_ms: u64,
+    pub method: String,
+    pub path: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: CapturedBody,
+    /// None if the local service never sent a complete response head
+    pub status: Option<u16>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: CapturedBody,
+}
+
+/// Recent exchanges, newest last
+pub struct Inspector {
+    policy: CapturePolicy,
+    capacity: usize,
+    next_id: AtomicU64,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Inspector {
+    pub fn new(policy: CapturePolicy, capacity: usize) -> Self {
+        Self {
+            policy,
+            capacity,
+            next_id: AtomicU64::new(1),
+            exchanges: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Start capturing a request from its head
+    pub fn begin(&self, head: &RequestHead) -> Capture {
+        Capture {
+            started_at: SystemTime::now(),
+            max_body_bytes: self.policy.max_body_bytes(),
+            method: head.method.clone(),
+            path: head.target.clone(),
+            request_headers: head.headers.clone(),
+            status: None,
+            response_headers: Headers::default(),
+        }
+    }
+
+    /// Store a finished exchange, dropping the oldest if full
+    pub fn record(&self, capture: Capture, request_body: Option<BodyTap>, response_body: Option<BodyTap>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let exchange = capture.finish(&self.policy, id, request_body, response_body);
+        let mut exchanges = self.exchanges.lock().unwrap_or_else(|e| e.into_inner());
+        if exchanges.len() == self.capacity {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    /// Every stored exchange, newest first
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap_or_else(|e| e.into_inner());
+        exchanges.iter().rev().cloned().collect()
+    }
+}
+
+/// An exchange in progress. Its bodies are collected by [`BodyTap`]s as they stream
+/// past, since the forwarder copies each direction separately.
+pub struct Capture {
+    started_at: SystemTime,
+    max_body_bytes: usize,
+    method: String,
+    path: String,
+    request_headers: Headers,
+    status: Option<u16>,
+    response_headers: Headers,
+}
+
+impl Capture {
+    /// A tap for the request body
+    pub fn request_body(&self) -> BodyTap {
+        BodyTap::new(self.max_body_bytes, self.request_headers.is_chunked())
+    }
+
+    /// The local service's response head has arrived, or one that couldn't be parsed
+    /// (None); returns a tap for its body
+    pub fn response(&mut self, head: Option<&ResponseHead>) -> BodyTap {
+        self.status = head.map(|head| head.status);
+        self.response_headers = head.map(|head| head.headers.clone()).unwrap_or_default();
+        BodyTap::new(self.max_body_bytes, self.response_headers.is_chunked())
+    }
+
+    /// A response made up by the forwarder, such as a 502 when the service is down
+    pub fn error_response(&mut self, status: u16, message: &str) -> BodyTap {
+        self.status = Some(status);
+        self.response_headers = Headers::default();
+        self.response_headers.set("Content-Type", "text/plain");
+        let mut tap = BodyTap::new(self.max_body_bytes, false);
+        tap.feed(message.as_bytes());
+        tap
+    }
+
+    fn finish(self, policy: &CapturePolicy, id: u64, request_body: Option<BodyTap>, response_body: Option<BodyTap>) -> Exchange {
+        let redacted = |headers: &Headers| -> Vec<(String, String)> {
+            let headers: Vec<(&str, String)> =
+                headers.iter().map(|(name, value)| (name, String::from_utf8_lossy(value).into_owned())).collect();
+            policy.headers(headers.iter().map(|(name, value)| (*name, value.as_str())))
+        };
+        let captured = |tap: Option<BodyTap>, headers: &Headers| match tap {
+            Some(tap) => tap.captured(policy, headers.get_str("content-type")),
+            None => CapturedBody::Empty,
+        };
+        let request_body = captured(request_body, &self.request_headers);
+        let response_body = captured(response_body, &self.response_headers);
+        let unix_ms = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+
+        Exchange {
+            id,
+            started_at_ms: unix_ms(self.started_at),
+            duration_ms: self.started_at.elapsed().map_or(0, |d| d.as_millis() as u64),
+            method: self.method,
+            path: self.path,
+            request_headers: redacted(&self.request_headers),
+            request_body,
+            status: self.status,
+            response_headers: redacted(&self.response_headers),
+            response_body,
+        }
+    }
+}
+
+/// Keeps the start of a body as it streams past, and counts the rest. Chunked bodies
+/// are decoded, so what's kept is the content rather than the framing.
+pub struct BodyTap {
+    kept: Vec<u8>,
+    max_bytes: usize,
+    total: usize,
+    framing: TapFraming,
+}
+
+enum TapFraming {
+    Plain,
+    /// The decoder, and the bytes it hasn't decoded yet
+    Chunked(ChunkedDecoder, BytesMut),
+    /// Badly chunked: what was decoded before is all that's kept
+    Invalid,
+}
+
+impl BodyTap {
+    fn new(max_bytes: usize, chunked: bool) -> Self {
+        let framing = if chunked {
+            TapFraming::Chunked(ChunkedDecoder::default(), BytesMut::new())
+        } else {
+            TapFraming::Plain
+        };
+        Self { kept: Vec::new(), max_bytes, total: 0, framing }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let (decoder, pending) = match &mut self.framing {
+            TapFraming::Plain => return self.keep(bytes),
+            TapFraming::Chunked(decoder, pending) => (decoder, pending),
+            TapFraming::Invalid => return,
+        };
+        pending.extend_from_slice(bytes);
+        loop {
+            match decoder.next_chunk(pending) {
+                Ok(Some(data)) => {
+                    self.total += data.len();
+                    let room = self.max_bytes.saturating_sub(self.kept.len());
+                    self.kept.extend_from_slice(&data[..data.len().min(room)]);
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    self.framing = TapFraming::Invalid;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn keep(&mut self, bytes: &[u8]) {
+        self.total += bytes.len();
+        let room = self.max_bytes.saturating_sub(self.kept.len());
+        self.kept.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    fn captured(&self, policy: &CapturePolicy, content_type: Option<&str>) -> CapturedBody {
+        policy.body(content_type, &self.kept, self.total)
+    }
+}
+
+#[derive(Serialize)]
+struct RequestList {
+    requests: Vec<Exchange>,
+}
+
+/// Serve the inspector on `addr` until the process exits
+pub async fn serve(listener: tokio::net::TcpListener, inspector: Arc<Inspector>) {
+    let app = Router::new()
+        .route("/", get(|| async { Html(PAGE) }))
+        .route("/api/requests", get(list_requests))
+        .with_state(inspector);
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("Inspector stopped: {}", e);
+    }
+}
+
+async fn list_requests(State(inspector): State<Arc<Inspector>>) -> impl IntoResponse {
+    Json(RequestList { requests: inspector.exchanges() })
+}
+
+/// Listen for the inspector on localhost only: captures can hold sensitive data
+pub async fn bind(port: u16) -> anyhow::Result<tokio::net::TcpListener> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Can't start the inspector on {}: {}", addr, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_head::{parse_request, parse_response};
+
+    #[test]
+    fn test_body_tap_caps_and_counts() {
+        let mut tap = BodyTap::new(4, false);
+        tap.feed(b"hello ");
+        tap.feed(b"world");
+        let policy = CapturePolicy::new(4);
+        assert_eq!(
+            tap.captured(&policy, Some("text/plain")),
+            CapturedBody::Text { text: "hell".into(), truncated_bytes: 7 }
+        );
+
+        let mut tap = BodyTap::new(100, true);
+        tap.feed(b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n");
+        assert_eq!(tap.captured(&policy, None), CapturedBody::Text { text: "abcd".into(), truncated_bytes: 2 });
+
+        // Split anywhere, and badly framed from some point on
+        let mut tap = BodyTap::new(100, true);
+        for piece in [&b"3\r\nab"[..], b"c\r\n", b"zz\r\n", b"3\r\ndef\r\n"] {
+            tap.feed(piece);
+        }
+        assert_eq!(tap.captured(&policy, None), CapturedBody::Text { text: "abc".into(), truncated_bytes: 0 });
+    }
+
+    #[test]
+    fn test_ring_buffer_and_redaction() {
+        let inspector = Inspector::new(CapturePolicy::default(), 2);
+        for path in ["/one", "/two", "/three"] {
+            let head = format!("POST {} HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nContent-Type: application/json\r\n\r\n", path);
+            let mut capture = inspector.begin(&parse_request(head.as_bytes()).unwrap());
+            let mut request = capture.request_body();
+            request.feed(b"{\"ok\":true}");
+            let mut response = capture.response(Some(&parse_response(b"HTTP/1.1 201 Created\r\nSet-Cookie: s=1\r\n\r\n").unwrap()));
+            response.feed(b"done");
+            inspector.record(capture, Some(request), Some(response));
+        }
+
+        let exchanges = inspector.exchanges();
+        let paths: Vec<&str> = exchanges.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["/three", "/two"]);
+        assert_eq!(exchanges[0].id, 3);
+        assert_eq!(exchanges[0].method, "POST");
+        assert_eq!(exchanges[0].status, Some(201));
+        assert_eq!(exchanges[0].request_headers[1], ("Authorization".to_string(), "[redacted]".to_string()));
+        assert_eq!(exchanges[0].response_headers[0], ("Set-Cookie".to_string(), "[redacted]".to_string()));
+        assert_eq!(exchanges[0].request_body.to_string(), "{\"ok\":true}");
+        assert_eq!(exchanges[0].response_body.to_string(), "done");
+    }
+
+    #[test]
+    fn test_chunked_only_as_the_final_coding() {
+        let inspector = Inspector::new(CapturePolicy::default(), 1);
+        let mut capture = inspector.begin(&parse_request(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap());
+        let head = parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\nContent-Type: text/plain\r\n\r\n").unwrap();
+        let mut response = capture.response(Some(&head));
+        response.feed(b"3\r\nabc");
+        inspector.record(capture, None, Some(response));
+        assert_eq!(inspector.exchanges()[0].response_body.to_string(), "3\r\nabc");
+    }
+}
@@ -0,0 +1,121 @@
+//! TLS to the local service, for backends that only speak HTTPS (`--local-https`)
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// How to wrap connections to the local service in TLS
+#[derive(Clone)]
+pub struct LocalTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl LocalTls {
+    /// Present `name` (the local host, or `--local-host`) as the SNI name, and check
+    /// the certificate against it unless `insecure`
+    pub fn new(name: &str, insecure: bool) -> Result<Self> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to set up TLS for the local service")?;
+        let config = if insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: server_name(name)?,
+        })
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
+
+/// The host part of `name`, which may carry a port as a Host header does
+fn server_name(name: &str) -> Result<ServerName<'static>> {
+    let host = match name.parse::<IpAddr>() {
+        Ok(_) => name,
+        Err(_) => name
+            .rsplit_once(':')
+            .filter(|(_, port)| port.parse::<u16>().is_ok())
+            .map_or(name, |(host, _)| host),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).with_context(|| format!("Invalid TLS server name '{}'", name))
+}
+
+/// Accepts whatever certificate the local service presents (`--local-insecure`), for
+/// self-signed development certificates. Signatures are still checked, so the
+/// handshake itself is sound.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        let name = |s| server_name(s).unwrap().to_str().into_owned();
+        assert_eq!(name("localhost"), "localhost");
+        assert_eq!(name("myapp.test:8443"), "myapp.test");
+        assert_eq!(name("127.0.0.1"), "127.0.0.1");
+        assert_eq!(name("::1"), "::1");
+        assert_eq!(name("[::1]:8443"), "::1");
+        assert!(server_name("not a host").is_err());
+    }
+}
@@ -1,145 +1,482 @@
 mod client;
-mod forwarder;
+mod commands;
+pub(crate) mod connect;
+mod dial;
+pub(crate) mod echo;
+mod examples;
+pub(crate) mod forwarder;
+pub(crate) mod inspector;
+mod local_tls;
 mod reconnect;
-mod tunnel;
+pub(crate) mod replay;
+mod start;
+pub(crate) mod static_files;
+pub(crate) mod summary;
+pub(crate) mod tunnel;
 
 use anyhow::Result;
 use colored::Colorize;
+use ipnet::IpNet;
 use std::net::SocketAddr;
-use tracing::Level;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, Level};
 use tracing_subscriber::FmtSubscriber;
 
 use client::TunnelClient;
+use commands::Replayer;
+pub use connect::connect;
+pub use dial::Dialer;
+pub use examples::Provider;
+pub use forwarder::LogDetail;
+use forwarder::{Recording, RequestLog};
+use inspector::Inspector;
+use local_tls::LocalTls;
 use reconnect::ReconnectStrategy;
+use replay::ReplayBuffer;
+pub use start::start;
+use static_files::StaticFiles;
+pub use summary::BandwidthLimits;
+use summary::SessionStats;
+use tunnel::{LocalService, TunnelEnd};
 
+use crate::capture::CapturePolicy;
 use crate::client_config::ClientConfig;
+use crate::idn;
+use crate::names;
+use crate::proto::Protocol;
+use crate::schedule::Window;
 
-fn generate_subdomain() -> String {
-    use rand::Rng;
-    let mut rng = rand::rng();
-    let adjectives = ["quick", "bright", "calm", "eager", "fancy", "gentle", "happy", "jolly", "kind", "lively"];
-    let nouns = ["fox", "owl", "bear", "wolf", "deer", "hawk", "lynx", "seal", "duck", "frog"];
-    let adj = adjectives[rng.random_range(0..adjectives.len())];
-    let noun = nouns[rng.random_range(0..nouns.len())];
-    let num: u16 = rng.random_range(100..1000);
-    format!("{}-{}-{}", adj, noun, num)
-}
-
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     server: Option<String>,
     token: Option<String>,
     subdomain: Option<String>,
     host: String,
     port: u16,
+    protocol: Protocol,
+    remote_port: Option<u16>,
+    share: bool,
     local_host: Option<String>,
+    local_https: bool,
+    local_insecure: bool,
+    serve: Option<PathBuf>,
+    dir_listing: bool,
+    publish_manifest: bool,
+    basic_auth: Option<String>,
+    allow_ips: Vec<IpNet>,
+    path_mode: bool,
+    aliases: Vec<String>,
+    service_name: Option<String>,
+    service_version: Option<String>,
+    inspect: Option<u16>,
+    redact_headers: Vec<String>,
+    replay_buffer: Option<usize>,
     max_retries: u32,
-    forward_timeout_secs: u64,
+    forward_timeout: std::time::Duration,
+    ping_interval: std::time::Duration,
+    keep_alive: bool,
+    pause_schedule: Option<Window>,
+    bandwidth: BandwidthLimits,
+    strict_clock: bool,
+    dialer: Dialer,
     log_level: Level,
     quiet: bool,
+    log_detail: Vec<LogDetail>,
     show_qr: bool,
+    print_examples: Option<Vec<Provider>>,
 ) -> Result<()> {
     // Load from config if not provided
-    let (server, token) = match (server, token) {
-        (Some(s), Some(t)) => (s, t),
-        (s, t) => {
-            let config = ClientConfig::load()?
-                .ok_or_else(|| anyhow::anyhow!("Not logged in. Run 'loophole login' first, or provide --server and --token."))?;
-            (s.unwrap_or(config.server), t.unwrap_or(config.token))
-        }
-    };
+    let (server, token) = credentials(server, token)?;
 
-    // Generate subdomain if not provided
-    let subdomain = subdomain.unwrap_or_else(generate_subdomain);
+    // Fail fast on bind options that can never work, rather than retrying forever
+    dialer.validate()?;
 
     let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
     let local_addr: SocketAddr = format!("{}:{}", host, port).parse()?;
-    println!(
-        "{} Forwarding to {}",
-        "→".cyan(),
-        local_addr.to_string().cyan()
-    );
-
-    let mut reconnect = ReconnectStrategy::new();
-    let forward_timeout = std::time::Duration::from_secs(forward_timeout_secs);
-
-    loop {
-        // Check if we've exceeded max retries
-        if max_retries > 0 && reconnect.attempts() >= max_retries {
-            eprintln!(
-                "{} Maximum reconnection attempts ({}) exceeded",
-                "✗".red(),
-                max_retries
-            );
-            return Err(anyhow::anyhow!("Maximum reconnection attempts exceeded"));
+
+    // Bind before connecting, so a port that's in use is reported straight away
+    let policy = CapturePolicy::default().redact_headers(&redact_headers);
+    let inspector = match inspect {
+        Some(port) => {
+            let listener = inspector::bind(port).await?;
+            let inspector = Arc::new(Inspector::new(policy.clone(), inspector::CAPACITY));
+            println!("{} Inspector at {}", "→".cyan(), format!("http://{}", listener.local_addr()?).cyan());
+            tokio::spawn(inspector::serve(listener, inspector.clone()));
+            Some(inspector)
+        }
+        None => None,
+    };
+    let replay = replay_buffer.filter(|&capacity| capacity > 0).map(|capacity| Arc::new(ReplayBuffer::new(capacity)));
+    let local = match (serve, protocol) {
+        (Some(dir), _) => LocalService::Files(Arc::new(StaticFiles::new(&dir, dir_listing)?)),
+        (None, Protocol::Tcp) => LocalService::Tcp { addr: local_addr },
+        (None, Protocol::Http) => LocalService::Http {
+            addr: local_addr,
+            host: local_host.clone(),
+            tls: match local_https {
+                true => Some(LocalTls::new(local_host.as_deref().unwrap_or(&host), local_insecure)?),
+                false => None,
+            },
+            recording: Recording {
+                inspector,
+                replay: replay.clone(),
+                policy,
+            },
+        },
+    };
+    match &local {
+        LocalService::Files(files) => {
+            println!("{} Serving {}", "→".cyan(), files.root().display().to_string().cyan())
+        }
+        LocalService::Http { tls: Some(_), .. } => {
+            println!("{} Forwarding to {}", "→".cyan(), format!("https://{}", local_addr).cyan())
         }
+        _ => println!("{} Forwarding to {}", "→".cyan(), local_addr.to_string().cyan()),
+    }
+    if replay.is_some() {
+        println!("{} Enter {} to replay the last request, or {} for the Nth most recent", "→".cyan(), "r".bold(), "r N".bold());
+    }
+    let stats = Arc::new(SessionStats::new().with_limits(bandwidth));
+    if replay.is_some() || bandwidth.stop_at.is_some() {
+        let replayer = replay.map(|buffer| Replayer {
+            buffer,
+            local: local.clone(),
+            timeout: forward_timeout,
+            log: RequestLog::new(quiet, &log_detail),
+        });
+        tokio::spawn(commands::read(replayer, stats.clone()));
+    }
+    let watcher = tokio::spawn(summary::watch(stats.clone(), ""));
 
-        let client = TunnelClient::new(server.clone(), token.clone(), subdomain.clone());
-
-        match client.connect().await {
-            Ok(mut conn) => {
-                reconnect.reset();
-
-                // Print success message
-                println!("{} Connected to {}", "✓".green(), server.green());
-
-                // Check certificate status before showing URL
-                let cert_status = TunnelClient::wait_for_cert_status(&mut conn.read).await;
-                
-                if let Some(false) = cert_status {
-                    // Certificate is being provisioned, wait for it
-                    print!("{} Waiting for SSL certificate...", "⏳".yellow());
-                    std::io::Write::flush(&mut std::io::stdout()).ok();
-                    
-                    let cert_ready = TunnelClient::wait_for_cert_ready(&mut conn.read, 90).await;
-                    
-                    if cert_ready {
-                        println!(" {}", "ready!".green());
-                    } else {
-                        println!(" {}", "timeout (HTTPS may not work immediately)".yellow());
-                    }
-                }
+    let result = Session {
+        server,
+        token,
+        subdomain,
+        dialer,
+        remote_port,
+        // One key for the whole run, so connectors keep working across reconnects
+        share_key: share.then(|| crate::init::generate_token("sk")),
+        publish_manifest,
+        basic_auth,
+        allow_ips,
+        path_mode,
+        aliases,
+        service_name,
+        service_version,
+        max_retries,
+        forward_timeout,
+        ping_interval,
+        keep_alive,
+        pause_schedule,
+        strict_clock,
+        log: RequestLog::new(quiet, &log_detail),
+        show_qr,
+        print_examples,
+        stats,
+        shutdown: on_ctrl_c(),
+    }
+    .run(local)
+    .await;
+    watcher.abort();
+    summary::reset_title();
+    result
+}
+
+/// Cancelled on the first Ctrl+C, so sessions can disconnect cleanly; a second one
+/// exits straight away
+fn on_ctrl_c() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+    let token = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        println!();
+        println!("{} Disconnecting... (Ctrl+C again to quit now)", "!".yellow());
+        token.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    shutdown
+}
+
+/// The saved login, unless both are given
+pub fn credentials(server: Option<String>, token: Option<String>) -> Result<(String, String)> {
+    match (server, token) {
+        (Some(s), Some(t)) => Ok((s, t)),
+        (s, t) => {
+            let config = ClientConfig::load()?
+                .ok_or_else(|| anyhow::anyhow!("Not logged in. Run 'loophole login' first, or provide --server and --token."))?;
+            Ok((s.unwrap_or(config.server), t.unwrap_or(config.token)))
+        }
+    }
+}
+
+/// One tunnel, kept connected: everything it needs to connect again after a drop
+struct Session {
+    server: String,
+    token: String,
+    /// None to have the server pick one
+    subdomain: Option<String>,
+    dialer: Dialer,
+    remote_port: Option<u16>,
+    /// Lets connectors reach a TCP tunnel without the token
+    share_key: Option<String>,
+    publish_manifest: bool,
+    /// `user:password` the server asks visitors for
+    basic_auth: Option<String>,
+    /// Networks the server lets visitors come from
+    allow_ips: Vec<IpNet>,
+    /// Reached under `/t/<subdomain>/` on the server's domain instead of a subdomain
+    path_mode: bool,
+    /// Other subdomains the server routes to the tunnel
+    aliases: Vec<String>,
+    service_name: Option<String>,
+    service_version: Option<String>,
+    max_retries: u32,
+    forward_timeout: std::time::Duration,
+    ping_interval: std::time::Duration,
+    keep_alive: bool,
+    /// Windows the server should pause the tunnel for maintenance in
+    pause_schedule: Option<Window>,
+    strict_clock: bool,
+    /// Its prefix starts every line the session prints
+    log: RequestLog,
+    show_qr: bool,
+    print_examples: Option<Vec<Provider>>,
+    /// Counted across reconnects, and printed when the session ends
+    stats: Arc<SessionStats>,
+    /// Cancelled to disconnect cleanly and print a summary
+    shutdown: CancellationToken,
+}
+
+impl Session {
+    /// Connect, reconnecting whenever the connection drops. Returns an error only if
+    /// retrying won't fix it, or after `max_retries` failed attempts in a row; returns
+    /// Ok once `shutdown` is cancelled and the tunnel has disconnected.
+    async fn run(self, local: LocalService) -> Result<()> {
+        let prefix = self.log.prefix();
+        let protocol = local.protocol();
+        let mut reconnect = ReconnectStrategy::new();
+        let mut examples_shown = false;
+        let mut clock_checked = false;
+        // Shown when it first arrives and again only if the operator changes it
+        let mut motd_shown: Option<String> = None;
+        // A TCP tunnel asks for the port it was given before, so its address survives reconnects
+        let mut tcp_port = self.remote_port;
+        // Once the server has picked a name, keep it across reconnects too
+        let mut subdomain = self.subdomain.clone();
+        let stats = self.stats.clone();
+        let mut url = None;
 
-                println!(
-                    "{} Tunnel URL: {}",
-                    "✓".green(),
-                    conn.url.bright_green().bold()
+        while !self.shutdown.is_cancelled() {
+            // Check if we've exceeded max retries
+            if self.max_retries > 0 && reconnect.attempts() >= self.max_retries {
+                eprintln!(
+                    "{}{} Maximum reconnection attempts ({}) exceeded",
+                    prefix,
+                    "✗".red(),
+                    self.max_retries
                 );
-                println!();
+                return Err(anyhow::anyhow!("Maximum reconnection attempts exceeded"));
+            }
+
+            let mut client = TunnelClient::new(self.server.clone(), self.token.clone(), subdomain.clone().unwrap_or_default(), self.dialer.clone())
+                .manifest(self.publish_manifest, self.service_name.clone(), self.service_version.clone())
+                .pause_schedule(self.pause_schedule.clone())
+                .allow_ips(self.allow_ips.clone());
+            if protocol == Protocol::Tcp {
+                client = client.tcp(tcp_port).share(self.share_key.clone());
+            } else {
+                client = client.basic_auth(self.basic_auth.clone()).path_mode(self.path_mode).aliases(self.aliases.clone());
+            }
+
+            let connected = tokio::select! {
+                connected = client.connect() => connected,
+                _ = self.shutdown.cancelled() => break,
+            };
+            match connected {
+                Ok(mut conn) => {
+                    reconnect.reset();
+                    subdomain = Some(conn.subdomain.clone());
+                    url = Some(conn.url.clone());
+
+                    // Print success message
+                    println!("{}{} Connected to {}", prefix, "✓".green(), self.server.green());
+
+                    // Once per run: a skewed clock won't fix itself between reconnects
+                    if !clock_checked {
+                        clock_checked = true;
+                        if let Some(warning) = conn.server_date.and_then(|date| date.skew_warning("the tunnel server")) {
+                            if self.strict_clock {
+                                return Err(anyhow::anyhow!(warning));
+                            }
+                            eprintln!("{}{} {}", prefix, "!".yellow(), warning);
+                        }
+                    }
+
+                    if protocol == Protocol::Tcp {
+                        tcp_port = url::Url::parse(&conn.url).ok().and_then(|url| url.port()).or(tcp_port);
+                    }
 
-                // Show QR code if requested
-                if show_qr {
-                    print_qr_code(&conn.url);
+                    // Check certificate status before showing URL (TCP tunnels don't have one)
+                    let cert_status = match protocol {
+                        Protocol::Http => TunnelClient::wait_for_cert_status(&mut conn.read).await,
+                        Protocol::Tcp => None,
+                    };
+                    let mut url_ready = true;
+
+                    if let Some(false) = cert_status {
+                        // Certificate is being provisioned, wait for it
+                        print!("{}{} Waiting for SSL certificate...", prefix, "⏳".yellow());
+                        std::io::Write::flush(&mut std::io::stdout()).ok();
+
+                        let cert_ready = tokio::select! {
+                            ready = TunnelClient::wait_for_cert_ready(&mut conn.read, 90) => Some(ready),
+                            // Straight on to disconnecting
+                            _ = self.shutdown.cancelled() => None,
+                        };
+
+                        match cert_ready {
+                            Some(true) => println!(" {}", "ready!".green()),
+                            Some(false) => {
+                                println!(" {}", "timeout (HTTPS may not work immediately)".yellow());
+                                url_ready = false;
+                            }
+                            None => println!(),
+                        }
+                    }
+
+                    // Internationalized names are shown as people wrote them, with the
+                    // punycode form for tools that don't take Unicode hosts
+                    let display_url = idn::display_url(&conn.url);
+                    println!(
+                        "{}{} Tunnel URL: {}",
+                        prefix,
+                        "✓".green(),
+                        display_url.bright_green().bold()
+                    );
+                    if display_url != conn.url {
+                        println!("{}  {} {}", prefix, "Punycode:".dimmed(), conn.url);
+                    }
+                    for alias_url in &conn.alias_urls {
+                        println!("{}  {} {}", prefix, "Also at:".dimmed(), idn::display_url(alias_url).bright_green());
+                    }
+                    if let Some(limits) = conn.limits.as_ref().and_then(client::describe_limits) {
+                        println!("{}  {} {}", prefix, "Limits:".dimmed(), limits);
+                    }
+                    if let Some(ref share_key) = self.share_key {
+                        println!(
+                            "{}{} Others can connect with: {}",
+                            prefix,
+                            "→".cyan(),
+                            format!(
+                                "loophole connect {} --server {} --key {} --local-port <PORT>",
+                                conn.subdomain, self.server, share_key
+                            )
+                            .bold()
+                        );
+                    }
+                    if let Some(ref motd) = conn.motd {
+                        if !self.log.quiet && motd_shown.as_ref() != Some(motd) {
+                            println!();
+                            for line in motd.lines() {
+                                println!("{}  {}", prefix, line.dimmed());
+                            }
+                        }
+                    }
+                    motd_shown = conn.motd.clone();
+                    println!();
+
+                    // Show QR code if requested
+                    if self.show_qr {
+                        print_qr_code(&conn.url);
+                    }
+
+                    // Once per session, and only when the URL works (the certificate is in place)
+                    if let Some(ref providers) = self.print_examples {
+                        if !self.log.quiet && !examples_shown && url_ready {
+                            examples::print(&conn.url, providers);
+                            examples_shown = true;
+                        }
+                    }
+
+                    // Reunite the split stream for yamux
+                    let ws = conn.write.reunite(conn.read).expect("reunite failed");
+
+                    // Run the tunnel
+                    match tunnel::run_tunnel(
+                        ws,
+                        local.clone(),
+                        self.forward_timeout,
+                        self.ping_interval,
+                        self.keep_alive,
+                        self.log,
+                        stats.clone(),
+                        self.shutdown.clone(),
+                    )
+                    .await {
+                        Ok(TunnelEnd::ServerShutdown(message)) => {
+                            println!("{}{} {}", prefix, "!".yellow(), message);
+                            // It's most likely restarting, or closed the tunnel on purpose;
+                            // don't hammer it either way
+                            reconnect.server_restarting();
+                        }
+                        Ok(TunnelEnd::Disconnected) => break,
+                        Ok(TunnelEnd::Closed) => {}
+                        Err(e) => eprintln!("{}{} Tunnel error: {}", prefix, "✗".red(), e),
+                    }
                 }
+                Err(e) => {
+                    let msg = e.to_string();
 
-                // Reunite the split stream for yamux
-                let ws = conn.write.reunite(conn.read).expect("reunite failed");
+                    // Servers that predate picking names refuse an empty one: pick it here
+                    if subdomain.is_none() && msg.contains("Invalid subdomain") {
+                        debug!("Server didn't pick a subdomain ({}), choosing one", msg);
+                        subdomain = Some(names::random_subdomain());
+                        continue;
+                    }
 
-                // Run the tunnel
-                if let Err(e) =
-                    tunnel::run_tunnel(ws, local_addr, local_host.clone(), forward_timeout, quiet)
-                        .await
-                {
-                    eprintln!("{} Tunnel error: {}", "✗".red(), e);
+                    eprintln!("{}{} Connection failed: {}", prefix, "✗".red(), e);
+
+                    // Someone took the name we were given while we were away. It wasn't the
+                    // user's choice, so get another instead of giving up.
+                    let name_lost = self.subdomain.is_none() && msg.contains("Subdomain already taken");
+                    if name_lost {
+                        subdomain = None;
+                    }
+
+                    // Check if it's a fatal error
+                    if msg.contains("Invalid token")
+                        || msg.contains("Invalid subdomain")
+                        || (msg.contains("Subdomain already taken") && !name_lost)
+                        || msg.contains("Tunnel limit reached")
+                        || msg.contains("TCP tunnels unavailable")
+                    {
+                        return Err(e);
+                    }
+
+                    // The port this tunnel had was taken while it was away: take any other
+                    if msg.contains("Port unavailable") && tcp_port != self.remote_port {
+                        tcp_port = self.remote_port;
+                    }
                 }
             }
-            Err(e) => {
-                eprintln!("{} Connection failed: {}", "✗".red(), e);
-
-                // Check if it's a fatal error
-                let msg = e.to_string();
-                if msg.contains("Invalid token")
-                    || msg.contains("Invalid subdomain")
-                    || msg.contains("Subdomain already taken")
-                {
-                    return Err(e);
-                }
+
+            println!("{}{} Connection lost, reconnecting...", prefix, "!".yellow());
+            tokio::select! {
+                _ = reconnect.wait() => {}
+                _ = self.shutdown.cancelled() => break,
             }
         }
 
-        println!("{} Connection lost, reconnecting...", "!".yellow());
-        reconnect.wait().await;
+        stats.print(prefix, url.as_deref(), protocol);
+        Ok(())
     }
 }
 
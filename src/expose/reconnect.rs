@@ -5,7 +5,10 @@ pub struct ReconnectStrategy {
     pub base_delay: Duration,
     pub max_delay: Duration,
     pub multiplier: f64,
+    /// Minimum delay before the first attempt after the server announced a shutdown
+    pub restart_delay: Duration,
     attempts: u32,
+    server_restarting: bool,
 }
 
 impl ReconnectStrategy {
@@ -14,7 +17,9 @@ impl ReconnectStrategy {
             base_delay: Duration::from_secs(1),
             max_delay: Duration::from_secs(60),
             multiplier: 2.0,
+            restart_delay: Duration::from_secs(5),
             attempts: 0,
+            server_restarting: false,
         }
     }
 
@@ -22,6 +27,12 @@ impl ReconnectStrategy {
         self.attempts = 0;
     }
 
+    /// The server said it's shutting down: wait at least `restart_delay` before the
+    /// next attempt, since reconnecting straight away would only fail
+    pub fn server_restarting(&mut self) {
+        self.server_restarting = true;
+    }
+
     /// Get the current number of attempts
     pub fn attempts(&self) -> u32 {
         self.attempts
@@ -34,7 +45,10 @@ impl ReconnectStrategy {
     }
 
     fn next_delay(&mut self) -> Duration {
-        let delay = self.base_delay.mul_f64(self.multiplier.powi(self.attempts as i32));
+        let mut delay = self.base_delay.mul_f64(self.multiplier.powi(self.attempts as i32));
+        if std::mem::take(&mut self.server_restarting) {
+            delay = delay.max(self.restart_delay);
+        }
         self.attempts += 1;
 
         // Add some jitter (±10%)
@@ -60,3 +74,23 @@ fn rand_f64() -> f64 {
         .subsec_nanos();
     (nanos % 1000) as f64 / 1000.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longer_delay_after_server_shutdown() {
+        let mut reconnect = ReconnectStrategy::new();
+        assert!(reconnect.next_delay() < Duration::from_secs(2));
+
+        reconnect.reset();
+        reconnect.server_restarting();
+        let delay = reconnect.next_delay();
+        assert!(delay >= Duration::from_millis(4500), "{:?}", delay);
+
+        // Only the first attempt is stretched; backoff continues from there
+        let delay = reconnect.next_delay();
+        assert!(delay < Duration::from_secs(3), "{:?}", delay);
+    }
+}
@@ -0,0 +1,278 @@
+//! Replaying recent requests against the local service (`--replay-buffer`), for
+//! iterating on a webhook handler without waiting for the sender to try again.
+//!
+//! Requests are kept under the tunnel's [`CapturePolicy`] body cap like any other
+//! capture, but not redacted: a replay has to send the request byte for byte, and
+//! one without its `Authorization` or `Cookie` would be refused by the service it
+//! was meant for. The buffer is never shown or exported, only sent back to the local
+//! service that already saw those headers.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use super::forwarder::{handle_tunnel_stream, Recording, RequestLog};
+use super::tunnel::LocalService;
+use crate::capture::CapturePolicy;
+use crate::http_head::{parse_request, request_head_len, RequestHead};
+
+/// The raw bytes of recent requests, newest last
+pub struct ReplayBuffer {
+    capacity: usize,
+    requests: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Keep a request, dropping the oldest if full. Requests that were cut short, or
+    /// given up on as too large, are ignored: they can't be sent again as they were.
+    pub fn push(&self, tap: RequestTap) {
+        let Some(request) = tap.request.filter(|request| is_complete(request)) else {
+            return;
+        };
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        if requests.len() == self.capacity {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    /// The `nth` most recent request, counting from 1
+    pub fn get(&self, nth: usize) -> Option<Vec<u8>> {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        let index = requests.len().checked_sub(nth)?;
+        requests.get(index).cloned()
+    }
+}
+
+/// Collects a request's bytes as they stream through the forwarder
+pub struct RequestTap {
+    request: Option<Vec<u8>>,
+    /// The head's length plus the policy's body cap; longer requests are given up on
+    limit: usize,
+}
+
+impl RequestTap {
+    /// Start from what has been read so far: `head`, and perhaps some of the body.
+    /// Upgrades (WebSockets) aren't kept, as there's no single response to replay for,
+    /// nor are requests whose body is over `policy`'s cap, as they can't be replayed
+    /// whole.
+    pub fn new(read: &[u8], head: &RequestHead, policy: &CapturePolicy) -> Self {
+        let upgrade = head.headers.contains("upgrade");
+        let limit = request_head_len(read).unwrap_or(read.len()) + policy.max_body_bytes();
+        Self {
+            request: (!upgrade && read.len() <= limit).then(|| read.to_vec()),
+            limit,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if let Some(request) = self.request.as_mut() {
+            if request.len() + bytes.len() > self.limit {
+                self.request = None;
+            } else {
+                request.extend_from_slice(bytes);
+            }
+        }
+    }
+}
+
+/// Whether `request` holds a whole request according to its framing
+fn is_complete(request: &[u8]) -> bool {
+    let Some(len) = request_head_len(request) else {
+        return false;
+    };
+    let Ok(head) = parse_request(&request[..len]) else {
+        return false;
+    };
+    let body = &request[len..];
+    if head.headers.is_chunked() {
+        // Trailers after the last chunk are rare enough not to look for
+        return body.ends_with(b"0\r\n\r\n");
+    }
+    match head.headers.content_length() {
+        Ok(length) => length.is_none_or(|length| body.len() as u64 >= length),
+        Err(_) => false,
+    }
+}
+
+/// `request` with `Connection: close`, so the local service closes the connection
+/// once it has responded, which is how a replay knows the response is complete
+fn with_connection_close(request: &[u8]) -> Vec<u8> {
+    let Some(len) = request_head_len(request) else {
+        return request.to_vec();
+    };
+    let Ok(mut head) = parse_request(&request[..len]) else {
+        return request.to_vec();
+    };
+    // Along with the fields the visitor's connection named as its own
+    for option in head.headers.connection().options() {
+        head.headers.remove(option);
+    }
+    head.headers.remove("connection");
+    head.headers.remove("keep-alive");
+    head.headers.set("Connection", "close");
+    let mut replayed = head.to_bytes();
+    replayed.extend_from_slice(&request[len..]);
+    replayed
+}
+
+/// Send `request` to the local service again, as if it had come through the tunnel.
+/// The response is logged (and inspected) as usual, then discarded.
+pub async fn replay(request: &[u8], local: &LocalService, timeout: Duration, log: RequestLog) {
+    let LocalService::Http { addr, host, tls, recording } = local else {
+        return;
+    };
+    // A replay shows up in the inspector, but isn't kept to be replayed again
+    let recording = Recording {
+        replay: None,
+        ..recording.clone()
+    };
+
+    let (tunnel, visitor) = tokio::io::duplex(64 * 1024);
+    let forwarding = tokio::spawn(handle_tunnel_stream(
+        tunnel.compat(),
+        *addr,
+        host.clone(),
+        tls.clone(),
+        recording,
+        timeout,
+        log,
+    ));
+
+    let request = with_connection_close(request);
+    let (mut read, mut write) = tokio::io::split(visitor);
+    let send = async {
+        let _ = write.write_all(&request).await;
+    };
+    let mut discard = tokio::io::sink();
+    let receive = tokio::io::copy(&mut read, &mut discard);
+    let _ = tokio::join!(send, receive);
+    // Closing the stream lets the forwarder finish and log the response
+    drop((read, write));
+    let _ = forwarding.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::expose::inspector::Inspector;
+
+    fn tap(request: &[u8]) -> RequestTap {
+        let head = parse_request(&request[..request_head_len(request).unwrap()]).unwrap();
+        RequestTap::new(request, &head, &CapturePolicy::new(16))
+    }
+
+    #[test]
+    fn test_buffer_keeps_complete_requests() {
+        let buffer = ReplayBuffer::new(2);
+        buffer.push(tap(b"GET /one HTTP/1.1\r\nHost: x\r\n\r\n"));
+        // The body never arrived in full
+        buffer.push(tap(b"POST /cut HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"));
+        buffer.push(tap(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"));
+        let mut chunked = tap(b"POST /two HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        chunked.feed(b"3\r\nabc\r\n");
+        chunked.feed(b"0\r\n\r\n");
+        buffer.push(chunked);
+        buffer.push(tap(b"POST /three HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc"));
+
+        let path = |nth| buffer.get(nth).map(|request| String::from_utf8(request).unwrap().split(' ').nth(1).unwrap().to_string());
+        assert_eq!(path(1).as_deref(), Some("/three"));
+        assert_eq!(path(2).as_deref(), Some("/two"));
+        // Dropped when full, or never kept
+        assert_eq!(path(3), None);
+        assert_eq!(path(0), None);
+
+        // Bodies over the capture policy's cap aren't kept, whatever the head's length
+        let mut large = tap(b"POST /large HTTP/1.1\r\nContent-Length: 17\r\n\r\n");
+        large.feed(&[b'x'; 17]);
+        buffer.push(large);
+        assert_eq!(path(1).as_deref(), Some("/three"));
+        let mut capped = tap(b"POST /capped HTTP/1.1\r\nContent-Length: 16\r\n\r\n");
+        capped.feed(&[b'x'; 16]);
+        buffer.push(capped);
+        assert_eq!(path(1).as_deref(), Some("/capped"));
+    }
+
+    #[test]
+    fn test_with_connection_close() {
+        let request = b"POST /hook HTTP/1.1\r\nHost: x\r\nConnection: keep-alive, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(
+            with_connection_close(request),
+            b"POST /hook HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_reaches_local_service() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post({
+                let received = received.clone();
+                move |body: String| async move {
+                    received.lock().unwrap().push(body);
+                    "ok"
+                }
+            }),
+        );
+        // Keeps connections alive unless asked not to
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let inspector = Arc::new(Inspector::new(Default::default(), 10));
+        let buffer = Arc::new(ReplayBuffer::new(5));
+        let local = LocalService::Http {
+            addr,
+            host: None,
+            tls: None,
+            recording: Recording {
+                inspector: Some(inspector.clone()),
+                replay: Some(buffer.clone()),
+                policy: CapturePolicy::default(),
+            },
+        };
+        let LocalService::Http { recording, .. } = &local else { unreachable!() };
+
+        // Through the tunnel, as the server would send it: the stream closes once the
+        // response has arrived
+        let request = b"POST /hook HTTP/1.1\r\nHost: x\r\nContent-Length: 7\r\n\r\npayload";
+        let (tunnel, mut visitor) = tokio::io::duplex(4096);
+        let log = RequestLog::new(true, &[]);
+        let forwarding = tokio::spawn(handle_tunnel_stream(tunnel.compat(), addr, None, None, recording.clone(), Duration::from_secs(5), log));
+        visitor.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"ok") {
+            let mut buf = [0u8; 1024];
+            let n = tokio::io::AsyncReadExt::read(&mut visitor, &mut buf).await.unwrap();
+            assert!(n > 0, "{:?}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buf[..n]);
+        }
+        drop(visitor);
+        tokio::time::timeout(Duration::from_secs(5), forwarding).await.unwrap().unwrap();
+        assert_eq!(buffer.get(1).as_deref(), Some(&request[..]));
+
+        for _ in 0..2 {
+            let request = buffer.get(1).unwrap();
+            let replayed = replay(&request, &local, Duration::from_secs(5), log);
+            tokio::time::timeout(Duration::from_secs(5), replayed).await.unwrap();
+        }
+
+        assert_eq!(*received.lock().unwrap(), ["payload", "payload", "payload"]);
+        let exchanges = inspector.exchanges();
+        assert_eq!(exchanges.len(), 3);
+        assert_eq!(exchanges[0].status, Some(200));
+        // Replays aren't themselves kept for replay
+        assert!(buffer.get(2).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+//! `loophole start`: every tunnel in a tunnels file at once, each over its own
+//! connection with its own reconnects, their log lines told apart by a prefix
+
+use anyhow::Result;
+use colored::{Color, Colorize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+use super::forwarder::{LogDetail, RequestLog};
+use super::summary::SessionStats;
+use super::tunnel::LocalService;
+use super::{credentials, on_ctrl_c, Dialer, Session};
+use crate::client_config::TunnelsFile;
+
+/// Prefix colours, in the order tunnels are listed
+const COLORS: [Color; 6] = [Color::Cyan, Color::Magenta, Color::Yellow, Color::Blue, Color::Green, Color::BrightRed];
+
+#[allow(clippy::too_many_arguments)]
+pub async fn start(
+    path: &Path,
+    server: Option<String>,
+    token: Option<String>,
+    fail_fast: bool,
+    max_retries: u32,
+    ping_interval: Duration,
+    keep_alive: bool,
+    strict_clock: bool,
+    dialer: Dialer,
+    log_level: Level,
+    quiet: bool,
+    log_detail: Vec<LogDetail>,
+) -> Result<()> {
+    let file = TunnelsFile::load(path)?;
+    let (server, token) = credentials(server, token)?;
+    dialer.validate()?;
+
+    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let shutdown = on_ctrl_c();
+    let width = file.tunnels.keys().map(String::len).max().unwrap_or_default();
+    let mut sessions = JoinSet::new();
+    for (index, (name, spec)) in file.tunnels.into_iter().enumerate() {
+        // Printed with every line for as long as the process runs
+        let prefix = format!("{} ", format!("{:<width$}", name).color(COLORS[index % COLORS.len()]).bold());
+        let prefix: &'static str = Box::leak(prefix.into_boxed_str());
+
+        println!("{}{} Forwarding to {}", prefix, "→".cyan(), spec.local_addr().to_string().cyan());
+        let local = LocalService::Http {
+            addr: spec.local_addr(),
+            host: spec.local_host.clone(),
+            tls: None,
+            recording: Default::default(),
+        };
+        let session = Session {
+            server: server.clone(),
+            token: token.clone(),
+            subdomain: spec.subdomain.clone(),
+            dialer: dialer.clone(),
+            remote_port: None,
+            share_key: None,
+            publish_manifest: false,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            path_mode: false,
+            aliases: spec.aliases.clone(),
+            service_name: None,
+            service_version: None,
+            max_retries,
+            forward_timeout: spec.forward_timeout(),
+            ping_interval,
+            keep_alive,
+            pause_schedule: None,
+            strict_clock,
+            log: RequestLog::new(quiet, &log_detail).prefixed(prefix),
+            show_qr: false,
+            print_examples: None,
+            stats: Arc::new(SessionStats::new()),
+            shutdown: shutdown.clone(),
+        };
+        sessions.spawn(async move { (name, session.run(local).await) });
+    }
+
+    // Sessions only end on errors that reconnecting won't fix, or on Ctrl+C
+    let mut failed = Vec::new();
+    while let Some(ended) = sessions.join_next().await {
+        let (name, result) = ended?;
+        let Err(e) = result else {
+            continue;
+        };
+        eprintln!("{} Tunnel {} stopped: {}", "✗".red(), name.bold(), e);
+        if fail_fast {
+            sessions.abort_all();
+            return Err(e.context(format!("Tunnel {} failed", name)));
+        }
+        failed.push(name);
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!("Every tunnel stopped ({})", failed.join(", "))
+}
@@ -0,0 +1,367 @@
+//! Serving a directory from the client itself (`expose --serve`), instead of
+//! forwarding to a local server
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::io::ReaderStream;
+use tracing::debug;
+
+use super::forwarder::RequestLog;
+
+type Body = BoxBody<Bytes, io::Error>;
+
+/// Characters escaped in directory listing links
+const HREF: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?').add(b'<').add(b'>');
+
+/// Content types by file extension; anything else is `application/octet-stream`
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// A directory to serve
+#[derive(Debug)]
+pub struct StaticFiles {
+    /// Canonical, so resolved paths can be checked against it
+    root: PathBuf,
+    dir_listing: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: &Path, dir_listing: bool) -> anyhow::Result<Self> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| anyhow::anyhow!("Can't serve {}: {}", root.display(), e))?;
+        if !root.is_dir() {
+            anyhow::bail!("Can't serve {}: not a directory", root.display());
+        }
+        Ok(Self { root, dir_listing })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The response for a request, by method and request-target
+    pub async fn respond(&self, method: &Method, target: &str) -> Response<Body> {
+        if method != Method::GET && method != Method::HEAD {
+            let mut response = text_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+            response.headers_mut().insert(ALLOW, "GET, HEAD".parse().expect("valid header"));
+            return response;
+        }
+
+        let path = target.split(['?', '#']).next().unwrap_or("/");
+        let Some(relative) = relative_path(path) else {
+            return text_response(StatusCode::FORBIDDEN, "Forbidden");
+        };
+        let Some(resolved) = self.resolve(&relative) else {
+            return text_response(StatusCode::NOT_FOUND, "Not Found");
+        };
+
+        let response = if resolved.is_dir() {
+            if !path.ends_with('/') {
+                // So relative links in the page resolve inside the directory
+                let mut response = text_response(StatusCode::MOVED_PERMANENTLY, "Moved Permanently");
+                let location = format!("{}/", path);
+                response.headers_mut().insert(LOCATION, location.parse().expect("valid header"));
+                return response;
+            }
+            let index = resolved.join("index.html");
+            if index.is_file() {
+                self.file(&index).await
+            } else if self.dir_listing {
+                self.listing(&resolved, path).await
+            } else {
+                text_response(StatusCode::NOT_FOUND, "Not Found")
+            }
+        } else {
+            self.file(&resolved).await
+        };
+
+        if method == Method::HEAD {
+            let (parts, _) = response.into_parts();
+            return Response::from_parts(parts, empty());
+        }
+        response
+    }
+
+    /// The file or directory at `relative`, if it exists inside the root (symlinks
+    /// pointing outside it don't count)
+    fn resolve(&self, relative: &Path) -> Option<PathBuf> {
+        let resolved = self.root.join(relative).canonicalize().ok()?;
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+
+    async fn file(&self, path: &Path) -> Response<Body> {
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                debug!("Failed to open {}: {}", path.display(), e);
+                return text_response(StatusCode::NOT_FOUND, "Not Found");
+            }
+        };
+        let len = match file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
+        };
+        let body = StreamBody::new(ReaderStream::new(file).map_ok(Frame::data));
+        Response::builder()
+            .header(CONTENT_TYPE, content_type(path))
+            .header(CONTENT_LENGTH, len)
+            .body(body.boxed())
+            .expect("valid response")
+    }
+
+    async fn listing(&self, dir: &Path, path: &str) -> Response<Body> {
+        let mut entries = Vec::new();
+        let mut read_dir = match tokio::fs::read_dir(dir).await {
+            Ok(read_dir) => read_dir,
+            Err(_) => return text_response(StatusCode::NOT_FOUND, "Not Found"),
+        };
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+            entries.push((!is_dir, entry.file_name().to_string_lossy().into_owned()));
+        }
+        // Directories first, then by name
+        entries.sort();
+
+        let title = html_escape(&percent_decode_str(path).decode_utf8_lossy());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+            title
+        );
+        if path != "/" {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for (is_file, name) in entries {
+            let suffix = if is_file { "" } else { "/" };
+            html.push_str(&format!(
+                "<li><a href=\"{}{}\">{}{}</a></li>\n",
+                utf8_percent_encode(&name, HREF),
+                suffix,
+                html_escape(&name),
+                suffix
+            ));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+
+        Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_LENGTH, html.len())
+            .body(Full::new(Bytes::from(html)).map_err(|never| match never {}).boxed())
+            .expect("valid response")
+    }
+}
+
+/// Serve the requests on a tunnel stream from `files`, logging each like forwarded ones
+pub async fn handle_static_stream<S>(stream: S, files: Arc<StaticFiles>, log: RequestLog)
+where
+    S: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+        let files = files.clone();
+        async move {
+            let start_time = Instant::now();
+            let target = request.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+            let response = files.respond(request.method(), &target).await;
+            let content_type = response.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+            let body_bytes = response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .unwrap_or(0);
+            log.request(
+                request.method().as_str(),
+                &target,
+                response.status().as_u16(),
+                start_time.elapsed(),
+                body_bytes,
+                content_type,
+            );
+            Ok::<_, io::Error>(response)
+        }
+    });
+
+    let io = hyper_util::rt::TokioIo::new(stream.compat());
+    if let Err(e) = hyper::server::conn::http1::Builder::new().serve_connection(io, service).await {
+        debug!("Static file connection ended: {}", e);
+    }
+}
+
+/// The request path as a path under the root, or None if it tries to leave it
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        // Backslashes and drive prefixes would be separators on Windows
+        if segment.contains(['\\', '\0']) {
+            return None;
+        }
+        match Path::new(segment).components().next() {
+            None | Some(Component::CurDir) => {}
+            Some(Component::Normal(part)) if Path::new(segment).components().count() == 1 => relative.push(part),
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+fn text_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(CONTENT_LENGTH, message.len())
+        .body(Full::new(Bytes::from_static(message.as_bytes())).map_err(|never| match never {}).boxed())
+        .expect("valid response")
+}
+
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body_text(response: Response<Body>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn site(dir_listing: bool) -> (StaticFiles, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("loophole-serve-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>home</h1>").unwrap();
+        std::fs::write(dir.join("assets/app.js"), "console.log(1)").unwrap();
+        std::fs::write(dir.join("assets/a <b>.txt"), "odd name").unwrap();
+        std::fs::write(dir.join("docs/guide.md"), "# guide").unwrap();
+        (StaticFiles::new(&dir, dir_listing).unwrap(), dir)
+    }
+
+    #[test]
+    fn test_relative_path_rejects_traversal() {
+        assert_eq!(relative_path("/"), Some(PathBuf::new()));
+        assert_eq!(relative_path("/a/./b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(relative_path("/a%20b.txt"), Some(PathBuf::from("a b.txt")));
+        for path in ["/../etc/passwd", "/a/../../x", "/%2e%2e/x", "/a%2f..%2f..%2fx", "/a\\..\\x", "/a%00"] {
+            assert_eq!(relative_path(path), None, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serves_files_and_index() {
+        let (files, dir) = site(false);
+
+        let response = files.respond(&Method::GET, "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body_text(response).await, "<h1>home</h1>");
+
+        let response = files.respond(&Method::GET, "/assets/app.js?v=2").await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/javascript; charset=utf-8");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "14");
+        assert_eq!(body_text(response).await, "console.log(1)");
+
+        let response = files.respond(&Method::HEAD, "/docs/guide.md").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "7");
+        assert_eq!(body_text(response).await, "");
+
+        let response = files.respond(&Method::GET, "/docs").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[LOCATION], "/docs/");
+
+        assert_eq!(files.respond(&Method::GET, "/missing.html").await.status(), StatusCode::NOT_FOUND);
+        // No index.html and listings are off
+        assert_eq!(files.respond(&Method::GET, "/docs/").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(files.respond(&Method::GET, "/../secret").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(files.respond(&Method::POST, "/").await.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinks_out_of_root_are_not_served() {
+        let (files, dir) = site(false);
+        let outside = std::env::temp_dir().join(format!("loophole-secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&outside, "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link.txt")).unwrap();
+
+        assert_eq!(files.respond(&Method::GET, "/link.txt").await.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_directory_listing() {
+        let (files, dir) = site(true);
+
+        let response = files.respond(&Method::GET, "/assets/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let html = body_text(response).await;
+        assert!(html.contains("<title>Index of /assets/</title>"), "{}", html);
+        assert!(html.contains("<a href=\"../\">../</a>"), "{}", html);
+        assert!(html.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"), "{}", html);
+        assert!(html.find("a%20").unwrap() < html.find("app.js").unwrap());
+
+        // index.html still wins over a listing
+        assert_eq!(body_text(files.respond(&Method::GET, "/").await).await, "<h1>home</h1>");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
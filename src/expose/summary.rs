@@ -0,0 +1,287 @@
+//! What a session did, printed when it's stopped with Ctrl+C, and the bandwidth
+//! thresholds (`--warn-at`, `--stop-at`) checked as it goes
+
+use colored::Colorize;
+use futures::io::{AsyncRead, AsyncWrite};
+use std::io;
+use std::pin::Pin;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::units::format_size;
+use crate::proto::Protocol;
+use crate::units::format_duration;
+
+/// Counted across reconnects, from when the session started
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    /// Tunnel streams served: one per request, or per connection for TCP tunnels
+    streams: AtomicU64,
+    /// Read from the tunnel (requests)
+    bytes_in: AtomicU64,
+    /// Written to the tunnel (responses)
+    bytes_out: AtomicU64,
+    limits: BandwidthLimits,
+    /// Set once `warn_at` is reached
+    warned: AtomicBool,
+    /// Set while the tunnel is paused at `stop_at`
+    stopped: AtomicBool,
+    /// Set once the user has chosen to continue past `stop_at`, which then no longer applies
+    resumed: AtomicBool,
+    /// Woken when a threshold is reached
+    crossed: Notify,
+}
+
+/// Thresholds on the bytes a session transfers, received and sent together
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BandwidthLimits {
+    /// Print a warning once this much has been transferred
+    pub warn_at: Option<u64>,
+    /// Stop forwarding once this much has been transferred, until the user continues
+    pub stop_at: Option<u64>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            streams: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            limits: BandwidthLimits::default(),
+            warned: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            resumed: AtomicBool::new(false),
+            crossed: Notify::new(),
+        }
+    }
+
+    pub fn with_limits(self, limits: BandwidthLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Count a tunnel stream, and the bytes that pass through it
+    pub fn count<S>(self: &Arc<Self>, stream: S) -> Counted<S> {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        Counted {
+            inner: stream,
+            stats: self.clone(),
+        }
+    }
+
+    pub fn streams(&self) -> u64 {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from and sent to the tunnel so far
+    pub fn transferred(&self) -> (u64, u64) {
+        (self.bytes_in.load(Ordering::Relaxed), self.bytes_out.load(Ordering::Relaxed))
+    }
+
+    /// Whether the tunnel is paused at `stop_at`: streams are answered without
+    /// reaching the local service
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Continue past `stop_at` for the rest of the session. Returns whether the
+    /// tunnel was stopped.
+    pub fn resume(&self) -> bool {
+        self.resumed.store(true, Ordering::Relaxed);
+        self.stopped.swap(false, Ordering::Relaxed)
+    }
+
+    /// Wait until a threshold is reached
+    pub async fn crossed(&self) {
+        self.crossed.notified().await
+    }
+
+    /// Note that `n` more bytes were transferred, checking the thresholds
+    fn add(&self, counter: &AtomicU64, n: usize) {
+        if n == 0 {
+            return;
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        let (bytes_in, bytes_out) = self.transferred();
+        let total = bytes_in + bytes_out;
+        let reached = |limit: Option<u64>| limit.is_some_and(|limit| total >= limit);
+        let warn = reached(self.limits.warn_at) && !self.warned.swap(true, Ordering::Relaxed);
+        let stop = reached(self.limits.stop_at)
+            && !self.resumed.load(Ordering::Relaxed)
+            && !self.stopped.swap(true, Ordering::Relaxed);
+        if warn || stop {
+            self.crossed.notify_one();
+        }
+    }
+
+    /// The running totals, e.g. `↓ 1.2MB ↑ 48.0MB`
+    fn totals(&self) -> String {
+        let (bytes_in, bytes_out) = self.transferred();
+        format!("↓ {} ↑ {}", format_size(bytes_in), format_size(bytes_out))
+    }
+
+    pub fn print(&self, prefix: &str, url: Option<&str>, protocol: Protocol) {
+        let label = match protocol {
+            Protocol::Http => "Requests",
+            Protocol::Tcp => "Connections",
+        };
+        println!("{}{} Disconnected", prefix, "✓".green());
+        if let Some(url) = url {
+            println!("{}  {:<12} {}", prefix, "Tunnel URL", url);
+        }
+        let duration = Duration::from_secs(self.started.elapsed().as_secs());
+        println!("{}  {:<12} {}", prefix, "Duration", format_duration(duration));
+        println!("{}  {:<12} {}", prefix, label, self.streams());
+        println!(
+            "{}  {:<12} {} received, {} sent",
+            prefix,
+            "Transferred",
+            format_size(self.bytes_in.load(Ordering::Relaxed)),
+            format_size(self.bytes_out.load(Ordering::Relaxed))
+        );
+    }
+}
+
+/// Announce thresholds as they're reached, and keep the running totals in the
+/// terminal's title (shown in its tab or status line) while stdout is a terminal.
+/// Runs until the session ends.
+pub async fn watch(stats: Arc<SessionStats>, prefix: &'static str) {
+    let title = std::io::stdout().is_terminal();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut shown = None;
+    let mut warned = false;
+    loop {
+        tokio::select! {
+            _ = tick.tick(), if title => {
+                let totals = stats.totals();
+                if shown.as_ref() != Some(&totals) {
+                    set_title(&format!("loophole {}", totals));
+                    shown = Some(totals);
+                }
+            }
+            _ = stats.crossed() => {
+                let (bytes_in, bytes_out) = stats.transferred();
+                let total = format_size(bytes_in + bytes_out);
+                if stats.warned.load(Ordering::Relaxed) && !warned {
+                    warned = true;
+                    eprintln!("{}{} {} transferred this session ({})", prefix, "!".yellow(), total, stats.totals());
+                }
+                if stats.stopped() {
+                    eprintln!(
+                        "{}{} Stopped forwarding at {} transferred (--stop-at); visitors get 503 until you enter {} to continue",
+                        prefix,
+                        "!".yellow(),
+                        total,
+                        "c".bold()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Put the terminal's title back, once the session has ended
+pub fn reset_title() {
+    if std::io::stdout().is_terminal() {
+        set_title("");
+    }
+}
+
+fn set_title(title: &str) {
+    print!("\x1b]2;{}\x07", title);
+    std::io::Write::flush(&mut std::io::stdout()).ok();
+}
+
+/// A tunnel stream that adds what passes through it to the session's totals
+pub struct Counted<S> {
+    inner: S,
+    stats: Arc<SessionStats>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.stats.add(&self.stats.bytes_in, n);
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.stats.add(&self.stats.bytes_out, n);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    #[tokio::test]
+    async fn test_counts_streams_and_bytes() {
+        let stats = Arc::new(SessionStats::new());
+
+        let mut first = stats.count(Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()));
+        let mut request = Vec::new();
+        first.read_to_end(&mut request).await.unwrap();
+        first.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+
+        let mut second = stats.count(Cursor::new(Vec::new()));
+        second.write_all(b"hello").await.unwrap();
+
+        assert_eq!(stats.streams.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.bytes_in.load(Ordering::Relaxed), 18);
+        assert_eq!(stats.bytes_out.load(Ordering::Relaxed), 19 + 5);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_limits() {
+        let stats = Arc::new(SessionStats::new().with_limits(BandwidthLimits {
+            warn_at: Some(10),
+            stop_at: Some(20),
+        }));
+        let crossed = || tokio::time::timeout(Duration::from_millis(50), stats.crossed());
+        let mut stream = stats.count(Cursor::new(Vec::new()));
+
+        stream.write_all(b"hello").await.unwrap();
+        assert!(crossed().await.is_err());
+
+        // Received and sent count together
+        let mut request = Vec::new();
+        stats.count(Cursor::new(b"world".to_vec())).read_to_end(&mut request).await.unwrap();
+        crossed().await.unwrap();
+        assert!(stats.warned.load(Ordering::Relaxed));
+        assert!(!stats.stopped());
+
+        stream.write_all(b"0123456789").await.unwrap();
+        crossed().await.unwrap();
+        assert!(stats.stopped());
+        assert_eq!(stats.transferred(), (5, 15));
+
+        // Continuing lifts the limit for the rest of the session
+        assert!(stats.resume());
+        stream.write_all(b"0123456789").await.unwrap();
+        assert!(!stats.stopped());
+        assert!(crossed().await.is_err());
+        assert!(!stats.resume());
+    }
+}
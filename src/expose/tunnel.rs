@@ -1,173 +1,350 @@
 use anyhow::Result;
-use bytes::{Buf, Bytes};
-use futures::io::{AsyncRead, AsyncWrite};
-use futures::{Sink, Stream};
-use std::collections::VecDeque;
-use std::io;
-use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio_tungstenite::tungstenite::Message;
+use std::net::SocketAddr;
+use colored::Colorize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use yamux::{Connection, Mode};
 
-use super::forwarder::handle_tunnel_stream;
+use super::echo::{self, Peeked};
+use super::forwarder::{handle_stopped_stream, handle_tcp_stream, handle_tunnel_stream, Recording, RequestLog};
+use super::local_tls::LocalTls;
+use super::static_files::{handle_static_stream, StaticFiles};
+use super::summary::SessionStats;
+use crate::proto::compat::{ClientCompat, ControlChannel};
+use crate::proto::transport::{MAX_WS_FRAME_SIZE, MAX_WS_MESSAGE_SIZE, MISSED_PINGS};
+use crate::proto::{ClientMessage, Protocol, ServerMessage};
 
-/// Wrapper to make WebSocket stream implement futures AsyncRead + AsyncWrite
-pub struct WsCompat<S> {
-    inner: S,
-    read_buffer: VecDeque<Bytes>,
-    closed: bool,
+/// WebSocket limits for the client end, matching the server's
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_WS_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_WS_FRAME_SIZE),
+        ..Default::default()
+    }
+}
+
+/// Where tunnel streams are served from
+#[derive(Clone)]
+pub enum LocalService {
+    /// Forward HTTP to a local server, over TLS if `tls` is set, with `host` as the Host header if set.
+    /// Requests are kept as `recording` asks.
+    Http {
+        addr: SocketAddr,
+        host: Option<String>,
+        tls: Option<LocalTls>,
+        recording: Recording,
+    },
+    /// Copy raw TCP to a local port
+    Tcp { addr: SocketAddr },
+    /// Serve files from a directory in this process
+    Files(Arc<StaticFiles>),
 }
 
-impl<S> WsCompat<S> {
-    pub fn new(inner: S) -> Self {
-        Self {
-            inner,
-            read_buffer: VecDeque::new(),
-            closed: false,
+impl LocalService {
+    /// What to register the tunnel as
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            LocalService::Tcp { .. } => Protocol::Tcp,
+            LocalService::Http { .. } | LocalService::Files(_) => Protocol::Http,
         }
     }
 }
 
-impl<S> Unpin for WsCompat<S> {}
-
-impl<S> AsyncRead for WsCompat<S>
-where
-    S: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
-{
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut [u8],
-    ) -> Poll<io::Result<usize>> {
-        // First try buffer
-        if let Some(mut data) = self.read_buffer.pop_front() {
-            let len = std::cmp::min(data.len(), buf.len());
-            buf[..len].copy_from_slice(&data[..len]);
-            data.advance(len);
-            if !data.is_empty() {
-                self.read_buffer.push_front(data);
-            }
-            return Poll::Ready(Ok(len));
-        }
+/// How long in-flight requests get to finish after Ctrl+C
+const DISCONNECT_DRAIN: Duration = Duration::from_secs(5);
 
-        if self.closed {
-            return Poll::Ready(Ok(0));
-        }
+/// Why a tunnel connection ended, when it wasn't an error
+#[derive(Debug, PartialEq, Eq)]
+pub enum TunnelEnd {
+    Closed,
+    /// The server is shutting down or closed the tunnel (e.g. an admin disconnected
+    /// it), with its message
+    ServerShutdown(String),
+    /// `shutdown` was triggered: the server was told and in-flight requests finished
+    Disconnected,
+}
+
+/// Serve tunnel streams until the connection closes. Pings the server every
+/// `ping_interval` and gives up on it after `MISSED_PINGS` intervals of silence.
+/// With `keep_alive`, idle warnings are answered with a keep-alive ping. TCP tunnel
+/// streams are copied to the local port as they are, without any HTTP handling.
+/// While `stats` is stopped at its bandwidth limit, streams are answered without
+/// reaching the local service. Once `shutdown` is cancelled the server is sent a
+/// Disconnect, so it stops sending requests, and the connection is closed when the
+/// ones in flight are done.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_tunnel(
+    ws: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    local: LocalService,
+    forward_timeout: Duration,
+    ping_interval: Duration,
+    keep_alive: bool,
+    log: RequestLog,
+    stats: Arc<SessionStats>,
+    shutdown: CancellationToken,
+) -> Result<TunnelEnd> {
+    let (compat, ControlChannel { tx: control_tx, rx: mut server_messages }) = ClientCompat::with_control(ws);
+    let last_heard = compat.last_heard();
+    let config = yamux::Config::default();
+    let mut connection = Connection::new(compat, config, Mode::Client);
+
+    let mut ping = tokio::time::interval(ping_interval);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let ping_timeout = ping_interval * MISSED_PINGS;
+    let mut shutdown_message = None;
+    let streams = TaskTracker::new();
+    let drain = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(drain);
+    let mut disconnecting = false;
+
+    tracing::debug!("Tunnel established, waiting for requests...");
 
-        let inner = Pin::new(&mut self.inner);
-        match inner.poll_next(cx) {
-            Poll::Ready(Some(Ok(Message::Binary(data)))) => {
-                let data = Bytes::from(data);
-                let len = std::cmp::min(data.len(), buf.len());
-                buf[..len].copy_from_slice(&data[..len]);
-                if len < data.len() {
-                    self.read_buffer.push_back(data.slice(len..));
+    // Accept incoming streams from server using poll_next_inbound
+    loop {
+        tokio::select! {
+            result = std::future::poll_fn(|cx| connection.poll_next_inbound(cx)) => match result {
+                Some(Ok(stream)) => {
+                    let local = local.clone();
+                    let stats = stats.clone();
+                    streams.spawn(async move {
+                        // The server's round-trip probes come on HTTP tunnels' streams too,
+                        // and are answered outside the streams tracked here
+                        let http = local.protocol() == Protocol::Http;
+                        let stream = if http {
+                            let Some(stream) = echo::dispatch(stream).await else {
+                                return;
+                            };
+                            stream
+                        } else {
+                            Peeked::new(stream)
+                        };
+                        // Stopped at --stop-at: turned away without adding to the totals
+                        if stats.stopped() {
+                            return handle_stopped_stream(stream, http).await;
+                        }
+                        let stream = stats.count(stream);
+                        match local {
+                            LocalService::Http { addr, host, tls, recording } => {
+                                handle_tunnel_stream(stream, addr, host, tls, recording, forward_timeout, log).await
+                            }
+                            LocalService::Tcp { addr } => handle_tcp_stream(stream, addr, forward_timeout, log).await,
+                            LocalService::Files(files) => handle_static_stream(stream, files, log).await,
+                        }
+                    });
                 }
-                Poll::Ready(Ok(len))
-            }
-            Poll::Ready(Some(Ok(Message::Close(_)))) => {
-                self.closed = true;
-                Poll::Ready(Ok(0))
-            }
-            Poll::Ready(Some(Ok(_))) => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
-            Poll::Ready(Some(Err(e))) => {
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
-            }
-            Poll::Ready(None) => {
-                self.closed = true;
-                Poll::Ready(Ok(0))
+                Some(Err(e)) => {
+                    tracing::error!("Yamux error: {}", e);
+                    break;
+                }
+                None => {
+                    tracing::debug!("Connection closed");
+                    break;
+                }
+            },
+
+            // Keeps NAT mappings and load balancers from dropping an idle tunnel, and
+            // notices a dead server before the next request would
+            _ = ping.tick() => {
+                let silent_for = last_heard.lock().map(|t| t.elapsed()).unwrap_or_default();
+                if silent_for > ping_timeout {
+                    anyhow::bail!("Server stopped responding (silent for {}s)", silent_for.as_secs());
+                }
+                let _ = control_tx.send(ClientMessage::Ping { keep_alive: false });
             }
-            Poll::Pending => Poll::Pending,
-        }
-    }
-}
 
-impl<S> AsyncWrite for WsCompat<S>
-where
-    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
-{
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        let inner = Pin::new(&mut self.inner);
-        match inner.poll_ready(cx) {
-            Poll::Ready(Ok(())) => {
-                let data = buf.to_vec();
-                let len = data.len();
-                let inner = Pin::new(&mut self.inner);
-                match inner.start_send(Message::Binary(data)) {
-                    Ok(()) => Poll::Ready(Ok(len)),
-                    Err(e) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string()))),
+            Some(message) = server_messages.recv() => match message {
+                ServerMessage::Shutdown { message } => {
+                    tracing::debug!("Server is shutting down: {}", message);
+                    shutdown_message = Some(message);
+                }
+                ServerMessage::IdleWarning { disconnect_in_secs } => {
+                    if keep_alive {
+                        tracing::debug!("Tunnel idle, sending keep-alive");
+                        let _ = control_tx.send(ClientMessage::Ping { keep_alive: true });
+                    } else {
+                        println!(
+                            "{}{} Tunnel idle; the server will disconnect it in about {}s unless it's used (see --keep-alive)",
+                            log.prefix(),
+                            "!".yellow(),
+                            disconnect_in_secs
+                        );
+                    }
+                }
+                ServerMessage::Throttled { until_ms } => {
+                    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+                    let remaining_ms = until_ms.saturating_sub(now_ms).max(1000);
+                    println!(
+                        "{}{} Visitors are over the tunnel's request rate; the server is refusing some with 429 (for at least {})",
+                        log.prefix(),
+                        "!".yellow(),
+                        crate::units::format_duration(Duration::from_millis(remaining_ms))
+                    );
                 }
+                _ => {}
+            },
+
+            _ = shutdown.cancelled(), if !disconnecting => {
+                tracing::debug!("Disconnecting, waiting for {} in-flight streams", streams.len());
+                let _ = control_tx.send(ClientMessage::Disconnect);
+                streams.close();
+                drain.as_mut().reset(tokio::time::Instant::now() + DISCONNECT_DRAIN);
+                disconnecting = true;
             }
-            Poll::Ready(Err(e)) => {
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
+
+            _ = streams.wait(), if disconnecting => break,
+
+            _ = &mut drain, if disconnecting => {
+                tracing::debug!("Closing with {} streams still in flight", streams.len());
+                break;
             }
-            Poll::Pending => Poll::Pending,
         }
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let inner = Pin::new(&mut self.inner);
-        match inner.poll_flush(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => {
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
-            }
-            Poll::Pending => Poll::Pending,
+    if disconnecting {
+        if let Err(e) = std::future::poll_fn(|cx| connection.poll_close(cx)).await {
+            tracing::debug!("Error closing tunnel: {}", e);
         }
+        return Ok(TunnelEnd::Disconnected);
     }
 
-    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let inner = Pin::new(&mut self.inner);
-        match inner.poll_close(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => {
-                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
-            }
-            Poll::Pending => Poll::Pending,
+    // The shutdown notice may have arrived just before the connection closed
+    while let Ok(message) = server_messages.try_recv() {
+        if let ServerMessage::Shutdown { message } = message {
+            shutdown_message = Some(message);
         }
     }
+    Ok(match shutdown_message {
+        Some(message) => TunnelEnd::ServerShutdown(message),
+        None => TunnelEnd::Closed,
+    })
 }
 
-pub async fn run_tunnel(
-    ws: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-    local_addr: std::net::SocketAddr,
-    local_host: Option<String>,
-    forward_timeout: std::time::Duration,
-    quiet: bool,
-) -> Result<()> {
-    let compat = WsCompat::new(ws);
-    let config = yamux::Config::default();
-    let mut connection = Connection::new(compat, config, Mode::Client);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::Message;
 
-    tracing::debug!("Tunnel established, waiting for requests...");
+    /// Drive a yamux connection in the background until it closes
+    fn drive<T>(mut connection: Connection<T>) -> tokio::task::JoinHandle<()>
+    where
+        T: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            while let Some(Ok(_)) = std::future::poll_fn(|cx| connection.poll_next_inbound(cx)).await {}
+        })
+    }
 
-    // Accept incoming streams from server using poll_next_inbound
-    loop {
-        let result = std::future::poll_fn(|cx| connection.poll_next_inbound(cx)).await;
-        match result {
-            Some(Ok(stream)) => {
-                let local_host = local_host.clone();
-                tokio::spawn(async move {
-                    handle_tunnel_stream(stream, local_addr, local_host, forward_timeout, quiet).await;
-                });
-            }
-            Some(Err(e)) => {
-                tracing::error!("Yamux error: {}", e);
-                break;
-            }
-            None => {
-                tracing::debug!("Connection closed");
-                break;
+    #[tokio::test]
+    async fn test_large_response_with_small_frame_budget() {
+        const RESPONSE_SIZE: usize = 50 * 1024 * 1024;
+        const PAYLOAD: usize = 8 * 1024;
+
+        // A frame/message budget far below the response size: without chunked writes
+        // yamux frames would exceed it and the transfer would fail
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(PAYLOAD),
+            max_frame_size: Some(PAYLOAD),
+            ..Default::default()
+        };
+
+        let (server_io, client_io) = tokio::io::duplex(256 * 1024);
+        let server_ws = WebSocketStream::from_raw_socket(server_io, Role::Server, Some(ws_config)).await;
+        let client_ws = WebSocketStream::from_raw_socket(client_io, Role::Client, Some(ws_config)).await;
+
+        let mut server = Connection::new(
+            crate::proto::compat::ServerCompat::with_max_payload(server_ws, PAYLOAD),
+            yamux::Config::default(),
+            Mode::Server,
+        );
+        let mut client = Connection::new(
+            ClientCompat::with_max_payload(client_ws, PAYLOAD),
+            yamux::Config::default(),
+            Mode::Client,
+        );
+
+        let reader = tokio::spawn(async move {
+            let mut stream = std::future::poll_fn(|cx| client.poll_next_inbound(cx))
+                .await
+                .unwrap()
+                .unwrap();
+            let driver = drive(client);
+            let mut received = 0usize;
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                assert!(buf[..n].iter().all(|&b| b == 0xab));
+                received += n;
             }
+            driver.abort();
+            received
+        });
+
+        let mut stream = std::future::poll_fn(|cx| server.poll_new_outbound(cx)).await.unwrap();
+        let driver = drive(server);
+        let chunk = vec![0xabu8; 256 * 1024];
+        for _ in 0..(RESPONSE_SIZE / chunk.len()) {
+            stream.write_all(&chunk).await.unwrap();
         }
+        stream.close().await.unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(120), reader)
+            .await
+            .expect("transfer timed out")
+            .unwrap();
+        assert_eq!(received, RESPONSE_SIZE);
+        driver.abort();
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_pings_and_gives_up_on_silent_server() {
+        // A server that accepts the tunnel, then never sends anything back
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            let mut pings = 0;
+            while let Some(Ok(msg)) = futures::StreamExt::next(&mut ws).await {
+                if let Message::Text(text) = msg {
+                    if matches!(ClientMessage::from_json(&text), Ok(ClientMessage::Ping { keep_alive: false })) {
+                        pings += 1;
+                    }
+                }
+            }
+            pings
+        });
+
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let local_addr = "127.0.0.1:9".parse().unwrap();
+        let interval = Duration::from_millis(100);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_tunnel(
+                ws,
+                LocalService::Http { addr: local_addr, host: None, tls: None, recording: Recording::default() },
+                Duration::from_secs(1),
+                interval,
+                false,
+                RequestLog::new(true, &[]),
+                Arc::new(SessionStats::new()),
+                CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("client kept a dead tunnel");
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("stopped responding"), "{}", err);
+
+        let pings = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(pings >= MISSED_PINGS, "sent {} pings", pings);
+    }
 }
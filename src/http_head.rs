@@ -0,0 +1,461 @@
+//! HTTP/1.1 message heads as they cross the tunnel: finding where one ends as bytes
+//! arrive, and parsing it with httparse within fixed limits. The server's proxy reads
+//! response heads with it, and the client's forwarder and replay read request heads.
+
+use std::fmt;
+
+/// Most header fields a head may have
+pub const MAX_HEADERS: usize = 100;
+
+/// Largest head, start line and blank line included. A request's line has a limit of
+/// its own on top, so a long URL doesn't leave less room for header fields.
+pub const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Longest request line, CRLF included
+pub const MAX_REQUEST_LINE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadError {
+    /// No end within [`MAX_HEAD_BYTES`]
+    TooLarge,
+    /// A request line longer than [`MAX_REQUEST_LINE_BYTES`]
+    LineTooLong,
+    /// More than [`MAX_HEADERS`] header fields
+    TooManyHeaders,
+    /// Not an HTTP/1.x head, or a field (such as Content-Length) that can't be read
+    Invalid,
+}
+
+impl fmt::Display for HeadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeadError::TooLarge => write!(f, "head larger than {}KB", MAX_HEAD_BYTES / 1024),
+            HeadError::LineTooLong => write!(f, "request line longer than {}KB", MAX_REQUEST_LINE_BYTES / 1024),
+            HeadError::TooManyHeaders => write!(f, "more than {} header fields", MAX_HEADERS),
+            HeadError::Invalid => f.write_str("malformed head"),
+        }
+    }
+}
+
+impl std::error::Error for HeadError {}
+
+/// Finds the end of the head at the start of a buffer that grows with each read,
+/// looking at each byte once however small the reads
+#[derive(Debug, Default, Clone)]
+pub struct HeadScanner {
+    scanned: usize,
+    /// Set for request heads, whose line is limited apart from the header fields
+    max_line: Option<usize>,
+    /// The start line's length, CRLF included, once found
+    line_len: Option<usize>,
+}
+
+impl HeadScanner {
+    /// A scanner for request heads, allowing a request line of up to
+    /// [`MAX_REQUEST_LINE_BYTES`] and [`MAX_HEAD_BYTES`] after it
+    pub fn for_requests() -> Self {
+        Self {
+            max_line: Some(MAX_REQUEST_LINE_BYTES),
+            ..Self::default()
+        }
+    }
+
+    /// The most the head may take up, as far as it's been scanned
+    fn limit(&self) -> usize {
+        match (self.max_line, self.line_len) {
+            (None, _) => MAX_HEAD_BYTES,
+            (Some(max_line), None) => max_line,
+            (Some(_), Some(line_len)) => line_len + MAX_HEAD_BYTES,
+        }
+    }
+
+    fn too_large(&self) -> HeadError {
+        match (self.max_line, self.line_len) {
+            (Some(_), None) => HeadError::LineTooLong,
+            _ => HeadError::TooLarge,
+        }
+    }
+
+    /// The length of the head at the start of `buf`, blank line included, once it's all
+    /// there. Between calls `buf` may only grow, or lose a head found by the last call
+    /// from its front.
+    pub fn scan(&mut self, buf: &[u8]) -> Result<Option<usize>, HeadError> {
+        let mut from = self.scanned.min(buf.len());
+        while let Some(offset) = buf[from..].iter().position(|&byte| byte == b'\n') {
+            let lf = from + offset;
+            if let (Some(max_line), None) = (self.max_line, self.line_len) {
+                if lf + 1 > max_line {
+                    return Err(HeadError::LineTooLong);
+                }
+                self.line_len = Some(lf + 1);
+            }
+            // A blank line: "\r\n" or, leniently, a bare "\n" after the last line's end
+            let blank = lf > 0 && (buf[lf - 1] == b'\n' || (lf > 1 && buf[lf - 1] == b'\r' && buf[lf - 2] == b'\n'));
+            if blank {
+                let limit = self.limit();
+                self.scanned = 0;
+                self.line_len = None;
+                return match lf + 1 {
+                    len if len > limit => Err(HeadError::TooLarge),
+                    len => Ok(Some(len)),
+                };
+            }
+            from = lf + 1;
+        }
+        self.scanned = buf.len();
+        if buf.len() > self.limit() {
+            return Err(self.too_large());
+        }
+        Ok(None)
+    }
+}
+
+/// The length of the request head at the start of `buf`, if it's all there
+pub fn request_head_len(buf: &[u8]) -> Option<usize> {
+    HeadScanner::for_requests().scan(buf).ok().flatten()
+}
+
+/// Header fields in the order they came, names as sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    fn from_parsed(parsed: &[httparse::Header<'_>]) -> Self {
+        Self(
+            parsed
+                .iter()
+                .map(|header| {
+                    // An obsolete line folding is a space, as RFC 9112 says to read it
+                    let value = header
+                        .value
+                        .iter()
+                        .map(|&byte| if byte == b'\r' || byte == b'\n' { b' ' } else { byte })
+                        .collect();
+                    (header.name.to_string(), value)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    fn values<'a: 'n, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = &'a [u8]> + 'n {
+        self.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values(name).next().is_some()
+    }
+
+    /// The first value of `name`, trimmed, if it's text
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.values(name).next().and_then(|value| std::str::from_utf8(value).ok()).map(str::trim)
+    }
+
+    /// The comma-separated items of every `name` field, trimmed
+    fn list<'a: 'n, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = &'a str> + 'n {
+        self.values(name)
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+    }
+
+    /// Content-Length, if sent. Repeats must agree, as a body can't have two lengths.
+    pub fn content_length(&self) -> Result<Option<u64>, HeadError> {
+        let mut length = None;
+        for item in self.list("content-length") {
+            let value = item.parse::<u64>().map_err(|_| HeadError::Invalid)?;
+            if length.is_some_and(|length| length != value) {
+                return Err(HeadError::Invalid);
+            }
+            length = Some(value);
+        }
+        Ok(length)
+    }
+
+    /// Whether the body is chunked, which it is when chunked is the last transfer coding
+    pub fn is_chunked(&self) -> bool {
+        self.list("transfer-encoding").last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    }
+
+    /// The options of every Connection field
+    pub fn connection(&self) -> Connection {
+        Connection(self.list("connection").map(str::to_ascii_lowercase).collect())
+    }
+
+    /// Replace every `name` field with one holding `value`, where the first was
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.0.iter().position(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(first) => {
+                self.0[first] = (name.to_string(), value.as_bytes().to_vec());
+                let mut index = 0;
+                self.0.retain(|(n, _)| {
+                    index += 1;
+                    index - 1 == first || !n.eq_ignore_ascii_case(name)
+                });
+            }
+            None => self.0.push((name.to_string(), value.as_bytes().to_vec())),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in self.iter() {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// A head's Connection options, lowercased: `close`, `keep-alive` or `upgrade`, or
+/// the names of fields meant only for the next hop
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Connection(Vec<String>);
+
+impl Connection {
+    pub fn options(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    /// The request target, usually a path and query
+    pub target: String,
+    /// The minor version: 1 for HTTP/1.1
+    pub version: u8,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// The head as sent on the wire, with CRLF line endings
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} HTTP/1.{}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    /// The minor version: 1 for HTTP/1.1
+    pub version: u8,
+    pub status: u16,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// A 1xx other than 101 Switching Protocols, which the real response follows
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+}
+
+fn complete(status: httparse::Result<usize>) -> Result<(), HeadError> {
+    match status {
+        Ok(httparse::Status::Complete(_)) => Ok(()),
+        Ok(httparse::Status::Partial) => Err(HeadError::Invalid),
+        Err(httparse::Error::TooManyHeaders) => Err(HeadError::TooManyHeaders),
+        Err(_) => Err(HeadError::Invalid),
+    }
+}
+
+/// Parse a whole request head, as found by [`HeadScanner::for_requests`]
+pub fn parse_request(head: &[u8]) -> Result<RequestHead, HeadError> {
+    if head.len() > MAX_REQUEST_LINE_BYTES + MAX_HEAD_BYTES {
+        return Err(HeadError::TooLarge);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    complete(request.parse(head))?;
+    Ok(RequestHead {
+        method: request.method.unwrap_or_default().to_string(),
+        target: request.path.unwrap_or_default().to_string(),
+        version: request.version.unwrap_or(1),
+        headers: Headers::from_parsed(request.headers),
+    })
+}
+
+/// Parse a whole response head, as found by [`HeadScanner`]. Obsolete line folding,
+/// which some old servers still send, is read as a space.
+pub fn parse_response(head: &[u8]) -> Result<ResponseHead, HeadError> {
+    if head.len() > MAX_HEAD_BYTES {
+        return Err(HeadError::TooLarge);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let mut config = httparse::ParserConfig::default();
+    config.allow_obsolete_multiline_headers_in_responses(true);
+    complete(config.parse_response(&mut response, head))?;
+    Ok(ResponseHead {
+        version: response.version.unwrap_or(1),
+        status: response.code.unwrap_or_default(),
+        headers: Headers::from_parsed(response.headers),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head_len(buf: &[u8]) -> Option<usize> {
+        HeadScanner::default().scan(buf).ok().flatten()
+    }
+
+    /// Feed `data` to a scanner `step` bytes at a time
+    fn scan_in_steps(data: &[u8], step: usize) -> Result<Option<usize>, HeadError> {
+        scan_in_steps_with(HeadScanner::default(), data, step)
+    }
+
+    fn scan_in_steps_with(mut scanner: HeadScanner, data: &[u8], step: usize) -> Result<Option<usize>, HeadError> {
+        let mut buf = Vec::new();
+        for piece in data.chunks(step) {
+            buf.extend_from_slice(piece);
+            if let Some(len) = scanner.scan(&buf)? {
+                return Ok(Some(len));
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn test_scan() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\nbody";
+        for step in 1..=request.len() {
+            assert_eq!(scan_in_steps(request, step), Ok(Some(27)), "{} bytes at a time", step);
+        }
+        assert_eq!(head_len(b"HTTP/1.0 200 OK\nServer: old\n\nbody"), Some(29));
+        assert_eq!(head_len(b"HTTP/1.1 200 OK\r\nServer: x\r\n"), None);
+        // A blank line needs its CR and LF together, or a bare LF
+        assert_eq!(head_len(b"HTTP/1.1 200 OK\r\nServer: x\r\r\r\n"), None);
+    }
+
+    #[test]
+    fn test_scan_after_draining_a_head() {
+        let mut scanner = HeadScanner::default();
+        let mut buf = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200".to_vec();
+        let len = scanner.scan(&buf).unwrap().unwrap();
+        assert!(parse_response(&buf[..len]).unwrap().is_interim());
+        buf.drain(..len);
+        assert_eq!(scanner.scan(&buf), Ok(None));
+        buf.extend_from_slice(b" OK\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(scanner.scan(&buf), Ok(Some(buf.len())));
+    }
+
+    #[test]
+    fn test_scan_limits() {
+        let mut huge = b"GET / HTTP/1.1\r\n".to_vec();
+        while huge.len() <= MAX_HEAD_BYTES {
+            huge.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        assert_eq!(scan_in_steps(&huge, 4096), Err(HeadError::TooLarge));
+        // Also when the end arrives in the same read that passes the limit
+        huge.extend_from_slice(b"\r\n");
+        assert_eq!(HeadScanner::default().scan(&huge), Err(HeadError::TooLarge));
+    }
+
+    #[test]
+    fn test_request_line_limit() {
+        // Fields of 1KB, so the header bytes run out before MAX_HEADERS does
+        let padding = format!("X-Padding: {}\r\n", "a".repeat(1010));
+        let request = |target_len: usize, header_bytes: usize| {
+            let mut request = format!("GET /?q={} HTTP/1.1\r\n", "a".repeat(target_len - 4)).into_bytes();
+            while request.len() < target_len + header_bytes {
+                request.extend_from_slice(padding.as_bytes());
+            }
+            request.extend_from_slice(b"\r\n");
+            request
+        };
+
+        // A 32KB URL leaves the header fields their whole allowance
+        let long = request(32 * 1024, MAX_HEAD_BYTES - 1024);
+        for step in [1024, 4096, long.len()] {
+            assert_eq!(
+                scan_in_steps_with(HeadScanner::for_requests(), &long, step),
+                Ok(Some(long.len())),
+                "{} bytes at a time",
+                step
+            );
+        }
+        assert_eq!(parse_request(&long).unwrap().target.len(), 32 * 1024);
+        assert_eq!(scan_in_steps(&long, 4096), Err(HeadError::TooLarge));
+
+        let too_long = request(MAX_REQUEST_LINE_BYTES, 100);
+        assert_eq!(scan_in_steps_with(HeadScanner::for_requests(), &too_long, 4096), Err(HeadError::LineTooLong));
+        assert_eq!(HeadScanner::for_requests().scan(&too_long), Err(HeadError::LineTooLong));
+        // Header fields past their own allowance are still too large
+        let too_large = request(1024, MAX_HEAD_BYTES + 1024);
+        assert_eq!(scan_in_steps_with(HeadScanner::for_requests(), &too_large, 4096), Err(HeadError::TooLarge));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let head = parse_request(b"POST /upload?x=1 HTTP/1.1\r\nHost: myapp.tunnel.example.com\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.target, "/upload?x=1");
+        assert_eq!(head.version, 1);
+        assert_eq!(head.headers.get_str("host"), Some("myapp.tunnel.example.com"));
+        assert_eq!(head.headers.content_length(), Ok(Some(5)));
+
+        assert_eq!(parse_request(b"GET / HTTP/1.1\r\nHost: x\r\n"), Err(HeadError::Invalid));
+        assert_eq!(parse_request(b"not http\r\n\r\n"), Err(HeadError::Invalid));
+        let mut many = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADERS {
+            many.extend_from_slice(format!("X-{}: {}\r\n", i, i).as_bytes());
+        }
+        many.extend_from_slice(b"\r\n");
+        assert_eq!(parse_request(&many), Err(HeadError::TooManyHeaders));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let head = parse_response(b"HTTP/1.0 200 OK\r\nX-Old: one\r\n two\r\nContent-Type: text/plain\r\n\r\n").unwrap();
+        assert_eq!((head.version, head.status), (0, 200));
+        assert_eq!(head.headers.get_str("x-old"), Some("one   two"));
+        assert_eq!(head.headers.get_str("content-type"), Some("text/plain"));
+
+        assert!(parse_response(b"HTTP/1.1 103 Early Hints\r\n\r\n").unwrap().is_interim());
+        assert!(!parse_response(b"HTTP/1.1 101 Switching Protocols\r\n\r\n").unwrap().is_interim());
+        assert_eq!(parse_response(b"\xff\xfe\r\n\r\n"), Err(HeadError::Invalid));
+    }
+
+    #[test]
+    fn test_framing_fields() {
+        let headers = |fields: &str| parse_response(format!("HTTP/1.1 200 OK\r\n{}\r\n\r\n", fields).as_bytes()).unwrap().headers;
+
+        assert_eq!(headers("Content-Length: 10").content_length(), Ok(Some(10)));
+        assert_eq!(headers("Content-Length: 10\r\ncontent-length: 10").content_length(), Ok(Some(10)));
+        assert_eq!(headers("Content-Length: 10, 10").content_length(), Ok(Some(10)));
+        assert_eq!(headers("Content-Length: 10\r\nContent-Length: 11").content_length(), Err(HeadError::Invalid));
+        assert_eq!(headers("Content-Length: -1").content_length(), Err(HeadError::Invalid));
+        assert_eq!(headers("Server: x").content_length(), Ok(None));
+
+        assert!(headers("Transfer-Encoding: gzip, Chunked").is_chunked());
+        assert!(!headers("Transfer-Encoding: chunked, gzip").is_chunked());
+        assert!(!headers("X-Chunked: chunked").is_chunked());
+
+        let connection = headers("Connection: Keep-Alive, X-Trace\r\nconnection: upgrade").connection();
+        assert_eq!(connection.options().collect::<Vec<_>>(), ["keep-alive", "x-trace", "upgrade"]);
+        assert_eq!(headers("Server: x").connection(), Connection::default());
+    }
+
+    #[test]
+    fn test_rewrite_request() {
+        let mut head = parse_request(b"GET / HTTP/1.1\r\nhost: a\r\nAccept: */*\r\nHost: b\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        head.headers.set("Host", "localhost:3000");
+        head.headers.remove("connection");
+        assert_eq!(head.to_bytes(), b"GET / HTTP/1.1\r\nHost: localhost:3000\r\nAccept: */*\r\n\r\n");
+        head.headers.set("Connection", "close");
+        assert!(head.to_bytes().ends_with(b"Accept: */*\r\nConnection: close\r\n\r\n"));
+    }
+}
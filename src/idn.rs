@@ -0,0 +1,138 @@
+//! Internationalized subdomains such as `münchen-demo`. Names travel to the server, get
+//! certificates and are matched against Host headers in their ASCII (punycode) form,
+//! `xn--mnchen-demo-thb`; the Unicode form is only for showing to people.
+
+use icu_properties::props::Script;
+use icu_properties::CodePointMapData;
+
+/// Starts every punycode label
+const ACE_PREFIX: &str = "xn--";
+
+/// Scripts that may share a name, since the languages written in them mix them as a
+/// matter of course (UTS #39's highly restrictive profile)
+const SCRIPTS_USED_TOGETHER: [&[Script]; 3] = [
+    &[Script::Latin, Script::Han, Script::Hiragana, Script::Katakana],
+    &[Script::Latin, Script::Han, Script::Bopomofo],
+    &[Script::Latin, Script::Han, Script::Hangul],
+];
+
+/// The ASCII form of a subdomain: punycode for one with non-ASCII characters, after
+/// the usual IDNA mapping such as lowercasing, and any other as it is
+pub fn to_ascii(subdomain: &str) -> Result<String, String> {
+    if subdomain.is_ascii() {
+        return Ok(subdomain.to_string());
+    }
+    let ascii = idna::domain_to_ascii(subdomain)
+        .map_err(|_| format!("'{}' isn't a valid internationalized name", subdomain))?;
+    // Ideographic full stops and the like map to dots
+    if ascii.contains('.') {
+        return Err(format!("'{}' must be a single name, without dots", subdomain));
+    }
+    Ok(ascii)
+}
+
+/// The Unicode form of a punycode subdomain, for display; any other as it is
+pub fn to_unicode(subdomain: &str) -> String {
+    if !is_punycode(subdomain) {
+        return subdomain.to_string();
+    }
+    match idna::domain_to_unicode(subdomain) {
+        (unicode, Ok(())) => unicode,
+        _ => subdomain.to_string(),
+    }
+}
+
+/// Whether a label claims to be punycode
+pub fn is_punycode(label: &str) -> bool {
+    label.get(..ACE_PREFIX.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+}
+
+/// Whether a punycode label decodes, and is exactly what `to_ascii` makes of what it
+/// decodes to, so each name has one ASCII form
+pub fn is_valid_punycode(label: &str) -> bool {
+    let (unicode, result) = idna::domain_to_unicode(label);
+    result.is_ok() && !unicode.is_ascii() && to_ascii(&unicode).is_ok_and(|ascii| ascii == label)
+}
+
+/// `url` with its host in Unicode, for display
+pub fn display_url(url: &str) -> String {
+    let host = url::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string));
+    match host {
+        Some(host) if host.split('.').any(is_punycode) => url.replacen(&host, &idna::domain_to_unicode(&host).0, 1),
+        _ => url.to_string(),
+    }
+}
+
+/// Whether a subdomain mixes letters from different scripts, as a look-alike of
+/// another name might (`pаypal` with a Cyrillic `а`). Digits and hyphens belong to no
+/// script in particular.
+pub fn is_mixed_script(subdomain: &str) -> bool {
+    let scripts = CodePointMapData::<Script>::new();
+    let mut found = Vec::new();
+    for c in to_unicode(subdomain).chars() {
+        let script = scripts.get(c);
+        if script != Script::Common && script != Script::Inherited && !found.contains(&script) {
+            found.push(script);
+        }
+    }
+    found.len() > 1 && !SCRIPTS_USED_TOGETHER.iter().any(|allowed| found.iter().all(|script| allowed.contains(script)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let names = [
+            ("münchen-demo", "xn--mnchen-demo-thb"),
+            ("пример", "xn--e1afmkfd"),
+            ("δοκιμή", "xn--jxalpdlp"),
+            ("テスト", "xn--zckzah"),
+            ("例子", "xn--fsqu00a"),
+            ("مثال", "xn--mgbh0fb"),
+            ("उदाहरण", "xn--p1b6ci4b4b3a"),
+        ];
+        for (unicode, ascii) in names {
+            assert_eq!(to_ascii(unicode).unwrap(), ascii, "{}", unicode);
+            assert_eq!(to_unicode(ascii), unicode);
+            assert!(is_valid_punycode(ascii), "{}", ascii);
+        }
+        // Mapped as IDNA does: lowercased and normalized
+        assert_eq!(to_ascii("MÜNCHEN-demo").unwrap(), "xn--mnchen-demo-thb");
+        assert_eq!(to_ascii("My-App").unwrap(), "My-App");
+        assert_eq!(to_unicode("myapp"), "myapp");
+        assert!(to_ascii("a。b").is_err());
+    }
+
+    #[test]
+    fn test_invalid_punycode() {
+        assert!(!is_valid_punycode("xn--"));
+        assert!(!is_valid_punycode("xn--zz-zzzzzzzz"));
+        // Decodes to plain ASCII, or isn't the canonical form
+        assert!(!is_valid_punycode("xn--myapp-"));
+        assert!(!is_valid_punycode("XN--MNCHEN-DEMO-9DB"));
+        assert!(!is_punycode("myapp"));
+    }
+
+    #[test]
+    fn test_display_url() {
+        assert_eq!(
+            display_url("https://xn--mnchen-demo-thb.tunnel.example.com:8443"),
+            "https://münchen-demo.tunnel.example.com:8443"
+        );
+        assert_eq!(display_url("https://myapp.tunnel.example.com"), "https://myapp.tunnel.example.com");
+        assert_eq!(display_url("tcp://tunnel.example.com:20003"), "tcp://tunnel.example.com:20003");
+    }
+
+    #[test]
+    fn test_mixed_scripts() {
+        // A Cyrillic а among Latin letters
+        assert!(is_mixed_script("p\u{430}ypal"));
+        assert!(is_mixed_script(&to_ascii("p\u{430}ypal").unwrap()));
+        assert!(is_mixed_script("δοκιμή-пример"));
+        for name in ["münchen-demo", "пример-2", "demo-テスト", "日本のテスト", "한국-例子", "myapp"] {
+            assert!(!is_mixed_script(name), "{}", name);
+        }
+    }
+}
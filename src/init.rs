@@ -3,13 +3,13 @@ use colored::Colorize;
 use rand::Rng;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub const DEFAULT_CONFIG_PATH: &str = "/etc/loophole/server.toml";
 const SYSTEMD_SERVICE_PATH: &str = "/etc/systemd/system/loophole.service";
 
-fn generate_token(prefix: &str) -> String {
+pub fn generate_token(prefix: &str) -> String {
     let mut rng = rand::rng();
     let random: [u8; 16] = rng.random();
     let hex: String = random.iter().map(|b| format!("{:02x}", b)).collect();
@@ -38,7 +38,7 @@ fn prompt_yes_no(message: &str, default: bool) -> Result<bool> {
     }
 }
 
-fn install_systemd_service(config_path: &PathBuf) -> Result<()> {
+fn install_systemd_service(config_path: &Path) -> Result<()> {
     // Find the loophole binary
     let binary_path = std::env::current_exe()
         .context("Failed to determine loophole binary path")?;
@@ -198,6 +198,67 @@ domain = "{domain}"
 # Default: 443
 # https_port = 443
 
+# Serve the control endpoint (/_tunnel/connect) and the admin API (/_admin/*) on
+# this port only, e.g. one kept behind a firewall; they're 404s on the others.
+# Clients then log in with the port, e.g. https://tunnel.example.com:9443
+# control_port = 9443
+
+# Address to listen on, for all ports. "::" takes IPv4 connections too where the
+# OS allows it. http_bind and https_bind override it for one port, e.g. to keep
+# plain HTTP on a private interface
+# bind_address = "0.0.0.0"
+# http_bind = "10.0.0.5"
+# https_bind = "::"
+
+# Only the token that first registered a subdomain may register it again,
+# until it has been unused for ownership_expiry (or an admin releases it)
+# strict_subdomain_ownership = false
+# ownership_expiry = "30d"
+
+# Refuse internationalized subdomains that mix scripts, such as a Latin name with
+# a Cyrillic look-alike letter in it
+# reject_confusables = false
+
+# How long a subdomain is kept for a client that dropped without disconnecting,
+# so no other token can take it before the client reconnects ("0" = not kept)
+# reconnect_grace = "60s"
+
+# When a tunnel reconnects or its subdomain is taken over while a request is in
+# flight, fail the request (502) instead of finishing it from the previous client
+# strict_epoch = false
+
+# Set when the domain is proxied through Cloudflare: trusts CF-Connecting-IP and
+# X-Forwarded-Proto from Cloudflare's IP ranges. Requires removing [https],
+# setting manual_certs or [https.dns], since HTTP-01 challenges can't reach the
+# server
+# behind_cloudflare = false
+
+# Load balancers or reverse proxies in front of the server (addresses or CIDR
+# networks). Requests from these are trusted to give the visitor's address in
+# X-Forwarded-For and the scheme they used in X-Forwarded-Proto
+# trusted_proxies = ["10.0.0.0/8"]
+
+# Set when an L4 load balancer such as HAProxy sends a PROXY protocol (v1 or v2)
+# header on every connection to the HTTP and HTTPS ports. Connections without one
+# are closed, so only turn this on behind such a balancer
+# proxy_protocol = false
+
+# Port and scheme visitors use, when a proxy in front of the server listens on a
+# different port or terminates TLS (used in tunnel URLs and redirects)
+# public_port = 443
+# public_scheme = "https"
+
+# Where tokens created at runtime, usage and subdomain reservations are saved
+# (beside this file if unset)
+# state_dir = "/var/lib/loophole"
+
+# Message shown to clients when their tunnel connects, e.g. planned maintenance;
+# text, or {{ file = "/etc/loophole/motd.txt" }}. Clients older than
+# motd_min_client_version are shown motd_outdated instead
+# motd = "Maintenance Saturday 02:00 UTC"
+# motd_min_client_version = "0.5.0"
+# motd_outdated = "Please upgrade: https://github.com/timrogers/loophole/releases"
+
 [tokens.{token}]
 # Token with admin privileges (can access admin API)
 admin = true
@@ -205,6 +266,12 @@ admin = true
 # Example: non-admin token
 # [tokens.tk_example123]
 # admin = false
+# keep_alive = false  # Allow `expose --keep-alive` to hold idle tunnels open
+# max_tunnels = 5     # Overrides limits.max_tunnels_per_token for this token
+# max_requests_per_second = 50  # Overrides limits.max_requests_per_second for this token
+# allowed_subdomains = ["ci-*"]  # Only names matching these (any if unset)
+# strip_request_headers = ["authorization", "cookie"]  # Never passed to its tunnels
+# allowed_methods = ["GET", "HEAD"]  # Others are refused with 405 (any if unset)
 
 [limits]
 # Timeout for proxied requests (seconds)
@@ -213,9 +280,31 @@ admin = true
 # Maximum request body size (bytes)
 # max_request_body_bytes = 10485760
 
+# Longest request line (method, URL and version); longer ones get 414 (at most 64KB)
+# max_request_line = "64KB"
+
 # Disconnect tunnels idle for this long (seconds)
 # idle_tunnel_timeout_secs = 3600
 
+# Disconnect tunnels whose client hasn't pinged for this long (seconds, 0 = never)
+# ping_timeout_secs = 90
+
+# Most tunnels connected at once, and tunnel connections from one IP (0 = no limit)
+# max_tunnels = 0
+# max_connections_per_ip = 0
+
+# Tunnel connection attempts allowed per IP per minute; invalid tokens count five times (0 = no limit)
+# registrations_per_minute_per_ip = 10
+
+# Requests per second visitors may send each tunnel; more get 429 (0 = no limit)
+# max_requests_per_second = 0
+
+# Most tunnels one token may have connected at once (0 = no limit)
+# max_tunnels_per_token = 0
+
+# Addresses or networks whose tunnel connections are refused
+# banned_ips = ["203.0.113.0/24"]
+
 [https]
 # HTTPS configuration with automatic Let's Encrypt certificates
 email = "{email}"
@@ -225,6 +314,103 @@ certs_dir = "/var/lib/loophole/certs"
 
 # Use Let's Encrypt staging for testing (avoids rate limits)
 # staging = false
+
+# Serve certificates placed in certs_dir (e.g. a Cloudflare origin certificate)
+# instead of requesting them from Let's Encrypt
+# manual_certs = false
+
+# Keep certificates in an S3 bucket instead of certs_dir ("fs" or "s3"; needs a
+# build with the s3 feature, and LOOPHOLE_S3_BUCKET and AWS credentials set)
+# storage = "fs"
+
+# Delete certificates of subdomains no tunnel has used for this many days, so
+# random names don't pile up (0 = keep them all; at most usage.retention_days)
+# prune_unused_after_days = 30
+
+# Answer challenges on the HTTPS port instead of port 80, for hosts that can't
+# open it ("http-01" or "tls-alpn-01")
+# challenge = "http-01"
+
+# Answer challenges with TXT records through the DNS provider hosting the zone,
+# getting one wildcard certificate for every subdomain instead of one each
+# [https.dns]
+# provider = "cloudflare"
+# api_token = "cf_..."
+
+[metrics]
+# Serve Prometheus metrics at /metrics on the base domain
+# enabled = false
+
+# Require "Authorization: Bearer <token>" to scrape
+# token = "change-me"
+
+# Serve metrics on their own port instead
+# port = 9090
+
+[logging]
+# Warn about proxied requests whose response headers take longer than this
+# (milliseconds, 0 = off). Reloaded on SIGHUP.
+# slow_request_threshold_ms = 0
+
+# Warn when more tunnel registrations than this arrive in a minute, a sign
+# of a crash-looping client (0 = off)
+# registration_rate_warning = 0
+
+[tcp]
+# Ports to hand out to `loophole expose --tcp` tunnels. TCP tunnels are
+# refused unless this is set; open the range in your firewall too.
+# port_range = "20000-20100"
+
+[usage]
+# Days of hourly per-token usage (requests, bytes, tunnel hours) to keep
+# retention_days = 90
+
+[registry]
+# Subdomains no token may register, on top of www, api, admin, mail, ftp,
+# ssh and tunnel
+# reserved = ["staging", "status"]
+
+# Subdomains that lead to the tunnel on another, whoever has it connected; no
+# token may register them
+# [registry.aliases]
+# www-app = "app"
+
+[maintenance]
+# Recurring windows when every tunnel shows a maintenance page instead of
+# forwarding: a cron expression for the start, then "for" and a length
+# windows = ["0 2 * * sun for 1h"]
+
+# Time zone the windows' cron times are in
+# timezone = "UTC"
+
+[monitoring]
+# Check this often that the domain and its wildcard still resolve to this
+# server, alerting when they don't (off by default)
+# dns_check_interval = "15m"
+
+# Addresses they should resolve to; this server's public address if unset.
+# Required behind Cloudflare
+# expected_ips = ["203.0.113.10"]
+
+# DNS-over-HTTPS resolver (JSON API) the checks ask
+# dns_resolver = "https://cloudflare-dns.com/dns-query"
+
+# Where events such as dns_mismatch are POSTed as JSON
+# webhook_url = "https://hooks.example.com/loophole"
+
+[pages]
+# Templates for the pages the server answers visitors with itself:
+# <page>.html (not_found, forbidden, rate_limited, proxy_error, maintenance),
+# else error.html, else the built-in page. Reloaded on SIGHUP.
+# theme_dir = "/etc/loophole/theme"
+
+# Headers put on every response from tunnels whose subdomain matches, after
+# the service's own. Rules apply in order, so a later rule's `set` wins.
+# Reloaded on SIGHUP.
+# [[response_headers]]
+# subdomain = "staging-*"
+# set = {{ X-Env = "staging" }}
+# add = {{ Cache-Control = "no-transform" }}
 "#
     );
 
@@ -1,8 +1,9 @@
 use anyhow::{Context, Result};
 use colored::Colorize;
 use std::io::{self, Write};
+use std::time::Duration;
 
-use crate::client_config::ClientConfig;
+use crate::client_config::{ClientConfig, Profile, TlsOptions};
 
 fn prompt(message: &str) -> Result<String> {
     print!("{}: ", message);
@@ -19,7 +20,12 @@ fn prompt_secret(message: &str) -> Result<String> {
     Ok(input.trim().to_string())
 }
 
-pub async fn run(server: Option<String>, token: Option<String>) -> Result<()> {
+pub async fn run(
+    server: Option<String>,
+    token: Option<String>,
+    profile: Option<String>,
+    control_port: Option<u16>,
+) -> Result<()> {
     let server = match server {
         Some(s) => s,
         None => {
@@ -31,18 +37,17 @@ pub async fn run(server: Option<String>, token: Option<String>) -> Result<()> {
         }
     };
 
-    // Validate and normalize the server URL
-    let server = if !server.starts_with("http://") && !server.starts_with("https://") {
-        // Default to https:// if no scheme provided
-        format!("https://{}", server)
-    } else {
-        server
-    };
-
-    // Validate URL format
-    if url::Url::parse(&server).is_err() {
-        anyhow::bail!("Invalid server URL: {}", server);
+    // Validate and normalize the server URL, then check it before asking for a token
+    let mut server_url = normalize_server_url(&server)?;
+    if let Some(control_port) = control_port {
+        server_url = with_port(server_url, control_port)?;
+    }
+    let ServerUrl { url: server, host, port, warnings } = server_url;
+    for warning in warnings {
+        println!("{} {}", "!".yellow(), warning);
     }
+    resolve_host(&host, port).await?;
+    check_control_path(&server).await?;
 
     let token = match token {
         Some(t) => t,
@@ -61,10 +66,36 @@ pub async fn run(server: Option<String>, token: Option<String>) -> Result<()> {
     let result = crate::test::check_connection(&server, &token).await;
 
     match result {
-        Ok(()) => {
-            // Save config
-            let config = ClientConfig::new(server.clone(), token);
-            let path = config.save()?;
+        Ok(tunnel_url) => {
+            if let Some(base) = tunnel_url.as_deref().and_then(|url| tunnel_host_base(&host, url)) {
+                anyhow::bail!(
+                    "'{}' looks like a tunnel URL, not the server. Log in to the base domain instead: loophole login --server {}",
+                    host,
+                    server.replacen(&host, &base, 1)
+                );
+            }
+
+            // Save config, keeping any other profiles, and the TLS options of a server
+            // logged in to again
+            let path = ClientConfig::update(|config| match (config, profile.as_deref()) {
+                (Some(mut config), Some(name)) => {
+                    let tls = match config.profiles.remove(name) {
+                        Some(saved) if saved.server == server => saved.tls,
+                        _ => TlsOptions::default(),
+                    };
+                    config.profiles.insert(name.to_string(), Profile { server: server.clone(), token, tls });
+                    config
+                }
+                (Some(mut config), None) => {
+                    if config.server != server {
+                        config.tls = TlsOptions::default();
+                    }
+                    config.server = server.clone();
+                    config.token = token;
+                    config
+                }
+                (None, _) => ClientConfig::new(server.clone(), token),
+            })?;
 
             println!("{} Logged in to {}", "✓".green(), server.green());
             println!("{} Credentials saved to {}", "✓".green(), path.display());
@@ -82,3 +113,275 @@ pub async fn run(server: Option<String>, token: Option<String>) -> Result<()> {
 
     Ok(())
 }
+
+/// Path the server accepts tunnel connections on
+const CONTROL_PATH: &str = "/_tunnel/connect";
+
+/// A server URL reduced to `scheme://host[:port]`
+#[derive(Debug, PartialEq, Eq)]
+struct ServerUrl {
+    url: String,
+    host: String,
+    port: u16,
+    /// Things dropped or changed on the way, to show the user
+    warnings: Vec<String>,
+}
+
+/// Normalize what the user typed: default to https, and drop paths, queries and
+/// trailing slashes, which would break the control path appended later
+fn normalize_server_url(input: &str) -> Result<ServerUrl> {
+    let input = input.trim();
+    let mut warnings = Vec::new();
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        // Default to https:// if no scheme provided
+        format!("https://{}", input)
+    };
+    let mut url = url::Url::parse(&with_scheme).map_err(|e| anyhow::anyhow!("Invalid server URL '{}': {}", input, e))?;
+
+    let scheme = match url.scheme() {
+        "http" | "https" => url.scheme().to_string(),
+        "ws" | "wss" => {
+            let scheme = if url.scheme() == "wss" { "https" } else { "http" };
+            warnings.push(format!("Using {}:// instead of {}://", scheme, url.scheme()));
+            scheme.to_string()
+        }
+        other => anyhow::bail!("Unsupported scheme '{}' in server URL: use https:// (or http://)", other),
+    };
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => anyhow::bail!("Server URL '{}' has no host", input),
+    };
+
+    let path = url.path().trim_end_matches('/');
+    if !path.is_empty() || url.query().is_some() || url.fragment().is_some() {
+        let dropped = &url[url::Position::BeforePath..];
+        warnings.push(format!(
+            "Ignoring '{}' after the host: the server URL is just the scheme and host",
+            dropped
+        ));
+    }
+    // url's default port follows the original scheme, so switch scheme first
+    if url.set_scheme(&scheme).is_err() {
+        anyhow::bail!("Invalid server URL '{}'", input);
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let url = match url.port() {
+        Some(port) => format!("{}://{}:{}", scheme, host, port),
+        None => format!("{}://{}", scheme, host),
+    };
+    Ok(ServerUrl {
+        url,
+        host,
+        port,
+        warnings,
+    })
+}
+
+/// Use `port`, the server's control_port, unless the URL already names another
+fn with_port(url: ServerUrl, port: u16) -> Result<ServerUrl> {
+    let (scheme, rest) = url.url.split_once("://").expect("normalized URLs have a scheme");
+    let default_port = if scheme == "https" { 443 } else { 80 };
+    let has_port = rest.len() > url.host.len();
+    if has_port && url.port != port {
+        anyhow::bail!("{} already has port {}; leave out --control-port {}", url.url, url.port, port);
+    }
+    let url_string = if port == default_port {
+        format!("{}://{}", scheme, url.host)
+    } else {
+        format!("{}://{}:{}", scheme, url.host, port)
+    };
+    Ok(ServerUrl {
+        url: url_string,
+        port,
+        ..url
+    })
+}
+
+/// Fail early, with a clear message, if the host doesn't resolve
+async fn resolve_host(host: &str, port: u16) -> Result<()> {
+    // IPv6 literals come bracketed from the URL
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("'{}' doesn't resolve to any address", host),
+        },
+        Err(e) => anyhow::bail!("Could not resolve '{}': {}. Check the server address for typos.", host, e),
+    }
+}
+
+/// Check that `server` answers like a loophole server before trying a tunnel
+/// connection, whose errors are much less helpful
+async fn check_control_path(server: &str) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let url = format!("{}{}", server, CONTROL_PATH);
+    let response = match client.get(&url).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            // Leave connection problems to the tunnel check, which can fall back to ws://
+            tracing::debug!("Couldn't fetch {}: {}", url, e);
+            return Ok(());
+        }
+    };
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    check_control_response(server, status, &body)
+}
+
+/// What a loophole server sends a plain GET of its control path
+fn check_control_response(server: &str, status: u16, body: &str) -> Result<()> {
+    match status {
+        // No upgrade in the request, so the server asks for one
+        400 if body.contains("WebSocket upgrade required") => Ok(()),
+        // Refused before the upgrade (banned, per-IP or server limit); the tunnel check explains
+        403 | 429 | 503 => Ok(()),
+        301 | 302 | 307 | 308 if server.starts_with("http://") => anyhow::bail!(
+            "{} redirects to HTTPS; use {}",
+            server,
+            server.replacen("http://", "https://", 1)
+        ),
+        // A server with a control_port hides the control path on the others
+        404 => anyhow::bail!(
+            "{} doesn't look like a loophole server: GET {} returned 404. If the server has a \
+             control_port, log in with it, e.g. --control-port 9443",
+            server,
+            CONTROL_PATH
+        ),
+        _ => anyhow::bail!(
+            "{} doesn't look like a loophole server: GET {} returned {} instead of asking for a WebSocket upgrade",
+            server,
+            CONTROL_PATH,
+            status
+        ),
+    }
+}
+
+/// If `host` is a subdomain of the server's base domain (the user pasted a tunnel
+/// URL), the base domain, taken from the test tunnel's URL
+fn tunnel_host_base(host: &str, tunnel_url: &str) -> Option<String> {
+    let tunnel_host = url::Url::parse(tunnel_url).ok()?.host_str()?.to_string();
+    let (_, base) = tunnel_host.split_once('.')?;
+    let host = host.to_ascii_lowercase();
+    (host != base && host.ends_with(&format!(".{}", base))).then(|| base.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_server_url() {
+        let normalized = |input: &str| normalize_server_url(input).unwrap();
+
+        let url = normalized("tunnel.example.com");
+        assert_eq!(url.url, "https://tunnel.example.com");
+        assert_eq!((url.host.as_str(), url.port), ("tunnel.example.com", 443));
+        assert!(url.warnings.is_empty());
+
+        assert_eq!(normalized("http://localhost:8080/").url, "http://localhost:8080");
+        assert!(normalized("http://localhost:8080/").warnings.is_empty());
+        assert_eq!(normalized("http://localhost:8080").port, 8080);
+
+        let url = normalized("https://tunnel.example.com/somepath?x=1");
+        assert_eq!(url.url, "https://tunnel.example.com");
+        assert_eq!(url.warnings.len(), 1);
+        assert!(url.warnings[0].contains("/somepath?x=1"), "{}", url.warnings[0]);
+
+        let url = normalized("wss://tunnel.example.com/_tunnel/connect");
+        assert_eq!(url.url, "https://tunnel.example.com");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.warnings.len(), 2);
+
+        assert_eq!(normalized("ws://localhost").port, 80);
+        assert_eq!(normalized("[::1]:8080").url, "https://[::1]:8080");
+    }
+
+    #[test]
+    fn test_normalize_server_url_errors() {
+        let err = normalize_server_url("ftp://tunnel.example.com").unwrap_err();
+        assert!(err.to_string().contains("Unsupported scheme 'ftp'"), "{}", err);
+        let err = normalize_server_url("https://").unwrap_err();
+        assert!(err.to_string().contains("Invalid server URL"), "{}", err);
+        let err = normalize_server_url("https://exa mple.com").unwrap_err();
+        assert!(err.to_string().contains("Invalid server URL"), "{}", err);
+    }
+
+    #[test]
+    fn test_with_port() {
+        let with = |input: &str, port| with_port(normalize_server_url(input).unwrap(), port);
+
+        let url = with("tunnel.example.com", 9443).unwrap();
+        assert_eq!((url.url.as_str(), url.port), ("https://tunnel.example.com:9443", 9443));
+        assert_eq!(with("tunnel.example.com:9443", 9443).unwrap().url, "https://tunnel.example.com:9443");
+        assert_eq!(with("http://localhost", 80).unwrap().url, "http://localhost");
+        assert_eq!(with("[::1]", 9443).unwrap().url, "https://[::1]:9443");
+
+        let err = with("tunnel.example.com:8443", 9443).unwrap_err();
+        assert!(err.to_string().contains("already has port 8443"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_host() {
+        resolve_host("127.0.0.1", 443).await.unwrap();
+        resolve_host("[::1]", 443).await.unwrap();
+        let err = resolve_host("no-such-host.invalid", 443).await.unwrap_err();
+        assert!(err.to_string().contains("Could not resolve 'no-such-host.invalid'"), "{}", err);
+    }
+
+    #[test]
+    fn test_check_control_response() {
+        let server = "https://tunnel.example.com";
+        assert!(check_control_response(server, 400, "WebSocket upgrade required").is_ok());
+        assert!(check_control_response(server, 429, "Too many tunnel connections").is_ok());
+
+        let err = check_control_response(server, 404, "Not Found").unwrap_err();
+        assert!(err.to_string().contains("doesn't look like a loophole server"), "{}", err);
+        assert!(err.to_string().contains("--control-port"), "{}", err);
+        let err = check_control_response(server, 500, "").unwrap_err();
+        assert!(err.to_string().contains("returned 500"), "{}", err);
+        let err = check_control_response("http://tunnel.example.com", 308, "").unwrap_err();
+        assert!(err.to_string().contains("use https://tunnel.example.com"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_check_control_path() {
+        use axum::{http::StatusCode, routing::get, Router};
+
+        let app = Router::new().route(
+            CONTROL_PATH,
+            get(|| async { (StatusCode::BAD_REQUEST, "WebSocket upgrade required") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        check_control_path(&server).await.unwrap();
+
+        // Some other web server
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let err = check_control_path(&other).await.unwrap_err();
+        assert!(err.to_string().contains("returned 404"), "{}", err);
+    }
+
+    #[test]
+    fn test_tunnel_host_base() {
+        let tunnel = "https://test-123.tunnel.example.com";
+        assert_eq!(
+            tunnel_host_base("myapp.tunnel.example.com", tunnel),
+            Some("tunnel.example.com".to_string())
+        );
+        assert_eq!(tunnel_host_base("tunnel.example.com", tunnel), None);
+        // A different name for the server (e.g. an IP) isn't a tunnel URL
+        assert_eq!(tunnel_host_base("203.0.113.1", tunnel), None);
+        assert_eq!(tunnel_host_base("example.com", tunnel), None);
+    }
+}
+
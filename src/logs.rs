@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use colored::Colorize;
+use std::time::Duration;
+use tracing::{debug, Level};
+
+use crate::admin_client::{self, AdminClient};
+use crate::client_config::TlsOptions;
+use crate::server::LogEvent;
+
+/// Print the server's recent log events, and with `follow` keep printing new ones
+pub async fn run(
+    server: Option<String>,
+    token: Option<String>,
+    config_path: String,
+    follow: bool,
+    level: Option<Level>,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    let (server, token) = admin_client::resolve_credentials(server, token, &config_path)?;
+    let client = AdminClient::new(&server, &token, timeout, &admin_client::resolve_tls(&server, tls)?)?;
+
+    let level = level.unwrap_or(Level::INFO).as_str().to_lowercase();
+    let path = format!("/_admin/logs?level={}&follow={}", level, follow);
+    let mut response = if follow { client.get_stream(&path).await? } else { client.get(&path).await? };
+
+    let mut lines = NdjsonLines::default();
+    while let Some(chunk) = response.chunk().await.context("Lost the connection to the server")? {
+        for event in lines.push(&chunk) {
+            println!("{}", format_event(&event));
+        }
+    }
+    if follow {
+        println!("{}", "The server closed the log stream".dimmed());
+    }
+    Ok(())
+}
+
+/// Splits a body arriving in arbitrary chunks into the events on its lines
+#[derive(Default)]
+struct NdjsonLines {
+    partial: Vec<u8>,
+}
+
+impl NdjsonLines {
+    fn push(&mut self, chunk: &[u8]) -> Vec<LogEvent> {
+        self.partial.extend_from_slice(chunk);
+        let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let complete: Vec<u8> = self.partial.drain(..=end).collect();
+        complete
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    debug!("Skipping unreadable log line: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// An event laid out the way the server prints it:
+/// `2024-06-19T15:22:45.123000Z  WARN loophole::server::tls: message key=value`
+fn format_event(event: &LogEvent) -> String {
+    let time = jiff::Timestamp::from_millisecond(event.time as i64)
+        .map(|time| format!("{:.6}", time))
+        .unwrap_or_default();
+    let level = format!("{:>5}", event.level);
+    let level = match event.level.parse::<Level>() {
+        Ok(Level::ERROR) => level.red(),
+        Ok(Level::WARN) => level.yellow(),
+        Ok(Level::INFO) => level.green(),
+        Ok(Level::DEBUG) => level.blue(),
+        Ok(Level::TRACE) => level.purple(),
+        Err(_) => level.normal(),
+    };
+    let mut line = format!("{} {} {} {}", time.dimmed(), level, format!("{}:", event.target).dimmed(), event.message);
+    for (name, value) in &event.fields {
+        line.push_str(&format!(" {}={}", name.italic(), value));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_lines_split_across_chunks() {
+        let mut lines = NdjsonLines::default();
+        let body = concat!(
+            r#"{"time":1718810565123,"level":"WARN","target":"loophole::server::tls","message":"Renewal failed","fields":{"attempt":"2"}}"#,
+            "\n",
+            "not json\n",
+            r#"{"time":1718810566000,"level":"INFO","target":"loophole::server","message":"Tunnel registered"}"#,
+            "\n",
+        );
+        let (first, rest) = body.split_at(40);
+        assert!(lines.push(first.as_bytes()).is_empty());
+        let events = lines.push(rest.as_bytes());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].fields, BTreeMap::from([("attempt".to_string(), "2".to_string())]));
+        assert_eq!(events[1].message, "Tunnel registered");
+        assert!(lines.partial.is_empty());
+
+        let line = format_event(&events[0]);
+        assert!(line.contains("2024-06-19T15:22:45.123000Z"), "{}", line);
+        assert!(line.contains(" WARN "), "{}", line);
+        assert!(line.contains("loophole::server::tls:"), "{}", line);
+        assert!(line.contains("Renewal failed"), "{}", line);
+        assert!(line.contains("=2"), "{}", line);
+    }
+}
@@ -1,29 +1,60 @@
+mod admin_client;
+mod build_info;
+mod capture;
+mod check;
+mod chunked;
 mod client_config;
+mod clock;
+mod disconnect;
 mod expose;
+mod http_head;
+mod idn;
 mod init;
 mod login;
+mod logs;
+mod names;
 mod proto;
+mod reserve;
+mod schedule;
 mod server;
 mod status;
 mod test;
+mod tokens;
+mod units;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use ipnet::IpNet;
+use proto::Protocol;
+use schedule::Window;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing::Level;
 
 #[derive(Parser)]
 #[command(name = "loophole")]
 #[command(about = "A self-hosted HTTP tunnel")]
-#[command(version)]
+#[command(disable_version_flag = true, arg_required_else_help = true)]
 struct Cli {
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// With --version, also print build metadata (git sha, build date, target, rustc, features)
+    #[arg(long, requires = "version")]
+    verbose: bool,
+
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 fn default_config_path() -> String {
     init::DEFAULT_CONFIG_PATH.to_string()
 }
 
+// Parsed once at startup, so Expose's many flags making it the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new server configuration
@@ -54,6 +85,36 @@ enum Commands {
         /// Log level
         #[arg(long, default_value = "info")]
         log_level: String,
+
+        /// Refuse to start (or reload) if the config file has unknown keys, instead of warning
+        #[arg(long)]
+        strict_config: bool,
+
+        /// Refuse to start if the system clock is more than 2 minutes off the ACME server's, instead of warning
+        #[arg(long)]
+        strict_clock: bool,
+
+        /// Print every setting the server would run with, and where it came from, then exit
+        #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "toml", value_parser = ["toml", "json"])]
+        print_config: Option<String>,
+    },
+
+    /// Check a server configuration file, treating unknown keys as errors
+    CheckConfig {
+        /// Path to configuration file
+        #[arg(short, long, default_value_t = default_config_path())]
+        config: String,
+    },
+
+    /// Convert a config written for the older server to the current format
+    MigrateConfig {
+        /// The old config file
+        #[arg(long = "in")]
+        input: String,
+
+        /// Where to write the converted config (must not exist yet)
+        #[arg(long = "out")]
+        output: String,
     },
 
     /// Login to a tunnel server
@@ -65,6 +126,14 @@ enum Commands {
         /// Authentication token
         #[arg(long)]
         token: Option<String>,
+
+        /// Save as a named profile instead of the default server
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// The server's control port, if it serves the control endpoint on its own port
+        #[arg(long)]
+        control_port: Option<u16>,
     },
 
     /// Test connection to the tunnel server
@@ -88,8 +157,8 @@ enum Commands {
         #[arg(long)]
         token: Option<String>,
 
-        /// Subdomain to register (random if not provided)
-        #[arg(long)]
+        /// Subdomain to register (random if not provided; Unicode names are sent as punycode)
+        #[arg(long, value_parser = idn::to_ascii)]
         subdomain: Option<String>,
 
         /// Local port to forward to
@@ -100,17 +169,122 @@ enum Commands {
         #[arg(long, default_value = "127.0.0.1")]
         host: String,
 
+        /// Forward raw TCP (e.g. Postgres or SSH) through a port on the server, instead of HTTP
+        #[arg(long, conflicts_with_all = ["local_host", "print_examples"])]
+        tcp: bool,
+
+        /// Server port to ask for with --tcp (any free one in the server's range if not set)
+        #[arg(long, value_name = "PORT", requires = "tcp")]
+        remote_port: Option<u16>,
+
+        /// With --tcp, also let others reach the tunnel with `loophole connect` and a
+        /// share key printed at startup, without your token
+        #[arg(long, requires = "tcp")]
+        share: bool,
+
         /// Override Host header for local requests
         #[arg(long)]
         local_host: Option<String>,
 
+        /// Connect to the local service over HTTPS, using --local-host (or --host) as the TLS server name
+        #[arg(long, conflicts_with = "tcp")]
+        local_https: bool,
+
+        /// Accept any certificate from the local service, e.g. a self-signed dev certificate
+        #[arg(long, requires = "local_https")]
+        local_insecure: bool,
+
+        /// Serve files from this directory instead of forwarding to a local server
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["tcp", "local_https", "local_host", "port", "host"])]
+        serve: Option<PathBuf>,
+
+        /// List the contents of directories without an index.html (with --serve)
+        #[arg(long, requires = "serve")]
+        dir_listing: bool,
+
+        /// Publish the tunnel's manifest at /_loophole/manifest, for tooling that discovers tunnels
+        #[arg(long, conflicts_with = "tcp")]
+        publish_manifest: bool,
+
+        /// Ask visitors to log in with these credentials before reaching the service
+        #[arg(long, value_name = "USER:PASSWORD", conflicts_with = "tcp", value_parser = parse_basic_auth)]
+        basic_auth: Option<String>,
+
+        /// Only let visitors from this address or CIDR network reach the tunnel, e.g.
+        /// 203.0.113.0/24 or 2001:db8::/32 (repeatable)
+        #[arg(long = "allow-ip", value_name = "CIDR", value_parser = parse_allow_ip)]
+        allow_ip: Vec<IpNet>,
+
+        /// Serve the tunnel at /t/<SUBDOMAIN>/ on the server's own domain instead of on a
+        /// subdomain, for servers without wildcard DNS
+        #[arg(long, conflicts_with = "tcp")]
+        path_mode: bool,
+
+        /// Another subdomain to reach the tunnel on, counted against the token's tunnel
+        /// limit (repeatable)
+        #[arg(long = "alias", value_name = "SUBDOMAIN", value_parser = idn::to_ascii, conflicts_with_all = ["tcp", "path_mode"])]
+        alias: Vec<String>,
+
+        /// Name of the exposed service, shown in the manifest
+        #[arg(long, value_name = "NAME")]
+        service_name: Option<String>,
+
+        /// Version of the exposed service, shown in the manifest
+        #[arg(long, value_name = "VERSION")]
+        service_version: Option<String>,
+
+        /// Record recent requests and responses, viewable at http://127.0.0.1:PORT (4040 if not given)
+        #[arg(long, value_name = "PORT", num_args = 0..=1, default_missing_value = "4040", conflicts_with_all = ["tcp", "serve"])]
+        inspect: Option<u16>,
+
+        /// Also hide this header's value in the inspector (repeatable, or comma-separated)
+        #[arg(long, value_name = "NAME", value_delimiter = ',', requires = "inspect")]
+        redact_header: Vec<String>,
+
+        /// Keep the last N requests (50 if not given) to replay against the local service by entering `r`
+        #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "50", conflicts_with_all = ["tcp", "serve"])]
+        replay_buffer: Option<usize>,
+
         /// Maximum number of reconnection attempts (0 = unlimited)
         #[arg(long, default_value = "0")]
         max_retries: u32,
 
-        /// Timeout for forwarding requests to local server (seconds)
-        #[arg(long, default_value = "30")]
-        forward_timeout: u64,
+        /// Timeout for forwarding requests to local server (e.g. 30, 90s, 2m30s)
+        #[arg(long, default_value = "30s", value_parser = units::parse_flag_duration)]
+        forward_timeout: Duration,
+
+        /// How often to ping the server to keep the tunnel alive (e.g. 30s, 1m)
+        #[arg(long, default_value = "30s", value_parser = units::parse_flag_duration)]
+        ping_interval: Duration,
+
+        /// Keep the tunnel open while idle, if the server allows it for your token
+        #[arg(long)]
+        keep_alive: bool,
+
+        /// Have the server show a maintenance page instead of forwarding during these
+        /// windows, e.g. "0 2 * * * for 30m" (cron times in the server's maintenance time zone)
+        #[arg(long, value_name = "SCHEDULE", value_parser = Window::parse)]
+        pause_schedule: Option<Window>,
+
+        /// Warn once the session has transferred this much, received and sent together (e.g. 1GB)
+        #[arg(long, value_name = "SIZE", value_parser = units::parse_bytes)]
+        warn_at: Option<u64>,
+
+        /// Stop forwarding once the session has transferred this much, until you enter `c` (e.g. 5GB)
+        #[arg(long, value_name = "SIZE", value_parser = units::parse_bytes)]
+        stop_at: Option<u64>,
+
+        /// Exit if the system clock is more than 2 minutes off the server's, instead of warning
+        #[arg(long)]
+        strict_clock: bool,
+
+        /// Local IP address to bind the outbound connection to the server
+        #[arg(long, value_name = "IP")]
+        bind_interface: Option<IpAddr>,
+
+        /// Network device to bind the outbound connection to, e.g. eth1 (Linux only)
+        #[arg(long, value_name = "NAME")]
+        bind_device: Option<String>,
 
         /// Log level
         #[arg(long, default_value = "info")]
@@ -120,9 +294,113 @@ enum Commands {
         #[arg(long)]
         quiet: bool,
 
+        /// Add response details to request log lines (e.g. --log-detail size,type)
+        #[arg(long, value_name = "DETAILS", value_delimiter = ',')]
+        log_detail: Vec<expose::LogDetail>,
+
         /// Show QR code for tunnel URL
         #[arg(long)]
         qr: bool,
+
+        /// Print example curl commands for the tunnel URL, plus webhook setup hints for
+        /// the given providers (e.g. --print-examples stripe,github)
+        #[arg(long, value_name = "PROVIDERS", num_args = 0..=1, value_delimiter = ',')]
+        print_examples: Option<Vec<expose::Provider>>,
+    },
+
+    /// Expose every tunnel defined in a tunnels file, each with its own connection
+    Start {
+        /// Tunnels file, with a [tunnels.<name>] table per tunnel
+        #[arg(default_value = "loophole.toml")]
+        file: PathBuf,
+
+        /// Tunnel server address (uses saved config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token (uses saved config if not provided)
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Stop every tunnel as soon as one fails for good (e.g. its subdomain is taken)
+        #[arg(long)]
+        fail_fast: bool,
+
+        /// Maximum number of reconnection attempts per tunnel (0 = unlimited)
+        #[arg(long, default_value = "0")]
+        max_retries: u32,
+
+        /// How often to ping the server to keep each tunnel alive (e.g. 30s, 1m)
+        #[arg(long, default_value = "30s", value_parser = units::parse_flag_duration)]
+        ping_interval: Duration,
+
+        /// Keep tunnels open while idle, if the server allows it for your token
+        #[arg(long)]
+        keep_alive: bool,
+
+        /// Stop a tunnel if the system clock is more than 2 minutes off the server's, instead of warning
+        #[arg(long)]
+        strict_clock: bool,
+
+        /// Local IP address to bind the outbound connections to the server
+        #[arg(long, value_name = "IP")]
+        bind_interface: Option<IpAddr>,
+
+        /// Network device to bind the outbound connections to, e.g. eth1 (Linux only)
+        #[arg(long, value_name = "NAME")]
+        bind_device: Option<String>,
+
+        /// Log level
+        #[arg(long, default_value = "info")]
+        log_level: String,
+
+        /// Suppress request logging output
+        #[arg(long)]
+        quiet: bool,
+
+        /// Add response details to request log lines (e.g. --log-detail size,type)
+        #[arg(long, value_name = "DETAILS", value_delimiter = ',')]
+        log_detail: Vec<expose::LogDetail>,
+    },
+
+    /// Reach someone's TCP tunnel through the server's HTTP(S) port, for networks that
+    /// only allow web traffic out (e.g. `ssh -p 2222 localhost`)
+    Connect {
+        /// Subdomain of the TCP tunnel
+        #[arg(value_parser = idn::to_ascii)]
+        subdomain: String,
+
+        /// Local port to listen on; connections to it are carried to the tunnel
+        #[arg(long)]
+        local_port: u16,
+
+        /// Local address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+
+        /// Tunnel server address (uses saved config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// The tunnel's token (uses saved config if neither this nor --key is provided)
+        #[arg(long, conflicts_with = "key")]
+        token: Option<String>,
+
+        /// Share key printed by `loophole expose --tcp --share`, instead of the tunnel's token
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Local IP address to bind the outbound connection to the server
+        #[arg(long, value_name = "IP")]
+        bind_interface: Option<IpAddr>,
+
+        /// Network device to bind the outbound connection to, e.g. eth1 (Linux only)
+        #[arg(long, value_name = "NAME")]
+        bind_device: Option<String>,
+
+        /// Log level
+        #[arg(long, default_value = "info")]
+        log_level: String,
     },
 
     /// Show status of active tunnels on a server
@@ -138,7 +416,286 @@ enum Commands {
         /// Path to server configuration file
         #[arg(short, long, default_value_t = default_config_path())]
         config: String,
+
+        /// Query every server saved with `loophole login` (default and named profiles)
+        #[arg(long, conflicts_with_all = ["server", "token"])]
+        all_profiles: bool,
+
+        /// Exit with an error if any server fails, not only if all of them do
+        #[arg(long)]
+        strict: bool,
+
+        /// Print JSON (one envelope per server) instead of tables
+        #[arg(long)]
+        json: bool,
+
+        /// Show each token's requests, bytes and tunnel hours, instead of the tunnels
+        #[arg(long, conflicts_with_all = ["all_profiles", "strict"])]
+        usage: bool,
+
+        /// Add each tunnel's round trip to its client and the share of probes lost
+        #[arg(long, conflicts_with = "usage")]
+        wide: bool,
+
+        /// Timeout for each admin API request (e.g. 10s, 1m)
+        #[arg(long, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Check whether a subdomain could be registered now, without registering it.
+    /// Exits with an error if it couldn't, saying why.
+    Check {
+        /// Subdomain to check
+        #[arg(long, value_parser = idn::to_ascii)]
+        subdomain: String,
+
+        /// Server URL (uses saved config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token (uses saved config if not provided)
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Print the server's verdict as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Timeout for the request (e.g. 10s, 1m)
+        #[arg(long, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Force disconnect a tunnel on a server (requires an admin token)
+    Disconnect {
+        /// Subdomain of the tunnel to disconnect
+        #[arg(value_parser = idn::to_ascii)]
+        subdomain: String,
+
+        /// Server URL (uses config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token (uses config if not provided, must have admin privileges)
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Path to server configuration file
+        #[arg(short, long, default_value_t = default_config_path())]
+        config: String,
+
+        /// Timeout for each admin API request (e.g. 10s, 1m)
+        #[arg(long, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Show the server's recent log events, and with --follow keep showing new ones
+    /// (requires an admin token)
+    Logs {
+        /// Server URL (uses config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token (uses config if not provided, must have admin privileges)
+        #[arg(long, alias = "admin-token")]
+        token: Option<String>,
+
+        /// Path to server configuration file
+        #[arg(short, long, default_value_t = default_config_path())]
+        config: String,
+
+        /// Keep the connection open, printing events as the server logs them
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Least severe level to show: error, warn, info, debug or trace (info if not given;
+        /// the server only has what its own log level lets through)
+        #[arg(long)]
+        level: Option<Level>,
+
+        /// Timeout for connecting, and for the whole request without --follow (e.g. 10s, 1m)
+        #[arg(long, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// Reserve a subdomain for a token, so no other token can register it while the
+    /// owner is offline (requires an admin token)
+    Reserve {
+        /// Subdomain to reserve
+        #[arg(value_parser = idn::to_ascii)]
+        subdomain: String,
+
+        /// Token to reserve it for, or its id (the token used to authenticate if not provided)
+        #[arg(long = "for", value_name = "TOKEN", conflicts_with = "release")]
+        owner: Option<String>,
+
+        /// Release the subdomain's reservation instead
+        #[arg(long)]
+        release: bool,
+
+        /// Server URL (uses config if not provided)
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token (uses config if not provided, must have admin privileges)
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Path to server configuration file
+        #[arg(short, long, default_value_t = default_config_path())]
+        config: String,
+
+        /// Timeout for each admin API request (e.g. 10s, 1m)
+        #[arg(long, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+
+    /// List, create and revoke the server's tokens without restarting it (requires an admin token)
+    Tokens {
+        #[command(subcommand)]
+        command: TokensCommand,
+
+        /// Server URL (uses config if not provided)
+        #[arg(long, global = true)]
+        server: Option<String>,
+
+        /// Authentication token (uses config if not provided, must have admin privileges)
+        #[arg(long, global = true)]
+        token: Option<String>,
+
+        /// Path to server configuration file
+        #[arg(short, long, global = true, default_value_t = default_config_path())]
+        config: String,
+
+        /// Timeout for each admin API request (e.g. 10s, 1m)
+        #[arg(long, global = true, default_value = "10s", value_parser = units::parse_flag_duration)]
+        timeout: Duration,
+
+        #[command(flatten)]
+        tls: TlsArgs,
     },
+
+    /// Describe the control protocol, for implementing clients in other languages
+    #[cfg(feature = "protocol-schema")]
+    #[command(hide = true)]
+    Protocol {
+        #[command(subcommand)]
+        command: ProtocolCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokensCommand {
+    /// List the tokens the server accepts, with how many tunnels each has connected
+    List {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Create a new random token, valid immediately
+    Create {
+        /// Give the token admin privileges
+        #[arg(long)]
+        admin: bool,
+
+        /// Let clients using the token keep idle tunnels open (`expose --keep-alive`)
+        #[arg(long)]
+        keep_alive: bool,
+
+        /// Most tunnels the token may have connected at once (default: the server's limit)
+        #[arg(long)]
+        max_tunnels: Option<usize>,
+
+        /// The token's share of stream opens when the fair queue is in use (default: 1)
+        #[arg(long)]
+        weight: Option<u32>,
+    },
+
+    /// Stop accepting a token, disconnecting its tunnels
+    Revoke {
+        /// The token to revoke
+        #[arg(value_name = "TOKEN")]
+        revoked: String,
+    },
+}
+
+/// TLS flags of the admin commands, taking precedence over the options saved with
+/// the server's profile
+#[derive(Args)]
+struct TlsArgs {
+    /// PEM file of CA certificates to trust on top of the system's, for a server with
+    /// a certificate from a private CA
+    #[arg(long, global = true, value_name = "PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate to present, for a server that requires one
+    #[arg(long, global = true, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM key of --client-cert: PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+    #[arg(long, global = true, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Accept any server certificate. For testing only: anyone in between can read
+    /// the admin token.
+    #[arg(long, global = true, conflicts_with = "ca_cert")]
+    insecure_skip_verify: bool,
+
+    /// Verify the server's certificate even if the profile's insecure_skip_verify is
+    /// set
+    #[arg(long, global = true, conflicts_with = "insecure_skip_verify")]
+    no_insecure_skip_verify: bool,
+}
+
+impl From<TlsArgs> for client_config::TlsOptions {
+    fn from(args: TlsArgs) -> Self {
+        Self {
+            ca_cert: args.ca_cert,
+            client_cert: args.client_cert,
+            client_key: args.client_key,
+            insecure_skip_verify: match (args.insecure_skip_verify, args.no_insecure_skip_verify) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(feature = "protocol-schema")]
+#[derive(Subcommand)]
+enum ProtocolCommand {
+    /// Print the JSON Schema for every control message, with examples
+    Dump,
+}
+
+fn parse_basic_auth(s: &str) -> Result<String, String> {
+    match s.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(s.to_string()),
+        _ => Err("expected USER:PASSWORD".to_string()),
+    }
+}
+
+fn parse_allow_ip(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("'{}' isn't an IP address or CIDR network such as 203.0.113.0/24", s))
 }
 
 fn parse_log_level(s: &str) -> Level {
@@ -161,18 +718,49 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
-    match cli.command {
+    if cli.version {
+        let info = build_info::BuildInfo::current();
+        if cli.verbose {
+            println!("{}", info.verbose());
+        } else {
+            println!("loophole {}", info.version);
+        }
+        return Ok(());
+    }
+
+    let Some(command) = cli.command else {
+        Cli::command().print_help()?;
+        return Ok(());
+    };
+
+    match command {
         Commands::Init {
             domain,
             email,
             output,
             install,
         } => init::run(domain, email, output, install),
-        Commands::Server { config, log_level } => {
+        Commands::Server {
+            config,
+            log_level,
+            strict_config,
+            strict_clock,
+            print_config,
+        } => {
+            if let Some(format) = print_config {
+                return server::print_config(&config, strict_config, &format);
+            }
             let level = parse_log_level(&log_level);
-            server::run(&config, level).await
+            server::run(&config, level, strict_config, strict_clock).await
         }
-        Commands::Login { server, token } => login::run(server, token).await,
+        Commands::CheckConfig { config } => server::check_config(&config),
+        Commands::MigrateConfig { input, output } => server::migrate_config(&input, &output),
+        Commands::Login {
+            server,
+            token,
+            profile,
+            control_port,
+        } => login::run(server, token, profile, control_port).await,
         Commands::Test { server, token } => test::run(server, token).await,
         Commands::Expose {
             server,
@@ -180,12 +768,39 @@ async fn main() -> Result<()> {
             subdomain,
             port,
             host,
+            tcp,
+            remote_port,
+            share,
             local_host,
+            local_https,
+            local_insecure,
+            serve,
+            dir_listing,
+            publish_manifest,
+            basic_auth,
+            allow_ip,
+            path_mode,
+            alias,
+            service_name,
+            service_version,
+            inspect,
+            redact_header,
+            replay_buffer,
             max_retries,
             forward_timeout,
+            ping_interval,
+            keep_alive,
+            pause_schedule,
+            warn_at,
+            stop_at,
+            strict_clock,
+            bind_interface,
+            bind_device,
             log_level,
             quiet,
+            log_detail,
             qr,
+            print_examples,
         } => {
             let level = parse_log_level(&log_level);
             expose::run(
@@ -194,12 +809,101 @@ async fn main() -> Result<()> {
                 subdomain,
                 host,
                 port,
+                if tcp { Protocol::Tcp } else { Protocol::Http },
+                remote_port,
+                share,
                 local_host,
+                local_https,
+                local_insecure,
+                serve,
+                dir_listing,
+                publish_manifest,
+                basic_auth,
+                allow_ip,
+                path_mode,
+                alias,
+                service_name,
+                service_version,
+                inspect,
+                redact_header,
+                replay_buffer,
                 max_retries,
                 forward_timeout,
+                ping_interval,
+                keep_alive,
+                pause_schedule,
+                expose::BandwidthLimits { warn_at, stop_at },
+                strict_clock,
+                expose::Dialer {
+                    bind_ip: bind_interface,
+                    bind_device,
+                },
                 level,
                 quiet,
+                log_detail,
                 qr,
+                print_examples,
+            )
+            .await
+        }
+        Commands::Start {
+            file,
+            server,
+            token,
+            fail_fast,
+            max_retries,
+            ping_interval,
+            keep_alive,
+            strict_clock,
+            bind_interface,
+            bind_device,
+            log_level,
+            quiet,
+            log_detail,
+        } => {
+            let level = parse_log_level(&log_level);
+            expose::start(
+                &file,
+                server,
+                token,
+                fail_fast,
+                max_retries,
+                ping_interval,
+                keep_alive,
+                strict_clock,
+                expose::Dialer {
+                    bind_ip: bind_interface,
+                    bind_device,
+                },
+                level,
+                quiet,
+                log_detail,
+            )
+            .await
+        }
+        Commands::Connect {
+            subdomain,
+            local_port,
+            bind,
+            server,
+            token,
+            key,
+            bind_interface,
+            bind_device,
+            log_level,
+        } => {
+            expose::connect(
+                server,
+                token,
+                key,
+                subdomain,
+                local_port,
+                bind,
+                expose::Dialer {
+                    bind_ip: bind_interface,
+                    bind_device,
+                },
+                parse_log_level(&log_level),
             )
             .await
         }
@@ -207,6 +911,80 @@ async fn main() -> Result<()> {
             server,
             token,
             config,
-        } => status::run(server, token, config).await,
+            all_profiles,
+            strict,
+            json,
+            usage,
+            wide,
+            timeout,
+            tls,
+        } => status::run(server, token, config, all_profiles, strict, json, usage, wide, timeout, tls.into()).await,
+        Commands::Check {
+            subdomain,
+            server,
+            token,
+            json,
+            timeout,
+            tls,
+        } => check::run(subdomain, server, token, json, timeout, tls.into()).await,
+        Commands::Disconnect {
+            subdomain,
+            server,
+            token,
+            config,
+            timeout,
+            tls,
+        } => disconnect::run(subdomain, server, token, config, timeout, tls.into()).await,
+        Commands::Logs {
+            server,
+            token,
+            config,
+            follow,
+            level,
+            timeout,
+            tls,
+        } => logs::run(server, token, config, follow, level, timeout, tls.into()).await,
+        Commands::Reserve {
+            subdomain,
+            owner,
+            release,
+            server,
+            token,
+            config,
+            timeout,
+            tls,
+        } => reserve::run(subdomain, owner, release, server, token, config, timeout, tls.into()).await,
+        Commands::Tokens {
+            command,
+            server,
+            token,
+            config,
+            timeout,
+            tls,
+        } => {
+            let action = match command {
+                TokensCommand::List { json } => tokens::Action::List { json },
+                TokensCommand::Create {
+                    admin,
+                    keep_alive,
+                    max_tunnels,
+                    weight,
+                } => tokens::Action::Create(tokens::NewToken {
+                    admin,
+                    keep_alive,
+                    max_tunnels,
+                    weight,
+                }),
+                TokensCommand::Revoke { revoked } => tokens::Action::Revoke(revoked),
+            };
+            tokens::run(action, server, token, config, timeout, tls.into()).await
+        }
+        #[cfg(feature = "protocol-schema")]
+        Commands::Protocol {
+            command: ProtocolCommand::Dump,
+        } => {
+            println!("{}", serde_json::to_string_pretty(&proto::schema::dump())?);
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,16 @@
+//! Random subdomains like `calm-owl-123`, for tunnels registered without one. The
+//! server picks them for clients that leave the name to it; older servers don't, so
+//! the client can still pick one itself.
+
+use rand::Rng;
+
+const ADJECTIVES: [&str; 10] = ["quick", "bright", "calm", "eager", "fancy", "gentle", "happy", "jolly", "kind", "lively"];
+const NOUNS: [&str; 10] = ["fox", "owl", "bear", "wolf", "deer", "hawk", "lynx", "seal", "duck", "frog"];
+
+pub fn random_subdomain() -> String {
+    let mut rng = rand::rng();
+    let adj = ADJECTIVES[rng.random_range(0..ADJECTIVES.len())];
+    let noun = NOUNS[rng.random_range(0..NOUNS.len())];
+    let num: u16 = rng.random_range(100..1000);
+    format!("{}-{}-{}", adj, noun, num)
+}
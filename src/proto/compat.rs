@@ -0,0 +1,377 @@
+//! The WebSocket carrying a tunnel, as the AsyncRead + AsyncWrite yamux runs over.
+//! Both ends use it: the server over axum's WebSocket, the client over tungstenite's.
+//! yamux's traffic travels as Binary messages, and control messages (pings, shutdown
+//! notices) as Text messages between them.
+
+use bytes::{Buf, Bytes};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{Sink, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use super::transport::MAX_WS_PAYLOAD;
+use super::{ClientMessage, ServerMessage};
+
+/// A WebSocket message, as far as [`WsCompat`] cares
+pub enum Frame {
+    Data(Vec<u8>),
+    Text(String),
+    Close,
+    /// Pings and pongs, which the WebSocket library answers itself
+    Other,
+}
+
+/// The message type of a WebSocket library
+pub trait WsMessage: Sized {
+    fn binary(data: Vec<u8>) -> Self;
+    fn text(text: String) -> Self;
+    fn into_frame(self) -> Frame;
+}
+
+impl WsMessage for axum::extract::ws::Message {
+    fn binary(data: Vec<u8>) -> Self {
+        Self::Binary(data)
+    }
+
+    fn text(text: String) -> Self {
+        Self::Text(text)
+    }
+
+    fn into_frame(self) -> Frame {
+        match self {
+            Self::Binary(data) => Frame::Data(data),
+            Self::Text(text) => Frame::Text(text),
+            Self::Close(_) => Frame::Close,
+            Self::Ping(_) | Self::Pong(_) => Frame::Other,
+        }
+    }
+}
+
+impl WsMessage for tokio_tungstenite::tungstenite::Message {
+    fn binary(data: Vec<u8>) -> Self {
+        Self::Binary(data)
+    }
+
+    fn text(text: String) -> Self {
+        Self::Text(text)
+    }
+
+    fn into_frame(self) -> Frame {
+        match self {
+            // Fragmented messages arrive already reassembled (up to MAX_WS_MESSAGE_SIZE);
+            // raw frames are only surfaced if a peer sends them as-is
+            message @ (Self::Binary(_) | Self::Frame(_)) => Frame::Data(message.into_data()),
+            Self::Text(text) => Frame::Text(text),
+            Self::Close(_) => Frame::Close,
+            Self::Ping(_) | Self::Pong(_) => Frame::Other,
+        }
+    }
+}
+
+/// The control messages one end receives
+pub trait Inbound: DeserializeOwned {
+    /// The control messages that end sends
+    type Outbound: Serialize;
+
+    /// The reply sent straight back, since the socket belongs to yamux by now
+    fn answer(&self) -> Option<Self::Outbound> {
+        None
+    }
+}
+
+impl Inbound for ClientMessage {
+    type Outbound = ServerMessage;
+
+    fn answer(&self) -> Option<ServerMessage> {
+        matches!(self, ClientMessage::Ping { .. }).then_some(ServerMessage::Pong)
+    }
+}
+
+impl Inbound for ServerMessage {
+    type Outbound = ClientMessage;
+}
+
+/// The server's end of a tunnel, receiving the client's control messages
+pub type ServerCompat<S> = WsCompat<S, ClientMessage>;
+
+/// The client's end of a tunnel, receiving the server's control messages
+pub type ClientCompat<S> = WsCompat<S, ServerMessage>;
+
+/// A WebSocket as futures AsyncRead + AsyncWrite, passing on the control messages
+/// of type `In` that arrive between the data
+pub struct WsCompat<S, In: Inbound> {
+    inner: S,
+    read_buffer: VecDeque<Bytes>,
+    closed: bool,
+    /// Writes are split so no Binary message exceeds this many bytes
+    max_payload: usize,
+    /// Control messages to send as Text between yamux's Binary frames
+    control_rx: Option<mpsc::UnboundedReceiver<In::Outbound>>,
+    /// Where control messages from the other end are passed on to
+    inbound_tx: Option<mpsc::UnboundedSender<In>>,
+    control_queue: VecDeque<String>,
+    control_unflushed: bool,
+    /// When anything last arrived from the other end
+    last_heard: Arc<Mutex<Instant>>,
+}
+
+/// The control messages carried alongside yamux, for whoever drives the connection
+pub struct ControlChannel<In: Inbound> {
+    /// Messages to send to the other end
+    pub tx: mpsc::UnboundedSender<In::Outbound>,
+    /// Messages from the other end (those with an [`Inbound::answer`] are answered too)
+    pub rx: mpsc::UnboundedReceiver<In>,
+}
+
+impl<S, In: Inbound> WsCompat<S, In> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_payload(inner, MAX_WS_PAYLOAD)
+    }
+
+    pub fn with_max_payload(inner: S, max_payload: usize) -> Self {
+        Self {
+            inner,
+            read_buffer: VecDeque::new(),
+            closed: false,
+            max_payload,
+            control_rx: None,
+            inbound_tx: None,
+            control_queue: VecDeque::new(),
+            control_unflushed: false,
+            last_heard: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Like `new`, plus a channel for control messages interleaved with yamux's
+    /// traffic once the socket has been handed to yamux. Both ends skip Text messages
+    /// when reading yamux data, so these don't disturb the stream.
+    pub fn with_control(inner: S) -> (Self, ControlChannel<In>) {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let mut compat = Self::new(inner);
+        compat.control_rx = Some(control_rx);
+        compat.inbound_tx = Some(inbound_tx);
+        let channel = ControlChannel {
+            tx: control_tx,
+            rx: inbound_rx,
+        };
+        (compat, channel)
+    }
+
+    /// When anything (yamux data or a control message) last arrived from the other end
+    pub fn last_heard(&self) -> Arc<Mutex<Instant>> {
+        self.last_heard.clone()
+    }
+
+    fn queue(&mut self, message: &In::Outbound) {
+        self.control_queue.push_back(serde_json::to_string(message).expect("control messages serialize"));
+    }
+
+    /// Send any queued control messages. Called from `poll_read`, which yamux polls
+    /// whenever the connection is driven, so queued messages go out promptly.
+    fn poll_send_control<M, E>(&mut self, cx: &mut Context<'_>) -> io::Result<()>
+    where
+        S: Sink<M, Error = E> + Unpin,
+        M: WsMessage,
+        E: Display,
+    {
+        while let Some(control_rx) = self.control_rx.as_mut() {
+            match control_rx.poll_recv(cx) {
+                Poll::Ready(Some(message)) => self.queue(&message),
+                Poll::Ready(None) => self.control_rx = None,
+                Poll::Pending => break,
+            }
+        }
+
+        while !self.control_queue.is_empty() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let text = self.control_queue.pop_front().expect("queued control message");
+                    Pin::new(&mut self.inner).start_send(M::text(text)).map_err(io_error)?;
+                    self.control_unflushed = true;
+                }
+                Poll::Ready(Err(e)) => return Err(io_error(e)),
+                Poll::Pending => return Ok(()),
+            }
+        }
+
+        if self.control_unflushed {
+            match Pin::new(&mut self.inner).poll_flush(cx) {
+                Poll::Ready(Ok(())) => self.control_unflushed = false,
+                Poll::Ready(Err(e)) => return Err(io_error(e)),
+                Poll::Pending => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn io_error(e: impl Display) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+impl<S, In: Inbound> Unpin for WsCompat<S, In> {}
+
+impl<S, M, E, In> AsyncRead for WsCompat<S, In>
+where
+    S: Stream<Item = Result<M, E>> + Sink<M, Error = E> + Unpin,
+    M: WsMessage,
+    E: Display,
+    In: Inbound,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if let Err(e) = self.poll_send_control(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        // First, try to read from buffer
+        if let Some(mut data) = self.read_buffer.pop_front() {
+            let len = std::cmp::min(data.len(), buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            data.advance(len);
+            if !data.is_empty() {
+                self.read_buffer.push_front(data);
+            }
+            return Poll::Ready(Ok(len));
+        }
+
+        if self.closed {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Fragmented messages arrive reassembled, bounded by the WebSocket's max
+        // message size
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(_))) = polled {
+            if let Ok(mut last_heard) = self.last_heard.lock() {
+                *last_heard = Instant::now();
+            }
+        }
+        match polled {
+            Poll::Ready(Some(Ok(message))) => match message.into_frame() {
+                Frame::Data(data) => {
+                    let data = Bytes::from(data);
+                    let len = std::cmp::min(data.len(), buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    if len < data.len() {
+                        self.read_buffer.push_back(data.slice(len..));
+                    }
+                    Poll::Ready(Ok(len))
+                }
+                Frame::Close => {
+                    self.closed = true;
+                    Poll::Ready(Ok(0))
+                }
+                Frame::Text(text) => {
+                    // Control messages can still arrive after yamux has taken over
+                    if let Ok(message) = serde_json::from_str::<In>(&text) {
+                        if let Some(answer) = message.answer() {
+                            self.queue(&answer);
+                        }
+                        if let Some(inbound_tx) = &self.inbound_tx {
+                            let _ = inbound_tx.send(message);
+                        }
+                    }
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                Frame::Other => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            },
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Err(io_error(e))),
+            Poll::Ready(None) => {
+                self.closed = true;
+                Poll::Ready(Ok(0))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// The Stream bound only names the message type, which both WebSocket types read and write
+impl<S, M, E, In> AsyncWrite for WsCompat<S, In>
+where
+    S: Stream<Item = Result<M, E>> + Sink<M, Error = E> + Unpin,
+    M: WsMessage,
+    E: Display,
+    In: Inbound,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_ready(cx) {
+            Poll::Ready(Ok(())) => {
+                // Partial writes are fine for AsyncWrite; yamux writes the rest next
+                let len = buf.len().min(self.max_payload);
+                let message = M::binary(buf[..len].to_vec());
+                match Pin::new(&mut self.inner).start_send(message) {
+                    Ok(()) => Poll::Ready(Ok(len)),
+                    Err(e) => Poll::Ready(Err(io_error(e))),
+                }
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(io_error(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Queued control messages (e.g. a Disconnect or a Shutdown) go out before the close
+        self.poll_send_control(cx)?;
+        if !self.control_queue.is_empty() || self.control_unflushed {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    #[tokio::test]
+    async fn test_control_messages_between_data() {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let server_ws = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+        let mut client_ws = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+        let (mut server, mut control) = ServerCompat::with_control(server_ws);
+
+        // A ping among the data is answered, passed on, and left out of what's read
+        let ping = ClientMessage::Ping { keep_alive: true }.to_json().unwrap();
+        client_ws.send(Message::Binary(b"one".to_vec())).await.unwrap();
+        client_ws.send(Message::Text(ping)).await.unwrap();
+        client_ws.send(Message::Binary(b"two".to_vec())).await.unwrap();
+        let mut buf = [0u8; 6];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"onetwo");
+        assert!(matches!(control.rx.recv().await, Some(ClientMessage::Ping { keep_alive: true })));
+        let pong = client_ws.next().await.unwrap().unwrap();
+        assert!(matches!(ServerMessage::from_json(pong.to_text().unwrap()), Ok(ServerMessage::Pong)));
+
+        // Queued control messages go out before the close
+        control.tx.send(ServerMessage::Shutdown { message: "restarting".to_string() }).unwrap();
+        server.close().await.unwrap();
+        let sent = client_ws.next().await.unwrap().unwrap();
+        assert!(matches!(
+            ServerMessage::from_json(sent.to_text().unwrap()),
+            Ok(ServerMessage::Shutdown { message }) if message == "restarting"
+        ));
+        assert!(matches!(client_ws.next().await, Some(Ok(Message::Close(_)))));
+    }
+}
@@ -1,26 +1,154 @@
 use serde::{Deserialize, Serialize};
 
 /// Messages sent from client to server
+// Register is sent once per connection, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
-    Register { token: String, subdomain: String },
-    Ping,
+    Register {
+        token: String,
+        /// Empty (or absent) for the server to pick a free random one; servers that
+        /// predate this refuse an empty name as invalid
+        #[serde(default)]
+        subdomain: String,
+        /// Absent from older clients, which only register HTTP tunnels
+        #[serde(default)]
+        protocol: Protocol,
+        /// Port to listen on for a TCP tunnel; any free one in the server's range if absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_port: Option<u16>,
+        /// Name of the service being exposed, as the client declares it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_name: Option<String>,
+        /// Version of the service being exposed, as the client declares it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_version: Option<String>,
+        /// Serve the tunnel's manifest at `/_loophole/manifest` on its host
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        publish_manifest: bool,
+        /// Client build, e.g. `0.1.0 (1a2b3c4d5e6f 2026-10-17)`; absent from older clients
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_version: Option<String>,
+        /// Lets `loophole connect` reach a TCP tunnel with this key instead of the
+        /// tunnel's token; ignored for HTTP tunnels
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        share_key: Option<String>,
+        /// When to serve a maintenance page instead of proxying, as a cron expression
+        /// and a length, e.g. `0 2 * * * for 30m`, in the server's maintenance time zone
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pause_schedule: Option<String>,
+        /// `user:password` the server asks visitors of an HTTP tunnel for; ignored for
+        /// TCP tunnels
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        basic_auth: Option<String>,
+        /// Addresses or CIDR networks, e.g. `203.0.113.0/24`, that may reach the
+        /// tunnel; anyone may when empty
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow_ips: Vec<String>,
+        /// How visitors reach an HTTP tunnel; absent from older clients, which only use
+        /// subdomains. Ignored for TCP tunnels
+        #[serde(default, skip_serializing_if = "TunnelMode::is_subdomain")]
+        mode: TunnelMode,
+        /// The client answers echo streams (see `transport::ECHO_STREAM`), so the server
+        /// may measure the tunnel's round trip; absent from older clients, which aren't probed
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        echo_streams: bool,
+        /// Other subdomains visitors reach the tunnel on, which count against the
+        /// token's tunnel limit; subdomain HTTP tunnels only
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        aliases: Vec<String>,
+    },
+    /// Liveness ping; with `keep_alive` it also counts as tunnel activity, if the
+    /// token is allowed to keep idle tunnels open
+    Ping {
+        #[serde(default)]
+        keep_alive: bool,
+    },
     Disconnect,
 }
 
 /// Messages sent from server to client
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
-    Registered { subdomain: String, url: String },
+    Registered {
+        subdomain: String,
+        url: String,
+        /// Server build, e.g. `0.1.0 (1a2b3c4d5e6f 2026-10-17)`; absent from older servers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_version: Option<String>,
+        /// What the server holds the tunnel's visitors to; absent from older servers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<TunnelLimits>,
+        /// The operator's message of the day, to show the user; absent when there's none
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        motd: Option<String>,
+        /// Where visitors reach the tunnel on its aliases, the server's own included
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alias_urls: Vec<String>,
+    },
     Error { code: ErrorCode, message: String },
     Pong,
     Ping,
     CertificateStatus { ready: bool },
     Shutdown { message: String },
+    /// The tunnel has been idle long enough that it'll be disconnected soon
+    IdleWarning { disconnect_in_secs: u64 },
+    /// Visitors have started getting 429s for going over the tunnel's request rate;
+    /// the last of them may retry at `until_ms` (unix milliseconds). Sent once per
+    /// stretch of refusals, however long it lasts.
+    Throttled { until_ms: u64 },
+}
+
+/// Limits the server enforces on an HTTP tunnel's visitors, each absent when there's none
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelLimits {
+    /// Requests per second, with a second's worth allowed in a burst
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rps: Option<u32>,
+    /// Requests in flight at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<u32>,
+    /// Largest request body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_bytes: Option<u64>,
+}
+
+/// What a tunnel carries
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// HTTP requests to `https://<subdomain>.<domain>`
+    #[default]
+    Http,
+    /// Raw connections to a dedicated port on the server, copied as-is
+    Tcp,
 }
 
+/// How visitors reach an HTTP tunnel
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelMode {
+    /// At `https://<subdomain>.<domain>`
+    #[default]
+    Subdomain,
+    /// At `https://<domain>/t/<subdomain>/`, for DNS that can't have a wildcard record
+    Path,
+}
+
+impl TunnelMode {
+    pub fn is_subdomain(&self) -> bool {
+        *self == TunnelMode::Subdomain
+    }
+}
+
+#[cfg_attr(feature = "protocol-schema", derive(schemars::JsonSchema))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ErrorCode {
@@ -28,6 +156,13 @@ pub enum ErrorCode {
     SubdomainTaken,
     SubdomainInvalid,
     TunnelLimitReached,
+    /// TCP tunnels aren't enabled, or the requested port is outside the server's range
+    TcpUnavailable,
+    /// The requested TCP port is in use, or every port in the range is
+    PortUnavailable,
+    /// One of the tunnel's options, such as its pause schedule or basic auth credentials,
+    /// isn't valid
+    InvalidOption,
     InternalError,
 }
 
@@ -41,6 +176,33 @@ impl ClientMessage {
     }
 }
 
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 8] = [
+        ErrorCode::InvalidToken,
+        ErrorCode::SubdomainTaken,
+        ErrorCode::SubdomainInvalid,
+        ErrorCode::TunnelLimitReached,
+        ErrorCode::TcpUnavailable,
+        ErrorCode::PortUnavailable,
+        ErrorCode::InvalidOption,
+        ErrorCode::InternalError,
+    ];
+
+    /// The code as it's sent on the wire
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "invalid_token",
+            ErrorCode::SubdomainTaken => "subdomain_taken",
+            ErrorCode::SubdomainInvalid => "subdomain_invalid",
+            ErrorCode::TunnelLimitReached => "tunnel_limit_reached",
+            ErrorCode::TcpUnavailable => "tcp_unavailable",
+            ErrorCode::PortUnavailable => "port_unavailable",
+            ErrorCode::InvalidOption => "invalid_option",
+            ErrorCode::InternalError => "internal_error",
+        }
+    }
+}
+
 impl ServerMessage {
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -67,17 +229,128 @@ mod tests {
         let msg = ClientMessage::Register {
             token: "tk_abc123".to_string(),
             subdomain: "myapp".to_string(),
+            protocol: Protocol::Tcp,
+            remote_port: Some(20042),
+            service_name: Some("api".to_string()),
+            service_version: None,
+            publish_manifest: true,
+            client_version: Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)".to_string()),
+            share_key: Some("sk_abc123".to_string()),
+            pause_schedule: Some("0 2 * * * for 30m".to_string()),
+            basic_auth: Some("alice:s3cret".to_string()),
+            allow_ips: vec!["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()],
+            mode: TunnelMode::Path,
+            echo_streams: true,
+            aliases: vec!["www-myapp".to_string()],
         };
         let json = msg.to_json().unwrap();
         assert!(json.contains("register"));
+        assert!(!json.contains("service_version"), "{}", json);
         let parsed = ClientMessage::from_json(&json).unwrap();
         match parsed {
-            ClientMessage::Register { token, subdomain } => {
+            ClientMessage::Register { token, subdomain, protocol, remote_port, service_name, service_version, publish_manifest, client_version, share_key, pause_schedule, basic_auth, allow_ips, mode, echo_streams, aliases } => {
                 assert_eq!(token, "tk_abc123");
                 assert_eq!(subdomain, "myapp");
+                assert_eq!(protocol, Protocol::Tcp);
+                assert_eq!(remote_port, Some(20042));
+                assert_eq!(service_name.as_deref(), Some("api"));
+                assert_eq!(service_version, None);
+                assert!(publish_manifest);
+                assert_eq!(client_version.as_deref(), Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)"));
+                assert_eq!(share_key.as_deref(), Some("sk_abc123"));
+                assert_eq!(pause_schedule.as_deref(), Some("0 2 * * * for 30m"));
+                assert_eq!(basic_auth.as_deref(), Some("alice:s3cret"));
+                assert_eq!(allow_ips, ["203.0.113.0/24", "2001:db8::/32"]);
+                assert_eq!(mode, TunnelMode::Path);
+                assert!(echo_streams);
+                assert_eq!(aliases, ["www-myapp"]);
+            }
+            _ => panic!("Wrong variant"),
+        }
+    }
+
+    #[test]
+    fn test_register_defaults_to_http() {
+        // Older clients don't say which protocol they want
+        let legacy = r#"{"type":"register","token":"tk_abc123","subdomain":"myapp"}"#;
+        match ClientMessage::from_json(legacy).unwrap() {
+            ClientMessage::Register { protocol, remote_port, service_name, publish_manifest, client_version, share_key, pause_schedule, basic_auth, allow_ips, mode, echo_streams, aliases, .. } => {
+                assert_eq!(protocol, Protocol::Http);
+                assert_eq!(remote_port, None);
+                assert_eq!(service_name, None);
+                assert!(!publish_manifest);
+                assert_eq!(client_version, None);
+                assert_eq!(share_key, None);
+                assert_eq!(pause_schedule, None);
+                assert_eq!(basic_auth, None);
+                assert!(allow_ips.is_empty());
+                assert_eq!(mode, TunnelMode::Subdomain);
+                assert!(!echo_streams);
+                assert!(aliases.is_empty());
             }
             _ => panic!("Wrong variant"),
         }
+
+        // The server picks the name
+        let anonymous = r#"{"type":"register","token":"tk_abc123"}"#;
+        match ClientMessage::from_json(anonymous).unwrap() {
+            ClientMessage::Register { subdomain, .. } => assert_eq!(subdomain, ""),
+            _ => panic!("Wrong variant"),
+        }
+    }
+
+    #[test]
+    fn test_register_client_version_compat() {
+        // Register as servers before client_version read it
+        #[derive(Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum OldClientMessage {
+            Register { token: String, subdomain: String },
+        }
+
+        let msg = ClientMessage::Register {
+            token: "tk_abc123".to_string(),
+            subdomain: "myapp".to_string(),
+            protocol: Protocol::Http,
+            remote_port: None,
+            service_name: None,
+            service_version: None,
+            publish_manifest: false,
+            client_version: Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)".to_string()),
+            share_key: None,
+            pause_schedule: None,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            mode: TunnelMode::Subdomain,
+            echo_streams: false,
+            aliases: Vec::new(),
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""client_version":"0.1.0 (1a2b3c4d5e6f 2026-10-17)""#), "{}", json);
+        // Older servers ignore the version
+        match serde_json::from_str::<OldClientMessage>(&json).unwrap() {
+            OldClientMessage::Register { token, subdomain } => assert_eq!((token.as_str(), subdomain.as_str()), ("tk_abc123", "myapp")),
+        }
+
+        // And an unknown version is left out rather than sent as null
+        let msg = ClientMessage::Register {
+            token: "tk_abc123".to_string(),
+            subdomain: "myapp".to_string(),
+            protocol: Protocol::Http,
+            remote_port: None,
+            service_name: None,
+            service_version: None,
+            publish_manifest: false,
+            client_version: None,
+            share_key: None,
+            pause_schedule: None,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            mode: TunnelMode::Subdomain,
+            echo_streams: false,
+            aliases: Vec::new(),
+        };
+        assert!(!msg.to_json().unwrap().contains("client_version"));
     }
 
     #[test]
@@ -85,12 +358,71 @@ mod tests {
         let msg = ServerMessage::Registered {
             subdomain: "myapp".to_string(),
             url: "http://myapp.localhost:8080".to_string(),
+            server_version: Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)".to_string()),
+            limits: Some(TunnelLimits {
+                max_rps: Some(20),
+                max_concurrent: None,
+                max_body_bytes: Some(10 * 1024 * 1024),
+            }),
+            motd: Some("Maintenance Saturday 02:00 UTC".to_string()),
+            alias_urls: Vec::new(),
         };
         let json = msg.to_json().unwrap();
         assert!(json.contains("registered"));
+        assert!(json.contains("server_version"));
+        assert!(json.contains(r#""limits":{"max_rps":20,"max_body_bytes":10485760}"#), "{}", json);
+
+        // Older servers don't send a version or limits
+        let legacy = r#"{"type":"registered","subdomain":"myapp","url":"http://myapp.localhost"}"#;
+        match ServerMessage::from_json(legacy).unwrap() {
+            ServerMessage::Registered { server_version, limits, motd, alias_urls, .. } => {
+                assert_eq!(server_version, None);
+                assert_eq!(limits, None);
+                assert_eq!(motd, None);
+                assert!(alias_urls.is_empty());
+            }
+            _ => panic!("Wrong variant"),
+        }
+        // And older clients read past them
+        #[derive(Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum OldServerMessage {
+            Registered { subdomain: String, url: String },
+        }
+        match serde_json::from_str::<OldServerMessage>(&json).unwrap() {
+            OldServerMessage::Registered { subdomain, url } => {
+                assert_eq!((subdomain.as_str(), url.as_str()), ("myapp", "http://myapp.localhost:8080"))
+            }
+        }
+        // Where they don't know a message, such as throttled, they skip it
+        let json = ServerMessage::Throttled { until_ms: 1_792_324_800_000 }.to_json().unwrap();
+        assert_eq!(json, r#"{"type":"throttled","until_ms":1792324800000}"#);
+        assert!(serde_json::from_str::<OldServerMessage>(&json).is_err());
 
         let err = ServerMessage::error(ErrorCode::InvalidToken, "Bad token");
         let json = err.to_json().unwrap();
         assert!(json.contains("invalid_token"));
     }
+
+    #[test]
+    fn test_error_codes_match_the_wire() {
+        for (i, code) in ErrorCode::ALL.into_iter().enumerate() {
+            assert_eq!(code as usize, i);
+            assert_eq!(serde_json::to_string(&code).unwrap(), format!("\"{}\"", code.code()));
+        }
+    }
+
+    #[test]
+    fn test_ping_keep_alive_defaults_off() {
+        // Older clients send a bare ping
+        assert!(matches!(
+            ClientMessage::from_json(r#"{"type":"ping"}"#).unwrap(),
+            ClientMessage::Ping { keep_alive: false }
+        ));
+        let json = ClientMessage::Ping { keep_alive: true }.to_json().unwrap();
+        assert!(matches!(ClientMessage::from_json(&json).unwrap(), ClientMessage::Ping { keep_alive: true }));
+
+        let json = ServerMessage::IdleWarning { disconnect_in_secs: 60 }.to_json().unwrap();
+        assert_eq!(json, r#"{"type":"idle_warning","disconnect_in_secs":60}"#);
+    }
 }
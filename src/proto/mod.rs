@@ -1,3 +1,7 @@
+pub mod compat;
 mod messages;
+#[cfg(feature = "protocol-schema")]
+pub mod schema;
+pub mod transport;
 
 pub use messages::*;
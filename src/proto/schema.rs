@@ -0,0 +1,89 @@
+//! JSON Schema for the control messages, with example messages, for clients written
+//! in other languages (`loophole protocol dump`). The examples double as conformance
+//! vectors: the tests check that every one still validates against the schema and
+//! reads back into the same message, so a change that breaks existing clients fails.
+
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+use super::{ClientMessage, ServerMessage};
+
+/// Example messages, and messages both sides must refuse
+const EXAMPLES: &str = include_str!("examples.json");
+
+/// Schemas for both directions plus the examples, as `loophole protocol dump` prints them
+pub fn dump() -> Value {
+    let examples: Value = serde_json::from_str(EXAMPLES).expect("examples.json is valid JSON");
+    json!({
+        "client_message": schema_for!(ClientMessage),
+        "server_message": schema_for!(ServerMessage),
+        "examples": {
+            "client_message": examples["client"],
+            "server_message": examples["server"],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::collections::BTreeSet;
+
+    fn examples(key: &str) -> Vec<Value> {
+        let examples: Value = serde_json::from_str(EXAMPLES).unwrap();
+        examples[key].as_array().unwrap().clone()
+    }
+
+    /// The `type` of every variant the schema allows
+    fn variants(schema: &Value) -> BTreeSet<String> {
+        schema["oneOf"]
+            .as_array()
+            .expect("messages are a oneOf")
+            .iter()
+            .map(|variant| variant["properties"]["type"]["const"].as_str().expect("tagged by type").to_string())
+            .collect()
+    }
+
+    fn check<T: Serialize + DeserializeOwned>(schema: &Value, valid: &[Value], invalid: &[Value]) {
+        let validator = jsonschema::validator_for(schema).expect("schema compiles");
+
+        let mut covered = BTreeSet::new();
+        for example in valid {
+            if let Err(e) = validator.validate(example) {
+                panic!("{} doesn't match the schema: {}", example, e);
+            }
+            let message: T = serde_json::from_value(example.clone()).unwrap_or_else(|e| panic!("{}: {}", example, e));
+            // Written back exactly as it was: same tag, same field names, same defaults
+            assert_eq!(&serde_json::to_value(&message).unwrap(), example);
+            covered.insert(example["type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(covered, variants(schema), "every message type needs an example");
+
+        for example in invalid {
+            assert!(!validator.is_valid(example), "schema accepts {}", example);
+            assert!(serde_json::from_value::<T>(example.clone()).is_err(), "serde accepts {}", example);
+        }
+    }
+
+    #[test]
+    fn test_client_messages_conform() {
+        let dump = dump();
+        check::<ClientMessage>(&dump["client_message"], &examples("client"), &examples("invalid_client"));
+    }
+
+    #[test]
+    fn test_server_messages_conform() {
+        let dump = dump();
+        check::<ServerMessage>(&dump["server_message"], &examples("server"), &examples("invalid_server"));
+    }
+
+    #[test]
+    fn test_dump_includes_examples() {
+        let dump = dump();
+        assert_eq!(dump["examples"]["client_message"].as_array().unwrap().len(), examples("client").len());
+        assert_eq!(dump["examples"]["server_message"].as_array().unwrap().len(), examples("server").len());
+        assert_eq!(dump["client_message"]["title"], "ClientMessage");
+    }
+}
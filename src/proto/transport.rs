@@ -0,0 +1,35 @@
+//! WebSocket limits, keepalive timing and paths shared by the server and client ends
+//! of the tunnel transport.
+//!
+//! Both sides configure their WebSocket with the same limits, and the yamux compat
+//! wrappers split writes so no single Binary message exceeds [`MAX_WS_PAYLOAD`].
+//! Keepalive `Ping`/`Pong` control messages travel as Text messages between yamux's
+//! Binary ones.
+
+use std::time::Duration;
+
+/// Largest payload written into a single WebSocket Binary message
+pub const MAX_WS_PAYLOAD: usize = 64 * 1024;
+
+/// Largest WebSocket frame accepted from the peer
+pub const MAX_WS_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Largest (reassembled) WebSocket message accepted from the peer. Fragmented
+/// messages are reassembled by the WebSocket layer up to this size.
+pub const MAX_WS_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// How often the client pings the server by default
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Pings that may go unanswered before a side treats the connection as dead
+pub const MISSED_PINGS: u32 = 3;
+
+/// Where `loophole connect` opens a WebSocket to reach a TCP tunnel, followed by
+/// `/<subdomain>`. Each WebSocket carries one connection's bytes as Binary messages.
+pub const CONNECT_PATH: &str = "/_tunnel/tcp";
+
+/// First byte of a yamux stream the server opens to measure the tunnel's round trip,
+/// rather than to send a request. HTTP requests start with a method, so it can't be
+/// mistaken for one. The client echoes everything after it straight back; the server
+/// sends each probe as an 8-byte big-endian sequence number.
+pub const ECHO_STREAM: u8 = 0;
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::admin_client::{self, AdminClient};
+use crate::client_config::TlsOptions;
+
+#[derive(Debug, Serialize)]
+struct NewReservation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedReservation {
+    subdomain: String,
+    owner: String,
+    saved: bool,
+}
+
+/// Reserve a subdomain for a token via the admin API, or release its reservation
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    subdomain: String,
+    owner: Option<String>,
+    release: bool,
+    server: Option<String>,
+    token: Option<String>,
+    config_path: String,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    let (server, token) = admin_client::resolve_credentials(server, token, &config_path)?;
+    let client = AdminClient::new(&server, &token, timeout, &admin_client::resolve_tls(&server, tls)?)?;
+
+    if release {
+        client.delete(&format!("/_admin/reservations/{}", subdomain)).await?;
+        println!("{} Released {}; any token may register it now", "✓".green(), subdomain.green());
+        return Ok(());
+    }
+
+    let reservation = reserve(&client, &subdomain, owner).await?;
+    println!(
+        "{} Reserved {} for token {}",
+        "✓".green(),
+        reservation.subdomain.green(),
+        reservation.owner
+    );
+    if !reservation.saved {
+        println!(
+            "  {} The server has nowhere to save it, so it lasts until the server restarts",
+            "!".yellow()
+        );
+    }
+    Ok(())
+}
+
+async fn reserve(client: &AdminClient, subdomain: &str, owner: Option<String>) -> Result<CreatedReservation> {
+    let path = format!("/_admin/reservations/{}", subdomain);
+    Ok(client.put_json(&path, &NewReservation { token: owner }).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::put, Json, Router};
+
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let server = mock_server(
+            Router::new()
+                .route(
+                    "/_admin/reservations/myapp",
+                    put(|Json(body): Json<serde_json::Value>| async move {
+                        let owner = match body.get("token") {
+                            Some(_) => "9f86d081884c7d65",
+                            None => "a665a45920422f9d",
+                        };
+                        Json(serde_json::json!({ "subdomain": "myapp", "owner": owner, "saved": true }))
+                    }),
+                )
+                .route(
+                    "/_admin/reservations/taken",
+                    put(|| async {
+                        (StatusCode::CONFLICT, Json(serde_json::json!({ "error": "'taken' is connected with another token" })))
+                    }),
+                ),
+        )
+        .await;
+        let client = AdminClient::new(&server, "tk_admin", Duration::from_secs(5), &TlsOptions::default()).unwrap();
+
+        // For the caller's own token unless another is given
+        let reservation = reserve(&client, "myapp", None).await.unwrap();
+        assert_eq!(reservation.owner, "a665a45920422f9d");
+        let reservation = reserve(&client, "myapp", Some("tk_alice".to_string())).await.unwrap();
+        assert_eq!(reservation.owner, "9f86d081884c7d65");
+        assert!(reservation.saved);
+
+        let err = reserve(&client, "taken", None).await.unwrap_err();
+        assert!(err.to_string().contains("connected with another token"), "{}", err);
+    }
+}
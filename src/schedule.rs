@@ -0,0 +1,351 @@
+//! Recurring windows, written as a cron expression and how long each occurrence lasts,
+//! e.g. `0 2 * * * for 30m` for half an hour from 2am every day.
+//!
+//! The cron part is the usual five fields (minute, hour, day of month, month, day of
+//! week) with `*`, lists, ranges, steps and month or day names, or one of `@hourly`,
+//! `@daily`, `@weekly`, `@monthly` and `@yearly`. As in cron, a day matches if either
+//! day field does when both are restricted. Times are wall-clock times in a time zone,
+//! so a window that starts in the hour skipped when clocks go forward doesn't happen
+//! that day, and one in the hour repeated when they go back happens twice.
+
+use jiff::civil::DateTime;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::units;
+
+/// Longest a window may last, which also bounds how far back an occurrence in
+/// progress is looked for
+pub const MAX_LENGTH: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Overlapping or back-to-back windows joined up before giving up on finding the end
+const MAX_CHAINED: usize = 64;
+
+/// A cron expression and how long each occurrence lasts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    cron: Cron,
+    length: Duration,
+    /// As written, for showing back
+    text: String,
+}
+
+impl Window {
+    /// Parse `<cron> for <duration>`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let text = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        let (cron, length) = text
+            .rsplit_once(" for ")
+            .ok_or_else(|| format!("'{}' should be a cron expression and a length, e.g. \"0 2 * * * for 30m\"", value))?;
+        let length = units::parse_duration(length)?;
+        if length < Duration::from_secs(60) {
+            return Err(format!("'{}' is shorter than a minute", value));
+        }
+        if length > MAX_LENGTH {
+            return Err(format!("'{}' is longer than {}", value, units::format_duration(MAX_LENGTH)));
+        }
+        Ok(Self {
+            cron: Cron::parse(cron)?,
+            length,
+            text,
+        })
+    }
+
+    /// When the occurrence in progress at `now` ends, wall-clock times being in `tz`;
+    /// None if there isn't one
+    pub fn active_until(&self, now: Timestamp, tz: &TimeZone) -> Option<Timestamp> {
+        let now = now.as_second();
+        let length = self.length.as_secs() as i64;
+        // Occurrences start on the minute; the latest to start ends last
+        let mut start = now - now.rem_euclid(60);
+        while start + length > now {
+            let at = Timestamp::from_second(start).ok()?;
+            if self.cron.matches(tz.to_datetime(at)) {
+                return Timestamp::from_second(start + length).ok();
+            }
+            start -= 60;
+        }
+        None
+    }
+}
+
+/// When the windows in progress at `now` end, following on through any that overlap
+/// or start as another ends; None if none is in progress
+pub fn active_until<'a>(
+    windows: impl IntoIterator<Item = &'a Window> + Clone,
+    now: Timestamp,
+    tz: &TimeZone,
+) -> Option<Timestamp> {
+    let until = windows.clone().into_iter().filter_map(|w| w.active_until(now, tz)).max()?;
+    Some(extend(windows, until, tz))
+}
+
+/// Follow on from `until`, when a window ends, through any of `windows` in progress
+/// then or starting just as it ends
+pub fn extend<'a>(windows: impl IntoIterator<Item = &'a Window> + Clone, mut until: Timestamp, tz: &TimeZone) -> Timestamp {
+    let latest = |at: Timestamp| windows.clone().into_iter().filter_map(|w| w.active_until(at, tz)).max();
+    for _ in 0..MAX_CHAINED {
+        match latest(until) {
+            Some(later) if later > until => until = later,
+            _ => break,
+        }
+    }
+    until
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl serde::Serialize for Window {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Window {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The time zone named `name`, e.g. "Europe/London" or "UTC"
+pub fn time_zone(name: &str) -> Result<TimeZone, String> {
+    TimeZone::get(name).map_err(|_| format!("Unknown time zone '{}'; use a name like \"Europe/London\"", name))
+}
+
+/// Which minutes, hours, days and months a cron expression matches, as bit sets
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0
+    weekdays: u64,
+    /// Whether each day field is `*`, for cron's either-day rule
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.to_ascii_lowercase().as_str() {
+            "@hourly" => "0 * * * *".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            other => other.to_string(),
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "'{}' should have five fields (minute, hour, day of month, month, day of week)",
+                expression
+            ));
+        };
+        let weekdays = field(weekday, 0, 7, &WEEKDAYS, 0).map_err(|e| format!("Day of week: {}", e))?;
+        Ok(Self {
+            minutes: field(minute, 0, 59, &[], 0).map_err(|e| format!("Minute: {}", e))?,
+            hours: field(hour, 0, 23, &[], 0).map_err(|e| format!("Hour: {}", e))?,
+            days: field(day, 1, 31, &[], 1).map_err(|e| format!("Day of month: {}", e))?,
+            months: field(month, 1, 12, &MONTHS, 1).map_err(|e| format!("Month: {}", e))?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    fn matches(&self, at: DateTime) -> bool {
+        let has = |set: u64, value: i8| set & (1 << value) != 0;
+        let day = has(self.days, at.day());
+        let weekday = has(self.weekdays, at.weekday().to_sunday_zero_offset());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, at.minute()) && has(self.hours, at.hour()) && has(self.months, at.month()) && day_matches
+    }
+}
+
+/// The values one field matches as a bit set. `names` are the values' names from
+/// `first_name` on.
+fn field(value: &str, min: u8, max: u8, names: &[&str], first_name: u8) -> Result<u64, String> {
+    let number = |part: &str| -> Result<u8, String> {
+        let n = match names.iter().position(|name| *name == part) {
+            Some(index) => index as u8 + first_name,
+            None => part.parse().map_err(|_| format!("'{}' isn't a number", part))?,
+        };
+        if n < min || n > max {
+            return Err(format!("{} is outside {}-{}", n, min, max));
+        }
+        Ok(n)
+    };
+
+    let mut set = 0u64;
+    for part in value.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u8 = step.parse().map_err(|_| format!("'{}' isn't a step", step))?;
+                if step == 0 {
+                    return Err(format!("'{}' has a step of zero", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `5/15` runs from 5 to the end
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(format!("'{}' runs backwards", range));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(datetime: &str, tz: &TimeZone) -> Timestamp {
+        datetime.parse::<DateTime>().unwrap().to_zoned(tz.clone()).unwrap().timestamp()
+    }
+
+    #[test]
+    fn test_parse() {
+        let window = Window::parse("0 2 * * *   for 30m").unwrap();
+        assert_eq!(window.length, Duration::from_secs(30 * 60));
+        assert_eq!(window.to_string(), "0 2 * * * for 30m");
+
+        let cron = Cron::parse("*/15 9-17 * jan,JUL mon-fri").unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, (9..=17).fold(0, |set, h| set | 1 << h));
+        assert_eq!(cron.months, 1 << 1 | 1 << 7);
+        assert_eq!(cron.weekdays, 0b0111110);
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(Cron::parse("5/20 0 * * *").unwrap().minutes, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(Cron::parse("@weekly").unwrap(), Cron::parse("0 0 * * sun").unwrap());
+
+        for (value, error) in [
+            ("0 2 * * *", "cron expression and a length"),
+            ("0 2 * * for 30m", "five fields"),
+            ("60 2 * * * for 30m", "Minute: 60 is outside 0-59"),
+            ("0 2 0 * * for 30m", "Day of month: 0 is outside 1-31"),
+            ("0 2 * * 8 for 30m", "Day of week"),
+            ("0 5-2 * * * for 30m", "runs backwards"),
+            ("*/0 * * * * for 30m", "step of zero"),
+            ("0 2 * * * for 30s", "shorter than a minute"),
+            ("0 2 * * * for 8d", "longer than"),
+            ("0 2 * * * for soon", "soon"),
+        ] {
+            let err = Window::parse(value).unwrap_err();
+            assert!(err.contains(error), "{}: {}", value, err);
+        }
+    }
+
+    #[test]
+    fn test_active_until() {
+        let utc = TimeZone::UTC;
+        let window = Window::parse("0 2 * * * for 30m").unwrap();
+        assert_eq!(window.active_until(at("2026-03-10T01:59:59", &utc), &utc), None);
+        assert_eq!(window.active_until(at("2026-03-10T02:00", &utc), &utc), Some(at("2026-03-10T02:30", &utc)));
+        assert_eq!(window.active_until(at("2026-03-10T02:29:59", &utc), &utc), Some(at("2026-03-10T02:30", &utc)));
+        assert_eq!(window.active_until(at("2026-03-10T02:30", &utc), &utc), None);
+
+        // Across midnight, only from months with a 31st
+        let window = Window::parse("30 23 31 * * for 1h").unwrap();
+        assert_eq!(window.active_until(at("2026-05-01T00:15", &utc), &utc), None);
+        assert_eq!(window.active_until(at("2026-07-01T00:15", &utc), &utc), None);
+        assert_eq!(window.active_until(at("2026-08-01T00:15", &utc), &utc), Some(at("2026-08-01T00:30", &utc)));
+
+        // Either day field, when both are restricted: the 13th, or any Friday
+        let window = Window::parse("0 0 13 * fri for 1d").unwrap();
+        assert!(window.active_until(at("2026-10-13T12:00", &utc), &utc).is_some()); // Tuesday
+        assert!(window.active_until(at("2026-10-16T12:00", &utc), &utc).is_some()); // Friday
+        assert!(window.active_until(at("2026-10-14T12:00", &utc), &utc).is_none());
+    }
+
+    #[test]
+    fn test_time_zones() {
+        let new_york = time_zone("America/New_York").unwrap();
+        let window = Window::parse("0 2 * * * for 30m").unwrap();
+
+        // 2am in New York is 7am UTC in winter and 6am in summer
+        let winter = "2026-01-15T07:15:00Z".parse().unwrap();
+        assert_eq!(window.active_until(winter, &new_york), Some("2026-01-15T07:30:00Z".parse().unwrap()));
+        assert_eq!(window.active_until("2026-01-15T06:15:00Z".parse().unwrap(), &new_york), None);
+        let summer = "2026-07-15T06:15:00Z".parse().unwrap();
+        assert_eq!(window.active_until(summer, &new_york), Some("2026-07-15T06:30:00Z".parse().unwrap()));
+
+        // 2:00-2:59 doesn't happen the day the clocks go forward...
+        let spring_forward = "2026-03-08T07:15:00Z".parse().unwrap();
+        assert_eq!(window.active_until(spring_forward, &new_york), None);
+        // ...and a window across the change still lasts its length in real time
+        let overnight = Window::parse("30 1 * * * for 1h").unwrap();
+        assert_eq!(
+            overnight.active_until("2026-03-08T07:15:00Z".parse().unwrap(), &new_york),
+            Some("2026-03-08T07:30:00Z".parse().unwrap())
+        );
+        // 1:30 happens twice the day they go back, so the window does too
+        let fall_back = "2026-11-01T06:45:00Z".parse().unwrap();
+        assert_eq!(
+            overnight.active_until(fall_back, &new_york),
+            Some("2026-11-01T07:30:00Z".parse().unwrap())
+        );
+
+        assert!(time_zone("Mars/Olympus_Mons").unwrap_err().contains("Unknown time zone"));
+    }
+
+    #[test]
+    fn test_overlapping_windows() {
+        let utc = TimeZone::UTC;
+        let windows = [
+            Window::parse("0 2 * * * for 30m").unwrap(),
+            Window::parse("15 2 * * * for 1h").unwrap(),
+            // Starts just as the one above ends
+            Window::parse("15 3 * * * for 15m").unwrap(),
+            Window::parse("0 12 * * * for 10m").unwrap(),
+        ];
+
+        assert_eq!(active_until(&windows, at("2026-05-01T02:05", &utc), &utc), Some(at("2026-05-01T03:30", &utc)));
+        assert_eq!(active_until(&windows, at("2026-05-01T03:20", &utc), &utc), Some(at("2026-05-01T03:30", &utc)));
+        assert_eq!(active_until(&windows, at("2026-05-01T12:05", &utc), &utc), Some(at("2026-05-01T12:10", &utc)));
+        assert_eq!(active_until(&windows, at("2026-05-01T11:59", &utc), &utc), None);
+        assert_eq!(active_until(&[] as &[Window], at("2026-05-01T02:05", &utc), &utc), None);
+        assert_eq!(extend(&windows, at("2026-05-01T02:30", &utc), &utc), at("2026-05-01T03:30", &utc));
+        assert_eq!(extend(&windows, at("2026-05-01T04:00", &utc), &utc), at("2026-05-01T04:00", &utc));
+
+        // Windows that never end stop being followed eventually
+        let always = [Window::parse("* * * * * for 1m").unwrap()];
+        let now = at("2026-05-01T00:00", &utc);
+        let until = active_until(&always, now, &utc).unwrap();
+        assert_eq!(until.as_second() - now.as_second(), 60 * (MAX_CHAINED as i64 + 1));
+    }
+}
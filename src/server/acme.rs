@@ -5,43 +5,166 @@ use http_body_util::Full;
 use hyper_util::client::legacy::Client as HyperClient;
 use hyper_util::rt::TokioExecutor;
 use instant_acme::{
-    Account, AuthorizationStatus, ChallengeType, HttpClient, Identifier, NewAccount, NewOrder,
-    OrderStatus,
+    Account, AuthorizationStatus, BytesResponse, Challenge, ChallengeType, HttpClient, Identifier,
+    NewAccount, NewOrder, Order, OrderStatus,
 };
-use rcgen::{CertificateParams, DistinguishedName, KeyPair};
-use rustls::pki_types::CertificateDer;
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
 use rustls::RootCertStore;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::fs;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, error, info, warn};
 
+use crate::clock::ServerDate;
+
+use super::cert_store::{CertStore, Item};
+use super::dns_monitor::DohLookup;
+use super::dns_provider::DnsProvider;
+
+/// How long a challenge token is served before it's considered abandoned
+const CHALLENGE_TTL: Duration = Duration::from_secs(15 * 60);
+/// Upper bound on stored tokens; the oldest are evicted beyond this
+const MAX_CHALLENGE_TOKENS: usize = 1000;
+
+/// How often the resolver is asked whether a DNS-01 record is visible yet
+const DNS_PROPAGATION_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for a DNS-01 record to show up before letting the ACME server
+/// look for it anyway
+const DNS_PROPAGATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The ALPN protocol TLS-ALPN-01 validators ask for, and the only one they offer
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// How the ACME server checks the server holds a domain, unless `[https.dns]`
+/// answers challenges over DNS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    /// A token served over plain HTTP on port 80
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// A certificate made for the challenge, served on port 443 to handshakes asking
+    /// for `acme-tls/1`, for hosts that can't open port 80
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "http-01" => Ok(AcmeChallenge::Http01),
+            "tls-alpn-01" => Ok(AcmeChallenge::TlsAlpn01),
+            _ => Err(format!("expected http-01 or tls-alpn-01, got '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for AcmeChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AcmeChallenge::Http01 => "HTTP-01",
+            AcmeChallenge::TlsAlpn01 => "TLS-ALPN-01",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ChallengeEntry {
+    key_auth: String,
+    inserted: Instant,
+}
+
 /// Stores HTTP-01 challenge tokens for ACME validation
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct ChallengeStore {
     /// Maps challenge token -> key authorization
-    tokens: DashMap<String, String>,
+    tokens: DashMap<String, ChallengeEntry>,
+    /// Lookups for tokens we never issued (a sign of path probing)
+    unknown_hits: AtomicU64,
+    ttl: Duration,
+    max_tokens: usize,
+}
+
+impl Default for ChallengeStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChallengeStore {
     pub fn new() -> Self {
+        Self::with_limits(CHALLENGE_TTL, MAX_CHALLENGE_TOKENS)
+    }
+
+    pub fn with_limits(ttl: Duration, max_tokens: usize) -> Self {
         Self {
             tokens: DashMap::new(),
+            unknown_hits: AtomicU64::new(0),
+            ttl,
+            max_tokens,
         }
     }
 
     pub fn set(&self, token: &str, key_auth: &str) {
         info!("ACME: Setting challenge token {} (key_auth length: {})", token, key_auth.len());
-        self.tokens.insert(token.to_string(), key_auth.to_string());
+        self.purge_expired();
+
+        // Evict the oldest tokens to stay under the cap
+        while self.tokens.len() >= self.max_tokens {
+            let oldest = self
+                .tokens
+                .iter()
+                .min_by_key(|e| e.value().inserted)
+                .map(|e| e.key().clone());
+            match oldest {
+                Some(oldest) => {
+                    warn!("ACME: Challenge store full, evicting token {}", oldest);
+                    self.tokens.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        self.tokens.insert(
+            token.to_string(),
+            ChallengeEntry {
+                key_auth: key_auth.to_string(),
+                inserted: Instant::now(),
+            },
+        );
     }
 
+    /// Store a token that is removed again when the returned guard is dropped,
+    /// so every exit path of an ACME flow (including errors and panics) cleans up
+    pub fn set_guarded(&self, token: &str, key_auth: &str) -> ChallengeGuard<'_> {
+        self.set(token, key_auth);
+        ChallengeGuard {
+            store: self,
+            token: token.to_string(),
+        }
+    }
+
+    /// Look up a token, comparing against every stored token so the time taken
+    /// doesn't reveal how close a guess was
     pub fn get(&self, token: &str) -> Option<String> {
-        let result = self.tokens.get(token).map(|v| v.clone());
+        self.purge_expired();
+
+        let mut result = None;
+        for entry in self.tokens.iter() {
+            if constant_time_eq(entry.key().as_bytes(), token.as_bytes()) {
+                result = Some(entry.value().key_auth.clone());
+            }
+        }
         if result.is_some() {
             debug!("ACME: Challenge token {} found", token);
         } else {
-            warn!("ACME: Challenge token {} NOT found (available tokens: {})", token, self.tokens.len());
+            self.unknown_hits.fetch_add(1, Ordering::Relaxed);
         }
         result
     }
@@ -50,23 +173,177 @@ impl ChallengeStore {
         debug!("ACME: Removing challenge token {}", token);
         self.tokens.remove(token);
     }
+
+    /// Drop tokens older than the TTL
+    pub fn purge_expired(&self) {
+        let ttl = self.ttl;
+        self.tokens.retain(|token, entry| {
+            let keep = entry.inserted.elapsed() < ttl;
+            if !keep {
+                debug!("ACME: Challenge token {} expired", token);
+            }
+            keep
+        });
+    }
+
+    /// Number of stored tokens
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Total number of lookups for unknown tokens
+    pub fn unknown_hits(&self) -> u64 {
+        self.unknown_hits.load(Ordering::Relaxed)
+    }
+}
+
+/// Removes a challenge token from the store when dropped
+pub struct ChallengeGuard<'a> {
+    store: &'a ChallengeStore,
+    token: String,
+}
+
+impl Drop for ChallengeGuard<'_> {
+    fn drop(&mut self) {
+        self.store.remove(&self.token);
+    }
+}
+
+/// Certificates answering TLS-ALPN-01 challenges, by the domain being validated. Each
+/// is only kept while its validation runs.
+#[derive(Debug, Default)]
+pub struct TlsAlpnChallenges {
+    certs: DashMap<String, Arc<CertifiedKey>>,
+}
+
+impl TlsAlpnChallenges {
+    /// Serve `cert` to validators of `domain` until the returned guard is dropped
+    pub fn set_guarded(&self, domain: &str, cert: CertifiedKey) -> TlsAlpnGuard<'_> {
+        let domain = domain.to_ascii_lowercase();
+        debug!("ACME: Serving TLS-ALPN-01 challenge certificate for {}", domain);
+        self.certs.insert(domain.clone(), Arc::new(cert));
+        TlsAlpnGuard { store: self, domain }
+    }
+
+    pub fn get(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs.get(domain.to_ascii_lowercase().as_str()).map(|cert| cert.clone())
+    }
+
+    /// Number of challenges being validated
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.certs.len()
+    }
+}
+
+/// Stops serving a TLS-ALPN-01 challenge certificate when dropped
+pub struct TlsAlpnGuard<'a> {
+    store: &'a TlsAlpnChallenges,
+    domain: String,
+}
+
+impl Drop for TlsAlpnGuard<'_> {
+    fn drop(&mut self) {
+        debug!("ACME: Removing TLS-ALPN-01 challenge certificate for {}", self.domain);
+        self.store.certs.remove(&self.domain);
+    }
+}
+
+/// The self-signed certificate answering a TLS-ALPN-01 challenge for `domain`: the
+/// domain as its only name, and the SHA-256 of the key authorization in a critical
+/// acmeIdentifier extension (RFC 8737)
+pub fn tls_alpn_certificate(domain: &str, key_auth_digest: &[u8]) -> Result<CertifiedKey> {
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.distinguished_name = DistinguishedName::new();
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(key_auth_digest)];
+    let cert = params.self_signed(&key_pair)?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
+        .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], signing_key))
+}
+
+/// Compare two byte strings without short-circuiting on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 /// ACME client for requesting certificates from Let's Encrypt
 pub struct AcmeClient {
     account: Account,
-    certs_dir: PathBuf,
+    store: Arc<dyn CertStore>,
     challenge_store: Arc<ChallengeStore>,
+    directory_date: Option<ServerDate>,
+    /// Answers challenges over DNS instead of HTTP, when the zone's provider is set
+    dns: Option<Dns01>,
+    /// Answers challenges over TLS instead of HTTP, when `challenge = "tls-alpn-01"`
+    tls_alpn: Option<Arc<TlsAlpnChallenges>>,
 }
 
 impl std::fmt::Debug for AcmeClient {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AcmeClient")
-            .field("certs_dir", &self.certs_dir)
+            .field("store", &self.store)
+            .field("dns", &self.dns.as_ref().map(|dns| &dns.provider))
+            .field("tls_alpn", &self.tls_alpn.is_some())
             .finish_non_exhaustive()
     }
 }
 
+/// Publishes DNS-01 challenge records, and checks they can be seen
+struct Dns01 {
+    provider: Arc<dyn DnsProvider>,
+    lookup: DohLookup,
+}
+
+impl Dns01 {
+    /// Publish a TXT record `name` with `value` for as long as `validate` runs, and
+    /// remove it again whether that succeeds or not
+    async fn with_txt(&self, name: &str, value: &str, validate: impl Future<Output = Result<()>>) -> Result<()> {
+        let record = self
+            .provider
+            .create_txt(name, value)
+            .await
+            .with_context(|| format!("Failed to publish TXT record {}", name))?;
+        let result = validate.await;
+        if let Err(e) = self.provider.delete_txt(&record).await {
+            warn!("ACME: Failed to remove TXT record {}: {:#}", name, e);
+        }
+        result
+    }
+
+    /// Wait until the resolver sees `value` at `name`, so the ACME server isn't asked
+    /// to look before the record has spread. Gives up after [`DNS_PROPAGATION_TIMEOUT`]
+    /// and leaves it to the ACME server, which asks the zone's own nameservers.
+    async fn wait_for_txt(&self, name: &str, value: &str) {
+        let deadline = Instant::now() + DNS_PROPAGATION_TIMEOUT;
+        loop {
+            tokio::time::sleep(DNS_PROPAGATION_INTERVAL).await;
+            match self.lookup.txt(name).await {
+                Ok(values) if values.iter().any(|found| found == value) => {
+                    debug!("ACME: TXT record {} is visible", name);
+                    return;
+                }
+                Ok(_) => debug!("ACME: TXT record {} isn't visible yet", name),
+                Err(e) => debug!("ACME: Failed to look up TXT record {}: {:#}", name, e),
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "ACME: TXT record {} still isn't visible after {}s, asking for validation anyway",
+                    name,
+                    DNS_PROPAGATION_TIMEOUT.as_secs()
+                );
+                return;
+            }
+        }
+    }
+}
+
 /// Certificate and private key pair
 pub struct Certificate {
     pub cert_pem: String,
@@ -83,9 +360,9 @@ fn create_http_client_with_roots(
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
 
     // Add additional roots if provided
-    if let Some(pem_data) = additional_roots {
+    if let Some(mut pem_data) = additional_roots {
         let certs: Vec<CertificateDer<'static>> =
-            rustls_pemfile::certs(&mut pem_data.as_ref())
+            rustls_pemfile::certs(&mut pem_data)
                 .filter_map(|r| r.ok())
                 .collect();
         for cert in certs {
@@ -109,61 +386,121 @@ fn create_http_client_with_roots(
     Ok(Box::new(client))
 }
 
+/// Passes requests to the ACME server through, keeping the `Date` of the first
+/// response (the directory) so the system clock can be checked against it
+struct DateRecordingClient {
+    inner: Box<dyn HttpClient>,
+    first_date: Arc<OnceLock<ServerDate>>,
+}
+
+impl HttpClient for DateRecordingClient {
+    fn request(
+        &self,
+        req: hyper::Request<Full<Bytes>>,
+    ) -> Pin<Box<dyn Future<Output = Result<BytesResponse, instant_acme::Error>> + Send>> {
+        let response = self.inner.request(req);
+        let first_date = self.first_date.clone();
+        Box::pin(async move {
+            let response = response.await?;
+            let date = response.parts.headers.get(hyper::header::DATE);
+            if let Some(date) = date.and_then(|date| date.to_str().ok()) {
+                if let Some(date) = ServerDate::parse(date, SystemTime::now()) {
+                    let _ = first_date.set(date);
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
 impl AcmeClient {
     /// Create a new ACME client
     #[allow(dead_code)]
     pub async fn new(
         email: &str,
         directory_url: &str,
-        certs_dir: PathBuf,
+        store: Arc<dyn CertStore>,
         challenge_store: Arc<ChallengeStore>,
     ) -> Result<Self> {
-        Self::new_with_roots(email, directory_url, certs_dir, challenge_store, None).await
+        Self::new_with_roots(email, directory_url, store, challenge_store, None).await
     }
 
     /// Create a new ACME client with additional root CAs (for testing with Pebble)
     pub async fn new_with_roots(
         email: &str,
         directory_url: &str,
-        certs_dir: PathBuf,
+        store: Arc<dyn CertStore>,
         challenge_store: Arc<ChallengeStore>,
         additional_roots: Option<&[u8]>,
     ) -> Result<Self> {
-        // Create certs directory if it doesn't exist
-        fs::create_dir_all(&certs_dir)
-            .await
-            .context("Failed to create certs directory")?;
-
-        // Create or load ACME account
+        // Create or load ACME account, which fetches the directory
+        let first_date = Arc::new(OnceLock::new());
+        let http_client = || -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(DateRecordingClient {
+                inner: create_http_client_with_roots(additional_roots)?,
+                first_date: first_date.clone(),
+            }))
+        };
         let account =
-            Self::get_or_create_account(email, directory_url, &certs_dir, additional_roots).await?;
+            Self::get_or_create_account(email, directory_url, store.as_ref(), http_client).await?;
 
         Ok(Self {
             account,
-            certs_dir,
+            store,
             challenge_store,
+            directory_date: first_date.get().copied(),
+            dns: None,
+            tls_alpn: None,
         })
     }
 
+    /// Answer challenges with TXT records published through `provider` instead of
+    /// over HTTP, checking with the DNS-over-HTTPS `resolver` that they're visible.
+    /// Unlike HTTP-01 this can issue wildcard certificates.
+    pub fn with_dns(mut self, provider: Arc<dyn DnsProvider>, resolver: String) -> Self {
+        self.dns = Some(Dns01 {
+            provider,
+            lookup: DohLookup::new(resolver),
+        });
+        self
+    }
+
+    /// Answer challenges with certificates served on the HTTPS port to handshakes asking
+    /// for `acme-tls/1`, instead of over HTTP. The certificates are kept in
+    /// [`tls_alpn_challenges`](Self::tls_alpn_challenges) for the TLS resolver.
+    pub fn with_tls_alpn(mut self) -> Self {
+        self.tls_alpn = Some(Arc::new(TlsAlpnChallenges::default()));
+        self
+    }
+
+    /// The TLS-ALPN-01 challenge certificates being validated, if this client uses them
+    pub fn tls_alpn_challenges(&self) -> Option<Arc<TlsAlpnChallenges>> {
+        self.tls_alpn.clone()
+    }
+
+    /// Whether this client can issue wildcard certificates, which needs DNS-01
+    pub fn issues_wildcards(&self) -> bool {
+        self.dns.is_some()
+    }
+
+    /// The `Date` of the ACME directory response, for checking the system clock
+    pub fn directory_date(&self) -> Option<ServerDate> {
+        self.directory_date
+    }
+
     async fn get_or_create_account(
         email: &str,
         directory_url: &str,
-        certs_dir: &PathBuf,
-        additional_roots: Option<&[u8]>,
+        store: &dyn CertStore,
+        http_client: impl Fn() -> Result<Box<dyn HttpClient>>,
     ) -> Result<Account> {
-        let account_path = certs_dir.join("account.json");
-
-        let http_client = create_http_client_with_roots(additional_roots)?;
-
         // Try to load existing account
-        if account_path.exists() {
-            let account_data = fs::read_to_string(&account_path).await?;
+        if let Some(account_data) = store.get(Item::Account).await? {
             if let Ok(credentials) =
-                serde_json::from_str::<instant_acme::AccountCredentials>(&account_data)
+                serde_json::from_slice::<instant_acme::AccountCredentials>(&account_data)
             {
                 info!("Loaded existing ACME account");
-                let http_client = create_http_client_with_roots(additional_roots)?;
-                return Account::from_credentials_and_http(credentials, http_client)
+                return Account::from_credentials_and_http(credentials, http_client()?)
                     .await
                     .context("Failed to load ACME account from credentials");
             }
@@ -179,14 +516,14 @@ impl AcmeClient {
             },
             directory_url,
             None,
-            http_client,
+            http_client()?,
         )
         .await
         .context("Failed to create ACME account")?;
 
         // Save account credentials
         let account_json = serde_json::to_string_pretty(&credentials)?;
-        fs::write(&account_path, account_json).await?;
+        store.put(Item::Account, account_json.as_bytes()).await?;
         info!("Saved ACME account credentials");
 
         Ok(account)
@@ -214,6 +551,24 @@ impl AcmeClient {
 
         for auth in authorizations {
             match auth.status {
+                AuthorizationStatus::Pending if self.dns.is_some() => {
+                    let Identifier::Dns(ref name) = auth.identifier;
+                    let challenge = auth
+                        .challenges
+                        .iter()
+                        .find(|c| c.r#type == ChallengeType::Dns01)
+                        .context("No DNS-01 challenge found")?;
+                    self.complete_dns01(&mut order, challenge, name).await?;
+                }
+                AuthorizationStatus::Pending if self.tls_alpn.is_some() => {
+                    let Identifier::Dns(ref name) = auth.identifier;
+                    let challenge = auth
+                        .challenges
+                        .iter()
+                        .find(|c| c.r#type == ChallengeType::TlsAlpn01)
+                        .context("No TLS-ALPN-01 challenge found")?;
+                    self.complete_tls_alpn01(&mut order, challenge, name).await?;
+                }
                 AuthorizationStatus::Pending => {
                     // Find HTTP-01 challenge
                     let challenge = auth
@@ -228,7 +583,8 @@ impl AcmeClient {
                     info!("ACME: HTTP-01 challenge for {}", domain);
                     info!("ACME: Let's Encrypt will request: http://{}/.well-known/acme-challenge/{}", domain, token);
                     debug!("Setting HTTP-01 challenge token: {} for domain: {}", token, domain);
-                    self.challenge_store.set(token, key_auth.as_str());
+                    // Removed when this arm exits, whether validation succeeds or not
+                    let _challenge = self.challenge_store.set_guarded(token, key_auth.as_str());
 
                     // Notify ACME server that challenge is ready
                     order
@@ -237,10 +593,7 @@ impl AcmeClient {
                         .context("Failed to set challenge ready")?;
 
                     // Wait for challenge to be validated
-                    Self::wait_for_order_ready(&mut order).await?;
-
-                    // Clean up challenge token
-                    self.challenge_store.remove(token);
+                    Self::wait_for_order_ready(&mut order, ChallengeType::Http01).await?;
                 }
                 AuthorizationStatus::Valid => {
                     debug!("Authorization already valid for {}", domain);
@@ -280,7 +633,44 @@ impl AcmeClient {
         Ok(Certificate { cert_pem, key_pem })
     }
 
-    async fn wait_for_order_ready(order: &mut instant_acme::Order) -> Result<()> {
+    /// Publish the TXT record answering `challenge` for `name`, have it validated, and
+    /// remove the record again whether validation succeeds or not
+    async fn complete_dns01(&self, order: &mut Order, challenge: &Challenge, name: &str) -> Result<()> {
+        let dns = self.dns.as_ref().context("DNS-01 isn't configured")?;
+        // Wildcard identifiers come without the `*.`, and share the record of their base
+        let record_name = format!("_acme-challenge.{}", name);
+        let value = order.key_authorization(challenge).dns_value();
+
+        info!("ACME: DNS-01 challenge for {}, publishing TXT record {}", name, record_name);
+        dns.with_txt(&record_name, &value, async {
+            dns.wait_for_txt(&record_name, &value).await;
+            order
+                .set_challenge_ready(&challenge.url)
+                .await
+                .context("Failed to set challenge ready")?;
+            Self::wait_for_order_ready(order, ChallengeType::Dns01).await
+        })
+        .await
+    }
+
+    /// Serve the certificate answering `challenge` for `name` and have it validated. The
+    /// certificate is removed again whether validation succeeds or not.
+    async fn complete_tls_alpn01(&self, order: &mut Order, challenge: &Challenge, name: &str) -> Result<()> {
+        let challenges = self.tls_alpn.as_ref().context("TLS-ALPN-01 isn't configured")?;
+        let digest = order.key_authorization(challenge).digest();
+        let cert = tls_alpn_certificate(name, digest.as_ref())
+            .with_context(|| format!("Failed to create the TLS-ALPN-01 certificate for {}", name))?;
+
+        info!("ACME: TLS-ALPN-01 challenge for {}", name);
+        let _challenge = challenges.set_guarded(name, cert);
+        order
+            .set_challenge_ready(&challenge.url)
+            .await
+            .context("Failed to set challenge ready")?;
+        Self::wait_for_order_ready(order, ChallengeType::TlsAlpn01).await
+    }
+
+    async fn wait_for_order_ready(order: &mut Order, challenge_type: ChallengeType) -> Result<()> {
         let mut attempts = 0;
         loop {
             tokio::time::sleep(Duration::from_secs(2)).await;
@@ -292,6 +682,23 @@ impl AcmeClient {
                     debug!("Order is ready");
                     return Ok(());
                 }
+                OrderStatus::Invalid if challenge_type == ChallengeType::Dns01 => {
+                    error!("Order became invalid. This usually means the ACME DNS-01 challenge failed.");
+                    error!("Common causes:");
+                    error!("  1. The TXT record went to a zone that isn't the one the domain is delegated to");
+                    error!("  2. Let's Encrypt looked before the record reached all of the zone's nameservers");
+                    error!("  3. A CAA record for the domain doesn't allow Let's Encrypt");
+                    return Err(anyhow::anyhow!("Order became invalid"));
+                }
+                OrderStatus::Invalid if challenge_type == ChallengeType::TlsAlpn01 => {
+                    error!("Order became invalid. This usually means the ACME TLS-ALPN-01 challenge failed.");
+                    error!("Common causes:");
+                    error!("  1. Let's Encrypt cannot reach your server on port 443");
+                    error!("  2. DNS for the domain does not point to this server");
+                    error!("  3. A proxy in front of the server terminates TLS itself");
+                    error!("Note: Let's Encrypt TLS-ALPN-01 challenges MUST be answered on port 443");
+                    return Err(anyhow::anyhow!("Order became invalid"));
+                }
                 OrderStatus::Invalid => {
                     // Log authorization details to help diagnose the failure
                     error!("Order became invalid. This usually means the ACME HTTP-01 challenge failed.");
@@ -313,7 +720,7 @@ impl AcmeClient {
         }
     }
 
-    async fn wait_for_certificate(order: &mut instant_acme::Order) -> Result<String> {
+    async fn wait_for_certificate(order: &mut Order) -> Result<String> {
         let mut attempts = 0;
         loop {
             tokio::time::sleep(Duration::from_secs(2)).await;
@@ -343,126 +750,279 @@ impl AcmeClient {
     }
 
     async fn save_certificate(&self, domain: &str, cert_pem: &str, key_pem: &str) -> Result<()> {
-        let cert_dir = self.certs_dir.join(domain);
-        fs::create_dir_all(&cert_dir).await?;
-
-        let cert_path = cert_dir.join("cert.pem");
-        let key_path = cert_dir.join("key.pem");
-
-        fs::write(&cert_path, cert_pem).await?;
-        fs::write(&key_path, key_pem).await?;
+        self.store.put(Item::Cert(domain), cert_pem.as_bytes()).await?;
+        self.store.put(Item::Key(domain), key_pem.as_bytes()).await?;
 
-        debug!("Saved certificate to {:?}", cert_path);
+        debug!("Saved certificate for {} to {}", domain, self.store.describe());
         Ok(())
     }
 
-    /// Load an existing certificate from disk
+    /// Load an existing certificate from the store
     #[allow(dead_code)]
     pub async fn load_certificate(&self, domain: &str) -> Result<Option<Certificate>> {
-        let cert_dir = self.certs_dir.join(domain);
-        let cert_path = cert_dir.join("cert.pem");
-        let key_path = cert_dir.join("key.pem");
-
-        if !cert_path.exists() || !key_path.exists() {
+        let cert_pem = self.store.get(Item::Cert(domain)).await?;
+        let key_pem = self.store.get(Item::Key(domain)).await?;
+        let (Some(cert_pem), Some(key_pem)) = (cert_pem, key_pem) else {
             return Ok(None);
-        }
-
-        let cert_pem = fs::read_to_string(&cert_path).await?;
-        let key_pem = fs::read_to_string(&key_path).await?;
+        };
 
-        Ok(Some(Certificate { cert_pem, key_pem }))
+        Ok(Some(Certificate {
+            cert_pem: String::from_utf8(cert_pem).context("Stored certificate is not UTF-8")?,
+            key_pem: String::from_utf8(key_pem).context("Stored key is not UTF-8")?,
+        }))
     }
 
-    /// Check if certificate needs renewal (within 30 days of expiry)
-    #[allow(dead_code)]
-    pub fn needs_renewal(cert_pem: &str) -> bool {
-        use pem::parse;
-
-        let pem = match parse(cert_pem) {
-            Ok(p) => p,
-            Err(e) => {
+    /// Check if certificate needs renewal: its first certificate expires within
+    /// [`RENEW_BEFORE`] of `now`, or its expiry can't be read
+    pub fn needs_renewal(cert_pem: &str, now: SystemTime) -> bool {
+        let cert = match rustls_pemfile::certs(&mut cert_pem.as_bytes()).next() {
+            Some(Ok(cert)) => cert,
+            Some(Err(e)) => {
                 error!("Failed to parse certificate PEM: {}", e);
                 return true;
             }
-        };
-
-        // Parse the certificate to check expiry
-        let cert = match rustls_pemfile::certs(&mut pem.contents().as_ref())
-            .next()
-            .and_then(|r| r.ok())
-        {
-            Some(c) => c,
             None => {
-                error!("Failed to parse certificate DER");
+                error!("No certificate found in PEM");
                 return true;
             }
         };
+        match certificate_expiry(&cert) {
+            Ok(expiry) => expires_within(expiry, now, RENEW_BEFORE),
+            Err(e) => {
+                error!("{:#}", e);
+                true
+            }
+        }
+    }
+}
 
-        // Use webpki to check validity
-        // For simplicity, we'll just check if the file is older than 60 days
-        // In production, you'd parse the X.509 certificate properly
-        let _ = cert;
+/// Renew certificates once fewer than this many days of validity remain. Let's Encrypt
+/// issues them for 90 days and suggests renewing with a third left.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// When a DER certificate stops being valid
+fn certificate_expiry(cert: &CertificateDer<'_>) -> Result<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+    let not_after = cert.validity().not_after.timestamp();
+    let since_epoch = Duration::from_secs(u64::try_from(not_after).unwrap_or(0));
+    Ok(SystemTime::UNIX_EPOCH + since_epoch)
+}
+
+/// Whether `expiry` is less than `margin` after `now`, or already past
+fn expires_within(expiry: SystemTime, now: SystemTime, margin: Duration) -> bool {
+    expiry.duration_since(now).map_or(true, |left| left < margin)
+}
 
-        // Default to renewing if we can't determine expiry
-        // This is a simplified check - in production use x509-parser crate
-        false
+/// A self-signed certificate for `domain` that expires `valid_for` from now
+#[cfg(test)]
+pub(crate) fn test_certificate(domain: &str, valid_for: Duration) -> Certificate {
+    let key_pair = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![domain.to_string()]).unwrap();
+    let not_after = SystemTime::now() + valid_for;
+    let secs = not_after.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    params.not_after = x509_parser::time::ASN1Time::from_timestamp(secs as i64)
+        .unwrap()
+        .to_datetime();
+    let cert = params.self_signed(&key_pair).unwrap();
+    Certificate {
+        cert_pem: cert.pem(),
+        key_pem: key_pair.serialize_pem(),
     }
 }
 
-/// Background task to check and renew certificates
-#[allow(dead_code)]
-pub async fn certificate_renewal_task(
-    acme_client: Arc<AcmeClient>,
-    certs_dir: PathBuf,
-) -> Result<()> {
+/// Background task that periodically drops expired challenge tokens
+pub async fn challenge_sweep_task(
+    challenge_store: Arc<ChallengeStore>,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) {
     loop {
-        tokio::time::sleep(Duration::from_secs(86400)).await; // Check daily
-
-        info!("Checking certificates for renewal...");
-
-        let mut entries = match fs::read_dir(&certs_dir).await {
-            Ok(e) => e,
-            Err(e) => {
-                error!("Failed to read certs directory: {}", e);
-                continue;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(60)) => {
+                challenge_store.purge_expired();
             }
-        };
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+}
 
-        while let Ok(Some(entry)) = entries.next_entry().await {
-            let path = entry.path();
-            if !path.is_dir() {
-                continue;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::dns_provider::TxtRecord;
+    use std::sync::Mutex;
+
+    /// Answers every request with the given `Date` header
+    struct DatedResponses(&'static str);
+
+    impl HttpClient for DatedResponses {
+        fn request(
+            &self,
+            _req: hyper::Request<Full<Bytes>>,
+        ) -> Pin<Box<dyn Future<Output = Result<BytesResponse, instant_acme::Error>> + Send>> {
+            let (parts, _) = hyper::Response::builder()
+                .header(hyper::header::DATE, self.0)
+                .body(())
+                .unwrap()
+                .into_parts();
+            Box::pin(async move { Ok(BytesResponse { parts, body: Box::new(Bytes::new()) }) })
+        }
+    }
 
-            let domain = match path.file_name().and_then(|n| n.to_str()) {
-                Some(d) => d.to_string(),
-                None => continue,
+    #[tokio::test]
+    async fn test_directory_date_is_recorded() {
+        let first_date = Arc::new(OnceLock::new());
+        let request = || hyper::Request::new(Full::default());
+        for date in ["not a date", "Sun, 06 Nov 1994 08:49:37 GMT", "Mon, 07 Nov 1994 08:49:37 GMT"] {
+            let client = DateRecordingClient {
+                inner: Box::new(DatedResponses(date)),
+                first_date: first_date.clone(),
             };
+            client.request(request()).await.unwrap();
+        }
+        // The first valid one, from the directory fetch
+        let date = first_date.get().unwrap();
+        assert_eq!(date.date, httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap());
+        assert!(date.skew_warning("the ACME server").unwrap().contains("ahead of the ACME server's"));
+    }
 
-            // Skip account.json directory check
-            if domain == "account.json" {
-                continue;
-            }
+    /// Keeps the records it's asked to publish
+    #[derive(Debug, Default)]
+    struct FakeDns(Mutex<Vec<TxtRecord>>);
 
-            let cert_path = path.join("cert.pem");
-            if !cert_path.exists() {
-                continue;
-            }
+    #[async_trait::async_trait]
+    impl DnsProvider for FakeDns {
+        async fn create_txt(&self, name: &str, value: &str) -> Result<TxtRecord> {
+            let record = TxtRecord { name: name.to_string(), id: value.to_string() };
+            self.0.lock().unwrap().push(record.clone());
+            Ok(record)
+        }
 
-            let cert_pem = match fs::read_to_string(&cert_path).await {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("Failed to read certificate for {}: {}", domain, e);
-                    continue;
-                }
-            };
+        async fn delete_txt(&self, record: &TxtRecord) -> Result<()> {
+            self.0.lock().unwrap().retain(|published| published != record);
+            Ok(())
+        }
+    }
 
-            if AcmeClient::needs_renewal(&cert_pem) {
-                info!("Certificate for {} needs renewal", domain);
-                if let Err(e) = acme_client.request_certificate(&domain).await {
-                    error!("Failed to renew certificate for {}: {}", domain, e);
-                }
-            }
+    #[tokio::test]
+    async fn test_txt_record_removed_when_validation_fails() {
+        let provider = Arc::new(FakeDns::default());
+        let dns = Dns01 { provider: provider.clone(), lookup: DohLookup::new("http://127.0.0.1:9".to_string()) };
+        let name = "_acme-challenge.example.com";
+
+        let result = dns
+            .with_txt(name, "digest", async {
+                assert_eq!(provider.0.lock().unwrap().len(), 1);
+                anyhow::bail!("Order became invalid")
+            })
+            .await;
+        assert!(result.is_err());
+        assert!(provider.0.lock().unwrap().is_empty());
+
+        dns.with_txt(name, "digest", async { Ok(()) }).await.unwrap();
+        assert!(provider.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_challenge_tokens_expire() {
+        let store = ChallengeStore::with_limits(Duration::from_millis(20), 10);
+        store.set("token", "key-auth");
+        assert_eq!(store.get("token").as_deref(), Some("key-auth"));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("token"), None);
+        assert_eq!(store.len(), 0);
+
+        store.set("a", "1");
+        std::thread::sleep(Duration::from_millis(30));
+        store.purge_expired();
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn test_challenge_guard_cleans_up_on_error() {
+        fn failing_flow(store: &ChallengeStore) -> Result<()> {
+            let _challenge = store.set_guarded("token", "key-auth");
+            assert!(store.get("token").is_some());
+            anyhow::bail!("Order became invalid")
+        }
+
+        let store = ChallengeStore::new();
+        assert!(failing_flow(&store).is_err());
+        assert_eq!(store.get("token"), None);
+        assert_eq!(store.len(), 0);
+
+        // Also on panic
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _challenge = store.set_guarded("token", "key-auth");
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn test_needs_renewal() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let renewed = |valid_for| AcmeClient::needs_renewal(&test_certificate("app.example.com", valid_for).cert_pem, now);
+
+        assert!(!renewed(89 * DAY));
+        assert!(!renewed(31 * DAY));
+        assert!(renewed(29 * DAY));
+        assert!(renewed(Duration::from_secs(60)));
+        // Already lapsed
+        let expired = test_certificate("app.example.com", Duration::ZERO);
+        assert!(AcmeClient::needs_renewal(&expired.cert_pem, now + DAY));
+        // Unreadable certificates are replaced
+        assert!(AcmeClient::needs_renewal("", now));
+        assert!(AcmeClient::needs_renewal("-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n", now));
+    }
+
+    #[test]
+    fn test_tls_alpn_certificate() {
+        let digest = [7u8; 32];
+        let cert = tls_alpn_certificate("app.example.com", &digest).unwrap();
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert.cert[0]).unwrap();
+        let names = parsed.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(names.value.general_names, [x509_parser::extensions::GeneralName::DNSName("app.example.com")]);
+
+        let oid = x509_parser::oid_registry::Oid::from(&[1, 3, 6, 1, 5, 5, 7, 1, 31]).unwrap();
+        let identifier = parsed.get_extension_unique(&oid).unwrap().unwrap();
+        assert!(identifier.critical);
+        // An OCTET STRING holding the digest
+        assert_eq!(identifier.value[..2], [0x04, 32]);
+        assert_eq!(identifier.value[2..], digest);
+    }
+
+    #[test]
+    fn test_tls_alpn_challenges_are_removed() {
+        let challenges = TlsAlpnChallenges::default();
+        let cert = tls_alpn_certificate("app.example.com", &[0; 32]).unwrap();
+        let guard = challenges.set_guarded("App.example.com", cert);
+        assert!(challenges.get("app.example.com").is_some());
+        assert!(challenges.get("api.example.com").is_none());
+        drop(guard);
+        assert!(challenges.get("app.example.com").is_none());
+        assert_eq!(challenges.len(), 0);
+    }
+
+    #[test]
+    fn test_challenge_names() {
+        assert_eq!(AcmeChallenge::parse("TLS-ALPN-01"), Ok(AcmeChallenge::TlsAlpn01));
+        assert_eq!(AcmeChallenge::parse("http-01"), Ok(AcmeChallenge::Http01));
+        assert!(AcmeChallenge::parse("dns-01").unwrap_err().contains("dns-01"));
+    }
+
+    #[test]
+    fn test_challenge_store_cap_evicts_oldest() {
+        let store = ChallengeStore::with_limits(CHALLENGE_TTL, 3);
+        for token in ["a", "b", "c", "d"] {
+            store.set(token, token);
+            std::thread::sleep(Duration::from_millis(2));
         }
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get("a"), None);
+        assert_eq!(store.get("d").as_deref(), Some("d"));
     }
 }
@@ -0,0 +1,188 @@
+//! JSON responses for the admin API's listings, which dashboards poll: tagged with
+//! an ETag so an unchanged listing costs a 304, and gzipped when the client accepts it.
+//! Fields that move with the clock, such as a tunnel's idle time, can be left out of
+//! the ETag, or it would change on every poll.
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ring::digest;
+use serde::Serialize;
+use std::io::Write;
+
+/// Smaller bodies are sent as they are, as gzip's header would eat most of the saving
+const GZIP_MIN_BYTES: usize = 1024;
+
+/// `value` as JSON, or 304 Not Modified if the request's If-None-Match has its ETag
+pub fn respond<T: Serialize>(request: &HeaderMap, value: &T) -> Response {
+    respond_ignoring(request, value, &[])
+}
+
+/// Like [`respond`], with an ETag that leaves out the `volatile` fields, wherever they
+/// are in `value`. A 304 then means only those have changed since the tagged listing.
+pub fn respond_ignoring<T: Serialize>(request: &HeaderMap, value: &T, volatile: &[&str]) -> Response {
+    let value = match serde_json::to_value(value) {
+        Ok(value) => value,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let body = value.to_string().into_bytes();
+    let etag = if volatile.is_empty() {
+        etag(&body)
+    } else {
+        let mut stable = value;
+        strip(&mut stable, volatile);
+        etag(stable.to_string().as_bytes())
+    };
+    let vary = (header::VARY, "Accept-Encoding".to_string());
+
+    if not_modified(request, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag), vary]).into_response();
+    }
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/json".to_string()),
+        (header::ETAG, etag),
+        vary,
+    ];
+    if body.len() >= GZIP_MIN_BYTES && accepts_gzip(request) {
+        if let Ok(compressed) = gzip(&body) {
+            return (headers, [(header::CONTENT_ENCODING, "gzip")], compressed).into_response();
+        }
+    }
+    (headers, body).into_response()
+}
+
+/// A strong ETag from the body's SHA-256
+fn etag(body: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, body);
+    let hex: String = digest.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Remove the `fields` from every object in `value`
+fn strip(value: &mut serde_json::Value, fields: &[&str]) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|key, _| !fields.contains(&key.as_str()));
+            object.values_mut().for_each(|value| strip(value, fields));
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|value| strip(value, fields)),
+        _ => {}
+    }
+}
+
+fn not_modified(request: &HeaderMap, etag: &str) -> bool {
+    request
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        // A GET compares weakly, so W/"x" matches "x"
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Whether Accept-Encoding allows gzip, i.e. names it (or `*`) without q=0
+fn accepts_gzip(request: &HeaderMap) -> bool {
+    request
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, "gzip")])));
+        assert!(accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, "br, GZIP;q=0.5")])));
+        assert!(accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, "*")])));
+        assert!(!accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, "gzip;q=0")])));
+        assert!(!accepts_gzip(&headers(&[(header::ACCEPT_ENCODING, "deflate, br")])));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = etag(b"{}");
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, &etag)]), &etag));
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, &format!("\"old\", W/{}", etag))]), &etag));
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, "*")]), &etag));
+        assert!(!not_modified(&headers(&[(header::IF_NONE_MATCH, "\"old\"")]), &etag));
+        assert!(!not_modified(&HeaderMap::new(), &etag));
+    }
+
+    #[tokio::test]
+    async fn test_gzips_large_bodies() {
+        let large: Vec<String> = (0..200).map(|i| format!("tunnel-{}", i)).collect();
+        let gzip = headers(&[(header::ACCEPT_ENCODING, "gzip, deflate")]);
+
+        let response = respond(&gzip, &large);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        let etag = response.headers()[header::ETAG].clone();
+        let mut json = String::new();
+        GzDecoder::new(&body(response).await[..]).read_to_string(&mut json).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<String>>(&json).unwrap(), large);
+
+        // The ETag is the same either way, as it's of the JSON
+        let plain = respond(&HeaderMap::new(), &large);
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(plain.headers()[header::ETAG], etag);
+
+        // Small bodies aren't worth it
+        let small = respond(&gzip, &["tunnel-1"]);
+        assert!(small.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body(small).await, b"[\"tunnel-1\"]");
+    }
+
+    #[tokio::test]
+    async fn test_volatile_fields_leave_etag_alone() {
+        let listing = |idle_secs: u64, requests: u64| {
+            serde_json::json!({ "tunnels": [{ "subdomain": "myapp", "idle_secs": idle_secs, "request_count": requests }], "count": 1 })
+        };
+        let tag = |value: &serde_json::Value| respond_ignoring(&HeaderMap::new(), value, &["idle_secs"]).headers()[header::ETAG].clone();
+        assert_eq!(tag(&listing(3, 10)), tag(&listing(4, 10)));
+        assert_ne!(tag(&listing(3, 10)), tag(&listing(3, 11)));
+
+        // The body still has them
+        let response = respond_ignoring(&HeaderMap::new(), &listing(4, 10), &["idle_secs"]);
+        let json: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(json, listing(4, 10));
+    }
+}
@@ -0,0 +1,252 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::config::LimitsConfig;
+use super::rate_limit::TokenBucket;
+
+/// Seconds a client is told to wait after a capacity rejection
+const RETRY_AFTER_SECS: u64 = 30;
+
+/// What a tunnel connection that failed its token check costs, in attempts. Guessing
+/// tokens runs out of attempts five times as fast as reconnecting does.
+const FAILED_AUTH_COST: u32 = 5;
+
+/// Cheap checks run on control connections before the WebSocket upgrade, so refused
+/// clients never cost a handshake. Token and subdomain checks still run after
+/// registration.
+#[derive(Debug)]
+pub struct Admission {
+    max_tunnels: usize,
+    max_connections_per_ip: u32,
+    banned: Vec<IpNet>,
+    /// Open control connections per IP, registered or not
+    connections: DashMap<IpAddr, u32>,
+    /// Connection attempts per IP, refilled at registrations_per_minute_per_ip
+    attempts: Option<TokenBucket>,
+}
+
+/// Why a control connection was refused before upgrading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Banned,
+    TooManyFromIp,
+    /// Out of registration attempts; one more is allowed after this many seconds
+    TooManyAttempts(u64),
+    ServerFull,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Rejection::Banned => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+            Rejection::TooManyFromIp => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                "Too many tunnel connections from this address",
+            )
+                .into_response(),
+            Rejection::TooManyAttempts(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "Too many tunnel registration attempts from this address",
+            )
+                .into_response(),
+            Rejection::ServerFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                "Tunnel limit reached",
+            )
+                .into_response(),
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(limits: &LimitsConfig) -> Self {
+        Self {
+            max_tunnels: limits.max_tunnels,
+            max_connections_per_ip: limits.max_connections_per_ip,
+            banned: limits.banned_ips.clone(),
+            connections: DashMap::new(),
+            attempts: (limits.registrations_per_minute_per_ip > 0)
+                .then(|| TokenBucket::new(limits.registrations_per_minute_per_ip, Duration::from_secs(60))),
+        }
+    }
+
+    /// Whether another tunnel fits under `max_tunnels`
+    pub fn has_capacity(&self, active_tunnels: usize) -> bool {
+        self.max_tunnels == 0 || active_tunnels < self.max_tunnels
+    }
+
+    /// Admit a control connection from `ip`, counting it until the guard is dropped
+    pub fn admit(self: &Arc<Self>, ip: IpAddr, active_tunnels: usize) -> Result<ConnectionGuard, Rejection> {
+        let ip = ip.to_canonical();
+        self.admit_attempt(ip)?;
+        if !self.has_capacity(active_tunnels) {
+            return Err(Rejection::ServerFull);
+        }
+
+        let mut count = self.connections.entry(ip).or_insert(0);
+        if self.max_connections_per_ip > 0 && *count >= self.max_connections_per_ip {
+            return Err(Rejection::TooManyFromIp);
+        }
+        *count += 1;
+
+        Ok(ConnectionGuard {
+            admission: self.clone(),
+            ip,
+        })
+    }
+
+    /// Refuse banned addresses and charge `ip` an attempt. A registration check
+    /// (`/_check/registration`) goes through this alone: it holds no connection and
+    /// registers nothing, but could otherwise be used to guess tokens.
+    pub fn admit_attempt(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let ip = ip.to_canonical();
+        if self.banned.iter().any(|net| net.contains(&ip)) {
+            return Err(Rejection::Banned);
+        }
+        if let Some(attempts) = &self.attempts {
+            if !attempts.take(&ip, 1) {
+                return Err(Rejection::TooManyAttempts(attempts.secs_per_token()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Charge `ip` for a tunnel connection that failed its token check, on top of the
+    /// attempt it was admitted with
+    pub fn record_failed_auth(&self, ip: IpAddr) {
+        if let Some(attempts) = &self.attempts {
+            attempts.charge(&ip.to_canonical(), FAILED_AUTH_COST - 1);
+        }
+    }
+
+    /// Open control connections from `ip`
+    #[cfg(test)]
+    pub fn connections(&self, ip: IpAddr) -> u32 {
+        self.connections.get(&ip.to_canonical()).map(|c| *c).unwrap_or(0)
+    }
+}
+
+/// Holds one of an IP's connection slots for the life of a control connection
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut entry) = self.admission.connections.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_tunnels: usize, max_connections_per_ip: u32, banned: &[&str]) -> Arc<Admission> {
+        Arc::new(Admission::new(&LimitsConfig {
+            max_tunnels,
+            max_connections_per_ip,
+            registrations_per_minute_per_ip: 0,
+            banned_ips: banned.iter().map(|b| b.parse().unwrap()).collect(),
+            ..LimitsConfig::default()
+        }))
+    }
+
+    #[test]
+    fn test_per_ip_limit_released_on_drop() {
+        let admission = admission(0, 2, &[]);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let first = admission.admit(ip, 0).unwrap();
+        let _second = admission.admit(ip, 0).unwrap();
+        assert_eq!(admission.admit(ip, 0).unwrap_err(), Rejection::TooManyFromIp);
+
+        // Other addresses have their own allowance
+        assert!(admission.admit("192.0.2.2".parse().unwrap(), 0).is_ok());
+
+        drop(first);
+        assert_eq!(admission.connections(ip), 1);
+        assert!(admission.admit(ip, 0).is_ok());
+    }
+
+    #[test]
+    fn test_global_cap_and_bans() {
+        let admission = admission(10, 0, &["203.0.113.0/24", "2001:db8::/32"]);
+
+        assert_eq!(admission.admit("192.0.2.1".parse().unwrap(), 10).unwrap_err(), Rejection::ServerFull);
+        assert!(admission.admit("192.0.2.1".parse().unwrap(), 9).is_ok());
+
+        assert_eq!(admission.admit("203.0.113.9".parse().unwrap(), 0).unwrap_err(), Rejection::Banned);
+        assert_eq!(admission.admit("::ffff:203.0.113.9".parse().unwrap(), 0).unwrap_err(), Rejection::Banned);
+        assert_eq!(admission.admit("2001:db8::1".parse().unwrap(), 0).unwrap_err(), Rejection::Banned);
+    }
+
+    #[test]
+    fn test_registration_attempts() {
+        let admission = Arc::new(Admission::new(&LimitsConfig::default()));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..10 {
+            admission.admit(ip, 0).unwrap();
+        }
+        assert_eq!(admission.admit(ip, 0).unwrap_err(), Rejection::TooManyAttempts(6));
+        assert_eq!(admission.admit("::ffff:192.0.2.1".parse().unwrap(), 0).unwrap_err(), Rejection::TooManyAttempts(6));
+        assert!(admission.admit("192.0.2.2".parse().unwrap(), 0).is_ok());
+
+        // A failed token check uses up five attempts
+        let other: IpAddr = "192.0.2.3".parse().unwrap();
+        for _ in 0..2 {
+            admission.admit(other, 0).unwrap();
+            admission.record_failed_auth(other);
+        }
+        assert!(matches!(admission.admit(other, 0), Err(Rejection::TooManyAttempts(_))));
+    }
+
+    #[test]
+    fn test_attempts_without_connections() {
+        let admission = Arc::new(Admission::new(&LimitsConfig {
+            max_connections_per_ip: 1,
+            banned_ips: vec!["203.0.113.0/24".parse().unwrap()],
+            ..LimitsConfig::default()
+        }));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // Holds no connection, so an address at its connection limit may still check
+        let _guard = admission.admit(ip, 0).unwrap();
+        for _ in 0..9 {
+            admission.admit_attempt(ip).unwrap();
+        }
+        assert_eq!(admission.connections(ip), 1);
+        // But shares the address's attempts with connections
+        assert_eq!(admission.admit_attempt(ip).unwrap_err(), Rejection::TooManyAttempts(6));
+        assert_eq!(admission.admit_attempt("203.0.113.9".parse().unwrap()).unwrap_err(), Rejection::Banned);
+    }
+
+    #[test]
+    fn test_zero_is_unlimited() {
+        let admission = Arc::new(Admission::new(&LimitsConfig {
+            registrations_per_minute_per_ip: 0,
+            ..LimitsConfig::default()
+        }));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let guards: Vec<_> = (0..100).map(|_| admission.admit(ip, 100_000).unwrap()).collect();
+        assert_eq!(admission.connections(ip), 100);
+        drop(guards);
+        assert_eq!(admission.connections(ip), 0);
+    }
+}
@@ -0,0 +1,104 @@
+//! Visitor basic auth: a client exposing with `--basic-auth user:password` has the
+//! server challenge every request to its tunnel, so the local service needs no login
+//! of its own. Only a SHA-256 digest of the credentials is kept.
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+
+use super::acme::constant_time_eq;
+
+#[derive(Clone)]
+pub struct BasicAuth {
+    /// SHA-256 of `user:password`
+    digest: digest::Digest,
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth").finish_non_exhaustive()
+    }
+}
+
+impl BasicAuth {
+    /// Parse `user:password`. The password may contain colons, the user may not.
+    pub fn new(credentials: &str) -> Result<Self, &'static str> {
+        match credentials.split_once(':') {
+            Some((user, _)) if !user.is_empty() => Ok(Self {
+                digest: digest::digest(&digest::SHA256, credentials.as_bytes()),
+            }),
+            Some(_) => Err("user must not be empty"),
+            None => Err("expected user:password"),
+        }
+    }
+
+    /// Whether the request's `Authorization` header carries the right credentials
+    pub fn admits(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let Some((scheme, encoded)) = value.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Ok(decoded) = STANDARD.decode(encoded.trim()) else {
+            return false;
+        };
+        let presented = digest::digest(&digest::SHA256, &decoded);
+        constant_time_eq(presented.as_ref(), self.digest.as_ref())
+    }
+
+    /// 401 asking the browser for credentials
+    pub fn challenge() -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"loophole\"")],
+            "Authentication required",
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        assert!(BasicAuth::new("alice:s3cret").is_ok());
+        assert!(BasicAuth::new("alice:with:colons").is_ok());
+        assert!(BasicAuth::new("alice:").is_ok());
+        assert_eq!(BasicAuth::new("alice").unwrap_err(), "expected user:password");
+        assert_eq!(BasicAuth::new(":s3cret").unwrap_err(), "user must not be empty");
+    }
+
+    #[test]
+    fn test_admits() {
+        let auth = BasicAuth::new("alice:s3cret").unwrap();
+        let good = STANDARD.encode("alice:s3cret");
+
+        assert!(auth.admits(&headers(&format!("Basic {good}"))));
+        assert!(auth.admits(&headers(&format!("basic {good}"))));
+        assert!(!auth.admits(&HeaderMap::new()));
+        assert!(!auth.admits(&headers(&format!("Bearer {good}"))));
+        assert!(!auth.admits(&headers(&format!("Basic {}", STANDARD.encode("alice:wrong")))));
+        assert!(!auth.admits(&headers("Basic not-base64!")));
+    }
+
+    #[test]
+    fn test_debug_hides_digest() {
+        let auth = BasicAuth::new("alice:s3cret").unwrap();
+        assert_eq!(format!("{auth:?}"), "BasicAuth { .. }");
+    }
+}
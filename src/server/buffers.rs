@@ -0,0 +1,119 @@
+//! Buffers for proxied requests, sized so the hot path doesn't regrow them: request
+//! heads from a running estimate of their size, and response reads from the room
+//! earlier reads left. They aren't pooled; body chunks are split off and frozen, so a
+//! buffer's allocation lives on in them until the visitor has been sent them, and a
+//! pooled buffer would have had to reallocate anyway.
+
+use bytes::BytesMut;
+use futures::io::{AsyncRead, AsyncReadExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of the buffers responses are read from the tunnel into, and most one read takes
+pub const READ_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Least room worth reading into; with less, the buffer grows by [`READ_BUFFER_BYTES`]
+const MIN_READ_BYTES: usize = 1024;
+
+/// Starting guess at a request head's size, before any have been written
+const INITIAL_HEAD_ESTIMATE: usize = 1024;
+
+/// A running estimate of request head sizes, shared by every request the server proxies
+#[derive(Debug)]
+pub struct ProxyBuffers {
+    /// Moving average of request head sizes, so a head is written without regrowing
+    head_estimate: AtomicUsize,
+}
+
+impl Default for ProxyBuffers {
+    fn default() -> Self {
+        Self {
+            head_estimate: AtomicUsize::new(INITIAL_HEAD_ESTIMATE),
+        }
+    }
+}
+
+impl ProxyBuffers {
+    /// An empty buffer for reading a response from the tunnel
+    pub fn read_buffer(&self) -> BytesMut {
+        BytesMut::with_capacity(READ_BUFFER_BYTES)
+    }
+
+    /// An empty buffer sized for a request head, with some room to spare over the
+    /// heads seen so far
+    pub fn head_buffer(&self) -> BytesMut {
+        let estimate = self.head_estimate.load(Ordering::Relaxed);
+        BytesMut::with_capacity(estimate + estimate / 4)
+    }
+
+    /// Fold a request head of `len` bytes into the estimate
+    pub fn record_head(&self, len: usize) {
+        let _ = self.head_estimate.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |estimate| {
+            Some(estimate - estimate / 8 + len / 8)
+        });
+    }
+}
+
+/// Read once from `reader` onto the end of `buf`, taking up to [`READ_BUFFER_BYTES`].
+/// The read goes into the room left after what's been split off `buf`, so it only
+/// allocates once that's used up. The tunnel's streams are futures-io readers, which
+/// read into initialized memory, so that room is zeroed first.
+pub async fn read_into<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut BytesMut) -> std::io::Result<usize> {
+    if buf.capacity() - buf.len() < MIN_READ_BYTES {
+        buf.reserve(READ_BUFFER_BYTES);
+    }
+    let len = buf.len();
+    let room = (buf.capacity() - len).min(READ_BUFFER_BYTES);
+    buf.resize(len + room, 0);
+    let result = reader.read(&mut buf[len..]).await;
+    buf.truncate(len + *result.as_ref().unwrap_or(&0));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_estimate() {
+        let buffers = ProxyBuffers::default();
+        for _ in 0..64 {
+            buffers.record_head(4000);
+        }
+        let estimate = buffers.head_estimate.load(Ordering::Relaxed);
+        assert!((3900..=4000).contains(&estimate), "{estimate}");
+        assert!(buffers.head_buffer().capacity() >= 4000);
+    }
+
+    #[tokio::test]
+    async fn test_read_into() {
+        let mut reader = &b"HTTP/1.1 200 OK\r\n\r\n"[..];
+        let mut buf = BytesMut::from(&b"partial"[..]);
+        assert_eq!(read_into(&mut reader, &mut buf).await.unwrap(), 19);
+        assert_eq!(&buf[..], b"partialHTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(read_into(&mut reader, &mut buf).await.unwrap(), 0);
+        assert_eq!(buf.len(), 26);
+    }
+
+    #[tokio::test]
+    async fn test_read_into_room_left_by_split() {
+        let buffers = ProxyBuffers::default();
+        let mut buf = buffers.read_buffer();
+        let mut reader = &[b'x'; 100][..];
+        read_into(&mut reader, &mut buf).await.unwrap();
+        let chunk = buf.split_to(60).freeze();
+
+        // The next read goes after what's left, in the same allocation
+        let end = buf.as_ptr() as usize + buf.len();
+        let mut reader = &b"more"[..];
+        read_into(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(buf.as_ptr() as usize + 40, end);
+        assert_eq!(&buf[40..], b"more");
+        assert_eq!(chunk.len(), 60);
+
+        // With too little room left, it grows rather than taking a short read
+        let room = buf.capacity() - buf.len();
+        buf.extend_from_slice(&vec![b'y'; room - 10]);
+        let mut reader = &[b'z'; READ_BUFFER_BYTES][..];
+        assert_eq!(read_into(&mut reader, &mut buf).await.unwrap(), READ_BUFFER_BYTES);
+    }
+}
@@ -0,0 +1,217 @@
+//! Pruning certificates of subdomains no tunnel uses any more
+//! (`https.prune_unused_after_days`). Every random name a client is given leaves a
+//! certificate behind, so without this the store only grows.
+//!
+//! A subdomain was last used when it last had a tunnel connected, or was an alias of
+//! one, as kept with usage in the state directory and brought up to date every minute,
+//! or when its owner last registered it if that's later. The base domain and its
+//! wildcard, names no tunnel could have (deeper ones, or under another domain),
+//! reserved names, names the config provisions for a token or an alias, and names in
+//! use are never pruned.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use super::names::FullDomain;
+use super::ownership::now_secs;
+use super::router::ServerState;
+use super::tls::CertManager;
+use crate::units;
+
+/// Before the first pass, so tunnels connected before a restart are back by then
+const FIRST_PASS_DELAY: Duration = Duration::from_secs(3600);
+const PASS_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+const DAY: u64 = 24 * 3600;
+
+/// A certificate that would be, or was, pruned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unused {
+    pub domain: FullDomain,
+    #[serde(skip)]
+    pub subdomain: String,
+    /// When its subdomain was last used (unix seconds)
+    pub last_used_at: u64,
+}
+
+/// The certificates among `domains` for subdomains of `base_domain` last used before
+/// `cutoff`, other than `protected` ones
+pub fn find_unused(
+    domains: &[FullDomain],
+    base_domain: &str,
+    cutoff: u64,
+    last_used: impl Fn(&str) -> u64,
+    protected: impl Fn(&str) -> bool,
+) -> Vec<Unused> {
+    domains
+        .iter()
+        .filter_map(|domain| {
+            let subdomain = domain.strip_suffix(base_domain)?.strip_suffix('.')?;
+            if subdomain.is_empty() || subdomain.contains(['.', '*']) || protected(subdomain) {
+                return None;
+            }
+            let last_used_at = last_used(subdomain);
+            (last_used_at < cutoff).then(|| Unused {
+                domain: domain.clone(),
+                subdomain: subdomain.to_string(),
+                last_used_at,
+            })
+        })
+        .collect()
+}
+
+/// Names that keep their certificates however long ago they were used
+fn is_protected(state: &ServerState, cert_manager: &CertManager, subdomain: &str) -> bool {
+    let registry = &state.registry;
+    registry.is_reserved(subdomain)
+        || registry.reservations().owner(subdomain).is_some()
+        || registry.resolve(subdomain).is_some()
+        || registry.is_static_alias(subdomain)
+        || state.tokens.list().iter().any(|(_, token)| token.names_subdomain(subdomain))
+        || cert_manager.is_pending(&format!("{}.{}", subdomain, state.config.server.domain))
+}
+
+/// Delete the certificates unused for `after_days`, returning them. With `dry_run`
+/// they're only found.
+pub async fn prune(
+    state: &ServerState,
+    cert_manager: &CertManager,
+    after_days: u64,
+    dry_run: bool,
+) -> Result<Vec<Unused>> {
+    let now = now_secs();
+    let base_domain = &state.config.server.domain;
+    // Tunnels connected now count as using their names
+    state.usage.roll_up(&state.registry.tunnels(), now);
+
+    let last_used = |subdomain: &str| {
+        let registered = cert_manager
+            .owner(&format!("{}.{}", subdomain, base_domain))
+            .map_or(0, |owner| owner.last_seen_at);
+        state.usage.last_used(subdomain).max(registered)
+    };
+    let domains = cert_manager.stored_domains().await?;
+    let cutoff = now.saturating_sub(after_days.saturating_mul(DAY));
+    let unused = find_unused(&domains, base_domain, cutoff, last_used, |subdomain| {
+        is_protected(state, cert_manager, subdomain)
+    });
+    if dry_run {
+        return Ok(unused);
+    }
+
+    let mut pruned = Vec::new();
+    for cert in unused {
+        // A tunnel may have taken the name since
+        if is_protected(state, cert_manager, &cert.subdomain) {
+            continue;
+        }
+        match cert_manager.remove(&cert.domain).await {
+            Ok(()) => {
+                info!(
+                    "Pruned the certificate for {}, unused for {}",
+                    cert.domain,
+                    units::format_duration(Duration::from_secs(now.saturating_sub(cert.last_used_at)))
+                );
+                state.usage.forget_subdomain(&cert.subdomain);
+                pruned.push(cert);
+            }
+            Err(e) => warn!("Failed to prune the certificate for {}: {:#}", cert.domain, e),
+        }
+    }
+    Ok(pruned)
+}
+
+/// Prune unused certificates once a day until the server shuts down
+pub async fn prune_task(
+    state: Arc<ServerState>,
+    cert_manager: Arc<CertManager>,
+    after_days: u64,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut delay = FIRST_PASS_DELAY;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {
+                match prune(&state, &cert_manager, after_days, false).await {
+                    Ok(pruned) if pruned.is_empty() => debug!("No certificates unused for {} days", after_days),
+                    Ok(pruned) => info!("Pruned {} certificate(s) unused for {} days", pruned.len(), after_days),
+                    Err(e) => warn!("Failed to prune unused certificates: {:#}", e),
+                }
+                delay = PASS_INTERVAL;
+            }
+            _ = shutdown_rx.recv() => {
+                debug!("Certificate pruning task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noon on some day
+    const NOW: u64 = 1_792_324_800;
+
+    fn unused(domains: &[&str], protected: &[&str]) -> Vec<String> {
+        let domains: Vec<FullDomain> = domains.iter().map(|d| FullDomain::new(d)).collect();
+        let last_used = |subdomain: &str| match subdomain {
+            "recent" => NOW - 2 * DAY,
+            "edge" => NOW - 30 * DAY,
+            _ => NOW - 45 * DAY,
+        };
+        find_unused(&domains, "tunnel.example.com", NOW - 30 * DAY, last_used, |subdomain| {
+            protected.contains(&subdomain)
+        })
+        .into_iter()
+        .map(|cert| cert.domain.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_only_stale_subdomains_are_pruned() {
+        let domains = [
+            "quick-fox-123.tunnel.example.com",
+            "recent.tunnel.example.com",
+            "edge.tunnel.example.com",
+        ];
+        assert_eq!(unused(&domains, &[]), ["quick-fox-123.tunnel.example.com"]);
+    }
+
+    #[test]
+    fn test_protected_names_are_kept() {
+        let domains = [
+            // The base domain and its wildcard
+            "tunnel.example.com",
+            "*.tunnel.example.com",
+            // Not a tunnel's: deeper, another domain, or only ending the same way
+            "a.b.tunnel.example.com",
+            "other.example.com",
+            "eviltunnel.example.com",
+            // Reserved or in use
+            "staging.tunnel.example.com",
+            "old.tunnel.example.com",
+        ];
+        assert!(unused(&domains, &["staging"]).contains(&"old.tunnel.example.com".to_string()));
+        assert!(unused(&domains, &["staging", "old"]).is_empty());
+    }
+
+    #[test]
+    fn test_last_used_is_reported() {
+        let domains = [FullDomain::new("quick-fox-123.tunnel.example.com")];
+        let unused = find_unused(&domains, "tunnel.example.com", NOW, |_| NOW - DAY, |_| false);
+        assert_eq!(
+            unused,
+            [Unused {
+                domain: FullDomain::new("quick-fox-123.tunnel.example.com"),
+                subdomain: "quick-fox-123".to_string(),
+                last_used_at: NOW - DAY,
+            }]
+        );
+    }
+}
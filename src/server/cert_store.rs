@@ -0,0 +1,295 @@
+//! Where certificates, their keys and ownership records, and the ACME account are
+//! kept. The default is a local directory (`certs_dir`); servers on ephemeral disks
+//! can use an S3-compatible bucket instead (`storage = "s3"`, with the `s3` feature).
+//!
+//! Every backend lays items out the same way: `account.json`, and `<domain>/cert.pem`,
+//! `<domain>/key.pem` and `<domain>/meta.json`. Writing an item replaces it whole, so a
+//! reader sees either the old or the new contents. Nothing is atomic across items: a
+//! crash between writing a certificate and its key can leave them mismatched, which
+//! the server reports when loading and fixes by requesting a new certificate.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+
+use super::config::HttpsConfig;
+
+/// Where the server keeps certificates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// `certs_dir` on the local disk
+    #[default]
+    Fs,
+    /// An S3-compatible bucket, configured by environment variables
+    S3,
+}
+
+impl Storage {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "fs" => Ok(Storage::Fs),
+            "s3" => Ok(Storage::S3),
+            _ => Err(format!("expected fs or s3, got '{}'", value)),
+        }
+    }
+}
+
+/// Something kept in a certificate store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Item<'a> {
+    /// ACME account credentials (JSON)
+    Account,
+    /// A domain's certificate chain (PEM)
+    Cert(&'a str),
+    /// A domain's private key (PEM)
+    Key(&'a str),
+    /// A domain's ownership record (JSON)
+    Meta(&'a str),
+}
+
+impl Item<'_> {
+    /// Where the item lives, relative to the store's root
+    pub fn path(&self) -> Result<String> {
+        let (domain, file) = match *self {
+            Item::Account => return Ok("account.json".to_string()),
+            Item::Cert(domain) => (domain, "cert.pem"),
+            Item::Key(domain) => (domain, "key.pem"),
+            Item::Meta(domain) => (domain, "meta.json"),
+        };
+        if !is_valid_domain(domain) {
+            anyhow::bail!("Invalid domain for certificate storage: '{}'", domain);
+        }
+        Ok(format!("{}/{}", domain, file))
+    }
+
+    /// Private keys and account credentials are readable by the server's user only
+    pub fn is_secret(&self) -> bool {
+        matches!(self, Item::Account | Item::Key(_))
+    }
+}
+
+/// Names are used as directory names and object keys, so they must stay inside the store
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && !domain.starts_with('.')
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '*' | '_'))
+}
+
+#[async_trait]
+pub trait CertStore: Send + Sync + std::fmt::Debug {
+    /// The item's contents, or None if it isn't stored
+    async fn get(&self, item: Item<'_>) -> Result<Option<Vec<u8>>>;
+
+    /// Store the item, replacing any previous contents
+    async fn put(&self, item: Item<'_>, contents: &[u8]) -> Result<()>;
+
+    /// Remove the item; removing one that isn't stored is not an error. A domain
+    /// whose last item is removed is no longer listed.
+    async fn delete(&self, item: Item<'_>) -> Result<()>;
+
+    /// Every domain with at least one item stored, sorted
+    async fn list(&self) -> Result<Vec<String>>;
+
+    /// Where the items are, for log messages
+    fn describe(&self) -> String;
+}
+
+/// Open the store `config` asks for
+pub async fn open(config: &HttpsConfig) -> Result<Arc<dyn CertStore>> {
+    match config.storage {
+        Storage::Fs => Ok(Arc::new(FsCertStore::new(PathBuf::from(&config.certs_dir)).await?)),
+        #[cfg(feature = "s3")]
+        Storage::S3 => Ok(Arc::new(super::s3_store::S3CertStore::from_env()?)),
+        #[cfg(not(feature = "s3"))]
+        Storage::S3 => anyhow::bail!("https.storage = \"s3\" needs a build with the s3 feature"),
+    }
+}
+
+/// Items as files under a directory. Writes go to a temporary file that is renamed
+/// into place, so a crash mid-write leaves the previous contents.
+#[derive(Debug)]
+pub struct FsCertStore {
+    dir: PathBuf,
+}
+
+impl FsCertStore {
+    pub async fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create certs directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn file(&self, item: Item<'_>) -> Result<PathBuf> {
+        Ok(self.dir.join(item.path()?))
+    }
+}
+
+#[async_trait]
+impl CertStore for FsCertStore {
+    async fn get(&self, item: Item<'_>) -> Result<Option<Vec<u8>>> {
+        let path = self.file(item)?;
+        match fs::read(&path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    async fn put(&self, item: Item<'_>, contents: &[u8]) -> Result<()> {
+        let path = self.file(item)?;
+        let dir = path.parent().expect("items are inside the store");
+        fs::create_dir_all(dir).await?;
+
+        let temp = dir.join(format!(".{}.tmp", uuid::Uuid::new_v4()));
+        let (temp_path, contents, secret) = (temp.clone(), contents.to_vec(), item.is_secret());
+        let mut written = tokio::task::spawn_blocking(move || write_new(&temp_path, &contents, secret)).await?;
+        if written.is_ok() {
+            written = fs::rename(&temp, &path).await;
+        }
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp).await;
+            return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, item: Item<'_>) -> Result<()> {
+        let path = self.file(item)?;
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {}", path.display()));
+            }
+            _ => {}
+        }
+        // Fails, and is meant to, while the domain has other items
+        if let Some(dir) = path.parent().filter(|dir| *dir != self.dir) {
+            let _ = fs::remove_dir(dir).await;
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut domains = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if let Some(domain) = entry.file_name().to_str().filter(|name| is_valid_domain(name)) {
+                domains.push(domain.to_string());
+            }
+        }
+        domains.sort();
+        Ok(domains)
+    }
+
+    fn describe(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+
+/// Write `contents` to a new file and flush it to disk
+fn write_new(path: &Path, contents: &[u8], secret: bool) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Checks every backend must pass, run against each one's tests
+#[cfg(test)]
+pub(crate) mod conformance {
+    use super::*;
+
+    /// `store` must start out empty
+    pub async fn run(store: &dyn CertStore) {
+        assert_eq!(store.get(Item::Account).await.unwrap(), None);
+        assert_eq!(store.get(Item::Cert("app.example.com")).await.unwrap(), None);
+        assert!(store.list().await.unwrap().is_empty());
+
+        store.put(Item::Account, b"{\"id\":1}").await.unwrap();
+        store.put(Item::Cert("app.example.com"), b"cert").await.unwrap();
+        store.put(Item::Key("app.example.com"), b"key").await.unwrap();
+        // Ownership records are kept for names without certificates too
+        store.put(Item::Meta("b.example.com"), b"{}").await.unwrap();
+        store.put(Item::Cert("*.example.com"), b"wildcard").await.unwrap();
+
+        assert_eq!(store.get(Item::Account).await.unwrap().as_deref(), Some(&b"{\"id\":1}"[..]));
+        assert_eq!(store.get(Item::Cert("app.example.com")).await.unwrap().as_deref(), Some(&b"cert"[..]));
+        assert_eq!(store.get(Item::Key("app.example.com")).await.unwrap().as_deref(), Some(&b"key"[..]));
+        assert_eq!(store.get(Item::Meta("app.example.com")).await.unwrap(), None);
+        assert_eq!(store.list().await.unwrap(), ["*.example.com", "app.example.com", "b.example.com"]);
+
+        // Replaced whole
+        store.put(Item::Cert("app.example.com"), b"renewed").await.unwrap();
+        assert_eq!(store.get(Item::Cert("app.example.com")).await.unwrap().as_deref(), Some(&b"renewed"[..]));
+
+        store.delete(Item::Key("app.example.com")).await.unwrap();
+        store.delete(Item::Key("app.example.com")).await.unwrap();
+        assert_eq!(store.get(Item::Key("app.example.com")).await.unwrap(), None);
+        assert_eq!(store.get(Item::Cert("app.example.com")).await.unwrap().as_deref(), Some(&b"renewed"[..]));
+
+        store.delete(Item::Meta("b.example.com")).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), ["*.example.com", "app.example.com"]);
+
+        // Names that would escape the store
+        for domain in ["", "../etc", "a/b", ".hidden"] {
+            assert!(store.put(Item::Cert(domain), b"x").await.is_err(), "{:?}", domain);
+            assert!(store.get(Item::Cert(domain)).await.is_err(), "{:?}", domain);
+        }
+        assert!(!store.describe().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fs_store_conformance() {
+        let dir = std::env::temp_dir().join(format!("loophole-store-{}", uuid::Uuid::new_v4()));
+        let store = FsCertStore::new(dir.clone()).await.unwrap();
+        conformance::run(&store).await;
+
+        // The layout existing certs directories already have
+        assert_eq!(std::fs::read(dir.join("app.example.com/cert.pem")).unwrap(), b"renewed");
+        assert!(dir.join("account.json").is_file());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &str| std::fs::metadata(dir.join(path)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode("account.json"), 0o600);
+            assert_eq!(mode("app.example.com/cert.pem"), 0o644);
+        }
+        // No temporary files left behind
+        let names: Vec<_> = std::fs::read_dir(dir.join("app.example.com")).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["cert.pem"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_storage_parse() {
+        assert_eq!(Storage::parse("S3"), Ok(Storage::S3));
+        assert_eq!(Storage::parse("fs"), Ok(Storage::Fs));
+        assert!(Storage::parse("gcs").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Control connection churn: clients reconnecting, and bursts of registrations that
+//! usually mean a client is crash-looping.
+
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::names::{Subdomain, TokenSecret};
+use super::tunnel::Tunnel;
+
+/// How soon a token must register a name again after its tunnel there went away for
+/// that to count as a reconnect
+pub const RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Registrations are counted per minute
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Clients named in a registration burst warning
+const BURST_TOP_CLIENTS: usize = 3;
+
+#[derive(Debug)]
+pub struct Churn {
+    /// Registrations per minute above which a warning is logged (0 = off)
+    threshold: u64,
+    /// Names whose tunnel went away within the reconnect window: its token, and when
+    departed: DashMap<Subdomain, (TokenSecret, Instant)>,
+    window: Mutex<RateWindow>,
+}
+
+#[derive(Debug)]
+struct RateWindow {
+    started: Instant,
+    registrations: u64,
+    by_client: HashMap<IpAddr, u64>,
+}
+
+impl RateWindow {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            registrations: 0,
+            by_client: HashMap::new(),
+        }
+    }
+}
+
+/// A minute with more registrations than the threshold
+#[derive(Debug, PartialEq)]
+pub struct Burst {
+    pub registrations: u64,
+    pub threshold: u64,
+    /// The clients that registered most that minute, busiest first
+    pub top_clients: Vec<(IpAddr, u64)>,
+}
+
+impl Burst {
+    /// The busiest clients as "ip (count)", for logging
+    pub fn clients(&self) -> String {
+        let clients: Vec<String> = self
+            .top_clients
+            .iter()
+            .map(|(ip, count)| format!("{} ({})", ip, count))
+            .collect();
+        clients.join(", ")
+    }
+}
+
+impl Churn {
+    pub fn new(threshold_per_minute: u64) -> Self {
+        Self {
+            threshold: threshold_per_minute,
+            departed: DashMap::new(),
+            window: Mutex::new(RateWindow::new()),
+        }
+    }
+
+    /// Remember that `tunnel` went away, so its token registering the name again soon
+    /// counts as a reconnect
+    pub fn record_departure(&self, tunnel: &Tunnel) {
+        self.departed.retain(|_, (_, at)| at.elapsed() < RECONNECT_WINDOW);
+        self.departed
+            .insert(tunnel.subdomain.clone(), (tunnel.token.clone(), Instant::now()));
+    }
+
+    /// Whether `token` registering `subdomain` is a reconnect: the tunnel it had there
+    /// went away within the reconnect window
+    pub fn is_reconnect(&self, subdomain: &str, token: &TokenSecret) -> bool {
+        self.departed
+            .remove_if(subdomain, |_, (departed_token, at)| {
+                departed_token == token && at.elapsed() < RECONNECT_WINDOW
+            })
+            .is_some()
+    }
+
+    /// Count a Register message from `client`. Returns the burst the first time this
+    /// minute's registrations pass the threshold, so it's warned about once a minute.
+    pub fn record_registration(&self, client: IpAddr) -> Option<Burst> {
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() >= RATE_WINDOW {
+            *window = RateWindow::new();
+        }
+        window.registrations += 1;
+        *window.by_client.entry(client).or_insert(0) += 1;
+
+        if self.threshold == 0 || window.registrations != self.threshold + 1 {
+            return None;
+        }
+        let mut top_clients: Vec<(IpAddr, u64)> = window.by_client.iter().map(|(ip, count)| (*ip, *count)).collect();
+        top_clients.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top_clients.truncate(BURST_TOP_CLIENTS);
+        Some(Burst {
+            registrations: window.registrations,
+            threshold: self.threshold,
+            top_clients,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn tunnel(subdomain: &str, token: &str) -> Tunnel {
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        let addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        Tunnel::new(Subdomain::new(subdomain).unwrap(), TokenSecret::new(token), addr, request_tx)
+    }
+
+    #[test]
+    fn test_reconnects() {
+        let churn = Churn::new(0);
+        assert!(!churn.is_reconnect("myapp", &TokenSecret::new("tk_alice")));
+
+        churn.record_departure(&tunnel("myapp", "tk_alice"));
+        // Another token taking the name isn't reconnecting
+        assert!(!churn.is_reconnect("myapp", &TokenSecret::new("tk_bob")));
+        assert!(churn.is_reconnect("myapp", &TokenSecret::new("tk_alice")));
+        // Each departure counts once
+        assert!(!churn.is_reconnect("myapp", &TokenSecret::new("tk_alice")));
+    }
+
+    #[test]
+    fn test_bursts_are_reported_once() {
+        let churn = Churn::new(3);
+        let flapping: IpAddr = "10.0.0.1".parse().unwrap();
+        let steady: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(churn.record_registration(steady), None);
+        for _ in 0..2 {
+            assert_eq!(churn.record_registration(flapping), None);
+        }
+
+        let burst = churn.record_registration(flapping).unwrap();
+        assert_eq!(burst.registrations, 4);
+        assert_eq!(burst.top_clients, vec![(flapping, 3), (steady, 1)]);
+        assert_eq!(burst.clients(), "10.0.0.1 (3), 10.0.0.2 (1)");
+        assert_eq!(churn.record_registration(flapping), None);
+    }
+
+    #[test]
+    fn test_no_threshold() {
+        let churn = Churn::new(0);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!((0..100).all(|_| churn.record_registration(client).is_none()));
+    }
+}
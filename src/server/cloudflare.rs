@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// Cloudflare's published edge ranges, bundled so the server works without fetching them
+/// (https://www.cloudflare.com/ips/)
+const BUNDLED_RANGES: &str = "
+173.245.48.0/20
+103.21.244.0/22
+103.22.200.0/22
+103.31.4.0/22
+141.101.64.0/18
+108.162.192.0/18
+190.93.240.0/20
+188.114.96.0/20
+197.234.240.0/22
+198.41.128.0/17
+162.158.0.0/15
+104.16.0.0/13
+104.24.0.0/14
+172.64.0.0/13
+131.0.72.0/22
+2400:cb00::/32
+2606:4700::/32
+2803:f800::/32
+2405:b500::/32
+2405:8100::/32
+2a06:98c0::/29
+2c0f:f248::/32
+";
+
+const RANGE_URLS: [&str; 2] = [
+    "https://www.cloudflare.com/ips-v4",
+    "https://www.cloudflare.com/ips-v6",
+];
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Addresses Cloudflare's proxy connects from. Only requests from these may set
+/// CF-Connecting-IP or X-Forwarded-Proto; anyone else could spoof them.
+#[derive(Debug)]
+pub struct CloudflareRanges {
+    nets: RwLock<Vec<IpNet>>,
+}
+
+impl CloudflareRanges {
+    pub fn bundled() -> Self {
+        Self {
+            nets: RwLock::new(parse_ranges(BUNDLED_RANGES).expect("bundled Cloudflare ranges are valid")),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers can show up as IPv4-mapped IPv6 on dual-stack listeners
+        let ip = ip.to_canonical();
+        self.nets
+            .read()
+            .map(|nets| nets.iter().any(|net| net.contains(&ip)))
+            .unwrap_or(false)
+    }
+
+    fn replace(&self, nets: Vec<IpNet>) {
+        if let Ok(mut current) = self.nets.write() {
+            *current = nets;
+        }
+    }
+
+    /// Fetch the current ranges from Cloudflare
+    async fn refresh(&self) -> Result<usize> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let mut nets = Vec::new();
+        for url in RANGE_URLS {
+            let text = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("Failed to fetch {}", url))?
+                .text()
+                .await?;
+            nets.extend(parse_ranges(&text).with_context(|| format!("Invalid ranges from {}", url))?);
+        }
+        if nets.is_empty() {
+            anyhow::bail!("Cloudflare returned no ranges");
+        }
+        let count = nets.len();
+        self.replace(nets);
+        Ok(count)
+    }
+}
+
+/// Parse one CIDR per line, ignoring blank lines
+fn parse_ranges(text: &str) -> Result<Vec<IpNet>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().with_context(|| format!("Invalid CIDR '{}'", line)))
+        .collect()
+}
+
+/// Background task that refreshes the ranges daily, keeping the last good set on failure
+pub async fn refresh_task(ranges: Arc<CloudflareRanges>, mut shutdown_rx: broadcast::Receiver<()>) {
+    loop {
+        match ranges.refresh().await {
+            Ok(count) => info!("Refreshed Cloudflare IP ranges ({} ranges)", count),
+            Err(e) => warn!("Failed to refresh Cloudflare IP ranges, keeping current list: {:#}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(REFRESH_INTERVAL) => {}
+            _ = shutdown_rx.recv() => {
+                debug!("Cloudflare range refresh task shutting down");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_ranges() {
+        let ranges = CloudflareRanges::bundled();
+        assert!(ranges.contains("173.245.48.1".parse().unwrap()));
+        assert!(ranges.contains("2606:4700::6810:84e5".parse().unwrap()));
+        assert!(ranges.contains("::ffff:104.16.0.1".parse().unwrap()));
+        assert!(!ranges.contains("192.0.2.1".parse().unwrap()));
+        assert!(!ranges.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let nets = parse_ranges("10.0.0.0/8\n\n  2001:db8::/32  \n").unwrap();
+        assert_eq!(nets.len(), 2);
+
+        let err = parse_ranges("10.0.0.0/8\nnot-a-cidr\n").unwrap_err();
+        assert!(err.to_string().contains("not-a-cidr"), "{}", err);
+    }
+
+    #[test]
+    fn test_replace_ranges() {
+        let ranges = CloudflareRanges::bundled();
+        ranges.replace(parse_ranges("192.0.2.0/24").unwrap());
+        assert!(ranges.contains("192.0.2.1".parse().unwrap()));
+        assert!(!ranges.contains("173.245.48.1".parse().unwrap()));
+    }
+}
@@ -1,6 +1,28 @@
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::path::Path;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use super::acme::AcmeChallenge;
+use super::cert_store::Storage;
+use super::config_schema;
+use super::config_summary::Provenance;
+use super::dns_provider::Provider;
+use super::migrate;
+use super::motd::{self, MotdSource};
+use super::public_url::Scheme;
+use super::registry::Registry;
+use super::response_headers::HeaderRules;
+use super::tcp::PortRange;
+use crate::http_head::MAX_REQUEST_LINE_BYTES;
+use crate::proto::transport::{DEFAULT_PING_INTERVAL, MISSED_PINGS};
+use crate::names;
+use crate::schedule::{self, Window};
+use crate::units;
+use jiff::tz::TimeZone;
+use rand::Rng;
 
 const CONFIG_VERSION: u32 = 1;
 
@@ -9,18 +31,139 @@ pub mod env {
     pub const DOMAIN: &str = "LOOPHOLE_DOMAIN";
     pub const HTTP_PORT: &str = "LOOPHOLE_HTTP_PORT";
     pub const HTTPS_PORT: &str = "LOOPHOLE_HTTPS_PORT";
+    pub const CONTROL_PORT: &str = "LOOPHOLE_CONTROL_PORT";
     pub const TOKENS: &str = "LOOPHOLE_TOKENS";
     pub const ADMIN_TOKENS: &str = "LOOPHOLE_ADMIN_TOKENS";
     pub const ACME_EMAIL: &str = "LOOPHOLE_ACME_EMAIL";
     pub const ACME_STAGING: &str = "LOOPHOLE_ACME_STAGING";
     pub const ACME_DIRECTORY: &str = "LOOPHOLE_ACME_DIRECTORY";
+    pub const ACME_CHALLENGE: &str = "LOOPHOLE_ACME_CHALLENGE";
     pub const CERTS_DIR: &str = "LOOPHOLE_CERTS_DIR";
+    pub const STORAGE: &str = "LOOPHOLE_STORAGE";
+    /// DNS-01 settings, for `[https.dns]`
+    pub const DNS_PROVIDER: &str = "LOOPHOLE_DNS_PROVIDER";
+    pub const DNS_API_TOKEN: &str = "LOOPHOLE_DNS_API_TOKEN";
+    pub const DNS_ZONE_ID: &str = "LOOPHOLE_DNS_ZONE_ID";
+    /// S3 settings, for `storage = "s3"`. Credentials and region come from the standard
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and `AWS_REGION`.
+    #[cfg(feature = "s3")]
+    pub const S3_BUCKET: &str = "LOOPHOLE_S3_BUCKET";
+    #[cfg(feature = "s3")]
+    pub const S3_PREFIX: &str = "LOOPHOLE_S3_PREFIX";
+    /// For S3-compatible services such as MinIO; addressed path-style
+    #[cfg(feature = "s3")]
+    pub const S3_ENDPOINT: &str = "LOOPHOLE_S3_ENDPOINT";
     pub const REQUEST_TIMEOUT: &str = "LOOPHOLE_REQUEST_TIMEOUT_SECS";
     pub const MAX_BODY: &str = "LOOPHOLE_MAX_REQUEST_BODY_BYTES";
+    pub const MAX_REQUEST_LINE: &str = "LOOPHOLE_MAX_REQUEST_LINE";
     pub const IDLE_TIMEOUT: &str = "LOOPHOLE_IDLE_TUNNEL_TIMEOUT_SECS";
+    pub const PING_TIMEOUT: &str = "LOOPHOLE_PING_TIMEOUT_SECS";
+    pub const ALLOW_KEEP_ALIVE: &str = "LOOPHOLE_ALLOW_KEEP_ALIVE";
+    pub const STRICT_OWNERSHIP: &str = "LOOPHOLE_STRICT_SUBDOMAIN_OWNERSHIP";
+    pub const OWNERSHIP_EXPIRY: &str = "LOOPHOLE_OWNERSHIP_EXPIRY_SECS";
+    pub const RECONNECT_GRACE: &str = "LOOPHOLE_RECONNECT_GRACE_SECS";
+    pub const STRICT_EPOCH: &str = "LOOPHOLE_STRICT_EPOCH";
+    pub const BEHIND_CLOUDFLARE: &str = "LOOPHOLE_BEHIND_CLOUDFLARE";
+    pub const TRUSTED_PROXIES: &str = "LOOPHOLE_TRUSTED_PROXIES";
+    pub const PROXY_PROTOCOL: &str = "LOOPHOLE_PROXY_PROTOCOL";
+    pub const REJECT_CONFUSABLES: &str = "LOOPHOLE_REJECT_CONFUSABLES";
+    pub const BIND_ADDRESS: &str = "LOOPHOLE_BIND_ADDRESS";
+    pub const HTTP_BIND: &str = "LOOPHOLE_HTTP_BIND";
+    pub const HTTPS_BIND: &str = "LOOPHOLE_HTTPS_BIND";
+    pub const MANUAL_CERTS: &str = "LOOPHOLE_MANUAL_CERTS";
+    pub const PRUNE_UNUSED_AFTER_DAYS: &str = "LOOPHOLE_PRUNE_UNUSED_AFTER_DAYS";
+    pub const MAX_TUNNELS: &str = "LOOPHOLE_MAX_TUNNELS";
+    pub const MAX_TUNNELS_PER_TOKEN: &str = "LOOPHOLE_MAX_TUNNELS_PER_TOKEN";
+    pub const MAX_CONNECTIONS_PER_IP: &str = "LOOPHOLE_MAX_CONNECTIONS_PER_IP";
+    pub const FAIR_QUEUE_THRESHOLD: &str = "LOOPHOLE_FAIR_QUEUE_THRESHOLD";
+    pub const REGISTRATIONS_PER_MINUTE_PER_IP: &str = "LOOPHOLE_REGISTRATIONS_PER_MINUTE_PER_IP";
+    pub const MAX_REQUESTS_PER_SECOND: &str = "LOOPHOLE_MAX_REQUESTS_PER_SECOND";
+    pub const BANNED_IPS: &str = "LOOPHOLE_BANNED_IPS";
+    pub const PUBLIC_PORT: &str = "LOOPHOLE_PUBLIC_PORT";
+    pub const PUBLIC_SCHEME: &str = "LOOPHOLE_PUBLIC_SCHEME";
+    pub const METRICS: &str = "LOOPHOLE_METRICS";
+    pub const METRICS_TOKEN: &str = "LOOPHOLE_METRICS_TOKEN";
+    pub const METRICS_PORT: &str = "LOOPHOLE_METRICS_PORT";
+    pub const SLOW_REQUEST_THRESHOLD: &str = "LOOPHOLE_SLOW_REQUEST_THRESHOLD_MS";
+    pub const REGISTRATION_RATE_WARNING: &str = "LOOPHOLE_REGISTRATION_RATE_WARNING";
+    pub const TCP_PORT_RANGE: &str = "LOOPHOLE_TCP_PORT_RANGE";
+    pub const USAGE_RETENTION_DAYS: &str = "LOOPHOLE_USAGE_RETENTION_DAYS";
+    pub const RESERVED_SUBDOMAINS: &str = "LOOPHOLE_RESERVED_SUBDOMAINS";
+    /// Comma-separated `alias=subdomain` pairs
+    pub const ALIASES: &str = "LOOPHOLE_ALIASES";
+    pub const STATE_DIR: &str = "LOOPHOLE_STATE_DIR";
+    pub const MOTD: &str = "LOOPHOLE_MOTD";
+    pub const DNS_CHECK_INTERVAL: &str = "LOOPHOLE_DNS_CHECK_INTERVAL";
+    pub const EXPECTED_IPS: &str = "LOOPHOLE_EXPECTED_IPS";
+    pub const DNS_RESOLVER: &str = "LOOPHOLE_DNS_RESOLVER";
+    pub const WEBHOOK_URL: &str = "LOOPHOLE_WEBHOOK_URL";
+    pub const THEME_DIR: &str = "LOOPHOLE_THEME_DIR";
+    pub const MAINTENANCE_WINDOWS: &str = "LOOPHOLE_MAINTENANCE_WINDOWS";
+    pub const MAINTENANCE_TIMEZONE: &str = "LOOPHOLE_MAINTENANCE_TIMEZONE";
+}
+
+/// Parse an address or CIDR network; a bare address is a single-host network
+pub fn parse_ip_net(value: &str) -> Result<IpNet, String> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<std::net::IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address or network '{}'", value))
+}
+
+/// Parse an address to listen on
+fn parse_bind_address(value: &str) -> Result<IpAddr, String> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .map_err(|_| format!("invalid address '{}': expected an IPv4 or IPv6 address such as 0.0.0.0 or ::", value))
+}
+
+fn deserialize_bind_address<'de, D>(deserializer: D) -> Result<IpAddr, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    parse_bind_address(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_bind_address<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_bind_address(deserializer).map(Some)
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Parse a comma-separated list of addresses or CIDR networks
+fn parse_ip_list(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(parse_ip_net)
+        .collect()
+}
+
+fn serialize_ip_list<S: serde::Serializer>(list: &[IpNet], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(list.iter().map(ToString::to_string))
+}
+
+fn deserialize_ip_list<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_ip_net(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Parse a boolean environment variable ("true" or "1")
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|s| s.eq_ignore_ascii_case("true") || s == "1")
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default = "default_version")]
     pub version: u32,
@@ -31,58 +174,555 @@ pub struct Config {
     /// HTTPS configuration (renamed from acme for clarity)
     #[serde(default, alias = "acme")]
     pub https: Option<HttpsConfig>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub registry: RegistryConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub monitoring: MonitoringConfig,
+    #[serde(default)]
+    pub pages: PagesConfig,
+    /// Headers put on responses from matching tunnels, in order. Reloaded on SIGHUP.
+    #[serde(default)]
+    pub response_headers: Vec<ResponseHeaderRule>,
+    /// Where the settings came from, for the summary logged at startup
+    #[serde(skip)]
+    pub provenance: Provenance,
+}
+
+/// Headers stamped on every response from tunnels whose subdomain matches, whatever
+/// the service behind them sends
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseHeaderRule {
+    /// A name, or a pattern where `*` stands for any run of characters, such as "staging-*"
+    pub subdomain: String,
+    /// Headers that replace any the service sent with the same name
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Headers sent alongside any the service sent with the same name
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+impl ResponseHeaderRule {
+    /// Whether the rule applies to `subdomain`'s responses
+    pub fn matches(&self, subdomain: &str) -> bool {
+        glob_matches(&self.subdomain, &subdomain.to_ascii_lowercase())
+    }
+}
+
+/// Which subdomains tunnels may register
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistryConfig {
+    /// Names refused to every token, on top of the built-in ones (www, api, admin, ...)
+    #[serde(default)]
+    pub reserved: Vec<String>,
+    /// Subdomains that lead to the tunnel on another, e.g. `www-app = "app"`. No token
+    /// may register them.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// Recurring windows when every tunnel serves a maintenance page instead of proxying
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// e.g. "0 2 * * * for 30m"; clients may add their own with `--pause-schedule`
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    /// Time zone the windows' times are in, server-wide ones and clients' alike
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+impl MaintenanceConfig {
+    pub fn time_zone(&self) -> anyhow::Result<TimeZone> {
+        schedule::time_zone(&self.timezone).map_err(|e| anyhow::anyhow!("maintenance.timezone: {}", e))
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+/// Per-token usage accounting, served by `/_admin/tokens/<id>/usage`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageConfig {
+    /// Days of hourly usage kept
+    #[serde(default = "default_usage_retention")]
+    pub retention_days: u64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            retention_days: default_usage_retention(),
+        }
+    }
+}
+
+/// Background checks that the server is still reachable as configured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitoringConfig {
+    /// How often to check that the base domain and its wildcard resolve to this
+    /// server (0 = off)
+    #[serde(default, alias = "dns_check_interval", deserialize_with = "units::deserialize_secs")]
+    pub dns_check_interval_secs: u64,
+    /// Addresses the domain should resolve to; this server's public address, as
+    /// detected at startup, when empty
+    #[serde(default)]
+    pub expected_ips: Vec<IpAddr>,
+    /// DNS-over-HTTPS resolver (JSON API) the checks ask
+    #[serde(default = "default_dns_resolver")]
+    pub dns_resolver: String,
+    /// Where events such as `dns_mismatch` are POSTed as JSON
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        Self {
+            dns_check_interval_secs: 0,
+            expected_ips: Vec::new(),
+            dns_resolver: default_dns_resolver(),
+            webhook_url: None,
+        }
+    }
+}
+
+fn default_dns_resolver() -> String {
+    "https://cloudflare-dns.com/dns-query".to_string()
+}
+
+/// Shortest DNS check interval, so a typo doesn't hammer the resolver
+const MIN_DNS_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// The pages the server answers visitors with itself, such as "Tunnel not found".
+/// Reloaded on SIGHUP.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PagesConfig {
+    /// Directory of templates (`not_found.html`, `error.html`, ...) replacing the
+    /// built-in page
+    #[serde(default)]
+    pub theme_dir: Option<PathBuf>,
+}
+
+/// Raw TCP tunnels (`expose --tcp`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TcpConfig {
+    /// Ports TCP tunnels may listen on, e.g. "20000-20100"; TCP tunnels are refused
+    /// when unset
+    #[serde(default)]
+    pub port_range: Option<PortRange>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// Warn about proxied requests whose response headers take longer than this
+    /// (0 = off). Reloaded on SIGHUP.
+    #[serde(default)]
+    pub slow_request_threshold_ms: u64,
+    /// Warn when more tunnel registrations than this arrive in a minute, which usually
+    /// means a client is crash-looping (0 = off)
+    #[serde(default)]
+    pub registration_rate_warning: u64,
+}
+
+/// Prometheus `/metrics` endpoint
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Required as `Authorization: Bearer <token>` when set
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Serve metrics on their own port instead of the base domain
+    #[serde(default)]
+    pub port: Option<u16>,
 }
 
 fn default_version() -> u32 {
     1
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TokenConfig {
     /// Whether this token has admin privileges
     #[serde(default)]
     pub admin: bool,
+    /// Whether clients using this token may keep idle tunnels open (`expose --keep-alive`)
+    #[serde(default)]
+    pub keep_alive: bool,
+    /// Most tunnels this token may have connected at once, overriding
+    /// limits.max_tunnels_per_token (0 = no limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tunnels: Option<usize>,
+    /// Requests per second each of this token's tunnels may be sent, overriding
+    /// limits.max_requests_per_second (0 = no limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_second: Option<u32>,
+    /// This token's tunnels' share of stream opens when the fair queue is in use,
+    /// relative to other tokens' (default 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Subdomains this token may register: names, or patterns where `*` stands for
+    /// any run of characters, such as "ci-*". Any subdomain when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_subdomains: Option<Vec<String>>,
+    /// Request headers never passed to this token's tunnels, whatever the visitor
+    /// sends, such as "authorization" or "cookie"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strip_request_headers: Vec<String>,
+    /// Request methods this token's tunnels may receive; others are refused with 405.
+    /// Any method when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_methods: Option<Vec<String>>,
+}
+
+impl TokenConfig {
+    /// Whether this token may register `subdomain`
+    pub fn allows_subdomain(&self, subdomain: &str) -> bool {
+        let subdomain = subdomain.to_ascii_lowercase();
+        self.allowed_subdomains
+            .as_ref()
+            .is_none_or(|patterns| patterns.iter().any(|pattern| glob_matches(pattern, &subdomain)))
+    }
+
+    /// Whether `allowed_subdomains` names `subdomain` itself, rather than through a pattern
+    pub fn names_subdomain(&self, subdomain: &str) -> bool {
+        self.allowed_subdomains.as_ref().is_some_and(|patterns| {
+            patterns.iter().any(|pattern| !pattern.contains('*') && pattern.eq_ignore_ascii_case(subdomain))
+        })
+    }
+
+    /// A random subdomain this token may register: one of its allowed names, or a
+    /// pattern with a random name in place of its `*`
+    pub fn random_subdomain(&self) -> String {
+        let Some(patterns) = self.allowed_subdomains.as_ref().filter(|patterns| !patterns.is_empty()) else {
+            return names::random_subdomain();
+        };
+        let pattern = &patterns[rand::rng().random_range(0..patterns.len())];
+        pattern.replacen('*', &names::random_subdomain(), 1).replace('*', "")
+    }
+
+    /// Why `allowed_subdomains` can't be used, if it can't
+    pub fn check_allowed_subdomains(&self) -> Result<(), String> {
+        let Some(ref patterns) = self.allowed_subdomains else {
+            return Ok(());
+        };
+        if patterns.is_empty() {
+            return Err("allowed_subdomains is empty, so no subdomain would be allowed; leave it out to allow any".to_string());
+        }
+        for pattern in patterns {
+            let valid = !pattern.is_empty()
+                && pattern.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '*');
+            if !valid {
+                return Err(format!(
+                    "allowed_subdomains pattern '{}' may only contain lowercase letters, digits, hyphens and *",
+                    pattern
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Why `strip_request_headers` or `allowed_methods` can't be used, if one can't
+    pub fn check_request_policy(&self) -> Result<(), String> {
+        for name in &self.strip_request_headers {
+            if axum::http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(format!("strip_request_headers: '{}' isn't a header name", name));
+            }
+        }
+        let Some(ref methods) = self.allowed_methods else {
+            return Ok(());
+        };
+        if methods.is_empty() {
+            return Err("allowed_methods is empty, so every request would be refused; leave it out to allow any".to_string());
+        }
+        for method in methods {
+            if axum::http::Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(format!("allowed_methods: '{}' isn't a request method", method));
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut remaining) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(at) => remaining = &remaining[at + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub domain: String,
     #[serde(default = "default_http_port")]
     pub http_port: u16,
     #[serde(default = "default_https_port")]
     pub https_port: u16,
+    /// Serve the control endpoint and admin API on this port instead of the HTTP and
+    /// HTTPS ports, e.g. to keep them behind a firewall
+    pub control_port: Option<u16>,
+    /// Address the server's ports listen on (HTTP, HTTPS, metrics and TCP tunnels).
+    /// `::` takes IPv4 connections too where the OS allows it.
+    #[serde(default = "default_bind_address", deserialize_with = "deserialize_bind_address")]
+    pub bind_address: IpAddr,
+    /// Overrides `bind_address` for the HTTP port
+    #[serde(default, deserialize_with = "deserialize_optional_bind_address")]
+    pub http_bind: Option<IpAddr>,
+    /// Overrides `bind_address` for the HTTPS port
+    #[serde(default, deserialize_with = "deserialize_optional_bind_address")]
+    pub https_bind: Option<IpAddr>,
+    /// Only the token a subdomain's certificate was issued for may register it again,
+    /// until the claim expires or an admin releases it
+    #[serde(default)]
+    pub strict_subdomain_ownership: bool,
+    /// Refuse internationalized subdomains that mix scripts, such as Latin with a
+    /// Cyrillic look-alike letter, which could pass for another name
+    #[serde(default)]
+    pub reject_confusables: bool,
+    /// How long a claim lasts after its owner last registered the subdomain
+    #[serde(
+        default = "default_ownership_expiry",
+        alias = "ownership_expiry",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub ownership_expiry_secs: u64,
+    /// How long a subdomain stays kept for its token after the client drops without
+    /// disconnecting, so it can reconnect and get the name back (0 = not kept)
+    #[serde(
+        default = "default_reconnect_grace",
+        alias = "reconnect_grace",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub reconnect_grace_secs: u64,
+    /// Fail requests with 502 when another client registers their subdomain before
+    /// the response is complete, rather than finishing them from the old client
+    #[serde(default)]
+    pub strict_epoch: bool,
+    /// Running behind Cloudflare's proxy: trust CF-Connecting-IP and X-Forwarded-Proto
+    /// from Cloudflare's address ranges
+    #[serde(default)]
+    pub behind_cloudflare: bool,
+    /// Load balancers or reverse proxies in front of the server, trusted to say who the
+    /// visitor is in X-Forwarded-For and how they connected in X-Forwarded-Proto
+    #[serde(default, deserialize_with = "deserialize_ip_list", serialize_with = "serialize_ip_list")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Every connection to the HTTP and HTTPS ports starts with a PROXY protocol header
+    /// from the load balancer in front, giving the visitor's address
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Port visitors use, when a proxy in front listens on a different one
+    pub public_port: Option<u16>,
+    /// Scheme visitors use, when a proxy in front terminates TLS
+    pub public_scheme: Option<Scheme>,
+    /// Where the server saves what changes while it runs (tokens, usage, subdomain
+    /// reservations). Beside the config file when unset.
+    pub state_dir: Option<PathBuf>,
+    /// Message shown to clients when their tunnel connects, or `{ file = "..." }`
+    pub motd: Option<MotdSource>,
+    /// Clients older than this, or too old to report a version, are shown
+    /// `motd_outdated` instead of `motd`
+    pub motd_min_client_version: Option<String>,
+    pub motd_outdated: Option<MotdSource>,
 }
 
-const CONTROL_PATH: &str = "/_tunnel/connect";
+/// Where clients open their control connection. Older servers let configs choose it.
+pub const CONTROL_PATH: &str = "/_tunnel/connect";
 
 impl ServerConfig {
     pub fn control_path(&self) -> &'static str {
         CONTROL_PATH
     }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.http_bind.unwrap_or(self.bind_address), self.http_port)
+    }
+
+    pub fn https_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.https_bind.unwrap_or(self.bind_address), self.https_port)
+    }
+
+    pub fn control_addr(&self) -> Option<SocketAddr> {
+        self.control_port.map(|port| SocketAddr::new(self.bind_address, port))
+    }
+
+    /// `state_dir`, or the directory of the config file at `config_path`. None with
+    /// neither, as for an environment-only config, when changes last until restart.
+    pub fn state_dir(&self, config_path: &str) -> Option<PathBuf> {
+        if let Some(ref dir) = self.state_dir {
+            return Some(dir.clone());
+        }
+        let config_path = Path::new(config_path);
+        config_path
+            .exists()
+            .then(|| config_path.parent().unwrap_or(Path::new("")).to_path_buf())
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpsConfig {
+    /// ACME account email (not needed with `manual_certs`)
+    #[serde(default)]
     pub email: String,
     #[serde(default = "default_acme_directory")]
     pub directory: String,
     #[serde(default = "default_certs_dir")]
     pub certs_dir: String,
+    /// Where certificates are kept: `certs_dir`, or an S3 bucket
+    #[serde(default)]
+    pub storage: Storage,
     #[serde(default)]
     pub staging: bool,
     /// Path to additional root CA PEM file (for testing with Pebble)
     pub ca_file: Option<String>,
+    /// Serve only the certificates already in `certs_dir` (e.g. a Cloudflare origin
+    /// certificate) and never request any via ACME
+    #[serde(default)]
+    pub manual_certs: bool,
+    /// Delete certificates of subdomains no tunnel has used for this many days
+    /// (0 = keep them all)
+    #[serde(default)]
+    pub prune_unused_after_days: u64,
+    /// How ACME challenges are answered: `http-01` on port 80, or `tls-alpn-01` on
+    /// the HTTPS port, for when port 80 can't be opened
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Answer ACME challenges over DNS instead of HTTP, which also gets a wildcard
+    /// certificate covering every subdomain
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// `[https.dns]`: the DNS provider hosting the domain's zone, which DNS-01
+/// challenge records are published through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsConfig {
+    pub provider: Provider,
+    /// API token allowed to edit the zone's DNS records
+    #[serde(default)]
+    pub api_token: String,
+    /// The zone's id at the provider; looked up from the domain when not set
+    #[serde(default)]
+    pub zone_id: Option<String>,
+}
+
+/// Request limits. Each value can be given under its original numeric key
+/// (`request_timeout_secs = 30`) or a human-friendly one (`request_timeout = "30s"`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct LimitsConfig {
-    #[serde(default = "default_request_timeout")]
+    #[serde(
+        default = "default_request_timeout",
+        alias = "request_timeout",
+        deserialize_with = "units::deserialize_secs"
+    )]
     pub request_timeout_secs: u64,
-    #[serde(default = "default_max_body")]
+    #[serde(
+        default = "default_max_body",
+        alias = "max_request_body",
+        deserialize_with = "units::deserialize_bytes"
+    )]
     pub max_request_body_bytes: usize,
-    #[serde(default = "default_idle_timeout")]
+    /// Longest request line (method, URL and version) proxied to a tunnel; longer ones
+    /// get 414. At most the 64KB tunnel clients accept.
+    #[serde(default = "default_max_request_line", deserialize_with = "units::deserialize_bytes")]
+    pub max_request_line: usize,
+    #[serde(
+        default = "default_idle_timeout",
+        alias = "idle_tunnel_timeout",
+        deserialize_with = "units::deserialize_secs"
+    )]
     pub idle_tunnel_timeout_secs: u64,
+    /// Deregister a tunnel whose client hasn't been heard from (pings included) for
+    /// this long (0 = never). The default allows three missed 30s client pings. Only
+    /// clients that have pinged are held to it: older ones never do.
+    #[serde(
+        default = "default_ping_timeout",
+        alias = "ping_timeout",
+        deserialize_with = "units::deserialize_secs"
+    )]
+    pub ping_timeout_secs: u64,
+    /// Most tunnels connected at once (0 = no limit)
+    #[serde(default)]
+    pub max_tunnels: usize,
+    /// Most tunnels one token may have connected at once, unless the token sets its
+    /// own max_tunnels (0 = no limit)
+    #[serde(default)]
+    pub max_tunnels_per_token: usize,
+    /// Most open control connections from one IP, registered or not (0 = no limit)
+    #[serde(default)]
+    pub max_connections_per_ip: u32,
+    /// Tunnel connection attempts one IP may make per minute, with failed token checks
+    /// counting several times over (0 = no limit)
+    #[serde(default = "default_registrations_per_minute_per_ip")]
+    pub registrations_per_minute_per_ip: u32,
+    /// Requests per second visitors may send one tunnel, with a second's worth allowed
+    /// in a burst, unless its token sets its own max_requests_per_second (0 = no limit)
+    #[serde(default)]
+    pub max_requests_per_second: u32,
+    /// Addresses and networks refused before the WebSocket upgrade
+    #[serde(default, deserialize_with = "deserialize_ip_list", serialize_with = "serialize_ip_list")]
+    pub banned_ips: Vec<IpNet>,
+    /// Requests in flight across all tunnels before new ones wait their tunnel's turn
+    /// (0 = never wait)
+    #[serde(default)]
+    pub fair_queue_threshold: usize,
+}
+
+impl LimitsConfig {
+    /// Reject values that parse but make no sense, naming the offending key
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.request_timeout_secs == 0 {
+            anyhow::bail!("limits.request_timeout must be greater than zero");
+        }
+        if self.max_request_body_bytes == 0 {
+            anyhow::bail!("limits.max_request_body must be greater than zero");
+        }
+        if self.max_request_line == 0 || self.max_request_line > MAX_REQUEST_LINE_BYTES {
+            anyhow::bail!(
+                "limits.max_request_line must be between 1B and {}, the most tunnel clients accept",
+                units::format_bytes(MAX_REQUEST_LINE_BYTES as u64)
+            );
+        }
+        if self.idle_tunnel_timeout_secs == 0 {
+            anyhow::bail!("limits.idle_tunnel_timeout must be greater than zero");
+        }
+        if self.ping_timeout_secs != 0 && self.ping_timeout_secs <= DEFAULT_PING_INTERVAL.as_secs() {
+            anyhow::bail!(
+                "limits.ping_timeout must be longer than the client's {}s ping interval, or 0 to never time out",
+                DEFAULT_PING_INTERVAL.as_secs()
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Default for LimitsConfig {
@@ -90,11 +730,46 @@ impl Default for LimitsConfig {
         Self {
             request_timeout_secs: default_request_timeout(),
             max_request_body_bytes: default_max_body(),
+            max_request_line: default_max_request_line(),
             idle_tunnel_timeout_secs: default_idle_timeout(),
+            ping_timeout_secs: default_ping_timeout(),
+            max_tunnels: 0,
+            max_tunnels_per_token: 0,
+            max_connections_per_ip: 0,
+            registrations_per_minute_per_ip: default_registrations_per_minute_per_ip(),
+            max_requests_per_second: 0,
+            banned_ips: Vec::new(),
+            fair_queue_threshold: 0,
         }
     }
 }
 
+/// Read an optional environment variable, naming the variable if its value is invalid
+fn env_value<T>(name: &str, parse: fn(&str) -> Result<T, String>) -> anyhow::Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) => parse(&value)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("{}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// `www-app=app,docs=app`, as `LOOPHOLE_ALIASES` gives them
+fn parse_aliases(value: &str) -> Result<BTreeMap<String, String>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((alias, subdomain)) => Ok((alias.trim().to_string(), subdomain.trim().to_string())),
+            None => Err(format!("'{}' isn't alias=subdomain", pair)),
+        })
+        .collect()
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
 fn default_http_port() -> u16 {
     80
 }
@@ -107,9 +782,30 @@ fn default_request_timeout() -> u64 {
 fn default_max_body() -> usize {
     10 * 1024 * 1024
 }
+fn default_max_request_line() -> usize {
+    MAX_REQUEST_LINE_BYTES
+}
 fn default_idle_timeout() -> u64 {
     3600
 }
+fn default_ping_timeout() -> u64 {
+    DEFAULT_PING_INTERVAL.as_secs() * MISSED_PINGS as u64
+}
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+fn default_registrations_per_minute_per_ip() -> u32 {
+    10
+}
+fn default_ownership_expiry() -> u64 {
+    30 * 86400
+}
+fn default_reconnect_grace() -> u64 {
+    60
+}
+fn default_usage_retention() -> u64 {
+    90
+}
 fn default_acme_directory() -> String {
     "https://acme-v02.api.letsencrypt.org/directory".to_string()
 }
@@ -118,11 +814,37 @@ fn default_certs_dir() -> String {
 }
 
 impl Config {
-    /// Load configuration from file
-    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    /// Load configuration from file. Unknown keys are logged as warnings, or
+    /// rejected when `strict`.
+    pub fn load(path: impl AsRef<Path>, strict: bool) -> anyhow::Result<Self> {
         let path = path.as_ref();
         let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+
+        // Older servers' configs fail to parse in confusing ways, or lose their admin
+        // token to an unknown-key warning
+        if let Ok(document) = content.parse::<toml::Table>() {
+            let signs = migrate::legacy_signs(&document);
+            if !signs.is_empty() {
+                anyhow::bail!(
+                    "{} is in the older server's format ({}). Convert it with: loophole migrate-config --in {} --out <new.toml>",
+                    path.display(),
+                    signs.join(", "),
+                    path.display()
+                );
+            }
+        }
+
+        // Before validation, since a misspelt key often explains why it fails
+        let unknown = Self::unknown_keys(&content);
+        if strict && !unknown.is_empty() {
+            let keys: Vec<String> = unknown.iter().map(ToString::to_string).collect();
+            anyhow::bail!("{} has unknown keys:\n  {}", path.display(), keys.join("\n  "));
+        }
+        for key in &unknown {
+            warn!("{}: {}", path.display(), key);
+        }
+
+        let config = Self::parse(&content)?;
 
         if config.version != CONFIG_VERSION {
             anyhow::bail!(
@@ -135,6 +857,174 @@ impl Config {
         Ok(config)
     }
 
+    /// Keys in `content` that no config setting reads (none if it isn't valid TOML,
+    /// which parsing reports instead)
+    pub fn unknown_keys(content: &str) -> Vec<config_schema::UnknownKey> {
+        content
+            .parse::<toml::Table>()
+            .map(|document| config_schema::unknown_keys(&document))
+            .unwrap_or_default()
+    }
+
+    /// Parse and validate configuration from a TOML string
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut config: Config = toml::from_str(content)?;
+        config.provenance = Provenance::File(content.parse()?);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject settings that can't work together
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.limits.validate()?;
+        match (&self.server.motd_min_client_version, &self.server.motd_outdated) {
+            (Some(version), _) if motd::parse_version(version).is_none() => {
+                anyhow::bail!("server.motd_min_client_version '{}' isn't a version, e.g. 0.5.0", version);
+            }
+            (None, Some(_)) => anyhow::bail!("server.motd_outdated needs server.motd_min_client_version"),
+            _ => {}
+        }
+        let monitoring = &self.monitoring;
+        if monitoring.dns_check_interval_secs > 0 {
+            if monitoring.dns_check_interval_secs < MIN_DNS_CHECK_INTERVAL_SECS {
+                anyhow::bail!("monitoring.dns_check_interval must be at least 1m");
+            }
+            if self.server.behind_cloudflare && monitoring.expected_ips.is_empty() {
+                anyhow::bail!(
+                    "monitoring.expected_ips must be set behind Cloudflare, whose addresses the domain resolves to"
+                );
+            }
+        }
+        for (key, url) in [("dns_resolver", Some(&monitoring.dns_resolver)), ("webhook_url", monitoring.webhook_url.as_ref())] {
+            if let Some(url) = url {
+                match url::Url::parse(url) {
+                    Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                    _ => anyhow::bail!("monitoring.{} '{}' isn't an http(s) URL", key, url),
+                }
+            }
+        }
+        if self.usage.retention_days == 0 {
+            anyhow::bail!("usage.retention_days must be greater than zero");
+        }
+
+        if let Some(name) = self.tokens.iter().find(|(_, token)| token.weight == Some(0)).map(|(name, _)| name) {
+            anyhow::bail!("tokens.{}.weight must be greater than zero", name);
+        }
+        for (name, token) in &self.tokens {
+            if let Err(e) = token.check_allowed_subdomains() {
+                anyhow::bail!("tokens.{}.{}", name, e);
+            }
+            if let Err(e) = token.check_request_policy() {
+                anyhow::bail!("tokens.{}.{}", name, e);
+            }
+        }
+        self.maintenance.time_zone()?;
+        HeaderRules::new(&self.response_headers)?;
+        for name in &self.registry.reserved {
+            if let Err(e) = Registry::validate_subdomain(name) {
+                anyhow::bail!("registry.reserved: '{}': {}", name, e);
+            }
+        }
+        for (alias, subdomain) in &self.registry.aliases {
+            for name in [alias, subdomain] {
+                if let Err(e) = Registry::validate_subdomain(name) {
+                    anyhow::bail!("registry.aliases: '{}': {}", name, e);
+                }
+            }
+            if alias.eq_ignore_ascii_case(subdomain) {
+                anyhow::bail!("registry.aliases: '{}' leads to itself", alias);
+            }
+            // One step only, so every alias leads straight to a tunnel
+            if self.registry.aliases.keys().any(|other| other.eq_ignore_ascii_case(subdomain)) {
+                anyhow::bail!("registry.aliases: '{}' leads to '{}', which is an alias itself", alias, subdomain);
+            }
+        }
+
+        if let Some(range) = self.tcp.port_range {
+            let mut used = vec![("server.http_port", self.server.http_port)];
+            if self.https.is_some() {
+                used.push(("server.https_port", self.server.https_port));
+            }
+            if let Some(port) = self.metrics.port {
+                used.push(("metrics.port", port));
+            }
+            if let Some(port) = self.server.control_port {
+                used.push(("server.control_port", port));
+            }
+            if let Some((name, port)) = used.into_iter().find(|(_, port)| range.contains(*port)) {
+                anyhow::bail!("tcp.port_range {} includes {} ({})", range, name, port);
+            }
+        }
+
+        if let Some(port) = self.metrics.port {
+            if port == self.server.http_port || (self.https.is_some() && port == self.server.https_port) {
+                anyhow::bail!("metrics.port {} is already used by the server", port);
+            }
+        }
+
+        if let Some(port) = self.server.control_port {
+            let mut used = vec![("server.http_port", self.server.http_port)];
+            if self.https.is_some() {
+                used.push(("server.https_port", self.server.https_port));
+            }
+            if let Some(metrics_port) = self.metrics.port {
+                used.push(("metrics.port", metrics_port));
+            }
+            if let Some((name, _)) = used.into_iter().find(|(_, used)| *used == port) {
+                anyhow::bail!("server.control_port {} is already used by {}", port, name);
+            }
+        }
+
+        if self.https.is_some() && self.server.public_scheme == Some(Scheme::Http) {
+            anyhow::bail!("server.public_scheme = \"http\" can't be used with [https]: plain HTTP is redirected to HTTPS");
+        }
+
+        if let Some(ref https) = self.https {
+            if !https.manual_certs && https.email.is_empty() {
+                anyhow::bail!("https.email is required unless https.manual_certs = true");
+            }
+            if https.prune_unused_after_days > 0 && https.manual_certs {
+                anyhow::bail!("https.prune_unused_after_days can't be used with manual_certs: every certificate was put there on purpose");
+            }
+            // When subdomains were last used is kept as long as usage is
+            if https.prune_unused_after_days > self.usage.retention_days {
+                anyhow::bail!(
+                    "https.prune_unused_after_days ({}) must not be more than usage.retention_days ({})",
+                    https.prune_unused_after_days,
+                    self.usage.retention_days
+                );
+            }
+            if https.storage == Storage::S3 && !cfg!(feature = "s3") {
+                anyhow::bail!("https.storage = \"s3\" needs a build with the s3 feature (cargo build --features s3)");
+            }
+            if let Some(ref dns) = https.dns {
+                if https.manual_certs {
+                    anyhow::bail!("[https.dns] can't be used with manual_certs, which never requests certificates");
+                }
+                if dns.api_token.is_empty() {
+                    anyhow::bail!("https.dns.api_token is required to publish challenge records with {}", dns.provider);
+                }
+                if https.challenge == AcmeChallenge::TlsAlpn01 {
+                    anyhow::bail!("https.challenge = \"tls-alpn-01\" can't be used with [https.dns], which answers challenges over DNS");
+                }
+            }
+            if https.challenge == AcmeChallenge::TlsAlpn01 && https.manual_certs {
+                anyhow::bail!("https.challenge = \"tls-alpn-01\" can't be used with manual_certs, which never requests certificates");
+            }
+            if self.server.behind_cloudflare && !https.manual_certs && https.dns.is_none() {
+                anyhow::bail!(
+                    "server.behind_cloudflare is set but [https] answers ACME challenges over {} (https.challenge), \
+                     which can't work: Cloudflare intercepts ports 80 and 443, so challenges never reach this server. Either let \
+                     Cloudflare terminate TLS (remove [https]), answer challenges over DNS with \
+                     [https.dns], or install a certificate, such as a Cloudflare origin certificate, \
+                     in certs_dir and set https.manual_certs = true",
+                    https.challenge
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Load configuration from environment variables (for Docker deployments)
     pub fn from_env() -> anyhow::Result<Self> {
         let domain = std::env::var(env::DOMAIN)
@@ -150,7 +1040,8 @@ impl Config {
             .and_then(|s| s.parse().ok())
             .unwrap_or_else(default_https_port);
 
-        // Parse tokens from comma-separated list
+        // Parse tokens from comma-separated list (keep-alive is allowed for all or none)
+        let keep_alive = env_flag(env::ALLOW_KEEP_ALIVE);
         let tokens_str = std::env::var(env::TOKENS)
             .map_err(|_| anyhow::anyhow!("{} environment variable is required", env::TOKENS))?;
 
@@ -158,13 +1049,13 @@ impl Config {
             .split(',')
             .map(|s| s.trim().to_string())
             .filter(|s| !s.is_empty())
-            .map(|token| (token, TokenConfig { admin: false }))
+            .map(|token| (token, TokenConfig { keep_alive, ..Default::default() }))
             .collect();
 
         // Add admin tokens if specified
         if let Ok(admin_tokens_str) = std::env::var(env::ADMIN_TOKENS) {
             for token in admin_tokens_str.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()) {
-                tokens.insert(token, TokenConfig { admin: true });
+                tokens.insert(token, TokenConfig { admin: true, keep_alive, ..Default::default() });
             }
         }
 
@@ -172,12 +1063,20 @@ impl Config {
             anyhow::bail!("{} must contain at least one token", env::TOKENS);
         }
 
-        // Parse HTTPS/ACME config if email is provided
-        let https = std::env::var(env::ACME_EMAIL).ok().map(|email| {
-            let staging = std::env::var(env::ACME_STAGING)
-                .ok()
-                .map(|s| s.eq_ignore_ascii_case("true") || s == "1")
-                .unwrap_or(false);
+        // Parse HTTPS config if an ACME email is provided or certificates are managed manually
+        let manual_certs = env_flag(env::MANUAL_CERTS);
+        let acme_email = std::env::var(env::ACME_EMAIL).ok();
+        let storage = env_value(env::STORAGE, Storage::parse)?.unwrap_or_default();
+        let prune_unused_after_days =
+            env_value(env::PRUNE_UNUSED_AFTER_DAYS, |s| s.parse::<u64>().map_err(|e| e.to_string()))?.unwrap_or(0);
+        let challenge = env_value(env::ACME_CHALLENGE, AcmeChallenge::parse)?.unwrap_or_default();
+        let dns = env_value(env::DNS_PROVIDER, Provider::parse)?.map(|provider| DnsConfig {
+            provider,
+            api_token: std::env::var(env::DNS_API_TOKEN).unwrap_or_default(),
+            zone_id: std::env::var(env::DNS_ZONE_ID).ok().filter(|zone_id| !zone_id.is_empty()),
+        });
+        let https = (acme_email.is_some() || manual_certs).then(|| {
+            let staging = env_flag(env::ACME_STAGING);
 
             let directory = std::env::var(env::ACME_DIRECTORY)
                 .ok()
@@ -188,53 +1087,184 @@ impl Config {
                 .unwrap_or_else(default_certs_dir);
 
             HttpsConfig {
-                email,
+                email: acme_email.unwrap_or_default(),
                 directory,
                 certs_dir,
+                storage,
                 staging,
                 ca_file: None,
+                manual_certs,
+                prune_unused_after_days,
+                challenge,
+                dns,
             }
         });
 
-        // Parse limits
-        let request_timeout_secs = std::env::var(env::REQUEST_TIMEOUT)
-            .ok()
-            .and_then(|s| s.parse().ok())
+        // Parse limits (accepts plain numbers or human-friendly values like "10MB" / "1h")
+        let request_timeout_secs = env_value(env::REQUEST_TIMEOUT, units::parse_duration_secs)?
             .unwrap_or_else(default_request_timeout);
 
-        let max_request_body_bytes = std::env::var(env::MAX_BODY)
-            .ok()
-            .and_then(|s| s.parse().ok())
+        let max_request_body_bytes = env_value(env::MAX_BODY, units::parse_bytes)?
+            .map(|bytes| bytes as usize)
             .unwrap_or_else(default_max_body);
 
-        let idle_tunnel_timeout_secs = std::env::var(env::IDLE_TIMEOUT)
-            .ok()
-            .and_then(|s| s.parse().ok())
+        let max_request_line = env_value(env::MAX_REQUEST_LINE, units::parse_bytes)?
+            .map(|bytes| bytes as usize)
+            .unwrap_or_else(default_max_request_line);
+
+        let idle_tunnel_timeout_secs = env_value(env::IDLE_TIMEOUT, units::parse_duration_secs)?
             .unwrap_or_else(default_idle_timeout);
 
-        Ok(Config {
+        let ping_timeout_secs = env_value(env::PING_TIMEOUT, units::parse_duration_secs)?
+            .unwrap_or_else(default_ping_timeout);
+
+        let strict_subdomain_ownership = env_flag(env::STRICT_OWNERSHIP);
+
+        let ownership_expiry_secs = env_value(env::OWNERSHIP_EXPIRY, units::parse_duration_secs)?
+            .unwrap_or_else(default_ownership_expiry);
+        let reconnect_grace_secs = env_value(env::RECONNECT_GRACE, units::parse_duration_secs)?
+            .unwrap_or_else(default_reconnect_grace);
+
+        let max_tunnels = env_value(env::MAX_TUNNELS, |s| s.parse::<usize>().map_err(|e| e.to_string()))?
+            .unwrap_or(0);
+        let max_tunnels_per_token =
+            env_value(env::MAX_TUNNELS_PER_TOKEN, |s| s.parse::<usize>().map_err(|e| e.to_string()))?
+                .unwrap_or(0);
+        let max_connections_per_ip =
+            env_value(env::MAX_CONNECTIONS_PER_IP, |s| s.parse::<u32>().map_err(|e| e.to_string()))?
+                .unwrap_or(0);
+        let registrations_per_minute_per_ip = env_value(env::REGISTRATIONS_PER_MINUTE_PER_IP, |s| {
+            s.parse::<u32>().map_err(|e| e.to_string())
+        })?
+        .unwrap_or_else(default_registrations_per_minute_per_ip);
+        let max_requests_per_second =
+            env_value(env::MAX_REQUESTS_PER_SECOND, |s| s.parse::<u32>().map_err(|e| e.to_string()))?
+                .unwrap_or(0);
+        let banned_ips = env_value(env::BANNED_IPS, parse_ip_list)?.unwrap_or_default();
+        let fair_queue_threshold =
+            env_value(env::FAIR_QUEUE_THRESHOLD, |s| s.parse::<usize>().map_err(|e| e.to_string()))?
+                .unwrap_or(0);
+
+        let limits = LimitsConfig {
+            request_timeout_secs,
+            max_request_body_bytes,
+            max_request_line,
+            idle_tunnel_timeout_secs,
+            ping_timeout_secs,
+            max_tunnels,
+            max_tunnels_per_token,
+            max_connections_per_ip,
+            registrations_per_minute_per_ip,
+            max_requests_per_second,
+            banned_ips,
+            fair_queue_threshold,
+        };
+
+        let config = Config {
             version: CONFIG_VERSION,
             server: ServerConfig {
                 domain,
                 http_port,
                 https_port,
+                control_port: env_value(env::CONTROL_PORT, |s| s.parse::<u16>().map_err(|e| e.to_string()))?,
+                bind_address: env_value(env::BIND_ADDRESS, parse_bind_address)?.unwrap_or_else(default_bind_address),
+                http_bind: env_value(env::HTTP_BIND, parse_bind_address)?,
+                https_bind: env_value(env::HTTPS_BIND, parse_bind_address)?,
+                strict_subdomain_ownership,
+                reject_confusables: env_flag(env::REJECT_CONFUSABLES),
+                ownership_expiry_secs,
+                reconnect_grace_secs,
+                strict_epoch: env_flag(env::STRICT_EPOCH),
+                behind_cloudflare: env_flag(env::BEHIND_CLOUDFLARE),
+                trusted_proxies: env_value(env::TRUSTED_PROXIES, parse_ip_list)?.unwrap_or_default(),
+                proxy_protocol: env_flag(env::PROXY_PROTOCOL),
+                public_port: env_value(env::PUBLIC_PORT, |s| s.parse::<u16>().map_err(|e| e.to_string()))?,
+                public_scheme: env_value(env::PUBLIC_SCHEME, Scheme::parse)?,
+                state_dir: std::env::var_os(env::STATE_DIR).filter(|dir| !dir.is_empty()).map(PathBuf::from),
+                motd: std::env::var(env::MOTD).ok().filter(|motd| !motd.is_empty()).map(MotdSource::Text),
+                motd_min_client_version: None,
+                motd_outdated: None,
             },
             tokens,
-            limits: LimitsConfig {
-                request_timeout_secs,
-                max_request_body_bytes,
-                idle_tunnel_timeout_secs,
-            },
+            limits,
             https,
-        })
+            metrics: MetricsConfig {
+                enabled: env_flag(env::METRICS),
+                token: std::env::var(env::METRICS_TOKEN).ok().filter(|t| !t.is_empty()),
+                port: env_value(env::METRICS_PORT, |s| s.parse::<u16>().map_err(|e| e.to_string()))?,
+            },
+            logging: LoggingConfig {
+                slow_request_threshold_ms: env_value(env::SLOW_REQUEST_THRESHOLD, |s| {
+                    s.parse::<u64>().map_err(|e| e.to_string())
+                })?
+                .unwrap_or(0),
+                registration_rate_warning: env_value(env::REGISTRATION_RATE_WARNING, |s| {
+                    s.parse::<u64>().map_err(|e| e.to_string())
+                })?
+                .unwrap_or(0),
+            },
+            tcp: TcpConfig {
+                port_range: env_value(env::TCP_PORT_RANGE, PortRange::parse)?,
+            },
+            usage: UsageConfig {
+                retention_days: env_value(env::USAGE_RETENTION_DAYS, |s| s.parse::<u64>().map_err(|e| e.to_string()))?
+                    .unwrap_or_else(default_usage_retention),
+            },
+            registry: RegistryConfig {
+                reserved: std::env::var(env::RESERVED_SUBDOMAINS)
+                    .map(|names| {
+                        names
+                            .split(',')
+                            .map(|name| name.trim().to_string())
+                            .filter(|name| !name.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                aliases: env_value(env::ALIASES, parse_aliases)?.unwrap_or_default(),
+            },
+            maintenance: MaintenanceConfig {
+                // Semicolon-separated, as cron expressions have commas of their own
+                windows: env_value(env::MAINTENANCE_WINDOWS, |s| {
+                    s.split(';').map(str::trim).filter(|w| !w.is_empty()).map(Window::parse).collect()
+                })?
+                .unwrap_or_default(),
+                timezone: std::env::var(env::MAINTENANCE_TIMEZONE)
+                    .ok()
+                    .filter(|tz| !tz.is_empty())
+                    .unwrap_or_else(default_timezone),
+            },
+            monitoring: MonitoringConfig {
+                dns_check_interval_secs: env_value(env::DNS_CHECK_INTERVAL, units::parse_duration_secs)?.unwrap_or(0),
+                expected_ips: env_value(env::EXPECTED_IPS, |s| {
+                    s.split(',')
+                        .map(str::trim)
+                        .filter(|ip| !ip.is_empty())
+                        .map(|ip| ip.parse::<IpAddr>().map_err(|e| format!("'{}': {}", ip, e)))
+                        .collect()
+                })?
+                .unwrap_or_default(),
+                dns_resolver: std::env::var(env::DNS_RESOLVER)
+                    .ok()
+                    .filter(|url| !url.is_empty())
+                    .unwrap_or_else(default_dns_resolver),
+                webhook_url: std::env::var(env::WEBHOOK_URL).ok().filter(|url| !url.is_empty()),
+            },
+            pages: PagesConfig {
+                theme_dir: std::env::var_os(env::THEME_DIR).filter(|dir| !dir.is_empty()).map(PathBuf::from),
+            },
+            response_headers: Vec::new(),
+            provenance: Provenance::env(),
+        };
+        config.validate()?;
+        Ok(config)
     }
 
     /// Load configuration: try file first, fall back to environment variables
-    pub fn load_or_from_env(path: Option<&str>) -> anyhow::Result<Self> {
+    pub fn load_or_from_env(path: Option<&str>, strict: bool) -> anyhow::Result<Self> {
         // If a specific path is provided, try to load from file
         if let Some(path) = path {
             if Path::new(path).exists() {
-                return Self::load(path);
+                return Self::load(path, strict);
             }
         }
 
@@ -258,17 +1288,540 @@ impl Config {
             env::TOKENS
         );
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tokens::TokenStore;
+
+    const BASE: &str = r#"
+[server]
+domain = "tunnel.example.com"
 
-    /// Validate a token and return its config if valid
-    pub fn validate_token(&self, token: &str) -> Option<&TokenConfig> {
-        self.tokens.get(token)
+[tokens.tk_test]
+admin = false
+"#;
+
+    fn parse_limits(limits: &str) -> anyhow::Result<LimitsConfig> {
+        Config::parse(&format!("{}\n[limits]\n{}", BASE, limits)).map(|c| c.limits)
+    }
+
+    #[test]
+    fn test_limits_accept_legacy_numeric_keys() {
+        let limits = parse_limits(
+            "request_timeout_secs = 45\nmax_request_body_bytes = 10485760\nidle_tunnel_timeout_secs = 600",
+        )
+        .unwrap();
+        assert_eq!(limits.request_timeout_secs, 45);
+        assert_eq!(limits.max_request_body_bytes, 10 * 1024 * 1024);
+        assert_eq!(limits.idle_tunnel_timeout_secs, 600);
+    }
+
+    #[test]
+    fn test_limits_accept_human_friendly_keys() {
+        let limits = parse_limits(
+            "request_timeout = \"2m30s\"\nmax_request_body = \"10MB\"\nidle_tunnel_timeout = \"1h\"",
+        )
+        .unwrap();
+        assert_eq!(limits.request_timeout_secs, 150);
+        assert_eq!(limits.max_request_body_bytes, 10 * 1024 * 1024);
+        assert_eq!(limits.idle_tunnel_timeout_secs, 3600);
+
+        // Formatting and re-parsing yields the same values
+        let formatted = format!(
+            "request_timeout = \"{}\"\nmax_request_body = \"{}\"\nidle_tunnel_timeout = \"{}\"",
+            units::format_duration(std::time::Duration::from_secs(limits.request_timeout_secs)),
+            units::format_bytes(limits.max_request_body_bytes as u64),
+            units::format_duration(std::time::Duration::from_secs(limits.idle_tunnel_timeout_secs)),
+        );
+        let reparsed = parse_limits(&formatted).unwrap();
+        assert_eq!(reparsed.request_timeout_secs, limits.request_timeout_secs);
+        assert_eq!(reparsed.max_request_body_bytes, limits.max_request_body_bytes);
+        assert_eq!(reparsed.idle_tunnel_timeout_secs, limits.idle_tunnel_timeout_secs);
+    }
+
+    #[test]
+    fn test_limits_errors_point_at_key() {
+        let err = parse_limits("max_request_body = \"10XB\"").unwrap_err().to_string();
+        assert!(err.contains("max_request_body = \"10XB\""), "{}", err);
+        assert!(err.contains("unknown unit"), "{}", err);
+
+        let err = parse_limits("request_timeout = \"-5s\"").unwrap_err().to_string();
+        assert!(err.contains("request_timeout = \"-5s\""), "{}", err);
+        assert!(err.contains("negative"), "{}", err);
+
+        let err = parse_limits("idle_tunnel_timeout_secs = -1").unwrap_err().to_string();
+        assert!(err.contains("idle_tunnel_timeout_secs = -1"), "{}", err);
+
+        let err = parse_limits("request_timeout = \"0s\"").unwrap_err().to_string();
+        assert!(err.contains("limits.request_timeout"), "{}", err);
+
+        let err = parse_limits("max_request_body = 0").unwrap_err().to_string();
+        assert!(err.contains("limits.max_request_body"), "{}", err);
+
+        let err = parse_limits("idle_tunnel_timeout = \"0\"").unwrap_err().to_string();
+        assert!(err.contains("limits.idle_tunnel_timeout"), "{}", err);
+
+        let err = parse_limits("max_request_line = \"128KB\"").unwrap_err().to_string();
+        assert!(err.contains("limits.max_request_line"), "{}", err);
+        assert_eq!(parse_limits("max_request_line = \"16KB\"").unwrap().max_request_line, 16 * 1024);
+        assert_eq!(parse_limits("").unwrap().max_request_line, 64 * 1024);
+
+        // A ping timeout has to outlast the gap between client pings
+        let err = parse_limits("ping_timeout = \"30s\"").unwrap_err().to_string();
+        assert!(err.contains("limits.ping_timeout"), "{}", err);
+        assert_eq!(parse_limits("ping_timeout = \"31s\"").unwrap().ping_timeout_secs, 31);
+        assert_eq!(parse_limits("ping_timeout = 0").unwrap().ping_timeout_secs, 0);
     }
 
-    /// Check if a token is valid and has admin privileges
-    pub fn validate_admin_token(&self, token: &str) -> bool {
-        self.tokens
-            .get(token)
-            .map(|t| t.admin)
-            .unwrap_or(false)
+    #[test]
+    fn test_behind_cloudflare_rejects_acme() {
+        let config = |extra: &str| {
+            Config::parse(&format!(
+                "{}{}",
+                BASE.replace("[server]\n", "[server]\nbehind_cloudflare = true\n"),
+                extra
+            ))
+        };
+
+        // Cloudflare terminates TLS
+        assert!(config("").unwrap().server.behind_cloudflare);
+
+        let err = config("\n[https]\nemail = \"admin@example.com\"\n").unwrap_err().to_string();
+        assert!(err.contains("HTTP-01"), "{}", err);
+        assert!(err.contains("manual_certs"), "{}", err);
+
+        let config = config("\n[https]\nmanual_certs = true\n").unwrap();
+        assert!(config.https.unwrap().manual_certs);
+    }
+
+    #[test]
+    fn test_control_port() {
+        assert_eq!(Config::parse(BASE).unwrap().server.control_addr(), None);
+        let config = Config::parse(&BASE.replace("[server]\n", "[server]\ncontrol_port = 9443\n")).unwrap();
+        assert_eq!(config.server.control_addr(), Some("0.0.0.0:9443".parse().unwrap()));
+
+        let err = Config::parse(&BASE.replace("[server]\n", "[server]\ncontrol_port = 80\n")).unwrap_err();
+        assert!(err.to_string().contains("already used by server.http_port"), "{}", err);
+        let err = Config::parse(&format!(
+            "{}\n[tcp]\nport_range = \"9000-9999\"\n",
+            BASE.replace("[server]\n", "[server]\ncontrol_port = 9443\n")
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("server.control_port"), "{}", err);
+    }
+
+    #[test]
+    fn test_monitoring() {
+        let monitoring = |extra: &str| Config::parse(&format!("{}\n[monitoring]\n{}", BASE, extra)).map(|c| c.monitoring);
+
+        let off = monitoring("").unwrap();
+        assert_eq!(off.dns_check_interval_secs, 0);
+        assert_eq!(off.dns_resolver, "https://cloudflare-dns.com/dns-query");
+        let on = monitoring("dns_check_interval = \"15m\"\nexpected_ips = [\"203.0.113.10\"]\nwebhook_url = \"https://hooks.example.com/loophole\"")
+            .unwrap();
+        assert_eq!(on.dns_check_interval_secs, 900);
+        assert_eq!(on.expected_ips, ["203.0.113.10".parse::<IpAddr>().unwrap()]);
+        assert_eq!(on.webhook_url.as_deref(), Some("https://hooks.example.com/loophole"));
+
+        let err = monitoring("dns_check_interval = \"10s\"").unwrap_err().to_string();
+        assert!(err.contains("at least 1m"), "{}", err);
+        let err = monitoring("webhook_url = \"hooks.example.com\"").unwrap_err().to_string();
+        assert!(err.contains("monitoring.webhook_url"), "{}", err);
+        // Behind Cloudflare the domain resolves to Cloudflare, which can't be detected
+        let err = Config::parse(&format!(
+            "{}\n[monitoring]\ndns_check_interval = \"15m\"\n",
+            BASE.replace("[server]\n", "[server]\nbehind_cloudflare = true\n")
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("monitoring.expected_ips"), "{}", err);
+    }
+
+    #[test]
+    fn test_prune_unused_certificates() {
+        let https = |extra: &str| Config::parse(&format!("{}\n[https]\nemail = \"admin@example.com\"\n{}", BASE, extra));
+
+        assert_eq!(https("").unwrap().https.unwrap().prune_unused_after_days, 0);
+        assert_eq!(https("prune_unused_after_days = 30").unwrap().https.unwrap().prune_unused_after_days, 30);
+
+        let err = https("prune_unused_after_days = 30\nmanual_certs = true").unwrap_err().to_string();
+        assert!(err.contains("manual_certs"), "{}", err);
+        // Last use isn't known for longer than usage is kept
+        let err = https("prune_unused_after_days = 120").unwrap_err().to_string();
+        assert!(err.contains("usage.retention_days (90)"), "{}", err);
+        assert!(Config::parse(&format!(
+            "{}\n[usage]\nretention_days = 365\n\n[https]\nemail = \"admin@example.com\"\nprune_unused_after_days = 120\n",
+            BASE
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let parse = |proxies: &str| {
+            Config::parse(&BASE.replace("[server]\n", &format!("[server]\ntrusted_proxies = {}\n", proxies)))
+        };
+        assert_eq!(
+            parse("[\"10.0.0.0/8\", \"192.0.2.1\"]").unwrap().server.trusted_proxies,
+            vec!["10.0.0.0/8".parse::<IpNet>().unwrap(), "192.0.2.1/32".parse().unwrap()]
+        );
+        assert!(Config::parse(BASE).unwrap().server.trusted_proxies.is_empty());
+
+        let err = parse("[\"load-balancer\"]").unwrap_err().to_string();
+        assert!(err.contains("load-balancer"), "{}", err);
+    }
+
+    #[test]
+    fn test_bind_addresses() {
+        let parse = |keys: &str| Config::parse(&BASE.replace("[server]\n", &format!("[server]\n{}\n", keys)));
+
+        let server = Config::parse(BASE).unwrap().server;
+        assert_eq!(server.http_addr(), "0.0.0.0:80".parse().unwrap());
+        assert_eq!(server.https_addr(), "0.0.0.0:443".parse().unwrap());
+
+        let server = parse("bind_address = \"::\"\nhttp_bind = \"10.0.0.5\"").unwrap().server;
+        assert_eq!(server.http_addr(), "10.0.0.5:80".parse().unwrap());
+        assert_eq!(server.https_addr(), "[::]:443".parse().unwrap());
+
+        for (key, value) in [("bind_address", "localhost"), ("http_bind", "10.0.0.0/8"), ("https_bind", "10.0.0.5:443")] {
+            let err = parse(&format!("{} = \"{}\"", key, value)).unwrap_err();
+            let err = format!("{:#}", err);
+            assert!(err.contains(key) && err.contains(value), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_defaults_off() {
+        assert!(!Config::parse(BASE).unwrap().server.proxy_protocol);
+        let config = Config::parse(&BASE.replace("[server]\n", "[server]\nproxy_protocol = true\n")).unwrap();
+        assert!(config.server.proxy_protocol);
+    }
+
+    #[test]
+    fn test_dns_challenges() {
+        let https = |extra: &str| Config::parse(&format!("{}\n[https]\nemail = \"admin@example.com\"\n{}", BASE, extra));
+
+        assert!(https("").unwrap().https.unwrap().dns.is_none());
+        let dns = https("[https.dns]\nprovider = \"cloudflare\"\napi_token = \"cf_token\"\n")
+            .unwrap()
+            .https
+            .unwrap()
+            .dns
+            .unwrap();
+        assert_eq!(dns.provider, Provider::Cloudflare);
+        assert_eq!(dns.api_token, "cf_token");
+        assert_eq!(dns.zone_id, None);
+
+        let err = https("[https.dns]\nprovider = \"cloudflare\"\n").unwrap_err().to_string();
+        assert!(err.contains("https.dns.api_token"), "{}", err);
+        let err = https("manual_certs = true\n[https.dns]\nprovider = \"cloudflare\"\napi_token = \"cf_token\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("manual_certs"), "{}", err);
+        assert!(https("[https.dns]\nprovider = \"route53\"\napi_token = \"token\"\n").is_err());
+
+        // DNS-01 doesn't need port 80, so it works behind Cloudflare
+        let behind_cloudflare = BASE.replace("[server]\n", "[server]\nbehind_cloudflare = true\n");
+        assert!(Config::parse(&format!(
+            "{}\n[https]\nemail = \"admin@example.com\"\n\n[https.dns]\nprovider = \"cloudflare\"\napi_token = \"cf_token\"\n",
+            behind_cloudflare
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn test_tls_alpn_challenges() {
+        let https = |extra: &str| Config::parse(&format!("{}\n[https]\nemail = \"admin@example.com\"\n{}", BASE, extra));
+
+        assert_eq!(https("").unwrap().https.unwrap().challenge, AcmeChallenge::Http01);
+        let config = https("challenge = \"tls-alpn-01\"\n").unwrap();
+        assert_eq!(config.https.unwrap().challenge, AcmeChallenge::TlsAlpn01);
+        assert!(https("challenge = \"dns-01\"\n").is_err());
+
+        let err = https("challenge = \"tls-alpn-01\"\nmanual_certs = true\n").unwrap_err().to_string();
+        assert!(err.contains("manual_certs"), "{}", err);
+        let err = https("challenge = \"tls-alpn-01\"\n[https.dns]\nprovider = \"cloudflare\"\napi_token = \"cf_token\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[https.dns]"), "{}", err);
+
+        // Cloudflare takes port 443 as well as 80
+        let behind_cloudflare = BASE.replace("[server]\n", "[server]\nbehind_cloudflare = true\n");
+        let err = Config::parse(&format!(
+            "{}\n[https]\nemail = \"admin@example.com\"\nchallenge = \"tls-alpn-01\"\n",
+            behind_cloudflare
+        ))
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("TLS-ALPN-01"), "{}", err);
+    }
+
+    #[test]
+    fn test_https_requires_email_for_acme() {
+        let err = Config::parse(&format!("{}\n[https]\ncerts_dir = \"./certs\"\n", BASE))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("https.email"), "{}", err);
+    }
+
+    #[test]
+    fn test_admission_limits() {
+        let limits = parse_limits(
+            "max_tunnels = 100\nmax_connections_per_ip = 5\nbanned_ips = [\"203.0.113.7\", \"2001:db8::/32\"]",
+        )
+        .unwrap();
+        assert_eq!(limits.max_tunnels, 100);
+        assert_eq!(limits.max_connections_per_ip, 5);
+        assert_eq!(limits.registrations_per_minute_per_ip, 10);
+        assert_eq!(parse_limits("registrations_per_minute_per_ip = 0").unwrap().registrations_per_minute_per_ip, 0);
+        assert_eq!(
+            limits.banned_ips,
+            vec!["203.0.113.7/32".parse::<IpNet>().unwrap(), "2001:db8::/32".parse().unwrap()]
+        );
+
+        let err = parse_limits("banned_ips = [\"not-an-ip\"]").unwrap_err().to_string();
+        assert!(err.contains("not-an-ip"), "{}", err);
+
+        assert_eq!(parse_ip_list("192.0.2.1, 10.0.0.0/8,").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_max_tunnels_per_token() {
+        let config = Config::parse(&format!(
+            "{}\n[tokens.tk_ci]\nmax_tunnels = 20\n[tokens.tk_free]\nmax_tunnels = 0\n[limits]\nmax_tunnels_per_token = 3\n",
+            BASE
+        ))
+        .unwrap();
+        let tokens = TokenStore::new(&config);
+        assert_eq!(tokens.max_tunnels_for("tk_test"), 3);
+        assert_eq!(tokens.max_tunnels_for("tk_ci"), 20);
+        assert_eq!(tokens.max_tunnels_for("tk_free"), 0);
+
+        // No limit by default
+        assert_eq!(TokenStore::new(&Config::parse(BASE).unwrap()).max_tunnels_for("tk_test"), 0);
+    }
+
+    #[test]
+    fn test_fair_queue() {
+        let config = Config::parse(&format!(
+            "{}
+[tokens.tk_paid]
+weight = 4
+[limits]
+fair_queue_threshold = 64
+",
+            BASE
+        ))
+        .unwrap();
+        assert_eq!(config.limits.fair_queue_threshold, 64);
+        let tokens = TokenStore::new(&config);
+        assert_eq!(tokens.weight_for("tk_paid"), 4);
+        assert_eq!(tokens.weight_for("tk_test"), 1);
+        assert_eq!(Config::parse(BASE).unwrap().limits.fair_queue_threshold, 0);
+
+        let err = Config::parse(&format!("{}
+[tokens.tk_zero]
+weight = 0
+", BASE)).unwrap_err().to_string();
+        assert!(err.contains("tokens.tk_zero.weight"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_unknown_keys() {
+        let path = std::env::temp_dir().join(format!("loophole-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, format!("{}\n[limits]\nidle_tunnel_timout_secs = 60\n", BASE)).unwrap();
+
+        // Warned about, and the default applies
+        let config = Config::load(&path, false).unwrap();
+        assert_eq!(config.limits.idle_tunnel_timeout_secs, default_idle_timeout());
+
+        let err = Config::load(&path, true).unwrap_err().to_string();
+        assert!(
+            err.contains("unknown key `limits.idle_tunnel_timout_secs` (did you mean `idle_tunnel_timeout_secs`?)"),
+            "{}",
+            err
+        );
+
+        // Checked before validation, which would otherwise fail first
+        std::fs::write(&path, format!("{}\n[https]\nemial = \"me@example.com\"\n", BASE)).unwrap();
+        let err = Config::load(&path, true).unwrap_err().to_string();
+        assert!(err.contains("did you mean `email`?"), "{}", err);
+        assert!(Config::load(&path, false).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_points_old_configs_at_migrate() {
+        let path = std::env::temp_dir().join(format!("loophole-config-{}.toml", uuid::Uuid::new_v4()));
+        let old = "[server]\ndomain = \"tunnel.example.com\"\n\n[tokens]\ntk_alice = 5\n\n[admin]\ntoken = \"tk_admin\"\n";
+        std::fs::write(&path, old).unwrap();
+
+        // Rather than serde's complaint about tokens.tk_alice
+        let err = Config::load(&path, false).unwrap_err().to_string();
+        assert!(err.contains("older server's format (tokens.tk_alice is a number, an [admin] block)"), "{}", err);
+        assert!(err.contains("loophole migrate-config --in"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_allowed_subdomains() {
+        let restricted = |patterns: &[&str]| TokenConfig {
+            allowed_subdomains: Some(patterns.iter().map(|p| p.to_string()).collect()),
+            ..Default::default()
+        };
+
+        // Any name by default
+        assert!(TokenConfig::default().allows_subdomain("anything"));
+
+        let ci = restricted(&["ci-*", "nightly"]);
+        assert!(ci.allows_subdomain("ci-build"));
+        assert!(ci.allows_subdomain("CI-Build"));
+        assert!(ci.allows_subdomain("nightly"));
+        assert!(!ci.allows_subdomain("nightly-2"));
+        assert!(!ci.allows_subdomain("myapp"));
+        assert!(!ci.allows_subdomain("my-ci-build"));
+        for _ in 0..20 {
+            let name = ci.random_subdomain();
+            assert!(ci.allows_subdomain(&name), "{}", name);
+        }
+
+        let previews = restricted(&["*-preview", "pr-*-app"]);
+        assert!(previews.allows_subdomain("feature-preview"));
+        assert!(previews.allows_subdomain("pr-12-app"));
+        assert!(!previews.allows_subdomain("pr-app"));
+        assert!(!previews.allows_subdomain("preview"));
+        assert!(previews.allows_subdomain(&previews.random_subdomain()));
+    }
+
+    #[test]
+    fn test_allowed_subdomains_and_reserved_are_checked() {
+        let config = |extra: &str| Config::parse(&format!("{}{}", BASE, extra));
+        let config_err = |extra: &str| format!("{:#}", config(extra).unwrap_err());
+
+        assert!(config("allowed_subdomains = [\"ci-*\"]\n[registry]\nreserved = [\"staging\"]\n").is_ok());
+        assert!(config_err("allowed_subdomains = []\n").contains("tokens.tk_test.allowed_subdomains is empty"));
+        assert!(config_err("allowed_subdomains = [\"ci_*\"]\n").contains("pattern 'ci_*'"));
+        assert!(config_err("[registry]\nreserved = [\"a.b\"]\n").contains("registry.reserved: 'a.b'"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config = |extra: &str| Config::parse(&format!("{}{}", BASE, extra));
+        let config_err = |extra: &str| format!("{:#}", config(extra).unwrap_err());
+
+        let aliases = config("[registry.aliases]\nwww-app = \"app\"\ndocs = \"app\"\n").unwrap().registry.aliases;
+        assert_eq!(aliases, BTreeMap::from([("docs".to_string(), "app".to_string()), ("www-app".to_string(), "app".to_string())]));
+        assert!(config_err("[registry.aliases]\n\"a.b\" = \"app\"\n").contains("registry.aliases: 'a.b'"));
+        assert!(config_err("[registry.aliases]\nwww-app = \"WWW-APP\"\n").contains("leads to itself"));
+        assert!(config_err("[registry.aliases]\nwww-app = \"app\"\napp = \"main\"\n")
+            .contains("'www-app' leads to 'app', which is an alias itself"));
+
+        assert_eq!(parse_aliases(" www-app=app, docs = app ,"), Ok(aliases));
+        assert!(parse_aliases("www-app").unwrap_err().contains("isn't alias=subdomain"));
+    }
+
+    #[test]
+    fn test_state_dir() {
+        let config = Config::parse(BASE).unwrap();
+        assert_eq!(config.server.state_dir("/nonexistent/loophole/server.toml"), None);
+        let config_path = std::env::temp_dir().join(format!("loophole-state-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&config_path, BASE).unwrap();
+        assert_eq!(config.server.state_dir(config_path.to_str().unwrap()), Some(std::env::temp_dir()));
+        std::fs::remove_file(&config_path).unwrap();
+
+        let config = Config::parse(&BASE.replace("[server]\n", "[server]\nstate_dir = \"/var/lib/loophole\"\n")).unwrap();
+        assert_eq!(config.server.state_dir("/nonexistent/loophole/server.toml"), Some(PathBuf::from("/var/lib/loophole")));
+    }
+
+    #[test]
+    fn test_request_policy() {
+        let config = |extra: &str| Config::parse(&format!("{}{}", BASE, extra));
+        let config_err = |extra: &str| format!("{:#}", config(extra).unwrap_err());
+
+        let token = &config("strip_request_headers = [\"Authorization\"]\nallowed_methods = [\"get\", \"HEAD\"]\n").unwrap().tokens["tk_test"];
+        assert_eq!(token.strip_request_headers, vec!["Authorization"]);
+        assert_eq!(token.allowed_methods, Some(vec!["get".to_string(), "HEAD".to_string()]));
+
+        assert!(config_err("allowed_methods = []\n").contains("tokens.tk_test.allowed_methods is empty"));
+        assert!(config_err("allowed_methods = [\"GET POST\"]\n").contains("'GET POST' isn't a request method"));
+        assert!(config_err("strip_request_headers = [\"x:y\"]\n").contains("'x:y' isn't a header name"));
+    }
+
+    #[test]
+    fn test_tcp_port_range() {
+        let config = Config::parse(&format!("{}\n[tcp]\nport_range = \"20000-20100\"\n", BASE)).unwrap();
+        assert_eq!(config.tcp.port_range, Some(PortRange { start: 20000, end: 20100 }));
+        assert_eq!(Config::parse(BASE).unwrap().tcp.port_range, None);
+
+        let err = Config::parse(&format!("{}\n[tcp]\nport_range = \"20100-20000\"\n", BASE)).unwrap_err();
+        assert!(format!("{:#}", err).contains("greater than"), "{:#}", err);
+
+        // Ports the server itself listens on can't be handed to tunnels
+        let err = Config::parse(&format!("{}\n[tcp]\nport_range = \"1-1000\"\n", BASE)).unwrap_err();
+        assert!(err.to_string().contains("server.http_port (80)"), "{}", err);
+        let err = Config::parse(&format!(
+            "{}\n[metrics]\nport = 20050\n[tcp]\nport_range = \"20000-20100\"\n",
+            BASE
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("metrics.port (20050)"), "{}", err);
+    }
+
+    #[test]
+    fn test_maintenance_config() {
+        let config = Config::parse(BASE).unwrap();
+        assert!(config.maintenance.windows.is_empty());
+        assert_eq!(config.maintenance.timezone, "UTC");
+
+        let config = Config::parse(&format!(
+            "{}\n[maintenance]\nwindows = [\"0 2 * * * for 30m\", \"@weekly for 2h\"]\ntimezone = \"Europe/London\"\n",
+            BASE
+        ))
+        .unwrap();
+        let windows: Vec<String> = config.maintenance.windows.iter().map(ToString::to_string).collect();
+        assert_eq!(windows, vec!["0 2 * * * for 30m", "@weekly for 2h"]);
+
+        let err = Config::parse(&format!("{}\n[maintenance]\nwindows = [\"0 2 * * *\"]\n", BASE)).unwrap_err();
+        assert!(format!("{:#}", err).contains("for"), "{:#}", err);
+        let err = Config::parse(&format!("{}\n[maintenance]\ntimezone = \"Mars/Olympus\"\n", BASE)).unwrap_err();
+        assert!(format!("{:#}", err).contains("maintenance.timezone"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_response_headers_config() {
+        let config = Config::parse(BASE).unwrap();
+        assert!(config.response_headers.is_empty());
+
+        let config = Config::parse(&format!(
+            "{}\n[[response_headers]]\nsubdomain = \"staging-*\"\nset = {{ X-Env = \"staging\" }}\n\n[[response_headers]]\nsubdomain = \"*\"\nadd = {{ Cache-Control = \"no-transform\" }}\n",
+            BASE
+        ))
+        .unwrap();
+        let patterns: Vec<&str> = config.response_headers.iter().map(|rule| rule.subdomain.as_str()).collect();
+        assert_eq!(patterns, ["staging-*", "*"]);
+        assert!(config.response_headers[0].matches("Staging-Web"));
+        assert!(!config.response_headers[0].matches("prod"));
+
+        let err = Config::parse(&format!("{}\n[[response_headers]]\nsubdomain = \"*\"\nset = {{ Content-Length = \"0\" }}\n", BASE)).unwrap_err();
+        assert!(err.to_string().contains("response_headers[0]: set: 'Content-Length'"), "{}", err);
+    }
+
+    #[test]
+    fn test_metrics_config() {
+        let config = Config::parse(BASE).unwrap();
+        assert!(!config.metrics.enabled);
+
+        let config = Config::parse(&format!("{}\n[metrics]\nenabled = true\ntoken = \"secret\"\nport = 9090\n", BASE)).unwrap();
+        assert!(config.metrics.enabled);
+        assert_eq!(config.metrics.token.as_deref(), Some("secret"));
+        assert_eq!(config.metrics.port, Some(9090));
+
+        let err = Config::parse(&format!("{}\n[metrics]\nenabled = true\nport = 80\n", BASE))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("metrics.port 80"), "{}", err);
     }
 }
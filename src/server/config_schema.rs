@@ -0,0 +1,341 @@
+//! The keys each config table accepts. serde ignores keys it doesn't know, so a
+//! typo like `idle_tunnel_timout_secs` would otherwise leave the default in place
+//! without a word; the config file is walked against this listing to report them.
+//! Keep it in step with the structs in `config.rs`, aliases included.
+
+use std::fmt;
+
+enum Node {
+    /// A value (or inline array) that isn't checked any further
+    Value,
+    /// A table with a fixed set of keys
+    Table(&'static [(&'static str, Node)]),
+    /// A table whose keys are names chosen by the user, such as tokens
+    Map(&'static Node),
+    /// An array of tables, such as `[[response_headers]]`
+    List(&'static Node),
+}
+
+use Node::{List, Map, Table, Value};
+
+const SERVER: Node = Table(&[
+    ("domain", Value),
+    ("http_port", Value),
+    ("https_port", Value),
+    ("control_port", Value),
+    ("bind_address", Value),
+    ("http_bind", Value),
+    ("https_bind", Value),
+    ("strict_subdomain_ownership", Value),
+    ("reject_confusables", Value),
+    ("ownership_expiry_secs", Value),
+    ("ownership_expiry", Value),
+    ("reconnect_grace_secs", Value),
+    ("reconnect_grace", Value),
+    ("strict_epoch", Value),
+    ("behind_cloudflare", Value),
+    ("trusted_proxies", Value),
+    ("proxy_protocol", Value),
+    ("public_port", Value),
+    ("public_scheme", Value),
+    ("state_dir", Value),
+    ("motd", Value),
+    ("motd_min_client_version", Value),
+    ("motd_outdated", Value),
+]);
+
+const TOKEN: Node = Table(&[
+    ("admin", Value),
+    ("keep_alive", Value),
+    ("max_tunnels", Value),
+    ("max_requests_per_second", Value),
+    ("weight", Value),
+    ("allowed_subdomains", Value),
+    ("strip_request_headers", Value),
+    ("allowed_methods", Value),
+]);
+
+const LIMITS: Node = Table(&[
+    ("request_timeout_secs", Value),
+    ("request_timeout", Value),
+    ("max_request_body_bytes", Value),
+    ("max_request_body", Value),
+    ("max_request_line", Value),
+    ("idle_tunnel_timeout_secs", Value),
+    ("idle_tunnel_timeout", Value),
+    ("ping_timeout_secs", Value),
+    ("ping_timeout", Value),
+    ("max_tunnels", Value),
+    ("max_tunnels_per_token", Value),
+    ("max_connections_per_ip", Value),
+    ("registrations_per_minute_per_ip", Value),
+    ("max_requests_per_second", Value),
+    ("banned_ips", Value),
+    ("fair_queue_threshold", Value),
+]);
+
+const DNS: Node = Table(&[("provider", Value), ("api_token", Value), ("zone_id", Value)]);
+
+const HTTPS: Node = Table(&[
+    ("email", Value),
+    ("directory", Value),
+    ("certs_dir", Value),
+    ("storage", Value),
+    ("staging", Value),
+    ("ca_file", Value),
+    ("manual_certs", Value),
+    ("prune_unused_after_days", Value),
+    ("challenge", Value),
+    ("dns", DNS),
+]);
+
+const METRICS: Node = Table(&[("enabled", Value), ("token", Value), ("port", Value)]);
+
+const LOGGING: Node = Table(&[("slow_request_threshold_ms", Value), ("registration_rate_warning", Value)]);
+
+const TCP: Node = Table(&[("port_range", Value)]);
+
+const USAGE: Node = Table(&[("retention_days", Value)]);
+
+const REGISTRY: Node = Table(&[("reserved", Value), ("aliases", Map(&Value))]);
+
+const MAINTENANCE: Node = Table(&[("windows", Value), ("timezone", Value)]);
+
+const MONITORING: Node = Table(&[
+    ("dns_check_interval_secs", Value),
+    ("dns_check_interval", Value),
+    ("expected_ips", Value),
+    ("dns_resolver", Value),
+    ("webhook_url", Value),
+]);
+
+const RESPONSE_HEADERS: Node = Table(&[("subdomain", Value), ("set", Map(&Value)), ("add", Map(&Value))]);
+
+const PAGES: Node = Table(&[("theme_dir", Value)]);
+
+const CONFIG: Node = Table(&[
+    ("version", Value),
+    ("server", SERVER),
+    ("tokens", Map(&TOKEN)),
+    ("limits", LIMITS),
+    ("https", HTTPS),
+    ("acme", HTTPS),
+    ("metrics", METRICS),
+    ("logging", LOGGING),
+    ("tcp", TCP),
+    ("usage", USAGE),
+    ("registry", REGISTRY),
+    ("maintenance", MAINTENANCE),
+    ("monitoring", MONITORING),
+    ("pages", PAGES),
+    ("response_headers", List(&RESPONSE_HEADERS)),
+]);
+
+/// The keys a token's table may have
+pub fn token_keys() -> impl Iterator<Item = &'static str> {
+    let Table(fields) = TOKEN else { unreachable!("tokens are tables") };
+    fields.iter().map(|(name, _)| *name)
+}
+
+/// A key the config structs don't read
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownKey {
+    /// Dotted path to the key, e.g. `limits.idle_tunnel_timout_secs`
+    pub path: String,
+    /// The closest known key in the same table, if any is close enough
+    pub suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown key `{}`", self.path)?;
+        if let Some(suggestion) = self.suggestion {
+            write!(f, " (did you mean `{}`?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every key in `document` that the config structs don't read, sorted by path
+pub fn unknown_keys(document: &toml::Table) -> Vec<UnknownKey> {
+    let mut unknown = Vec::new();
+    walk(&CONFIG, document, "", &mut unknown);
+    unknown
+}
+
+fn walk(node: &Node, table: &toml::Table, prefix: &str, unknown: &mut Vec<UnknownKey>) {
+    for (key, value) in table {
+        let path = format!("{}{}", prefix, key);
+        let child = match node {
+            Value | List(_) => continue,
+            Map(child) => Some(*child),
+            Table(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, child)| child),
+        };
+        match (child, value) {
+            (Some(List(item)), toml::Value::Array(items)) => {
+                for (i, table) in items.iter().enumerate() {
+                    if let toml::Value::Table(table) = table {
+                        walk(item, table, &format!("{}[{}].", path, i), unknown);
+                    }
+                }
+            }
+            (Some(child), toml::Value::Table(table)) => walk(child, table, &format!("{}.", path), unknown),
+            (Some(_), _) => {}
+            (None, _) => unknown.push(UnknownKey {
+                path,
+                suggestion: match node {
+                    Table(fields) => suggest(key, fields.iter().map(|(name, _)| *name)),
+                    _ => None,
+                },
+            }),
+        }
+    }
+}
+
+/// The candidate nearest to `key`, if it's within a typo's distance
+fn suggest(key: &str, candidates: impl Iterator<Item = &'static str>) -> Option<&'static str> {
+    // Two edits covers a swapped pair of letters, even in short keys
+    let max_distance = (key.chars().count() / 3).max(2);
+    candidates
+        .map(|candidate| (levenshtein(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance between `a` and `b`: insertions, deletions and substitutions
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(content: &str) -> Vec<String> {
+        let document: toml::Table = toml::from_str(content).unwrap();
+        unknown_keys(&document).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("timout", "timeout"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("same", "same"), 0);
+    }
+
+    #[test]
+    fn test_typos_get_suggestions() {
+        let warnings = check(
+            r#"
+versoin = 1
+
+[limits]
+idle_tunnel_timout_secs = 60
+request_timeout = "30s"
+"#,
+        );
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `limits.idle_tunnel_timout_secs` (did you mean `idle_tunnel_timeout_secs`?)",
+                "unknown key `versoin` (did you mean `version`?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_tables() {
+        let warnings = check(
+            r#"
+[tokens.tk_alice]
+admin = true
+admn = true
+
+[tokens.tk_bob]
+max_tunnel = 2
+
+[acme]
+emial = "me@example.com"
+
+[https.dns]
+provider = "cloudflare"
+api_tokn = "cf_token"
+
+[server.tls]
+cert = "x"
+"#,
+        );
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `acme.emial` (did you mean `email`?)",
+                "unknown key `https.dns.api_tokn` (did you mean `api_token`?)",
+                "unknown key `server.tls`",
+                "unknown key `tokens.tk_alice.admn` (did you mean `admin`?)",
+                "unknown key `tokens.tk_bob.max_tunnel` (did you mean `max_tunnels`?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arrays_of_tables() {
+        let warnings = check(
+            r#"
+[[response_headers]]
+subdomain = "staging-*"
+set = { X-Env = "staging" }
+
+[[response_headers]]
+subdomains = "*"
+append = { X-Env = "any" }
+"#,
+        );
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `response_headers[1].append`",
+                "unknown key `response_headers[1].subdomains` (did you mean `subdomain`?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unrelated_keys_get_no_suggestion() {
+        assert_eq!(check("[metrics]\nprometheus_path = \"/m\""), ["unknown key `metrics.prometheus_path`"]);
+        assert_eq!(check("[dashboard]\nenabled = true"), ["unknown key `dashboard`"]);
+    }
+
+    #[test]
+    fn test_listing_covers_defaulted_structs() {
+        // Debug output names every field, so new fields can't be left out of the listing
+        use crate::server::config::{LimitsConfig, LoggingConfig, MetricsConfig, TcpConfig};
+        for (table, debug) in [
+            ("limits", format!("{:?}", LimitsConfig::default())),
+            ("metrics", format!("{:?}", MetricsConfig::default())),
+            ("logging", format!("{:?}", LoggingConfig::default())),
+            ("tcp", format!("{:?}", TcpConfig::default())),
+        ] {
+            let fields = debug
+                .split(['{', ','])
+                .skip(1)
+                .filter_map(|part| part.split_once(':'))
+                .map(|(name, _)| name.trim().to_string());
+            for field in fields {
+                let warnings = check(&format!("[{}]\n{} = 0", table, field));
+                assert!(warnings.is_empty(), "{}.{} is missing from the listing", table, field);
+            }
+        }
+    }
+}
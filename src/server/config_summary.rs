@@ -0,0 +1,468 @@
+//! The configuration the server ended up with: every setting, its value, and where
+//! the value came from (the config file, an environment variable, or the default).
+//! Logged at startup and on reload, and printed by `loophole server --print-config`.
+//! Tokens and other secrets only ever appear as a fingerprint.
+//!
+//! Keep [`ENV_VARS`] and [`ALIASES`] in step with `Config::from_env` and the
+//! `alias` attributes in `config.rs`; a test checks every variable is listed.
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+use tracing::{debug, info};
+
+use super::config::{env, Config};
+use super::config_schema;
+use super::names::TokenId;
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    File,
+    Env,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::File => "file",
+            Source::Env => "env",
+            Source::Default => "default",
+        })
+    }
+}
+
+/// What a config was loaded from, for telling where each setting came from
+#[derive(Clone)]
+pub enum Provenance {
+    /// The config file, as written
+    File(toml::Table),
+    /// The environment, with the variables that were set
+    Env(BTreeSet<&'static str>),
+}
+
+impl Default for Provenance {
+    /// Nothing written, so every setting is a default
+    fn default() -> Self {
+        Provenance::File(toml::Table::new())
+    }
+}
+
+// The file holds tokens, so it's never shown
+impl fmt::Debug for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provenance::File(_) => f.write_str("File"),
+            Provenance::Env(names) => f.debug_tuple("Env").field(names).finish(),
+        }
+    }
+}
+
+/// The variables `Config::from_env` reads for each setting, by dotted path, where
+/// `*` stands for any one key (a token). A path covers everything beneath it.
+const ENV_VARS: &[(&str, &[&str])] = &[
+    ("server.domain", &[env::DOMAIN]),
+    ("server.http_port", &[env::HTTP_PORT]),
+    ("server.https_port", &[env::HTTPS_PORT]),
+    ("server.control_port", &[env::CONTROL_PORT]),
+    ("server.bind_address", &[env::BIND_ADDRESS]),
+    ("server.http_bind", &[env::HTTP_BIND]),
+    ("server.https_bind", &[env::HTTPS_BIND]),
+    ("server.strict_subdomain_ownership", &[env::STRICT_OWNERSHIP]),
+    ("server.reject_confusables", &[env::REJECT_CONFUSABLES]),
+    ("server.ownership_expiry_secs", &[env::OWNERSHIP_EXPIRY]),
+    ("server.reconnect_grace_secs", &[env::RECONNECT_GRACE]),
+    ("server.strict_epoch", &[env::STRICT_EPOCH]),
+    ("server.behind_cloudflare", &[env::BEHIND_CLOUDFLARE]),
+    ("server.trusted_proxies", &[env::TRUSTED_PROXIES]),
+    ("server.proxy_protocol", &[env::PROXY_PROTOCOL]),
+    ("server.public_port", &[env::PUBLIC_PORT]),
+    ("server.public_scheme", &[env::PUBLIC_SCHEME]),
+    ("server.state_dir", &[env::STATE_DIR]),
+    ("server.motd", &[env::MOTD]),
+    ("tokens.*.admin", &[env::TOKENS, env::ADMIN_TOKENS]),
+    ("tokens.*.keep_alive", &[env::ALLOW_KEEP_ALIVE]),
+    ("limits.request_timeout_secs", &[env::REQUEST_TIMEOUT]),
+    ("limits.max_request_body_bytes", &[env::MAX_BODY]),
+    ("limits.max_request_line", &[env::MAX_REQUEST_LINE]),
+    ("limits.idle_tunnel_timeout_secs", &[env::IDLE_TIMEOUT]),
+    ("limits.ping_timeout_secs", &[env::PING_TIMEOUT]),
+    ("limits.max_tunnels", &[env::MAX_TUNNELS]),
+    ("limits.max_tunnels_per_token", &[env::MAX_TUNNELS_PER_TOKEN]),
+    ("limits.max_connections_per_ip", &[env::MAX_CONNECTIONS_PER_IP]),
+    ("limits.registrations_per_minute_per_ip", &[env::REGISTRATIONS_PER_MINUTE_PER_IP]),
+    ("limits.max_requests_per_second", &[env::MAX_REQUESTS_PER_SECOND]),
+    ("limits.banned_ips", &[env::BANNED_IPS]),
+    ("limits.fair_queue_threshold", &[env::FAIR_QUEUE_THRESHOLD]),
+    ("https.email", &[env::ACME_EMAIL]),
+    ("https.directory", &[env::ACME_DIRECTORY]),
+    ("https.certs_dir", &[env::CERTS_DIR]),
+    ("https.storage", &[env::STORAGE]),
+    ("https.staging", &[env::ACME_STAGING]),
+    ("https.manual_certs", &[env::MANUAL_CERTS]),
+    ("https.prune_unused_after_days", &[env::PRUNE_UNUSED_AFTER_DAYS]),
+    ("https.challenge", &[env::ACME_CHALLENGE]),
+    ("https.dns.provider", &[env::DNS_PROVIDER]),
+    ("https.dns.api_token", &[env::DNS_API_TOKEN]),
+    ("https.dns.zone_id", &[env::DNS_ZONE_ID]),
+    ("https.dns", &[env::DNS_PROVIDER]),
+    ("https", &[env::ACME_EMAIL, env::MANUAL_CERTS]),
+    ("metrics.enabled", &[env::METRICS]),
+    ("metrics.token", &[env::METRICS_TOKEN]),
+    ("metrics.port", &[env::METRICS_PORT]),
+    ("logging.slow_request_threshold_ms", &[env::SLOW_REQUEST_THRESHOLD]),
+    ("logging.registration_rate_warning", &[env::REGISTRATION_RATE_WARNING]),
+    ("tcp.port_range", &[env::TCP_PORT_RANGE]),
+    ("usage.retention_days", &[env::USAGE_RETENTION_DAYS]),
+    ("registry.reserved", &[env::RESERVED_SUBDOMAINS]),
+    ("registry.aliases", &[env::ALIASES]),
+    ("maintenance.windows", &[env::MAINTENANCE_WINDOWS]),
+    ("maintenance.timezone", &[env::MAINTENANCE_TIMEZONE]),
+    ("monitoring.dns_check_interval_secs", &[env::DNS_CHECK_INTERVAL]),
+    ("monitoring.expected_ips", &[env::EXPECTED_IPS]),
+    ("monitoring.dns_resolver", &[env::DNS_RESOLVER]),
+    ("monitoring.webhook_url", &[env::WEBHOOK_URL]),
+    ("pages.theme_dir", &[env::THEME_DIR]),
+];
+
+/// Other names a config file may give a setting, by dotted path
+const ALIASES: &[(&str, &str)] = &[
+    ("https", "acme"),
+    ("server.ownership_expiry_secs", "server.ownership_expiry"),
+    ("server.reconnect_grace_secs", "server.reconnect_grace"),
+    ("limits.request_timeout_secs", "limits.request_timeout"),
+    ("limits.max_request_body_bytes", "limits.max_request_body"),
+    ("limits.idle_tunnel_timeout_secs", "limits.idle_tunnel_timeout"),
+    ("limits.ping_timeout_secs", "limits.ping_timeout"),
+    ("monitoring.dns_check_interval_secs", "monitoring.dns_check_interval"),
+];
+
+/// Settings whose values are secrets, shown only as a fingerprint. Token names are
+/// secrets too; they're shown as the token's id.
+const SECRETS: &[&str] = &["metrics.token", "https.dns.api_token", "monitoring.webhook_url"];
+
+impl Provenance {
+    /// The environment as it is now, for a config read from it
+    pub fn env() -> Self {
+        let names = ENV_VARS
+            .iter()
+            .flat_map(|(_, names)| names.iter().copied())
+            .filter(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()))
+            .collect();
+        Provenance::Env(names)
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, Provenance::File(_))
+    }
+
+    /// Where the setting at `path` came from
+    fn source(&self, path: &[&str]) -> Source {
+        let given = match self {
+            Provenance::File(document) => {
+                written(document, path)
+                    || ALIASES.iter().any(|(name, alias)| {
+                        let name: Vec<&str> = name.split('.').collect();
+                        path.starts_with(&name) && {
+                            let mut aliased: Vec<&str> = alias.split('.').collect();
+                            aliased.extend_from_slice(&path[name.len()..]);
+                            written(document, &aliased)
+                        }
+                    })
+            }
+            Provenance::Env(set) => ENV_VARS
+                .iter()
+                .find(|(pattern, _)| covers(pattern, path))
+                .is_some_and(|(_, names)| names.iter().any(|name| set.contains(name))),
+        };
+        match (given, self) {
+            (false, _) => Source::Default,
+            (true, Provenance::File(_)) => Source::File,
+            (true, Provenance::Env(_)) => Source::Env,
+        }
+    }
+}
+
+/// Whether the config file sets `path`, or a table or array holding it
+fn written(document: &toml::Table, path: &[&str]) -> bool {
+    let mut table = document;
+    for key in path {
+        match table.get(*key) {
+            Some(toml::Value::Table(inner)) => table = inner,
+            Some(_) => return true,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Whether the dotted `pattern` is `path` or a table holding it
+fn covers(pattern: &str, path: &[&str]) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    pattern.len() <= path.len() && pattern.iter().zip(path).all(|(expected, key)| *expected == "*" || expected == key)
+}
+
+/// A secret as it's shown: the first bytes of its SHA-256
+fn fingerprint(secret: &str) -> String {
+    format!("redacted:{}", TokenId::of(secret))
+}
+
+/// One setting's value and where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    /// Dotted path, e.g. `limits.request_timeout_secs`
+    pub key: String,
+    /// Null when unset
+    pub value: Value,
+    pub source: Source,
+}
+
+impl Setting {
+    fn toml_value(&self) -> Option<toml::Value> {
+        toml::Value::try_from(&self.value).ok()
+    }
+}
+
+/// Every setting of a config, by path
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub settings: Vec<Setting>,
+}
+
+impl Summary {
+    pub fn of(config: &Config) -> Self {
+        let mut value = serde_json::to_value(config).expect("config tables only have string keys");
+        // Tokens leave out the limits they don't set when saved; here they're unset
+        if let Some(Value::Object(tokens)) = value.get_mut("tokens") {
+            for token in tokens.values_mut().filter_map(Value::as_object_mut) {
+                for key in config_schema::token_keys() {
+                    token.entry(key).or_insert(Value::Null);
+                }
+            }
+        }
+        let mut settings = Vec::new();
+        flatten(value, &mut Vec::new(), &config.provenance, &mut settings);
+        Self { settings }
+    }
+
+    /// One line per setting: the ones the file or environment set at info, so they
+    /// stand out in the log, and the defaults at debug
+    pub fn log(&self) {
+        for setting in &self.settings {
+            let value = match setting.toml_value() {
+                Some(value) => value.to_string(),
+                None if setting.value.is_null() => "(unset)".to_string(),
+                None => setting.value.to_string(),
+            };
+            match setting.source {
+                Source::Default => debug!(setting = %setting.key, value = %value, source = %setting.source, "Config"),
+                _ => info!(setting = %setting.key, value = %value, source = %setting.source, "Config"),
+            }
+        }
+    }
+
+    /// `"server.http_port" = { value = 80, source = "default" }`, one line per
+    /// setting; unset ones have no value
+    pub fn to_toml(&self) -> String {
+        let mut output = String::new();
+        for setting in &self.settings {
+            let key = toml::Value::String(setting.key.clone());
+            let _ = match setting.toml_value() {
+                Some(value) => writeln!(output, "{} = {{ value = {}, source = \"{}\" }}", key, value, setting.source),
+                None => writeln!(output, "{} = {{ source = \"{}\" }}", key, setting.source),
+            };
+        }
+        output
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("summaries serialize")
+    }
+}
+
+/// `{ "server.http_port": { "value": 80, "source": "default" }, ... }`
+impl Serialize for Summary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            value: &'a Value,
+            source: Source,
+        }
+
+        let mut map = serializer.serialize_map(Some(self.settings.len()))?;
+        for setting in &self.settings {
+            map.serialize_entry(&setting.key, &Entry { value: &setting.value, source: setting.source })?;
+        }
+        map.end()
+    }
+}
+
+/// Add the settings in `value`, found at `path`, to `settings`. Tables are walked;
+/// anything else, including arrays and empty tables, is one setting.
+fn flatten(value: Value, path: &mut Vec<String>, provenance: &Provenance, settings: &mut Vec<Setting>) {
+    match value {
+        Value::Object(table) if !table.is_empty() => {
+            for (key, value) in table {
+                path.push(key);
+                flatten(value, path, provenance, settings);
+                path.pop();
+            }
+        }
+        value => {
+            let path: Vec<&str> = path.iter().map(String::as_str).collect();
+            let source = provenance.source(&path);
+            let key = match path.as_slice() {
+                ["tokens", token, rest @ ..] => {
+                    let id = TokenId::of(token);
+                    std::iter::once("tokens").chain([&*id]).chain(rest.iter().copied()).collect::<Vec<_>>().join(".")
+                }
+                _ => path.join("."),
+            };
+            let value = match value {
+                Value::String(secret) if !secret.is_empty() && SECRETS.contains(&key.as_str()) => {
+                    Value::String(fingerprint(&secret))
+                }
+                value => value,
+            };
+            settings.push(Setting { key, value, source });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[server]
+domain = "tunnel.example.com"
+http_port = 8080
+
+[tokens.tk_secret_alice]
+admin = true
+
+[limits]
+request_timeout = "45s"
+
+[acme]
+email = "me@example.com"
+
+[metrics]
+enabled = true
+token = "metrics_secret"
+
+[monitoring]
+webhook_url = "https://hooks.example.com/services/hook_secret"
+"#;
+
+    fn setting<'a>(summary: &'a Summary, key: &str) -> &'a Setting {
+        summary
+            .settings
+            .iter()
+            .find(|setting| setting.key == key)
+            .unwrap_or_else(|| panic!("no setting {}", key))
+    }
+
+    #[test]
+    fn test_file_settings() {
+        let summary = Summary::of(&Config::parse(CONFIG).unwrap());
+        let domain = setting(&summary, "server.domain");
+        assert_eq!((&domain.value, domain.source), (&Value::from("tunnel.example.com"), Source::File));
+        assert_eq!(setting(&summary, "server.http_port").source, Source::File);
+        let https_port = setting(&summary, "server.https_port");
+        assert_eq!((&https_port.value, https_port.source), (&Value::from(443), Source::Default));
+        let unset = setting(&summary, "server.control_port");
+        assert_eq!((&unset.value, unset.source), (&Value::Null, Source::Default));
+
+        // Written under another name
+        let timeout = setting(&summary, "limits.request_timeout_secs");
+        assert_eq!((&timeout.value, timeout.source), (&Value::from(45), Source::File));
+        assert_eq!(setting(&summary, "https.email").source, Source::File);
+        assert_eq!(setting(&summary, "https.staging").source, Source::Default);
+        assert_eq!(setting(&summary, "limits.max_tunnels").source, Source::Default);
+    }
+
+    #[test]
+    fn test_env_settings() {
+        let mut set = BTreeSet::new();
+        set.extend([env::DOMAIN, env::TOKENS, env::MAX_TUNNELS]);
+        let mut config = Config::parse(CONFIG).unwrap();
+        config.provenance = Provenance::Env(set);
+        let summary = Summary::of(&config);
+        assert_eq!(setting(&summary, "server.domain").source, Source::Env);
+        assert_eq!(setting(&summary, "limits.max_tunnels").source, Source::Env);
+        assert_eq!(setting(&summary, "server.http_port").source, Source::Default);
+        assert_eq!(setting(&summary, "https.email").source, Source::Default);
+        let id = TokenId::of("tk_secret_alice");
+        assert_eq!(setting(&summary, &format!("tokens.{}.admin", id)).source, Source::Env);
+        assert_eq!(setting(&summary, &format!("tokens.{}.keep_alive", id)).source, Source::Default);
+    }
+
+    #[test]
+    fn test_unset_token_limits_are_shown() {
+        let summary = Summary::of(&Config::parse(CONFIG).unwrap());
+        let id = TokenId::of("tk_secret_alice");
+        for key in config_schema::token_keys().filter(|key| *key != "admin") {
+            let setting = setting(&summary, &format!("tokens.{}.{}", id, key));
+            assert_eq!(setting.source, Source::Default, "{}", key);
+        }
+        assert_eq!(setting(&summary, &format!("tokens.{}.max_tunnels", id)).value, Value::Null);
+        assert_eq!(setting(&summary, &format!("tokens.{}.strip_request_headers", id)).value, Value::Null);
+    }
+
+    #[test]
+    fn test_every_env_var_is_listed() {
+        // Read from the env module's source, so a new variable can't be left out
+        let source = include_str!("config.rs");
+        let module = &source[source.find("pub mod env {").unwrap()..];
+        let module = &module[..module.find("\n}\n").unwrap()];
+        let names: Vec<&str> = module
+            .lines()
+            .filter(|line| line.trim_start().starts_with("pub const"))
+            .filter_map(|line| line.split('"').nth(1))
+            .collect();
+        assert!(names.len() > 50, "{:?}", names);
+        // Read by S3 storage itself rather than being settings in the config
+        let storage_only = ["LOOPHOLE_S3_BUCKET", "LOOPHOLE_S3_PREFIX", "LOOPHOLE_S3_ENDPOINT"];
+        for name in names.into_iter().filter(|name| !storage_only.contains(name)) {
+            assert!(
+                ENV_VARS.iter().any(|(_, names)| names.contains(&name)),
+                "{} is missing from ENV_VARS",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_fingerprinted() {
+        let summary = Summary::of(&Config::parse(CONFIG).unwrap());
+        for output in [summary.to_toml(), summary.to_json(), format!("{:?}", summary)] {
+            for secret in ["tk_secret_alice", "metrics_secret", "hook_secret"] {
+                assert!(!output.contains(secret), "{} in {}", secret, output);
+            }
+        }
+        let id = TokenId::of("tk_secret_alice");
+        assert_eq!(setting(&summary, &format!("tokens.{}.admin", id)).value, Value::from(true));
+        assert_eq!(setting(&summary, "metrics.token").value, Value::from(fingerprint("metrics_secret")));
+        // Nothing to hide in an empty value
+        let mut config = Config::parse(CONFIG).unwrap();
+        config.metrics.token = Some(String::new());
+        assert_eq!(setting(&Summary::of(&config), "metrics.token").value, Value::from(""));
+    }
+
+    #[test]
+    fn test_outputs_parse() {
+        let summary = Summary::of(&Config::parse(CONFIG).unwrap());
+        let toml: toml::Table = summary.to_toml().parse().unwrap();
+        assert_eq!(toml["server.http_port"]["value"].as_integer(), Some(8080));
+        assert_eq!(toml["server.http_port"]["source"].as_str(), Some("file"));
+        assert!(toml["server.control_port"].get("value").is_none());
+        let json: Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["limits.request_timeout_secs"], serde_json::json!({ "value": 45, "source": "file" }));
+        assert_eq!(json.as_object().unwrap().len(), summary.settings.len());
+    }
+}
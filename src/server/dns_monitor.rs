@@ -0,0 +1,492 @@
+//! Checking that the base domain and its wildcard still resolve to this server
+//! (`[monitoring] dns_check_interval`). Hosting providers occasionally reset DNS, and
+//! otherwise the first sign is certificates failing to renew hours later.
+//!
+//! Each check asks a public DNS-over-HTTPS resolver for the base domain and a random
+//! name under it, and expects at least one of the `expected_ips` (or the server's
+//! public address) among the answers for each. Two mismatches in a row mark DNS as
+//! broken: the server logs an error, sends a `dns_mismatch` webhook event and reports
+//! itself not ready until a check passes again.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
+
+use super::ownership::now_secs;
+use super::webhook::Webhook;
+
+pub const MISMATCH_EVENT: &str = "dns_mismatch";
+
+/// Mismatches in a row before DNS counts as broken, so a check that catches a
+/// record mid-change doesn't raise an alarm
+const FAILURES_TO_ALERT: u32 = 2;
+
+/// Where the server's public address is found, when `expected_ips` is empty
+const PUBLIC_IP_URL: &str = "https://1.1.1.1/cdn-cgi/trace";
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolving names and finding the server's own address
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    /// The addresses `name` resolves to; empty when it doesn't exist
+    async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>>;
+    /// The address this server reaches the internet from
+    async fn public_ip(&self) -> Result<IpAddr>;
+}
+
+/// Lookups through a DNS-over-HTTPS resolver's JSON API
+pub struct DohLookup {
+    client: reqwest::Client,
+    resolver: String,
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const NXDOMAIN: u32 = 3;
+
+impl DohLookup {
+    pub fn new(resolver: String) -> Self {
+        Self {
+            client: reqwest::Client::builder().timeout(LOOKUP_TIMEOUT).build().unwrap_or_default(),
+            resolver,
+        }
+    }
+
+    /// The data of `name`'s records of `record_type`; empty when it doesn't exist
+    async fn query(&self, name: &str, record_type: u16, type_name: &str) -> Result<Vec<String>> {
+        let response: DohResponse = self
+            .client
+            .get(&self.resolver)
+            .query(&[("name", name), ("type", type_name)])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to ask {} for {}", self.resolver, name))?
+            .json()
+            .await
+            .with_context(|| format!("Unreadable answer from {} for {}", self.resolver, name))?;
+        match response.status {
+            0 => {}
+            NXDOMAIN => return Ok(Vec::new()),
+            status => anyhow::bail!("{} answered {} for {} with DNS status {}", self.resolver, type_name, name, status),
+        }
+        // CNAMEs come first in the answer; only the records they lead to count
+        Ok(response
+            .answer
+            .into_iter()
+            .filter(|answer| answer.record_type == record_type)
+            .map(|answer| answer.data)
+            .collect())
+    }
+
+    /// The values of `name`'s TXT records, such as ACME DNS-01 challenges
+    pub async fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let records = self.query(name, TYPE_TXT, "TXT").await?;
+        // Resolvers give each value as a quoted character-string
+        Ok(records.iter().map(|data| data.trim_matches('"').to_string()).collect())
+    }
+}
+
+#[async_trait]
+impl Lookup for DohLookup {
+    async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let mut addresses = Vec::new();
+        for (record_type, type_name) in [(TYPE_A, "A"), (TYPE_AAAA, "AAAA")] {
+            let records = self.query(name, record_type, type_name).await?;
+            addresses.extend(records.iter().filter_map(|data| data.parse::<IpAddr>().ok()));
+        }
+        Ok(addresses)
+    }
+
+    async fn public_ip(&self) -> Result<IpAddr> {
+        let trace = self
+            .client
+            .get(PUBLIC_IP_URL)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to find this server's public address")?
+            .text()
+            .await?;
+        trace
+            .lines()
+            .find_map(|line| line.strip_prefix("ip="))
+            .and_then(|ip| ip.trim().parse().ok())
+            .context("No address in the answer finding this server's public address")
+    }
+}
+
+/// A name that didn't resolve to any expected address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    pub name: String,
+    pub expected: Vec<IpAddr>,
+    /// What it resolved to; empty when it doesn't exist
+    pub found: Vec<IpAddr>,
+}
+
+/// What a check changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    /// DNS is now broken, after enough mismatches in a row
+    Broken(Vec<Mismatch>),
+    /// DNS is right again
+    Recovered,
+}
+
+#[derive(Debug, Default)]
+struct Checks {
+    failures_in_a_row: u32,
+    broken: bool,
+    checked_at: Option<u64>,
+    mismatches: Vec<Mismatch>,
+}
+
+/// What the checks found, for readiness and `/_admin/stats`
+#[derive(Debug, Default)]
+pub struct DnsStatus {
+    /// Whether the checks run at all
+    monitored: bool,
+    checks: Mutex<Checks>,
+}
+
+/// `/_admin/stats`' view of DNS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnsReport {
+    pub ok: bool,
+    /// Unix seconds of the last check that got answers; None before the first
+    pub checked_at: Option<u64>,
+    /// Names that resolved wrongly at the last check
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl DnsStatus {
+    pub fn monitored() -> Self {
+        Self {
+            monitored: true,
+            ..Self::default()
+        }
+    }
+
+    /// Whether DNS has been found not to point at the server
+    pub fn is_broken(&self) -> bool {
+        self.checks.lock().unwrap().broken
+    }
+
+    /// None when DNS isn't monitored
+    pub fn report(&self) -> Option<DnsReport> {
+        if !self.monitored {
+            return None;
+        }
+        let checks = self.checks.lock().unwrap();
+        Some(DnsReport {
+            ok: !checks.broken,
+            checked_at: checks.checked_at,
+            mismatches: checks.mismatches.clone(),
+        })
+    }
+
+    /// Record a check's mismatches (none when it passed)
+    pub fn record(&self, mismatches: Vec<Mismatch>, now: u64) -> Option<Transition> {
+        let mut checks = self.checks.lock().unwrap();
+        checks.checked_at = Some(now);
+        checks.mismatches = mismatches.clone();
+        if mismatches.is_empty() {
+            checks.failures_in_a_row = 0;
+            return std::mem::take(&mut checks.broken).then_some(Transition::Recovered);
+        }
+        checks.failures_in_a_row += 1;
+        if checks.broken || checks.failures_in_a_row < FAILURES_TO_ALERT {
+            return None;
+        }
+        checks.broken = true;
+        Some(Transition::Broken(mismatches))
+    }
+}
+
+/// The base domain and a name under it no tunnel has, which only the wildcard covers
+fn names_to_check(domain: &str) -> [String; 2] {
+    let probe: u32 = rand::random();
+    [domain.to_string(), format!("loophole-probe-{:08x}.{}", probe, domain)]
+}
+
+/// The checks on one server's DNS
+pub struct Monitor {
+    pub status: Arc<DnsStatus>,
+    pub lookup: Arc<dyn Lookup>,
+    pub domain: String,
+    /// Found at the first check when not configured
+    pub expected: Vec<IpAddr>,
+}
+
+impl Monitor {
+    /// Check once, returning what changed. Failing to ask the resolver says nothing
+    /// about the records, so it's logged and doesn't count.
+    pub async fn check(&mut self) -> Option<Transition> {
+        if self.expected.is_empty() {
+            match self.lookup.public_ip().await {
+                Ok(ip) => {
+                    info!("Expecting {} to resolve to {}, this server's public address", self.domain, ip);
+                    self.expected.push(ip);
+                }
+                Err(e) => {
+                    warn!("Skipping the DNS check: {:#}", e);
+                    return None;
+                }
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        for name in names_to_check(&self.domain) {
+            let found = match self.lookup.resolve(&name).await {
+                Ok(found) => found,
+                Err(e) => {
+                    warn!("Skipping the DNS check: {:#}", e);
+                    return None;
+                }
+            };
+            if !found.iter().any(|ip| self.expected.contains(ip)) {
+                warn!(
+                    "{} resolves to {}, not {}",
+                    name,
+                    format_ips(&found),
+                    format_ips(&self.expected)
+                );
+                mismatches.push(Mismatch {
+                    name,
+                    expected: self.expected.clone(),
+                    found,
+                });
+            }
+        }
+        self.status.record(mismatches, now_secs())
+    }
+}
+
+fn format_ips(ips: &[IpAddr]) -> String {
+    if ips.is_empty() {
+        return "nothing".to_string();
+    }
+    ips.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Check DNS every `interval` until the server shuts down
+pub async fn monitor_task(
+    mut monitor: Monitor,
+    interval: Duration,
+    webhook: Option<Webhook>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => match monitor.check().await {
+                Some(Transition::Broken(mismatches)) => {
+                    error!(
+                        "DNS for {} no longer points at this server; certificates will fail to renew until it's fixed",
+                        monitor.domain
+                    );
+                    if let Some(ref webhook) = webhook {
+                        #[derive(Serialize)]
+                        struct Details {
+                            mismatches: Vec<Mismatch>,
+                        }
+                        webhook.send(MISMATCH_EVENT, Details { mismatches }).await;
+                    }
+                }
+                Some(Transition::Recovered) => info!("DNS for {} points at this server again", monitor.domain),
+                None => debug!("DNS check for {} done", monitor.domain),
+            },
+            _ = shutdown_rx.recv() => {
+                debug!("DNS monitor shutting down");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HERE: &str = "203.0.113.10";
+    const ELSEWHERE: &str = "198.51.100.7";
+
+    /// Answers from a table set by the test: the base domain, and anything under it
+    /// by way of the wildcard
+    #[derive(Default)]
+    struct FakeLookup {
+        base: Mutex<Vec<IpAddr>>,
+        wildcard: Mutex<Vec<IpAddr>>,
+        failing: Mutex<bool>,
+    }
+
+    impl FakeLookup {
+        fn point(&self, base: &str, wildcard: &str) {
+            let parse = |ips: &str| ips.split(',').filter(|ip| !ip.is_empty()).map(|ip| ip.parse().unwrap()).collect();
+            *self.base.lock().unwrap() = parse(base);
+            *self.wildcard.lock().unwrap() = parse(wildcard);
+        }
+    }
+
+    #[async_trait]
+    impl Lookup for FakeLookup {
+        async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
+            if *self.failing.lock().unwrap() {
+                anyhow::bail!("resolver unreachable");
+            }
+            if name == "tunnel.example.com" {
+                return Ok(self.base.lock().unwrap().clone());
+            }
+            assert!(name.ends_with(".tunnel.example.com"), "{}", name);
+            Ok(self.wildcard.lock().unwrap().clone())
+        }
+
+        async fn public_ip(&self) -> Result<IpAddr> {
+            Ok(HERE.parse().unwrap())
+        }
+    }
+
+    fn monitor(lookup: Arc<FakeLookup>) -> Monitor {
+        Monitor {
+            status: Arc::new(DnsStatus::monitored()),
+            lookup,
+            domain: "tunnel.example.com".to_string(),
+            expected: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_dns_is_ok() {
+        let lookup = Arc::new(FakeLookup::default());
+        lookup.point(&format!("{},2001:db8::1", HERE), HERE);
+        let mut monitor = monitor(lookup);
+
+        assert_eq!(monitor.check().await, None);
+        assert_eq!(monitor.expected, [HERE.parse::<IpAddr>().unwrap()]);
+        let report = monitor.status.report().unwrap();
+        assert!(report.ok && report.checked_at.is_some() && report.mismatches.is_empty(), "{:?}", report);
+        assert!(!monitor.status.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_mismatch_needs_two_checks_in_a_row() {
+        let lookup = Arc::new(FakeLookup::default());
+        lookup.point(HERE, ELSEWHERE);
+        let mut monitor = monitor(lookup.clone());
+
+        // One mismatch is reported but isn't an alarm yet
+        assert_eq!(monitor.check().await, None);
+        assert!(!monitor.status.is_broken());
+        assert_eq!(monitor.status.report().unwrap().mismatches.len(), 1);
+
+        // A passing check in between starts the count again
+        lookup.point(HERE, HERE);
+        assert_eq!(monitor.check().await, None);
+        lookup.point(HERE, ELSEWHERE);
+        assert_eq!(monitor.check().await, None);
+
+        // The wildcard is gone altogether
+        lookup.point(HERE, "");
+        match monitor.check().await {
+            Some(Transition::Broken(mismatches)) => {
+                assert_eq!(mismatches.len(), 1);
+                assert!(mismatches[0].name.starts_with("loophole-probe-"), "{:?}", mismatches);
+                assert!(mismatches[0].found.is_empty());
+            }
+            other => panic!("expected Broken, got {:?}", other),
+        }
+        assert!(monitor.status.is_broken());
+        assert!(!monitor.status.report().unwrap().ok);
+
+        // Only announced once
+        assert_eq!(monitor.check().await, None);
+        assert!(monitor.status.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_recovery() {
+        let lookup = Arc::new(FakeLookup::default());
+        lookup.point(ELSEWHERE, ELSEWHERE);
+        let mut monitor = monitor(lookup.clone());
+        monitor.check().await;
+        assert!(matches!(monitor.check().await, Some(Transition::Broken(ref m)) if m.len() == 2));
+
+        // A resolver that can't be reached changes nothing
+        *lookup.failing.lock().unwrap() = true;
+        lookup.point(HERE, HERE);
+        assert_eq!(monitor.check().await, None);
+        assert!(monitor.status.is_broken());
+
+        *lookup.failing.lock().unwrap() = false;
+        assert_eq!(monitor.check().await, Some(Transition::Recovered));
+        assert!(!monitor.status.is_broken());
+        assert!(monitor.status.report().unwrap().ok);
+        assert_eq!(monitor.check().await, None);
+    }
+
+    #[test]
+    fn test_unmonitored_status() {
+        let status = DnsStatus::default();
+        assert_eq!(status.report(), None);
+        assert!(!status.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_doh_answers() {
+        use axum::extract::Query;
+        use serde_json::json;
+        use std::collections::HashMap;
+
+        // Answers the way cloudflare-dns.com's JSON API does
+        let app = axum::Router::new().route(
+            "/dns-query",
+            axum::routing::get(|Query(query): Query<HashMap<String, String>>| async move {
+                let answer = match (query["name"].as_str(), query["type"].as_str()) {
+                    ("app.tunnel.example.com", "A") => json!([
+                        { "type": 5, "data": "tunnel.example.com." },
+                        { "type": 1, "data": HERE },
+                    ]),
+                    ("_acme-challenge.tunnel.example.com", "TXT") => json!([
+                        { "type": 16, "data": "\"first\"" },
+                        { "type": 16, "data": "\"second\"" },
+                    ]),
+                    ("app.tunnel.example.com" | "_acme-challenge.tunnel.example.com", _) => json!([]),
+                    _ => return axum::Json(json!({ "Status": NXDOMAIN })),
+                };
+                axum::Json(json!({ "Status": 0, "Answer": answer }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let lookup = DohLookup::new(resolver);
+        assert_eq!(lookup.resolve("app.tunnel.example.com").await.unwrap(), vec![HERE.parse::<IpAddr>().unwrap()]);
+        assert_eq!(lookup.txt("_acme-challenge.tunnel.example.com").await.unwrap(), ["first", "second"]);
+        assert!(lookup.txt("_acme-challenge.example.org").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Publishing the TXT records of ACME DNS-01 challenges through the DNS provider
+//! hosting the domain's zone (`[https.dns]`). Let's Encrypt only issues wildcard
+//! certificates over DNS-01, so with a provider configured the server obtains one
+//! `*.{domain}` certificate instead of one per subdomain.
+//!
+//! Providers sit behind [`DnsProvider`]. Cloudflare is the only one so far; others
+//! need a [`Provider`] variant and an implementation.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::config::DnsConfig;
+
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+/// TTL of challenge records, which only live for as long as a validation
+const TXT_TTL_SECS: u32 = 60;
+
+/// Which provider hosts the domain's DNS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Cloudflare,
+}
+
+impl Provider {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "cloudflare" => Ok(Provider::Cloudflare),
+            _ => Err(format!("expected cloudflare, got '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::Cloudflare => f.write_str("Cloudflare"),
+        }
+    }
+}
+
+/// A TXT record a provider published, for removing it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxtRecord {
+    pub name: String,
+    /// The provider's id for the record
+    pub id: String,
+}
+
+#[async_trait]
+pub trait DnsProvider: Send + Sync + fmt::Debug {
+    /// Publish a TXT record `name` with `value`, next to any others of that name
+    async fn create_txt(&self, name: &str, value: &str) -> Result<TxtRecord>;
+
+    /// Remove a record [`create_txt`](Self::create_txt) published
+    async fn delete_txt(&self, record: &TxtRecord) -> Result<()>;
+}
+
+/// Open the provider `config` asks for
+pub fn open(config: &DnsConfig) -> Result<Arc<dyn DnsProvider>> {
+    match config.provider {
+        Provider::Cloudflare => Ok(Arc::new(Cloudflare::new(config.api_token.clone(), config.zone_id.clone())?)),
+    }
+}
+
+/// Records through Cloudflare's API, with a token allowed to edit the zone's DNS
+pub struct Cloudflare {
+    client: reqwest::Client,
+    api: String,
+    api_token: String,
+    /// The zone's id; looked up from each record's name when not configured
+    zone_id: Option<String>,
+}
+
+impl fmt::Debug for Cloudflare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cloudflare")
+            .field("api", &self.api)
+            .field("zone_id", &self.zone_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The envelope every Cloudflare API answer comes in
+#[derive(Deserialize)]
+struct CloudflareResponse<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<CloudflareError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct CloudflareError {
+    code: u32,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Id {
+    id: String,
+}
+
+impl Cloudflare {
+    pub fn new(api_token: String, zone_id: Option<String>) -> Result<Self> {
+        Self::with_api(CLOUDFLARE_API.to_string(), api_token, zone_id)
+    }
+
+    fn with_api(api: String, api_token: String, zone_id: Option<String>) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(API_TIMEOUT).build()?,
+            api,
+            api_token,
+            zone_id,
+        })
+    }
+
+    /// Send `request`, returning the result of a successful answer
+    async fn call<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder, action: &str) -> Result<T> {
+        let response = request
+            .bearer_auth(&self.api_token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to ask Cloudflare to {}", action))?;
+        let status = response.status();
+        let response: CloudflareResponse<T> = response
+            .json()
+            .await
+            .with_context(|| format!("Unreadable answer from Cloudflare to {} (HTTP {})", action, status))?;
+        if !response.success {
+            let errors: Vec<String> = response
+                .errors
+                .iter()
+                .map(|error| format!("{} (code {})", error.message, error.code))
+                .collect();
+            anyhow::bail!("Cloudflare refused to {}: {}", action, errors.join("; "));
+        }
+        response
+            .result
+            .with_context(|| format!("Cloudflare sent no result to {}", action))
+    }
+
+    /// The id of the zone `name` belongs to: the configured one, or else the
+    /// closest of its parent domains the token can see a zone for
+    async fn zone_for(&self, name: &str) -> Result<String> {
+        if let Some(ref zone_id) = self.zone_id {
+            return Ok(zone_id.clone());
+        }
+        let action = format!("find the zone of {}", name);
+        let mut candidate = name;
+        // A top-level domain is never anyone's zone
+        while let Some((_, parent)) = candidate.split_once('.').filter(|(_, parent)| parent.contains('.')) {
+            let request = self.client.get(format!("{}/zones", self.api)).query(&[("name", parent)]);
+            let zones: Vec<Id> = self.call(request, &action).await?;
+            if let Some(zone) = zones.into_iter().next() {
+                return Ok(zone.id);
+            }
+            candidate = parent;
+        }
+        anyhow::bail!("The Cloudflare API token can't see a zone for {}; check it has Zone.DNS:Edit on it", name)
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Cloudflare {
+    async fn create_txt(&self, name: &str, value: &str) -> Result<TxtRecord> {
+        let zone_id = self.zone_for(name).await?;
+        let request = self
+            .client
+            .post(format!("{}/zones/{}/dns_records", self.api, zone_id))
+            .json(&serde_json::json!({ "type": "TXT", "name": name, "content": value, "ttl": TXT_TTL_SECS }));
+        let record: Id = self.call(request, &format!("create a TXT record for {}", name)).await?;
+        Ok(TxtRecord { name: name.to_string(), id: record.id })
+    }
+
+    async fn delete_txt(&self, record: &TxtRecord) -> Result<()> {
+        let zone_id = self.zone_for(&record.name).await?;
+        let request = self
+            .client
+            .delete(format!("{}/zones/{}/dns_records/{}", self.api, zone_id, record.id));
+        let _: Id = self.call(request, &format!("delete the TXT record for {}", record.name)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path, Query, State};
+    use axum::http::HeaderMap;
+    use axum::routing::{delete, get, post};
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// TXT records by id, as a fake Cloudflare holding the zone `example.com` keeps them
+    type Records = Arc<Mutex<HashMap<String, (String, String)>>>;
+
+    fn envelope(result: Value) -> Json<Value> {
+        Json(json!({ "success": true, "errors": [], "result": result }))
+    }
+
+    fn refused(headers: &HeaderMap) -> Option<Json<Value>> {
+        let authorized = headers.get("authorization").is_some_and(|value| value == "Bearer cf_token");
+        (!authorized).then(|| Json(json!({ "success": false, "errors": [{ "code": 10000, "message": "Authentication error" }] })))
+    }
+
+    async fn fake_cloudflare() -> (String, Records) {
+        let records = Records::default();
+        let app = Router::new()
+            .route(
+                "/zones",
+                get(|headers: HeaderMap, Query(query): Query<HashMap<String, String>>| async move {
+                    if let Some(refused) = refused(&headers) {
+                        return refused;
+                    }
+                    let zones = if query["name"] == "example.com" { json!([{ "id": "zone1" }]) } else { json!([]) };
+                    envelope(zones)
+                }),
+            )
+            .route(
+                "/zones/zone1/dns_records",
+                post(|State(records): State<Records>, headers: HeaderMap, Json(body): Json<Value>| async move {
+                    if let Some(refused) = refused(&headers) {
+                        return refused;
+                    }
+                    assert_eq!(body["type"], "TXT");
+                    let mut records = records.lock().unwrap();
+                    let id = format!("record{}", records.len() + 1);
+                    let record = (body["name"].as_str().unwrap().to_string(), body["content"].as_str().unwrap().to_string());
+                    records.insert(id.clone(), record);
+                    envelope(json!({ "id": id }))
+                }),
+            )
+            .route(
+                "/zones/zone1/dns_records/:id",
+                delete(|State(records): State<Records>, Path(id): Path<String>| async move {
+                    records.lock().unwrap().remove(&id);
+                    envelope(json!({ "id": id }))
+                }),
+            )
+            .with_state(records.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (api, records)
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_records() {
+        let (api, records) = fake_cloudflare().await;
+        let cloudflare = Cloudflare::with_api(api.clone(), "cf_token".to_string(), None).unwrap();
+
+        // The zone is found from the record's parent domains
+        let name = "_acme-challenge.tunnel.example.com";
+        let record = cloudflare.create_txt(name, "key-auth-digest").await.unwrap();
+        assert_eq!(record.name, name);
+        let created = records.lock().unwrap().get(&record.id).cloned();
+        assert_eq!(created, Some((name.to_string(), "key-auth-digest".to_string())));
+
+        cloudflare.delete_txt(&record).await.unwrap();
+        assert!(records.lock().unwrap().is_empty());
+
+        let err = cloudflare.create_txt("_acme-challenge.tunnel.example.org", "digest").await.unwrap_err();
+        assert!(err.to_string().contains("can't see a zone for _acme-challenge.tunnel.example.org"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_errors_are_reported() {
+        let (api, records) = fake_cloudflare().await;
+        let cloudflare = Cloudflare::with_api(api, "wrong".to_string(), Some("zone1".to_string())).unwrap();
+        let err = cloudflare.create_txt("_acme-challenge.tunnel.example.com", "digest").await.unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("Cloudflare refused to create a TXT record") && err.contains("Authentication error (code 10000)"), "{}", err);
+        assert!(records.lock().unwrap().is_empty());
+        // The token isn't shown
+        assert!(!format!("{:?}", cloudflare).contains("wrong"));
+    }
+
+    #[test]
+    fn test_provider_names() {
+        assert_eq!(Provider::parse("Cloudflare"), Ok(Provider::Cloudflare));
+        assert!(Provider::parse("route53").unwrap_err().contains("route53"));
+    }
+}
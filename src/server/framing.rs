@@ -0,0 +1,119 @@
+//! Where a response body from the client ends, and the body split off the bytes read
+//! from the tunnel as they arrive.
+
+use bytes::{Bytes, BytesMut};
+
+use crate::chunked::ChunkedDecoder;
+
+/// Where a response body from the client ends
+#[derive(Debug)]
+pub enum BodyFraming {
+    /// Content-Length bytes (none for HEAD, 1xx, 204 and 304)
+    Length { remaining: u64 },
+    /// Transfer-Encoding: chunked, decoded so the visitor gets the plain body
+    Chunked(ChunkedDecoder),
+    /// Neither: an HTTP/1.0-style body that runs until the client closes the stream
+    UntilClose,
+}
+
+impl BodyFraming {
+    /// How the body of a response with these headers is framed. Chunked wins over
+    /// Content-Length when a response has both, as HTTP/1.1 says.
+    pub fn for_response(status: u16, is_head: bool, content_length: Option<u64>, is_chunked: bool) -> Self {
+        let no_body = is_head || status == 204 || status == 304 || (100..200).contains(&status);
+        if no_body {
+            BodyFraming::Length { remaining: 0 }
+        } else if is_chunked {
+            BodyFraming::Chunked(ChunkedDecoder::default())
+        } else if let Some(len) = content_length {
+            BodyFraming::Length { remaining: len }
+        } else {
+            BodyFraming::UntilClose
+        }
+    }
+
+    /// The next piece of body at the front of `data`, split off it without copying.
+    /// Framing bytes are consumed, and anything past the end of the body dropped.
+    /// None once `data` holds no more of the body.
+    pub fn next_chunk(&mut self, data: &mut BytesMut) -> std::io::Result<Option<Bytes>> {
+        match self {
+            BodyFraming::Length { remaining } => {
+                let n = (*remaining).min(data.len() as u64) as usize;
+                *remaining -= n as u64;
+                let chunk = data.split_to(n).freeze();
+                if *remaining == 0 {
+                    data.clear();
+                }
+                Ok((!chunk.is_empty()).then_some(chunk))
+            }
+            BodyFraming::Chunked(decoder) => decoder.next_chunk(data),
+            BodyFraming::UntilClose => Ok((!data.is_empty()).then(|| data.split().freeze())),
+        }
+    }
+
+    /// Whether the body has ended; one running until close never has, until the stream ends
+    pub fn is_complete(&self) -> bool {
+        match self {
+            BodyFraming::Length { remaining } => *remaining == 0,
+            BodyFraming::Chunked(decoder) => decoder.is_done(),
+            BodyFraming::UntilClose => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything `framing` makes of `reply`, fed in pieces of `size` bytes as reads
+    /// from the stream may split it
+    fn decode(framing: &mut BodyFraming, reply: &[u8], size: usize) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut received = BytesMut::new();
+        for piece in reply.chunks(size) {
+            received.extend_from_slice(piece);
+            while let Some(chunk) = framing.next_chunk(&mut received)? {
+                out.extend_from_slice(&chunk);
+            }
+            assert!(received.is_empty(), "left {:?}", received);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_body_framing() {
+        let body = |mut framing: BodyFraming, reply: &[u8]| {
+            let out = decode(&mut framing, reply, 4).unwrap();
+            (String::from_utf8(out).unwrap(), framing.is_complete())
+        };
+
+        let length = BodyFraming::for_response(200, false, Some(5), false);
+        assert_eq!(body(length, b"hello, and whatever follows"), ("hello".to_string(), true));
+
+        let chunked = BodyFraming::for_response(200, false, Some(99), true);
+        assert!(matches!(chunked, BodyFraming::Chunked(_)));
+        assert_eq!(body(chunked, b"5\r\nhello\r\n0\r\n\r\n"), ("hello".to_string(), true));
+        let mut chunked = BodyFraming::for_response(200, false, None, true);
+        assert!(decode(&mut chunked, b"zz\r\n", 64).is_err());
+
+        // Only the stream closing ends it
+        let until_close = BodyFraming::for_response(200, false, None, false);
+        assert!(matches!(until_close, BodyFraming::UntilClose));
+        assert_eq!(body(until_close, b"hello from HTTP/1.0"), ("hello from HTTP/1.0".to_string(), false));
+
+        for (status, is_head) in [(204, false), (304, false), (101, false), (200, true)] {
+            let framing = BodyFraming::for_response(status, is_head, None, false);
+            assert!(framing.is_complete(), "{} {}", status, is_head);
+        }
+    }
+
+    #[test]
+    fn test_body_split_without_copying() {
+        let mut framing = BodyFraming::for_response(200, false, Some(11), false);
+        let mut received = BytesMut::from(&b"hello world"[..]);
+        let start = received.as_ptr();
+        let chunk = framing.next_chunk(&mut received).unwrap().unwrap();
+        assert_eq!(chunk.as_ptr(), start);
+        assert_eq!(chunk, "hello world");
+    }
+}
@@ -1,138 +1,488 @@
 use anyhow::Result;
 use axum::extract::ws::{Message, WebSocket};
 use futures::StreamExt;
-use crate::proto::{ClientMessage, ErrorCode, ServerMessage};
+use ipnet::IpNet;
+use crate::build_info::BuildInfo;
+use crate::idn;
+use crate::proto::compat::ServerCompat;
+use crate::proto::{ClientMessage, ErrorCode, Protocol, ServerMessage, TunnelLimits, TunnelMode};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 use yamux::{Connection, Mode};
 
-use super::compat::Compat;
-use super::registry::Registry;
+use super::basic_auth::BasicAuth;
+use super::config::{parse_ip_net, TokenConfig};
+use super::metrics::Metrics;
+use super::names::{FullDomain, Subdomain, TokenSecret};
+use super::ownership::now_secs;
+use super::registry::RegistryError;
 use super::router::ServerState;
-use super::tunnel::{ProxyError, ProxyRequest, Tunnel};
+use super::rtt;
+use super::tcp::{self, PortError, TcpPorts};
+use super::tunnel::{ClientInfo, ProxyError, ProxyRequest, Tunnel};
+use crate::schedule::Window;
 
+/// How long tunnels keep serving in-flight requests after being told the server is
+/// shutting down
+pub const SHUTDOWN_DRAIN: Duration = Duration::from_secs(5);
+
+const SHUTDOWN_MESSAGE: &str = "Server is shutting down";
+
+const REPLACED_MESSAGE: &str = "Replaced by a newer connection with the same token";
+
+/// How long a reconnecting TCP tunnel waits for its stale tunnel to give up its port
+const PORT_HANDOVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often each tunnel checks that its client is still pinging and whether it's idle
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Random names tried for a client that didn't ask for one, before giving up
+const NAME_ATTEMPTS: usize = 10;
+
+/// Most aliases one tunnel may register
+const MAX_ALIASES: usize = 10;
+
+/// Fraction of the idle timeout after which the client is warned
+const IDLE_WARNING_AT: f64 = 0.8;
+
+/// Check often enough to act well within the shortest configured timeout
+fn check_interval(ping_timeout: Duration, idle_timeout: Duration) -> Duration {
+    let mut interval = CHECK_INTERVAL.min(idle_timeout / 5);
+    if !ping_timeout.is_zero() {
+        interval = interval.min(ping_timeout);
+    }
+    interval
+}
+
+/// Serve a control connection. `client_addr` is the client's own address, as the
+/// admission checks saw it, so behind Cloudflare or a trusted proxy it's not the peer's.
 pub async fn handle_websocket(
     mut socket: WebSocket,
     state: Arc<ServerState>,
-    addr: SocketAddr,
+    client_addr: SocketAddr,
 ) -> Result<()> {
     // Wait for Register message
-    let (token, subdomain) = match wait_for_registration(&mut socket).await? {
-        Some((t, s)) => (t, s),
+    let Registration {
+        token,
+        subdomain: requested,
+        protocol,
+        remote_port,
+        client_info,
+        share_key,
+        pause_schedule,
+        basic_auth,
+        allow_ips,
+        mode,
+        aliases,
+    } = match wait_for_registration(&mut socket, &state.metrics).await? {
+        Some(registration) => registration,
         None => return Ok(()),
     };
+    // Counted whether or not it succeeds: a crash-looping client may never get as far
+    if let Some(burst) = state.churn.record_registration(client_addr.ip()) {
+        warn!(
+            registrations = burst.registrations,
+            threshold = burst.threshold,
+            top_clients = %burst.clients(),
+            "Registration burst: a client may be crash-looping"
+        );
+    }
+    // For logging until a name is picked
+    let subdomain = requested.as_deref().unwrap_or("(random)");
 
-    debug!("Registration request: subdomain={}, from={}", subdomain, addr);
+    debug!("Registration request: subdomain={}, protocol={:?}, from={}", subdomain, protocol, client_addr);
 
-    // Validate token
-    if state.config.validate_token(&token).is_none() {
-        warn!("Invalid token from {}", addr);
-        send_error(&mut socket, ErrorCode::InvalidToken, "Invalid token").await;
-        return Ok(());
-    }
+    let checked = check_token(&state, &token, requested.as_deref()).and_then(|token_config| {
+        let aliases = check_aliases(&state, &token_config, protocol, mode, requested.as_deref(), &aliases)?;
+        Ok((token_config, aliases))
+    });
+    let (token_config, aliases) = match checked {
+        Ok(checked) => checked,
+        Err(refusal) => {
+            warn!("Refused '{}' from {}: {}", subdomain, client_addr, refusal.message);
+            if refusal.code == ErrorCode::InvalidToken {
+                state.admission.record_failed_auth(client_addr.ip());
+            }
+            send_error(&mut socket, &state.metrics, refusal.code, refusal.message).await;
+            return Ok(());
+        }
+    };
 
-    // Validate subdomain
-    if let Err(e) = Registry::validate_subdomain(&subdomain) {
-        warn!("Invalid subdomain '{}': {}", subdomain, e);
-        send_error(&mut socket, ErrorCode::SubdomainInvalid, e.to_string()).await;
-        return Ok(());
+    // TCP tunnels take a port of their own, before anything is registered, so the
+    // client hears why if none is free
+    let tcp_listener = match (protocol, &state.tcp_ports) {
+        (Protocol::Http, _) => None,
+        (Protocol::Tcp, None) => {
+            warn!("Refused TCP tunnel '{}' from {}: TCP tunnels aren't enabled", subdomain, client_addr);
+            send_error(&mut socket, &state.metrics, ErrorCode::TcpUnavailable, "TCP tunnels aren't enabled on this server").await;
+            return Ok(());
+        }
+        (Protocol::Tcp, Some(tcp_ports)) => match bind_tcp_port(&state, tcp_ports, requested.as_deref(), &token, remote_port).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                warn!("Refused TCP tunnel '{}' from {}: {}", subdomain, client_addr, e);
+                let code = match e {
+                    PortError::OutOfRange(..) => ErrorCode::TcpUnavailable,
+                    PortError::InUse(_) | PortError::Exhausted(_) => ErrorCode::PortUnavailable,
+                };
+                send_error(&mut socket, &state.metrics, code, e.to_string()).await;
+                return Ok(());
+            }
+        },
+    };
+    let tcp_port = match tcp_listener.as_ref().map(|listener| listener.local_addr()) {
+        Some(Ok(local_addr)) => Some(local_addr.port()),
+        Some(Err(e)) => {
+            error!("TCP listener for '{}' has no address: {}", subdomain, e);
+            send_error(&mut socket, &state.metrics, ErrorCode::InternalError, "Failed to open a TCP port").await;
+            return Ok(());
+        }
+        None => None,
+    };
+
+    // The aliases' names are the same whatever name the tunnel gets, so a certificate
+    // of another token's refuses the registration outright (only subdomain tunnels
+    // have aliases)
+    for alias in &aliases {
+        let alias_domain = alias.under(&state.config.server.domain);
+        if let Err(refusal) = check_certificate_owner(&state, &token, &alias_domain) {
+            warn!("Rejected registration for '{}' from {}: alias {}", subdomain, client_addr, refusal.message);
+            send_error(&mut socket, &state.metrics, refusal.code, refusal.message).await;
+            return Ok(());
+        }
     }
 
-    // Determine URL based on HTTPS availability
-    let full_domain = format!("{}.{}", subdomain, state.config.server.domain);
-    let (url, cert_ready) = if state.config.https.is_some() {
-        // HTTPS mode
-        let https_port = state.config.server.https_port;
-        let url = if https_port == 443 {
-            format!("https://{}", full_domain)
+    // A client that leaves the name to us gets a random one its token allows, and
+    // another if it's taken
+    let assigned = requested.is_none();
+    let candidates: Vec<String> = match requested {
+        Some(subdomain) => vec![subdomain],
+        None => std::iter::repeat_with(|| token_config.random_subdomain())
+            .filter(|subdomain| !aliases.iter().any(|alias| alias == subdomain))
+            .take(NAME_ATTEMPTS)
+            .collect(),
+    };
+
+    // Path tunnels are served under the base domain's certificate, and TCP tunnels are
+    // reached by port, so only subdomain tunnels get one of their own
+    let own_certificate = tcp_port.is_none() && mode == TunnelMode::Subdomain;
+
+    // Create channel for proxy requests
+    let (request_tx, mut request_rx) = mpsc::channel::<ProxyRequest>(32);
+
+    let mut registered = None;
+    for candidate in candidates {
+        // A requested name has been checked already, but a random one may not fit
+        let subdomain = match Subdomain::new(&candidate) {
+            Ok(subdomain) => subdomain,
+            Err(e) => {
+                warn!("Failed to register tunnel '{}' from {}: {}", candidate, client_addr, e);
+                let refusal = Refusal::from_registry(e, &candidate);
+                send_error(&mut socket, &state.metrics, refusal.code, refusal.message).await;
+                return Ok(());
+            }
+        };
+        // Determine URL based on HTTPS availability
+        let full_domain = subdomain.under(&state.config.server.domain);
+
+        if own_certificate {
+            if let Err(refusal) = check_certificate_owner(&state, &token, &full_domain) {
+                if assigned {
+                    continue;
+                }
+                warn!("Rejected registration for '{}' from {}: {}", subdomain, client_addr, refusal.message);
+                send_error(&mut socket, &state.metrics, refusal.code, refusal.message).await;
+                return Ok(());
+            }
+        }
+
+        // Create tunnel with channel sender
+        let mut tunnel = Tunnel::new(subdomain.clone(), token.clone(), client_addr, request_tx.clone())
+            .with_client_info(client_info.clone())
+            .with_pause_schedule(pause_schedule.clone())
+            .with_allow_ips(allow_ips.clone())
+            .with_max_requests_per_second(state.tokens.max_requests_per_second_for(&token));
+        if let Some(port) = tcp_port {
+            tunnel = tunnel.with_tcp_port(port).with_share_key(share_key.clone());
         } else {
-            format!("https://{}:{}", full_domain, https_port)
-        };
-        
-        // Check if certificate exists
-        let cert_ready = state.cert_manager
-            .as_ref()
-            .map(|cm| cm.has_cert(&full_domain))
-            .unwrap_or(false);
-        
-        (url, cert_ready)
-    } else {
-        // HTTP mode
-        let http_port = state.config.server.http_port;
-        let url = if http_port == 80 {
-            format!("http://{}", full_domain)
+            tunnel = tunnel.with_basic_auth(basic_auth.clone()).with_mode(mode).with_aliases(aliases.clone());
+        }
+        // Paused from the start if it registers during a window
+        state.maintenance.update(&tunnel, SystemTime::now());
+        let tunnel = Arc::new(tunnel);
+
+        // Register before telling the client it succeeded, so a name already in use is
+        // reported to the client instead of leaving it with a URL that 404s. A name the
+        // client asked for may be its own, from a connection that hasn't been noticed gone.
+        let max_tunnels = state.tokens.max_tunnels_for(&tunnel.token);
+        let result = if assigned {
+            state.registry.register(&subdomain, tunnel.clone(), max_tunnels).map(|()| None)
         } else {
-            format!("http://{}:{}", full_domain, http_port)
+            state.registry.reclaim(&subdomain, tunnel.clone(), max_tunnels)
         };
-        (url, true) // No cert needed for HTTP
+        match result {
+            Ok(stale) => {
+                registered = Some((subdomain, full_domain, tunnel, stale));
+                break;
+            }
+            Err(
+                RegistryError::SubdomainTaken | RegistryError::ReservedSubdomain | RegistryError::HeldForReconnect,
+            ) if assigned => continue,
+            Err(e) => {
+                warn!("Failed to register tunnel '{}' from {}: {}", subdomain, client_addr, e);
+                let refusal = Refusal::from_registry(e, &subdomain);
+                send_error(&mut socket, &state.metrics, refusal.code, refusal.message).await;
+                return Ok(());
+            }
+        }
+    }
+    // The registered tunnel holds the only sender now
+    drop(request_tx);
+    let Some((subdomain, full_domain, tunnel, stale)) = registered else {
+        warn!("No free subdomain for {} after {} random picks", client_addr, NAME_ATTEMPTS);
+        send_error(&mut socket, &state.metrics, ErrorCode::InternalError, "Couldn't find a free subdomain").await;
+        return Ok(());
+    };
+
+    state.metrics.record_registration();
+    if let Some(ref stale) = stale {
+        info!("Tunnel {} reconnected from {}, replacing the connection from {}", subdomain, client_addr, stale.client_addr);
+        stale.close(REPLACED_MESSAGE);
+        state.metrics.record_reconnect();
+    } else if state.churn.is_reconnect(&subdomain, &tunnel.token) {
+        debug!("Tunnel {} reconnected", subdomain);
+        state.metrics.record_reconnect();
+    }
+
+    // The tunnel's own aliases are claimed like its name; the server's configured ones
+    // belong to no token, but need certificates all the same
+    let alias_names = state.registry.aliases_of(&tunnel);
+    let domains: Vec<FullDomain> = std::iter::once(full_domain.clone())
+        .chain(alias_names.iter().map(|alias| alias.under(&state.config.server.domain)))
+        .collect();
+    if let (Some(ref cert_manager), true) = (&state.cert_manager, own_certificate) {
+        let claimed = std::iter::once(&subdomain).chain(&tunnel.aliases);
+        for domain in claimed.map(|name| name.under(&state.config.server.domain)) {
+            if let Err(e) = cert_manager.claim(&domain, &tunnel.token).await {
+                warn!("Failed to record ownership of {}: {}", domain, e);
+            }
+        }
+    }
+
+    let url = match tcp_port {
+        Some(port) => state.public_url.tcp_url(port),
+        None => state.public_url.http_tunnel_url(&subdomain, mode),
+    };
+    let cert_ready = match state.cert_manager {
+        Some(ref cert_manager) if own_certificate => domains.iter().all(|domain| cert_manager.has_cert(domain)),
+        // No cert needed: plain HTTP, TLS terminated in front of the server, or not one
+        // of the tunnel's own
+        _ => true,
     };
 
-    // Send success response first
+    // Send success response first, with the limits visitors will be held to (TCP
+    // tunnels' connections aren't limited)
+    let limits = tcp_port.is_none().then(|| TunnelLimits {
+        max_rps: (tunnel.max_requests_per_second > 0).then_some(tunnel.max_requests_per_second),
+        max_concurrent: None,
+        max_body_bytes: Some(state.config.limits.max_request_body_bytes as u64),
+    });
     let response = ServerMessage::Registered {
-        subdomain: subdomain.clone(),
+        subdomain: subdomain.to_string(),
         url: url.clone(),
+        server_version: Some(BuildInfo::current().to_string()),
+        limits,
+        motd: state.motd.for_client(tunnel.client_info.client_version.as_deref()),
+        alias_urls: alias_names.iter().map(|alias| state.public_url.http_tunnel_url(alias, mode)).collect(),
     };
     if socket
-        .send(Message::Text(response.to_json().unwrap().into()))
+        .send(Message::Text(response.to_json().unwrap()))
         .await
         .is_err()
     {
+        // By tunnel, as below: a reconnecting client may already have taken the name
+        state.registry.deregister_tunnel(&tunnel);
+        state.usage.finish(&tunnel, now_secs());
         return Ok(());
     }
 
     info!("Tunnel registered: {} -> {}", subdomain, url);
 
+    let tcp_task = tcp_listener.map(|listener| tokio::spawn(tcp::serve(listener, tunnel.clone())));
+
     // If HTTPS is enabled and cert doesn't exist, request it
-    if state.config.https.is_some() {
+    if state.config.https.is_some() && own_certificate {
         if !cert_ready {
             // Send certificate status (not ready)
             let cert_status = ServerMessage::CertificateStatus { ready: false };
-            let _ = socket.send(Message::Text(cert_status.to_json().unwrap().into())).await;
+            let _ = socket.send(Message::Text(cert_status.to_json().unwrap())).await;
             
-            // Request certificate synchronously so client can wait
+            // Request certificates synchronously so client can wait, the aliases' too
             if let Some(ref cert_manager) = state.cert_manager {
-                match cert_manager.request_cert(&full_domain).await {
-                    Ok(()) => {
-                        info!("Certificate ready for {}", full_domain);
-                        // Send certificate ready status
-                        let cert_status = ServerMessage::CertificateStatus { ready: true };
-                        let _ = socket.send(Message::Text(cert_status.to_json().unwrap().into())).await;
-                    }
-                    Err(e) => {
-                        error!("Failed to get certificate for {}: {}", full_domain, e);
-                        // Don't send ready status - client will timeout
+                let mut ready = true;
+                for domain in domains.iter().filter(|domain| !cert_manager.has_cert(domain)) {
+                    match cert_manager.request_cert(domain).await {
+                        Ok(()) => info!("Certificate ready for {}", domain),
+                        Err(e) => {
+                            error!("Failed to get certificate for {}: {}", domain, e);
+                            ready = false;
+                        }
                     }
                 }
+                if ready {
+                    // Send certificate ready status
+                    let cert_status = ServerMessage::CertificateStatus { ready: true };
+                    let _ = socket.send(Message::Text(cert_status.to_json().unwrap())).await;
+                }
+                // Otherwise don't send ready status - client will timeout
             }
         } else {
             // Send certificate status (ready)
             let cert_status = ServerMessage::CertificateStatus { ready: true };
-            let _ = socket.send(Message::Text(cert_status.to_json().unwrap().into())).await;
+            let _ = socket.send(Message::Text(cert_status.to_json().unwrap())).await;
         }
     }
 
-    // Create channel for proxy requests
-    let (request_tx, mut request_rx) = mpsc::channel::<ProxyRequest>(32);
-
-    // Create tunnel with channel sender
-    let tunnel = Arc::new(Tunnel::new(subdomain.clone(), token, request_tx));
-
-    // Register in registry
-    if let Err(e) = state.registry.register(&subdomain, tunnel.clone()) {
-        error!("Failed to register tunnel: {}", e);
-        return Ok(());
-    }
-
     // Create yamux connection
     let config = yamux::Config::default();
-    let compat_ws = Compat::new(socket);
+    let (compat_ws, mut control) = ServerCompat::with_control(socket);
+    let last_heard = compat_ws.last_heard();
+    let limits = &state.config.limits;
+    let ping_timeout = Duration::from_secs(limits.ping_timeout_secs);
+    let idle_timeout = Duration::from_secs(limits.idle_tunnel_timeout_secs);
+    let idle_warning_after = idle_timeout.mul_f64(IDLE_WARNING_AT);
+    let keep_alive_allowed = state.tokens.get(&tunnel.token).is_some_and(|token| token.keep_alive);
+    let mut checks = tokio::time::interval(check_interval(ping_timeout, idle_timeout));
+    let mut idle_warned = false;
+    // Clients from before pings existed are never held to the ping timeout
+    let mut pinged = false;
     let mut connection = Connection::new(compat_ws, config, Mode::Server);
+    let mut shutdown_rx = state.shutdown_tx.subscribe();
+    let drain = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(drain);
+    let mut draining = false;
+    // Measures the round trip over a stream of its own, opened again if it closes
+    let mut rtt_probe: Option<tokio::task::JoinHandle<()>> = None;
+    let mut rtt_reopen = tokio::time::interval(rtt::PROBE_INTERVAL);
 
     // Run the connection handler loop
     loop {
         tokio::select! {
+            // Tell the client before tearing down, then give in-flight requests time to finish
+            _ = shutdown_rx.recv(), if !draining => {
+                info!("Notifying tunnel {} of shutdown", subdomain);
+                let shutdown = ServerMessage::Shutdown { message: SHUTDOWN_MESSAGE.to_string() };
+                let _ = control.tx.send(shutdown);
+                drain.as_mut().reset(tokio::time::Instant::now() + SHUTDOWN_DRAIN);
+                draining = true;
+            }
+
+            // Deregistered by an admin, a token revocation or the idle cleanup: tell the
+            // client why, so it reconnects rather than waiting on a tunnel that's gone
+            reason = tunnel.closed(), if !draining => {
+                info!("Closing tunnel {}: {}", subdomain, reason);
+                let shutdown = ServerMessage::Shutdown { message: reason.to_string() };
+                let _ = control.tx.send(shutdown);
+                if let Some(ref task) = tcp_task {
+                    task.abort();
+                }
+                drain.as_mut().reset(tokio::time::Instant::now() + SHUTDOWN_DRAIN);
+                draining = true;
+            }
+
+            _ = checks.tick() => {
+                // A client that stopped pinging is gone (e.g. its NAT mapping expired)
+                // even if the TCP connection still looks open
+                let silent_for = last_heard.lock().map(|t| t.elapsed()).unwrap_or_default();
+                if pinged && !ping_timeout.is_zero() && silent_for > ping_timeout {
+                    warn!("Tunnel {} silent for {:?}, disconnecting", subdomain, silent_for);
+                    break;
+                }
+
+                // Revoking a token closes its tunnels, but this one may have registered
+                // while that was happening
+                if !draining && state.tokens.get(&tunnel.token).is_none() {
+                    state.registry.disconnect(&tunnel, "The tunnel's token was revoked");
+                }
+
+                // Warn once per idle stretch, so the client can say so or keep the tunnel alive
+                let idle_for = tunnel.idle_for();
+                if idle_for < idle_warning_after {
+                    idle_warned = false;
+                } else if !idle_warned {
+                    idle_warned = true;
+                    let disconnect_in_secs = idle_timeout.saturating_sub(idle_for).as_secs();
+                    debug!("Tunnel {} idle, warning client ({}s left)", subdomain, disconnect_in_secs);
+                    let warning = ServerMessage::IdleWarning { disconnect_in_secs };
+                    let _ = control.tx.send(warning);
+                }
+            }
+
+            // Tell the client its visitors are getting 429s, once per stretch of them
+            until_ms = tunnel.throttled(), if !draining => {
+                debug!("Tunnel {} over its request rate, telling the client", subdomain);
+                let notice = ServerMessage::Throttled { until_ms };
+                let _ = control.tx.send(notice);
+            }
+
+            Some(message) = control.rx.recv() => match message {
+                ClientMessage::Ping { keep_alive } => {
+                    pinged = true;
+                    if keep_alive {
+                        if keep_alive_allowed {
+                            debug!("Keep-alive from tunnel {}", subdomain);
+                            tunnel.touch();
+                        } else {
+                            debug!("Ignoring keep-alive from tunnel {}: not allowed for its token", subdomain);
+                        }
+                    }
+                }
+                // The client is going away: stop sending it requests now, and let it
+                // close the connection once the ones in flight are done
+                ClientMessage::Disconnect => {
+                    info!("Tunnel {} disconnected by its client", subdomain);
+                    state.registry.deregister_tunnel(&tunnel);
+                    if let Some(ref task) = tcp_task {
+                        task.abort();
+                    }
+                    if !draining {
+                        drain.as_mut().reset(tokio::time::Instant::now() + SHUTDOWN_DRAIN);
+                        draining = true;
+                    }
+                }
+                _ => {}
+            },
+
+            _ = &mut drain, if draining => {
+                debug!("Closing tunnel {} after shutdown drain", subdomain);
+                if let Err(e) = std::future::poll_fn(|cx| connection.poll_close(cx)).await {
+                    debug!("Error closing tunnel {}: {}", subdomain, e);
+                }
+                break;
+            }
+
+            _ = rtt_reopen.tick(), if tunnel.client_info.echo_streams
+                && !draining
+                && rtt_probe.as_ref().is_none_or(|task| task.is_finished()) =>
+            {
+                // Opened here rather than with `get_stream`, which counts as activity
+                match std::future::poll_fn(|cx| connection.poll_new_outbound(cx)).await {
+                    Ok(stream) => {
+                        let tunnel = tunnel.clone();
+                        rtt_probe = Some(tokio::spawn(async move {
+                            if let Err(e) = rtt::probe(stream, &tunnel.rtt, rtt::PROBE_INTERVAL, rtt::PROBE_TIMEOUT).await {
+                                debug!("RTT probe for tunnel {} ended: {}", tunnel.subdomain, e);
+                            }
+                        }));
+                    }
+                    Err(e) => debug!("Failed to open RTT probe stream for tunnel {}: {}", subdomain, e),
+                }
+            }
+
             // Handle proxy requests from the channel
             Some(request) = request_rx.recv() => {
                 debug!("Received stream request");
@@ -142,6 +492,7 @@ pub async fn handle_websocket(
                 
                 match stream_result {
                     Ok(stream) => {
+                        tunnel.touch();
                         // Send the stream back to the requester
                         let _ = request.stream_tx.send(Ok(stream));
                     }
@@ -179,37 +530,175 @@ pub async fn handle_websocket(
     }
 
     // Cleanup
-    state.registry.deregister(&subdomain);
+    if let Some(task) = tcp_task {
+        task.abort();
+    }
+    if let Some(task) = rtt_probe {
+        task.abort();
+    }
+    // By tunnel, not name: after a Disconnect the name may already be someone else's,
+    // and a reconnecting client may have taken it over
+    if state.registry.deregister_tunnel(&tunnel) {
+        state.churn.record_departure(&tunnel);
+        // The client dropped without saying goodbye, so it's likely to be back
+        if !draining {
+            let grace = Duration::from_secs(state.config.server.reconnect_grace_secs);
+            state.registry.hold_for_reconnect(&tunnel, grace);
+        }
+    }
+    state.usage.finish(&tunnel, now_secs());
     info!("Tunnel {} deregistered", subdomain);
 
     Ok(())
 }
 
-async fn wait_for_registration(socket: &mut WebSocket) -> Result<Option<(String, String)>> {
+/// Listen on the TCP port a client asked for, or any free one. A client reconnecting
+/// before its old connection was noticed gone asks for the port its stale tunnel
+/// still holds: that tunnel is closed so the port comes free.
+async fn bind_tcp_port(
+    state: &ServerState,
+    tcp_ports: &TcpPorts,
+    subdomain: Option<&str>,
+    token: &TokenSecret,
+    port: Option<u16>,
+) -> Result<TcpListener, PortError> {
+    let stale = subdomain
+        .and_then(|subdomain| state.registry.get(subdomain))
+        .filter(|stale| stale.token == *token && port.is_some() && stale.tcp_port == port);
+    let Some(stale) = stale else {
+        return tcp_ports.bind(port).await;
+    };
+    stale.close(REPLACED_MESSAGE);
+    let deadline = tokio::time::Instant::now() + PORT_HANDOVER_TIMEOUT;
+    loop {
+        match tcp_ports.bind(port).await {
+            Err(PortError::InUse(_)) if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// What a client asked for in its Register message
+struct Registration {
+    token: TokenSecret,
+    /// None for the server to pick one
+    subdomain: Option<String>,
+    protocol: Protocol,
+    remote_port: Option<u16>,
+    client_info: ClientInfo,
+    /// Empty keys are dropped, so they can't let anyone connect
+    share_key: Option<String>,
+    pause_schedule: Option<Window>,
+    /// What visitors to an HTTP tunnel must present
+    basic_auth: Option<BasicAuth>,
+    /// Visitors from elsewhere are refused; anyone may connect when empty
+    allow_ips: Vec<IpNet>,
+    /// Always `Subdomain` for TCP tunnels
+    mode: TunnelMode,
+    /// Other subdomains for the tunnel, lowercased
+    aliases: Vec<String>,
+}
+
+/// Longest service name, service version or client version kept from a Register message
+const MAX_DECLARED_LEN: usize = 64;
+
+/// A name or version as the client declared it, fit for showing to anyone
+fn declared(value: Option<String>) -> Option<String> {
+    let value: String = value?
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_DECLARED_LEN)
+        .collect();
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+async fn wait_for_registration(socket: &mut WebSocket, metrics: &Metrics) -> Result<Option<Registration>> {
     // Set a timeout for registration
     let result = tokio::time::timeout(std::time::Duration::from_secs(10), socket.next()).await;
 
     match result {
         Ok(Some(Ok(Message::Text(text)))) => {
             match ClientMessage::from_json(&text) {
-                Ok(ClientMessage::Register { token, subdomain }) => {
-                    Ok(Some((token, subdomain)))
+                Ok(ClientMessage::Register {
+                    token,
+                    subdomain,
+                    protocol,
+                    remote_port,
+                    service_name,
+                    service_version,
+                    publish_manifest,
+                    client_version,
+                    share_key,
+                    pause_schedule,
+                    basic_auth,
+                    allow_ips,
+                    mode,
+                    echo_streams,
+                    aliases,
+                }) => {
+                    let pause_schedule = match pause_schedule.as_deref().map(Window::parse).transpose() {
+                        Ok(schedule) => schedule,
+                        Err(e) => {
+                            warn!("Invalid pause schedule: {}", e);
+                            send_error(socket, metrics, ErrorCode::InvalidOption, format!("Invalid pause schedule: {}", e)).await;
+                            return Ok(None);
+                        }
+                    };
+                    let basic_auth = match basic_auth.as_deref().filter(|c| !c.is_empty()).map(BasicAuth::new).transpose() {
+                        Ok(auth) => auth,
+                        Err(e) => {
+                            warn!("Invalid basic auth: {}", e);
+                            send_error(socket, metrics, ErrorCode::InvalidOption, format!("Invalid basic auth: {}", e)).await;
+                            return Ok(None);
+                        }
+                    };
+                    let allow_ips = match allow_ips.iter().map(|net| parse_ip_net(net)).collect::<Result<Vec<_>, _>>() {
+                        Ok(nets) => nets,
+                        Err(e) => {
+                            warn!("Invalid allowed IPs: {}", e);
+                            send_error(socket, metrics, ErrorCode::InvalidOption, format!("Invalid allowed IPs: {}", e)).await;
+                            return Ok(None);
+                        }
+                    };
+                    Ok(Some(Registration {
+                        token: TokenSecret::from(token),
+                        subdomain: (!subdomain.is_empty()).then_some(subdomain),
+                        protocol,
+                        remote_port,
+                        client_info: ClientInfo {
+                            service_name: declared(service_name),
+                            service_version: declared(service_version),
+                            publish_manifest,
+                            client_version: declared(client_version),
+                            // TCP tunnels' streams carry the visitor's bytes from the first
+                            echo_streams: echo_streams && protocol == Protocol::Http,
+                        },
+                        share_key: share_key.filter(|key| !key.is_empty()),
+                        pause_schedule,
+                        basic_auth,
+                        allow_ips,
+                        mode: if protocol == Protocol::Http { mode } else { TunnelMode::Subdomain },
+                        aliases: aliases.iter().map(|alias| alias.to_ascii_lowercase()).collect(),
+                    }))
                 }
                 Ok(_) => {
                     warn!("Expected Register message, got something else");
-                    send_error(socket, ErrorCode::InternalError, "Expected Register message").await;
+                    send_error(socket, metrics, ErrorCode::InternalError, "Expected Register message").await;
                     Ok(None)
                 }
                 Err(e) => {
                     warn!("Failed to parse client message: {}", e);
-                    send_error(socket, ErrorCode::InternalError, "Invalid message format").await;
+                    send_error(socket, metrics, ErrorCode::InternalError, "Invalid message format").await;
                     Ok(None)
                 }
             }
         }
         Ok(Some(Ok(_))) => {
             warn!("Expected text message");
-            send_error(socket, ErrorCode::InternalError, "Expected text message").await;
+            send_error(socket, metrics, ErrorCode::InternalError, "Expected text message").await;
             Ok(None)
         }
         Ok(Some(Err(e))) => {
@@ -222,15 +711,2578 @@ async fn wait_for_registration(socket: &mut WebSocket) -> Result<Option<(String,
         }
         Err(_) => {
             warn!("Registration timeout");
-            send_error(socket, ErrorCode::InternalError, "Registration timeout").await;
+            send_error(socket, metrics, ErrorCode::InternalError, "Registration timeout").await;
             Ok(None)
         }
     }
 }
 
-async fn send_error(socket: &mut WebSocket, code: ErrorCode, message: impl Into<String>) {
+/// Refuse a registration, counting it by `code`
+/// Why a registration is refused, as its client is told
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refusal {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Refusal {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn from_registry(e: RegistryError, subdomain: &str) -> Self {
+        match e {
+            RegistryError::SubdomainTaken => {
+                Self::new(ErrorCode::SubdomainTaken, format!("Subdomain '{}' is already in use", subdomain))
+            }
+            RegistryError::ReservedSubdomain => {
+                Self::new(ErrorCode::SubdomainTaken, format!("Subdomain '{}' is reserved", subdomain))
+            }
+            RegistryError::HeldForReconnect => Self::new(
+                ErrorCode::SubdomainTaken,
+                format!("Subdomain '{}' is held for the client that just dropped it to reconnect", subdomain),
+            ),
+            RegistryError::InvalidSubdomain(_) => Self::new(ErrorCode::SubdomainInvalid, e.to_string()),
+            RegistryError::TunnelLimitReached(max) => Self::new(
+                ErrorCode::TunnelLimitReached,
+                format!("This token already has {} tunnels connected, the most it may have", max),
+            ),
+            RegistryError::Alias(alias, e) => {
+                let refusal = Self::from_registry(*e, &alias);
+                Self::new(refusal.code, format!("Alias: {}", refusal.message))
+            }
+        }
+    }
+}
+
+/// The checks on a registration's token and requested name that come before any
+/// port or name is taken. Failed authentication is left for the caller to record.
+fn check_token(state: &ServerState, token: &TokenSecret, requested: Option<&str>) -> Result<TokenConfig, Refusal> {
+    let token_config = state
+        .tokens
+        .get(token)
+        .ok_or_else(|| Refusal::new(ErrorCode::InvalidToken, "Invalid token"))?;
+
+    // Recheck the global cap: other clients may have registered since this one was admitted
+    if !state.admission.has_capacity(state.registry.count()) {
+        return Err(Refusal::new(
+            ErrorCode::TunnelLimitReached,
+            "The server has reached its maximum number of tunnels",
+        ));
+    }
+
+    if let Some(requested) = requested {
+        check_name(state, &token_config, requested)?;
+    }
+    Ok(token_config)
+}
+
+/// Whether the token may ask for `name`, as a subdomain or an alias
+fn check_name(state: &ServerState, token_config: &TokenConfig, name: &str) -> Result<Subdomain, Refusal> {
+    let subdomain = Subdomain::new(name).map_err(|e| Refusal::new(ErrorCode::SubdomainInvalid, e.to_string()))?;
+    if state.config.server.reject_confusables && idn::is_mixed_script(name) {
+        return Err(Refusal::new(
+            ErrorCode::SubdomainInvalid,
+            format!(
+                "Subdomain '{}' mixes scripts, so it could pass for another name",
+                idn::to_unicode(name)
+            ),
+        ));
+    }
+    if let Some(patterns) = &token_config.allowed_subdomains {
+        if !token_config.allows_subdomain(name) {
+            return Err(Refusal::new(
+                ErrorCode::SubdomainInvalid,
+                format!("This token may only register subdomains matching {}", patterns.join(", ")),
+            ));
+        }
+    }
+    Ok(subdomain)
+}
+
+/// The checks on a registration's aliases that come before anything is taken,
+/// returning the aliases
+fn check_aliases(
+    state: &ServerState,
+    token_config: &TokenConfig,
+    protocol: Protocol,
+    mode: TunnelMode,
+    requested: Option<&str>,
+    aliases: &[String],
+) -> Result<Vec<Subdomain>, Refusal> {
+    if aliases.is_empty() {
+        return Ok(Vec::new());
+    }
+    if protocol != Protocol::Http || mode != TunnelMode::Subdomain {
+        return Err(Refusal::new(
+            ErrorCode::SubdomainInvalid,
+            "Only HTTP tunnels on a subdomain of their own can have aliases",
+        ));
+    }
+    if aliases.len() > MAX_ALIASES {
+        return Err(Refusal::new(
+            ErrorCode::SubdomainInvalid,
+            format!("A tunnel can have at most {} aliases", MAX_ALIASES),
+        ));
+    }
+    let mut checked = Vec::with_capacity(aliases.len());
+    for (i, alias) in aliases.iter().enumerate() {
+        if Some(alias.as_str()) == requested || aliases[..i].contains(alias) {
+            return Err(Refusal::new(
+                ErrorCode::SubdomainInvalid,
+                format!("Alias '{}' is given more than once", alias),
+            ));
+        }
+        let alias = check_name(state, token_config, alias)
+            .map_err(|refusal| Refusal::new(refusal.code, format!("Alias: {}", refusal.message)))?;
+        checked.push(alias);
+    }
+    Ok(checked)
+}
+
+/// Refuse a name whose certificate belongs to another token (strict ownership only)
+fn check_certificate_owner(state: &ServerState, token: &TokenSecret, full_domain: &FullDomain) -> Result<(), Refusal> {
+    let Some(cert_manager) = &state.cert_manager else {
+        return Ok(());
+    };
+    let server = &state.config.server;
+    cert_manager
+        .check_ownership(
+            full_domain,
+            token,
+            server.strict_subdomain_ownership,
+            Duration::from_secs(server.ownership_expiry_secs),
+        )
+        .map_err(|message| Refusal::new(ErrorCode::SubdomainTaken, message))
+}
+
+/// Whether `token` could register an HTTP tunnel on `subdomain` now, by the same
+/// checks a registration goes through, without registering anything
+pub fn check_registration(state: &ServerState, token: &TokenSecret, subdomain: &str) -> Result<(), Refusal> {
+    let token_config = check_token(state, token, None)?;
+    let subdomain = check_name(state, &token_config, subdomain)?;
+    check_certificate_owner(state, token, &subdomain.under(&state.config.server.domain))?;
+    state
+        .registry
+        .check(&subdomain, token, state.tokens.max_tunnels_for(token))
+        .map_err(|e| Refusal::from_registry(e, &subdomain))
+}
+
+async fn send_error(socket: &mut WebSocket, metrics: &Metrics, code: ErrorCode, message: impl Into<String>) {
+    metrics.record_registration_failure(code);
     let msg = ServerMessage::error(code, message);
     if let Ok(json) = msg.to_json() {
-        let _ = socket.send(Message::Text(json.into())).await;
+        let _ = socket.send(Message::Text(json)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::acme::{self, ChallengeStore};
+    use crate::server::cert_store::{CertStore, FsCertStore};
+    use crate::server::tls::CertManager;
+    use crate::server::admission::Admission;
+    use crate::server::maintenance::Maintenance;
+    use crate::server::motd::{Messages, Motd};
+    use crate::server::response_headers::{HeaderRules, ResponseHeaders};
+    use crate::server::scheduler::FairScheduler;
+    use crate::expose::summary::SessionStats;
+    use crate::expose::tunnel::TunnelEnd;
+    use crate::server::config::Config;
+    use crate::server::metrics::Metrics;
+    use crate::server::public_url::PublicUrlBuilder;
+    use crate::server::router::{acme_probe_limiter, create_acme_router};
+    use crate::server::churn::Churn;
+    use crate::server::registry::Registry;
+    use crate::server::names::TokenId;
+    use crate::server::usage::Usage;
+    use crate::server::slow_requests::SlowRequests;
+    use crate::server::tcp::TcpPorts;
+    use crate::server::tokens::TokenStore;
+    use futures::SinkExt;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    /// Serve the HTTP router on a local port, returning the control URL and server state
+    async fn start_server() -> (String, Arc<ServerState>) {
+        start_server_with_limits("").await
+    }
+
+    async fn start_server_with_limits(limits: &str) -> (String, Arc<ServerState>) {
+        start_server_with("", limits).await
+    }
+
+    /// A server with extra `[server]` settings, and anything after `[limits]`
+    async fn start_server_with(server: &str, limits: &str) -> (String, Arc<ServerState>) {
+        start_server_with_certs(server, limits, None).await
+    }
+
+    async fn start_server_with_certs(
+        server: &str,
+        limits: &str,
+        cert_manager: Option<Arc<CertManager>>,
+    ) -> (String, Arc<ServerState>) {
+        start_server_with_config(test_config(server, limits), cert_manager).await
+    }
+
+    fn test_config(server: &str, limits: &str) -> Config {
+        Config::parse(&format!(
+            r#"
+[server]
+domain = "tunnel.example.com"
+{}
+
+[tokens.tk_alice]
+[tokens.tk_bob]
+[tokens.tk_carol]
+keep_alive = true
+[tokens.tk_dave]
+max_tunnels = 2
+[tokens.tk_admin]
+admin = true
+
+[limits]
+{}
+"#,
+            server, limits
+        ))
+        .unwrap()
+    }
+
+    /// For settings validation wouldn't allow, like timeouts short enough to test
+    async fn start_server_with_config(config: Config, cert_manager: Option<Arc<CertManager>>) -> (String, Arc<ServerState>) {
+        let metrics = Arc::new(Metrics::new());
+        let state = Arc::new(ServerState {
+            admission: Arc::new(Admission::new(&config.limits)),
+            scheduler: Arc::new(FairScheduler::new(config.limits.fair_queue_threshold, metrics.clone())),
+            public_url: PublicUrlBuilder::from_config(&config),
+            slow_requests: Arc::new(SlowRequests::new(config.logging.slow_request_threshold_ms)),
+            churn: Arc::new(Churn::new(config.logging.registration_rate_warning)),
+            usage: Arc::new(Usage::new(config.usage.retention_days)),
+            tcp_ports: config.tcp.port_range.map(|range| Arc::new(TcpPorts::new(range))),
+            maintenance: Arc::new(Maintenance::new(&config.maintenance).unwrap()),
+            response_headers: Arc::new(ResponseHeaders::new(HeaderRules::new(&config.response_headers).unwrap())),
+            started_at: std::time::Instant::now(),
+            motd: Arc::new(Motd::new(Messages::load(&config.server).unwrap())),
+            dns: Arc::default(),
+            pages: Arc::default(),
+            proxy_buffers: Arc::default(),
+            logs: Arc::default(),
+            tokens: Arc::new(TokenStore::new(&config)),
+            registry: Arc::new(Registry::new(&config.registry.reserved).with_aliases(&config.registry.aliases)),
+            config: Arc::new(config),
+            cert_manager,
+            acme_probe_limiter: acme_probe_limiter(),
+            metrics,
+            cloudflare: None,
+            shutdown_tx: broadcast::channel(1).0,
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), state.config.server.control_path());
+        let app = create_acme_router(state.clone(), Arc::new(ChallengeStore::new()), false);
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        (url, state)
+    }
+
+    /// Connect and register, returning the socket and the server's first reply
+    type ClientWs = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn register(url: &str, token: &str, subdomain: &str) -> (ClientWs, ServerMessage) {
+        register_as(url, token, subdomain, Protocol::Http, None).await
+    }
+
+    async fn register_as(
+        url: &str,
+        token: &str,
+        subdomain: &str,
+        protocol: Protocol,
+        remote_port: Option<u16>,
+    ) -> (ClientWs, ServerMessage) {
+        let mut register = register_message(token, subdomain);
+        if let ClientMessage::Register { protocol: ref mut p, remote_port: ref mut port, .. } = register {
+            *p = protocol;
+            *port = remote_port;
+        }
+        send_register(url, register).await
+    }
+
+    /// A Register for an HTTP tunnel with nothing optional set, for tests to change the
+    /// fields they care about
+    fn register_message(token: &str, subdomain: &str) -> ClientMessage {
+        ClientMessage::Register {
+            token: token.to_string(),
+            subdomain: subdomain.to_string(),
+            protocol: Protocol::Http,
+            remote_port: None,
+            service_name: None,
+            service_version: None,
+            publish_manifest: false,
+            client_version: None,
+            share_key: None,
+            pause_schedule: None,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            mode: TunnelMode::Subdomain,
+            echo_streams: false,
+            aliases: Vec::new(),
+        }
+    }
+
+    async fn send_register(url: &str, register: ClientMessage) -> (ClientWs, ServerMessage) {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        ws.send(WsMessage::Text(register.to_json().unwrap())).await.unwrap();
+        let reply = tokio::time::timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .expect("no reply to Register")
+            .unwrap()
+            .unwrap();
+        let reply = ServerMessage::from_json(reply.to_text().unwrap()).unwrap();
+        (ws, reply)
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_subdomain_gets_error() {
+        let (url, _state) = start_server().await;
+
+        let (_first, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { ref subdomain, .. } if subdomain == "myapp"), "{:?}", reply);
+
+        let (_second, reply) = register(&url, "tk_bob", "myapp").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::SubdomainTaken);
+                assert!(message.contains("already in use"), "{}", message);
+            }
+            other => panic!("expected SubdomainTaken, got {:?}", other),
+        }
+
+        let (_reserved, reply) = register(&url, "tk_bob", "admin").await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SubdomainTaken, .. }), "{:?}", reply);
+    }
+
+    /// The next Shutdown message on `ws`, skipping the tunnel's other frames
+    async fn shutdown_message(ws: &mut ClientWs) -> String {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(2), ws.next())
+                .await
+                .expect("no Shutdown message")
+                .unwrap()
+                .unwrap();
+            if let WsMessage::Text(text) = msg {
+                if let Ok(ServerMessage::Shutdown { message }) = ServerMessage::from_json(&text) {
+                    return message;
+                }
+            }
+        }
+    }
+
+    /// Wait for the tunnel on `subdomain` to be deregistered
+    async fn deregistered(state: &ServerState, subdomain: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.registry.get(subdomain).is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("tunnel not deregistered");
+    }
+
+    #[tokio::test]
+    async fn test_same_token_takes_over_its_subdomain() {
+        let (url, state) = start_server().await;
+        // A connection the server hasn't noticed is dead yet
+        let (mut stale, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let stale_tunnel = state.registry.get("myapp").unwrap();
+
+        let (_fresh, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { ref subdomain, .. } if subdomain == "myapp"), "{:?}", reply);
+        assert!(state.registry.get("myapp").is_some_and(|t| !Arc::ptr_eq(&t, &stale_tunnel)));
+        assert_eq!(shutdown_message(&mut stale).await, REPLACED_MESSAGE);
+        assert_eq!(state.registry.count_for_token(&TokenSecret::new("tk_alice")), 1);
+        assert_eq!(state.metrics.reconnects(), 1);
+
+        // Another token is still refused
+        let (_other, reply) = register(&url, "tk_bob", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SubdomainTaken, .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_client_subdomain_is_held_for_it() {
+        let (url, state) = start_server().await;
+        let (ws, _) = register(&url, "tk_alice", "myapp").await;
+        drop(ws);
+        deregistered(&state, "myapp").await;
+
+        // Another token can't take the name in the gap
+        let (_other, reply) = register(&url, "tk_bob", "myapp").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::SubdomainTaken);
+                assert!(message.contains("held for the client"), "{}", message);
+            }
+            other => panic!("expected SubdomainTaken, got {:?}", other),
+        }
+        let (_back, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // A client that disconnects cleanly isn't coming back, so its name is free
+        let (mut ws, _) = register(&url, "tk_alice", "polite").await;
+        ws.send(WsMessage::Text(ClientMessage::Disconnect.to_json().unwrap())).await.unwrap();
+        deregistered(&state, "polite").await;
+        drop(ws);
+        let (_other, reply) = register(&url, "tk_bob", "polite").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_server_picks_subdomain_when_none_given() {
+        let (url, state) = start_server().await;
+
+        let mut names = Vec::new();
+        let mut sockets = Vec::new();
+        for _ in 0..2 {
+            let (ws, reply) = register(&url, "tk_alice", "").await;
+            match reply {
+                ServerMessage::Registered { subdomain, url, .. } => {
+                    Registry::validate_subdomain(&subdomain).unwrap();
+                    assert!(url.contains(&subdomain), "{}", url);
+                    assert!(state.registry.get(&subdomain).is_some());
+                    names.push(subdomain);
+                }
+                other => panic!("expected Registered, got {:?}", other),
+            }
+            sockets.push(ws);
+        }
+        assert_ne!(names[0], names[1]);
+
+        // Asking for a name by hand still gets exactly that name
+        let (_ws, reply) = register(&url, "tk_bob", &names[0]).await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SubdomainTaken, .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_names_and_token_restrictions() {
+        let (url, _state) = start_server_with_limits(
+            "[registry]\nreserved = [\"staging\"]\n\n[tokens.tk_ci]\nallowed_subdomains = [\"ci-*\"]",
+        )
+        .await;
+
+        let (_ws, reply) = register(&url, "tk_alice", "staging").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::SubdomainTaken);
+                assert_eq!(message, "Subdomain 'staging' is reserved");
+            }
+            other => panic!("expected a reserved name error, got {:?}", other),
+        }
+
+        let (_ws, reply) = register(&url, "tk_ci", "myapp").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::SubdomainInvalid);
+                assert_eq!(message, "This token may only register subdomains matching ci-*");
+            }
+            other => panic!("expected SubdomainInvalid, got {:?}", other),
+        }
+        let (_build, reply) = register(&url, "tk_ci", "ci-build-42").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // Names the server picks are allowed too
+        let (_random, reply) = register(&url, "tk_ci", "").await;
+        match reply {
+            ServerMessage::Registered { subdomain, .. } => assert!(subdomain.starts_with("ci-"), "{}", subdomain),
+            other => panic!("expected Registered, got {:?}", other),
+        }
+
+        // Other tokens aren't restricted
+        let (_ws, reply) = register(&url, "tk_alice", "ci-other").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_internationalized_subdomains() {
+        let (url, _state) = start_server_with("reject_confusables = true", "").await;
+
+        let (_ws, reply) = register(&url, "tk_alice", "xn--mnchen-demo-thb").await;
+        match reply {
+            ServerMessage::Registered { subdomain, url, .. } => {
+                assert_eq!(subdomain, "xn--mnchen-demo-thb");
+                assert_eq!(url, "http://xn--mnchen-demo-thb.tunnel.example.com");
+            }
+            other => panic!("expected Registered, got {:?}", other),
+        }
+
+        // Unicode has to arrive as punycode
+        let (_ws, reply) = register(&url, "tk_alice", "münchen-demo").await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::SubdomainInvalid, .. }), "{:?}", reply);
+
+        // "pаypal" with a Cyrillic а
+        let (_ws, reply) = register(&url, "tk_alice", "xn--pypal-4ve").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::SubdomainInvalid);
+                assert_eq!(message, "Subdomain 'p\u{430}ypal' mixes scripts, so it could pass for another name");
+            }
+            other => panic!("expected SubdomainInvalid, got {:?}", other),
+        }
+
+        // Allowed when the server doesn't ask otherwise
+        let (url, _state) = start_server().await;
+        let (_ws, reply) = register(&url, "tk_alice", "xn--pypal-4ve").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_reservations() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        let reserve = |subdomain: &str, body: serde_json::Value| {
+            client
+                .put(format!("{}/_admin/reservations/{}", base, subdomain))
+                .bearer_auth("tk_admin")
+                .json(&body)
+                .send()
+        };
+        let taken = |reply: ServerMessage| match reply {
+            ServerMessage::Error { code, .. } => assert_eq!(code, ErrorCode::SubdomainTaken),
+            other => panic!("expected SubdomainTaken, got {:?}", other),
+        };
+
+        let response = reserve("myapp", serde_json::json!({ "token": "tk_alice" })).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let reservation: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(reservation["owner"], TokenId::of("tk_alice").to_string());
+        assert_eq!(reservation["saved"], false);
+
+        // Other tokens are refused whether or not the owner is connected
+        let (_ws, reply) = register(&url, "tk_bob", "myapp").await;
+        taken(reply);
+        let (_owner, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        crate::server::remove_idle_tunnels(&state.registry, Duration::ZERO);
+        assert!(state.registry.get("myapp").is_none());
+        let (_ws, reply) = register(&url, "tk_bob", "myapp").await;
+        taken(reply);
+
+        // A name connected with another token can't be reserved from under it
+        let (_bob, reply) = register(&url, "tk_bob", "bobs-app").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let response = reserve("bobs-app", serde_json::json!({ "token": "tk_alice" })).await.unwrap();
+        assert_eq!(response.status(), 409);
+        let response = reserve("admin", serde_json::json!({})).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let response = reserve("other", serde_json::json!({ "token": "tk_nobody" })).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/reservations", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list, serde_json::json!([{ "subdomain": "myapp", "owner": TokenId::of("tk_alice") }]));
+
+        let release = || client.delete(format!("{}/_admin/reservations/myapp", base)).bearer_auth("tk_admin").send();
+        assert_eq!(release().await.unwrap().status(), 204);
+        assert_eq!(release().await.unwrap().status(), 404);
+        let (_ws, reply) = register(&url, "tk_bob", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_per_token_tunnel_limit() {
+        let (url, state) = start_server().await;
+
+        let (first, reply) = register(&url, "tk_dave", "app-one").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let (_second, reply) = register(&url, "tk_dave", "app-two").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        let (_third, reply) = register(&url, "tk_dave", "app-three").await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::TunnelLimitReached);
+                assert!(message.contains("already has 2 tunnels"), "{}", message);
+            }
+            other => panic!("expected TunnelLimitReached, got {:?}", other),
+        }
+        assert!(state.registry.get("app-three").is_none());
+
+        // Other tokens aren't affected
+        let (_other, reply) = register(&url, "tk_alice", "app-four").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // Disconnecting frees a slot
+        drop(first);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while state.registry.count_for_token(&TokenSecret::new("tk_dave")) > 1 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("tunnel not deregistered");
+        let (_third, reply) = register(&url, "tk_dave", "app-three").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_registration_check_verdicts() {
+        let (url, state) = start_server_with_limits(
+            "max_tunnels = 4\nregistrations_per_minute_per_ip = 0\n\n[registry]\nreserved = [\"staging\"]\n\n[tokens.tk_ci]\nallowed_subdomains = [\"ci-*\"]",
+        )
+        .await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        let check = |token: &str, subdomain: &str| {
+            let request = client
+                .post(format!("{}/_check/registration", base))
+                .bearer_auth(token)
+                .json(&serde_json::json!({ "subdomain": subdomain }))
+                .send();
+            async move {
+                let response = request.await.unwrap();
+                assert_eq!(response.status(), reqwest::StatusCode::OK);
+                response.json::<serde_json::Value>().await.unwrap()
+            }
+        };
+        let refused = |verdict: serde_json::Value, code: &str, message: &str| {
+            assert_eq!(verdict["available"], false, "{}", verdict);
+            assert_eq!(verdict["code"], code, "{}", verdict);
+            assert_eq!(verdict["message"], message, "{}", verdict);
+        };
+
+        let verdict = check("tk_alice", "pr-1234").await;
+        assert_eq!(verdict["available"], true, "{}", verdict);
+        assert_eq!(verdict["subdomain"], "pr-1234");
+        assert!(verdict["url"].as_str().unwrap().starts_with("http://pr-1234.tunnel.example.com"), "{}", verdict);
+        assert!(state.registry.get("pr-1234").is_none());
+
+        refused(check("tk_nobody", "pr-1234").await, "invalid_token", "Invalid token");
+        refused(check("tk_alice", "a").await, "subdomain_invalid", "Invalid subdomain: Subdomain must be 3-63 characters");
+        refused(
+            check("tk_ci", "pr-1234").await,
+            "subdomain_invalid",
+            "This token may only register subdomains matching ci-*",
+        );
+        assert_eq!(check("tk_ci", "ci-1234").await["available"], true);
+        refused(check("tk_alice", "staging").await, "subdomain_taken", "Subdomain 'staging' is reserved");
+
+        // A name is available to the token whose tunnel has it, which would replace it
+        let (_mine, reply) = register(&url, "tk_alice", "mine").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        assert_eq!(check("tk_alice", "mine").await["available"], true);
+        refused(check("tk_bob", "mine").await, "subdomain_taken", "Subdomain 'mine' is already in use");
+
+        let (_first, _) = register(&url, "tk_dave", "app-one").await;
+        let (_second, _) = register(&url, "tk_dave", "app-two").await;
+        refused(
+            check("tk_dave", "app-three").await,
+            "tunnel_limit_reached",
+            "This token already has 2 tunnels connected, the most it may have",
+        );
+
+        let (_last, _) = register(&url, "tk_bob", "last").await;
+        refused(
+            check("tk_alice", "pr-1234").await,
+            "tunnel_limit_reached",
+            "The server has reached its maximum number of tunnels",
+        );
+        // Nothing was registered along the way
+        assert_eq!(state.registry.count(), 4);
+
+        let response = client.post(format!("{}/_check/registration", base)).json(&serde_json::json!({ "subdomain": "pr-1234" })).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let response = client.post(format!("{}/_check/registration", base)).bearer_auth("tk_alice").body("{}").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_registration_attempts_limited_per_ip() {
+        let (url, _state) = start_server().await;
+        async fn refused(url: &str) {
+            match tokio_tungstenite::connect_async(url).await {
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+                    assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "6");
+                }
+                other => panic!("expected 429 before the upgrade, got {:?}", other.map(|(_, r)| r.status())),
+            }
+        }
+
+        let mut tunnels = Vec::new();
+        for i in 0..10 {
+            let (ws, reply) = register(&url, "tk_alice", &format!("app-{}", i)).await;
+            assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+            tunnels.push(ws);
+        }
+        refused(&url).await;
+
+        // Failed token checks use up the allowance faster
+        let (url, _state) = start_server().await;
+        for _ in 0..2 {
+            let (_ws, reply) = register(&url, "tk_nobody", "myapp").await;
+            assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidToken, .. }), "{:?}", reply);
+        }
+        refused(&url).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_charged_to_the_forwarded_client() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (url, _state) = start_server_with("trusted_proxies = [\"127.0.0.1/32\"]", "").await;
+        let connect = |visitor: &'static str| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            request.headers_mut().insert("x-forwarded-for", visitor.parse().unwrap());
+            tokio_tungstenite::connect_async(request)
+        };
+
+        // Two wrong tokens through the proxy use up the guesser's allowance...
+        for _ in 0..2 {
+            let (mut ws, _) = connect("198.51.100.7").await.unwrap();
+            ws.send(WsMessage::Text(register_message("tk_nobody", "myapp").to_json().unwrap())).await.unwrap();
+            let reply = ws.next().await.unwrap().unwrap();
+            let reply = ServerMessage::from_json(reply.to_text().unwrap()).unwrap();
+            assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidToken, .. }), "{:?}", reply);
+        }
+        match connect("198.51.100.7").await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+            }
+            other => panic!("expected 429 before the upgrade, got {:?}", other.map(|(_, r)| r.status())),
+        }
+        // ...and not the proxy's, so everyone else behind it still gets in
+        let (_ws, reply) = send_register(&url, register_message("tk_alice", "myapp")).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        assert!(connect("198.51.100.8").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_client_then_closes() {
+        let (url, state) = start_server().await;
+        let (mut ws, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // Wait until the handler is in its main loop and listening for shutdown
+        while state.shutdown_tx.receiver_count() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        state.shutdown_tx.send(()).unwrap();
+
+        let message = loop {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(2), ws.next())
+                .await
+                .expect("no Shutdown message")
+                .unwrap()
+                .unwrap();
+            if let WsMessage::Text(text) = msg {
+                if let Ok(ServerMessage::Shutdown { message }) = ServerMessage::from_json(&text) {
+                    break message;
+                }
+            }
+        };
+        assert!(message.contains("shutting down"), "{}", message);
+        assert!(state.registry.get("myapp").is_some(), "tunnel kept during the drain");
+
+        // After the drain period the connection is closed and the tunnel removed
+        tokio::time::timeout(SHUTDOWN_DRAIN + std::time::Duration::from_secs(2), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_close() {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("connection not closed after drain");
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while state.registry.get("myapp").is_some() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("tunnel not deregistered");
+    }
+
+    #[tokio::test]
+    async fn test_client_that_stops_pinging_is_deregistered() {
+        let mut config = test_config("", "");
+        config.limits.ping_timeout_secs = 1;
+        let (url, state) = start_server_with_config(config, None).await;
+        let (mut ws, reply) = register(&url, "tk_alice", "myapp").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        // A client from before pings existed never sends one
+        let (_legacy, reply) = register(&url, "tk_bob", "legacy").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // Pinging past the timeout keeps the tunnel, and every ping is answered
+        let ping = WsMessage::Text(ClientMessage::Ping { keep_alive: false }.to_json().unwrap());
+        let mut pongs = 0;
+        for _ in 0..6 {
+            ws.send(ping.clone()).await.unwrap();
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(300);
+            while let Ok(Some(Ok(msg))) = tokio::time::timeout_at(deadline, ws.next()).await {
+                if let WsMessage::Text(text) = msg {
+                    if matches!(ServerMessage::from_json(&text), Ok(ServerMessage::Pong)) {
+                        pongs += 1;
+                    }
+                }
+            }
+        }
+        assert!(pongs >= 5, "only {} pongs", pongs);
+        assert!(state.registry.get("myapp").is_some(), "pinging tunnel was dropped");
+
+        // Going quiet (e.g. a NAT dropped the mapping) gets it removed within the window
+        tokio::time::timeout(std::time::Duration::from_secs(3), async {
+            while state.registry.get("myapp").is_some() {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("silent tunnel not deregistered");
+        drop(ws);
+        assert!(state.registry.get("legacy").is_some(), "tunnel that never pinged was dropped");
+    }
+
+    /// Read until the server warns the tunnel is idle, returning the seconds it has left
+    async fn wait_for_idle_warning(
+        ws: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    ) -> u64 {
+        loop {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(3), ws.next())
+                .await
+                .expect("no idle warning")
+                .unwrap()
+                .unwrap();
+            if let WsMessage::Text(text) = msg {
+                if let Ok(ServerMessage::IdleWarning { disconnect_in_secs }) = ServerMessage::from_json(&text) {
+                    return disconnect_in_secs;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_warning_and_keep_alive() {
+        let idle_timeout = std::time::Duration::from_secs(2);
+        let (url, state) = start_server_with_limits("idle_tunnel_timeout = 2").await;
+        let registered_at = std::time::Instant::now();
+        let (mut allowed, _) = register(&url, "tk_carol", "allowed").await;
+        let (mut refused, _) = register(&url, "tk_alice", "refused").await;
+
+        let keep_alive = WsMessage::Text(ClientMessage::Ping { keep_alive: true }.to_json().unwrap());
+        for ws in [&mut allowed, &mut refused] {
+            let disconnect_in_secs = wait_for_idle_warning(ws).await;
+            let warned_after = registered_at.elapsed();
+            assert!(warned_after >= idle_timeout.mul_f64(IDLE_WARNING_AT), "warned after {:?}", warned_after);
+            assert!(warned_after < idle_timeout, "warned after {:?}", warned_after);
+            assert!(disconnect_in_secs <= 1, "{}s left", disconnect_in_secs);
+            ws.send(keep_alive.clone()).await.unwrap();
+        }
+
+        // Past the idle timeout, only the token allowed to keep tunnels alive still has one
+        tokio::time::sleep_until((registered_at + idle_timeout + std::time::Duration::from_millis(500)).into()).await;
+        crate::server::remove_idle_tunnels(&state.registry, idle_timeout);
+        assert!(state.registry.get("allowed").is_some(), "keep-alive didn't count as activity");
+        assert!(state.registry.get("refused").is_none(), "keep-alive counted for a token without it");
+        assert_eq!(state.registry.count_for_token(&TokenSecret::new("tk_carol")), 1);
+        assert_eq!(state.registry.count_for_token(&TokenSecret::new("tk_alice")), 0);
+    }
+
+    /// Serve `app` through a tunnel registered as `subdomain`, returning the server's
+    /// base URL for plain HTTP requests
+    async fn start_tunnel(url: &str, state: &ServerState, subdomain: &str, app: axum::Router) -> String {
+        start_stoppable_tunnel(url, state, subdomain, app, CancellationToken::new()).await.0
+    }
+
+    /// Like `start_tunnel`, with the client disconnecting once `shutdown` is cancelled
+    async fn start_stoppable_tunnel(
+        url: &str,
+        state: &ServerState,
+        subdomain: &str,
+        app: axum::Router,
+        shutdown: CancellationToken,
+    ) -> (String, Arc<SessionStats>, tokio::task::JoinHandle<Result<TunnelEnd>>) {
+        start_tunnel_as(url, state, "tk_alice", subdomain, app, shutdown).await
+    }
+
+    /// Like `start_stoppable_tunnel`, registering with `token`
+    async fn start_tunnel_as(
+        url: &str,
+        state: &ServerState,
+        token: &str,
+        subdomain: &str,
+        app: axum::Router,
+        shutdown: CancellationToken,
+    ) -> (String, Arc<SessionStats>, tokio::task::JoinHandle<Result<TunnelEnd>>) {
+        let stats = Arc::new(SessionStats::new());
+        start_tunnel_with_stats(url, state, token, subdomain, app, stats, shutdown).await
+    }
+
+    /// Like `start_tunnel_as`, counting the session in `stats`
+    async fn start_tunnel_with_stats(
+        url: &str,
+        state: &ServerState,
+        token: &str,
+        subdomain: &str,
+        app: axum::Router,
+        stats: Arc<SessionStats>,
+        shutdown: CancellationToken,
+    ) -> (String, Arc<SessionStats>, tokio::task::JoinHandle<Result<TunnelEnd>>) {
+        let (ws, reply) = register(url, token, subdomain).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let client = serve_tunnel(ws, app, stats.clone(), shutdown).await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        (base, stats, client)
+    }
+
+    /// Forward a registered tunnel's requests to `app`
+    async fn serve_tunnel(
+        ws: ClientWs,
+        app: axum::Router,
+        stats: Arc<SessionStats>,
+        shutdown: CancellationToken,
+    ) -> tokio::task::JoinHandle<Result<TunnelEnd>> {
+        use crate::expose::forwarder::RequestLog;
+        use crate::expose::tunnel::{run_tunnel, LocalService};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        tokio::spawn(run_tunnel(
+            ws,
+            LocalService::Http { addr: local_addr, host: None, tls: None, recording: Default::default() },
+            Duration::from_secs(5),
+            Duration::from_secs(30),
+            false,
+            RequestLog::new(true, &[]),
+            stats,
+            shutdown,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_token_request_policy() {
+        let (url, state) = start_server_with_limits(
+            "[tokens.tk_guest]\nstrip_request_headers = [\"authorization\"]\nallowed_methods = [\"GET\"]",
+        )
+        .await;
+        let echo = |headers: axum::http::HeaderMap| async move {
+            headers.get("authorization").map_or("none".to_string(), |v| v.to_str().unwrap().to_string())
+        };
+        let app = || axum::Router::new().route("/", axum::routing::get(echo).post(echo));
+        let (base, _, _) = start_tunnel_as(&url, &state, "tk_guest", "guest", app(), CancellationToken::new()).await;
+        start_tunnel(&url, &state, "trusted", app()).await;
+        let client = reqwest::Client::new();
+        let send = |method: reqwest::Method, host: &str| {
+            client
+                .request(method, format!("{}/", base))
+                .header("host", host)
+                .bearer_auth("visitor-secret")
+                .send()
+        };
+
+        let response = send(reqwest::Method::GET, "guest.tunnel.example.com").await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "none");
+        let response = send(reqwest::Method::POST, "guest.tunnel.example.com").await.unwrap();
+        assert_eq!(response.status(), 405);
+
+        // Other tokens' tunnels are untouched
+        let response = send(reqwest::Method::POST, "trusted.tunnel.example.com").await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "Bearer visitor-secret");
+    }
+
+    #[tokio::test]
+    async fn test_closed_tunnels_disconnect_their_clients() {
+        let (url, state) = start_server().await;
+        let app = || axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        let (base, _, kicked) = start_stoppable_tunnel(&url, &state, "kicked", app(), CancellationToken::new()).await;
+        let (_, _, idle) = start_stoppable_tunnel(&url, &state, "idle", app(), CancellationToken::new()).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("{}/_admin/tunnels/kicked", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(state.registry.get("kicked").is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        crate::server::remove_idle_tunnels(&state.registry, Duration::from_millis(10));
+        assert!(state.registry.get("idle").is_none());
+
+        // Each client is told why and its connection closed, so it reconnects instead of
+        // waiting on a tunnel the server has forgotten
+        let wait = Duration::from_secs(10);
+        let end = tokio::time::timeout(wait, kicked).await.expect("client not disconnected").unwrap().unwrap();
+        assert_eq!(end, TunnelEnd::ServerShutdown("Disconnected by an admin".to_string()));
+        let end = tokio::time::timeout(wait, idle).await.expect("client not disconnected").unwrap().unwrap();
+        assert_eq!(end, TunnelEnd::ServerShutdown("Disconnected for being idle".to_string()));
+
+        // And reconnecting gets its name back
+        let (_ws, reply) = register(&url, "tk_alice", "kicked").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_response_keeps_tunnel_from_idling() {
+        let (url, state) = start_server().await;
+        // A chunk every 100ms for a second
+        let app = axum::Router::new().route(
+            "/stream",
+            axum::routing::get(|| async {
+                let chunks = futures::stream::unfold(0, |sent| async move {
+                    if sent == 10 {
+                        return None;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Some((Ok::<_, std::io::Error>(format!("chunk {}\n", sent)), sent + 1))
+                });
+                axum::body::Body::from_stream(chunks)
+            }),
+        );
+        let base = start_tunnel(&url, &state, "myapp", app).await;
+        let download = tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .get(format!("{}/stream", base))
+                .header("host", "myapp.tunnel.example.com")
+                .send()
+                .await
+                .unwrap();
+            response.text().await.unwrap()
+        });
+
+        // Idle means no traffic, not no new requests
+        let idle_timeout = Duration::from_millis(300);
+        while !download.is_finished() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            crate::server::remove_idle_tunnels(&state.registry, idle_timeout);
+            assert!(state.registry.get("myapp").is_some(), "streaming tunnel removed as idle");
+        }
+        assert!(download.await.unwrap().ends_with("chunk 9\n"));
+
+        tokio::time::sleep(idle_timeout + Duration::from_millis(50)).await;
+        crate::server::remove_idle_tunnels(&state.registry, idle_timeout);
+        assert!(state.registry.get("myapp").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_finishes_in_flight_requests() {
+        let (url, state) = start_server().await;
+        let (started_tx, mut started_rx) = mpsc::channel::<()>(1);
+        let app = axum::Router::new().route(
+            "/slow",
+            axum::routing::get(move || {
+                let started_tx = started_tx.clone();
+                async move {
+                    let _ = started_tx.send(()).await;
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    "done"
+                }
+            }),
+        );
+        let shutdown = CancellationToken::new();
+        let (base, stats, client) = start_stoppable_tunnel(&url, &state, "myapp", app, shutdown.clone()).await;
+
+        let http = reqwest::Client::new();
+        let request = tokio::spawn(http.get(format!("{}/slow", base)).header("host", "myapp.tunnel.example.com").send());
+        started_rx.recv().await.unwrap();
+        shutdown.cancel();
+
+        // The name is freed straight away, well before the request finishes
+        tokio::time::timeout(Duration::from_millis(300), async {
+            while state.registry.get("myapp").is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("tunnel not deregistered on Disconnect");
+
+        // ...while the request in flight still gets its response
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let end = tokio::time::timeout(Duration::from_secs(5), client).await.expect("client didn't stop").unwrap();
+        assert_eq!(end.unwrap(), TunnelEnd::Disconnected);
+        assert_eq!(stats.streams(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_proxied_requests() {
+        let (url, state) = start_server_with_limits("[metrics]\nenabled = true").await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        let get = |host: &'static str, path: &'static str| {
+            client.get(format!("{}{}", base, path)).header("host", host).send()
+        };
+        let scrape = || async {
+            let response = get("tunnel.example.com", "/metrics").await.unwrap();
+            assert_eq!(response.status(), 200);
+            response.text().await.unwrap()
+        };
+
+        let before = scrape().await;
+        assert!(before.contains("\nloophole_tunnels 0\n"), "{}", before);
+
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        start_tunnel(&url, &state, "myapp", app).await;
+
+        let response = get("myapp.tunnel.example.com", "/").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        let after = scrape().await;
+        for expected in [
+            "\nloophole_tunnels 1\n",
+            "\nloophole_tunnel_registrations_total 1\n",
+            "\nloophole_tunnel_requests_total{subdomain=\"myapp\"} 1\n",
+            "\nloophole_responses_total{status=\"200\"} 1\n",
+            "\nloophole_response_bytes_total 5\n",
+        ] {
+            assert!(after.contains(expected), "missing {:?} in:\n{}", expected, after);
+        }
+
+        // Tunnel subdomains' /metrics paths are still proxied
+        let response = get("myapp.tunnel.example.com", "/metrics").await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_serves_static_files_through_tunnel() {
+        use crate::expose::forwarder::RequestLog;
+        use crate::expose::static_files::StaticFiles;
+        use crate::expose::tunnel::{run_tunnel, LocalService};
+
+        let (url, state) = start_server_with_limits("").await;
+        let dir = std::env::temp_dir().join(format!("loophole-serve-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>hi</h1>").unwrap();
+        let files = Arc::new(StaticFiles::new(&dir, false).unwrap());
+
+        let (ws, reply) = register(&url, "tk_alice", "site").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        tokio::spawn(run_tunnel(
+            ws,
+            LocalService::Files(files),
+            Duration::from_secs(5),
+            Duration::from_secs(30),
+            false,
+            RequestLog::new(true, &[]),
+            Arc::new(SessionStats::new()),
+            CancellationToken::new(),
+        ));
+
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let response = client.get(format!("{}/", base)).header("host", "site.tunnel.example.com").send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+            assert_eq!(response.text().await.unwrap(), "<h1>hi</h1>");
+        }
+        let response = client.get(format!("{}/nope.css", base)).header("host", "site.tunnel.example.com").send().await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manifest_is_opt_in() {
+        let (url, state) = start_server_with_limits("").await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        let get = |host: &'static str| {
+            client.get(format!("{}/_loophole/manifest", base)).header("host", host).send()
+        };
+
+        let publishing = |subdomain: &str| {
+            let mut register = register_message("tk_alice", subdomain);
+            if let ClientMessage::Register {
+                ref mut service_name,
+                ref mut service_version,
+                ref mut publish_manifest,
+                ref mut client_version,
+                ..
+            } = register
+            {
+                *service_name = Some("web\u{7}app".to_string());
+                *service_version = Some("1.4.2".to_string());
+                *publish_manifest = true;
+                *client_version = Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)".to_string());
+            }
+            register
+        };
+        let (_ws, reply) = send_register(&url, publishing("preview")).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        let response = get("preview.tunnel.example.com").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let manifest: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(manifest["subdomain"], "preview");
+        assert_eq!(manifest["url"], "http://preview.tunnel.example.com");
+        assert_eq!(manifest["service_name"], "webapp");
+        assert_eq!(manifest["service_version"], "1.4.2");
+        assert!(manifest["uptime_secs"].is_u64());
+        assert_eq!(manifest["basic_auth"], false);
+        let fields: Vec<&String> = manifest.as_object().unwrap().keys().collect();
+        assert_eq!(fields, ["basic_auth", "service_name", "service_version", "subdomain", "uptime_secs", "url"]);
+
+        // A protected tunnel's manifest is behind its password too
+        let mut guarded = publishing("guarded");
+        if let ClientMessage::Register { ref mut basic_auth, .. } = guarded {
+            *basic_auth = Some("alice:s3cret".to_string());
+        }
+        let (_guarded_ws, reply) = send_register(&url, guarded).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        assert_eq!(get("guarded.tunnel.example.com").await.unwrap().status(), 401);
+        let manifest: serde_json::Value = client
+            .get(format!("{}/_loophole/manifest", base))
+            .header("host", "guarded.tunnel.example.com")
+            .basic_auth("alice", Some("s3cret"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(manifest["basic_auth"], true);
+
+        // Maintenance shows, and the manifest is still served while the tunnel is paused
+        let mut nightly = publishing("nightly");
+        if let ClientMessage::Register { ref mut pause_schedule, .. } = nightly {
+            *pause_schedule = Some("0 2 * * * for 30m".to_string());
+        }
+        let (_nightly_ws, reply) = send_register(&url, nightly).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let manifest: serde_json::Value = get("nightly.tunnel.example.com").await.unwrap().json().await.unwrap();
+        assert_eq!(manifest["pause_schedule"], "0 2 * * * for 30m");
+        assert!(manifest.get("paused_until").is_none());
+        let until = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        state.registry.get("nightly").unwrap().set_paused_until(Some(until));
+        let response = get("nightly.tunnel.example.com").await.unwrap();
+        assert_eq!(response.status(), 200);
+        let manifest: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(manifest["paused_until"], 2_000_000_000u64);
+
+        // Without the flag the path is still the server's, so the app can't fake a manifest
+        let app = axum::Router::new().route("/_loophole/manifest", axum::routing::get(|| async { "from the app" }));
+        start_tunnel(&url, &state, "private", app).await;
+        let response = get("private.tunnel.example.com").await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_ne!(response.text().await.unwrap(), "from the app");
+    }
+
+    #[tokio::test]
+    async fn test_admin_list_shows_client_details() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+
+        let mut current = register_message("tk_alice", "listed");
+        if let ClientMessage::Register { ref mut client_version, .. } = current {
+            *client_version = Some("0.1.0 (1a2b3c4d5e6f 2026-10-17)".to_string());
+        }
+        let (_ws, reply) = send_register(&url, current).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let (_old_ws, reply) = register(&url, "tk_bob", "older").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        let list: serde_json::Value = reqwest::Client::new()
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let tunnels = list["tunnels"].as_array().unwrap();
+        let listed = tunnels.iter().find(|t| t["subdomain"] == "listed").unwrap();
+        assert_eq!(listed["client_ip"], "127.0.0.1");
+        assert_eq!(listed["client_version"], "0.1.0 (1a2b3c4d5e6f 2026-10-17)");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(now.abs_diff(listed["connected_at"].as_u64().unwrap()) < 60);
+
+        let older = tunnels.iter().find(|t| t["subdomain"] == "older").unwrap();
+        assert_eq!(older["client_ip"], "127.0.0.1");
+        assert!(older.get("client_version").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_tunnel_listing_etag_and_long_poll() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+        let list = |query: &str| {
+            client
+                .get(format!("{}/_admin/tunnels{}", base, query))
+                .bearer_auth("tk_admin")
+                .send()
+        };
+
+        let response = list("").await.unwrap();
+        let etag = response.headers()[reqwest::header::ETAG].clone();
+        let listing: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(listing["count"], 0);
+        let generation = listing["generation"].as_u64().unwrap();
+
+        // An unchanged listing is a 304
+        let response = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .header(reqwest::header::IF_NONE_MATCH, etag.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[reqwest::header::ETAG], etag);
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        // A poller waiting on the current generation wakes when a tunnel registers
+        let started = Instant::now();
+        let poll = tokio::spawn(list(&format!("?since={}", generation)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!poll.is_finished());
+        let (_ws, reply) = register(&url, "tk_alice", "polled").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let response = poll.await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_ne!(response.headers()[reqwest::header::ETAG], etag);
+        let listing: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(listing["tunnels"][0]["subdomain"], "polled");
+        assert!(listing["generation"].as_u64().unwrap() > generation);
+
+        // A poller that's behind gets the listing straight away
+        let response = tokio::time::timeout(Duration::from_secs(5), list(&format!("?since={}", generation)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap()["count"], 1);
+
+        // Time passing alone (idle and uptime seconds) isn't a change
+        let etag = list("").await.unwrap().headers()[reqwest::header::ETAG].clone();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .header(reqwest::header::IF_NONE_MATCH, etag.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304);
+        let listing: serde_json::Value = list("").await.unwrap().json().await.unwrap();
+        assert!(listing["tunnels"][0]["idle_secs"].as_u64().unwrap() >= 1);
+
+        let response = list("?since=soon").await.unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_admin_manages_tokens() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let client = reqwest::Client::new();
+
+        // A created token can register straight away
+        let response = client
+            .post(format!("{}/_admin/tokens", base))
+            .bearer_auth("tk_admin")
+            .json(&serde_json::json!({ "max_tunnels": 1 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        let created: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(created["saved"], false);
+        assert_eq!(created["max_tunnels"], 1);
+        let (_ws, reply) = register(&url, created["token"].as_str().unwrap(), "fresh").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tokens", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list["count"], 6);
+        let listed = list["tokens"].as_array().unwrap().iter().find(|t| t["token"] == created["token"]).unwrap();
+        assert_eq!(listed["tunnels"], 1);
+
+        // Revoking disconnects the token's tunnels and refuses new ones
+        let (_bob, reply) = register(&url, "tk_bob", "bobs-app").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let response = client
+            .delete(format!("{}/_admin/tokens/tk_bob", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let revoked: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(revoked["tunnels_disconnected"], 1);
+        assert!(state.registry.get("bobs-app").is_none());
+        let (_bob, reply) = register(&url, "tk_bob", "bobs-app").await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidToken, .. }),
+            "{:?}",
+            reply
+        );
+
+        let status = |path: &str, body: Option<serde_json::Value>| {
+            let request = match body {
+                Some(body) => client.post(format!("{}{}", base, path)).json(&body),
+                None => client.delete(format!("{}{}", base, path)),
+            };
+            async move { request.bearer_auth("tk_admin").send().await.unwrap().status() }
+        };
+        assert_eq!(status("/_admin/tokens/tk_bob", None).await, reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(status("/_admin/tokens/tk_admin", None).await, reqwest::StatusCode::CONFLICT);
+        let unknown_field = serde_json::json!({ "admn": true });
+        assert_eq!(status("/_admin/tokens", Some(unknown_field)).await, reqwest::StatusCode::BAD_REQUEST);
+        let zero_weight = serde_json::json!({ "weight": 0 });
+        assert_eq!(status("/_admin/tokens", Some(zero_weight)).await, reqwest::StatusCode::BAD_REQUEST);
+
+        // Only admins may manage tokens
+        let response = client
+            .get(format!("{}/_admin/tokens", base))
+            .bearer_auth("tk_alice")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reports_token_usage() {
+        let (url, state) = start_server().await;
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        let shutdown = CancellationToken::new();
+        let (base, _, client_task) = start_stoppable_tunnel(&url, &state, "myapp", app, shutdown.clone()).await;
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let response = client.get(&base).header("host", "myapp.tunnel.example.com").send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "hello");
+        }
+        let usage = |path: String| {
+            let request = client.get(format!("{}{}", base, path)).bearer_auth("tk_admin");
+            async move {
+                let response = request.send().await.unwrap();
+                (response.status(), response.json::<serde_json::Value>().await.unwrap())
+            }
+        };
+
+        // Connected tunnels count before they're rolled up, by token or by id
+        let (status, live) = usage("/_admin/tokens/tk_alice/usage".to_string()).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert_eq!(live["id"], TokenId::of("tk_alice").to_string());
+        assert_eq!(live["total"]["requests"], 2);
+        assert!(live["total"]["bytes_out"].as_u64().unwrap() > 0, "{}", live);
+        assert_eq!(live["buckets"].as_array().unwrap().len(), 1);
+
+        // And what's counted stays once the tunnel has gone
+        shutdown.cancel();
+        client_task.await.unwrap().unwrap();
+        deregistered(&state, "myapp").await;
+        let (_, after) = usage(format!("/_admin/tokens/{}/usage", TokenId::of("tk_alice"))).await;
+        assert_eq!(after["total"]["requests"], 2);
+        assert_eq!(after["total"]["bytes_in"], live["total"]["bytes_in"]);
+
+        // Nothing outside the range asked for
+        let later = now_secs() + 3600;
+        let (_, empty) = usage(format!("/_admin/tokens/tk_alice/usage?from={}&to={}", later, later + 60)).await;
+        assert_eq!(empty["buckets"].as_array().unwrap().len(), 0);
+        assert_eq!(empty["total"]["requests"], 0);
+
+        let (status, _) = usage("/_admin/tokens/tk_alice/usage?from=10&to=5".to_string()).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+        let (status, _) = usage("/_admin/tokens/tk_alice/usage?from=soon".to_string()).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+        let (status, _) = usage("/_admin/tokens/tk_nobody/usage".to_string()).await;
+        assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_declared_values_are_cleaned_up() {
+        assert_eq!(declared(None), None);
+        assert_eq!(declared(Some("  ".to_string())), None);
+        assert_eq!(declared(Some(" api\n".to_string())).as_deref(), Some("api"));
+        assert_eq!(declared(Some("x".repeat(100))).unwrap().len(), MAX_DECLARED_LEN);
+    }
+
+    /// Collects formatted log output for assertions
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_requests_are_logged_and_counted() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::WARN)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let (url, state) = start_server_with_limits("[logging]\nslow_request_threshold_ms = 200").await;
+        let app = axum::Router::new()
+            .route("/fast", axum::routing::get(|| async { "fast" }))
+            .route(
+                "/slow",
+                axum::routing::get(|| async {
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                    "slow"
+                }),
+            );
+        let base = start_tunnel(&url, &state, "myapp", app).await;
+        let client = reqwest::Client::new();
+        for path in ["/fast", "/slow"] {
+            let response = client
+                .get(format!("{}{}", base, path))
+                .header("host", "myapp.tunnel.example.com")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        assert_eq!(state.metrics.slow_requests("myapp"), 1);
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let slow: Vec<_> = output.lines().filter(|line| line.contains("Slow request")).collect();
+        assert_eq!(slow.len(), 1, "{}", output);
+        assert!(slow[0].contains("WARN"), "{}", slow[0]);
+        assert!(slow[0].contains("path=/slow"), "{}", slow[0]);
+        assert!(slow[0].contains("subdomain=myapp"), "{}", slow[0]);
+        assert!(slow[0].contains("threshold_ms=200"), "{}", slow[0]);
+
+        // Turning the threshold off (as a reload would) stops the reports
+        state.slow_requests.set_threshold(0);
+        client
+            .get(format!("{}/slow", base))
+            .header("host", "myapp.tunnel.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(state.metrics.slow_requests("myapp"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flapping_client_is_counted_and_warned_about() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::WARN)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let (url, state) = start_server_with_limits("[logging]\nregistration_rate_warning = 3").await;
+        let (_ws, reply) = register(&url, "tk_wrong", "flappy").await;
+        assert!(matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidToken, .. }), "{:?}", reply);
+
+        // A client that connects, registers and drops straight away, over and over
+        for _ in 0..5 {
+            let (ws, reply) = register(&url, "tk_alice", "flappy").await;
+            assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+            drop(ws);
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while state.registry.get("flappy").is_some() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("tunnel not deregistered");
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.metrics.control_stats().control_connections_open > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("control connections not closed");
+
+        let stats = state.metrics.control_stats();
+        assert_eq!(stats.control_connections_opened, 6);
+        assert_eq!(stats.registrations, 5);
+        // Every registration after the first took back the name it had just left
+        assert_eq!(stats.reconnects, 4);
+        assert_eq!(state.metrics.registration_failures(ErrorCode::InvalidToken), 1);
+        assert!(stats.average_lifetime_secs.is_some());
+
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let summary: serde_json::Value = reqwest::Client::new()
+            .get(format!("{}/_admin/stats", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(summary["tunnels"], 0);
+        assert_eq!(summary["control_connections_opened"], 6);
+        assert_eq!(summary["reconnects"], 4);
+        assert_eq!(summary["registration_failures"]["invalid_token"], 1);
+        assert!(summary["average_lifetime_secs"].is_number(), "{}", summary);
+
+        // One warning for the minute, when it passed the threshold
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let bursts: Vec<_> = output.lines().filter(|line| line.contains("Registration burst")).collect();
+        assert_eq!(bursts.len(), 1, "{}", output);
+        assert!(bursts[0].contains("WARN"), "{}", bursts[0]);
+        assert!(bursts[0].contains("registrations=4"), "{}", bursts[0]);
+        assert!(bursts[0].contains("top_clients=127.0.0.1 (4)"), "{}", bursts[0]);
+    }
+
+    /// A port nothing is listening on right now, for a one-port `[tcp]` range
+    async fn free_port() -> u16 {
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_registration_errors() {
+        // Not enabled
+        let (url, _state) = start_server().await;
+        let (_ws, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, None).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::TcpUnavailable, .. }),
+            "{:?}",
+            reply
+        );
+
+        let port = free_port().await;
+        let (url, state) = start_server_with_limits(&format!("[tcp]\nport_range = \"{}-{}\"", port, port)).await;
+
+        let (_ws, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, Some(port.wrapping_sub(1))).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::TcpUnavailable, .. }),
+            "{:?}",
+            reply
+        );
+
+        // The range's only port is taken by the first tunnel
+        let (_first, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, None).await;
+        match reply {
+            ServerMessage::Registered { url, .. } => assert_eq!(url, format!("tcp://tunnel.example.com:{}", port)),
+            other => panic!("{:?}", other),
+        }
+        let (_ws, reply) = register_as(&url, "tk_alice", "otherdb", Protocol::Tcp, Some(port)).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::PortUnavailable, .. }),
+            "{:?}",
+            reply
+        );
+        assert!(state.registry.get("otherdb").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_tcp_tunnel_gets_its_port_back() {
+        let port = free_port().await;
+        let (url, state) = start_server_with_limits(&format!("[tcp]\nport_range = \"{}-{}\"", port, port)).await;
+        let (mut stale, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, Some(port)).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // The stale tunnel still listens on the port when its client comes back for it
+        let (_fresh, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, Some(port)).await;
+        match reply {
+            ServerMessage::Registered { url, .. } => assert_eq!(url, format!("tcp://tunnel.example.com:{}", port)),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(shutdown_message(&mut stale).await, REPLACED_MESSAGE);
+        assert_eq!(state.registry.get("mydb").unwrap().tcp_port, Some(port));
+
+        // Other tokens don't get the port that way
+        let (_other, reply) = register_as(&url, "tk_bob", "mydb", Protocol::Tcp, Some(port)).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::PortUnavailable, .. }),
+            "{:?}",
+            reply
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_tunnel_copies_bytes_both_ways() {
+        use crate::expose::forwarder::RequestLog;
+        use crate::expose::tunnel::{run_tunnel, LocalService};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let port = free_port().await;
+        let (url, state) = start_server_with_limits(&format!(
+            "idle_tunnel_timeout_secs = 1\n[tcp]\nport_range = \"{}-{}\"",
+            port, port
+        ))
+        .await;
+
+        // A local service that isn't HTTP: it greets, then echoes in upper case
+        let service = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = service.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    conn.write_all(b"HELLO\n").await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = conn.read(&mut buf).await {
+                        conn.write_all(&buf[..n].to_ascii_uppercase()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let (ws, reply) = register_as(&url, "tk_alice", "mydb", Protocol::Tcp, None).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        tokio::spawn(run_tunnel(
+            ws,
+            LocalService::Tcp { addr: local_addr },
+            Duration::from_secs(5),
+            Duration::from_secs(30),
+            false,
+            RequestLog::new(true, &[]),
+            Arc::new(SessionStats::new()),
+            CancellationToken::new(),
+        ));
+
+        let mut visitor = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut greeting = [0u8; 6];
+        visitor.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"HELLO\n");
+        visitor.write_all(b"select 1;").await.unwrap();
+        let mut reply = [0u8; 9];
+        visitor.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"SELECT 1;");
+
+        // An open connection keeps the tunnel from being idle, however quiet it is
+        let tunnel = state.registry.get("mydb").unwrap();
+        assert_eq!(tunnel.tcp_port, Some(port));
+        assert_eq!(tunnel.request_count.load(std::sync::atomic::Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        crate::server::remove_idle_tunnels(&state.registry, Duration::from_millis(10));
+        assert!(state.registry.get("mydb").is_some(), "removed while a connection was open");
+
+        // HTTP requests don't reach it
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let response = reqwest::Client::new()
+            .get(&base)
+            .header("host", "mydb.tunnel.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        drop(visitor);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tunnel.bytes_out.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection totals not recorded");
+        assert_eq!(tunnel.bytes_in.load(std::sync::atomic::Ordering::Relaxed), 9);
+        assert_eq!(tunnel.bytes_out.load(std::sync::atomic::Ordering::Relaxed), 15);
+
+        // Once idle and deregistered, the port is freed for other tunnels
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        crate::server::remove_idle_tunnels(&state.registry, Duration::from_millis(10));
+        assert!(state.registry.get("mydb").is_none());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tokio::net::TcpListener::bind(("0.0.0.0", port)).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("TCP port still in use after deregistration");
+    }
+
+    #[tokio::test]
+    async fn test_connector_reaches_tcp_tunnel_over_websocket() {
+        use crate::expose::connect::Connector;
+        use crate::expose::forwarder::RequestLog;
+        use crate::expose::tunnel::{run_tunnel, LocalService};
+        use crate::proto::transport::CONNECT_PATH;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let port = free_port().await;
+        let (url, state) = start_server_with_limits(&format!("[tcp]\nport_range = \"{}-{}\"", port, port)).await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+
+        // A service that speaks first, like SSH
+        let service = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = service.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    conn.write_all(b"SSH-2.0-test\n").await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = conn.read(&mut buf).await {
+                        conn.write_all(&buf[..n].to_ascii_uppercase()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut shared = register_message("tk_alice", "myssh");
+        if let ClientMessage::Register { ref mut protocol, ref mut share_key, .. } = shared {
+            *protocol = Protocol::Tcp;
+            *share_key = Some("sk_secret".to_string());
+        }
+        let (ws, reply) = send_register(&url, shared).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        tokio::spawn(run_tunnel(
+            ws,
+            LocalService::Tcp { addr: local_addr },
+            Duration::from_secs(5),
+            Duration::from_secs(30),
+            false,
+            RequestLog::new(true, &[]),
+            Arc::new(SessionStats::new()),
+            CancellationToken::new(),
+        ));
+        let (_http, reply) = register(&url, "tk_alice", "web").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+
+        // Both hops: visitor -> connector -> server -> tunnel client -> service
+        for key in ["sk_secret", "tk_alice"] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let connector_addr = listener.local_addr().unwrap();
+            let connector = Arc::new(Connector::new(base.clone(), "myssh".to_string(), key.to_string(), Default::default()));
+            tokio::spawn(connector.serve(listener));
+
+            let mut visitor = tokio::net::TcpStream::connect(connector_addr).await.unwrap();
+            let mut banner = [0u8; 13];
+            visitor.read_exact(&mut banner).await.unwrap();
+            assert_eq!(&banner, b"SSH-2.0-test\n");
+            visitor.write_all(b"hello").await.unwrap();
+            let mut reply = [0u8; 5];
+            visitor.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"HELLO");
+        }
+        let tunnel = state.registry.get("myssh").unwrap();
+        assert_eq!(tunnel.request_count.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        // Refused before upgrading
+        let client = reqwest::Client::new();
+        let status = |subdomain: &str, key: &str| {
+            let request = client.get(format!("{}{}/{}", base, CONNECT_PATH, subdomain)).bearer_auth(key);
+            async move { request.send().await.unwrap().status() }
+        };
+        assert_eq!(status("myssh", "tk_bob").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("myssh", "sk_wrong").await, reqwest::StatusCode::UNAUTHORIZED);
+        // Nor is there any telling which tunnels exist; HTTP tunnels aren't reachable
+        // this way either
+        assert_eq!(status("missing", "tk_alice").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("web", "tk_alice").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("myssh", "sk_secret").await, reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_windows_pause_tunnels() {
+        let (url, state) = start_server_with_limits("[maintenance]\nwindows = [\"* * * * * for 1m\"]").await;
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        let base = start_tunnel(&url, &state, "paused", app).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/", base))
+            .header("host", "paused.tunnel.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key("retry-after"));
+        assert!(response.text().await.unwrap().contains("scheduled maintenance"));
+
+        let list: serde_json::Value = reqwest::Client::new()
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(list["tunnels"][0]["paused_until"].is_u64(), "{}", list);
+    }
+
+    #[tokio::test]
+    async fn test_pause_schedule_registration() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let with_schedule = |schedule: &str| {
+            let mut register = register_message("tk_alice", "nightly");
+            if let ClientMessage::Register { ref mut pause_schedule, .. } = register {
+                *pause_schedule = Some(schedule.to_string());
+            }
+            register
+        };
+
+        let (_ws, reply) = send_register(&url, with_schedule("0 2 * * * for 2 fortnights")).await;
+        match reply {
+            ServerMessage::Error { code, message } => {
+                assert_eq!(code, ErrorCode::InvalidOption);
+                assert!(message.contains("Invalid pause schedule"), "{}", message);
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        let (_ws, reply) = send_register(&url, with_schedule("0 2 * * * for 30m")).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let list: serde_json::Value = reqwest::Client::new()
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list["tunnels"][0]["pause_schedule"], "0 2 * * * for 30m");
+    }
+
+    #[tokio::test]
+    async fn test_stop_at_turns_visitors_away() {
+        use crate::expose::summary::BandwidthLimits;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (url, state) = start_server().await;
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                "x".repeat(4096)
+            }),
+        );
+        let limits = BandwidthLimits { warn_at: None, stop_at: Some(4096) };
+        let stats = Arc::new(SessionStats::new().with_limits(limits));
+        let (base, stats, _) =
+            start_tunnel_with_stats(&url, &state, "tk_alice", "capped", app, stats, CancellationToken::new()).await;
+        let get = || {
+            reqwest::Client::new()
+                .get(format!("{}/", base))
+                .header("host", "capped.tunnel.example.com")
+                .send()
+        };
+
+        let response = get().await.unwrap();
+        assert_eq!(response.text().await.unwrap().len(), 4096);
+        let (bytes_in, bytes_out) = stats.transferred();
+        assert!(bytes_in > 0 && bytes_out > 4096, "{} {}", bytes_in, bytes_out);
+        assert!(stats.stopped());
+
+        // Answered on the client's side, without reaching the local service
+        let response = get().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.text().await.unwrap().contains("bandwidth limit"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.transferred(), (bytes_in, bytes_out));
+
+        assert!(stats.resume());
+        assert_eq!(get().await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_rate_limited_per_tunnel() {
+        let (url, state) = start_server_with_limits("max_requests_per_second = 5").await;
+        let app = || axum::Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        let base = start_tunnel(&url, &state, "busy", app()).await;
+        start_tunnel(&url, &state, "quiet", app()).await;
+        let client = reqwest::Client::new();
+        let get = |subdomain: &str| {
+            client
+                .get(format!("{}/", base))
+                .header("host", format!("{}.tunnel.example.com", subdomain))
+                .send()
+        };
+
+        // With time standing still, nothing refills during the burst. A task that's
+        // always ready keeps the runtime from ever idling, which would move the paused
+        // clock on to the next timer.
+        tokio::time::pause();
+        let spinner = tokio::spawn(async {
+            loop {
+                tokio::task::yield_now().await;
+            }
+        });
+        let burst = futures::future::join_all((0..20).map(|_| get("busy"))).await;
+        let statuses: Vec<_> = burst.into_iter().map(|response| response.unwrap()).collect();
+        let throttled: Vec<_> = statuses.iter().filter(|r| r.status() == reqwest::StatusCode::TOO_MANY_REQUESTS).collect();
+        let ok = statuses.iter().filter(|r| r.status() == reqwest::StatusCode::OK).count();
+        assert_eq!(ok, 5);
+        assert_eq!(throttled.len(), 15);
+        assert_eq!(throttled[0].headers()["retry-after"], "1");
+
+        // Other tunnels have buckets of their own
+        assert_eq!(get("quiet").await.unwrap().status(), reqwest::StatusCode::OK);
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let busy = list["tunnels"].as_array().unwrap().iter().find(|t| t["subdomain"] == "busy").unwrap();
+        assert_eq!(busy["max_requests_per_second"], 5);
+        assert_eq!(busy["requests_throttled"], throttled.len() as u64);
+
+        // The bucket refills, a token every 200ms
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(get("busy").await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(get("busy").await.unwrap().status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        spinner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_proxied_over_http_only() {
+        use axum::extract::ws::{Message as AxumMessage, WebSocketUpgrade};
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (url, state) = start_server().await;
+        let app = axum::Router::new().route(
+            "/socket",
+            axum::routing::get(|ws: WebSocketUpgrade| async {
+                ws.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(AxumMessage::Text(text))) = socket.recv().await {
+                        let _ = socket.send(AxumMessage::Text(format!("echo: {}", text))).await;
+                    }
+                })
+            }),
+        );
+        let base = start_tunnel(&url, &state, "live", app).await;
+
+        let mut request = format!("{}/socket", base.replace("http://", "ws://")).into_client_request().unwrap();
+        request.headers_mut().insert("host", "live.tunnel.example.com".parse().unwrap());
+        let (mut ws, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.status(), 101);
+        ws.send(WsMessage::Text("hi".to_string())).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(reply, WsMessage::Text("echo: hi".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_limits_announced_and_throttling_notified() {
+        let (url, state) = start_server_with_limits("max_requests_per_second = 5\nmax_request_body = \"1MB\"").await;
+        let (mut ws, reply) = register(&url, "tk_alice", "busy").await;
+        match reply {
+            ServerMessage::Registered { limits, .. } => assert_eq!(
+                limits,
+                Some(TunnelLimits {
+                    max_rps: Some(5),
+                    max_concurrent: None,
+                    max_body_bytes: Some(1024 * 1024),
+                })
+            ),
+            other => panic!("expected Registered, got {:?}", other),
+        }
+
+        // One notice however many requests the stretch refuses
+        let tunnel = state.registry.get("busy").unwrap();
+        let refused = (0..20).filter_map(|_| tunnel.throttle()).count();
+        assert!(refused >= 10, "{} refused", refused);
+        let mut notices = Vec::new();
+        while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_millis(300), ws.next()).await {
+            if let WsMessage::Text(text) = msg {
+                if let Ok(ServerMessage::Throttled { until_ms }) = ServerMessage::from_json(&text) {
+                    notices.push(until_ms);
+                }
+            }
+        }
+        assert_eq!(notices.len(), 1, "{:?}", notices);
+        let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert!(notices[0] > now_ms && notices[0] <= now_ms + 1000, "{} {}", notices[0], now_ms);
+    }
+
+    #[tokio::test]
+    async fn test_motd_sent_on_registration() {
+        let (url, _state) = start_server_with(
+            "motd = \"Maintenance Saturday\"\nmotd_min_client_version = \"0.5.0\"\nmotd_outdated = \"Please upgrade\"",
+            "",
+        )
+        .await;
+        let register_as = |subdomain: &str, version: &str| {
+            let mut register = register_message("tk_alice", subdomain);
+            if let ClientMessage::Register { ref mut client_version, .. } = register {
+                *client_version = Some(version.to_string());
+            }
+            register
+        };
+
+        let (_current, reply) = send_register(&url, register_as("current", "0.5.1 (1a2b3c4d5e6f 2026-10-17)")).await;
+        assert!(
+            matches!(reply, ServerMessage::Registered { ref motd, .. } if motd.as_deref() == Some("Maintenance Saturday")),
+            "{:?}",
+            reply
+        );
+        let (_old, reply) = send_register(&url, register_as("old", "0.4.2")).await;
+        assert!(
+            matches!(reply, ServerMessage::Registered { ref motd, .. } if motd.as_deref() == Some("Please upgrade")),
+            "{:?}",
+            reply
+        );
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_challenges_visitors() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let with_auth = |credentials: &str| {
+            let mut register = register_message("tk_alice", "private");
+            if let ClientMessage::Register { ref mut basic_auth, .. } = register {
+                *basic_auth = Some(credentials.to_string());
+            }
+            register
+        };
+
+        let (_ws, reply) = send_register(&url, with_auth("no-colon")).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidOption, ref message } if message.contains("Invalid basic auth")),
+            "{:?}",
+            reply
+        );
+
+        let (ws, reply) = send_register(&url, with_auth("alice:s3cret")).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let app = axum::Router::new().fallback(|| async { "secret stuff" });
+        serve_tunnel(ws, app, Arc::new(SessionStats::new()), CancellationToken::new()).await;
+        let client = reqwest::Client::new();
+        let get = |path: &str| {
+            client
+                .get(format!("{}{}", base, path))
+                .header("host", "private.tunnel.example.com")
+        };
+
+        let response = get("/").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Basic realm=\"loophole\"");
+        assert!(!response.text().await.unwrap().contains("secret stuff"));
+
+        let response = get("/").basic_auth("alice", Some("wrong")).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = get("/").basic_auth("alice", Some("s3cret")).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "secret stuff");
+
+        // The server answers its own ACME challenges before this, so the path is no way
+        // around the password
+        let response = get("/.well-known/acme-challenge/abc").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list["tunnels"][0]["basic_auth"], true);
+    }
+
+    #[tokio::test]
+    async fn test_allow_ips_refuse_other_visitors() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let allowing = |subdomain: &str, allowed: &[&str]| {
+            let mut register = register_message("tk_alice", subdomain);
+            if let ClientMessage::Register { ref mut allow_ips, .. } = register {
+                *allow_ips = allowed.iter().map(ToString::to_string).collect();
+            }
+            register
+        };
+
+        let (_ws, reply) = send_register(&url, allowing("bad", &["203.0.113.0/33"])).await;
+        assert!(
+            matches!(reply, ServerMessage::Error { code: ErrorCode::InvalidOption, ref message } if message.contains("203.0.113.0/33")),
+            "{:?}",
+            reply
+        );
+
+        let app = || axum::Router::new().fallback(|| async { "demo" });
+        for (subdomain, allow_ips) in [("elsewhere", &["203.0.113.0/24", "2001:db8::/32"][..]), ("local", &["127.0.0.0/8"][..])] {
+            let (ws, reply) = send_register(&url, allowing(subdomain, allow_ips)).await;
+            assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+            serve_tunnel(ws, app(), Arc::new(SessionStats::new()), CancellationToken::new()).await;
+        }
+        let client = reqwest::Client::new();
+        let get = |subdomain: &str| {
+            client
+                .get(format!("{}/", base))
+                .header("host", format!("{}.tunnel.example.com", subdomain))
+                .send()
+        };
+
+        // The test client connects from 127.0.0.1
+        assert_eq!(get("elsewhere").await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        // ACME challenge paths are no way around the list
+        let response = client
+            .get(format!("{}/.well-known/acme-challenge/x", base))
+            .header("host", "elsewhere.tunnel.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+        let response = get("local").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "demo");
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let elsewhere = list["tunnels"].as_array().unwrap().iter().find(|t| t["subdomain"] == "elsewhere").unwrap();
+        assert_eq!(elsewhere["allow_ips"], serde_json::json!(["203.0.113.0/24", "2001:db8::/32"]));
+    }
+
+    #[tokio::test]
+    async fn test_path_mode_tunnels() {
+        let (url, state) = start_server().await;
+        let base = start_tunnel(&url, &state, "blog", axum::Router::new().fallback(|| async { "blog" })).await;
+        let mut register = register_message("tk_alice", "docs");
+        if let ClientMessage::Register { ref mut mode, .. } = register {
+            *mode = TunnelMode::Path;
+        }
+        let (ws, reply) = send_register(&url, register).await;
+        match reply {
+            ServerMessage::Registered { url, .. } => assert!(url.ends_with("://tunnel.example.com/t/docs/"), "{}", url),
+            other => panic!("{:?}", other),
+        }
+
+        // An app that assumes it's at the root of its own host
+        let app = axum::Router::new()
+            .route(
+                "/echo",
+                axum::routing::get(|uri: axum::http::Uri, headers: axum::http::HeaderMap| async move {
+                    let prefix = headers.get("x-forwarded-prefix").map_or("", |v| v.to_str().unwrap()).to_string();
+                    format!("{} {}", uri, prefix)
+                }),
+            )
+            .route(
+                "/login",
+                axum::routing::get(|| async {
+                    ([(axum::http::header::SET_COOKIE, "session=abc; Path=/; HttpOnly")], axum::response::Redirect::to("/dashboard"))
+                }),
+            )
+            .route("/away", axum::routing::get(|| async { axum::response::Redirect::to("http://tunnel.example.com/home") }))
+            .route("/github", axum::routing::get(|| async { axum::response::Redirect::to("https://github.com/login") }));
+        serve_tunnel(ws, app, Arc::new(SessionStats::new()), CancellationToken::new()).await;
+        let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+        let get = |host: &str, path: &str| client.get(format!("{}{}", base, path)).header("host", host).send();
+        let location = |response: &reqwest::Response| response.headers()["location"].to_str().unwrap().to_string();
+
+        // The prefix is stripped, keeping the query, and passed along as X-Forwarded-Prefix
+        let response = get("tunnel.example.com", "/t/docs/echo?page=2").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "/echo?page=2 /t/docs");
+
+        let response = get("tunnel.example.com", "/t/docs?page=2").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(location(&response), "/t/docs/?page=2");
+
+        // Redirects and cookies point back inside the tunnel
+        let response = get("tunnel.example.com", "/t/docs/login").await.unwrap();
+        assert!(response.status().is_redirection());
+        assert_eq!(location(&response), "/t/docs/dashboard");
+        assert_eq!(response.headers()["set-cookie"], "session=abc; Path=/t/docs; HttpOnly");
+        let response = get("tunnel.example.com", "/t/docs/away").await.unwrap();
+        assert_eq!(location(&response), "http://tunnel.example.com/t/docs/home");
+        let response = get("tunnel.example.com", "/t/docs/github").await.unwrap();
+        assert_eq!(location(&response), "https://github.com/login");
+
+        // Each tunnel is reachable only the way it asked for
+        assert_eq!(get("docs.tunnel.example.com", "/echo").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("tunnel.example.com", "/t/blog/").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("tunnel.example.com", "/t/nope/").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("elsewhere.example.org", "/t/docs/echo").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("blog.tunnel.example.com", "/").await.unwrap().text().await.unwrap(), "blog");
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let mode = |subdomain: &str| {
+            list["tunnels"].as_array().unwrap().iter().find(|t| t["subdomain"] == subdomain).unwrap()["mode"].clone()
+        };
+        assert_eq!(mode("docs"), "path");
+        assert_eq!(mode("blog"), serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_echo_streams_measure_round_trip() {
+        let (url, state) = start_server().await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+        let registering = |subdomain: &str, echo: bool| {
+            let mut register = register_message("tk_alice", subdomain);
+            if let ClientMessage::Register { ref mut echo_streams, .. } = register {
+                *echo_streams = echo;
+            }
+            register
+        };
+        let app = || axum::Router::new().fallback(|| async { "demo" });
+        for (subdomain, echo_streams) in [("probed", true), ("legacy", false)] {
+            let (ws, reply) = send_register(&url, registering(subdomain, echo_streams)).await;
+            assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+            serve_tunnel(ws, app(), Arc::new(SessionStats::new()), CancellationToken::new()).await;
+        }
+
+        // The first probe goes out as soon as the tunnel is up
+        let probed = state.registry.get("probed").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while probed.rtt.stats().smoothed.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no round trip measured");
+        assert_eq!(state.registry.get("legacy").unwrap().rtt.stats(), Default::default());
+
+        // Requests still get through alongside the echo stream, which isn't counted as one
+        let client = reqwest::Client::new();
+        let response = client.get(format!("{}/", base)).header("host", "probed.tunnel.example.com").send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "demo");
+        assert_eq!(probed.request_count.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let list: serde_json::Value = client
+            .get(format!("{}/_admin/tunnels", base))
+            .bearer_auth("tk_admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let tunnel = |subdomain: &str| list["tunnels"].as_array().unwrap().iter().find(|t| t["subdomain"] == subdomain).unwrap().clone();
+        assert!(tunnel("probed")["rtt_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(tunnel("probed")["rtt_probes_sent"], 1);
+        assert_eq!(tunnel("probed")["rtt_probes_lost"], 0);
+        assert!(tunnel("legacy").get("rtt_ms").is_none());
+        assert!(tunnel("legacy").get("rtt_probes_sent").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_long_urls_at_the_edge() {
+        let (url, state) = start_server_with_limits("max_request_line = \"40KB\"").await;
+        let echo = |uri: axum::http::Uri| async move { uri.to_string() };
+        let base = start_tunnel(&url, &state, "myapp", axum::Router::new().fallback(echo)).await;
+        let client = reqwest::Client::new();
+        let get = |target: String| {
+            client
+                .get(format!("{}{}", base, target))
+                .header("host", "myapp.tunnel.example.com")
+                .send()
+        };
+        let target = |len: usize| {
+            let mut target = "/cb//track/%2F%7e?x=%20+%2B&q=".to_string();
+            while target.len() < len {
+                target.push_str("%41b");
+            }
+            target
+        };
+
+        for len in [8 * 1024, 32 * 1024] {
+            let target = target(len);
+            let response = get(target.clone()).await.unwrap();
+            assert_eq!(response.status(), 200, "{} bytes", len);
+            assert_eq!(response.text().await.unwrap(), target);
+        }
+        let response = get(target(48 * 1024)).await.unwrap();
+        assert_eq!(response.status(), 414);
+        assert_eq!(response.text().await.unwrap(), "Request URL too long");
+    }
+
+    fn registering_with_aliases(token: &str, subdomain: &str, names: &[&str]) -> ClientMessage {
+        let mut register = register_message(token, subdomain);
+        if let ClientMessage::Register { ref mut aliases, .. } = register {
+            *aliases = names.iter().map(|alias| alias.to_string()).collect();
+        }
+        register
+    }
+
+    fn refused(reply: &ServerMessage) -> (ErrorCode, &str) {
+        match reply {
+            ServerMessage::Error { code, message } => (*code, message),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alias_conflicts() {
+        let (url, state) = start_server_with_limits("registrations_per_minute_per_ip = 0\n\n[registry.aliases]\ndocs = \"web\"\n").await;
+
+        let (first, reply) = send_register(&url, registering_with_aliases("tk_alice", "web", &["WWW-web"])).await;
+        match reply {
+            ServerMessage::Registered { alias_urls, .. } => {
+                assert_eq!(alias_urls.len(), 2, "{:?}", alias_urls);
+                assert!(alias_urls[0].contains("://docs.tunnel.example.com"), "{:?}", alias_urls);
+                assert!(alias_urls[1].contains("://www-web.tunnel.example.com"), "{:?}", alias_urls);
+            }
+            other => panic!("expected Registered, got {:?}", other),
+        }
+        assert!(state.registry.resolve("www-web").is_some_and(|t| t.subdomain == "web"));
+
+        // Another tunnel can't claim the alias, whichever token it has
+        for token in ["tk_bob", "tk_alice"] {
+            let (_ws, reply) = send_register(&url, registering_with_aliases(token, "other", &["www-web"])).await;
+            let (code, message) = refused(&reply);
+            assert_eq!(code, ErrorCode::SubdomainTaken);
+            assert_eq!(message, "Alias: Subdomain 'www-web' is already in use");
+        }
+        // Nor register it, or one of the server's, as its subdomain
+        for name in ["www-web", "docs"] {
+            let (_ws, reply) = register(&url, "tk_bob", name).await;
+            assert_eq!(refused(&reply).0, ErrorCode::SubdomainTaken, "{}", name);
+        }
+        // And an alias can't take a registered subdomain
+        let (_ws, reply) = send_register(&url, registering_with_aliases("tk_bob", "other", &["web"])).await;
+        assert_eq!(refused(&reply).0, ErrorCode::SubdomainTaken);
+
+        // Aliases are checked like names, before anything is taken
+        for (aliases, expected) in [
+            (&["other"][..], "given more than once"),
+            (&["a_b"][..], "Alias: Invalid subdomain"),
+            (&["alias-0"; MAX_ALIASES + 1][..], "at most"),
+        ] {
+            let (_ws, reply) = send_register(&url, registering_with_aliases("tk_bob", "other", aliases)).await;
+            let (code, message) = refused(&reply);
+            assert_eq!(code, ErrorCode::SubdomainInvalid);
+            assert!(message.contains(expected), "{}", message);
+        }
+        let mut path_tunnel = registering_with_aliases("tk_bob", "other", &["www-other"]);
+        if let ClientMessage::Register { ref mut mode, .. } = path_tunnel {
+            *mode = TunnelMode::Path;
+        }
+        let (_ws, reply) = send_register(&url, path_tunnel).await;
+        assert!(refused(&reply).1.contains("Only HTTP tunnels on a subdomain"));
+
+        // Aliases take the token's slots
+        let (_ws, reply) = send_register(&url, registering_with_aliases("tk_dave", "dave-app", &["dave-www", "dave-api"])).await;
+        assert_eq!(refused(&reply).0, ErrorCode::TunnelLimitReached);
+        assert!(state.registry.get("other").is_none());
+        assert!(state.registry.get("dave-app").is_none());
+
+        // Its aliases go when the tunnel does
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.registry.get("web").is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(state.registry.resolve("www-web").is_none());
+        let (_ws, reply) = register(&url, "tk_bob", "www-web").await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn test_aliases_and_hosts_in_any_case_reach_the_tunnel() {
+        let (url, state) = start_server_with_limits("[registry.aliases]\ndocs = \"web\"\n").await;
+        let (ws, reply) = send_register(&url, registering_with_aliases("tk_alice", "web", &["www-web"])).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        serve_tunnel(ws, app, Arc::new(SessionStats::new()), CancellationToken::new()).await;
+        let base = url.replace("ws://", "http://").replace(state.config.server.control_path(), "");
+
+        let client = reqwest::Client::new();
+        let hosts = ["web.tunnel.example.com", "Web.Tunnel.Example.com", "WWW-WEB.tunnel.example.com", "Docs.tunnel.example.com"];
+        for host in hosts {
+            let response = client.get(format!("{}/", base)).header("host", host).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "hello", "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificates_for_aliases() {
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let cert_manager = Arc::new(
+            CertManager::new(
+                store,
+                None,
+                Arc::new(ChallengeStore::new()),
+                FullDomain::new("tunnel.example.com"),
+                Arc::new(Metrics::new()),
+            )
+            .await
+            .unwrap(),
+        );
+        let install = |name: &str| {
+            let domain = format!("{}.tunnel.example.com", name);
+            let cert = acme::test_certificate(&domain, Duration::from_secs(90 * 24 * 3600));
+            cert_manager.install_cert(&FullDomain::from(domain), &cert.cert_pem, &cert.key_pem).unwrap();
+        };
+        install("app");
+        let limits = "[https]\nmanual_certs = true\n[registry.aliases]\ndocs = \"app\"\n";
+        let (url, _state) = start_server_with_certs("", limits, Some(cert_manager.clone())).await;
+
+        async fn cert_status(ws: &mut ClientWs) -> ServerMessage {
+            let message = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap().unwrap().unwrap();
+            ServerMessage::from_json(message.to_text().unwrap()).unwrap()
+        }
+
+        // The aliases, the server's included, need certificates of their own before
+        // the tunnel is ready
+        let (mut ws, reply) = send_register(&url, registering_with_aliases("tk_alice", "app", &["www-app"])).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        assert!(matches!(cert_status(&mut ws).await, ServerMessage::CertificateStatus { ready: false }));
+        drop(ws);
+
+        // The tunnel's own aliases are claimed for its token, like its name
+        let owner = |name: &str| cert_manager.owner(&format!("{}.tunnel.example.com", name)).map(|o| o.token_id);
+        assert_eq!(owner("app"), Some(TokenId::of("tk_alice")));
+        assert_eq!(owner("www-app"), Some(TokenId::of("tk_alice")));
+        assert_eq!(owner("docs"), None);
+
+        install("www-app");
+        install("docs");
+        let (mut ws, reply) = send_register(&url, registering_with_aliases("tk_alice", "app", &["www-app"])).await;
+        assert!(matches!(reply, ServerMessage::Registered { .. }), "{:?}", reply);
+        assert!(matches!(cert_status(&mut ws).await, ServerMessage::CertificateStatus { ready: true }));
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
     }
 }
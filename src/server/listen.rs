@@ -0,0 +1,51 @@
+//! Listening sockets for the server's ports, on `server.bind_address` or the per-port
+//! overrides
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, TcpListener};
+
+/// Pending connections the OS queues before they're accepted, as tokio uses
+const BACKLOG: i32 = 1024;
+
+/// A non-blocking listener on `addr`. On `::` it takes IPv4 connections as well where
+/// the OS allows it, rather than leaving that to a system setting.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.ip() == Ipv6Addr::UNSPECIFIED {
+        // Some systems (OpenBSD) only ever listen on IPv6 here; carry on if so
+        let _ = socket.set_only_v6(false);
+    }
+    // So a restarted server can listen again while old connections are in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unspecified_ipv6_takes_both_stacks() {
+        // Sandboxes without IPv6 can't run this
+        let Ok(listener) = bind("[::]:0".parse().unwrap()) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        std::net::TcpStream::connect(("::1", port)).unwrap();
+    }
+
+    #[test]
+    fn test_specific_address() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        std::net::TcpStream::connect(addr).unwrap();
+        assert!(bind(addr).is_err(), "a port in use is an error");
+    }
+}
@@ -0,0 +1,239 @@
+//! Recent server log events, kept in memory for `GET /_admin/logs` so they can be read
+//! without a shell on the server. A tracing layer copies every event the server logs
+//! into a bounded ring, and broadcasts it to anyone following along.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Events kept for a request that doesn't follow; older ones are dropped
+const RECENT_EVENTS: usize = 1000;
+
+/// Events queued for each follower. One that falls this far behind misses the oldest,
+/// and is told how many.
+const FOLLOW_BUFFER: usize = 256;
+
+/// Messages longer than this are cut short, so a few huge events can't hold the
+/// ring's memory
+const MAX_MESSAGE_BYTES: usize = 4096;
+
+/// One event as the server logged it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEvent {
+    /// Unix milliseconds
+    pub time: u64,
+    /// `ERROR`, `WARN`, `INFO`, `DEBUG` or `TRACE`
+    pub level: String,
+    /// The module that logged it
+    pub target: String,
+    pub message: String,
+    /// The event's other fields, e.g. `request_id`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl LogEvent {
+    /// Whether the event is at `max` or more severe. Events with a level that can't be
+    /// read always pass.
+    pub fn at_least(&self, max: Level) -> bool {
+        self.level.parse::<Level>().map_or(true, |level| level <= max)
+    }
+
+    /// Stands in for events a follower missed by reading too slowly
+    pub fn dropped(count: u64) -> Self {
+        Self {
+            time: now_millis(),
+            level: Level::WARN.to_string(),
+            target: module_path!().to_string(),
+            message: format!("{} events dropped, reading too slowly", count),
+            fields: BTreeMap::new(),
+        }
+    }
+}
+
+/// The last [`RECENT_EVENTS`] events, and a channel carrying each new one
+#[derive(Debug)]
+pub struct LogBuffer {
+    recent: Mutex<VecDeque<LogEvent>>,
+    capacity: usize,
+    tx: broadcast::Sender<LogEvent>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::with_capacity(RECENT_EVENTS)
+    }
+}
+
+impl LogBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            tx: broadcast::channel(FOLLOW_BUFFER).0,
+        }
+    }
+
+    pub fn push(&self, event: LogEvent) {
+        let Ok(mut recent) = self.recent.lock() else {
+            return;
+        };
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        // Sent under the lock, so `follow` sees each event once: kept or sent
+        let _ = self.tx.send(event.clone());
+        recent.push_back(event);
+    }
+
+    /// The kept events at `max` or more severe, oldest first
+    pub fn recent(&self, max: Level) -> Vec<LogEvent> {
+        self.recent
+            .lock()
+            .map(|recent| recent.iter().filter(|e| e.at_least(max)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The kept events at `max` or more severe, and a receiver for every event after them
+    pub fn follow(&self, max: Level) -> (Vec<LogEvent>, broadcast::Receiver<LogEvent>) {
+        let recent = self.recent.lock();
+        let rx = self.tx.subscribe();
+        let events = recent
+            .map(|recent| recent.iter().filter(|e| e.at_least(max)).cloned().collect())
+            .unwrap_or_default();
+        (events, rx)
+    }
+}
+
+/// Copies every event into a [`LogBuffer`]
+pub struct LogLayer {
+    buffer: Arc<LogBuffer>,
+}
+
+impl LogLayer {
+    pub fn new(buffer: Arc<LogBuffer>) -> Self {
+        Self { buffer }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        self.buffer.push(LogEvent {
+            time: now_millis(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: truncate(visitor.message),
+            fields: visitor.fields,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.insert(field.name().to_string(), truncate(value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let mut text = String::new();
+        let _ = write!(text, "{:?}", value);
+        if field.name() == "message" {
+            self.message = text;
+        } else {
+            self.fields.insert(field.name().to_string(), truncate(text));
+        }
+    }
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_MESSAGE_BYTES {
+        let mut end = MAX_MESSAGE_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+    }
+    text
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_layer_captures_events() {
+        let buffer = Arc::new(LogBuffer::with_capacity(3));
+        let subscriber = tracing_subscriber::registry().with(LogLayer::new(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("first");
+            tracing::warn!(request_id = "req_1", status = 502, "Tunnel {} is gone", "app");
+            tracing::debug!("third");
+            tracing::error!("{}", "é".repeat(MAX_MESSAGE_BYTES));
+        });
+
+        // The oldest fell out of the ring
+        let events = buffer.recent(Level::TRACE);
+        let messages: Vec<_> = events.iter().map(|e| e.message.chars().take(18).collect::<String>()).collect();
+        assert_eq!(messages, ["Tunnel app is gone", "third", "éééééééééééééééééé"]);
+        assert_eq!(events[0].level, "WARN");
+        assert_eq!(events[0].target, module_path!());
+        assert_eq!(events[0].fields["request_id"], "req_1");
+        assert_eq!(events[0].fields["status"], "502");
+        assert!(events[2].message.len() <= MAX_MESSAGE_BYTES + '…'.len_utf8());
+
+        let levels: Vec<_> = buffer.recent(Level::WARN).into_iter().map(|e| e.level).collect();
+        assert_eq!(levels, ["WARN", "ERROR"]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_gets_each_event_once() {
+        let buffer = LogBuffer::default();
+        let event = |message: &str, level: Level| LogEvent {
+            time: 0,
+            level: level.to_string(),
+            target: "loophole".to_string(),
+            message: message.to_string(),
+            fields: BTreeMap::new(),
+        };
+        buffer.push(event("before", Level::WARN));
+
+        let (recent, mut rx) = buffer.follow(Level::WARN);
+        buffer.push(event("after", Level::ERROR));
+        assert_eq!(recent, vec![event("before", Level::WARN)]);
+        assert_eq!(rx.recv().await.unwrap(), event("after", Level::ERROR));
+        assert!(rx.try_recv().is_err());
+
+        // A follower that falls behind misses the oldest
+        for n in 0..FOLLOW_BUFFER + 10 {
+            buffer.push(event(&n.to_string(), Level::INFO));
+        }
+        assert!(matches!(rx.recv().await, Err(broadcast::error::RecvError::Lagged(10))));
+        assert_eq!(rx.recv().await.unwrap().message, "10");
+    }
+}
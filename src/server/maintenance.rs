@@ -0,0 +1,234 @@
+//! Scheduled maintenance: during one of the server's `[maintenance]` windows, or one a
+//! client asked for with `--pause-schedule`, a tunnel answers with a maintenance page
+//! instead of proxying, and it resumes once the window ends. Windows are checked on
+//! every minute and as each tunnel registers.
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+use tracing::{debug, info};
+
+use super::config::MaintenanceConfig;
+use super::pages::PageVars;
+use super::registry::Registry;
+use super::tunnel::Tunnel;
+use crate::schedule::{self, Window};
+
+#[derive(Debug)]
+pub struct Maintenance {
+    /// Windows that pause every tunnel
+    windows: Vec<Window>,
+    time_zone: TimeZone,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> Result<Self> {
+        Ok(Self {
+            windows: config.windows.clone(),
+            time_zone: config.time_zone()?,
+        })
+    }
+
+    /// When the server's maintenance in progress at `now` ends, if there is any. The
+    /// same for every tunnel, so worked out once for all of them.
+    fn server_until(&self, now: Timestamp) -> Option<Timestamp> {
+        schedule::active_until(&self.windows, now, &self.time_zone)
+    }
+
+    /// When the maintenance `tunnel` is in at `now` ends, whether the window is the
+    /// server's (`server`, from [`Self::server_until`]) or the tunnel's own
+    fn paused_until(&self, tunnel: &Tunnel, now: Timestamp, server: Option<Timestamp>) -> Option<Timestamp> {
+        let Some(own) = &tunnel.pause_schedule else {
+            return server;
+        };
+        // Only the tunnel's own window needs looking at, unless one of them runs into
+        // the other
+        let until = server.max(own.active_until(now, &self.time_zone))?;
+        Some(schedule::extend(self.windows.iter().chain([own]), until, &self.time_zone))
+    }
+
+    /// Pause or resume `tunnel` as its windows say it should be at `now`. Returns
+    /// whether that changed anything.
+    pub fn update(&self, tunnel: &Tunnel, now: SystemTime) -> bool {
+        let Ok(now) = Timestamp::try_from(now) else {
+            return false;
+        };
+        self.apply(tunnel, now, self.server_until(now))
+    }
+
+    /// [`Self::update`], with the server's maintenance already worked out
+    fn apply(&self, tunnel: &Tunnel, now: Timestamp, server: Option<Timestamp>) -> bool {
+        let until = self.paused_until(tunnel, now, server).map(SystemTime::from);
+        let was_paused = tunnel.paused_until().is_some();
+        if !tunnel.set_paused_until(until) {
+            return false;
+        }
+        match until {
+            Some(until) if !was_paused => {
+                info!(subdomain = %tunnel.subdomain, until = %self.format(until), "Tunnel paused for maintenance")
+            }
+            // A window that overlaps the one in progress
+            Some(until) => info!(subdomain = %tunnel.subdomain, until = %self.format(until), "Tunnel maintenance extended"),
+            None => info!(subdomain = %tunnel.subdomain, "Tunnel resumed after maintenance"),
+        }
+        true
+    }
+
+    /// Update every registered tunnel, waking tunnel listings if any paused or resumed
+    pub fn update_all(&self, registry: &Registry, now: SystemTime) {
+        let Ok(now) = Timestamp::try_from(now) else {
+            return;
+        };
+        let server = self.server_until(now);
+        let changed = registry.tunnels().iter().filter(|tunnel| self.apply(tunnel, now, server)).count();
+        if changed > 0 {
+            registry.bump_generation();
+        }
+    }
+
+    /// `until` as a date and time in the maintenance time zone, e.g. "2026-10-18 02:30 UTC"
+    pub fn format(&self, until: SystemTime) -> String {
+        match Timestamp::try_from(until) {
+            Ok(until) => until.to_zoned(self.time_zone.clone()).strftime("%Y-%m-%d %H:%M %Z").to_string(),
+            Err(_) => httpdate::fmt_http_date(until),
+        }
+    }
+
+    /// What visitors to a tunnel paused until `until` get: 503 with a page saying when
+    /// to come back
+    pub fn page(&self, until: SystemTime, host: &str) -> PageVars {
+        let retry_after = until.duration_since(SystemTime::now()).unwrap_or_default().as_secs().max(1);
+        PageVars::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("This service is down for scheduled maintenance until {}.", self.format(until)),
+            host,
+        )
+        .with_retry_after(retry_after)
+    }
+}
+
+/// Pause and resume tunnels at the start of every minute, when windows begin and
+/// end, until the server shuts down
+pub async fn task(maintenance: Arc<Maintenance>, registry: Arc<Registry>, mut shutdown_rx: broadcast::Receiver<()>) {
+    loop {
+        let into_minute = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() % 60_000;
+        let next_minute = Duration::from_millis(60_000 - into_minute as u64);
+        tokio::select! {
+            _ = tokio::time::sleep(next_minute) => maintenance.update_all(&registry, SystemTime::now()),
+            _ = shutdown_rx.recv() => {
+                debug!("Maintenance task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::names::{Subdomain, TokenSecret};
+
+    fn maintenance(windows: &[&str], timezone: &str) -> Maintenance {
+        Maintenance::new(&MaintenanceConfig {
+            windows: windows.iter().map(|w| Window::parse(w).unwrap()).collect(),
+            timezone: timezone.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn tunnel(pause_schedule: Option<&str>) -> Arc<Tunnel> {
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        Arc::new(
+            Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:1".parse().unwrap(), request_tx)
+                .with_pause_schedule(pause_schedule.map(|s| Window::parse(s).unwrap())),
+        )
+    }
+
+    fn at(time: &str) -> SystemTime {
+        SystemTime::from(time.parse::<Timestamp>().unwrap())
+    }
+
+    #[test]
+    fn test_pauses_and_resumes() {
+        let maintenance = maintenance(&["0 2 * * * for 30m"], "Europe/London");
+        let registry = Registry::new(&[]);
+        let nightly = tunnel(None);
+        let weekly = tunnel(Some("0 2 * * sun for 2h"));
+        registry.register(&Subdomain::new("nightly").unwrap(), nightly.clone(), 0).unwrap();
+        registry.register(&Subdomain::new("weekly").unwrap(), weekly.clone(), 0).unwrap();
+
+        // 2am in London is 1am UTC in summer
+        maintenance.update_all(&registry, at("2026-07-04T00:59:00Z"));
+        assert_eq!(nightly.paused_until(), None);
+
+        let generation = registry.generation();
+        maintenance.update_all(&registry, at("2026-07-04T01:00:00Z"));
+        assert_eq!(nightly.paused_until(), Some(at("2026-07-04T01:30:00Z")));
+        assert_eq!(weekly.paused_until(), Some(at("2026-07-04T01:30:00Z")));
+        assert!(registry.generation() > generation);
+
+        // A tunnel's own window runs on after the server's
+        let generation = registry.generation();
+        maintenance.update_all(&registry, at("2026-07-05T01:00:00Z"));
+        assert_eq!(nightly.paused_until(), Some(at("2026-07-05T01:30:00Z")));
+        assert_eq!(weekly.paused_until(), Some(at("2026-07-05T03:00:00Z")));
+        maintenance.update_all(&registry, at("2026-07-05T01:30:00Z"));
+        assert_eq!(nightly.paused_until(), None);
+        assert_eq!(weekly.paused_until(), Some(at("2026-07-05T03:00:00Z")));
+
+        // Nothing changed, so listings aren't woken
+        let unchanged = registry.generation();
+        maintenance.update_all(&registry, at("2026-07-05T01:31:00Z"));
+        assert_eq!(registry.generation(), unchanged);
+        assert!(unchanged > generation);
+
+        maintenance.update_all(&registry, at("2026-07-05T03:00:00Z"));
+        assert_eq!(weekly.paused_until(), None);
+    }
+
+    #[test]
+    fn test_own_window_following_the_servers() {
+        let maintenance = maintenance(&["0 2 * * * for 30m"], "UTC");
+        let registry = Registry::new(&[]);
+        let after = tunnel(Some("30 2 * * * for 30m"));
+        let before = tunnel(Some("30 1 * * * for 30m"));
+        registry.register(&Subdomain::new("after").unwrap(), after.clone(), 0).unwrap();
+        registry.register(&Subdomain::new("before").unwrap(), before.clone(), 0).unwrap();
+
+        maintenance.update_all(&registry, at("2026-07-04T01:45:00Z"));
+        assert_eq!(after.paused_until(), None);
+        assert_eq!(before.paused_until(), Some(at("2026-07-04T02:30:00Z")));
+        maintenance.update_all(&registry, at("2026-07-04T02:10:00Z"));
+        assert_eq!(after.paused_until(), Some(at("2026-07-04T03:00:00Z")));
+        assert_eq!(before.paused_until(), Some(at("2026-07-04T02:30:00Z")));
+    }
+
+    #[test]
+    fn test_paused_tunnels_are_not_idle() {
+        let maintenance = maintenance(&["* * * * * for 1m"], "UTC");
+        let tunnel = tunnel(None);
+        assert!(maintenance.update(&tunnel, SystemTime::now()));
+        assert_eq!(tunnel.idle_for(), Duration::ZERO);
+        assert!(!maintenance.update(&tunnel, SystemTime::now()));
+
+        let never = self::maintenance(&[], "UTC");
+        assert!(never.update(&tunnel, SystemTime::now()));
+        assert_eq!(tunnel.paused_until(), None);
+    }
+
+    #[test]
+    fn test_page() {
+        let maintenance = maintenance(&[], "America/New_York");
+        assert_eq!(maintenance.format(at("2026-01-15T07:30:00Z")), "2026-01-15 02:30 EST");
+
+        let page = maintenance.page(SystemTime::now() + Duration::from_secs(600), "myapp.tunnel.example.com");
+        assert_eq!(page.status, Some(StatusCode::SERVICE_UNAVAILABLE));
+        let retry_after = page.retry_after.unwrap();
+        assert!((595..=600).contains(&retry_after), "{}", retry_after);
+        assert!(page.message.contains("scheduled maintenance until"), "{}", page.message);
+    }
+}
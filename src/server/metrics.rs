@@ -0,0 +1,486 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::proxy::ProxyFailure;
+use crate::proto::ErrorCode;
+use super::registry::Registry;
+
+/// Counters shared across request handlers via `ServerState`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    proxy_errors: [AtomicU64; ProxyFailure::ALL.len()],
+    registrations: AtomicU64,
+    /// Registrations refused, by the error code the client was sent
+    registration_failures: [AtomicU64; ErrorCode::ALL.len()],
+    /// Registrations of a name the same token's tunnel had moments before
+    reconnects: AtomicU64,
+    control_connections_opened: AtomicU64,
+    control_connections_closed: AtomicU64,
+    /// How long the closed control connections were open, in all, in milliseconds
+    control_connection_ms: AtomicU64,
+    /// Proxied responses by status code, proxy errors included
+    responses: DashMap<u16, u64>,
+    /// Request body bytes sent to clients
+    bytes_in: AtomicU64,
+    /// Response body bytes received from clients
+    bytes_out: AtomicU64,
+    certificates_issued: AtomicU64,
+    certificate_failures: AtomicU64,
+    /// Requests over the slow request threshold, by subdomain
+    slow_requests: DashMap<String, u64>,
+    /// Requests waiting for the fair queue, by subdomain; absent when none are
+    queue_depth: DashMap<String, usize>,
+    /// Requests that waited in the fair queue and how long they waited in all, by subdomain
+    queue_waits: DashMap<String, (u64, Duration)>,
+    /// Responses passed on from a tunnel that another had replaced (without `strict_epoch`)
+    stale_responses: AtomicU64,
+    /// Idempotent requests sent again after their first attempt failed, by whether the
+    /// second got through
+    retries_succeeded: AtomicU64,
+    retries_failed: AtomicU64,
+    /// Requests refused for a method their tunnel's token may not receive
+    refused_methods: AtomicU64,
+    /// Request headers stripped because their tunnel's token may not receive them
+    stripped_headers: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_proxy_error(&self, failure: ProxyFailure) {
+        self.proxy_errors[failure as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of proxied requests that failed with `failure`
+    pub fn proxy_errors(&self, failure: ProxyFailure) -> u64 {
+        self.proxy_errors[failure as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_stale_response(&self) {
+        self.stale_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, succeeded: bool) {
+        let counter = if succeeded {
+            &self.retries_succeeded
+        } else {
+            &self.retries_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_refused_method(&self) {
+        self.refused_methods.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stripped_headers(&self, count: usize) {
+        self.stripped_headers.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn stale_responses(&self) -> u64 {
+        self.stale_responses.load(Ordering::Relaxed)
+    }
+
+    pub fn record_registration(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn registrations(&self) -> u64 {
+        self.registrations.load(Ordering::Relaxed)
+    }
+
+    pub fn record_registration_failure(&self, code: ErrorCode) {
+        self.registration_failures[code as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Registrations refused with `code`
+    pub fn registration_failures(&self, code: ErrorCode) -> u64 {
+        self.registration_failures[code as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn record_control_connection_opened(&self) {
+        self.control_connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_control_connection_closed(&self, lifetime: Duration) {
+        self.control_connections_closed.fetch_add(1, Ordering::Relaxed);
+        self.control_connection_ms
+            .fetch_add(lifetime.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Control connection churn, as summarised by `/_admin/stats`
+    pub fn control_stats(&self) -> ControlStats {
+        let opened = self.control_connections_opened.load(Ordering::Relaxed);
+        let closed = self.control_connections_closed.load(Ordering::Relaxed);
+        let total_ms = self.control_connection_ms.load(Ordering::Relaxed);
+        ControlStats {
+            control_connections_opened: opened,
+            control_connections_open: opened.saturating_sub(closed),
+            average_lifetime_secs: (closed > 0).then(|| total_ms as f64 / closed as f64 / 1000.0),
+            registrations: self.registrations(),
+            registration_failures: ErrorCode::ALL
+                .into_iter()
+                .map(|code| (code.code(), self.registration_failures(code)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            reconnects: self.reconnects(),
+        }
+    }
+
+    pub fn record_response(&self, status: u16) {
+        *self.responses.entry(status).or_insert(0) += 1;
+    }
+
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_certificate_request(&self, issued: bool) {
+        let counter = if issued {
+            &self.certificates_issued
+        } else {
+            &self.certificate_failures
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_slow_request(&self, subdomain: &str) {
+        *self.slow_requests.entry(subdomain.to_string()).or_insert(0) += 1;
+    }
+
+    /// Requests through `subdomain` that were over the slow request threshold
+    #[cfg(test)]
+    pub fn slow_requests(&self, subdomain: &str) -> u64 {
+        self.slow_requests.get(subdomain).map(|count| *count).unwrap_or(0)
+    }
+
+    pub fn set_queue_depth(&self, subdomain: &str, depth: usize) {
+        if depth == 0 {
+            self.queue_depth.remove(subdomain);
+        } else {
+            self.queue_depth.insert(subdomain.to_string(), depth);
+        }
+    }
+
+    pub fn queue_depth(&self, subdomain: &str) -> usize {
+        self.queue_depth.get(subdomain).map(|depth| *depth).unwrap_or(0)
+    }
+
+    pub fn record_queue_wait(&self, subdomain: &str, wait: Duration) {
+        let mut waits = self.queue_waits.entry(subdomain.to_string()).or_default();
+        waits.0 += 1;
+        waits.1 += wait;
+    }
+
+    /// Requests through `subdomain` that waited in the fair queue, and their total wait
+    #[cfg(test)]
+    pub fn queue_waits(&self, subdomain: &str) -> (u64, Duration) {
+        self.queue_waits.get(subdomain).map(|waits| *waits).unwrap_or_default()
+    }
+
+    /// Prometheus text exposition of the counters, plus gauges read from `registry`
+    pub fn render(&self, registry: &Registry) -> String {
+        let mut out = String::new();
+
+        metric(&mut out, "loophole_tunnels", "gauge", "Tunnels currently connected");
+        let _ = writeln!(out, "loophole_tunnels {}", registry.count());
+
+        metric(&mut out, "loophole_tunnel_registrations_total", "counter", "Tunnels registered since the server started");
+        let _ = writeln!(out, "loophole_tunnel_registrations_total {}", self.registrations.load(Ordering::Relaxed));
+
+        metric(&mut out, "loophole_tunnel_registration_failures_total", "counter", "Registrations refused, by error code");
+        for code in ErrorCode::ALL {
+            let _ = writeln!(
+                out,
+                "loophole_tunnel_registration_failures_total{{code=\"{}\"}} {}",
+                code.code(),
+                self.registration_failures(code)
+            );
+        }
+
+        metric(&mut out, "loophole_tunnel_reconnects_total", "counter", "Registrations of a name the same token's tunnel left within the last minute");
+        let _ = writeln!(out, "loophole_tunnel_reconnects_total {}", self.reconnects());
+
+        metric(&mut out, "loophole_control_connections_total", "counter", "Control connections opened by clients");
+        let _ = writeln!(out, "loophole_control_connections_total {}", self.control_connections_opened.load(Ordering::Relaxed));
+        metric(&mut out, "loophole_control_connections_closed_total", "counter", "Control connections that have closed");
+        let _ = writeln!(out, "loophole_control_connections_closed_total {}", self.control_connections_closed.load(Ordering::Relaxed));
+        metric(&mut out, "loophole_control_connection_seconds_total", "counter", "How long the closed control connections were open, in all");
+        let _ = writeln!(
+            out,
+            "loophole_control_connection_seconds_total {:.3}",
+            self.control_connection_ms.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+
+        metric(&mut out, "loophole_tunnel_requests_total", "counter", "Requests proxied through each connected tunnel");
+        let mut tunnels: Vec<_> = registry
+            .subdomains()
+            .into_iter()
+            .filter_map(|subdomain| registry.get(&subdomain))
+            .map(|tunnel| (tunnel.subdomain.clone(), tunnel.request_count.load(Ordering::Relaxed)))
+            .collect();
+        tunnels.sort();
+        for (subdomain, count) in tunnels {
+            let _ = writeln!(out, "loophole_tunnel_requests_total{{subdomain=\"{}\"}} {}", subdomain, count);
+        }
+
+        // Only tunnels whose clients answer echo streams are probed
+        let mut probed: Vec<_> = registry
+            .subdomains()
+            .into_iter()
+            .filter_map(|subdomain| registry.get(&subdomain))
+            .map(|tunnel| (tunnel.subdomain.clone(), tunnel.rtt.stats()))
+            .filter(|(_, rtt)| rtt.probes_sent > 0)
+            .collect();
+        probed.sort_by(|a, b| a.0.cmp(&b.0));
+        metric(&mut out, "loophole_tunnel_rtt_seconds", "gauge", "Smoothed round trip between the server and each probed tunnel's client");
+        for (subdomain, rtt) in &probed {
+            if let Some(smoothed) = rtt.smoothed {
+                let _ = writeln!(out, "loophole_tunnel_rtt_seconds{{subdomain=\"{}\"}} {:.6}", subdomain, smoothed.as_secs_f64());
+            }
+        }
+        metric(&mut out, "loophole_tunnel_rtt_probes_total", "counter", "Round-trip probes sent to each probed tunnel's client");
+        for (subdomain, rtt) in &probed {
+            let _ = writeln!(out, "loophole_tunnel_rtt_probes_total{{subdomain=\"{}\"}} {}", subdomain, rtt.probes_sent);
+        }
+        metric(&mut out, "loophole_tunnel_rtt_probes_lost_total", "counter", "Round-trip probes each probed tunnel's client didn't echo in time");
+        for (subdomain, rtt) in &probed {
+            let _ = writeln!(out, "loophole_tunnel_rtt_probes_lost_total{{subdomain=\"{}\"}} {}", subdomain, rtt.probes_lost);
+        }
+
+        metric(&mut out, "loophole_responses_total", "counter", "Proxied responses by status code, proxy errors included");
+        let mut responses: Vec<_> = self.responses.iter().map(|r| (*r.key(), *r.value())).collect();
+        responses.sort();
+        for (status, count) in responses {
+            let _ = writeln!(out, "loophole_responses_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        metric(&mut out, "loophole_proxy_errors_total", "counter", "Requests the server couldn't proxy, by error code");
+        for failure in ProxyFailure::ALL {
+            let _ = writeln!(
+                out,
+                "loophole_proxy_errors_total{{code=\"{}\",status=\"{}\"}} {}",
+                failure.code(),
+                failure.status().as_u16(),
+                self.proxy_errors(failure)
+            );
+        }
+
+        metric(&mut out, "loophole_proxy_retries_total", "counter", "Idempotent requests sent to a tunnel again after a failed attempt, by result");
+        for (result, counter) in [("success", &self.retries_succeeded), ("failure", &self.retries_failed)] {
+            let _ = writeln!(out, "loophole_proxy_retries_total{{result=\"{}\"}} {}", result, counter.load(Ordering::Relaxed));
+        }
+
+        metric(&mut out, "loophole_stale_responses_total", "counter", "Responses passed on from a tunnel another client had since replaced");
+        let _ = writeln!(out, "loophole_stale_responses_total {}", self.stale_responses());
+
+        metric(&mut out, "loophole_policy_violations_total", "counter", "Requests refused and request headers stripped by token policies, by kind");
+        let _ = writeln!(out, "loophole_policy_violations_total{{kind=\"method\"}} {}", self.refused_methods.load(Ordering::Relaxed));
+        let _ = writeln!(out, "loophole_policy_violations_total{{kind=\"header\"}} {}", self.stripped_headers.load(Ordering::Relaxed));
+
+        metric(&mut out, "loophole_slow_requests_total", "counter", "Requests over the slow request threshold, by subdomain");
+        let mut slow: Vec<_> = self.slow_requests.iter().map(|r| (r.key().clone(), *r.value())).collect();
+        slow.sort();
+        for (subdomain, count) in slow {
+            let _ = writeln!(out, "loophole_slow_requests_total{{subdomain=\"{}\"}} {}", subdomain, count);
+        }
+
+        metric(&mut out, "loophole_fair_queue_depth", "gauge", "Requests waiting for the fair queue, by subdomain");
+        let mut subdomains = registry.subdomains();
+        subdomains.sort();
+        for subdomain in subdomains {
+            let _ = writeln!(out, "loophole_fair_queue_depth{{subdomain=\"{}\"}} {}", subdomain, self.queue_depth(&subdomain));
+        }
+
+        let mut waits: Vec<_> = self.queue_waits.iter().map(|r| (r.key().clone(), *r.value())).collect();
+        waits.sort();
+        metric(&mut out, "loophole_fair_queue_waits_total", "counter", "Requests that waited in the fair queue, by subdomain");
+        for (subdomain, (count, _)) in &waits {
+            let _ = writeln!(out, "loophole_fair_queue_waits_total{{subdomain=\"{}\"}} {}", subdomain, count);
+        }
+        metric(&mut out, "loophole_fair_queue_wait_seconds_total", "counter", "Time requests spent waiting in the fair queue, by subdomain");
+        for (subdomain, (_, wait)) in &waits {
+            let _ = writeln!(out, "loophole_fair_queue_wait_seconds_total{{subdomain=\"{}\"}} {:.6}", subdomain, wait.as_secs_f64());
+        }
+
+        metric(&mut out, "loophole_request_bytes_total", "counter", "Request body bytes sent through tunnels");
+        let _ = writeln!(out, "loophole_request_bytes_total {}", self.bytes_in.load(Ordering::Relaxed));
+        metric(&mut out, "loophole_response_bytes_total", "counter", "Response body bytes received through tunnels");
+        let _ = writeln!(out, "loophole_response_bytes_total {}", self.bytes_out.load(Ordering::Relaxed));
+
+        metric(&mut out, "loophole_certificate_requests_total", "counter", "ACME certificate requests by result");
+        for (result, counter) in [("success", &self.certificates_issued), ("failure", &self.certificate_failures)] {
+            let _ = writeln!(
+                out,
+                "loophole_certificate_requests_total{{result=\"{}\"}} {}",
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+/// Control connection churn: how often clients connect and register, and how long
+/// they stay
+#[derive(Debug, Serialize)]
+pub struct ControlStats {
+    pub control_connections_opened: u64,
+    pub control_connections_open: u64,
+    /// Over the connections that have closed; None until one has
+    pub average_lifetime_secs: Option<f64>,
+    pub registrations: u64,
+    /// Refused registrations by error code, leaving out codes never sent
+    pub registration_failures: BTreeMap<&'static str, u64>,
+    pub reconnects: u64,
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::names::{Subdomain, TokenSecret};
+    use crate::server::tunnel::Tunnel;
+    use std::sync::Arc;
+
+    /// Sample lines, without comments
+    fn samples(text: &str) -> Vec<&str> {
+        text.lines().filter(|line| !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let registry = Registry::default();
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+        tunnel.increment_requests();
+        tunnel.rtt.record_sent();
+        tunnel.rtt.record_sent();
+        tunnel.rtt.record_lost();
+        tunnel.rtt.record(Duration::from_millis(42));
+        registry.register(&Subdomain::new("myapp").unwrap(), tunnel, 0).unwrap();
+
+        metrics.record_registration();
+        metrics.record_response(200);
+        metrics.record_response(200);
+        metrics.record_response(504);
+        metrics.record_proxy_error(ProxyFailure::ResponseHeaderTimeout);
+        metrics.record_bytes_in(10);
+        metrics.record_bytes_out(2048);
+        metrics.record_certificate_request(true);
+        metrics.record_certificate_request(false);
+        metrics.record_certificate_request(false);
+        metrics.record_slow_request("myapp");
+        metrics.set_queue_depth("myapp", 3);
+        metrics.record_queue_wait("myapp", Duration::from_millis(250));
+        metrics.record_queue_wait("myapp", Duration::from_millis(500));
+        metrics.record_stale_response();
+        metrics.record_retry(true);
+        metrics.record_refused_method();
+        metrics.record_stripped_headers(2);
+        metrics.record_registration_failure(ErrorCode::SubdomainTaken);
+        metrics.record_reconnect();
+        metrics.record_control_connection_opened();
+        metrics.record_control_connection_opened();
+        metrics.record_control_connection_closed(Duration::from_millis(1500));
+
+        let text = metrics.render(&registry);
+        let samples = samples(&text);
+        for expected in [
+            "loophole_tunnels 1",
+            "loophole_tunnel_registrations_total 1",
+            "loophole_tunnel_requests_total{subdomain=\"myapp\"} 1",
+            "loophole_tunnel_rtt_seconds{subdomain=\"myapp\"} 0.042000",
+            "loophole_tunnel_rtt_probes_total{subdomain=\"myapp\"} 2",
+            "loophole_tunnel_rtt_probes_lost_total{subdomain=\"myapp\"} 1",
+            "loophole_responses_total{status=\"200\"} 2",
+            "loophole_responses_total{status=\"504\"} 1",
+            "loophole_proxy_errors_total{code=\"response_header_timeout\",status=\"504\"} 1",
+            "loophole_proxy_errors_total{code=\"stream_open_failed\",status=\"502\"} 0",
+            "loophole_stale_responses_total 1",
+            "loophole_proxy_retries_total{result=\"success\"} 1",
+            "loophole_proxy_retries_total{result=\"failure\"} 0",
+            "loophole_policy_violations_total{kind=\"method\"} 1",
+            "loophole_policy_violations_total{kind=\"header\"} 2",
+            "loophole_tunnel_registration_failures_total{code=\"subdomain_taken\"} 1",
+            "loophole_tunnel_registration_failures_total{code=\"invalid_token\"} 0",
+            "loophole_tunnel_reconnects_total 1",
+            "loophole_control_connections_total 2",
+            "loophole_control_connections_closed_total 1",
+            "loophole_control_connection_seconds_total 1.500",
+            "loophole_request_bytes_total 10",
+            "loophole_response_bytes_total 2048",
+            "loophole_certificate_requests_total{result=\"success\"} 1",
+            "loophole_certificate_requests_total{result=\"failure\"} 2",
+            "loophole_slow_requests_total{subdomain=\"myapp\"} 1",
+            "loophole_fair_queue_depth{subdomain=\"myapp\"} 3",
+            "loophole_fair_queue_waits_total{subdomain=\"myapp\"} 2",
+            "loophole_fair_queue_wait_seconds_total{subdomain=\"myapp\"} 0.750000",
+        ] {
+            assert!(samples.contains(&expected), "missing {:?} in:\n{}", expected, text);
+        }
+    }
+
+    #[test]
+    fn test_control_stats() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.control_stats().average_lifetime_secs, None);
+
+        for _ in 0..3 {
+            metrics.record_control_connection_opened();
+        }
+        metrics.record_control_connection_closed(Duration::from_secs(1));
+        metrics.record_control_connection_closed(Duration::from_secs(4));
+        metrics.record_registration_failure(ErrorCode::InvalidToken);
+
+        let stats = metrics.control_stats();
+        assert_eq!(stats.control_connections_opened, 3);
+        assert_eq!(stats.control_connections_open, 1);
+        assert_eq!(stats.average_lifetime_secs, Some(2.5));
+        assert_eq!(stats.registration_failures, BTreeMap::from([("invalid_token", 1)]));
+    }
+
+    #[test]
+    fn test_render_is_valid_exposition() {
+        let text = Metrics::new().render(&Registry::default());
+        let mut typed = std::collections::HashSet::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(["counter", "gauge"].contains(&kind), "{}", line);
+                assert!(typed.insert(name), "{} declared twice", name);
+            } else if !line.starts_with("# HELP ") {
+                // Every sample belongs to a declared metric and has a numeric value
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                let name = series.split('{').next().unwrap();
+                assert!(typed.contains(name), "undeclared {}", name);
+                assert!(value.parse::<f64>().is_ok(), "{}", line);
+            }
+        }
+        assert!(text.ends_with('\n'));
+    }
+}
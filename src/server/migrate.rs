@@ -0,0 +1,281 @@
+//! Converting configs written for the older server, which had `[tokens]` as
+//! name = number, the admin token in an `[admin]` block and a configurable
+//! `control_path`, to the current format.
+
+use anyhow::Result;
+use std::fmt;
+
+use super::config::CONTROL_PATH;
+
+/// One thing the migration changed, shown diff-style
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    /// The old setting, as it was written
+    pub old: String,
+    /// What replaced it, if anything
+    pub new: Option<String>,
+    pub note: Option<String>,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "- {}", self.old)?;
+        if let Some(ref new) = self.new {
+            write!(f, "\n+ {}", new)?;
+        }
+        if let Some(ref note) = self.note {
+            write!(f, "\n  {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Migration {
+    /// The converted config, as TOML
+    pub config: String,
+    pub decisions: Vec<Decision>,
+}
+
+/// What in `document` only the older server's configs have, for pointing their
+/// owners at `loophole migrate-config`. Empty for current configs.
+pub fn legacy_signs(document: &toml::Table) -> Vec<String> {
+    let mut signs = Vec::new();
+    if let Some(toml::Value::Table(tokens)) = document.get("tokens") {
+        if let Some((name, _)) = tokens.iter().find(|(_, value)| value.is_integer()) {
+            signs.push(format!("tokens.{} is a number", name));
+        }
+    }
+    if document.get("admin").is_some_and(toml::Value::is_table) {
+        signs.push("an [admin] block".to_string());
+    }
+    let server_control_path = document
+        .get("server")
+        .and_then(toml::Value::as_table)
+        .is_some_and(|server| server.contains_key("control_path"));
+    if server_control_path || document.contains_key("control_path") {
+        signs.push("control_path".to_string());
+    }
+    signs
+}
+
+/// Convert an older server's config. Settings both formats share are kept as they are.
+pub fn migrate(content: &str) -> Result<Migration> {
+    let mut document: toml::Table = content.parse()?;
+    let mut decisions = Vec::new();
+
+    // Tokens have settings now, rather than a number
+    let mut tokens = match document.remove("tokens") {
+        Some(toml::Value::Table(tokens)) => tokens,
+        Some(_) => anyhow::bail!("tokens must be a table"),
+        None => toml::Table::new(),
+    };
+    for (name, value) in tokens.iter_mut() {
+        if let toml::Value::Integer(number) = value {
+            decisions.push(Decision {
+                old: format!("[tokens] {} = {}", key(name), number),
+                new: Some(format!("[tokens.{}]", key(name))),
+                note: Some("The number is dropped. Set max_tunnels if it was meant as a limit.".to_string()),
+            });
+            *value = toml::Value::Table(toml::Table::new());
+        }
+    }
+
+    match document.remove("admin") {
+        Some(toml::Value::Table(admin)) => {
+            let enabled = admin.get("enabled").and_then(toml::Value::as_bool).unwrap_or(true);
+            match admin.get("token").and_then(toml::Value::as_str) {
+                Some(token) if enabled => {
+                    let entry = tokens
+                        .entry(token.to_string())
+                        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                    if let toml::Value::Table(entry) = entry {
+                        entry.insert("admin".to_string(), toml::Value::Boolean(true));
+                    }
+                    decisions.push(Decision {
+                        old: format!("[admin] token = {}", quote(token)),
+                        new: Some(format!("[tokens.{}] admin = true", key(token))),
+                        note: Some("The admin token can also run tunnels now.".to_string()),
+                    });
+                }
+                Some(token) => decisions.push(Decision {
+                    old: format!("[admin] enabled = false, token = {}", quote(token)),
+                    new: None,
+                    note: Some("The admin API was off, so no admin token is added. Add admin = true to a token to use it.".to_string()),
+                }),
+                None => decisions.push(Decision {
+                    old: "[admin]".to_string(),
+                    new: None,
+                    note: Some("No admin token was set. Add admin = true to a token to use the admin API.".to_string()),
+                }),
+            }
+        }
+        Some(_) => anyhow::bail!("admin must be a table"),
+        None => {}
+    }
+    document.insert("tokens".to_string(), toml::Value::Table(tokens));
+
+    let control_paths = [
+        document.remove("control_path").map(|path| ("control_path", path)),
+        document
+            .get_mut("server")
+            .and_then(toml::Value::as_table_mut)
+            .and_then(|server| server.remove("control_path"))
+            .map(|path| ("[server] control_path", path)),
+    ];
+    for (key, path) in control_paths.into_iter().flatten() {
+        let path = path.as_str().unwrap_or_default().to_string();
+        let note = if path == CONTROL_PATH {
+            "It's always this now.".to_string()
+        } else {
+            format!(
+                "Clients now always connect at {}. Update anything in front of the server (proxies, firewalls) that routes {}.",
+                CONTROL_PATH, path
+            )
+        };
+        decisions.push(Decision {
+            old: format!("{} = {}", key, quote(&path)),
+            new: None,
+            note: Some(note),
+        });
+    }
+
+    if let Some(acme) = document.remove("acme") {
+        decisions.push(Decision {
+            old: "[acme]".to_string(),
+            new: Some("[https]".to_string()),
+            note: None,
+        });
+        document.insert("https".to_string(), acme);
+    }
+
+    Ok(Migration {
+        config: toml::to_string_pretty(&document)?,
+        decisions,
+    })
+}
+
+/// A string as TOML writes it
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// A key as TOML writes it: bare if it can be
+fn key(name: &str) -> String {
+    let bare = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Config;
+
+    /// As the older server's `loophole init` wrote it
+    const OLD: &str = r#"
+version = 1
+
+[server]
+domain = "tunnel.example.com"
+http_port = 8080
+control_path = "/tunnel"
+
+[tokens]
+"tk_test123" = 5
+"tk_unlimited" = 0
+
+[limits]
+request_timeout_secs = 30
+idle_tunnel_timeout_secs = 3600
+
+[admin]
+enabled = true
+token = "admin_secret_token"
+
+[acme]
+email = "admin@example.com"
+staging = false
+"#;
+
+    #[test]
+    fn test_migrates_old_config() {
+        let migration = migrate(OLD).unwrap();
+        let config = Config::parse(&migration.config).unwrap();
+        assert!(legacy_signs(&migration.config.parse().unwrap()).is_empty());
+
+        assert_eq!(config.server.domain, "tunnel.example.com");
+        assert_eq!(config.server.http_port, 8080);
+        assert_eq!(config.limits.request_timeout_secs, 30);
+        assert_eq!(config.https.unwrap().email, "admin@example.com");
+        assert_eq!(config.tokens.len(), 3);
+        assert!(!config.tokens["tk_test123"].admin);
+        assert_eq!(config.tokens["tk_test123"].max_tunnels, None);
+        assert!(config.tokens["admin_secret_token"].admin);
+        assert!(Config::unknown_keys(&migration.config).is_empty());
+
+        let summary: Vec<String> = migration.decisions.iter().map(ToString::to_string).collect();
+        assert!(summary.contains(&"- [tokens] tk_test123 = 5\n+ [tokens.tk_test123]\n  The number is dropped. Set max_tunnels if it was meant as a limit.".to_string()), "{:#?}", summary);
+        assert!(summary.iter().any(|d| d.starts_with("- [admin] token = \"admin_secret_token\"\n+ [tokens.admin_secret_token] admin = true")));
+        assert!(summary.iter().any(|d| d.starts_with("- [server] control_path = \"/tunnel\"\n  Clients now always connect at /_tunnel/connect")));
+        assert!(summary.contains(&"- [acme]\n+ [https]".to_string()));
+    }
+
+    #[test]
+    fn test_admin_token_that_is_also_a_tunnel_token() {
+        let old = r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens]
+tk_alice = 1
+
+[admin]
+token = "tk_alice"
+"#;
+        let config = Config::parse(&migrate(old).unwrap().config).unwrap();
+        assert_eq!(config.tokens.len(), 1);
+        assert!(config.tokens["tk_alice"].admin);
+    }
+
+    #[test]
+    fn test_disabled_admin_api() {
+        let old = r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens]
+tk_alice = 1
+
+[admin]
+enabled = false
+token = "admin_secret_token"
+"#;
+        let migration = migrate(old).unwrap();
+        let config = Config::parse(&migration.config).unwrap();
+        assert!(!config.tokens.contains_key("admin_secret_token"));
+        assert_eq!(migration.decisions[1].new, None);
+    }
+
+    #[test]
+    fn test_legacy_signs() {
+        let signs = legacy_signs(&OLD.parse().unwrap());
+        assert_eq!(signs, vec!["tokens.tk_test123 is a number", "an [admin] block", "control_path"]);
+
+        let current = r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens.tk_alice]
+admin = true
+"#;
+        assert!(legacy_signs(&current.parse().unwrap()).is_empty());
+        // Configs already in the current format go through unchanged
+        let migration = migrate(current).unwrap();
+        assert!(migration.decisions.is_empty());
+        assert!(Config::parse(&migration.config).unwrap().tokens["tk_alice"].admin);
+    }
+}
@@ -1,28 +1,107 @@
+mod admin_json;
 mod acme;
-mod compat;
+mod admission;
+mod buffers;
+mod basic_auth;
+mod cert_prune;
+mod cert_store;
+mod churn;
+mod cloudflare;
 mod config;
+mod config_schema;
+mod config_summary;
+mod dns_monitor;
+mod dns_provider;
+mod framing;
 mod handler;
+mod listen;
+mod logs;
+mod maintenance;
+mod metrics;
+mod migrate;
+mod motd;
+mod names;
+mod ownership;
+mod pages;
+mod path_tunnel;
 mod proxy;
+mod proxy_protocol;
+mod public_url;
+mod rate_limit;
 mod registry;
+mod reservations;
+mod response_headers;
 mod router;
+mod rtt;
+#[cfg(feature = "s3")]
+mod s3_store;
+mod scheduler;
+mod slow_requests;
+mod tcp;
 mod tls;
+mod tokens;
+mod trusted_proxies;
 mod tunnel;
+mod usage;
+mod webhook;
 
 pub use config::Config;
+pub use logs::LogEvent;
 
 use anyhow::{Context, Result};
+use axum_server::accept::DefaultAcceptor;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use std::net::SocketAddr;
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast;
-use tracing::{error, info, warn, Level};
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::FmtSubscriber;
 
-use acme::{AcmeClient, ChallengeStore};
+use crate::build_info::BuildInfo;
+use crate::units;
+use acme::{AcmeChallenge, AcmeClient, ChallengeStore};
+use admission::Admission;
+use churn::Churn;
+use cloudflare::CloudflareRanges;
+use config_summary::Summary;
+use dns_monitor::{DnsStatus, DohLookup, Monitor};
+use logs::{LogBuffer, LogLayer};
+use maintenance::Maintenance;
+use metrics::Metrics;
+use motd::{Messages, Motd};
+use names::FullDomain;
+use pages::{Pages, Theme};
+use proxy_protocol::ProxyProtocolAcceptor;
+use public_url::PublicUrlBuilder;
 use registry::Registry;
-use router::{create_acme_router, create_router, ServerState};
+use reservations::Reservations;
+use scheduler::FairScheduler;
+use router::{create_acme_router, create_control_router, create_metrics_router, create_router, ServerState};
+use response_headers::{HeaderRules, ResponseHeaders};
+use slow_requests::SlowRequests;
+use tcp::TcpPorts;
 use tls::CertManager;
+use tokens::TokenStore;
+use usage::Usage;
+use webhook::Webhook;
+
+/// Deregister tunnels with no activity (requests, or allowed keep-alives) for `idle_timeout`
+fn remove_idle_tunnels(registry: &Registry, idle_timeout: Duration) {
+    for subdomain in registry.subdomains() {
+        if let Some(tunnel) = registry.get(&subdomain) {
+            if tunnel.is_idle(idle_timeout) {
+                info!(
+                    subdomain = %subdomain,
+                    idle_seconds = tunnel.idle_for().as_secs(),
+                    "Removing idle tunnel"
+                );
+                registry.disconnect(&tunnel, "Disconnected for being idle");
+            }
+        }
+    }
+}
 
 /// Background task that periodically checks for idle tunnels and removes them
 async fn idle_tunnel_cleanup_task(
@@ -34,21 +113,7 @@ async fn idle_tunnel_cleanup_task(
 
     loop {
         tokio::select! {
-            _ = tokio::time::sleep(check_interval) => {
-                let subdomains = registry.subdomains();
-                for subdomain in subdomains {
-                    if let Some(tunnel) = registry.get(&subdomain) {
-                        if tunnel.is_idle(idle_timeout) {
-                            info!(
-                                subdomain = %subdomain,
-                                idle_seconds = tunnel.last_activity().elapsed().as_secs(),
-                                "Removing idle tunnel"
-                            );
-                            registry.deregister(&subdomain);
-                        }
-                    }
-                }
-            }
+            _ = tokio::time::sleep(check_interval) => remove_idle_tunnels(&registry, idle_timeout),
             _ = shutdown_rx.recv() => {
                 info!("Idle cleanup task shutting down");
                 break;
@@ -57,87 +122,349 @@ async fn idle_tunnel_cleanup_task(
     }
 }
 
-pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
+/// Give tunnels time to warn their clients and finish in-flight requests before exiting
+async fn drain_tunnels(registry: &Registry) {
+    let deadline = tokio::time::Instant::now() + handler::SHUTDOWN_DRAIN + Duration::from_secs(1);
+    while registry.count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Re-read the config and apply the settings that can change without a restart
+fn reload_config(
+    config_path: &str,
+    strict: bool,
+    slow_requests: &SlowRequests,
+    response_headers: &ResponseHeaders,
+    motd: &Motd,
+    pages: &Pages,
+) -> Result<()> {
+    let config = Config::load_or_from_env(Some(config_path), strict)?;
+    // Read everything that can fail before applying any of it
+    let rules = HeaderRules::new(&config.response_headers)?;
+    let messages = Messages::load(&config.server)?;
+    let theme = Theme::load(&config.pages)?;
+    slow_requests.set_threshold(config.logging.slow_request_threshold_ms);
+    match slow_requests.threshold() {
+        Some(threshold) => info!("Slow request threshold: {}", units::format_duration(threshold)),
+        None => info!("Slow request logging off"),
+    }
+    response_headers.set_rules(rules);
+    info!("Response header rules: {}", config.response_headers.len());
+    motd.set_messages(messages);
+    if let Some(ref dir) = config.pages.theme_dir {
+        info!("Page theme: {} ({})", dir.display(), theme.describe());
+    }
+    pages.set_theme(theme);
+    // Settings other than the ones above keep their startup values until a restart
+    info!("Reloaded configuration");
+    Summary::of(&config).log();
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn config_reload_task(
+    config_path: String,
+    strict: bool,
+    slow_requests: Arc<SlowRequests>,
+    response_headers: Arc<ResponseHeaders>,
+    motd: Arc<Motd>,
+    pages: Arc<Pages>,
+    mut reload_rx: broadcast::Receiver<()>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            Ok(()) = reload_rx.recv() => {
+                if let Err(e) = reload_config(&config_path, strict, &slow_requests, &response_headers, &motd, &pages) {
+                    warn!("Failed to reload config, keeping current settings: {:#}", e);
+                }
+            }
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+}
+
+/// Forward SIGHUP to the reload channel
+#[cfg(unix)]
+async fn reload_signal_task(reload_tx: broadcast::Sender<()>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            warn!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading");
+        let _ = reload_tx.send(());
+    }
+}
+
+/// Load a config file the way the server would, treating unknown keys as errors,
+/// and report whether it's valid without starting anything
+pub fn check_config(config_path: &str) -> Result<()> {
+    let config = Config::load(config_path, true).with_context(|| format!("Invalid config {}", config_path))?;
+    println!(
+        "{} is valid: domain {}, {} token(s)",
+        config_path,
+        config.server.domain,
+        config.tokens.len()
+    );
+    Ok(())
+}
+
+/// Print the configuration the server would run with, each setting with where it
+/// came from, as TOML or JSON
+pub fn print_config(config_path: &str, strict: bool, format: &str) -> Result<()> {
+    let config = Config::load_or_from_env(Some(config_path), strict)?;
+    let summary = Summary::of(&config);
+    match format {
+        "json" => println!("{}", summary.to_json()),
+        _ => print!("{}", summary.to_toml()),
+    }
+    Ok(())
+}
+
+/// Convert a config written for the older server to the current format, saying what
+/// changed, and check the result loads
+pub fn migrate_config(input: &str, output: &str) -> Result<()> {
+    let content = std::fs::read_to_string(input).with_context(|| format!("Failed to read {}", input))?;
+    let migration = migrate::migrate(&content).with_context(|| format!("Failed to convert {}", input))?;
+    // Tokens end up in the file, so only the owner may read it, and nothing there is replaced
+    match tokens::write_private(std::path::Path::new(output), migration.config.as_bytes()) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            anyhow::bail!("{} already exists; choose another --out, or move it aside", output)
+        }
+        result => result.with_context(|| format!("Failed to write {}", output))?,
+    }
+
+    if migration.decisions.is_empty() {
+        println!("{} needed no changes", input);
+    }
+    for decision in &migration.decisions {
+        println!("{}\n", decision);
+    }
+    println!("Comments aren't carried over; compare with {} for anything worth keeping.", input);
+
+    let config = Config::load(output, false)
+        .with_context(|| format!("{} was written but doesn't load; fix it by hand", output))?;
+    println!("{} is valid: domain {}, {} token(s)", output, config.server.domain, config.tokens.len());
+    Ok(())
+}
+
+/// Run the server. With `strict_config`, unknown keys in the config file are
+/// errors rather than warnings, on reload as well as at startup.
+pub async fn run(config_path: &str, log_level: Level, strict_config: bool, strict_clock: bool) -> Result<()> {
     // Crypto provider is already installed in main.rs
 
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    // Also keep recent events in memory, for `loophole logs`
+    let logs = Arc::new(LogBuffer::default());
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(log_level)
+        .finish()
+        .with(LogLayer::new(logs.clone()));
     tracing::subscriber::set_global_default(subscriber)?;
 
+    info!("Starting loophole server {}", BuildInfo::current());
+
     // Load config from file or environment variables
-    let config = Config::load_or_from_env(Some(config_path))?;
+    let config = Config::load_or_from_env(Some(config_path), strict_config)?;
     
-    if std::env::var(config::env::DOMAIN).is_ok() {
-        info!("Loaded configuration from environment variables");
-    } else {
+    if config.provenance.is_file() {
         info!("Loaded configuration from {}", config_path);
+    } else {
+        info!("Loaded configuration from environment variables");
     }
+    Summary::of(&config).log();
     info!("Domain: {}", config.server.domain);
     info!("HTTP port: {}", config.server.http_port);
+    info!(
+        "Limits: request timeout {}, max request body {}, idle timeout {}",
+        units::format_duration(Duration::from_secs(config.limits.request_timeout_secs)),
+        units::format_bytes(config.limits.max_request_body_bytes as u64),
+        units::format_duration(Duration::from_secs(config.limits.idle_tunnel_timeout_secs)),
+    );
 
     // Create shutdown signal channel
     let (shutdown_tx, _) = broadcast::channel::<()>(1);
 
-    // Create challenge store for ACME HTTP-01
+    // Reload requests (SIGHUP) are broadcast to the tasks that act on them
+    let (reload_tx, _) = broadcast::channel::<()>(1);
+    #[cfg(unix)]
+    tokio::spawn(reload_signal_task(reload_tx.clone()));
+
+    // Counters for /metrics, also updated by the certificate manager
+    let metrics = Arc::new(Metrics::new());
+
+    // Create challenge store for ACME HTTP-01 (TLS-ALPN-01 certificates live in the
+    // ACME client)
     let challenge_store = Arc::new(ChallengeStore::new());
 
     // Create ACME client and cert manager if configured
-    let (_acme_client, cert_manager) = if let Some(ref https_config) = config.https {
-        info!("HTTPS enabled with email: {}", https_config.email);
+    let (acme_client, cert_manager) = if let Some(ref https_config) = config.https {
         info!("HTTPS port: {}", config.server.https_port);
+        let store = cert_store::open(https_config).await?;
 
-        let certs_dir = PathBuf::from(&https_config.certs_dir);
-        let directory_url = if https_config.staging {
-            "https://acme-staging-v02.api.letsencrypt.org/directory"
-        } else {
-            &https_config.directory
-        };
-
-        // Load custom CA file if specified (for testing with Pebble)
-        let additional_roots = if let Some(ref ca_file) = https_config.ca_file {
-            info!("Loading additional CA from: {}", ca_file);
-            Some(std::fs::read(ca_file).context("Failed to read CA file")?)
-        } else {
+        let acme_client = if https_config.manual_certs {
+            info!("HTTPS enabled with manually managed certificates from {}", store.describe());
             None
-        };
-
-        let acme_client = Arc::new(
-            AcmeClient::new_with_roots(
+        } else {
+            info!("HTTPS enabled with email: {}", https_config.email);
+
+            let directory_url = if https_config.staging {
+                "https://acme-staging-v02.api.letsencrypt.org/directory"
+            } else {
+                &https_config.directory
+            };
+
+            // Load custom CA file if specified (for testing with Pebble)
+            let additional_roots = if let Some(ref ca_file) = https_config.ca_file {
+                info!("Loading additional CA from: {}", ca_file);
+                Some(std::fs::read(ca_file).context("Failed to read CA file")?)
+            } else {
+                None
+            };
+
+            let acme_client = AcmeClient::new_with_roots(
                 &https_config.email,
                 directory_url,
-                certs_dir.clone(),
+                store.clone(),
                 challenge_store.clone(),
                 additional_roots.as_deref(),
             )
-            .await?,
-        );
+            .await?;
+            match acme_client.directory_date() {
+                Some(date) => {
+                    if let Some(warning) = date.skew_warning("the ACME server") {
+                        if strict_clock {
+                            anyhow::bail!("{}", warning);
+                        }
+                        warn!("{}", warning);
+                    }
+                }
+                None => debug!("The ACME directory sent no Date header; not checking the system clock"),
+            }
+            let acme_client = match https_config.dns {
+                Some(ref dns) => {
+                    info!(
+                        "Answering ACME challenges over DNS with {}, with a wildcard certificate for *.{}",
+                        dns.provider, config.server.domain
+                    );
+                    acme_client.with_dns(dns_provider::open(dns)?, config.monitoring.dns_resolver.clone())
+                }
+                None if https_config.challenge == AcmeChallenge::TlsAlpn01 => {
+                    info!("Answering ACME challenges over TLS-ALPN-01 on the HTTPS port");
+                    acme_client.with_tls_alpn()
+                }
+                None => acme_client,
+            };
+            Some(Arc::new(acme_client))
+        };
 
         let cert_manager = Arc::new(
             CertManager::new(
-                certs_dir,
-                Some(acme_client.clone()),
+                store,
+                acme_client.clone(),
                 challenge_store.clone(),
-                config.server.domain.clone(),
+                FullDomain::new(&config.server.domain),
+                metrics.clone(),
             )
             .await?,
         );
 
-        // Note: Base domain certificate will be requested after HTTP server starts
-        // so that ACME HTTP-01 challenges can be served
+        // Note: Base domain certificate will be requested after the HTTP server starts
+        // so that ACME HTTP-01 challenges can be served (TLS-ALPN-01 ones wait on the
+        // HTTPS server, which the bootstrap retries until it answers)
 
-        (Some(acme_client), Some(cert_manager))
+        (acme_client, Some(cert_manager))
     } else {
         info!("HTTPS not configured, running HTTP only");
         (None, None)
     };
 
+    // Trust Cloudflare's forwarding headers only from its published ranges
+    let cloudflare = config.server.behind_cloudflare.then(|| {
+        info!("Running behind Cloudflare");
+        let ranges = Arc::new(CloudflareRanges::bundled());
+        tokio::spawn(cloudflare::refresh_task(ranges.clone(), shutdown_tx.subscribe()));
+        ranges
+    });
+
+    // Tokens created and revoked at runtime, usage and reservations are saved in the
+    // state directory; with an environment-only config and no state_dir they last
+    // until restart
+    let state_dir = config.server.state_dir(config_path);
+    if let Some(ref dir) = config.server.state_dir {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create state_dir {}", dir.display()))?;
+    }
+    let (tokens, usage, reservations) = match state_dir {
+        Some(ref dir) => (
+            TokenStore::load(&config, TokenStore::path_in(dir))?,
+            Usage::load(config.usage.retention_days, Usage::path_in(dir))?,
+            Reservations::load(Reservations::path_in(dir))?,
+        ),
+        None => (
+            TokenStore::new(&config),
+            Usage::new(config.usage.retention_days),
+            Reservations::default(),
+        ),
+    };
+
     // Create shared state
-    let registry = Arc::new(Registry::new());
+    let registry = Arc::new(
+        Registry::new(&config.registry.reserved)
+            .with_aliases(&config.registry.aliases)
+            .with_reservations(reservations),
+    );
+    let theme = Theme::load(&config.pages)?;
+    if let Some(ref dir) = config.pages.theme_dir {
+        info!("Page theme: {} ({})", dir.display(), theme.describe());
+    }
     let state = Arc::new(ServerState {
         config: Arc::new(config.clone()),
+        tokens: Arc::new(tokens),
         registry: registry.clone(),
         cert_manager: cert_manager.clone(),
+        acme_probe_limiter: router::acme_probe_limiter(),
+        scheduler: Arc::new(FairScheduler::new(config.limits.fair_queue_threshold, metrics.clone())),
+        metrics,
+        cloudflare,
+        admission: Arc::new(Admission::new(&config.limits)),
+        public_url: PublicUrlBuilder::from_config(&config),
+        shutdown_tx: shutdown_tx.clone(),
+        slow_requests: Arc::new(SlowRequests::new(config.logging.slow_request_threshold_ms)),
+        churn: Arc::new(Churn::new(config.logging.registration_rate_warning)),
+        usage: Arc::new(usage),
+        tcp_ports: config
+            .tcp
+            .port_range
+            .map(|range| Arc::new(TcpPorts::new(range).with_bind_address(config.server.bind_address))),
+        maintenance: Arc::new(Maintenance::new(&config.maintenance)?),
+        response_headers: Arc::new(ResponseHeaders::new(HeaderRules::new(&config.response_headers)?)),
+        started_at: std::time::Instant::now(),
+        motd: Arc::new(Motd::new(Messages::load(&config.server)?)),
+        dns: Arc::new(if config.monitoring.dns_check_interval_secs > 0 {
+            DnsStatus::monitored()
+        } else {
+            DnsStatus::default()
+        }),
+        pages: Arc::new(Pages::new(theme)),
+        proxy_buffers: Arc::default(),
+        logs,
     });
 
+    tokio::spawn(config_reload_task(
+        config_path.to_string(),
+        strict_config,
+        state.slow_requests.clone(),
+        state.response_headers.clone(),
+        state.motd.clone(),
+        state.pages.clone(),
+        reload_tx.subscribe(),
+        shutdown_tx.subscribe(),
+    ));
+
     // Start idle tunnel cleanup task
     let idle_timeout = Duration::from_secs(config.limits.idle_tunnel_timeout_secs);
     let cleanup_registry = registry.clone();
@@ -146,6 +473,61 @@ pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
         idle_tunnel_cleanup_task(cleanup_registry, idle_timeout, cleanup_shutdown_rx).await;
     });
 
+    // Pause tunnels for their maintenance windows, and resume them after
+    if !config.maintenance.windows.is_empty() {
+        info!(
+            "Maintenance windows ({}): {}",
+            config.maintenance.timezone,
+            config.maintenance.windows.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+        );
+    }
+    tokio::spawn(maintenance::task(state.maintenance.clone(), registry.clone(), shutdown_tx.subscribe()));
+
+    // Roll up usage by token, saving it now and then
+    tokio::spawn(usage::rollup_task(state.usage.clone(), registry.clone(), shutdown_tx.subscribe()));
+
+    // Delete certificates of subdomains nobody uses any more
+    let prune_after_days = config.https.as_ref().map_or(0, |https| https.prune_unused_after_days);
+    if let Some(cert_manager) = cert_manager.as_ref().filter(|_| prune_after_days > 0) {
+        info!("Pruning certificates unused for {} days", prune_after_days);
+        tokio::spawn(cert_prune::prune_task(
+            state.clone(),
+            cert_manager.clone(),
+            prune_after_days,
+            shutdown_tx.subscribe(),
+        ));
+    }
+
+    // Watch for DNS no longer pointing here, before certificates fail to renew
+    let monitoring = &config.monitoring;
+    if monitoring.dns_check_interval_secs > 0 {
+        let interval = Duration::from_secs(monitoring.dns_check_interval_secs);
+        info!(
+            "Checking DNS for {} every {} with {}",
+            config.server.domain,
+            units::format_duration(interval),
+            monitoring.dns_resolver
+        );
+        let monitor = Monitor {
+            status: state.dns.clone(),
+            lookup: Arc::new(DohLookup::new(monitoring.dns_resolver.clone())),
+            domain: config.server.domain.clone(),
+            expected: monitoring.expected_ips.clone(),
+        };
+        let webhook = monitoring
+            .webhook_url
+            .clone()
+            .map(|url| Webhook::new(url, config.server.domain.clone()));
+        tokio::spawn(dns_monitor::monitor_task(monitor, interval, webhook, shutdown_tx.subscribe()));
+    }
+
+    // Start challenge token sweep task
+    let sweep_store = challenge_store.clone();
+    let sweep_shutdown_rx = shutdown_tx.subscribe();
+    tokio::spawn(async move {
+        acme::challenge_sweep_task(sweep_store, sweep_shutdown_rx).await;
+    });
+
     // Create graceful shutdown signal
     let shutdown_signal = async {
         let ctrl_c = async {
@@ -174,18 +556,77 @@ pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
         let _ = shutdown_tx.send(());
     };
 
+    // Serve metrics on their own port if configured, otherwise they're on the base domain
+    if config.metrics.enabled {
+        match config.metrics.port {
+            Some(port) => {
+                let metrics_addr = SocketAddr::new(config.server.bind_address, port);
+                let app = create_metrics_router(state.clone());
+                tokio::spawn(async move {
+                    info!("Serving metrics on {}{}", metrics_addr, router::METRICS_PATH);
+                    let result = match listen::bind(metrics_addr).and_then(tokio::net::TcpListener::from_std) {
+                        Ok(listener) => axum::serve(listener, app).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        error!("Metrics server error: {}", e);
+                    }
+                });
+            }
+            None => info!("Serving metrics at {}", state.public_url.url(&config.server.domain, router::METRICS_PATH)),
+        }
+    }
+
+    // Serve the control endpoint and admin API on their own port if configured, over
+    // TLS with the base domain's certificate when HTTPS is on
+    if let Some(control_addr) = config.server.control_addr() {
+        let app = create_control_router(state.clone());
+        let tls_config = cert_manager.clone().map(tls::create_tls_config).transpose()?;
+        let listener = listen::bind(control_addr).with_context(|| format!("Failed to listen on {}", control_addr))?;
+        tokio::spawn(async move {
+            info!("Serving the control endpoint and admin API on {}", control_addr);
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            let result = match tls_config {
+                Some(tls_config) => {
+                    axum_server::from_tcp_rustls(listener, RustlsConfig::from_config(Arc::new(tls_config)))
+                        .serve(service)
+                        .await
+                }
+                None => match tokio::net::TcpListener::from_std(listener) {
+                    Ok(listener) => axum::serve(listener, service).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = result {
+                error!("Control server error: {}", e);
+            }
+        });
+    }
+
     // Start HTTP server (always runs for ACME challenges and plain HTTP)
-    let http_addr = SocketAddr::from(([0, 0, 0, 0], config.server.http_port));
+    let http_addr = config.server.http_addr();
     let http_state = state.clone();
     let http_challenge_store = challenge_store.clone();
     let has_https = cert_manager.is_some();
+    // The acceptor reads each connection's header and supplies ConnectInfo itself
+    let proxy_protocol = config.server.proxy_protocol;
+    if proxy_protocol {
+        info!("Expecting PROXY protocol headers on the HTTP and HTTPS ports");
+    }
 
     let http_handle = tokio::spawn(async move {
         let app = create_acme_router(http_state, http_challenge_store, has_https);
         info!("Starting HTTP server on {}", http_addr);
-        let listener = tokio::net::TcpListener::bind(http_addr).await?;
+        let listener = listen::bind(http_addr).with_context(|| format!("Failed to listen on {}", http_addr))?;
+        if proxy_protocol {
+            return axum_server::from_tcp(listener)
+                .acceptor(ProxyProtocolAcceptor::new(DefaultAcceptor))
+                .serve(app.into_make_service())
+                .await
+                .map_err(|e| anyhow::anyhow!("HTTP server error: {}", e));
+        }
         axum::serve(
-            listener,
+            tokio::net::TcpListener::from_std(listener)?,
             app.into_make_service_with_connect_info::<SocketAddr>(),
         )
         .await
@@ -194,35 +635,60 @@ pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
 
     // Start HTTPS server if configured
     if let Some(cert_manager) = cert_manager {
-        let https_addr = SocketAddr::from(([0, 0, 0, 0], config.server.https_port));
+        let https_addr = config.server.https_addr();
         let https_state = state.clone();
 
-        // Request base domain certificate in background (after HTTP server has started)
-        let base_domain = config.server.domain.clone();
-        let cert_manager_clone = cert_manager.clone();
-        tokio::spawn(async move {
-            // Give HTTP server a moment to start
-            tokio::time::sleep(Duration::from_millis(500)).await;
-            
-            if !cert_manager_clone.has_cert(&base_domain) {
-                info!("Requesting certificate for base domain: {}", base_domain);
-                if let Err(e) = cert_manager_clone.request_cert(&base_domain).await {
-                    warn!("Failed to get base domain certificate: {}. Clients should connect via http:// until certificate is obtained.", e);
-                } else {
-                    info!("Base domain certificate ready - clients can now connect via https://");
-                }
-            }
-        });
+        // Request base domain certificate in background (after HTTP server has started),
+        // retrying with backoff until it's issued
+        if acme_client.is_some() {
+            let bootstrap_manager = cert_manager.clone();
+            let bootstrap_reload_rx = reload_tx.subscribe();
+            let bootstrap_shutdown_rx = shutdown_tx.subscribe();
+            tokio::spawn(async move {
+                // Give HTTP server a moment to start
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                tls::base_cert_bootstrap_task(bootstrap_manager, bootstrap_reload_rx, bootstrap_shutdown_rx).await;
+            });
+
+            // Renew certificates before they expire, swapping each in as it's issued
+            let renewal_manager = cert_manager.clone();
+            let renewal_shutdown_rx = shutdown_tx.subscribe();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                tls::certificate_renewal_task(renewal_manager, renewal_shutdown_rx).await;
+            });
+
+            // Request certificates visitors find missing, e.g. after a failed request
+            // at registration or a deleted certificate
+            let on_demand_registry = registry.clone();
+            let on_demand_requests =
+                cert_manager.issue_on_demand(move |subdomain| on_demand_registry.serves_subdomain(subdomain));
+            tokio::spawn(tls::on_demand_task(
+                cert_manager.clone(),
+                on_demand_requests,
+                shutdown_tx.subscribe(),
+            ));
+        }
 
         let https_handle = tokio::spawn(async move {
             let app = create_router(https_state);
             let tls_config = tls::create_tls_config(cert_manager)?;
 
             info!("Starting HTTPS server on {}", https_addr);
-
-            let config = axum_server::tls_rustls::RustlsConfig::from_config(Arc::new(tls_config));
-
-            axum_server::bind_rustls(https_addr, config)
+            let listener = listen::bind(https_addr).with_context(|| format!("Failed to listen on {}", https_addr))?;
+
+            let config = RustlsConfig::from_config(Arc::new(tls_config));
+
+            if proxy_protocol {
+                // The header comes before the TLS handshake
+                let acceptor = RustlsAcceptor::new(config).acceptor(ProxyProtocolAcceptor::new(DefaultAcceptor));
+                return axum_server::from_tcp(listener)
+                    .acceptor(acceptor)
+                    .serve(app.into_make_service())
+                    .await
+                    .map_err(|e| anyhow::anyhow!("HTTPS server error: {}", e));
+            }
+            axum_server::from_tcp_rustls(listener, config)
                 .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .map_err(|e| anyhow::anyhow!("HTTPS server error: {}", e))
@@ -246,6 +712,7 @@ pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
             },
             _ = shutdown_signal => {
                 info!("Shutting down gracefully...");
+                drain_tunnels(&registry).await;
             }
         }
     } else {
@@ -260,10 +727,128 @@ pub async fn run(config_path: &str, log_level: Level) -> Result<()> {
             },
             _ = shutdown_signal => {
                 info!("Shutting down gracefully...");
+                drain_tunnels(&registry).await;
             }
         }
     }
 
+    // Count whatever the tunnels still connected did, so it isn't lost
+    state.usage.roll_up(&registry.tunnels(), ownership::now_secs());
+    state.usage.save();
+
     info!("Server shutdown complete");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_config_updates_slow_request_threshold() {
+        let path = std::env::temp_dir().join(format!("loophole-config-{}.toml", uuid::Uuid::new_v4()));
+        let write = |threshold: &str| {
+            std::fs::write(
+                &path,
+                format!("[server]\ndomain = \"tunnel.example.com\"\n[tokens.tk_test]\n[logging]\n{}\n", threshold),
+            )
+            .unwrap()
+        };
+        let path_str = path.to_str().unwrap();
+        let slow_requests = SlowRequests::new(0);
+        let response_headers = ResponseHeaders::default();
+        let motd = Motd::default();
+        let pages = Pages::default();
+
+        write("slow_request_threshold_ms = 250");
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(slow_requests.threshold(), Some(Duration::from_millis(250)));
+
+        // A broken file leaves the current threshold in place
+        write("slow_request_threshold_ms = \"soon\"");
+        assert!(reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).is_err());
+        assert_eq!(slow_requests.threshold(), Some(Duration::from_millis(250)));
+
+        write("");
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(slow_requests.threshold(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_config_updates_response_headers() {
+        let path = std::env::temp_dir().join(format!("loophole-config-{}.toml", uuid::Uuid::new_v4()));
+        let write = |rules: &str| {
+            std::fs::write(
+                &path,
+                format!("[server]\ndomain = \"tunnel.example.com\"\n[tokens.tk_test]\n{}\n", rules),
+            )
+            .unwrap()
+        };
+        let path_str = path.to_str().unwrap();
+        let slow_requests = SlowRequests::new(0);
+        let response_headers = ResponseHeaders::default();
+        let motd = Motd::default();
+        let pages = Pages::default();
+        let x_env = || {
+            let mut headers = axum::http::HeaderMap::new();
+            response_headers.rules().apply("staging-web", &mut headers);
+            headers.get("x-env").map(|value| value.to_str().unwrap().to_string())
+        };
+
+        write("[[response_headers]]\nsubdomain = \"staging-*\"\nset = { X-Env = \"staging\" }");
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(x_env().as_deref(), Some("staging"));
+
+        // An invalid rule leaves the current ones in place
+        write("[[response_headers]]\nsubdomain = \"staging-*\"\nset = { \"X Env\" = \"qa\" }");
+        assert!(reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).is_err());
+        assert_eq!(x_env().as_deref(), Some("staging"));
+
+        write("");
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(x_env(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_config_updates_motd() {
+        let path = std::env::temp_dir().join(format!("loophole-config-{}.toml", uuid::Uuid::new_v4()));
+        let motd_path = path.with_extension("txt");
+        let write = |server: &str| {
+            std::fs::write(
+                &path,
+                format!("[server]\ndomain = \"tunnel.example.com\"\n{}\n[tokens.tk_test]\n", server),
+            )
+            .unwrap()
+        };
+        let path_str = path.to_str().unwrap();
+        let slow_requests = SlowRequests::new(0);
+        let response_headers = ResponseHeaders::default();
+        let motd = Motd::default();
+        let pages = Pages::default();
+
+        std::fs::write(&motd_path, "Maintenance Saturday\n").unwrap();
+        write(&format!("motd = {{ file = {:?} }}", motd_path));
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(motd.for_client(None).as_deref(), Some("Maintenance Saturday"));
+
+        // Reloading rereads the file
+        std::fs::write(&motd_path, "Maintenance Sunday\n").unwrap();
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(motd.for_client(None).as_deref(), Some("Maintenance Sunday"));
+
+        // A missing file leaves the current message in place
+        std::fs::remove_file(&motd_path).unwrap();
+        assert!(reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).is_err());
+        assert_eq!(motd.for_client(None).as_deref(), Some("Maintenance Sunday"));
+
+        write("");
+        reload_config(path_str, false, &slow_requests, &response_headers, &motd, &pages).unwrap();
+        assert_eq!(motd.for_client(None), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! The message of the day clients are shown when their tunnel connects
+//! (`server.motd`), such as a maintenance notice. Clients older than
+//! `motd_min_client_version`, or too old to report a version, get `motd_outdated`
+//! instead, e.g. asking them to upgrade. Messages kept in files are read at startup
+//! and again on reload (SIGHUP), so editing the file and reloading changes them.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use super::config::ServerConfig;
+
+/// Where a message comes from: the text itself, or `{ file = "..." }`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MotdSource {
+    Text(String),
+    File { file: PathBuf },
+}
+
+impl MotdSource {
+    fn read(&self) -> Result<String> {
+        match self {
+            MotdSource::Text(text) => Ok(text.clone()),
+            MotdSource::File { file } => std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read the message of the day from {}", file.display())),
+        }
+    }
+}
+
+/// A version as `major.minor.patch`, missing parts being 0
+pub type Version = (u64, u64, u64);
+
+/// The version a client reports, e.g. `0.5.1` from `0.5.1-beta (1a2b3c4d5e6f 2026-10-17)`
+pub fn parse_version(version: &str) -> Option<Version> {
+    let number = version.split_whitespace().next()?;
+    let number = number.trim_start_matches('v').split(['-', '+']).next()?;
+    let mut parts = number.split('.').map(|part| part.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// The messages as read from the config
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Messages {
+    current: Option<String>,
+    outdated: Option<String>,
+    min_client_version: Option<Version>,
+}
+
+impl Messages {
+    pub fn load(config: &ServerConfig) -> Result<Self> {
+        let read = |source: &Option<MotdSource>| -> Result<Option<String>> {
+            Ok(source
+                .as_ref()
+                .map(MotdSource::read)
+                .transpose()?
+                .map(|text| text.trim_end().to_string())
+                .filter(|text| !text.is_empty()))
+        };
+        let min_client_version = match config.motd_min_client_version {
+            Some(ref version) => Some(
+                parse_version(version)
+                    .with_context(|| format!("server.motd_min_client_version '{}' isn't a version", version))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            current: read(&config.motd)?,
+            outdated: read(&config.motd_outdated)?,
+            min_client_version,
+        })
+    }
+
+    /// The message for a client reporting `client_version`
+    pub fn for_client(&self, client_version: Option<&str>) -> Option<String> {
+        if let Some(min) = self.min_client_version {
+            // Clients from before versions were reported are older than any minimum;
+            // one reporting something unreadable gets the benefit of the doubt
+            let outdated = client_version.is_none_or(|version| parse_version(version).is_some_and(|v| v < min));
+            if outdated {
+                return self.outdated.clone();
+            }
+        }
+        self.current.clone()
+    }
+}
+
+/// The messages in use, which a config reload replaces
+#[derive(Debug, Default)]
+pub struct Motd {
+    messages: RwLock<Messages>,
+}
+
+impl Motd {
+    pub fn new(messages: Messages) -> Self {
+        Self {
+            messages: RwLock::new(messages),
+        }
+    }
+
+    pub fn set_messages(&self, messages: Messages) {
+        *self.messages.write().unwrap() = messages;
+    }
+
+    pub fn for_client(&self, client_version: Option<&str>) -> Option<String> {
+        self.messages.read().unwrap().for_client(client_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Config;
+
+    fn load(server: &str) -> Result<Messages> {
+        let config = Config::parse(&format!(
+            "[server]\ndomain = \"tunnel.example.com\"\n{}\n[tokens.tk_test]\n",
+            server
+        ))?;
+        Messages::load(&config.server)
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("0.5.1 (1a2b3c4d5e6f 2026-10-17)"), Some((0, 5, 1)));
+        assert_eq!(parse_version("0.5"), Some((0, 5, 0)));
+        assert_eq!(parse_version("v1.2.3-beta.1"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2.3+build"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("dev"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn test_outdated_clients_get_their_own_message() {
+        let messages = load(
+            "motd = \"Maintenance Saturday 02:00 UTC\"\nmotd_min_client_version = \"0.5\"\nmotd_outdated = \"Please upgrade\"",
+        )
+        .unwrap();
+        let for_client = |version| messages.for_client(version);
+        assert_eq!(for_client(Some("0.4.9 (1a2b3c4d5e6f 2026-10-17)")).as_deref(), Some("Please upgrade"));
+        assert_eq!(for_client(None).as_deref(), Some("Please upgrade"));
+        assert_eq!(for_client(Some("0.5.0")).as_deref(), Some("Maintenance Saturday 02:00 UTC"));
+        assert_eq!(for_client(Some("1.0.0")).as_deref(), Some("Maintenance Saturday 02:00 UTC"));
+        assert_eq!(for_client(Some("custom build")).as_deref(), Some("Maintenance Saturday 02:00 UTC"));
+
+        // Without an outdated message, outdated clients get none
+        let messages = load("motd = \"Hello\"\nmotd_min_client_version = \"0.5\"").unwrap();
+        assert_eq!(messages.for_client(Some("0.4.0")), None);
+        assert_eq!(messages.for_client(Some("0.5.0")).as_deref(), Some("Hello"));
+
+        // Without a minimum, everyone gets the same one
+        let messages = load("motd = \"Hello\"").unwrap();
+        assert_eq!(messages.for_client(None).as_deref(), Some("Hello"));
+        assert_eq!(Messages::default().for_client(None), None);
+    }
+
+    #[test]
+    fn test_messages_from_files() {
+        let path = std::env::temp_dir().join(format!("loophole-motd-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "Maintenance Saturday\n02:00 UTC\n\n").unwrap();
+        let messages = load(&format!("motd = {{ file = {:?} }}", path)).unwrap();
+        assert_eq!(messages.for_client(None).as_deref(), Some("Maintenance Saturday\n02:00 UTC"));
+
+        std::fs::remove_file(&path).unwrap();
+        let err = load(&format!("motd = {{ file = {:?} }}", path)).unwrap_err();
+        assert!(err.to_string().contains("Failed to read the message of the day"), "{}", err);
+        let err = load("motd_min_client_version = \"latest\"").unwrap_err();
+        assert!(err.to_string().contains("isn't a version"), "{}", err);
+    }
+}
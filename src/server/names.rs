@@ -0,0 +1,262 @@
+//! The names the server passes around, each its own type so one can't stand in for
+//! another: a full domain where a registry key belongs, or a token where its
+//! fingerprint should be written down.
+//!
+//! Each is a cheap-to-clone `Arc<str>` and reads as a `&str` wherever one is
+//! expected, but only a [`Subdomain`] that passed [`Registry::validate_subdomain`]
+//! can be made from one.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::registry::{Registry, RegistryError};
+
+/// Implements the traits every name shares: reading as a `&str`, comparing with
+/// strings, and formatting and serializing as its text
+macro_rules! name_impls {
+    ($name:ident) => {
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Borrow<str> for $name {
+            fn borrow(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                &*self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                &*self.0 == *other
+            }
+        }
+
+        impl PartialEq<String> for $name {
+            fn eq(&self, other: &String) -> bool {
+                *self.0 == **other
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&*self.0, f)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.0)
+            }
+        }
+    };
+}
+
+/// A name a tunnel may be registered on, below the base domain: 3-63 ASCII letters,
+/// digits and hyphens, internationalized names in their punycode form
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Subdomain(Arc<str>);
+
+impl Subdomain {
+    /// `name` as a subdomain, if it's a valid one. Case is kept; the registry and the
+    /// handler lowercase names before they get here.
+    pub fn new(name: &str) -> Result<Self, RegistryError> {
+        Registry::validate_subdomain(name)?;
+        Ok(Self(name.into()))
+    }
+
+    /// This subdomain of `base_domain`
+    pub fn under(&self, base_domain: &str) -> FullDomain {
+        FullDomain(format!("{}.{}", self.0, base_domain).into())
+    }
+}
+
+name_impls!(Subdomain);
+
+impl FromStr for Subdomain {
+    type Err = RegistryError;
+
+    fn from_str(name: &str) -> Result<Self, RegistryError> {
+        Self::new(name)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subdomain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Self::new(&name).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A domain certificates are issued for and visitors connect to: the base domain, a
+/// subdomain under it, or its wildcard
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FullDomain(Arc<str>);
+
+impl FullDomain {
+    pub fn new(domain: &str) -> Self {
+        Self(domain.into())
+    }
+
+    /// The wildcard covering the names directly below this domain
+    pub fn wildcard(&self) -> FullDomain {
+        Self(format!("*.{}", self.0).into())
+    }
+
+    /// The subdomain of `base_domain` this domain is, if it's a valid one directly
+    /// below it
+    pub fn subdomain_of(&self, base_domain: &str) -> Option<Subdomain> {
+        let name = self.0.strip_suffix(base_domain)?.strip_suffix('.')?;
+        Subdomain::new(name).ok()
+    }
+}
+
+name_impls!(FullDomain);
+
+impl From<String> for FullDomain {
+    fn from(domain: String) -> Self {
+        Self(domain.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for FullDomain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// A token as clients present it. Never written to disk or shown; its [`TokenId`] is.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct TokenSecret(Arc<str>);
+
+impl TokenSecret {
+    pub fn new(token: &str) -> Self {
+        Self(token.into())
+    }
+
+    /// The token itself, where it has to be compared byte by byte or handed back to an admin
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The fingerprint the token is recorded and shown as
+    pub fn id(&self) -> TokenId {
+        TokenId::of(&self.0)
+    }
+}
+
+impl From<String> for TokenSecret {
+    fn from(token: String) -> Self {
+        Self(token.into())
+    }
+}
+
+impl Borrow<str> for TokenSecret {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for TokenSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TokenSecret({})", self.id())
+    }
+}
+
+/// Short, stable fingerprint of a token that is safe to write to disk and show to admins
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TokenId(Arc<str>);
+
+impl TokenId {
+    /// The fingerprint of `token`
+    pub fn of(token: &str) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+        let id: String = digest.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Self(id.into())
+    }
+}
+
+name_impls!(TokenId);
+
+impl From<String> for TokenId {
+    fn from(id: String) -> Self {
+        Self(id.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for TokenId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_subdomain_is_validated() {
+        let app = Subdomain::new("my-app").unwrap();
+        assert_eq!(app, "my-app");
+        assert_eq!(app.to_string(), "my-app");
+        assert_eq!(app.under("tunnel.example.com"), "my-app.tunnel.example.com");
+        for name in ["ab", "-app", "my.app", "my_app", "münchen"] {
+            assert!(Subdomain::new(name).is_err(), "{}", name);
+            assert!(serde_json::from_value::<Subdomain>(serde_json::json!(name)).is_err(), "{}", name);
+        }
+        assert_eq!(serde_json::to_value(&app).unwrap(), "my-app");
+        assert_eq!(serde_json::from_value::<Subdomain>(serde_json::json!("my-app")).unwrap(), app);
+
+        // Maps keyed by name are looked up with plain strings
+        let map = HashMap::from([(app.clone(), 1)]);
+        assert_eq!(map.get("my-app"), Some(&1));
+    }
+
+    #[test]
+    fn test_full_domain_subdomain() {
+        let base = "tunnel.example.com";
+        let subdomain = |domain: &str| FullDomain::new(domain).subdomain_of(base).map(|s| s.to_string());
+        assert_eq!(subdomain("app.tunnel.example.com").as_deref(), Some("app"));
+        assert_eq!(FullDomain::new(base).wildcard(), "*.tunnel.example.com");
+        for domain in ["tunnel.example.com", "a.b.tunnel.example.com", "apptunnel.example.com", "app.example.org", "*.tunnel.example.com"] {
+            assert_eq!(subdomain(domain), None, "{}", domain);
+        }
+    }
+
+    #[test]
+    fn test_token_id() {
+        let token = TokenSecret::new("tk_alice");
+        assert_eq!(token.id(), TokenId::of("tk_alice"));
+        assert_eq!(token.id().len(), 16);
+        assert_ne!(token.id(), TokenId::of("tk_bob"));
+        // Debug output names the token by its id alone
+        let debug = format!("{:?}", token);
+        assert!(!debug.contains("tk_alice") && debug.contains(&*token.id()), "{}", debug);
+    }
+}
@@ -0,0 +1,157 @@
+//! Subdomain ownership records, kept next to each certificate as its `meta.json`.
+//!
+//! A certificate stays on disk after its tunnel disconnects, so whoever registers the
+//! subdomain next serves over it. Recording which token the name belongs to lets the
+//! server refuse other tokens when `strict_subdomain_ownership` is enabled.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cert_store::{CertStore, Item};
+use super::names::{TokenId, TokenSecret};
+use crate::units;
+
+/// Who a subdomain belongs to. Tokens are stored as a fingerprint, never in full.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ownership {
+    pub token_id: TokenId,
+    /// When this token first claimed the subdomain (unix seconds)
+    pub issued_at: u64,
+    /// When this token last registered the subdomain (unix seconds)
+    pub last_seen_at: u64,
+}
+
+/// Contents of a domain's `meta.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CertMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<Ownership>,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Ownership {
+    /// Record `token` as the owner, keeping the original claim time if it already was
+    pub fn claim(previous: Option<&Ownership>, token: &TokenSecret, now: u64) -> Self {
+        let token_id = token.id();
+        let issued_at = previous
+            .filter(|p| p.token_id == token_id)
+            .map(|p| p.issued_at)
+            .unwrap_or(now);
+        Self {
+            token_id,
+            issued_at,
+            last_seen_at: now,
+        }
+    }
+
+    /// Time left on the claim, or None once it has expired
+    pub fn remaining(&self, expiry: Duration, now: u64) -> Option<Duration> {
+        let expires_at = self.last_seen_at.saturating_add(expiry.as_secs());
+        (expires_at > now).then(|| Duration::from_secs(expires_at - now))
+    }
+}
+
+/// Decide whether `token` may register a subdomain with the given owner
+pub fn check(
+    owner: Option<&Ownership>,
+    token: &TokenSecret,
+    strict: bool,
+    expiry: Duration,
+    now: u64,
+) -> Result<(), String> {
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+    if !strict || owner.token_id == token.id() {
+        return Ok(());
+    }
+    match owner.remaining(expiry, now) {
+        Some(remaining) => Err(format!(
+            "Subdomain is owned by another token (claim expires in {})",
+            units::format_duration(remaining)
+        )),
+        None => Ok(()),
+    }
+}
+
+pub async fn load(store: &dyn CertStore, domain: &str) -> Result<Option<Ownership>> {
+    let Some(content) = store.get(Item::Meta(domain)).await? else {
+        return Ok(None);
+    };
+    let meta: CertMeta = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid meta.json for {}", domain))?;
+    Ok(meta.owner)
+}
+
+/// Record `domain`'s owner, or remove the record when released
+pub async fn save(store: &dyn CertStore, domain: &str, owner: Option<&Ownership>) -> Result<()> {
+    let Some(owner) = owner else {
+        return store.delete(Item::Meta(domain)).await;
+    };
+    let meta = CertMeta {
+        owner: Some(owner.clone()),
+    };
+    store
+        .put(Item::Meta(domain), serde_json::to_string_pretty(&meta)?.as_bytes())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86400;
+    const EXPIRY: Duration = Duration::from_secs(30 * DAY);
+
+    fn token(token: &str) -> TokenSecret {
+        TokenSecret::new(token)
+    }
+
+    #[test]
+    fn test_default_mode_allows_any_token() {
+        let owner = Ownership::claim(None, &token("tk_alice"), 1000);
+        assert_eq!(check(Some(&owner), &token("tk_mallory"), false, EXPIRY, 1000), Ok(()));
+    }
+
+    #[test]
+    fn test_strict_mode_until_expiry() {
+        let owner = Ownership::claim(None, &token("tk_alice"), 1000);
+        assert_ne!(owner.token_id, "tk_alice");
+
+        assert_eq!(check(None, &token("tk_mallory"), true, EXPIRY, 1000), Ok(()));
+        assert_eq!(check(Some(&owner), &token("tk_alice"), true, EXPIRY, 1000), Ok(()));
+
+        let err = check(Some(&owner), &token("tk_mallory"), true, EXPIRY, 1000 + DAY).unwrap_err();
+        assert!(err.contains("expires in 29d"), "{}", err);
+
+        assert_eq!(check(Some(&owner), &token("tk_mallory"), true, EXPIRY, 1000 + 30 * DAY), Ok(()));
+
+        // Registering again extends the claim but keeps the original issue time
+        let renewed = Ownership::claim(Some(&owner), &token("tk_alice"), 1000 + 20 * DAY);
+        assert_eq!(renewed.issued_at, 1000);
+        assert!(check(Some(&renewed), &token("tk_mallory"), true, EXPIRY, 1000 + 30 * DAY).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_meta_round_trip() {
+        let dir = std::env::temp_dir().join(format!("loophole-meta-{}", uuid::Uuid::new_v4()));
+        let store = super::super::cert_store::FsCertStore::new(dir.clone()).await.unwrap();
+        let domain = "app.tunnel.example.com";
+
+        assert_eq!(load(&store, domain).await.unwrap(), None);
+        let owner = Ownership::claim(None, &token("tk_alice"), 1000);
+        save(&store, domain, Some(&owner)).await.unwrap();
+        assert_eq!(load(&store, domain).await.unwrap(), Some(owner));
+        save(&store, domain, None).await.unwrap();
+        assert_eq!(load(&store, domain).await.unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
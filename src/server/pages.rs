@@ -0,0 +1,526 @@
+//! The pages the server answers visitors with itself: unknown tunnels, refused and
+//! throttled requests, proxy errors and maintenance. Each is rendered from a template
+//! in `[pages] theme_dir` (`<page>.html`, else `error.html` for every page) or the
+//! built-in one, with `{{ name }}` placeholders filled in HTML-escaped. Browsers get
+//! the page; other clients get its message as plain text, as they always have.
+//!
+//! Pages carry values visitors control, such as the Host header, so every value is
+//! escaped, substitution is a single pass (a value containing `{{ host }}` stays as it
+//! is), and the Content-Security-Policy allows no scripts, only the template's own
+//! inline styles by hash. Those are hashed when the template is read, so placeholders
+//! aren't allowed in them. Templates are read at startup and on reload (SIGHUP), and
+//! one with an unknown placeholder is refused rather than rendered with a hole.
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::debug;
+
+use super::config::PagesConfig;
+
+const BUILT_IN: &str = include_str!("pages.html");
+
+/// Used for every page the theme has no template of its own for
+const FALLBACK_NAME: &str = "error";
+
+/// A page the server renders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Page {
+    /// No tunnel at the address visited
+    NotFound,
+    /// The visitor's address isn't allowed
+    Forbidden,
+    /// The tunnel is over its request rate
+    TooManyRequests,
+    /// The request couldn't be passed to the tunnel or answered by it
+    ProxyError,
+    /// The tunnel is paused for maintenance
+    Maintenance,
+}
+
+impl Page {
+    pub const ALL: [Page; 5] = [
+        Page::NotFound,
+        Page::Forbidden,
+        Page::TooManyRequests,
+        Page::ProxyError,
+        Page::Maintenance,
+    ];
+
+    /// The template's file name in the theme directory, without `.html`
+    pub fn name(self) -> &'static str {
+        match self {
+            Page::NotFound => "not_found",
+            Page::Forbidden => "forbidden",
+            Page::TooManyRequests => "rate_limited",
+            Page::ProxyError => "proxy_error",
+            Page::Maintenance => "maintenance",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Page::NotFound => "Tunnel not found",
+            Page::Forbidden => "Forbidden",
+            Page::TooManyRequests => "Too many requests",
+            Page::ProxyError => "Tunnel error",
+            Page::Maintenance => "Down for maintenance",
+        }
+    }
+}
+
+/// A placeholder templates may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    Subdomain,
+    Host,
+    RequestId,
+    Status,
+    Title,
+    Message,
+    RetryAfter,
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "subdomain" => Var::Subdomain,
+            "host" => Var::Host,
+            "request_id" => Var::RequestId,
+            "status" => Var::Status,
+            "title" => Var::Title,
+            "message" => Var::Message,
+            "retry_after" => Var::RetryAfter,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Var(Var),
+}
+
+/// A template split into text and placeholders, so rendering is one pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+    /// The CSP sources allowing its `<style>` blocks, e.g. `'sha256-...'`
+    style_src: Vec<String>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut style_src = Vec::new();
+        for style in style_blocks(source) {
+            if style.contains("{{") {
+                return Err("placeholders aren't allowed in <style> blocks".to_string());
+            }
+            let digest = ring::digest::digest(&ring::digest::SHA256, style.as_bytes());
+            style_src.push(format!("'sha256-{}'", base64::engine::general_purpose::STANDARD.encode(digest.as_ref())));
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let name = rest[start + 2..start + 2 + len].trim();
+            let var = Var::parse(name).ok_or_else(|| format!("unknown placeholder '{{{{ {} }}}}'", name))?;
+            segments.push(Segment::Text(rest[..start].to_string()));
+            segments.push(Segment::Var(var));
+            rest = &rest[start + 2 + len + 2..];
+        }
+        segments.push(Segment::Text(rest.to_string()));
+        segments.retain(|segment| segment != &Segment::Text(String::new()));
+        Ok(Self { segments, style_src })
+    }
+
+    fn render(&self, value: impl Fn(Var) -> String) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => out.push_str(text),
+                Segment::Var(var) => out.push_str(&escape(&value(*var))),
+            }
+        }
+        out
+    }
+}
+
+/// `value` made safe for HTML text and attribute values
+pub fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// What a page is about
+#[derive(Debug, Clone, Default)]
+pub struct PageVars {
+    pub status: Option<StatusCode>,
+    /// Shown as the page's text, and as the whole body for clients that aren't browsers
+    pub message: String,
+    pub host: String,
+    pub subdomain: Option<String>,
+    /// Seconds until the visitor should try again, also sent as Retry-After
+    pub retry_after: Option<u64>,
+    /// The ID the request was logged with, if it got that far; a new one otherwise
+    pub request_id: Option<String>,
+}
+
+impl PageVars {
+    pub fn new(status: StatusCode, message: impl Into<String>, host: &str) -> Self {
+        Self {
+            status: Some(status),
+            message: message.into(),
+            host: host.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_subdomain(mut self, subdomain: &str) -> Self {
+        self.subdomain = Some(subdomain.to_string());
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+}
+
+/// The templates from a theme directory
+#[derive(Debug, Default)]
+pub struct Theme {
+    pages: HashMap<Page, Template>,
+    fallback: Option<Template>,
+}
+
+impl Theme {
+    /// Read the theme in `config.theme_dir`, if any. Pages it has no template for use
+    /// its `error.html`, or the built-in page.
+    pub fn load(config: &PagesConfig) -> Result<Self> {
+        let Some(ref dir) = config.theme_dir else {
+            return Ok(Self::default());
+        };
+        if !dir.is_dir() {
+            anyhow::bail!("pages.theme_dir {} isn't a directory", dir.display());
+        }
+        let read = |name: &str| -> Result<Option<Template>> {
+            let path = dir.join(format!("{}.html", name));
+            if !path.exists() {
+                return Ok(None);
+            }
+            let source = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            Template::parse(&source)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+        };
+        let mut pages = HashMap::new();
+        for page in Page::ALL {
+            if let Some(template) = read(page.name())? {
+                pages.insert(page, template);
+            }
+        }
+        Ok(Self {
+            pages,
+            fallback: read(FALLBACK_NAME)?,
+        })
+    }
+
+    fn template(&self, page: Page) -> Option<&Template> {
+        self.pages.get(&page).or(self.fallback.as_ref())
+    }
+
+    /// The templates found, for the log, e.g. "maintenance, error"
+    pub fn describe(&self) -> String {
+        let mut names: Vec<&str> = self.pages.keys().map(|page| page.name()).collect();
+        names.sort_unstable();
+        if self.fallback.is_some() {
+            names.push(FALLBACK_NAME);
+        }
+        if names.is_empty() {
+            "no templates".to_string()
+        } else {
+            names.join(", ")
+        }
+    }
+}
+
+/// Renders pages with the current theme, which a config reload replaces
+#[derive(Debug)]
+pub struct Pages {
+    built_in: Template,
+    theme: RwLock<Arc<Theme>>,
+}
+
+impl Default for Pages {
+    fn default() -> Self {
+        Self::new(Theme::default())
+    }
+}
+
+impl Pages {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            built_in: Template::parse(BUILT_IN).expect("built-in page template is valid"),
+            theme: RwLock::new(Arc::new(theme)),
+        }
+    }
+
+    pub fn set_theme(&self, theme: Theme) {
+        *self.theme.write().unwrap() = Arc::new(theme);
+    }
+
+    /// `page` as HTML for browsers (see [`wants_html`]), or its message as plain text
+    pub fn render(&self, page: Page, html: bool, vars: PageVars) -> Response {
+        let status = vars.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let request_id = vars.request_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        debug!(page = page.name(), request_id = %request_id, host = %vars.host, "Serving page");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            headers.insert("x-request-id", value);
+        }
+        if let Some(retry_after) = vars.retry_after {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        if !html {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+            return (status, headers, vars.message).into_response();
+        }
+
+        let theme = self.theme.read().unwrap().clone();
+        let template = theme.template(page).unwrap_or(&self.built_in);
+        let body = template.render(|var| match var {
+            Var::Subdomain => vars.subdomain.clone().unwrap_or_default(),
+            Var::Host => vars.host.clone(),
+            Var::RequestId => request_id.clone(),
+            Var::Status => format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or_default()),
+            Var::Title => page.title().to_string(),
+            Var::Message => vars.message.clone(),
+            Var::RetryAfter => vars.retry_after.map(|secs| secs.to_string()).unwrap_or_default(),
+        });
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        if let Ok(csp) = HeaderValue::from_str(&content_security_policy(&template.style_src)) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, csp);
+        }
+        (status, headers, body).into_response()
+    }
+}
+
+/// Whether a request's headers ask for HTML, as browsers' do
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// The contents of `source`'s `<style>` blocks
+fn style_blocks(source: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("<style") {
+        let Some(open_end) = rest[start..].find('>') else {
+            break;
+        };
+        let content_start = start + open_end + 1;
+        let Some(len) = rest[content_start..].find("</style>") else {
+            break;
+        };
+        blocks.push(&rest[content_start..content_start + len]);
+        rest = &rest[content_start + len..];
+    }
+    blocks
+}
+
+/// A policy allowing nothing but the template's own `<style>` blocks, by hash, and
+/// data: images, so a theme can't be made to run or load anything
+fn content_security_policy(style_src: &[String]) -> String {
+    let style_src = if style_src.is_empty() { "'none'".to_string() } else { style_src.join(" ") };
+    format!(
+        "default-src 'none'; style-src {}; img-src data:; base-uri 'none'; form-action 'none'; frame-ancestors 'none'",
+        style_src
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+
+    async fn body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn theme_dir(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loophole-theme-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+        dir
+    }
+
+    fn load(dir: &Path) -> Result<Theme> {
+        Theme::load(&PagesConfig {
+            theme_dir: Some(dir.to_path_buf()),
+        })
+    }
+
+    #[test]
+    fn test_wants_html() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            wants_html(&headers)
+        };
+        assert!(accept("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(accept("application/json; q=0.9, TEXT/HTML;q=0.5"));
+        assert!(!accept("*/*"));
+        assert!(!accept("application/json"));
+        assert!(!wants_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("<script>alert('x')</script>"), "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;");
+        assert_eq!(escape("a\"b&c"), "a&quot;b&amp;c");
+        assert_eq!(escape("myapp.tunnel.example.com"), "myapp.tunnel.example.com");
+    }
+
+    #[test]
+    fn test_template_parse() {
+        let template = Template::parse("<h1>{{title}}</h1><p>{{  message  }}</p>").unwrap();
+        assert_eq!(template.render(|var| format!("{:?}", var)), "<h1>Title</h1><p>Message</p>");
+
+        // An unclosed brace is text; an unknown name is an error
+        let template = Template::parse("a {{ b").unwrap();
+        assert_eq!(template.render(|_| unreachable!()), "a {{ b");
+        let err = Template::parse("{{ hostname }}").unwrap_err();
+        assert!(err.contains("unknown placeholder '{{ hostname }}'"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_values_are_escaped_once() {
+        let pages = Pages::default();
+        let host = "<img src=x onerror=alert(1)>{{ request_id }}.tunnel.example.com";
+        let response = pages.render(Page::NotFound, true, PageVars::new(StatusCode::NOT_FOUND, "Tunnel not found", host));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        let body = body(response).await;
+        assert!(!body.contains("<img"), "{}", body);
+        // Placeholders in values aren't expanded
+        assert!(body.contains("&lt;img src=x onerror=alert(1)&gt;{{ request_id }}.tunnel.example.com"), "{}", body);
+        assert!(body.contains("404 Not Found"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_plain_text_for_other_clients() {
+        let pages = Pages::default();
+        let vars = PageVars::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests", "myapp.tunnel.example.com")
+            .with_retry_after(3);
+        let response = pages.render(Page::TooManyRequests, false, vars);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+        assert!(response.headers().get("x-request-id").is_some());
+        assert_eq!(body(response).await, "Too many requests");
+    }
+
+    #[tokio::test]
+    async fn test_csp_allows_only_the_pages_styles() {
+        let pages = Pages::default();
+        let response = pages.render(Page::ProxyError, true, PageVars::new(StatusCode::BAD_GATEWAY, "Bad Gateway", "x"));
+        let csp = response.headers()[header::CONTENT_SECURITY_POLICY].to_str().unwrap().to_string();
+        let body = body(response).await;
+        let style = &body[body.find("<style>").unwrap() + 7..body.find("</style>").unwrap()];
+        let digest = ring::digest::digest(&ring::digest::SHA256, style.as_bytes());
+        let hash = base64::engine::general_purpose::STANDARD.encode(digest.as_ref());
+        assert!(csp.starts_with("default-src 'none'; "), "{}", csp);
+        assert!(csp.contains(&format!("style-src 'sha256-{}';", hash)), "{}", csp);
+        assert!(!csp.contains("unsafe-inline") && !csp.contains("script-src"), "{}", csp);
+
+        assert!(content_security_policy(&Template::parse("<p>plain</p>").unwrap().style_src).contains("style-src 'none'"));
+
+        // Hashed from the template, so a value can't smuggle in a style of its own
+        let err = Template::parse("<style>p { color: {{ host }} }</style>").unwrap_err();
+        assert!(err.contains("<style>"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_the_logged_one() {
+        let pages = Pages::default();
+        let vars = PageVars::new(StatusCode::BAD_GATEWAY, "Bad Gateway", "x").with_request_id("3f2a-logged");
+        let response = pages.render(Page::ProxyError, true, vars);
+        assert_eq!(response.headers()["x-request-id"], "3f2a-logged");
+        assert!(body(response).await.contains("<code>3f2a-logged</code>"));
+    }
+
+    #[tokio::test]
+    async fn test_theme_overrides_and_fallback() {
+        let dir = theme_dir(&[
+            ("maintenance.html", "<p>Back in {{ retry_after }}s: {{ message }}</p>"),
+            ("error.html", "<p>{{ status }} for {{ subdomain }}</p>"),
+        ]);
+        let theme = load(&dir).unwrap();
+        assert_eq!(theme.describe(), "maintenance, error");
+        let pages = Pages::new(theme);
+
+        // Its own template
+        let vars = PageVars::new(StatusCode::SERVICE_UNAVAILABLE, "Patching <things>", "x").with_retry_after(60);
+        let response = pages.render(Page::Maintenance, true, vars);
+        assert_eq!(body(response).await, "<p>Back in 60s: Patching &lt;things&gt;</p>");
+
+        // error.html for the rest
+        let vars = PageVars::new(StatusCode::FORBIDDEN, "Forbidden", "x").with_subdomain("myapp");
+        assert_eq!(body(pages.render(Page::Forbidden, true, vars)).await, "<p>403 Forbidden for myapp</p>");
+
+        // Without error.html, the built-in page
+        std::fs::remove_file(dir.join("error.html")).unwrap();
+        pages.set_theme(load(&dir).unwrap());
+        let vars = PageVars::new(StatusCode::FORBIDDEN, "Forbidden", "x");
+        assert!(body(pages.render(Page::Forbidden, true, vars)).await.starts_with("<!DOCTYPE html>"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_themes_are_refused() {
+        let dir = theme_dir(&[("not_found.html", "<p>{{ hots }}</p>")]);
+        let err = load(&dir).unwrap_err().to_string();
+        assert!(err.contains("not_found.html") && err.contains("unknown placeholder"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let err = load(&dir).unwrap_err().to_string();
+        assert!(err.contains("isn't a directory"), "{}", err);
+        assert!(Theme::load(&PagesConfig::default()).unwrap().template(Page::NotFound).is_none());
+    }
+}
@@ -0,0 +1,179 @@
+//! Path tunnels (`expose --path-mode`), reached at `/t/<subdomain>/` on the base domain
+//! rather than on a subdomain of their own, for setups where a wildcard DNS record or
+//! certificate isn't an option. The prefix is stripped before a request reaches the
+//! client, so the service sees the paths it would on its own subdomain, and put back on
+//! the redirects and cookie paths it sends so visitors stay inside the tunnel.
+
+/// Where path tunnels live on the base domain
+const PREFIX: &str = "/t/";
+
+/// The prefix a path tunnel's requests arrive under, e.g. `/t/myapp`
+pub fn prefix(subdomain: &str) -> String {
+    format!("{}{}", PREFIX, subdomain)
+}
+
+/// Split a request path under `/t/` into the tunnel's name and the path the service
+/// sees, which is empty for `/t/<name>` itself. None for any other path.
+pub fn split(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix(PREFIX)?;
+    let (name, path) = match rest.find('/') {
+        Some(end) => rest.split_at(end),
+        None => (rest, ""),
+    };
+    (!name.is_empty()).then_some((name, path))
+}
+
+/// Rewrites a path tunnel's response headers to point back under its prefix
+#[derive(Debug, Clone)]
+pub struct PathRewrite {
+    prefix: String,
+    domain: String,
+}
+
+impl PathRewrite {
+    pub fn new(subdomain: &str, domain: &str) -> Self {
+        Self {
+            prefix: prefix(subdomain),
+            domain: domain.to_string(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The value to send instead of a response header's, if it needs rewriting
+    pub fn response_header(&self, name: &str, value: &str) -> Option<String> {
+        if name.eq_ignore_ascii_case("location") || name.eq_ignore_ascii_case("content-location") {
+            self.location(value)
+        } else if name.eq_ignore_ascii_case("set-cookie") {
+            self.set_cookie(value)
+        } else {
+            None
+        }
+    }
+
+    fn is_prefixed(&self, path: &str) -> bool {
+        path.strip_prefix(self.prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// A redirect to a path on the service, or to a URL on the base domain (where the
+    /// service believes it is), goes under the prefix. Relative references already
+    /// resolve inside it, and other hosts are left alone.
+    fn location(&self, value: &str) -> Option<String> {
+        if value.starts_with('/') && !value.starts_with("//") {
+            return (!self.is_prefixed(value)).then(|| format!("{}{}", self.prefix, value));
+        }
+
+        // Scheme-relative references are parsed as http and written back without it
+        let scheme_relative = value.starts_with("//");
+        let mut url = if scheme_relative {
+            url::Url::parse(&format!("http:{}", value)).ok()?
+        } else {
+            url::Url::parse(value).ok()?
+        };
+        let on_domain = url.host_str().is_some_and(|host| host.eq_ignore_ascii_case(&self.domain));
+        if !on_domain || self.is_prefixed(url.path()) {
+            return None;
+        }
+        url.set_path(&format!("{}{}", self.prefix, url.path()));
+        let rewritten = url.to_string();
+        Some(if scheme_relative {
+            rewritten["http:".len()..].to_string()
+        } else {
+            rewritten
+        })
+    }
+
+    /// A cookie scoped to a path on the service is scoped to that path under the
+    /// prefix. Without a `Path` the browser uses the request's directory, which is
+    /// already under it.
+    fn set_cookie(&self, value: &str) -> Option<String> {
+        let mut rewritten = false;
+        let attributes: Vec<String> = value
+            .split(';')
+            .enumerate()
+            .map(|(i, attribute)| {
+                // The first part is the cookie itself, which may contain anything
+                if i == 0 {
+                    return attribute.to_string();
+                }
+                let trimmed = attribute.trim();
+                match trimmed.split_once('=') {
+                    Some((name, path))
+                        if name.trim().eq_ignore_ascii_case("path")
+                            && path.trim().starts_with('/')
+                            && !self.is_prefixed(path.trim()) =>
+                    {
+                        rewritten = true;
+                        // `Path=/` covers the whole service, which is the prefix itself
+                        let path = path.trim();
+                        let path = if path == "/" { "" } else { path };
+                        format!(" {}={}{}", name.trim(), self.prefix, path)
+                    }
+                    _ => attribute.to_string(),
+                }
+            })
+            .collect();
+        rewritten.then(|| attributes.join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite() -> PathRewrite {
+        PathRewrite::new("myapp", "tunnel.example.com")
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("/t/myapp/login"), Some(("myapp", "/login")));
+        assert_eq!(split("/t/myapp/"), Some(("myapp", "/")));
+        assert_eq!(split("/t/myapp"), Some(("myapp", "")));
+        assert_eq!(split("/t/"), None);
+        assert_eq!(split("/tunnels"), None);
+        assert_eq!(split("/"), None);
+    }
+
+    #[test]
+    fn test_location_rewriting() {
+        let rewrite = rewrite();
+        let cases = [
+            ("/login?next=%2F", Some("/t/myapp/login?next=%2F")),
+            ("/", Some("/t/myapp/")),
+            ("/t/myapp/login", None),
+            ("/t/myapplication", Some("/t/myapp/t/myapplication")),
+            ("login", None),
+            ("../up", None),
+            ("https://tunnel.example.com/login#top", Some("https://tunnel.example.com/t/myapp/login#top")),
+            ("https://TUNNEL.example.com", Some("https://tunnel.example.com/t/myapp/")),
+            ("//tunnel.example.com/login", Some("//tunnel.example.com/t/myapp/login")),
+            ("https://tunnel.example.com/t/myapp/", None),
+            ("https://github.com/login", None),
+            ("https://other.tunnel.example.com/login", None),
+        ];
+        for (location, expected) in cases {
+            assert_eq!(rewrite.response_header("Location", location).as_deref(), expected, "{}", location);
+        }
+    }
+
+    #[test]
+    fn test_set_cookie_rewriting() {
+        let rewrite = rewrite();
+        let cases = [
+            ("session=abc; Path=/; HttpOnly", Some("session=abc; Path=/t/myapp; HttpOnly")),
+            ("session=abc; path=/admin/", Some("session=abc; path=/t/myapp/admin/")),
+            ("session=abc;Path=/app;Secure", Some("session=abc; Path=/t/myapp/app;Secure")),
+            ("session=abc; Path=/t/myapp", None),
+            ("session=abc; HttpOnly", None),
+            ("path=/; Max-Age=60", None),
+        ];
+        for (cookie, expected) in cases {
+            assert_eq!(rewrite.response_header("set-cookie", cookie).as_deref(), expected, "{}", cookie);
+        }
+        assert_eq!(rewrite.response_header("Cache-Control", "no-store"), None);
+    }
+}
@@ -1,85 +1,290 @@
-use anyhow::Result;
 use axum::body::Body;
 use axum::response::{IntoResponse, Response};
-use bytes::Bytes;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::io::AsyncWriteExt;
 use http_body_util::BodyExt;
 use hyper::StatusCode;
+use hyper_util::rt::TokioIo;
+use std::fmt::Write as _;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt as _;
 use tokio::sync::mpsc;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
-use super::tunnel::Tunnel;
+use super::buffers::{read_into, ProxyBuffers};
+use super::config::{Config, TokenConfig};
+use super::framing::BodyFraming;
+use super::metrics::Metrics;
+use super::path_tunnel::PathRewrite;
+use super::public_url::{PublicUrlBuilder, Scheme};
+use super::registry::Registry;
+use super::response_headers::HeaderRules;
+use super::tunnel::{ProxyError, Tunnel};
+use crate::http_head::{parse_response, HeadError, HeadScanner, ResponseHead};
 
+/// Response header naming why the server couldn't proxy a request
+pub const ERROR_HEADER: &str = "x-loophole-error";
+
+/// Why a proxied request failed. The code is safe to show to visitors (it is sent in
+/// `X-Loophole-Error`); details stay in the server log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyFailure {
+    /// No yamux stream could be opened to the client
+    StreamOpenFailed,
+    /// Sending the request to the client failed
+    ClientWriteFailed,
+    /// The client didn't send response headers in time
+    ResponseHeaderTimeout,
+    /// The client's response couldn't be read or parsed
+    ResponseParseError,
+    /// The response body ended early or failed after the headers were sent
+    BodyStreamError,
+    /// Another client registered the subdomain before the response was complete
+    /// (with `strict_epoch`)
+    TunnelReplaced,
+}
+
+impl ProxyFailure {
+    pub const ALL: [ProxyFailure; 6] = [
+        ProxyFailure::StreamOpenFailed,
+        ProxyFailure::ClientWriteFailed,
+        ProxyFailure::ResponseHeaderTimeout,
+        ProxyFailure::ResponseParseError,
+        ProxyFailure::BodyStreamError,
+        ProxyFailure::TunnelReplaced,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ProxyFailure::StreamOpenFailed => "stream_open_failed",
+            ProxyFailure::ClientWriteFailed => "client_write_failed",
+            ProxyFailure::ResponseHeaderTimeout => "response_header_timeout",
+            ProxyFailure::ResponseParseError => "response_parse_error",
+            ProxyFailure::BodyStreamError => "body_stream_error",
+            ProxyFailure::TunnelReplaced => "tunnel_replaced",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ProxyFailure::ResponseHeaderTimeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl From<ProxyError> for ProxyFailure {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::StreamOpenFailed | ProxyError::ConnectionClosed => ProxyFailure::StreamOpenFailed,
+            ProxyError::WriteFailed => ProxyFailure::ClientWriteFailed,
+            ProxyError::Timeout => ProxyFailure::ResponseHeaderTimeout,
+            ProxyError::ReadFailed => ProxyFailure::ResponseParseError,
+        }
+    }
+}
+
+/// Generic body with the failure code in a header
+impl IntoResponse for ProxyFailure {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        (
+            status,
+            [(ERROR_HEADER, self.code())],
+            status.canonical_reason().unwrap_or("Proxy error"),
+        )
+            .into_response()
+    }
+}
+
+/// Per-request settings taken from the server config
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    pub is_https: bool,
+    /// Port visitors connected to, sent as X-Forwarded-Port
+    pub public_port: u16,
+    /// How long to wait for the client's response headers
+    pub header_timeout: Duration,
+    /// Largest request body forwarded to the client; larger ones get 413
+    pub max_body_bytes: usize,
+    /// Longest request line forwarded to the client; longer ones get 414
+    pub max_request_line: usize,
+    /// Fail requests whose tunnel is replaced before the response is complete
+    pub strict_epoch: bool,
+    /// Request headers not passed to the client, from its token's `strip_request_headers`
+    pub strip_request_headers: Vec<String>,
+    /// Methods the client may receive, from its token's `allowed_methods` (any if unset)
+    pub allowed_methods: Option<Vec<String>>,
+    /// Operator-set headers for the response, from `[[response_headers]]`
+    pub response_headers: Arc<HeaderRules>,
+    /// Set for path tunnels, whose prefix is sent as X-Forwarded-Prefix and put back on
+    /// the service's redirects and cookie paths
+    pub path_rewrite: Option<PathRewrite>,
+    /// Sizes buffers for request heads from the heads every request has written
+    pub buffers: Arc<ProxyBuffers>,
+    /// Sent as X-Request-ID and logged with everything about the request, so the ID on
+    /// an error page can be looked up
+    pub request_id: String,
+}
+
+impl ProxyOptions {
+    pub fn new(config: &Config, public_url: &PublicUrlBuilder) -> Self {
+        Self {
+            is_https: public_url.scheme() == Scheme::Https,
+            public_port: public_url.port(),
+            header_timeout: Duration::from_secs(config.limits.request_timeout_secs),
+            max_body_bytes: config.limits.max_request_body_bytes,
+            max_request_line: config.limits.max_request_line,
+            strict_epoch: config.server.strict_epoch,
+            strip_request_headers: Vec::new(),
+            allowed_methods: None,
+            response_headers: Arc::default(),
+            path_rewrite: None,
+            buffers: Arc::default(),
+            request_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Apply the request policy the operator set for the tunnel's token
+    pub fn with_token_policy(mut self, token: &TokenConfig) -> Self {
+        self.strip_request_headers = token.strip_request_headers.clone();
+        self.allowed_methods = token.allowed_methods.clone();
+        self
+    }
+
+    pub fn with_response_headers(mut self, rules: Arc<HeaderRules>) -> Self {
+        self.response_headers = rules;
+        self
+    }
+
+    pub fn with_path_rewrite(mut self, rewrite: PathRewrite) -> Self {
+        self.path_rewrite = Some(rewrite);
+        self
+    }
+
+    pub fn with_buffers(mut self, buffers: Arc<ProxyBuffers>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    fn allows(&self, method: &hyper::Method) -> bool {
+        self.allowed_methods
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|name| name.eq_ignore_ascii_case(method.as_str())))
+    }
+
+    fn strips(&self, header: &str) -> bool {
+        self.strip_request_headers.iter().any(|name| name.eq_ignore_ascii_case(header))
+    }
+}
+
+/// Proxy `req` through the tunnel, counting failures in `metrics`. `registry` tells
+/// whether the tunnel was replaced while the request was in flight.
 pub async fn proxy_request(
     tunnel: Arc<Tunnel>,
     req: hyper::Request<axum::body::Body>,
     client_ip: std::net::IpAddr,
-    is_https: bool,
-) -> Result<Response> {
-    let request_id = uuid::Uuid::new_v4().to_string();
-    tunnel.increment_requests();
+    options: ProxyOptions,
+    registry: Arc<Registry>,
+    metrics: Arc<Metrics>,
+) -> Result<Response, ProxyFailure> {
+    let result = forward(tunnel, req, client_ip, options, registry, metrics.clone()).await;
+    if let Err(failure) = result {
+        metrics.record_proxy_error(failure);
+    }
+    result
+}
+
+/// Sent in `X-Loophole-Error` on responses to requests that needed a second attempt
+pub const RETRIED: &str = "retried";
+
+/// Request head and body bytes kept to send again; requests sending more aren't retried
+const RETRY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Methods safe to send to the client twice
+fn is_idempotent(method: &hyper::Method) -> bool {
+    matches!(*method, hyper::Method::GET | hyper::Method::HEAD | hyper::Method::OPTIONS)
+}
+
+enum Sent {
+    Stream(yamux::Stream),
+    /// The body passed `max_body_bytes`
+    TooLarge,
+}
 
-    // Get a yamux stream from the tunnel
+/// Open a stream to the client and send it `head`, the body an earlier attempt sent
+/// (kept in `replay`), then the rest of `body`. What's sent is added to `replay`
+/// while it fits in [`RETRY_BUFFER_BYTES`]; past that, or once the visitor's body
+/// fails, `replay` is cleared and the request can't be retried. Only the rest of
+/// `body` is counted, as the head and `replay` were by the first attempt.
+async fn send_request(
+    tunnel: &Tunnel,
+    request_id: &str,
+    head: &[u8],
+    body: &mut Body,
+    replay: &mut Option<Vec<u8>>,
+    options: &ProxyOptions,
+    metrics: &Metrics,
+) -> Result<Sent, ProxyFailure> {
     let mut stream = match tunnel.get_stream().await {
         Ok(s) => s,
         Err(e) => {
             error!(request_id = %request_id, "Failed to get tunnel stream: {}", e);
-            return Ok(bad_gateway("Failed to connect to tunnel"));
+            return Err(e.into());
         }
     };
 
-    // Build and send request headers
-    let (parts, body) = req.into_parts();
-    
-    let mut header_bytes = Vec::new();
-    header_bytes.extend_from_slice(
-        format!(
-            "{} {} HTTP/1.1\r\n",
-            parts.method,
-            parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/")
-        )
-        .as_bytes(),
-    );
-
-    // Add headers (skip hop-by-hop headers)
-    for (name, value) in &parts.headers {
-        if !is_hop_by_hop_header(name.as_str()) {
-            header_bytes.extend_from_slice(format!("{}: ", name).as_bytes());
-            header_bytes.extend_from_slice(value.as_bytes());
-            header_bytes.extend_from_slice(b"\r\n");
-        }
+    if let Err(e) = stream.write_all(head).await {
+        error!(request_id = %request_id, "Failed to write headers to tunnel: {}", e);
+        return Err(ProxyFailure::ClientWriteFailed);
     }
 
-    // Add forwarded headers
-    let proto = if is_https { "https" } else { "http" };
-    header_bytes.extend_from_slice(format!("X-Forwarded-For: {}\r\n", client_ip).as_bytes());
-    header_bytes.extend_from_slice(format!("X-Forwarded-Proto: {}\r\n", proto).as_bytes());
-    header_bytes.extend_from_slice(format!("X-Request-ID: {}\r\n", request_id).as_bytes());
-    header_bytes.extend_from_slice(b"\r\n");
-
-    // Write headers to tunnel
-    if let Err(e) = stream.write_all(&header_bytes).await {
-        error!(request_id = %request_id, "Failed to write headers to tunnel: {}", e);
-        return Ok(bad_gateway("Failed to send request to tunnel"));
+    let mut body_bytes = 0usize;
+    if let Some(sent) = replay.as_deref().filter(|sent| !sent.is_empty()) {
+        if let Err(e) = stream.write_all(sent).await {
+            error!(request_id = %request_id, "Failed to write body to tunnel: {}", e);
+            return Err(ProxyFailure::ClientWriteFailed);
+        }
+        body_bytes = sent.len();
     }
 
-    // Stream request body to tunnel
-    let mut body_stream = body;
-    while let Some(chunk) = body_stream.frame().await {
+    // Stream the rest of the body, enforcing the size limit as it arrives
+    while let Some(chunk) = body.frame().await {
         match chunk {
             Ok(frame) => {
                 if let Ok(data) = frame.into_data() {
+                    body_bytes = body_bytes.saturating_add(data.len());
+                    if body_bytes > options.max_body_bytes {
+                        debug!(request_id = %request_id, "Request body exceeds limit after {} bytes", body_bytes);
+                        return Ok(Sent::TooLarge);
+                    }
+                    if head.len() + body_bytes > RETRY_BUFFER_BYTES {
+                        *replay = None;
+                    }
+                    if let Some(sent) = replay.as_mut() {
+                        sent.extend_from_slice(&data);
+                    }
                     if let Err(e) = stream.write_all(&data).await {
                         error!(request_id = %request_id, "Failed to write body to tunnel: {}", e);
-                        return Ok(bad_gateway("Failed to send request body to tunnel"));
+                        return Err(ProxyFailure::ClientWriteFailed);
                     }
+                    metrics.record_bytes_in(data.len());
+                    tunnel.record_bytes_in(data.len());
                 }
             }
             Err(e) => {
                 error!(request_id = %request_id, "Failed to read request body: {}", e);
-                return Ok(bad_gateway("Failed to read request body"));
+                *replay = None;
+                return Err(ProxyFailure::ClientWriteFailed);
             }
         }
     }
@@ -87,149 +292,370 @@ pub async fn proxy_request(
     // Flush to ensure all data is sent
     if let Err(e) = stream.flush().await {
         error!(request_id = %request_id, "Failed to flush tunnel stream: {}", e);
-        return Ok(bad_gateway("Failed to send request to tunnel"));
+        return Err(ProxyFailure::ClientWriteFailed);
     }
+    Ok(Sent::Stream(stream))
+}
 
-    debug!(request_id = %request_id, "Request sent to tunnel, reading response");
+async fn forward(
+    tunnel: Arc<Tunnel>,
+    mut req: hyper::Request<axum::body::Body>,
+    client_ip: std::net::IpAddr,
+    options: ProxyOptions,
+    registry: Arc<Registry>,
+    metrics: Arc<Metrics>,
+) -> Result<Response, ProxyFailure> {
+    let request_id = options.request_id.clone();
 
-    // Read response headers from tunnel with timeout
-    let mut header_buf = Vec::new();
-    let mut buf = [0u8; 4096];
-    let header_end;
-    
-    let header_read_timeout = std::time::Duration::from_secs(30);
-    let header_read_start = std::time::Instant::now();
+    // Refused before anything reaches the client, which can't loosen its token's policy
+    if !options.allows(req.method()) {
+        info!(
+            request_id = %request_id,
+            subdomain = %tunnel.subdomain,
+            method = %req.method(),
+            "Refused a method the tunnel's token may not receive"
+        );
+        metrics.record_refused_method();
+        return Ok(method_not_allowed(options.allowed_methods.as_deref().unwrap_or_default()));
+    }
+
+    let epoch = tunnel.epoch();
+    tunnel.increment_requests();
+
+    // The visitor's connection, handed over once a WebSocket handshake completes
+    let on_upgrade = is_websocket_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
+
+    // The request line goes to the client as the visitor sent it, so a URL the client
+    // (or the service behind it) would refuse is answered here instead
+    let target = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let request_line = req.method().as_str().len() + 1 + target.len() + b" HTTP/1.1\r\n".len();
+    if request_line > options.max_request_line {
+        debug!(request_id = %request_id, length = request_line, "Request line exceeds limit");
+        return Ok(uri_too_long());
+    }
+
+    // Refuse bodies declared too large before involving the client
+    let declared_length = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_length.is_some_and(|len| len > options.max_body_bytes as u64) {
+        debug!(request_id = %request_id, "Request body exceeds limit (Content-Length)");
+        return Ok(payload_too_large());
+    }
+
+    // Build the request head
+    let (parts, mut body) = req.into_parts();
+    let is_head = parts.method == hyper::Method::HEAD;
     
-    loop {
-        // Check timeout
-        if header_read_start.elapsed() > header_read_timeout {
-            warn!(request_id = %request_id, "Timeout waiting for response headers");
-            return Ok(gateway_timeout("Timeout waiting for response"));
+    // Written straight into a buffer sized from the heads seen so far
+    let mut header_bytes = options.buffers.head_buffer();
+    header_bytes.put_slice(parts.method.as_str().as_bytes());
+    header_bytes.put_u8(b' ');
+    header_bytes.put_slice(parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/").as_bytes());
+    header_bytes.put_slice(b" HTTP/1.1\r\n");
+
+    // Add headers (skip hop-by-hop headers, and the forwarded headers set below so an
+    // upstream proxy's or a spoofed value isn't passed along twice)
+    let mut stripped = Vec::new();
+    for (name, value) in &parts.headers {
+        if is_hop_by_hop_header(name.as_str()) || is_forwarded_header(name.as_str()) {
+            continue;
+        }
+        if options.strips(name.as_str()) {
+            stripped.push(name.as_str());
+            continue;
+        }
+        put_header(&mut header_bytes, name.as_str(), value.as_bytes());
+    }
+
+    // The hop-by-hop headers skipped above are what ask the client for an upgrade
+    if on_upgrade.is_some() {
+        header_bytes.put_slice(b"Connection: Upgrade\r\nUpgrade: websocket\r\n");
+    }
+
+    // Add forwarded headers. The token's policy strips these too, so an operator can
+    // keep visitors' addresses from a tunnel.
+    let proto = if options.is_https { "https" } else { "http" };
+    let prefix = options.path_rewrite.as_ref().map(|rewrite| rewrite.prefix());
+    let forwarded: [(&str, Option<&dyn std::fmt::Display>); 5] = [
+        ("X-Forwarded-For", Some(&client_ip)),
+        ("X-Forwarded-Proto", Some(&proto)),
+        ("X-Forwarded-Port", Some(&options.public_port)),
+        ("X-Forwarded-Prefix", prefix.as_ref().map(|prefix| prefix as &dyn std::fmt::Display)),
+        ("X-Request-ID", Some(&request_id)),
+    ];
+    for (name, value) in forwarded {
+        let Some(value) = value else {
+            continue;
+        };
+        if options.strips(name) {
+            stripped.push(name);
+            continue;
+        }
+        // Formatted into the buffer itself, with no string in between
+        let _ = write!(header_bytes, "{}: {}\r\n", name, value);
+    }
+    header_bytes.put_slice(b"\r\n");
+    options.buffers.record_head(header_bytes.len());
+
+    if !stripped.is_empty() {
+        info!(
+            request_id = %request_id,
+            subdomain = %tunnel.subdomain,
+            headers = %stripped.join(", "),
+            "Stripped request headers the tunnel's token may not receive"
+        );
+        metrics.record_stripped_headers(stripped.len());
+    }
+
+    // Idempotent requests are sent again on a fresh stream if the first can't be
+    // opened or written to, before the visitor has seen anything
+    let mut replay = is_idempotent(&parts.method).then(Vec::new);
+    let mut retried = false;
+    tunnel.record_bytes_in(header_bytes.len());
+    let mut stream = loop {
+        match send_request(&tunnel, &request_id, &header_bytes, &mut body, &mut replay, &options, &metrics).await {
+            Ok(Sent::Stream(stream)) => break stream,
+            Ok(Sent::TooLarge) => return Ok(payload_too_large()),
+            Err(failure) if !retried && replay.is_some() => {
+                warn!(
+                    request_id = %request_id,
+                    subdomain = %tunnel.subdomain,
+                    error_code = failure.code(),
+                    "Retrying request on a new stream"
+                );
+                retried = true;
+            }
+            Err(failure) => {
+                if retried {
+                    metrics.record_retry(false);
+                }
+                return Err(failure);
+            }
         }
-        
-        // Use a short timeout for each read to allow checking overall timeout
-        let read_result = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            stream.read(&mut buf)
-        ).await;
-        
-        match read_result {
+    };
+    if retried {
+        metrics.record_retry(true);
+    }
+
+    debug!(request_id = %request_id, "Request sent to tunnel, reading response");
+
+    // Read response headers from tunnel with timeout
+    // The head is scanned from where the last read left off, and whatever follows it
+    // in the buffer is the start of the body
+    let mut received = options.buffers.read_buffer();
+    let mut scanner = HeadScanner::default();
+    let deadline = tokio::time::Instant::now() + options.header_timeout;
+    let (head_len, head) = loop {
+        match tokio::time::timeout_at(deadline, read_into(&mut stream, &mut received)).await {
+            Err(_) => {
+                warn!(request_id = %request_id, "Timeout waiting for response headers");
+                return Err(ProxyFailure::ResponseHeaderTimeout);
+            }
             Ok(Ok(0)) => {
                 warn!(request_id = %request_id, "Tunnel closed before response headers");
-                return Ok(bad_gateway("Tunnel closed unexpectedly"));
+                return Err(ProxyFailure::ResponseParseError);
             }
             Ok(Ok(n)) => {
-                header_buf.extend_from_slice(&buf[..n]);
-                if let Some(pos) = find_header_end(&header_buf) {
-                    header_end = pos;
-                    break;
-                }
-                if header_buf.len() > 65536 {
-                    warn!(request_id = %request_id, "Response headers too large");
-                    return Ok(bad_gateway("Response headers too large"));
+                tunnel.record_bytes_out(n);
+                match final_head(&mut scanner, &mut received) {
+                    Ok(Some(found)) => break found,
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!(request_id = %request_id, "Invalid response headers: {}", e);
+                        return Err(ProxyFailure::ResponseParseError);
+                    }
                 }
             }
             Ok(Err(e)) => {
                 error!(request_id = %request_id, "Failed to read response from tunnel: {}", e);
-                return Ok(bad_gateway("Failed to read response from tunnel"));
-            }
-            Err(_) => {
-                // Individual read timeout - continue to check overall timeout
-                continue;
+                return Err(ProxyFailure::ResponseParseError);
             }
         }
-    }
-
-    // Parse response headers
-    let header_bytes = &header_buf[..header_end];
-    let initial_body = header_buf[header_end + 4..].to_vec(); // Data after \r\n\r\n
+    };
+    received.advance(head_len);
 
-    let header_str = match std::str::from_utf8(header_bytes) {
-        Ok(s) => s,
-        Err(_) => {
-            warn!(request_id = %request_id, "Invalid UTF-8 in response headers");
-            return Ok(bad_gateway("Invalid response from backend"));
+    let status_code = head.status;
+    let content_length = match head.headers.content_length() {
+        Ok(length) => length,
+        Err(e) => {
+            warn!(request_id = %request_id, "Invalid Content-Length in response: {}", e);
+            return Err(ProxyFailure::ResponseParseError);
         }
     };
-
-    let mut lines = header_str.lines();
-    let status_line = lines.next().unwrap_or("HTTP/1.1 502 Bad Gateway");
-    let status_parts: Vec<&str> = status_line.splitn(3, ' ').collect();
-    let status_code = status_parts
-        .get(1)
-        .and_then(|s| s.parse::<u16>().ok())
-        .unwrap_or(502);
+    let is_chunked = head.headers.is_chunked();
 
     let mut builder = hyper::Response::builder()
         .status(status_code)
         .header("X-Request-ID", &request_id);
-
-    // Parse and add response headers
-    let mut content_length: Option<usize> = None;
-    let mut is_chunked = false;
-    
-    for line in lines {
-        if line.is_empty() {
-            break;
-        }
-        if let Some((name, value)) = line.split_once(':') {
-            let name = name.trim();
-            let value = value.trim();
-            
-            if name.to_lowercase() == "content-length" {
-                content_length = value.parse().ok();
-            }
-            if name.to_lowercase() == "transfer-encoding" && value.to_lowercase().contains("chunked") {
-                is_chunked = true;
-            }
-            
-            if !is_hop_by_hop_header(name) {
-                builder = builder.header(name, value);
-            }
+    if retried {
+        builder = builder.header(ERROR_HEADER, RETRIED);
+    }
+    for (name, value) in head.headers.iter() {
+        if is_hop_by_hop_header(name) {
+            continue;
         }
+        let rewritten = options
+            .path_rewrite
+            .as_ref()
+            .zip(std::str::from_utf8(value).ok())
+            .and_then(|(rewrite, value)| rewrite.response_header(name, value));
+        builder = builder.header(name, rewritten.as_deref().map(str::as_bytes).unwrap_or(value));
+    }
+
+    // After the service's own headers, so the operator's rules have the last word
+    if let Some(headers) = builder.headers_mut() {
+        options.response_headers.apply(&tunnel.subdomain, headers);
     }
 
     debug!(
         request_id = %request_id,
+        epoch = epoch,
         status = status_code,
         content_length = ?content_length,
         is_chunked = is_chunked,
         "Response headers parsed"
     );
 
+    // The client disconnected and another registered the subdomain while this request
+    // was with the old one
+    if registry.replaced(&tunnel) {
+        if options.strict_epoch {
+            warn!(
+                request_id = %request_id,
+                subdomain = %tunnel.subdomain,
+                epoch = epoch,
+                "Tunnel was replaced before its response arrived; not passing it on"
+            );
+            return Err(ProxyFailure::TunnelReplaced);
+        }
+        metrics.record_stale_response();
+        warn!(
+            request_id = %request_id,
+            subdomain = %tunnel.subdomain,
+            epoch = epoch,
+            "Passing on a response from a tunnel that has since been replaced"
+        );
+    }
+
+    // The local server accepted the WebSocket handshake: pass its 101 on to the visitor,
+    // then relay raw bytes both ways for as long as the socket lives
+    if let (Some(on_upgrade), 101) = (on_upgrade, status_code) {
+        let response = builder
+            .header(hyper::header::CONNECTION, "Upgrade")
+            .header(hyper::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .map_err(|e| {
+                warn!(request_id = %request_id, "Invalid response headers from tunnel: {}", e);
+                ProxyFailure::ResponseParseError
+            })?;
+        tokio::spawn(relay_upgraded(on_upgrade, stream, received.freeze(), request_id, tunnel));
+        return Ok(response);
+    }
+
+    // Transfer-Encoding was dropped above and the chunks are decoded below, so a
+    // Content-Length alongside it (which chunked overrides) would be wrong
+    if is_chunked {
+        if let Some(headers) = builder.headers_mut() {
+            headers.remove(hyper::header::CONTENT_LENGTH);
+        }
+    }
+    // Clients re-chunk close-delimited bodies, so a body's end is never confused with
+    // the stream closing; older ones pass them on as they are
+    let mut framing = BodyFraming::for_response(status_code, is_head, content_length, is_chunked);
+    if matches!(framing, BodyFraming::UntilClose) {
+        debug!(request_id = %request_id, "Response has no framing; reading until the client closes the stream");
+    }
+
     // Create a channel for streaming response body
     let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
-    
-    // Send initial body data if any
-    if !initial_body.is_empty() {
-        let _ = tx.send(Ok(Bytes::from(initial_body.clone()))).await;
-    }
 
-    // Spawn task to stream remaining response body
+    // Spawn task to stream the response body as it arrives. It ends where the framing
+    // says the body ends: the client may keep the stream open (e.g. keep-alive).
     let request_id_clone = request_id.clone();
     tokio::spawn(async move {
-        let mut buf = [0u8; 8192];
-        let mut total_read = initial_body.len();
-        
-        loop {
-            match stream.read(&mut buf).await {
+        let mut total_read = received.len();
+        let mut replaced = false;
+
+        let failure = 'body: loop {
+            if options.strict_epoch && registry.replaced(&tunnel) {
+                replaced = true;
+                break Some(std::io::Error::other("tunnel replaced before the response was complete"));
+            }
+            // Body split off the read buffer and sent on as it is, without copying
+            loop {
+                match framing.next_chunk(&mut received) {
+                    Ok(Some(data)) => {
+                        metrics.record_bytes_out(data.len());
+                        if tx.send(Ok(data)).await.is_err() {
+                            debug!(request_id = %request_id_clone, "Response receiver dropped");
+                            break 'body None;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => break 'body Some(e),
+                }
+            }
+            if framing.is_complete() {
+                debug!(request_id = %request_id_clone, epoch = epoch, total_bytes = total_read, "Response stream complete");
+                break None;
+            }
+
+            match read_into(&mut stream, &mut received).await {
                 Ok(0) => {
-                    debug!(request_id = %request_id_clone, total_bytes = total_read, "Response stream complete");
-                    break;
+                    // A response cut short of its framing must not look complete
+                    match framing {
+                        BodyFraming::Length { .. } => {
+                            break Some(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                format!(
+                                    "response body ended after {} of {} bytes",
+                                    total_read,
+                                    content_length.unwrap_or(0)
+                                ),
+                            ));
+                        }
+                        BodyFraming::Chunked(_) => {
+                            break Some(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "response body ended before the last chunk",
+                            ));
+                        }
+                        // The client closing its side ends the body. The whole stream
+                        // closed means the connection dropped, and the body may be cut short.
+                        BodyFraming::UntilClose if stream.is_closed() => {
+                            break Some(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "tunnel closed before the response body ended",
+                            ));
+                        }
+                        BodyFraming::UntilClose => {
+                            debug!(request_id = %request_id_clone, epoch = epoch, total_bytes = total_read, "Response stream complete");
+                            break None;
+                        }
+                    }
                 }
                 Ok(n) => {
                     total_read += n;
-                    if tx.send(Ok(Bytes::copy_from_slice(&buf[..n]))).await.is_err() {
-                        debug!(request_id = %request_id_clone, "Response receiver dropped");
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!(request_id = %request_id_clone, "Error reading response body: {}", e);
-                    let _ = tx.send(Err(e)).await;
-                    break;
+                    tunnel.record_bytes_out(n);
                 }
+                Err(e) => break Some(e),
             }
+        };
+
+        if let Some(e) = failure {
+            let failure = if replaced { ProxyFailure::TunnelReplaced } else { ProxyFailure::BodyStreamError };
+            error!(
+                request_id = %request_id_clone,
+                epoch = epoch,
+                error_code = failure.code(),
+                "Error reading response body: {}",
+                e
+            );
+            metrics.record_proxy_error(failure);
+            let _ = tx.send(Err(e)).await;
         }
     });
 
@@ -237,21 +663,106 @@ pub async fn proxy_request(
     let body_stream = ReceiverStream::new(rx);
     let body = Body::from_stream(body_stream);
 
-    Ok(builder
-        .body(body)
-        .unwrap_or_else(|_| bad_gateway("Failed to build response")))
+    builder.body(body).map_err(|e| {
+        warn!(request_id = %request_id, "Invalid response headers from tunnel: {}", e);
+        ProxyFailure::ResponseParseError
+    })
+}
+
+/// Whether the visitor is opening a WebSocket (`Connection: Upgrade` + `Upgrade: websocket`)
+pub fn is_websocket_upgrade(headers: &hyper::HeaderMap) -> bool {
+    let upgrade = headers
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let connection = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    upgrade && connection
+}
+
+/// Copy bytes between the visitor's upgraded connection and the tunnel stream until
+/// either side closes
+async fn relay_upgraded(
+    on_upgrade: hyper::upgrade::OnUpgrade,
+    stream: yamux::Stream,
+    initial_body: Bytes,
+    request_id: String,
+    tunnel: Arc<Tunnel>,
+) {
+    let upgraded = match on_upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            debug!(request_id = %request_id, "Visitor connection upgrade failed: {}", e);
+            return;
+        }
+    };
+    let mut visitor = TokioIo::new(upgraded);
+    let mut client = stream.compat();
+
+    // Frames the client sent straight after its 101
+    if !initial_body.is_empty() && visitor.write_all(&initial_body).await.is_err() {
+        return;
+    }
+
+    // Its bytes are only counted once it closes, so it keeps the tunnel from counting
+    // as idle while it's open, like a TCP tunnel's connections
+    let _open = tunnel.open_connection();
+    match tokio::io::copy_bidirectional(&mut visitor, &mut client).await {
+        Ok((to_client, to_visitor)) => {
+            tunnel.record_bytes_in(to_client as usize);
+            tunnel.record_bytes_out(to_visitor as usize);
+            debug!(
+                request_id = %request_id,
+                to_client = to_client,
+                to_visitor = to_visitor,
+                "WebSocket closed"
+            );
+        }
+        Err(e) => debug!(request_id = %request_id, "WebSocket relay ended: {}", e),
+    }
 }
 
-fn find_header_end(data: &[u8]) -> Option<usize> {
-    for i in 0..data.len().saturating_sub(3) {
-        if &data[i..i + 4] == b"\r\n\r\n" {
-            return Some(i);
+/// The first final response head in `buf` and its length, once it's all there.
+/// Interim responses (100 Continue, 103 Early Hints) before it are dropped, as the
+/// visitor is waiting for the real one.
+fn final_head(scanner: &mut HeadScanner, buf: &mut BytesMut) -> Result<Option<(usize, ResponseHead)>, HeadError> {
+    while let Some(len) = scanner.scan(buf)? {
+        let head = parse_response(&buf[..len])?;
+        if !head.is_interim() {
+            return Ok(Some((len, head)));
         }
+        debug!("Skipping an interim response");
+        buf.advance(len);
     }
-    None
+    Ok(None)
 }
 
-fn is_hop_by_hop_header(name: &str) -> bool {
+/// Append a `name: value` header line to a request head
+fn put_header(head: &mut BytesMut, name: &str, value: &[u8]) {
+    head.put_slice(name.as_bytes());
+    head.put_slice(b": ");
+    head.put_slice(value);
+    head.put_slice(b"\r\n");
+}
+
+fn uri_too_long() -> Response {
+    (StatusCode::URI_TOO_LONG, "Request URL too long").into_response()
+}
+
+fn payload_too_large() -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response()
+}
+
+fn method_not_allowed(allowed: &[String]) -> Response {
+    let allow = allowed.iter().map(|method| method.to_ascii_uppercase()).collect::<Vec<_>>().join(", ");
+    (StatusCode::METHOD_NOT_ALLOWED, [(hyper::header::ALLOW, allow)], "Method not allowed").into_response()
+}
+
+pub fn is_hop_by_hop_header(name: &str) -> bool {
     matches!(
         name.to_lowercase().as_str(),
         "connection"
@@ -265,10 +776,869 @@ fn is_hop_by_hop_header(name: &str) -> bool {
     )
 }
 
-fn bad_gateway(msg: &str) -> Response {
-    (StatusCode::BAD_GATEWAY, msg.to_string()).into_response()
+/// Headers the proxy sets itself from what the server observed
+fn is_forwarded_header(name: &str) -> bool {
+    matches!(
+        name.to_lowercase().as_str(),
+        "x-forwarded-for" | "x-forwarded-proto" | "x-forwarded-port" | "x-forwarded-prefix"
+    )
 }
 
-fn gateway_timeout(msg: &str) -> Response {
-    (StatusCode::GATEWAY_TIMEOUT, msg.to_string()).into_response()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expose::forwarder::RequestLog;
+    use crate::http_head::request_head_len;
+    use crate::server::names::{Subdomain, TokenSecret};
+    use crate::server::tunnel::ProxyRequest;
+    use futures::io::AsyncReadExt;
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+    use yamux::{Config, Connection, Mode};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// How the fake client side of the tunnel behaves
+    #[derive(Clone, Copy)]
+    enum Client {
+        /// The session dies as soon as a stream is opened
+        Disconnect,
+        /// Answer every request with these bytes, or read it all and never answer
+        Reply(Option<&'static [u8]>),
+        /// Read the request body (per Content-Length) and answer with its size
+        CountBody,
+        /// Send a chunked response's first chunk, then the rest once notified, and
+        /// keep the stream open afterwards like a keep-alive connection
+        SlowChunks(&'static tokio::sync::Notify),
+        /// Hand each stream to the real `expose` forwarder for a local server
+        Forward(std::net::SocketAddr),
+        /// Answer with these bytes after a delay, like a slow backend
+        Delayed(Duration, &'static [u8]),
+        /// Answer with these bytes once notified
+        Gated(&'static tokio::sync::Notify, &'static [u8]),
+        /// Answer with the request's head as the body
+        EchoHead,
+    }
+
+    /// A tunnel backed by an in-memory yamux session, driven like handler.rs drives the real one
+    fn test_tunnel(client: Client) -> Arc<Tunnel> {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (request_tx, mut request_rx) = mpsc::channel::<ProxyRequest>(1);
+
+        tokio::spawn(async move {
+            let mut connection = Connection::new(server_io.compat(), Config::default(), Mode::Server);
+            loop {
+                tokio::select! {
+                    Some(request) = request_rx.recv() => {
+                        let stream = std::future::poll_fn(|cx| connection.poll_new_outbound(cx))
+                            .await
+                            .map_err(|_| ProxyError::StreamOpenFailed);
+                        if matches!(client, Client::Disconnect) {
+                            drop(connection);
+                            let _ = request.stream_tx.send(stream);
+                            return;
+                        }
+                        let _ = request.stream_tx.send(stream);
+                    }
+                    inbound = std::future::poll_fn(|cx| connection.poll_next_inbound(cx)) => {
+                        if !matches!(inbound, Some(Ok(_))) {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut connection = Connection::new(client_io.compat(), Config::default(), Mode::Client);
+            while let Some(Ok(mut stream)) = std::future::poll_fn(|cx| connection.poll_next_inbound(cx)).await {
+                tokio::spawn(async move {
+                    if let Client::Forward(local_addr) = client {
+                        crate::expose::forwarder::handle_tunnel_stream(stream, local_addr, None, None, Default::default(), TIMEOUT, RequestLog::new(true, &[])).await;
+                        return;
+                    }
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while request_head_len(&request).is_none() {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    match client {
+                        Client::Reply(Some(reply)) => {
+                            let _ = stream.write_all(reply).await;
+                            let _ = stream.close().await;
+                        }
+                        Client::Delayed(delay, reply) => {
+                            tokio::time::sleep(delay).await;
+                            let _ = stream.write_all(reply).await;
+                            let _ = stream.close().await;
+                        }
+                        Client::Gated(gate, reply) => {
+                            gate.notified().await;
+                            let _ = stream.write_all(reply).await;
+                            let _ = stream.close().await;
+                        }
+                        Client::EchoHead => {
+                            let head = &request[..request_head_len(&request).unwrap()];
+                            let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", head.len());
+                            let _ = stream.write_all(reply.as_bytes()).await;
+                            let _ = stream.write_all(head).await;
+                            let _ = stream.close().await;
+                        }
+                        Client::SlowChunks(notify) => {
+                            let _ = stream
+                                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n")
+                                .await;
+                            let _ = stream.flush().await;
+                            notify.notified().await;
+                            let _ = stream.write_all(b"4;ext=1\r\nlast\r\n0\r\n\r\n").await;
+                            let _ = stream.flush().await;
+                            std::future::pending::<()>().await;
+                        }
+                        Client::CountBody => {
+                            let head_end = request_head_len(&request).unwrap();
+                            let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                            let expected: usize = head
+                                .lines()
+                                .find_map(|l| l.strip_prefix("content-length:"))
+                                .map(|v| v.trim().parse().unwrap())
+                                .unwrap_or(0);
+                            let mut received = request.len() - head_end;
+                            let mut buf = vec![0u8; 64 * 1024];
+                            while received < expected {
+                                match stream.read(&mut buf).await {
+                                    Ok(0) | Err(_) => return,
+                                    Ok(n) => received += n,
+                                }
+                            }
+                            let body = received.to_string();
+                            let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                            let _ = stream.write_all(reply.as_bytes()).await;
+                            let _ = stream.close().await;
+                        }
+                        _ => {
+                            // Keep reading so the request body never blocks on flow control
+                            while let Ok(n) = stream.read(&mut buf).await {
+                                if n == 0 {
+                                    std::future::pending::<()>().await;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx))
+    }
+
+    fn options() -> ProxyOptions {
+        ProxyOptions {
+            is_https: false,
+            public_port: 80,
+            header_timeout: Duration::from_millis(200),
+            max_body_bytes: 10 * 1024 * 1024,
+            max_request_line: 64 * 1024,
+            strict_epoch: false,
+            strip_request_headers: Vec::new(),
+            allowed_methods: None,
+            response_headers: Arc::default(),
+            path_rewrite: None,
+            buffers: Arc::default(),
+            request_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn send(
+        tunnel: Arc<Tunnel>,
+        req: hyper::Request<Body>,
+        metrics: &Arc<Metrics>,
+    ) -> Result<Response, ProxyFailure> {
+        tokio::time::timeout(
+            TIMEOUT,
+            proxy_request(tunnel, req, [127, 0, 0, 1].into(), options(), Arc::new(Registry::default()), metrics.clone()),
+        )
+        .await
+        .expect("proxy_request hung")
+    }
+
+    /// A tunnel whose first stream can't be opened, with `client` behind later ones.
+    /// Also returns how many streams have been asked for.
+    fn flaky_tunnel(client: Client) -> (Arc<Tunnel>, Arc<std::sync::atomic::AtomicUsize>) {
+        let inner = test_tunnel(client);
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (request_tx, mut request_rx) = mpsc::channel::<ProxyRequest>(1);
+        let counter = attempts.clone();
+        tokio::spawn(async move {
+            while let Some(request) = request_rx.recv().await {
+                let stream = match counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed) {
+                    0 => Err(ProxyError::StreamOpenFailed),
+                    _ => inner.get_stream().await,
+                };
+                let _ = request.stream_tx.send(stream);
+            }
+        });
+        let tunnel = Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx);
+        (Arc::new(tunnel), attempts)
+    }
+
+    async fn proxy(tunnel: Arc<Tunnel>, metrics: &Arc<Metrics>) -> Result<Response, ProxyFailure> {
+        send(tunnel, hyper::Request::get("/").body(Body::empty()).unwrap(), metrics).await
+    }
+
+    /// A body delivered in 64KiB chunks, like a real upload
+    fn chunked_body(len: usize) -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = (0..len)
+            .step_by(64 * 1024)
+            .map(|start| Ok(Bytes::from(vec![b'x'; (len - start).min(64 * 1024)])))
+            .collect();
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_streams_large_upload() {
+        let metrics = Arc::new(Metrics::new());
+        let len = 8 * 1024 * 1024;
+        let req = hyper::Request::post("/upload")
+            .header("content-length", len)
+            .body(chunked_body(len))
+            .unwrap();
+
+        let tunnel = test_tunnel(Client::CountBody);
+        let response = send(tunnel.clone(), req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, len.to_string());
+        assert!(tunnel.bytes_in.load(std::sync::atomic::Ordering::Relaxed) > len as u64);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_oversized_body() {
+        let metrics = Arc::new(Metrics::new());
+        let len = options().max_body_bytes + 1;
+
+        // Declared up front: refused without opening a stream
+        let (request_tx, request_rx) = mpsc::channel(1);
+        drop(request_rx);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+        let req = hyper::Request::post("/upload")
+            .header("content-length", len)
+            .body(Body::empty())
+            .unwrap();
+        let response = send(tunnel, req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Undeclared: refused as soon as the running total passes the limit
+        let req = hyper::Request::post("/upload").body(chunked_body(len)).unwrap();
+        let response = send(test_tunnel(Client::Reply(None)), req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_long_urls() {
+        let metrics = Arc::new(Metrics::new());
+        // Percent-encoding, case and doubled slashes all left as the visitor sent them
+        let target = |len: usize| {
+            let mut target = "/cb//track/%2F%7e?x=%20+%2B&q=".to_string();
+            while target.len() < len {
+                target.push_str("%41b");
+            }
+            target
+        };
+        for len in [8 * 1024, 32 * 1024] {
+            let target = target(len);
+            let req = hyper::Request::get(target.as_str()).body(Body::empty()).unwrap();
+            let response = send(test_tunnel(Client::EchoHead), req, &metrics).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let head = response.into_body().collect().await.unwrap().to_bytes();
+            assert!(head.starts_with(format!("GET {} HTTP/1.1\r\n", target).as_bytes()), "{} bytes", len);
+        }
+
+        // Over the limit: refused without opening a stream
+        let (request_tx, request_rx) = mpsc::channel(1);
+        drop(request_rx);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+        let options = ProxyOptions {
+            max_request_line: 16 * 1024,
+            ..options()
+        };
+        let req = hyper::Request::get(target(32 * 1024).as_str()).body(Body::empty()).unwrap();
+        let response = proxy_request(tunnel, req, [127, 0, 0, 1].into(), options, Arc::new(Registry::default()), metrics.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    /// Assert `failure` is reported with the right status, header and counter
+    #[tokio::test]
+    async fn test_strips_request_headers() {
+        let metrics = Arc::new(Metrics::new());
+        let options = ProxyOptions {
+            strip_request_headers: vec!["Authorization".to_string(), "cookie".to_string(), "x-forwarded-for".to_string()],
+            ..options()
+        };
+        let req = hyper::Request::get("/")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=secret")
+            .header("accept", "text/html")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = proxy_request(test_tunnel(Client::EchoHead), req, [127, 0, 0, 1].into(), options, Arc::new(Registry::default()), metrics.clone())
+            .await
+            .unwrap();
+        let head = String::from_utf8(response.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap().to_lowercase();
+        assert!(!head.contains("secret"), "{}", head);
+        assert!(head.contains("accept: text/html"), "{}", head);
+        // Headers the server adds itself are stripped just the same
+        assert!(!head.contains("x-forwarded-for"), "{}", head);
+        assert!(head.contains("x-forwarded-proto: http"), "{}", head);
+        assert!(metrics.render(&Registry::default()).contains("loophole_policy_violations_total{kind=\"header\"} 3"));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_disallowed_methods() {
+        let metrics = Arc::new(Metrics::new());
+        let options = ProxyOptions {
+            allowed_methods: Some(vec!["get".to_string(), "HEAD".to_string()]),
+            ..options()
+        };
+
+        // Refused without opening a stream
+        let (request_tx, request_rx) = mpsc::channel(1);
+        drop(request_rx);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+        let req = hyper::Request::post("/").body(Body::from("payload")).unwrap();
+        let response = proxy_request(tunnel.clone(), req, [127, 0, 0, 1].into(), options.clone(), Arc::new(Registry::default()), metrics.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[hyper::header::ALLOW], "GET, HEAD");
+        assert_eq!(tunnel.request_count.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert!(metrics.render(&Registry::default()).contains("loophole_policy_violations_total{kind=\"method\"} 1"));
+
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let response = proxy_request(test_tunnel(Client::EchoHead), req, [127, 0, 0, 1].into(), options, Arc::new(Registry::default()), metrics.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_applies_response_header_rules() {
+        use crate::server::config::ResponseHeaderRule;
+
+        let metrics = Arc::new(Metrics::new());
+        let rule = |subdomain: &str, set: &[(&str, &str)], add: &[(&str, &str)]| ResponseHeaderRule {
+            subdomain: subdomain.to_string(),
+            set: set.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            add: add.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        };
+        let rules = HeaderRules::new(&[
+            rule("my*", &[("X-Env", "staging")], &[("Cache-Control", "no-transform")]),
+            rule("other", &[("X-Env", "other")], &[]),
+        ])
+        .unwrap();
+        let options = options().with_response_headers(Arc::new(rules));
+        let reply = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Env: production\r\nCache-Control: max-age=60\r\n\r\nok";
+
+        let response = proxy_request(test_tunnel(Client::Reply(Some(reply))), hyper::Request::get("/").body(Body::empty()).unwrap(), [127, 0, 0, 1].into(), options, Arc::new(Registry::default()), metrics)
+            .await
+            .unwrap();
+        let values = |name: &str| response.headers().get_all(name).iter().map(|v| v.to_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(values("x-env"), ["staging"]);
+        assert_eq!(values("cache-control"), ["max-age=60", "no-transform"]);
+        assert_eq!(values("content-length"), ["2"]);
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "ok");
+    }
+
+    fn assert_failure(result: Result<Response, ProxyFailure>, expected: ProxyFailure, metrics: &Metrics) {
+        let failure = result.expect_err("request should fail");
+        assert_eq!(failure, expected);
+
+        let response = failure.into_response();
+        assert_eq!(response.status(), expected.status());
+        assert_eq!(response.headers()[ERROR_HEADER], expected.code());
+
+        for other in ProxyFailure::ALL {
+            let count = if other == expected { 1 } else { 0 };
+            assert_eq!(metrics.proxy_errors(other), count, "{}", other);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxies_response() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Reply(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")));
+
+        let response = proxy(tunnel.clone(), &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(ERROR_HEADER).is_none());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello");
+
+        // Everything read back from the client, head included
+        assert_eq!(tunnel.bytes_out.load(std::sync::atomic::Ordering::Relaxed), 43);
+        assert!(tunnel.bytes_in.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_stream_open_failed() {
+        let metrics = Arc::new(Metrics::new());
+        let (request_tx, request_rx) = mpsc::channel(1);
+        drop(request_rx);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+
+        let result = proxy(tunnel, &metrics).await;
+        assert_failure(result, ProxyFailure::StreamOpenFailed, &metrics);
+    }
+
+    #[tokio::test]
+    async fn test_client_write_failed() {
+        // A POST, as a GET would be retried and find the tunnel gone
+        let metrics = Arc::new(Metrics::new());
+        let req = hyper::Request::post("/").body(Body::empty()).unwrap();
+        let result = send(test_tunnel(Client::Disconnect), req, &metrics).await;
+        assert_failure(result, ProxyFailure::ClientWriteFailed, &metrics);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_requests_retried() {
+        let metrics = Arc::new(Metrics::new());
+        let (tunnel, attempts) = flaky_tunnel(Client::CountBody);
+        let req = hyper::Request::get("/search").header("content-length", 5).body(Body::from("hello")).unwrap();
+        let response = send(tunnel.clone(), req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ERROR_HEADER], RETRIED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"5");
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::Relaxed), 2);
+        // Counted as much as the same request sent once
+        let once = test_tunnel(Client::CountBody);
+        let req = hyper::Request::get("/search").header("content-length", 5).body(Body::from("hello")).unwrap();
+        send(once.clone(), req, &Arc::new(Metrics::new())).await.unwrap();
+        let bytes_in = |tunnel: &Tunnel| tunnel.bytes_in.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(bytes_in(&tunnel), bytes_in(&once));
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+        let text = metrics.render(&Registry::default());
+        assert!(text.contains("loophole_proxy_retries_total{result=\"success\"} 1"), "{}", text);
+
+        // Only once: a second failure goes to the visitor
+        let metrics = Arc::new(Metrics::new());
+        let (request_tx, request_rx) = mpsc::channel(1);
+        drop(request_rx);
+        let tunnel = Arc::new(Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx));
+        let req = hyper::Request::head("/").body(Body::empty()).unwrap();
+        assert_failure(send(tunnel, req, &metrics).await, ProxyFailure::StreamOpenFailed, &metrics);
+        let text = metrics.render(&Registry::default());
+        assert!(text.contains("loophole_proxy_retries_total{result=\"failure\"} 1"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_requests_not_retried() {
+        for method in [hyper::Method::POST, hyper::Method::PUT, hyper::Method::DELETE, hyper::Method::PATCH] {
+            let metrics = Arc::new(Metrics::new());
+            let (tunnel, attempts) = flaky_tunnel(Client::CountBody);
+            let req = hyper::Request::builder().method(method.clone()).uri("/").body(Body::empty()).unwrap();
+            assert_failure(send(tunnel, req, &metrics).await, ProxyFailure::StreamOpenFailed, &metrics);
+            assert_eq!(attempts.load(std::sync::atomic::Ordering::Relaxed), 1, "{}", method);
+        }
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&hyper::Method::GET));
+        assert!(is_idempotent(&hyper::Method::HEAD));
+        assert!(is_idempotent(&hyper::Method::OPTIONS));
+        assert!(!is_idempotent(&hyper::Method::POST));
+        // Idempotent by the spec, but services don't all treat them so
+        assert!(!is_idempotent(&hyper::Method::PUT));
+        assert!(!is_idempotent(&hyper::Method::DELETE));
+    }
+
+    #[tokio::test]
+    async fn test_response_header_timeout() {
+        let metrics = Arc::new(Metrics::new());
+        let result = proxy(test_tunnel(Client::Reply(None)), &metrics).await;
+        assert_failure(result, ProxyFailure::ResponseHeaderTimeout, &metrics);
+    }
+
+    #[tokio::test]
+    async fn test_header_timeout_follows_config() {
+        let slow = Client::Delayed(Duration::from_millis(1500), b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        for (request_timeout, expected) in [(3, StatusCode::OK), (1, StatusCode::GATEWAY_TIMEOUT)] {
+            let config = crate::server::config::Config::parse(&format!(
+                "[server]\ndomain = \"tunnel.example.com\"\n[tokens.tk_test]\n[limits]\nrequest_timeout = {}\n",
+                request_timeout
+            ))
+            .unwrap();
+            let options = ProxyOptions::new(&config, &PublicUrlBuilder::from_config(&config));
+            assert_eq!(options.header_timeout, Duration::from_secs(request_timeout));
+
+            let metrics = Arc::new(Metrics::new());
+            let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+            let response = proxy_request(test_tunnel(slow), req, [127, 0, 0, 1].into(), options, Arc::new(Registry::default()), metrics)
+                .await
+                .unwrap_or_else(IntoResponse::into_response);
+            assert_eq!(response.status(), expected, "request_timeout = {}", request_timeout);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_parse_error() {
+        let metrics = Arc::new(Metrics::new());
+        let result = proxy(test_tunnel(Client::Reply(Some(b"\xff\xfe\r\n\r\n"))), &metrics).await;
+        assert_failure(result, ProxyFailure::ResponseParseError, &metrics);
+
+        // Closing the stream without a response is also unparseable
+        let metrics = Arc::new(Metrics::new());
+        let result = proxy(test_tunnel(Client::Reply(Some(b""))), &metrics).await;
+        assert_failure(result, ProxyFailure::ResponseParseError, &metrics);
+    }
+
+    #[tokio::test]
+    async fn test_body_stream_error() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Reply(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort")));
+
+        // Headers were already sent, so the failure shows up as a broken body
+        let response = proxy(tunnel, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.into_body().collect().await.is_err());
+        assert_eq!(metrics.proxy_errors(ProxyFailure::BodyStreamError), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unframed_response_read_until_close() {
+        // As a client from before re-chunking passes on an HTTP/1.0 response
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Reply(Some(b"HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nhello")));
+        let response = proxy(tunnel, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), hyper::Version::HTTP_11);
+        assert!(response.headers().get(hyper::header::CONNECTION).is_none());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello");
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+
+        // Responses that can't have a body don't wait for the stream to close
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Reply(Some(b"HTTP/1.1 204 No Content\r\n\r\n")));
+        assert_eq!(proxy(tunnel, &metrics).await.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    /// A local server that answers HTTP/1.0-style: no length, body ends when it closes
+    async fn close_delimited_server(body: &'static [u8]) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while request_head_len(&request).is_none() {
+                        match tcp.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = tcp.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n").await;
+                    for part in body.chunks(3) {
+                        let _ = tcp.write_all(part).await;
+                        let _ = tcp.flush().await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_close_delimited_response_reframed_by_client() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Forward(close_delimited_server(b"hello from HTTP/1.0").await));
+
+        let response = proxy(tunnel, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(hyper::header::TRANSFER_ENCODING).is_none());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello from HTTP/1.0");
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    /// A local server that keeps connections alive and answers conditionally: HEAD with
+    /// the GET's Content-Length and no body, and 304 when the ETag matches
+    async fn keep_alive_server() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        while request_head_len(&request).is_none() {
+                            match tcp.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let end = request_head_len(&request).unwrap();
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        request.drain(..end);
+                        let reply = if head.starts_with("head ") {
+                            "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nETag: \"v1\"\r\n\r\n".to_string()
+                        } else if head.contains("if-none-match: \"v1\"") {
+                            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_string()
+                        } else {
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nETag: \"v1\"\r\n\r\n{}", "x".repeat(1234))
+                        };
+                        if tcp.write_all(reply.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_head_response_ends_at_headers() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Forward(keep_alive_server().await));
+
+        // The backend says how long the body would be, sends none and keeps the connection open
+        let req = hyper::Request::head("/").body(Body::empty()).unwrap();
+        let response = send(tunnel, req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "1234");
+        let body = tokio::time::timeout(TIMEOUT, response.into_body().collect())
+            .await
+            .expect("HEAD response body hung")
+            .unwrap()
+            .to_bytes();
+        assert!(body.is_empty());
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get_round_trip() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Forward(keep_alive_server().await));
+
+        let response = send(tunnel.clone(), hyper::Request::get("/").body(Body::empty()).unwrap(), &metrics)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[hyper::header::ETAG].clone();
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes().len(), 1234);
+
+        let req = hyper::Request::get("/").header(hyper::header::IF_NONE_MATCH, etag).body(Body::empty()).unwrap();
+        let response = send(tunnel, req, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[hyper::header::ETAG], "\"v1\"");
+        let body = tokio::time::timeout(TIMEOUT, response.into_body().collect())
+            .await
+            .expect("304 response body hung")
+            .unwrap()
+            .to_bytes();
+        assert!(body.is_empty());
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_interim_responses_skipped() {
+        let metrics = Arc::new(Metrics::new());
+        let reply = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </app.css>\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n";
+        let response = proxy(test_tunnel(Client::Reply(Some(reply))), &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(hyper::header::LINK).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_streams_chunked_response() {
+        let metrics = Arc::new(Metrics::new());
+        let notify: &'static tokio::sync::Notify = Box::leak(Box::new(tokio::sync::Notify::new()));
+        let tunnel = test_tunnel(Client::SlowChunks(notify));
+
+        let response = proxy(tunnel, &metrics).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("transfer-encoding").is_none());
+
+        // The first chunk arrives before the backend has produced the last one
+        let mut body = response.into_body();
+        let first = tokio::time::timeout(TIMEOUT, body.frame())
+            .await
+            .expect("first chunk not streamed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.into_data().unwrap(), "first");
+
+        // The body ends at the last chunk even though the stream stays open
+        notify.notify_one();
+        let rest = tokio::time::timeout(TIMEOUT, body.collect())
+            .await
+            .expect("body didn't end at the last chunk")
+            .unwrap()
+            .to_bytes();
+        assert_eq!(&rest[..], b"last");
+        assert_eq!(metrics.proxy_errors(ProxyFailure::BodyStreamError), 0);
+    }
+
+    /// A local WebSocket server echoing every message back
+    async fn echo_server() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                    while let Some(Ok(message)) = ws.next().await {
+                        if message.is_close() || ws.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_websocket_passthrough() {
+        let metrics = Arc::new(Metrics::new());
+        let tunnel = test_tunnel(Client::Forward(echo_server().await));
+
+        // Serve the proxy over real HTTP so the visitor's connection can be upgraded
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let proxy_metrics = metrics.clone();
+        let app = axum::Router::new().fallback(move |req: hyper::Request<Body>| async move {
+            proxy_request(tunnel, req, [127, 0, 0, 1].into(), options(), Arc::new(Registry::default()), proxy_metrics)
+                .await
+                .unwrap_or_else(IntoResponse::into_response)
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut ws, response) = tokio::time::timeout(
+            TIMEOUT,
+            tokio_tungstenite::connect_async(format!("ws://{}/ws", server_addr)),
+        )
+        .await
+        .expect("handshake hung")
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        for text in ["hello", "again"] {
+            ws.send(Message::Text(text.to_string())).await.unwrap();
+            let echoed = tokio::time::timeout(TIMEOUT, ws.next()).await.expect("no echo").unwrap().unwrap();
+            assert_eq!(echoed, Message::Text(text.to_string()));
+        }
+        ws.send(Message::Binary(vec![0, 1, 2])).await.unwrap();
+        let echoed = tokio::time::timeout(TIMEOUT, ws.next()).await.expect("no echo").unwrap().unwrap();
+        assert_eq!(echoed, Message::Binary(vec![0, 1, 2]));
+
+        ws.close(None).await.unwrap();
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    /// Have another client take over the subdomain of `tunnel`, which disconnects
+    fn replace(registry: &Registry, tunnel: &Arc<Tunnel>) {
+        registry.deregister_tunnel(tunnel);
+        let (request_tx, _) = mpsc::channel(1);
+        let successor = Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_other"), "127.0.0.1:50001".parse().unwrap(), request_tx);
+        registry.register(&Subdomain::new("myapp").unwrap(), Arc::new(successor), 0).unwrap();
+    }
+
+    /// A request whose tunnel is replaced while the client is still working on it
+    async fn replaced_before_response(strict_epoch: bool) -> (Result<Response, ProxyFailure>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let registry = Arc::new(Registry::default());
+        let gate: &'static tokio::sync::Notify = Box::leak(Box::new(tokio::sync::Notify::new()));
+        let tunnel = test_tunnel(Client::Gated(gate, b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nold"));
+        registry.register(&Subdomain::new("myapp").unwrap(), tunnel.clone(), 0).unwrap();
+
+        let options = ProxyOptions { strict_epoch, ..options() };
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let request = tokio::spawn(proxy_request(
+            tunnel.clone(),
+            req,
+            [127, 0, 0, 1].into(),
+            options,
+            registry.clone(),
+            metrics.clone(),
+        ));
+
+        // The old client only answers once the subdomain belongs to someone else
+        replace(&registry, &tunnel);
+        gate.notify_one();
+        let result = tokio::time::timeout(TIMEOUT, request).await.expect("proxy_request hung").unwrap();
+        (result, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_replaced_tunnel_response_passed_on() {
+        let (result, metrics) = replaced_before_response(false).await;
+        let response = result.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"old");
+        assert_eq!(metrics.stale_responses(), 1);
+        assert!(ProxyFailure::ALL.iter().all(|f| metrics.proxy_errors(*f) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_replaced_tunnel_response_refused_when_strict() {
+        let (result, metrics) = replaced_before_response(true).await;
+        assert_failure(result, ProxyFailure::TunnelReplaced, &metrics);
+        assert_eq!(metrics.stale_responses(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_replaced_mid_body_cut_off_when_strict() {
+        let metrics = Arc::new(Metrics::new());
+        let registry = Arc::new(Registry::default());
+        let notify: &'static tokio::sync::Notify = Box::leak(Box::new(tokio::sync::Notify::new()));
+        let tunnel = test_tunnel(Client::SlowChunks(notify));
+        registry.register(&Subdomain::new("myapp").unwrap(), tunnel.clone(), 0).unwrap();
+
+        let options = ProxyOptions { strict_epoch: true, ..options() };
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let response = proxy_request(tunnel.clone(), req, [127, 0, 0, 1].into(), options, registry.clone(), metrics.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The rest of the body comes from a client that no longer has the subdomain
+        replace(&registry, &tunnel);
+        notify.notify_one();
+        let body = tokio::time::timeout(TIMEOUT, response.into_body().collect()).await.expect("body hung");
+        assert!(body.is_err());
+        assert_eq!(metrics.proxy_errors(ProxyFailure::TunnelReplaced), 1);
+        assert_eq!(metrics.proxy_errors(ProxyFailure::BodyStreamError), 0);
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = hyper::HeaderMap::new();
+            for (name, value) in pairs {
+                map.append(*name, value.parse().unwrap());
+            }
+            map
+        };
+        assert!(is_websocket_upgrade(&headers(&[("connection", "Upgrade"), ("upgrade", "websocket")])));
+        assert!(is_websocket_upgrade(&headers(&[("connection", "keep-alive, Upgrade"), ("upgrade", "WebSocket")])));
+        assert!(!is_websocket_upgrade(&headers(&[("upgrade", "websocket")])));
+        assert!(!is_websocket_upgrade(&headers(&[("connection", "Upgrade"), ("upgrade", "h2c")])));
+    }
 }
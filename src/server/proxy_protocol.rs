@@ -0,0 +1,279 @@
+//! The PROXY protocol (`server.proxy_protocol`), for L4 load balancers such as HAProxy
+//! or an AWS Network Load Balancer, which can't add X-Forwarded-For and instead send
+//! the visitor's address in a header ahead of the connection's own bytes. Both the
+//! text (v1) and binary (v2) forms are read. A connection that doesn't start with a
+//! valid header is closed, since only the balancer should be able to reach the server.
+
+use axum::extract::ConnectInfo;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tower::Layer;
+use tracing::debug;
+
+/// How long a new connection has to send its header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header the spec allows, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY protocol header: {}", message))
+}
+
+/// Read the PROXY header at the start of `stream`, leaving the connection's own bytes
+/// unread. Returns the original client's address, or None when the header says there
+/// isn't one: v1 `UNKNOWN`, or v2 `LOCAL` (the balancer's own health checks) or a
+/// family other than IPv4 and IPv6.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both forms are at least this long: the shortest v1 header is `PROXY UNKNOWN\r\n`
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+/// The rest of a `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`
+/// line, a byte at a time so nothing past it is read
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("v1 line isn't text"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let ipv4 = match fields.get(1) {
+        // Whatever follows is to be ignored
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") => true,
+        Some(&"TCP6") => false,
+        _ => return Err(invalid("unknown v1 protocol")),
+    };
+    let [_, _, source, destination, source_port, destination_port] = fields[..] else {
+        return Err(invalid("wrong number of v1 fields"));
+    };
+    let address = |field: &str| match field.parse::<IpAddr>() {
+        Ok(ip) if ip.is_ipv4() == ipv4 => Ok(ip),
+        _ => Err(invalid("bad v1 address")),
+    };
+    let port = |field: &str| field.parse::<u16>().map_err(|_| invalid("bad v1 port"));
+    address(destination)?;
+    port(destination_port)?;
+    Ok(Some(SocketAddr::new(address(source)?, port(source_port)?)))
+}
+
+/// The rest of a v2 header after its signature: version and command, family, the
+/// length of what follows, then the addresses and any TLVs (which are skipped)
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+    let mut addresses = vec![0u8; u16::from_be_bytes([len_high, len_low]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection, which carries no client address
+        0 => Ok(None),
+        1 => parse_v2_addresses(family, &addresses),
+        _ => Err(invalid("unknown v2 command")),
+    }
+}
+
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    // Each family's source address is first, then the destination's, then the ports
+    let (ip, port): (IpAddr, &[u8]) = match family >> 4 {
+        // AF_UNSPEC and AF_UNIX: no IP address to use
+        0 | 3 => return Ok(None),
+        1 => {
+            let addresses = addresses.get(..12).ok_or_else(|| invalid("short v2 IPv4 addresses"))?;
+            let source: [u8; 4] = addresses[..4].try_into().expect("4 bytes");
+            (Ipv4Addr::from(source).into(), &addresses[8..10])
+        }
+        2 => {
+            let addresses = addresses.get(..36).ok_or_else(|| invalid("short v2 IPv6 addresses"))?;
+            let source: [u8; 16] = addresses[..16].try_into().expect("16 bytes");
+            (Ipv6Addr::from(source).into(), &addresses[32..34])
+        }
+        _ => return Err(invalid("unknown v2 address family")),
+    };
+    Ok(Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))))
+}
+
+/// Reads each connection's PROXY header before handing it on to `inner` (TLS, on the
+/// HTTPS listener), and gives the service the client address from the header as its
+/// `ConnectInfo<SocketAddr>`. Serve with `into_make_service`, not
+/// `into_make_service_with_connect_info`, which would replace it with the balancer's.
+#[derive(Debug, Clone)]
+pub struct ProxyProtocolAcceptor<A> {
+    inner: A,
+}
+
+impl<A> ProxyProtocolAcceptor<A> {
+    pub fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for ProxyProtocolAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, ConnectInfo<SocketAddr>>> + Clone + Send + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            let header = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                Ok(header) => header,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header in time")),
+            };
+            // Dropping the stream closes the connection
+            let client = header
+                .inspect_err(|e| debug!(peer = %peer, "Closing connection: {}", e))?
+                .unwrap_or(peer);
+            inner.accept(stream, Extension(ConnectInfo(client)).layer(service)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_server::accept::DefaultAcceptor;
+    use tokio::io::AsyncWriteExt;
+
+    async fn read(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut input = [header, b"GET / HTTP/1.1\r\n"].concat();
+        let mut reader = &input[..];
+        let result = read_header(&mut reader).await;
+        // Nothing after the header is consumed
+        if result.is_ok() {
+            let rest = reader.to_vec();
+            input.drain(..header.len());
+            assert_eq!(rest, input);
+        }
+        result
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let len = (addresses.len() as u16).to_be_bytes();
+        [&V2_SIGNATURE[..], &[0x20 | command, family, len[0], len[1]], addresses].concat()
+    }
+
+    #[tokio::test]
+    async fn test_v1_headers() {
+        let client = read(b"PROXY TCP4 203.0.113.9 192.0.2.1 56324 443\r\n").await.unwrap();
+        assert_eq!(client, Some("203.0.113.9:56324".parse().unwrap()));
+        let client = read(b"PROXY TCP6 2001:db8::9 2001:db8::1 56324 443\r\n").await.unwrap();
+        assert_eq!(client, Some("[2001:db8::9]:56324".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").await.unwrap(), None);
+
+        for header in [
+            &b"PROXY TCP4 2001:db8::9 192.0.2.1 56324 443\r\n"[..],
+            b"PROXY TCP4 203.0.113.9 192.0.2.1 56324\r\n",
+            b"PROXY TCP4 203.0.113.9 192.0.2.1 99999 443\r\n",
+            b"PROXY UDP4 203.0.113.9 192.0.2.1 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: tunnel.example.com\r\n",
+        ] {
+            assert!(read(header).await.is_err(), "{}", String::from_utf8_lossy(header));
+        }
+        // No CRLF within the limit
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        assert!(read(long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_headers() {
+        let ipv4 = [[203, 0, 113, 9], [192, 0, 2, 1]].concat();
+        let client = read(&v2(1, 0x11, &[&ipv4[..], &56324u16.to_be_bytes(), &443u16.to_be_bytes()].concat())).await.unwrap();
+        assert_eq!(client, Some("203.0.113.9:56324".parse().unwrap()));
+
+        let source: Ipv6Addr = "2001:db8::9".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        // With a TLV after the addresses, which is skipped
+        let addresses = [&source.octets()[..], &destination.octets(), &56324u16.to_be_bytes(), &443u16.to_be_bytes(), &[0x04, 0, 1, 0]].concat();
+        let client = read(&v2(1, 0x21, &addresses)).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::9]:56324".parse().unwrap()));
+
+        // LOCAL, as health checks send, and families without an IP address
+        assert_eq!(read(&v2(0, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).await.unwrap(), None);
+
+        assert!(read(&v2(1, 0x11, &ipv4)).await.is_err(), "addresses cut short");
+        assert!(read(&v2(2, 0x11, &[0; 12])).await.is_err(), "unknown command");
+        let mut wrong_version = v2(1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert!(read(&wrong_version).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acceptor_sets_client_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|ConnectInfo(client): ConnectInfo<SocketAddr>| async move { client.to_string() }));
+        tokio::spawn(
+            axum_server::from_tcp(listener)
+                .acceptor(ProxyProtocolAcceptor::new(DefaultAcceptor))
+                .serve(app.into_make_service()),
+        );
+
+        let request = |header: Vec<u8>| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&header).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: tunnel.example.com\r\nConnection: close\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            // A refused connection is closed, possibly with a reset
+            let _ = stream.read_to_string(&mut response).await;
+            response
+        };
+
+        let response = request(b"PROXY TCP4 203.0.113.9 192.0.2.1 56324 443\r\n".to_vec()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("203.0.113.9:56324"), "{}", response);
+
+        let addresses = [&[198, 51, 100, 7][..], &[192, 0, 2, 1], &40000u16.to_be_bytes(), &443u16.to_be_bytes()].concat();
+        let response = request(v2(1, 0x11, &addresses)).await;
+        assert!(response.ends_with("198.51.100.7:40000"), "{}", response);
+
+        // The balancer's own health check gets its own address
+        let response = request(v2(0, 0x00, &[])).await;
+        assert!(response.contains("127.0.0.1:"), "{}", response);
+
+        // Direct connections without a header are closed unanswered
+        assert_eq!(request(Vec::new()).await, "");
+        assert_eq!(request(b"PROXY TCP4 nonsense\r\n".to_vec()).await, "");
+    }
+}
@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+
+use crate::proto::TunnelMode;
+
+use super::config::Config;
+use super::path_tunnel;
+
+/// Scheme visitors use to reach tunnels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+
+    fn default_port(self) -> u16 {
+        match self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "http" => Ok(Scheme::Http),
+            "https" => Ok(Scheme::Https),
+            _ => Err(format!("expected http or https, got '{}'", value)),
+        }
+    }
+}
+
+/// Builds the URLs visitors use, which may differ from what the server listens on
+/// (e.g. behind Cloudflare or a TLS-terminating proxy). Every public URL the server
+/// hands out goes through here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicUrlBuilder {
+    scheme: Scheme,
+    port: u16,
+    domain: String,
+}
+
+impl PublicUrlBuilder {
+    pub fn from_config(config: &Config) -> Self {
+        let server = &config.server;
+        let scheme = server.public_scheme.unwrap_or(
+            if config.https.is_some() || server.behind_cloudflare {
+                Scheme::Https
+            } else {
+                Scheme::Http
+            },
+        );
+        let port = server.public_port.unwrap_or(if config.https.is_some() {
+            server.https_port
+        } else if server.behind_cloudflare {
+            // Cloudflare serves visitors on the standard port whatever the origin uses
+            Scheme::Https.default_port()
+        } else {
+            server.http_port
+        });
+
+        Self {
+            scheme,
+            port,
+            domain: server.domain.clone(),
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `scheme://host[:port]`, leaving out the scheme's default port
+    pub fn origin(&self, host: &str) -> String {
+        let host = strip_port(host);
+        if self.port == self.scheme.default_port() {
+            format!("{}://{}", self.scheme.as_str(), host)
+        } else {
+            format!("{}://{}:{}", self.scheme.as_str(), host, self.port)
+        }
+    }
+
+    /// The public URL for a path on `host` (whose port, if any, is replaced)
+    pub fn url(&self, host: &str, path_and_query: &str) -> String {
+        format!("{}{}", self.origin(host), path_and_query)
+    }
+
+    /// The URL of a tunnel
+    pub fn tunnel_url(&self, subdomain: &str) -> String {
+        self.origin(&format!("{}.{}", subdomain, self.domain))
+    }
+
+    /// The URL of an HTTP tunnel, under `/t/` on the base domain for path tunnels
+    pub fn http_tunnel_url(&self, subdomain: &str, mode: TunnelMode) -> String {
+        match mode {
+            TunnelMode::Subdomain => self.tunnel_url(subdomain),
+            TunnelMode::Path => self.url(&self.domain, &format!("{}/", path_tunnel::prefix(subdomain))),
+        }
+    }
+
+    /// The address of a TCP tunnel, on the base domain since the port identifies it
+    pub fn tcp_url(&self, port: u16) -> String {
+        format!("tcp://{}:{}", self.domain, port)
+    }
+}
+
+/// Remove a `:port` suffix, keeping bracketed IPv6 literals intact
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(server: &str, https: bool) -> PublicUrlBuilder {
+        let https = if https {
+            "\n[https]\nemail = \"admin@example.com\"\n"
+        } else {
+            ""
+        };
+        let config = Config::parse(&format!(
+            "[server]\ndomain = \"tunnel.example.com\"\n{}\n[tokens.tk_test]\n{}",
+            server, https
+        ))
+        .unwrap();
+        PublicUrlBuilder::from_config(&config)
+    }
+
+    #[test]
+    fn test_port_and_scheme_matrix() {
+        let cases = [
+            // ([server] settings, [https]?, tunnel URL)
+            ("", false, "http://myapp.tunnel.example.com"),
+            ("http_port = 8080", false, "http://myapp.tunnel.example.com:8080"),
+            ("", true, "https://myapp.tunnel.example.com"),
+            ("https_port = 8443", true, "https://myapp.tunnel.example.com:8443"),
+            ("http_port = 8080\nhttps_port = 8443", true, "https://myapp.tunnel.example.com:8443"),
+            ("behind_cloudflare = true\nhttp_port = 8080", false, "https://myapp.tunnel.example.com"),
+            ("http_port = 8080\npublic_port = 80", false, "http://myapp.tunnel.example.com"),
+            ("http_port = 8080\npublic_scheme = \"https\"\npublic_port = 443", false, "https://myapp.tunnel.example.com"),
+            ("http_port = 8080\npublic_scheme = \"https\"", false, "https://myapp.tunnel.example.com:8080"),
+            ("https_port = 8443\npublic_port = 443", true, "https://myapp.tunnel.example.com"),
+            ("behind_cloudflare = true\npublic_port = 8443", false, "https://myapp.tunnel.example.com:8443"),
+        ];
+        for (server, https, expected) in cases {
+            assert_eq!(builder(server, https).tunnel_url("myapp"), expected, "{} (https: {})", server, https);
+        }
+    }
+
+    #[test]
+    fn test_url_replaces_host_port() {
+        let builder = builder("https_port = 8443", true);
+        assert_eq!(
+            builder.url("myapp.tunnel.example.com:80", "/path?q=1"),
+            "https://myapp.tunnel.example.com:8443/path?q=1"
+        );
+        assert_eq!(builder.url("[2001:db8::1]:80", "/"), "https://[2001:db8::1]:8443/");
+        assert_eq!(builder.url("myapp.tunnel.example.com", "/"), "https://myapp.tunnel.example.com:8443/");
+    }
+
+    #[test]
+    fn test_http_tunnel_url_for_path_tunnels() {
+        let builder = builder("https_port = 8443", true);
+        assert_eq!(builder.http_tunnel_url("myapp", TunnelMode::Subdomain), "https://myapp.tunnel.example.com:8443");
+        assert_eq!(builder.http_tunnel_url("myapp", TunnelMode::Path), "https://tunnel.example.com:8443/t/myapp/");
+    }
+
+    #[test]
+    fn test_tcp_url_ignores_http_settings() {
+        let builder = builder("https_port = 8443
+public_port = 443", true);
+        assert_eq!(builder.tcp_url(20042), "tcp://tunnel.example.com:20042");
+    }
+}
@@ -0,0 +1,231 @@
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Duration;
+// Tokio's, so tests can pause time and count exactly
+use tokio::time::Instant;
+
+/// Stop tracking keys beyond this many and drop expired windows
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Fixed-window rate limiter keyed by remote IP (or any other key).
+///
+/// Each use site owns its own limiter so thresholds stay independent.
+#[derive(Debug)]
+pub struct RateLimiter<K = IpAddr>
+where
+    K: Eq + Hash,
+{
+    max_hits: u32,
+    window: Duration,
+    windows: DashMap<K, Window>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started: Instant,
+    hits: u32,
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(max_hits: u32, window: Duration) -> Self {
+        Self {
+            max_hits,
+            window,
+            windows: DashMap::new(),
+        }
+    }
+
+    /// Record a hit for `key`, returning false if it exceeds the limit
+    pub fn check(&self, key: &K) -> bool {
+        self.purge_if_full();
+
+        let now = Instant::now();
+        let mut entry = self.windows.entry(key.clone()).or_insert(Window {
+            started: now,
+            hits: 0,
+        });
+        if now.duration_since(entry.started) >= self.window {
+            *entry = Window {
+                started: now,
+                hits: 0,
+            };
+        }
+        if entry.hits >= self.max_hits {
+            return false;
+        }
+        entry.hits += 1;
+        true
+    }
+
+    /// Whether `key` is currently over the limit, without recording a hit
+    pub fn is_limited(&self, key: &K) -> bool {
+        self.windows
+            .get(key)
+            .map(|w| w.started.elapsed() < self.window && w.hits >= self.max_hits)
+            .unwrap_or(false)
+    }
+
+    /// Drop windows that have expired
+    pub fn purge_expired(&self) {
+        let window = self.window;
+        self.windows.retain(|_, w| w.started.elapsed() < window);
+    }
+
+    fn purge_if_full(&self) {
+        if self.windows.len() >= MAX_TRACKED_KEYS {
+            self.purge_expired();
+        }
+    }
+}
+
+/// Token bucket rate limiter keyed by remote IP (or any other key). Each key starts
+/// with `capacity` tokens, spends them on hits, and gets `capacity` back per `period`,
+/// so a burst is allowed and a steady rate after it.
+#[derive(Debug)]
+pub struct TokenBucket<K = IpAddr>
+where
+    K: Eq + Hash,
+{
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: DashMap<K, Bucket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K> TokenBucket<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_per_sec: capacity as f64 / period.as_secs_f64(),
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Spend `cost` of `key`'s tokens, returning false (spending nothing) if it
+    /// hasn't that many
+    pub fn take(&self, key: &K, cost: u32) -> bool {
+        self.purge_if_full();
+        let mut bucket = self.refilled(key);
+        if bucket.tokens < cost as f64 {
+            return false;
+        }
+        bucket.tokens -= cost as f64;
+        true
+    }
+
+    /// Spend up to `cost` more of `key`'s tokens after the fact, such as when a hit
+    /// turned out to be worse than it looked. Never leaves the bucket below empty, so
+    /// it refills as quickly as after any other burst.
+    pub fn charge(&self, key: &K, cost: u32) {
+        let mut bucket = self.refilled(key);
+        bucket.tokens = (bucket.tokens - cost as f64).max(0.0);
+    }
+
+    /// Seconds until an empty bucket has a token again, rounded up
+    pub fn secs_per_token(&self) -> u64 {
+        (1.0 / self.refill_per_sec).ceil() as u64
+    }
+
+    fn refilled(&self, key: &K) -> dashmap::mapref::one::RefMut<'_, K, Bucket> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.refill_per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+        bucket.updated = now;
+        bucket
+    }
+
+    /// Drop buckets that have refilled, which are no different from new ones
+    pub fn purge_full(&self) {
+        let (capacity, refill_per_sec) = (self.capacity, self.refill_per_sec);
+        self.buckets
+            .retain(|_, b| b.tokens + b.updated.elapsed().as_secs_f64() * refill_per_sec < capacity);
+    }
+
+    fn purge_if_full(&self) {
+        if self.buckets.len() >= MAX_TRACKED_KEYS {
+            self.purge_full();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_window() {
+        let limiter = RateLimiter::new(3, Duration::from_millis(50));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.check(&ip));
+        assert!(limiter.check(&ip));
+        assert!(!limiter.is_limited(&ip));
+        assert!(limiter.check(&ip));
+        assert!(!limiter.check(&ip));
+        assert!(limiter.is_limited(&ip));
+
+        // Keys are independent
+        assert!(limiter.check(&other));
+
+        // The window resets
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!limiter.is_limited(&ip));
+        assert!(limiter.check(&ip));
+
+        limiter.purge_expired();
+        assert_eq!(limiter.windows.len(), 1);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(4, Duration::from_millis(200));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        // A burst up to the capacity
+        for _ in 0..4 {
+            assert!(bucket.take(&ip, 1));
+        }
+        assert!(!bucket.take(&ip, 1));
+        assert!(bucket.take(&other, 1));
+
+        // Refills at capacity per period: one token every 50ms
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(bucket.take(&ip, 1));
+        assert!(!bucket.take(&ip, 1));
+
+        // A charge takes what it can, then refilling works as usual
+        bucket.charge(&other, 10);
+        assert!(!bucket.take(&other, 1));
+        std::thread::sleep(Duration::from_millis(210));
+        for _ in 0..4 {
+            assert!(bucket.take(&other, 1));
+        }
+
+        // A costlier hit isn't taken out of too few tokens
+        assert!(bucket.take(&ip, 3));
+        assert!(!bucket.take(&ip, 3));
+        assert!(bucket.take(&ip, 1));
+
+        std::thread::sleep(Duration::from_millis(210));
+        bucket.purge_full();
+        assert_eq!(bucket.buckets.len(), 0);
+    }
+}
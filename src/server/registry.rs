@@ -1,9 +1,16 @@
 use dashmap::DashMap;
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::watch;
 
+use super::names::{Subdomain, TokenSecret};
+use super::reservations::Reservations;
 use super::tunnel::Tunnel;
+use crate::idn;
+use crate::proto::{Protocol, TunnelMode};
 
 #[derive(Debug, Error)]
 pub enum RegistryError {
@@ -13,26 +20,128 @@ pub enum RegistryError {
     InvalidSubdomain(String),
     #[error("Reserved subdomain")]
     ReservedSubdomain,
+    #[error("Token already has {0} tunnels connected")]
+    TunnelLimitReached(usize),
+    #[error("Subdomain is held for its previous client to reconnect")]
+    HeldForReconnect,
+    /// One of the tunnel's aliases couldn't be registered
+    #[error("Alias '{0}': {1}")]
+    Alias(String, Box<RegistryError>),
+}
+
+/// Names leading to a tunnel registered under another, kept apart from the subdomains
+/// tunnels register on
+#[derive(Default)]
+struct Aliases {
+    /// Aliases clients registered, and the tunnel each leads to
+    registered: DashMap<Subdomain, Arc<Tunnel>>,
+    /// Aliases from the config (`registry.aliases`), and the subdomain each leads to.
+    /// No tunnel may register one of these names.
+    configured: HashMap<Subdomain, Subdomain>,
+}
+
+impl Aliases {
+    /// The tunnel that registered `name` as an alias
+    fn owner(&self, name: &str) -> Option<Arc<Tunnel>> {
+        self.registered.get(name).map(|r| r.value().clone())
+    }
+
+    fn claim(&self, tunnel: &Arc<Tunnel>) {
+        for alias in &tunnel.aliases {
+            self.registered.insert(alias.clone(), tunnel.clone());
+        }
+    }
+
+    fn release(&self, tunnel: &Arc<Tunnel>) {
+        for alias in &tunnel.aliases {
+            self.registered.remove_if(alias, |_, owner| Arc::ptr_eq(owner, tunnel));
+        }
+    }
+
+    /// The configured aliases leading to `subdomain`
+    fn configured_for<'a>(&'a self, subdomain: &'a Subdomain) -> impl Iterator<Item = &'a Subdomain> {
+        self.configured
+            .iter()
+            .filter(move |(_, canonical)| *canonical == subdomain)
+            .map(|(alias, _)| alias)
+    }
 }
 
 pub struct Registry {
-    tunnels: DashMap<String, Arc<Tunnel>>,
+    tunnels: DashMap<Subdomain, Arc<Tunnel>>,
+    aliases: Aliases,
+    /// Held while a tunnel registers or deregisters, so its name and its aliases
+    /// change together
+    changes: Mutex<()>,
+    /// Names registered per token, a tunnel's aliases included, for the per-token limit
+    per_token: DashMap<TokenSecret, usize>,
     reserved: HashSet<String>,
+    /// Names kept for the token whose client dropped without saying goodbye, until
+    /// the instant given
+    held: DashMap<Subdomain, (TokenSecret, Instant)>,
+    /// Next tunnel epoch. One counter for every subdomain keeps each subdomain's epochs
+    /// increasing without remembering every name ever registered.
+    next_epoch: AtomicU64,
+    /// Bumped whenever a tunnel registers or deregisters, for long-polling the
+    /// tunnel listing
+    generation: watch::Sender<u64>,
+    /// Subdomains only one token may register, connected or not
+    reservations: Reservations,
 }
 
+/// Names no tunnel may register, whatever the config adds
+pub const RESERVED: [&str; 7] = ["www", "api", "admin", "mail", "ftp", "ssh", "tunnel"];
+
 impl Registry {
-    pub fn new() -> Self {
-        let reserved: HashSet<String> = ["www", "api", "admin", "mail", "ftp", "ssh", "tunnel"]
+    /// A registry refusing the built-in reserved names and `reserved` (`registry.reserved`)
+    pub fn new(reserved: &[String]) -> Self {
+        let reserved: HashSet<String> = RESERVED
             .iter()
             .map(|s| s.to_string())
+            .chain(reserved.iter().map(|s| s.to_ascii_lowercase()))
             .collect();
 
         Self {
             tunnels: DashMap::new(),
+            aliases: Aliases::default(),
+            changes: Mutex::new(()),
+            per_token: DashMap::new(),
             reserved,
+            held: DashMap::new(),
+            next_epoch: AtomicU64::new(1),
+            generation: watch::Sender::new(0),
+            reservations: Reservations::default(),
         }
     }
 
+    /// Use `reservations`, such as ones loaded from the state directory
+    pub fn with_reservations(mut self, reservations: Reservations) -> Self {
+        self.reservations = reservations;
+        self
+    }
+
+    /// Route each alias in `aliases` (`registry.aliases`) to the subdomain it maps to.
+    /// The config has checked both are valid names.
+    pub fn with_aliases(mut self, aliases: &BTreeMap<String, String>) -> Self {
+        self.aliases.configured = aliases
+            .iter()
+            .filter_map(|(alias, canonical)| {
+                let alias = Subdomain::new(&alias.to_ascii_lowercase()).ok()?;
+                Some((alias, Subdomain::new(&canonical.to_ascii_lowercase()).ok()?))
+            })
+            .collect();
+        self
+    }
+
+    pub fn reservations(&self) -> &Reservations {
+        &self.reservations
+    }
+
+    /// Whether `subdomain` is one no tunnel may register
+    pub fn is_reserved(&self, subdomain: &str) -> bool {
+        self.reserved.contains(&subdomain.to_ascii_lowercase())
+    }
+
     pub fn validate_subdomain(subdomain: &str) -> Result<(), RegistryError> {
         if subdomain.len() < 3 || subdomain.len() > 63 {
             return Err(RegistryError::InvalidSubdomain(
@@ -40,6 +149,14 @@ impl Registry {
             ));
         }
 
+        // Clients send internationalized names as punycode, which is what certificates
+        // and Host headers carry
+        if !subdomain.is_ascii() {
+            return Err(RegistryError::InvalidSubdomain(
+                "Subdomain must be ASCII; send its punycode (xn--) form".to_string(),
+            ));
+        }
+
         if !subdomain
             .chars()
             .all(|c| c.is_ascii_alphanumeric() || c == '-')
@@ -55,54 +172,309 @@ impl Registry {
             ));
         }
 
+        if idn::is_punycode(subdomain) && !idn::is_valid_punycode(subdomain) {
+            return Err(RegistryError::InvalidSubdomain(
+                "Subdomain is not valid lowercase punycode".to_string(),
+            ));
+        }
+
         Ok(())
     }
 
-    pub fn register(&self, subdomain: &str, tunnel: Arc<Tunnel>) -> Result<(), RegistryError> {
-        Self::validate_subdomain(subdomain)?;
+    /// Register a tunnel, refusing it if its token already has `max_per_token` tunnels
+    /// (0 = no limit)
+    pub fn register(
+        &self,
+        subdomain: &Subdomain,
+        tunnel: Arc<Tunnel>,
+        max_per_token: usize,
+    ) -> Result<(), RegistryError> {
+        self.insert(subdomain, tunnel, max_per_token, false).map(|_| ())
+    }
 
-        if self.reserved.contains(subdomain) {
-            return Err(RegistryError::ReservedSubdomain);
+    /// Register a tunnel like `register`, except that a tunnel its token already has
+    /// on `subdomain` is replaced rather than refused: its client reconnected before
+    /// the old connection was noticed gone. Returns the replaced tunnel, for closing.
+    pub fn reclaim(
+        &self,
+        subdomain: &Subdomain,
+        tunnel: Arc<Tunnel>,
+        max_per_token: usize,
+    ) -> Result<Option<Arc<Tunnel>>, RegistryError> {
+        self.insert(subdomain, tunnel, max_per_token, true)
+    }
+
+    fn insert(
+        &self,
+        subdomain: &Subdomain,
+        tunnel: Arc<Tunnel>,
+        max_per_token: usize,
+        reclaim: bool,
+    ) -> Result<Option<Arc<Tunnel>>, RegistryError> {
+        self.check_name(subdomain, &tunnel.token)?;
+        for alias in &tunnel.aliases {
+            self.check_name(alias, &tunnel.token)
+                .map_err(|e| RegistryError::Alias(alias.to_string(), Box::new(e)))?;
         }
 
+        let _changes = self.changes.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.aliases.owner(subdomain).is_some() {
+            return Err(RegistryError::SubdomainTaken);
+        }
+        for alias in &tunnel.aliases {
+            // A tunnel its token is replacing on the same subdomain gives its aliases up
+            let taken = self.tunnels.contains_key(alias)
+                || self
+                    .aliases
+                    .owner(alias)
+                    .is_some_and(|owner| !(reclaim && owner.token == tunnel.token && owner.subdomain == *subdomain));
+            if taken {
+                return Err(RegistryError::Alias(alias.to_string(), Box::new(RegistryError::SubdomainTaken)));
+            }
+        }
+
+        // Hold the token's count while inserting, so concurrent registrations with the
+        // same token can't both take the last slot
+        let mut count = self.per_token.entry(tunnel.token.clone()).or_insert(0);
+        let slots = 1 + tunnel.aliases.len();
+
         // Try to insert, fail if already exists
-        match self.tunnels.entry(subdomain.to_string()) {
-            dashmap::mapref::entry::Entry::Occupied(_) => Err(RegistryError::SubdomainTaken),
+        let replaced = match self.tunnels.entry(subdomain.clone()) {
+            // Taking over its own tunnel only counts the aliases it adds
+            dashmap::mapref::entry::Entry::Occupied(mut entry) if reclaim && entry.get().token == tunnel.token => {
+                let released = 1 + entry.get().aliases.len();
+                if max_per_token > 0 && *count - released + slots > max_per_token {
+                    return Err(RegistryError::TunnelLimitReached(max_per_token));
+                }
+                tunnel.set_epoch(self.next_epoch.fetch_add(1, Ordering::Relaxed));
+                *count = *count - released + slots;
+                Some(entry.insert(tunnel.clone()))
+            }
+            dashmap::mapref::entry::Entry::Occupied(_) => return Err(RegistryError::SubdomainTaken),
             dashmap::mapref::entry::Entry::Vacant(entry) => {
-                entry.insert(tunnel);
+                if max_per_token > 0 && *count + slots > max_per_token {
+                    return Err(RegistryError::TunnelLimitReached(max_per_token));
+                }
+                tunnel.set_epoch(self.next_epoch.fetch_add(1, Ordering::Relaxed));
+                entry.insert(tunnel.clone());
+                *count += slots;
+                None
+            }
+        };
+        drop(count);
+        if let Some(ref replaced) = replaced {
+            self.aliases.release(replaced);
+        }
+        self.aliases.claim(&tunnel);
+        self.held.remove(subdomain);
+        self.bump_generation();
+        Ok(replaced)
+    }
+
+    /// Whether `reclaim` would accept `token`'s tunnel on `subdomain` right now,
+    /// without registering anything. Another registration may still beat it there.
+    pub fn check(&self, subdomain: &str, token: &TokenSecret, max_per_token: usize) -> Result<(), RegistryError> {
+        self.check_name(subdomain, token)?;
+        if self.aliases.owner(subdomain).is_some() {
+            return Err(RegistryError::SubdomainTaken);
+        }
+        match self.tunnels.get(subdomain) {
+            Some(current) if current.token == *token => Ok(()),
+            Some(_) => Err(RegistryError::SubdomainTaken),
+            None => {
+                let count = self.per_token.get(token).map_or(0, |count| *count);
+                if max_per_token > 0 && count >= max_per_token {
+                    return Err(RegistryError::TunnelLimitReached(max_per_token));
+                }
                 Ok(())
             }
         }
     }
 
-    pub fn deregister(&self, subdomain: &str) {
-        self.tunnels.remove(subdomain);
+    /// Whether `token` may have `subdomain` at all, whoever is connected on it
+    fn check_name(&self, subdomain: &str, token: &TokenSecret) -> Result<(), RegistryError> {
+        Self::validate_subdomain(subdomain)?;
+
+        if self.is_reserved(subdomain) {
+            return Err(RegistryError::ReservedSubdomain);
+        }
+        // Always leads to the tunnel on the subdomain it maps to
+        if self.aliases.configured.contains_key(subdomain) {
+            return Err(RegistryError::SubdomainTaken);
+        }
+        // Reserved for a token, which keeps it whether or not its client is connected
+        if self.reservations.owner(subdomain).is_some_and(|owner| token.id() != owner) {
+            return Err(RegistryError::SubdomainTaken);
+        }
+        if self.held_by(subdomain).is_some_and(|holder| holder != *token) {
+            return Err(RegistryError::HeldForReconnect);
+        }
+        Ok(())
+    }
+
+    /// Keep `tunnel`'s subdomain for its token for `grace` after its client dropped,
+    /// so no other token can take it before the client reconnects
+    pub fn hold_for_reconnect(&self, tunnel: &Tunnel, grace: Duration) {
+        if grace.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        self.held.retain(|_, (_, until)| *until > now);
+        self.held.insert(tunnel.subdomain.clone(), (tunnel.token.clone(), now + grace));
+    }
+
+    /// The token `subdomain` is held for, if its hold hasn't expired
+    fn held_by(&self, subdomain: &str) -> Option<TokenSecret> {
+        self.held
+            .get(subdomain)
+            .filter(|hold| hold.1 > Instant::now())
+            .map(|hold| hold.0.clone())
+    }
+
+    /// Deregister `tunnel` with its aliases, unless another tunnel has taken its
+    /// subdomain since. Returns whether it was still registered.
+    pub fn deregister_tunnel(&self, tunnel: &Arc<Tunnel>) -> bool {
+        let _changes = self.changes.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.tunnels.remove_if(&tunnel.subdomain, |_, current| Arc::ptr_eq(current, tunnel)) {
+            Some((_, tunnel)) => {
+                self.aliases.release(&tunnel);
+                self.release_token_slot(&tunnel);
+                self.bump_generation();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Deregister `tunnel` and close its client's connection, telling it `reason`
+    pub fn disconnect(&self, tunnel: &Arc<Tunnel>, reason: &str) {
+        self.deregister_tunnel(tunnel);
+        tunnel.close(reason);
+    }
+
+    fn release_token_slot(&self, tunnel: &Tunnel) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut entry) = self.per_token.entry(tunnel.token.clone()) {
+            *entry.get_mut() -= 1 + tunnel.aliases.len();
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Wake listings waiting for a change, such as a tunnel pausing for maintenance
+    pub fn bump_generation(&self) {
+        self.generation.send_modify(|generation| *generation += 1);
+    }
+
+    /// Counts registrations, deregistrations and tunnels pausing or resuming, so a
+    /// listing can say which state of the registry it shows
+    pub fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// Wait up to `timeout` for the generation to move on from `generation`, and
+    /// return the generation then. Returns at once if it already has.
+    pub async fn changed_since(&self, generation: u64, timeout: Duration) -> u64 {
+        let mut changes = self.generation.subscribe();
+        let _ = tokio::time::timeout(timeout, changes.wait_for(|current| *current != generation)).await;
+        self.generation()
+    }
+
+    /// Whether another tunnel has registered `tunnel`'s subdomain since it did. A
+    /// tunnel that disconnected without a successor hasn't been replaced.
+    pub fn replaced(&self, tunnel: &Tunnel) -> bool {
+        self.tunnels
+            .get(&tunnel.subdomain)
+            .is_some_and(|current| current.epoch() != tunnel.epoch())
     }
 
     pub fn get(&self, subdomain: &str) -> Option<Arc<Tunnel>> {
         self.tunnels.get(subdomain).map(|r| r.value().clone())
     }
 
+    /// The tunnel visitors to `name` reach: the one registered on it, or the one an
+    /// alias by that name leads to
+    pub fn resolve(&self, name: &str) -> Option<Arc<Tunnel>> {
+        if let Some(tunnel) = self.get(name) {
+            return Some(tunnel);
+        }
+        if let Some(canonical) = self.aliases.configured.get(name) {
+            return self.get(canonical);
+        }
+        // Its alias may outlive it for a moment while it deregisters
+        let tunnel = self.aliases.owner(name)?;
+        self.get(&tunnel.subdomain).filter(|current| Arc::ptr_eq(current, &tunnel))
+    }
+
+    /// Every name `tunnel` is reached on besides its subdomain, sorted: its own aliases
+    /// and the configured ones leading to its subdomain. Only HTTP tunnels on a
+    /// subdomain of their own have any.
+    pub fn aliases_of(&self, tunnel: &Tunnel) -> Vec<Subdomain> {
+        if tunnel.protocol() != Protocol::Http || tunnel.mode != TunnelMode::Subdomain {
+            return Vec::new();
+        }
+        let mut aliases: Vec<Subdomain> = self
+            .aliases
+            .configured_for(&tunnel.subdomain)
+            .chain(&tunnel.aliases)
+            .cloned()
+            .collect();
+        aliases.sort();
+        aliases
+    }
+
+    /// Whether a tunnel is reached on `name` as a subdomain of its own, so a
+    /// certificate for it is worth requesting
+    pub fn serves_subdomain(&self, name: &str) -> bool {
+        self.resolve(name)
+            .is_some_and(|tunnel| tunnel.protocol() == Protocol::Http && tunnel.mode == TunnelMode::Subdomain)
+    }
+
+    /// Whether `name` is an alias from the config
+    pub fn is_static_alias(&self, name: &str) -> bool {
+        self.aliases.configured.contains_key(name)
+    }
+
     /// Get all subdomain names (for iteration during idle cleanup)
-    pub fn subdomains(&self) -> Vec<String> {
+    pub fn subdomains(&self) -> Vec<Subdomain> {
         self.tunnels.iter().map(|r| r.key().clone()).collect()
     }
 
+    /// Every registered tunnel
+    pub fn tunnels(&self) -> Vec<Arc<Tunnel>> {
+        self.tunnels.iter().map(|r| r.value().clone()).collect()
+    }
+
     #[allow(dead_code)]
     pub fn count(&self) -> usize {
         self.tunnels.len()
     }
+
+    /// Tunnels registered with `token`
+    pub fn tunnels_for_token(&self, token: &TokenSecret) -> Vec<Arc<Tunnel>> {
+        self.tunnels
+            .iter()
+            .filter(|r| r.value().token == *token)
+            .map(|r| r.value().clone())
+            .collect()
+    }
+
+    /// Tunnels currently registered with `token`
+    pub fn count_for_token(&self, token: &TokenSecret) -> usize {
+        self.per_token.get(token).map(|count| *count).unwrap_or(0)
+    }
 }
 
 impl Default for Registry {
     fn default() -> Self {
-        Self::new()
+        Self::new(&[])
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::names::TokenId;
 
     #[test]
     fn test_subdomain_validation() {
@@ -115,5 +487,374 @@ mod tests {
         assert!(Registry::validate_subdomain("myapp-").is_err()); // ends with hyphen
         assert!(Registry::validate_subdomain("my_app").is_err()); // underscore
         assert!(Registry::validate_subdomain("my.app").is_err()); // dot
+        assert!(Registry::validate_subdomain("xn--mnchen-demo-thb").is_ok());
+        assert!(Registry::validate_subdomain("münchen-demo").is_err()); // not punycode
+        assert!(Registry::validate_subdomain("xn--zz-zzzzzzzz").is_err()); // doesn't decode
+        assert!(Registry::validate_subdomain("XN--MNCHEN-DEMO-9DB").is_err()); // not canonical
+    }
+
+    fn named(subdomain: &str) -> Subdomain {
+        Subdomain::new(subdomain).unwrap()
+    }
+
+    fn secret(token: &str) -> TokenSecret {
+        TokenSecret::new(token)
+    }
+
+    fn tunnel(subdomain: &str, token: &str) -> Arc<Tunnel> {
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        Arc::new(Tunnel::new(Subdomain::new(subdomain).unwrap(), TokenSecret::new(token), "127.0.0.1:50000".parse().unwrap(), request_tx))
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        // Only the built-in names by default
+        let registry = Registry::default();
+        assert!(matches!(registry.register(&named("www"), tunnel("www", "tk_a"), 0), Err(RegistryError::ReservedSubdomain)));
+        registry.register(&named("staging"), tunnel("staging", "tk_a"), 0).unwrap();
+
+        // Configured names are reserved exactly, whatever their case
+        let registry = Registry::new(&["Staging".to_string(), "status".to_string()]);
+        for name in ["staging", "STATUS", "tunnel"] {
+            assert!(
+                matches!(registry.register(&named(name), tunnel(name, "tk_a"), 0), Err(RegistryError::ReservedSubdomain)),
+                "{}",
+                name
+            );
+        }
+        registry.register(&named("staging-2"), tunnel("staging-2", "tk_a"), 0).unwrap();
+        registry.register(&named("my-status"), tunnel("my-status", "tk_a"), 0).unwrap();
+    }
+
+    #[test]
+    fn test_per_token_limit() {
+        let registry = Registry::default();
+        registry.register(&named("app-one"), tunnel("app-one", "tk_a"), 2).unwrap();
+        registry.register(&named("app-two"), tunnel("app-two", "tk_a"), 2).unwrap();
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+
+        assert!(matches!(
+            registry.register(&named("app-three"), tunnel("app-three", "tk_a"), 2),
+            Err(RegistryError::TunnelLimitReached(2))
+        ));
+        assert!(registry.get("app-three").is_none());
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+
+        // Other tokens have their own count
+        registry.register(&named("app-four"), tunnel("app-four", "tk_b"), 2).unwrap();
+        assert_eq!(registry.count_for_token(&secret("tk_b")), 1);
+
+        // Deregistering frees a slot
+        registry.deregister_tunnel(&registry.get("app-one").unwrap());
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 1);
+        registry.register(&named("app-three"), tunnel("app-three", "tk_a"), 2).unwrap();
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+    }
+
+    #[test]
+    fn test_deregister_tunnel_leaves_successor() {
+        let registry = Registry::default();
+        let old = tunnel("app-one", "tk_a");
+        registry.register(&named("app-one"), old.clone(), 0).unwrap();
+        registry.deregister_tunnel(&old);
+        assert!(registry.get("app-one").is_none());
+
+        // The name was taken again before the old tunnel's cleanup ran
+        let new = tunnel("app-one", "tk_b");
+        registry.register(&named("app-one"), new.clone(), 0).unwrap();
+        registry.deregister_tunnel(&old);
+        assert!(registry.get("app-one").is_some_and(|t| Arc::ptr_eq(&t, &new)));
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 0);
+        assert_eq!(registry.count_for_token(&secret("tk_b")), 1);
+    }
+
+    #[test]
+    fn test_epochs() {
+        let registry = Registry::default();
+        let old = tunnel("app-one", "tk_a");
+        registry.register(&named("app-one"), old.clone(), 0).unwrap();
+        assert!(old.epoch() > 0);
+        assert!(!registry.replaced(&old));
+
+        // Gone without a successor is not replaced
+        registry.deregister_tunnel(&old);
+        assert!(!registry.replaced(&old));
+
+        let new = tunnel("app-one", "tk_b");
+        registry.register(&named("app-one"), new.clone(), 0).unwrap();
+        assert!(new.epoch() > old.epoch());
+        assert!(registry.replaced(&old));
+        assert!(!registry.replaced(&new));
+
+        // A refused registration doesn't take an epoch
+        let refused = tunnel("app-one", "tk_c");
+        assert!(registry.register(&named("app-one"), refused.clone(), 0).is_err());
+        assert_eq!(refused.epoch(), 0);
+    }
+
+    #[test]
+    fn test_per_token_count_on_failures() {
+        let registry = Registry::default();
+        registry.register(&named("app-one"), tunnel("app-one", "tk_a"), 0).unwrap();
+
+        // Failed registrations don't count, and nor does taking over a tunnel
+        assert!(registry.register(&named("app-one"), tunnel("app-one", "tk_a"), 0).is_err());
+        assert!(registry.register(&named("app-one"), tunnel("app-one", "tk_b"), 0).is_err());
+        assert!(registry.register(&named("www"), tunnel("www", "tk_a"), 0).is_err());
+        assert!(registry.reclaim(&named("app-one"), tunnel("app-one", "tk_a"), 1).unwrap().is_some());
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 1);
+        assert_eq!(registry.count_for_token(&secret("tk_b")), 0);
+
+        // Nor do repeated or unknown deregistrations
+        let registered = registry.get("app-one").unwrap();
+        assert!(registry.deregister_tunnel(&registered));
+        assert!(!registry.deregister_tunnel(&registered));
+        assert!(!registry.deregister_tunnel(&tunnel("missing", "tk_a")));
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 0);
+        assert_eq!(registry.count(), 0);
+
+        // 0 means no limit
+        for i in 0..10 {
+            let name = format!("app-{}", i);
+            registry.register(&named(&name), tunnel(&name, "tk_a"), 0).unwrap();
+        }
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 10);
+    }
+
+    #[test]
+    fn test_same_token_reclaims_its_tunnel() {
+        let registry = Registry::default();
+        let stale = tunnel("app-one", "tk_a");
+        registry.register(&named("app-one"), stale.clone(), 0).unwrap();
+
+        let fresh = tunnel("app-one", "tk_a");
+        let replaced = registry.reclaim(&named("app-one"), fresh.clone(), 0).unwrap();
+        assert!(replaced.is_some_and(|t| Arc::ptr_eq(&t, &stale)));
+        assert!(registry.get("app-one").is_some_and(|t| Arc::ptr_eq(&t, &fresh)));
+        assert!(registry.replaced(&stale));
+
+        // The stale tunnel's cleanup leaves its successor alone
+        assert!(!registry.deregister_tunnel(&stale));
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 1);
+
+        // Another token can't take it over
+        assert!(matches!(
+            registry.reclaim(&named("app-one"), tunnel("app-one", "tk_b"), 0),
+            Err(RegistryError::SubdomainTaken)
+        ));
+        assert!(registry.get("app-one").is_some_and(|t| Arc::ptr_eq(&t, &fresh)));
+    }
+
+    #[test]
+    fn test_held_for_reconnect() {
+        let registry = Registry::default();
+        let dropped = tunnel("app-one", "tk_a");
+        registry.register(&named("app-one"), dropped.clone(), 0).unwrap();
+        assert!(registry.deregister_tunnel(&dropped));
+        registry.hold_for_reconnect(&dropped, Duration::from_secs(60));
+
+        // Other tokens are refused while it's held
+        assert!(matches!(
+            registry.register(&named("app-one"), tunnel("app-one", "tk_b"), 0),
+            Err(RegistryError::HeldForReconnect)
+        ));
+        assert!(registry.get("app-one").is_none());
+
+        // Its own token gets it back, which ends the hold
+        let back = tunnel("app-one", "tk_a");
+        registry.reclaim(&named("app-one"), back.clone(), 0).unwrap();
+        registry.deregister_tunnel(&back);
+        registry.register(&named("app-one"), tunnel("app-one", "tk_b"), 0).unwrap();
+
+        // Holds expire
+        let other = tunnel("app-two", "tk_a");
+        registry.hold_for_reconnect(&other, Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(5));
+        registry.register(&named("app-two"), tunnel("app-two", "tk_b"), 0).unwrap();
+
+        // And a grace of 0 holds nothing
+        registry.hold_for_reconnect(&tunnel("app-three", "tk_a"), Duration::ZERO);
+        registry.register(&named("app-three"), tunnel("app-three", "tk_b"), 0).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_generation() {
+        let registry = Arc::new(Registry::default());
+        assert_eq!(registry.generation(), 0);
+
+        let first = tunnel("app-one", "tk_a");
+        registry.register(&named("app-one"), first.clone(), 0).unwrap();
+        assert_eq!(registry.generation(), 1);
+        // Refusals and deregistering a tunnel that's already gone change nothing
+        assert!(registry.register(&named("app-one"), tunnel("app-one", "tk_b"), 0).is_err());
+        assert!(!registry.deregister_tunnel(&tunnel("app-two", "tk_a")));
+        assert_eq!(registry.generation(), 1);
+        // Nor does waiting for a generation that has already passed
+        assert_eq!(registry.changed_since(0, Duration::from_secs(30)).await, 1);
+
+        // A waiter wakes when a tunnel deregisters
+        let waiter = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.changed_since(1, Duration::from_secs(30)).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(registry.deregister_tunnel(&first));
+        assert_eq!(waiter.await.unwrap(), 2);
+        assert!(!registry.deregister_tunnel(&first));
+
+        // And gives up after the timeout when nothing changes
+        let started = tokio::time::Instant::now();
+        assert_eq!(registry.changed_since(2, Duration::from_secs(30)).await, 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_reservations() {
+        let reservations = Reservations::default();
+        reservations.reserve("myapp", &TokenId::of("tk_a"));
+        let registry = Registry::default().with_reservations(reservations);
+
+        assert!(matches!(
+            registry.register(&named("myapp"), tunnel("myapp", "tk_b"), 0),
+            Err(RegistryError::SubdomainTaken)
+        ));
+        assert!(matches!(
+            registry.register(&named("MyApp"), tunnel("MyApp", "tk_b"), 0),
+            Err(RegistryError::SubdomainTaken)
+        ));
+        let owned = tunnel("myapp", "tk_a");
+        registry.register(&named("myapp"), owned.clone(), 0).unwrap();
+
+        // Still the owner's once its tunnel is gone, however it went
+        registry.deregister_tunnel(&owned);
+        assert!(matches!(
+            registry.register(&named("myapp"), tunnel("myapp", "tk_b"), 0),
+            Err(RegistryError::SubdomainTaken)
+        ));
+        registry.reservations().release("myapp");
+        registry.register(&named("myapp"), tunnel("myapp", "tk_b"), 0).unwrap();
+    }
+
+    #[test]
+    fn test_check_registers_nothing() {
+        let reservations = Reservations::default();
+        reservations.reserve("owned", &TokenId::of("tk_b"));
+        let registry = Registry::default().with_reservations(reservations);
+        registry.register(&named("app-one"), tunnel("app-one", "tk_a"), 0).unwrap();
+        let dropped = tunnel("app-two", "tk_b");
+        registry.hold_for_reconnect(&dropped, Duration::from_secs(60));
+
+        assert!(registry.check("app-three", &secret("tk_a"), 0).is_ok());
+        // Its own tunnel would be replaced
+        assert!(registry.check("app-one", &secret("tk_a"), 1).is_ok());
+        assert!(matches!(registry.check("app-one", &secret("tk_b"), 0), Err(RegistryError::SubdomainTaken)));
+        assert!(matches!(registry.check("app-three", &secret("tk_a"), 1), Err(RegistryError::TunnelLimitReached(1))));
+        assert!(matches!(registry.check("www", &secret("tk_a"), 0), Err(RegistryError::ReservedSubdomain)));
+        assert!(matches!(registry.check("owned", &secret("tk_a"), 0), Err(RegistryError::SubdomainTaken)));
+        assert!(matches!(registry.check("app-two", &secret("tk_a"), 0), Err(RegistryError::HeldForReconnect)));
+        assert!(matches!(registry.check("a", &secret("tk_a"), 0), Err(RegistryError::InvalidSubdomain(_))));
+
+        assert_eq!(registry.count(), 1);
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 1);
+        assert!(registry.get("app-three").is_none());
+    }
+
+    fn aliased(subdomain: &str, token: &str, aliases: &[&str]) -> Arc<Tunnel> {
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        let tunnel = Tunnel::new(Subdomain::new(subdomain).unwrap(), TokenSecret::new(token), "127.0.0.1:50000".parse().unwrap(), request_tx)
+            .with_aliases(aliases.iter().map(|alias| named(alias)).collect());
+        Arc::new(tunnel)
+    }
+
+    #[test]
+    fn test_aliases_resolve_to_their_tunnel() {
+        let registry = Registry::default().with_aliases(&BTreeMap::from([("docs".to_string(), "app".to_string())]));
+        let app = aliased("app", "tk_a", &["www-app"]);
+        registry.register(&named("app"), app.clone(), 0).unwrap();
+        for name in ["app", "www-app", "docs"] {
+            assert!(registry.resolve(name).is_some_and(|t| Arc::ptr_eq(&t, &app)), "{}", name);
+        }
+        assert!(registry.get("www-app").is_none());
+        assert_eq!(registry.aliases_of(&app), ["docs", "www-app"]);
+        assert!(registry.serves_subdomain("docs") && !registry.serves_subdomain("api"));
+        assert_eq!(registry.count(), 1);
+
+        // Deregistering takes its aliases with it, leaving the configured one for its successor
+        assert!(registry.deregister_tunnel(&app));
+        assert!(registry.resolve("www-app").is_none());
+        assert!(registry.resolve("docs").is_none());
+        let successor = tunnel("app", "tk_b");
+        registry.register(&named("app"), successor.clone(), 0).unwrap();
+        assert!(registry.resolve("docs").is_some_and(|t| Arc::ptr_eq(&t, &successor)));
+        assert!(registry.resolve("www-app").is_none());
+        registry.register(&named("www-app"), tunnel("www-app", "tk_b"), 0).unwrap();
+    }
+
+    #[test]
+    fn test_alias_conflicts() {
+        let registry = Registry::default().with_aliases(&BTreeMap::from([("docs".to_string(), "app".to_string())]));
+        registry.register(&named("app"), aliased("app", "tk_a", &["www-app"]), 0).unwrap();
+        registry.register(&named("api-v2"), tunnel("api-v2", "tk_b"), 0).unwrap();
+
+        // An alias can't take a registered subdomain, or another tunnel's alias,
+        // whichever token asks
+        for (alias, token) in [("api-v2", "tk_a"), ("www-app", "tk_b"), ("www-app", "tk_a")] {
+            let result = registry.register(&named("other"), aliased("other", token, &[alias]), 0);
+            assert!(
+                matches!(result, Err(RegistryError::Alias(ref name, ref e)) if name == alias && matches!(**e, RegistryError::SubdomainTaken)),
+                "{} {:?}",
+                alias,
+                result
+            );
+        }
+        assert!(registry.get("other").is_none());
+
+        // Nor can a subdomain take an alias, configured or registered
+        assert!(matches!(registry.register(&named("www-app"), tunnel("www-app", "tk_b"), 0), Err(RegistryError::SubdomainTaken)));
+        assert!(matches!(registry.register(&named("docs"), tunnel("docs", "tk_b"), 0), Err(RegistryError::SubdomainTaken)));
+        assert!(matches!(registry.check("www-app", &secret("tk_a"), 0), Err(RegistryError::SubdomainTaken)));
+
+        // Aliases are checked like subdomains
+        assert!(matches!(
+            registry.register(&named("other"), aliased("other", "tk_a", &["www"]), 0),
+            Err(RegistryError::Alias(_, ref e)) if matches!(**e, RegistryError::ReservedSubdomain)
+        ));
+
+        assert!(registry.resolve("www-app").is_some_and(|t| t.subdomain == "app"));
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+        assert_eq!(registry.count_for_token(&secret("tk_b")), 1);
+    }
+
+    #[test]
+    fn test_aliases_count_against_the_token() {
+        let registry = Registry::default();
+        assert!(matches!(
+            registry.register(&named("app"), aliased("app", "tk_a", &["www-app", "app-2"]), 2),
+            Err(RegistryError::TunnelLimitReached(2))
+        ));
+        assert!(registry.resolve("www-app").is_none());
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 0);
+
+        let first = aliased("app", "tk_a", &["www-app"]);
+        registry.register(&named("app"), first.clone(), 2).unwrap();
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+
+        // Reconnecting keeps the aliases it asks for again and drops the others
+        let second = aliased("app", "tk_a", &["app-2"]);
+        assert!(registry.reclaim(&named("app"), second.clone(), 2).unwrap().is_some_and(|t| Arc::ptr_eq(&t, &first)));
+        assert!(registry.resolve("www-app").is_none());
+        assert!(registry.resolve("app-2").is_some_and(|t| Arc::ptr_eq(&t, &second)));
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 2);
+        let third = aliased("app", "tk_a", &["app-2"]);
+        registry.reclaim(&named("app"), third.clone(), 2).unwrap();
+        assert!(registry.resolve("app-2").is_some_and(|t| Arc::ptr_eq(&t, &third)));
+
+        // The stale tunnel's cleanup leaves its successor's aliases alone
+        assert!(!registry.deregister_tunnel(&second));
+        assert!(registry.resolve("app-2").is_some());
+
+        assert!(registry.deregister_tunnel(&third));
+        assert!(registry.resolve("app-2").is_none());
+        assert_eq!(registry.count_for_token(&secret("tk_a")), 0);
     }
 }
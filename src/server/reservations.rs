@@ -0,0 +1,142 @@
+//! Subdomains bound to a token for good, so no other token can register them while
+//! the owner's client is offline. Kept in `reservations.json` in the state directory,
+//! by token id, so the file holds no secrets.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use super::tokens::replace_private;
+
+pub const RESERVATIONS_FILE: &str = "reservations.json";
+
+#[derive(Debug, Default)]
+pub struct Reservations {
+    /// The owning token's id, by subdomain. The lock also serializes saves.
+    owners: Mutex<BTreeMap<String, String>>,
+    /// Where changes are saved; None keeps them in memory until the server stops
+    path: Option<PathBuf>,
+}
+
+impl Reservations {
+    /// The reservations saved in `path`, saving changes there
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let owners: BTreeMap<String, String> = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Invalid {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        if !owners.is_empty() {
+            info!("Loaded {} subdomain reservation(s) from {}", owners.len(), path.display());
+        }
+        Ok(Self {
+            owners: Mutex::new(owners),
+            path: Some(path),
+        })
+    }
+
+    /// `reservations.json` in `state_dir`
+    pub fn path_in(state_dir: &Path) -> PathBuf {
+        state_dir.join(RESERVATIONS_FILE)
+    }
+
+    /// The id of the token `subdomain` is reserved for
+    pub fn owner(&self, subdomain: &str) -> Option<String> {
+        self.lock().get(&subdomain.to_ascii_lowercase()).cloned()
+    }
+
+    /// Every reservation, as (subdomain, token id), by subdomain
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.lock().iter().map(|(subdomain, owner)| (subdomain.clone(), owner.clone())).collect()
+    }
+
+    /// Reserve `subdomain` for the token with id `owner`, replacing any reservation it
+    /// had. Returns whether the change was saved.
+    pub fn reserve(&self, subdomain: &str, owner: &str) -> bool {
+        let mut owners = self.lock();
+        owners.insert(subdomain.to_ascii_lowercase(), owner.to_string());
+        self.save(&owners)
+    }
+
+    /// Drop `subdomain`'s reservation. Returns whether the change was saved, or None
+    /// if it wasn't reserved.
+    pub fn release(&self, subdomain: &str) -> Option<bool> {
+        let mut owners = self.lock();
+        owners.remove(&subdomain.to_ascii_lowercase())?;
+        Some(self.save(&owners))
+    }
+
+    /// Drop every reservation for the token with id `owner`, such as when it's revoked.
+    /// Returns how many there were.
+    pub fn release_owner(&self, owner: &str) -> usize {
+        let mut owners = self.lock();
+        let before = owners.len();
+        owners.retain(|_, reserved_for| reserved_for != owner);
+        let released = before - owners.len();
+        if released > 0 {
+            self.save(&owners);
+        }
+        released
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, String>> {
+        self.owners.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn save(&self, owners: &BTreeMap<String, String>) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let written = serde_json::to_vec_pretty(owners)
+            .map_err(std::io::Error::from)
+            .and_then(|content| replace_private(path, &content));
+        if let Err(e) = written {
+            warn!("Failed to save reservations to {}, the change lasts until restart: {}", path.display(), e);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("loophole-reservations-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = Reservations::path_in(&dir);
+
+        let reservations = Reservations::load(path.clone()).unwrap();
+        assert!(reservations.reserve("MyApp", "id_alice"));
+        assert!(reservations.reserve("docs", "id_alice"));
+        assert!(reservations.reserve("shop", "id_bob"));
+        assert_eq!(reservations.release("docs"), Some(true));
+        assert_eq!(reservations.release("docs"), None);
+
+        let reloaded = Reservations::load(path).unwrap();
+        assert_eq!(reloaded.owner("myapp").as_deref(), Some("id_alice"));
+        assert_eq!(reloaded.owner("MYAPP").as_deref(), Some("id_alice"));
+        assert_eq!(reloaded.owner("docs"), None);
+        assert_eq!(
+            reloaded.list(),
+            vec![("myapp".to_string(), "id_alice".to_string()), ("shop".to_string(), "id_bob".to_string())]
+        );
+
+        assert_eq!(reloaded.release_owner("id_bob"), 1);
+        assert_eq!(reloaded.owner("shop"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_reservations_are_not_saved() {
+        let reservations = Reservations::default();
+        assert!(!reservations.reserve("myapp", "id_alice"));
+        assert_eq!(reservations.owner("myapp").as_deref(), Some("id_alice"));
+        assert_eq!(reservations.release("myapp"), Some(false));
+    }
+}
@@ -0,0 +1,243 @@
+//! Response headers the operator stamps on matching tunnels' responses, such as
+//! `X-Env: staging`, from the config's `[[response_headers]]` rules. They're applied
+//! after the service's own headers, in config order, so a later rule's `set`
+//! replaces what an earlier rule or the service sent. A reload (SIGHUP) swaps the
+//! rules while requests are being proxied.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use std::sync::{Arc, RwLock};
+
+use super::config::ResponseHeaderRule;
+use super::proxy::is_hop_by_hop_header;
+
+/// A rule with its headers parsed
+#[derive(Debug)]
+struct Rule {
+    config: ResponseHeaderRule,
+    set: Vec<(HeaderName, HeaderValue)>,
+    add: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Every rule, in config order
+#[derive(Debug, Default)]
+pub struct HeaderRules(Vec<Rule>);
+
+impl HeaderRules {
+    pub fn new(rules: &[ResponseHeaderRule]) -> anyhow::Result<Self> {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| parse_rule(rule).map_err(|e| anyhow::anyhow!("response_headers[{}]: {}", i, e)))
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    /// Apply the rules matching `subdomain` to a response's headers
+    pub fn apply(&self, subdomain: &str, headers: &mut HeaderMap) {
+        for rule in self.0.iter().filter(|rule| rule.config.matches(subdomain)) {
+            for (name, value) in &rule.set {
+                headers.insert(name.clone(), value.clone());
+            }
+            for (name, value) in &rule.add {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn parse_rule(rule: &ResponseHeaderRule) -> Result<Rule, String> {
+    let valid = !rule.subdomain.is_empty()
+        && rule.subdomain.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '*');
+    if !valid {
+        return Err(format!(
+            "subdomain pattern '{}' may only contain lowercase letters, digits, hyphens and *",
+            rule.subdomain
+        ));
+    }
+    Ok(Rule {
+        config: rule.clone(),
+        set: parse_headers("set", &rule.set)?,
+        add: parse_headers("add", &rule.add)?,
+    })
+}
+
+fn parse_headers<'a>(
+    kind: &str,
+    headers: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("{}: '{}' isn't a header name", kind, name))?;
+            // The proxy frames the body itself, so these would garble the response
+            if is_hop_by_hop_header(name) || header == axum::http::header::CONTENT_LENGTH {
+                return Err(format!("{}: '{}' can't be set on responses", kind, name));
+            }
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("{}: the value of '{}' isn't a valid header value", kind, name))?;
+            Ok((header, value))
+        })
+        .collect()
+}
+
+/// The rules in use, which a config reload replaces
+#[derive(Debug, Default)]
+pub struct ResponseHeaders {
+    rules: RwLock<Arc<HeaderRules>>,
+}
+
+impl ResponseHeaders {
+    pub fn new(rules: HeaderRules) -> Self {
+        Self {
+            rules: RwLock::new(Arc::new(rules)),
+        }
+    }
+
+    pub fn set_rules(&self, rules: HeaderRules) {
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+
+    /// The rules as they are now; a request keeps these even if a reload replaces them
+    pub fn rules(&self) -> Arc<HeaderRules> {
+        self.rules.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> anyhow::Result<HeaderRules> {
+        #[derive(serde::Deserialize)]
+        struct Document {
+            response_headers: Vec<ResponseHeaderRule>,
+        }
+        let document: Document = toml::from_str(toml).unwrap();
+        HeaderRules::new(&document.response_headers)
+    }
+
+    fn service_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", HeaderValue::from_static("max-age=3600"));
+        headers.insert("x-env", HeaderValue::from_static("from-service"));
+        headers
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers.get_all(name).iter().map(|value| value.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let rules = rules(
+            r#"
+[[response_headers]]
+subdomain = "staging-*"
+set = { "X-Env" = "staging" }
+
+[[response_headers]]
+subdomain = "*-api"
+add = { "X-Api" = "yes" }
+"#,
+        )
+        .unwrap();
+
+        let mut headers = service_headers();
+        rules.apply("staging-web", &mut headers);
+        assert_eq!(values(&headers, "x-env"), ["staging"]);
+        assert!(headers.get("x-api").is_none());
+
+        let mut headers = service_headers();
+        rules.apply("staging-api", &mut headers);
+        assert_eq!(values(&headers, "x-env"), ["staging"]);
+        assert_eq!(values(&headers, "x-api"), ["yes"]);
+
+        let mut headers = service_headers();
+        rules.apply("prod-web", &mut headers);
+        assert_eq!(headers, service_headers());
+    }
+
+    #[test]
+    fn test_set_overrides_and_add_appends() {
+        let rules = rules(
+            r#"
+[[response_headers]]
+subdomain = "*"
+set = { "Cache-Control" = "no-store" }
+add = { "X-Env" = "staging", "X-Compliance" = "internal only" }
+"#,
+        )
+        .unwrap();
+
+        let mut headers = service_headers();
+        rules.apply("myapp", &mut headers);
+        assert_eq!(values(&headers, "cache-control"), ["no-store"]);
+        assert_eq!(values(&headers, "x-env"), ["from-service", "staging"]);
+        assert_eq!(values(&headers, "x-compliance"), ["internal only"]);
+    }
+
+    #[test]
+    fn test_later_rules_win() {
+        let rules = rules(
+            r#"
+[[response_headers]]
+subdomain = "*"
+set = { "X-Frame-Options" = "DENY" }
+add = { "X-Env" = "any" }
+
+[[response_headers]]
+subdomain = "embed"
+set = { "X-Frame-Options" = "SAMEORIGIN", "X-Env" = "embed" }
+"#,
+        )
+        .unwrap();
+
+        let mut headers = service_headers();
+        rules.apply("embed", &mut headers);
+        assert_eq!(values(&headers, "x-frame-options"), ["SAMEORIGIN"]);
+        // The later set replaces the service's value and the earlier rule's addition
+        assert_eq!(values(&headers, "x-env"), ["embed"]);
+
+        let mut headers = service_headers();
+        rules.apply("other", &mut headers);
+        assert_eq!(values(&headers, "x-frame-options"), ["DENY"]);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let error = |toml: &str| rules(toml).unwrap_err().to_string();
+        assert_eq!(
+            error("[[response_headers]]\nsubdomain = \"Staging\"\nset = { X-Env = \"staging\" }"),
+            "response_headers[0]: subdomain pattern 'Staging' may only contain lowercase letters, digits, hyphens and *"
+        );
+        assert_eq!(
+            error("[[response_headers]]\nsubdomain = \"*\"\nset = { \"Bad Name\" = \"x\" }"),
+            "response_headers[0]: set: 'Bad Name' isn't a header name"
+        );
+        assert_eq!(
+            error("[[response_headers]]\nsubdomain = \"*\"\nadd = { Transfer-Encoding = \"chunked\" }"),
+            "response_headers[0]: add: 'Transfer-Encoding' can't be set on responses"
+        );
+        assert_eq!(
+            error("[[response_headers]]\nsubdomain = \"*\"\nset = { X-Env = \"line\\nbreak\" }"),
+            "response_headers[0]: set: the value of 'X-Env' isn't a valid header value"
+        );
+    }
+
+    #[test]
+    fn test_reload_replaces_rules() {
+        let headers = ResponseHeaders::new(rules("[[response_headers]]\nsubdomain = \"*\"\nset = { X-Env = \"old\" }").unwrap());
+        let in_flight = headers.rules();
+        headers.set_rules(rules("[[response_headers]]\nsubdomain = \"*\"\nset = { X-Env = \"new\" }").unwrap());
+
+        let mut response = HeaderMap::new();
+        headers.rules().apply("myapp", &mut response);
+        assert_eq!(values(&response, "x-env"), ["new"]);
+
+        // A request already being proxied keeps the rules it started with
+        let mut response = HeaderMap::new();
+        in_flight.apply("myapp", &mut response);
+        assert_eq!(values(&response, "x-env"), ["old"]);
+    }
+}
@@ -1,38 +1,207 @@
 use axum::{
     body::Body,
-    extract::{ConnectInfo, Path, State},
-    http::{header, Request, StatusCode},
+    extract::{rejection::QueryRejection, ConnectInfo, Path, Query, State},
+    http::{header, HeaderValue, Request, StatusCode},
     response::{IntoResponse, Json, Redirect, Response},
-    routing::{any, delete, get},
+    routing::{any, delete, get, post, put},
     Extension, Router,
 };
 use axum::extract::ws::WebSocketUpgrade;
-use serde::Serialize;
-use std::net::SocketAddr;
+use axum::extract::FromRequestParts;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tracing::{debug, error, info};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
 
-use super::acme::ChallengeStore;
-use super::config::Config;
-use super::proxy::proxy_request;
+use crate::build_info::BuildInfo;
+use crate::proto::compat::ServerCompat;
+use crate::proto::transport::{CONNECT_PATH, MAX_WS_FRAME_SIZE, MAX_WS_MESSAGE_SIZE};
+use crate::proto::{ErrorCode, Protocol, TunnelMode};
+
+use super::acme::{constant_time_eq, ChallengeStore};
+use super::admin_json;
+use super::admission::{Admission, ConnectionGuard};
+use super::basic_auth::BasicAuth;
+use super::buffers::ProxyBuffers;
+use super::cert_prune::{self, Unused};
+use super::churn::Churn;
+use super::cloudflare::CloudflareRanges;
+use super::config::{Config, TokenConfig};
+use super::dns_monitor::{DnsReport, DnsStatus};
+use super::logs::{LogBuffer, LogEvent};
+use super::maintenance::Maintenance;
+use super::metrics::{ControlStats, Metrics};
+use super::motd::Motd;
+use super::pages::{wants_html, Page, PageVars, Pages};
+use super::path_tunnel::{self, PathRewrite};
+use super::proxy::{is_websocket_upgrade, proxy_request, ProxyOptions, ERROR_HEADER};
+use super::public_url::PublicUrlBuilder;
+use super::rate_limit::RateLimiter;
 use super::registry::Registry;
-use super::tls::CertManager;
+use super::response_headers::ResponseHeaders;
+use super::scheduler::FairScheduler;
+use super::slow_requests::SlowRequests;
+use super::tcp::{self, TcpPorts};
+use super::tokens::{TokenError, TokenStore};
+use super::names::{FullDomain, Subdomain, TokenId, TokenSecret};
+use super::ownership::{now_secs, Ownership};
+use super::tls::{BaseCertState, CertManager};
+use super::trusted_proxies;
+use super::tunnel::Tunnel;
+use super::usage::{Bucket, Usage};
 
 pub struct ServerState {
     pub config: Arc<Config>,
+    /// The tokens accepted now: the config's, as changed through the admin API
+    pub tokens: Arc<TokenStore>,
     pub registry: Arc<Registry>,
     pub cert_manager: Option<Arc<CertManager>>,
+    pub acme_probe_limiter: RateLimiter,
+    pub metrics: Arc<Metrics>,
+    /// Set when `behind_cloudflare` is enabled
+    pub cloudflare: Option<Arc<CloudflareRanges>>,
+    pub admission: Arc<Admission>,
+    /// Shares stream opens fairly between tunnels under load
+    pub scheduler: Arc<FairScheduler>,
+    /// Builds every URL handed out to visitors and clients
+    pub public_url: PublicUrlBuilder,
+    /// Fires when the server starts shutting down, so tunnels can warn their clients
+    pub shutdown_tx: broadcast::Sender<()>,
+    pub slow_requests: Arc<SlowRequests>,
+    /// Reconnects and registration bursts, for the churn metrics and warning
+    pub churn: Arc<Churn>,
+    /// Requests, bytes and tunnel time by token, for billing
+    pub usage: Arc<Usage>,
+    /// Set when `tcp.port_range` is configured
+    pub tcp_ports: Option<Arc<TcpPorts>>,
+    /// Pauses tunnels during their maintenance windows
+    pub maintenance: Arc<Maintenance>,
+    /// Operator-set headers for tunnels' responses, replaced on reload
+    pub response_headers: Arc<ResponseHeaders>,
+    /// When the server started, for the uptime `/_health` reports
+    pub started_at: std::time::Instant,
+    /// Shown to clients when they register, replaced on reload
+    pub motd: Arc<Motd>,
+    /// Whether DNS still points at the server, when it's monitored
+    pub dns: Arc<DnsStatus>,
+    /// Pages the server answers visitors with itself, themed and replaced on reload
+    pub pages: Arc<Pages>,
+    /// Buffers the proxy reuses across requests
+    pub proxy_buffers: Arc<ProxyBuffers>,
+    /// Recent log events, for `GET /_admin/logs`
+    pub logs: Arc<LogBuffer>,
+}
+
+impl ServerState {
+    /// Whether the peer is Cloudflare's proxy, so its forwarding headers can be trusted
+    fn is_cloudflare_peer(&self, peer: IpAddr) -> bool {
+        self.cloudflare.as_ref().is_some_and(|ranges| ranges.contains(peer))
+    }
+
+    /// The real client address: CF-Connecting-IP when the peer is Cloudflare, from
+    /// X-Forwarded-For when it's a trusted proxy, else the peer
+    fn client_ip(&self, peer: IpAddr, headers: &header::HeaderMap) -> IpAddr {
+        if !self.is_cloudflare_peer(peer) {
+            return trusted_proxies::client_ip(&self.config.server.trusted_proxies, peer, headers);
+        }
+        headers
+            .get("cf-connecting-ip")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+
+    /// Whether Cloudflare or a trusted proxy received the request over HTTPS (it
+    /// terminated TLS)
+    fn forwarded_https(&self, peer: IpAddr, headers: &header::HeaderMap) -> bool {
+        let cloudflare_https = self.is_cloudflare_peer(peer)
+            && headers
+                .get("x-forwarded-proto")
+                .and_then(|h| h.to_str().ok())
+                .is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+        cloudflare_https || trusted_proxies::forwarded_https(&self.config.server.trusted_proxies, peer, headers)
+    }
 }
 
 /// Create the main router for HTTPS (tunnel connections and proxying)
 pub fn create_router(state: Arc<ServerState>) -> Router {
-    let control_path = state.config.server.control_path();
     Router::new()
-        .route(control_path, any(handle_request))
+        .merge(public_control_routes(&state.config))
+        .merge(visitor_routes())
         .route("/*path", any(handle_request))
         .route("/", any(handle_request))
+        .with_state(state)
+}
+
+/// Create the router for the separate control listener (`server.control_port`)
+pub fn create_control_router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .merge(control_routes(state.config.server.control_path()))
+        .merge(visitor_routes())
+        .with_state(state)
+}
+
+/// What visitors reach besides the tunnels themselves: TCP tunnels over WebSocket
+fn visitor_routes() -> Router<Arc<ServerState>> {
+    Router::new().route(&format!("{}/:subdomain", CONNECT_PATH), get(connect_tcp))
+}
+
+/// The control routes on the public listeners, or 404s in their place when they're
+/// served on `server.control_port`
+fn public_control_routes(config: &Config) -> Router<Arc<ServerState>> {
+    let control_path = config.server.control_path();
+    if config.server.control_port.is_none() {
+        return control_routes(control_path);
+    }
+    let not_found = || async { StatusCode::NOT_FOUND };
+    Router::new()
+        .route(control_path, any(not_found))
+        .route("/_check/registration", any(not_found))
+        .route("/_admin/*path", any(not_found))
+}
+
+/// What tunnel clients and admins use: the control WebSocket, registration checks
+/// and the admin API
+fn control_routes(control_path: &str) -> Router<Arc<ServerState>> {
+    Router::new()
+        .route(control_path, any(handle_request))
+        .route("/_check/registration", post(check_registration))
         .route("/_admin/tunnels", get(list_tunnels))
-        .route("/_admin/tunnels/{subdomain}", delete(delete_tunnel))
+        .route("/_admin/tunnels/:subdomain", delete(delete_tunnel))
+        .route("/_admin/tokens", get(list_tokens).post(create_token))
+        .route("/_admin/tokens/:token", delete(revoke_token))
+        .route("/_admin/tokens/:token/usage", get(get_token_usage))
+        .route("/_admin/version", get(get_version))
+        .route("/_admin/health", get(get_health))
+        .route("/_admin/stats", get(get_stats))
+        .route("/_admin/logs", get(get_logs))
+        .route("/_admin/ownership", get(list_ownership))
+        .route("/_admin/ownership/:subdomain", delete(release_ownership))
+        .route("/_admin/certificates/prune", post(prune_certificates))
+        .route("/_admin/reservations", get(list_reservations))
+        .route("/_admin/reservations/:subdomain", put(reserve_subdomain).delete(release_reservation))
+}
+
+/// Where a tunnel's manifest is served on its own host. Never proxied, so the service
+/// behind a tunnel can't pass off a manifest of its own.
+pub const MANIFEST_PATH: &str = "/_loophole/manifest";
+
+/// Where Prometheus metrics are served: on the base domain, or on any host when
+/// `metrics.port` gives them their own listener
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Liveness for load balancers and orchestrators, served like metrics on any host that
+/// isn't a tunnel's, without authentication or logging
+pub const HEALTH_PATH: &str = "/_health";
+/// Readiness: 503 until the base domain certificate is in place when HTTPS is enabled
+pub const HEALTH_READY_PATH: &str = "/_health/ready";
+
+/// Create the router for the separate metrics listener (`metrics.port`)
+pub fn create_metrics_router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route(METRICS_PATH, get(get_metrics))
         .with_state(state)
 }
 
@@ -42,12 +211,10 @@ pub fn create_acme_router(
     challenge_store: Arc<ChallengeStore>,
     has_https: bool,
 ) -> Router {
-    let control_path = state.config.server.control_path();
     let router = Router::new()
-        .route(control_path, any(handle_request))
-        .route("/_admin/tunnels", get(list_tunnels))
-        .route("/_admin/tunnels/{subdomain}", delete(delete_tunnel));
-    
+        .merge(public_control_routes(&state.config))
+        .merge(visitor_routes());
+
     if has_https {
         // HTTPS mode: ACME challenges served directly, everything else redirected
         router
@@ -63,34 +230,76 @@ pub fn create_acme_router(
     }
 }
 
+/// Unknown-token challenge lookups allowed per IP per window before answering 429
+const ACME_PROBE_LIMIT: u32 = 20;
+const ACME_PROBE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+/// Log the first of every N unknown-token lookups so probing can't flood the logs
+const ACME_PROBE_LOG_SAMPLE: u64 = 100;
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Limiter for unknown-token ACME challenge lookups
+pub fn acme_probe_limiter() -> RateLimiter {
+    RateLimiter::new(ACME_PROBE_LIMIT, ACME_PROBE_WINDOW)
+}
+
 /// Try to handle an ACME HTTP-01 challenge request, returns None if not an ACME request
-fn try_handle_acme_challenge(path: &str, host: &str, challenge_store: &ChallengeStore) -> Option<Response> {
-    let token = path.strip_prefix("/.well-known/acme-challenge/")?;
+fn try_handle_acme_challenge(
+    path: &str,
+    host: &str,
+    client_ip: IpAddr,
+    challenge_store: &ChallengeStore,
+    limiter: &RateLimiter,
+) -> Option<Response> {
+    let token = path.strip_prefix(ACME_CHALLENGE_PREFIX)?;
     let start = std::time::Instant::now();
-    
-    let (status, response) = match challenge_store.get(token) {
-        Some(key_auth) => (StatusCode::OK, (StatusCode::OK, key_auth).into_response()),
-        None => (StatusCode::NOT_FOUND, (StatusCode::NOT_FOUND, "Challenge not found").into_response()),
+
+    // Only lookups for unknown tokens count towards the limit, so a validator
+    // fetching a pending challenge is never throttled
+    if limiter.is_limited(&client_ip) {
+        debug!(client_ip = %client_ip, "ACME challenge lookup rate limited");
+        return Some((StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response());
+    }
+
+    let Some(key_auth) = challenge_store.get(token) else {
+        limiter.check(&client_ip);
+        let unknown_hits = challenge_store.unknown_hits();
+        if unknown_hits % ACME_PROBE_LOG_SAMPLE == 1 {
+            warn!(
+                host = %host,
+                path = %path,
+                client_ip = %client_ip,
+                unknown_hits = unknown_hits,
+                "ACME challenge for unknown token (logging sampled)"
+            );
+        }
+        return Some((StatusCode::NOT_FOUND, "Challenge not found").into_response());
     };
-    
+
     let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
     info!(
         host = %host,
         path = %path,
-        status = %status.as_u16(),
+        status = 200,
         latency_ms = format!("{:.2}", latency_ms),
         "ACME challenge"
     );
-    
-    Some(response)
+
+    Some((StatusCode::OK, key_auth).into_response())
 }
 
 /// Redirect HTTP to HTTPS (but serve ACME challenges directly)
 async fn redirect_to_https(
     State(state): State<Arc<ServerState>>,
     Extension(challenge_store): Extension<Arc<ChallengeStore>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     req: Request<Body>,
 ) -> Response {
+    // Cloudflare or a trusted proxy already served this over HTTPS; redirecting would loop
+    if state.forwarded_https(addr.ip(), req.headers()) {
+        return handle_request(State(state), ConnectInfo(addr), req).await;
+    }
+
     let path = req.uri().path();
     let host = req
         .headers()
@@ -98,28 +307,44 @@ async fn redirect_to_https(
         .and_then(|h| h.to_str().ok())
         .unwrap_or("");
 
+    // Health checks from load balancers don't follow redirects
+    if (path == HEALTH_PATH || path == HEALTH_READY_PATH)
+        && extract_subdomain(host, &state.config.server.domain).is_none()
+    {
+        return serve_health(&state, path);
+    }
+
     // Handle ACME challenges directly - don't redirect these
-    if let Some(response) = try_handle_acme_challenge(path, host, &challenge_store) {
+    if let Some(response) = try_handle_acme_challenge(
+        path,
+        host,
+        state.client_ip(addr.ip(), req.headers()),
+        &challenge_store,
+        &state.acme_probe_limiter,
+    ) {
         return response;
     }
 
-    // Remove port from host if present
-    let host_without_port = host.split(':').next().unwrap_or(host);
-
     let path_and_query = req
         .uri()
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("/");
 
-    let https_port = state.config.server.https_port;
-    
-    // Build HTTPS URL
-    let https_url = if https_port == 443 {
-        format!("https://{}{}", host_without_port, path_and_query)
-    } else {
-        format!("https://{}:{}{}", host_without_port, https_port, path_and_query)
-    };
+    // Only installed when HTTPS is enabled, so the public scheme is https
+    let https_url = state.public_url.url(host, path_and_query);
+
+    // WebSocket clients don't follow redirects, and would fail without saying why
+    if is_websocket_upgrade(req.headers()) {
+        let wss_url = format!("wss://{}", https_url.split_once("://").map_or(https_url.as_str(), |(_, rest)| rest));
+        debug!("Refusing WebSocket upgrade over plain HTTP, pointing to {}", wss_url);
+        return (
+            StatusCode::UPGRADE_REQUIRED,
+            [(header::UPGRADE, "TLS/1.2, HTTP/1.1")],
+            format!("WebSocket connections need TLS here; connect to {}\n", wss_url),
+        )
+            .into_response();
+    }
 
     debug!("Redirecting to HTTPS: {}", https_url);
     Redirect::permanent(&https_url).into_response()
@@ -127,93 +352,231 @@ async fn redirect_to_https(
 
 async fn handle_request(
     State(state): State<Arc<ServerState>>,
-    ws: Option<WebSocketUpgrade>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    req: Request<Body>,
+    mut req: Request<Body>,
 ) -> Response {
     let start = std::time::Instant::now();
     let method = req.method().clone();
     let path = req.uri().path().to_string();
+    // Names are registered lowercase, and hostnames aren't case-sensitive
     let host = req
         .headers()
         .get("host")
         .and_then(|h| h.to_str().ok())
         .unwrap_or("")
-        .to_string();
+        .to_ascii_lowercase();
 
-    // Check if this is a WebSocket upgrade request to the control path
+    // Check if this is a WebSocket upgrade request to the control path. Other WebSocket
+    // requests are proxied to the tunnel, so the upgrade is only taken here.
     if path == state.config.server.control_path() {
-        if let Some(ws) = ws {
-            return handle_tunnel_connect(ws, state, addr).await;
-        } else {
-            return (StatusCode::BAD_REQUEST, "WebSocket upgrade required").into_response();
+        // Refuse before upgrading, so rejected clients cost no handshake
+        let client_ip = state.client_ip(addr.ip(), req.headers());
+        let guard = match state.admission.admit(client_ip, state.registry.count()) {
+            Ok(guard) => guard,
+            Err(rejection) => {
+                warn!(client_ip = %client_ip, reason = ?rejection, "Refused tunnel connection");
+                return rejection.into_response();
+            }
+        };
+
+        // Behind Cloudflare the tunnel keeps the client's own IP, not Cloudflare's
+        let client_addr = SocketAddr::new(client_ip, addr.port());
+        let (mut parts, _body) = req.into_parts();
+        match WebSocketUpgrade::from_request_parts(&mut parts, &state).await {
+            Ok(ws) => return handle_tunnel_connect(ws, state, client_addr, guard).await,
+            Err(_) => return (StatusCode::BAD_REQUEST, "WebSocket upgrade required").into_response(),
         }
     }
 
-    // Extract subdomain from Host header
-    let subdomain = match extract_subdomain(&host, &state.config.server.domain) {
-        Some(s) => s,
+    // Browsers get the server's own pages as HTML, anything else as plain text
+    let html = wants_html(req.headers());
+
+    // Extract subdomain from Host header, or for a path tunnel the name under /t/ on the
+    // base domain along with the path its service sees
+    let domain = &state.config.server.domain;
+    let (subdomain, service_path) = match extract_subdomain(&host, domain) {
+        Some(s) => (s, None),
+        None if path == METRICS_PATH && state.config.metrics.enabled && state.config.metrics.port.is_none() => {
+            return serve_metrics(&state, req.headers());
+        }
+        None if path == HEALTH_PATH || path == HEALTH_READY_PATH => return serve_health(&state, &path),
+        None => match path_tunnel::split(&path).filter(|_| host.split(':').next() == Some(domain.as_str())) {
+            Some((name, service_path)) => (name.to_string(), Some(service_path.to_string())),
+            None => {
+                let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+                info!(
+                    method = %method,
+                    host = %host,
+                    path = %path,
+                    status = 404,
+                    latency_ms = format!("{:.2}", latency_ms),
+                    "Request to unknown subdomain"
+                );
+                let vars = PageVars::new(StatusCode::NOT_FOUND, "Unknown subdomain", &host);
+                return state.pages.render(Page::NotFound, html, vars);
+            }
+        },
+    };
+
+    // Look up tunnel in registry. TCP tunnels are only reachable on their own port, and
+    // path tunnels only under /t/, where aliases don't lead.
+    let mode = if service_path.is_some() { TunnelMode::Path } else { TunnelMode::Subdomain };
+    let found = match mode {
+        TunnelMode::Subdomain => state.registry.resolve(&subdomain),
+        TunnelMode::Path => state.registry.get(&subdomain),
+    };
+    let tunnel = match found.filter(|t| t.protocol() == Protocol::Http && t.mode == mode) {
+        Some(t) => t,
         None => {
             let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
             info!(
                 method = %method,
                 host = %host,
                 path = %path,
+                subdomain = %subdomain,
                 status = 404,
                 latency_ms = format!("{:.2}", latency_ms),
-                "Request to unknown subdomain"
+                "Tunnel not found"
             );
-            return (StatusCode::NOT_FOUND, "Unknown subdomain").into_response();
+            let vars = PageVars::new(StatusCode::NOT_FOUND, "Tunnel not found", &host).with_subdomain(&subdomain);
+            return state.pages.render(Page::NotFound, html, vars);
         }
     };
+    // A request on an alias is served, counted and queued as one on the tunnel's subdomain
+    let subdomain = tunnel.subdomain.clone();
 
-    // Look up tunnel in registry
-    let tunnel = match state.registry.get(&subdomain) {
-        Some(t) => t,
-        None => {
-            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    if let Some(service_path) = &service_path {
+        let query = req.uri().query().map(|query| format!("?{}", query)).unwrap_or_default();
+        // Relative links resolve against the directory, so `/t/<name>` needs its slash
+        if service_path.is_empty() {
+            return Redirect::permanent(&format!("{}/{}", path, query)).into_response();
+        }
+        match format!("{}{}", service_path, query).parse() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid path").into_response(),
+        }
+    }
+
+    let client_ip = state.client_ip(addr.ip(), req.headers());
+    if !tunnel.admits_ip(client_ip) {
+        state.metrics.record_response(StatusCode::FORBIDDEN.as_u16());
+        info!(
+            method = %method,
+            host = %host,
+            path = %path,
+            subdomain = %subdomain,
+            client_ip = %client_ip,
+            status = 403,
+            "Visitor address not allowed"
+        );
+        let vars = PageVars::new(StatusCode::FORBIDDEN, "Forbidden", &host).with_subdomain(&subdomain);
+        return state.pages.render(Page::Forbidden, html, vars);
+    }
+    if let Some(auth) = &tunnel.basic_auth {
+        if !auth.admits(req.headers()) {
+            state.metrics.record_response(StatusCode::UNAUTHORIZED.as_u16());
             info!(
                 method = %method,
                 host = %host,
                 path = %path,
                 subdomain = %subdomain,
-                status = 404,
-                latency_ms = format!("{:.2}", latency_ms),
-                "Tunnel not found"
+                status = 401,
+                "Visitor failed basic auth"
             );
-            return (StatusCode::NOT_FOUND, "Tunnel not found").into_response();
+            return BasicAuth::challenge();
         }
-    };
+    }
+
+    if req.uri().path() == MANIFEST_PATH {
+        return serve_manifest(&state, &tunnel);
+    }
+
+    if let Some(until) = tunnel.paused_until() {
+        state.metrics.record_response(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+        info!(
+            method = %method,
+            host = %host,
+            path = %path,
+            subdomain = %subdomain,
+            status = 503,
+            "Tunnel paused for maintenance"
+        );
+        let vars = state.maintenance.page(until, &host).with_subdomain(&subdomain);
+        return state.pages.render(Page::Maintenance, html, vars);
+    }
 
-    // Determine if this is HTTPS based on whether ACME is configured
-    let is_https = state.config.https.is_some();
+    // Turned away before the fair queue, so a flood can't hold up other tunnels
+    if let Some(retry_after) = tunnel.throttle() {
+        state.metrics.record_response(StatusCode::TOO_MANY_REQUESTS.as_u16());
+        debug!(
+            method = %method,
+            host = %host,
+            path = %path,
+            subdomain = %subdomain,
+            status = 429,
+            "Tunnel over its request rate"
+        );
+        let vars = PageVars::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests", &host)
+            .with_subdomain(&subdomain)
+            .with_retry_after(retry_after);
+        return state.pages.render(Page::TooManyRequests, html, vars);
+    }
 
     // Proxy the request
-    let response = match proxy_request(tunnel, req, addr.ip(), is_https).await {
+    let mut options = ProxyOptions::new(&state.config, &state.public_url)
+        .with_response_headers(state.response_headers.rules())
+        .with_buffers(state.proxy_buffers.clone());
+    if let Some(token) = state.tokens.get(&tunnel.token) {
+        options = options.with_token_policy(&token);
+    }
+    options.is_https |= state.forwarded_https(addr.ip(), req.headers());
+    if mode == TunnelMode::Path {
+        options = options.with_path_rewrite(PathRewrite::new(&subdomain, domain));
+    }
+    // Held until the response headers arrive; the body streams outside the fair queue
+    let permit = state.scheduler.admit(&subdomain, state.tokens.weight_for(&tunnel.token)).await;
+    let epoch = tunnel.epoch();
+    let request_id = options.request_id.clone();
+    let response = proxy_request(tunnel, req, client_ip, options, state.registry.clone(), state.metrics.clone()).await;
+    drop(permit);
+    let response = match response {
         Ok(response) => response,
-        Err(e) => {
+        Err(failure) => {
+            state.metrics.record_response(failure.status().as_u16());
+            check_slow_request(&state, &method, &path, &subdomain, failure.status(), start.elapsed());
             let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
             info!(
                 method = %method,
                 host = %host,
                 path = %path,
                 subdomain = %subdomain,
-                status = 502,
+                request_id = %request_id,
+                epoch = epoch,
+                status = failure.status().as_u16(),
                 latency_ms = format!("{:.2}", latency_ms),
-                error = %e,
+                error_code = failure.code(),
                 "Proxy error"
             );
-            return (StatusCode::BAD_GATEWAY, "Proxy error").into_response();
+            let reason = failure.status().canonical_reason().unwrap_or("Proxy error");
+            let vars = PageVars::new(failure.status(), reason, &host)
+                .with_subdomain(&subdomain)
+                .with_request_id(&request_id);
+            let mut response = state.pages.render(Page::ProxyError, html, vars);
+            response.headers_mut().insert(ERROR_HEADER, HeaderValue::from_static(failure.code()));
+            return response;
         }
     };
 
     let status = response.status();
+    state.metrics.record_response(status.as_u16());
+    check_slow_request(&state, &method, &path, &subdomain, status, start.elapsed());
     let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
     info!(
         method = %method,
         host = %host,
         path = %path,
         subdomain = %subdomain,
+        epoch = epoch,
         status = %status.as_u16(),
         latency_ms = format!("{:.2}", latency_ms),
         "Proxied request"
@@ -222,7 +585,31 @@ async fn handle_request(
     response
 }
 
-fn extract_subdomain<'a>(host: &'a str, domain: &str) -> Option<String> {
+/// Warn about and count a proxied request whose response headers took longer than
+/// `logging.slow_request_threshold_ms`
+fn check_slow_request(
+    state: &ServerState,
+    method: &axum::http::Method,
+    path: &str,
+    subdomain: &str,
+    status: StatusCode,
+    latency: std::time::Duration,
+) {
+    if let Some(threshold) = state.slow_requests.exceeded(latency) {
+        state.metrics.record_slow_request(subdomain);
+        warn!(
+            method = %method,
+            path = %path,
+            subdomain = %subdomain,
+            status = status.as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "Slow request"
+        );
+    }
+}
+
+fn extract_subdomain(host: &str, domain: &str) -> Option<String> {
     // Remove port from host if present
     let host = host.split(':').next().unwrap_or(host);
     
@@ -253,30 +640,251 @@ fn extract_subdomain<'a>(host: &'a str, domain: &str) -> Option<String> {
 async fn handle_tunnel_connect(
     ws: WebSocketUpgrade,
     state: Arc<ServerState>,
-    addr: SocketAddr,
+    client_addr: SocketAddr,
+    guard: ConnectionGuard,
+) -> Response {
+    info!("New tunnel connection from {}", client_addr);
+
+    ws.max_message_size(MAX_WS_MESSAGE_SIZE)
+        .max_frame_size(MAX_WS_FRAME_SIZE)
+        .on_upgrade(move |socket| async move {
+            // Counts against the client's per-IP limit until the connection ends
+            let _guard = guard;
+            let metrics = state.metrics.clone();
+            metrics.record_control_connection_opened();
+            let opened = std::time::Instant::now();
+            if let Err(e) = super::handler::handle_websocket(socket, state, client_addr).await {
+                error!("WebSocket handler error: {}", e);
+            }
+            metrics.record_control_connection_closed(opened.elapsed());
+        })
+}
+
+/// A connector (`loophole connect`) reaching a TCP tunnel over a WebSocket, for
+/// visitors whose network only lets HTTP(S) out. It authenticates with the tunnel's
+/// token or share key, checked before upgrading.
+async fn connect_tcp(
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(subdomain): Path<String>,
+    headers: header::HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+) -> Response {
+    let client_ip = state.client_ip(addr.ip(), &headers);
+    // Unknown tunnels are refused like a wrong key, so connectors can't find out
+    // which TCP tunnels there are
+    let refused = || (StatusCode::UNAUTHORIZED, "Invalid token or share key, or no such TCP tunnel").into_response();
+    let Some(tunnel) = state.registry.get(&subdomain).filter(|t| t.protocol() == Protocol::Tcp) else {
+        debug!("Connector from {} asked for unknown TCP tunnel '{}'", client_ip, subdomain);
+        return refused();
+    };
+
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    // A revoked token's tunnels go too, but may not have been removed yet
+    let token_valid = state.tokens.get(&tunnel.token).is_some();
+    if !key.is_some_and(|key| token_valid && tunnel.admits_connector(key)) {
+        warn!("Refused connector from {} for tunnel {}: invalid key", client_ip, subdomain);
+        return refused();
+    }
+
+    let Some(ws) = ws else {
+        return (StatusCode::BAD_REQUEST, "WebSocket upgrade required").into_response();
+    };
+    info!("Connector from {} for tunnel {}", client_ip, subdomain);
+    let peer = SocketAddr::new(client_ip, addr.port());
+    ws.max_message_size(MAX_WS_MESSAGE_SIZE)
+        .max_frame_size(MAX_WS_FRAME_SIZE)
+        .on_upgrade(move |socket| {
+            tcp::splice(tunnel, tokio_util::compat::FuturesAsyncReadCompatExt::compat(ServerCompat::new(socket)), peer)
+        })
+}
+
+#[derive(Deserialize)]
+struct RegistrationCheck {
+    subdomain: String,
+}
+
+/// Whether a registration would succeed, and if not, what its client would be told
+#[derive(Serialize)]
+struct RegistrationVerdict {
+    subdomain: String,
+    available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// A dry run of registering an HTTP tunnel, for CI to check a subdomain is free
+/// before starting one (`loophole check`). It authenticates with the tunnel's token
+/// and runs the registration checks without taking the name.
+async fn check_registration(
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
 ) -> Response {
-    info!("New tunnel connection from {}", addr);
+    let client_ip = state.client_ip(addr.ip(), req.headers());
+    if let Err(rejection) = state.admission.admit_attempt(client_ip) {
+        warn!(client_ip = %client_ip, reason = ?rejection, "Refused registration check");
+        return rejection.into_response();
+    }
+    let Some(token) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(TokenSecret::new)
+    else {
+        return (StatusCode::UNAUTHORIZED, Json(AdminError { error: "Authorization header required".to_string() })).into_response();
+    };
+
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(AdminError { error })).into_response();
+    let body = match axum::body::to_bytes(req.into_body(), 64 * 1024).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(format!("Failed to read request body: {}", e)),
+    };
+    let check = match serde_json::from_slice::<RegistrationCheck>(&body) {
+        Ok(check) => check,
+        Err(e) => return bad_request(format!("Invalid registration check: {}", e)),
+    };
 
-    ws.on_upgrade(move |socket| async move {
-        if let Err(e) = super::handler::handle_websocket(socket, state, addr).await {
-            error!("WebSocket handler error: {}", e);
+    let verdict = match super::handler::check_registration(&state, &token, &check.subdomain) {
+        Ok(()) => RegistrationVerdict {
+            url: Some(state.public_url.tunnel_url(&check.subdomain)),
+            subdomain: check.subdomain,
+            available: true,
+            code: None,
+            message: None,
+        },
+        Err(refusal) => {
+            if refusal.code == ErrorCode::InvalidToken {
+                state.admission.record_failed_auth(client_ip);
+            }
+            debug!("Registration check for '{}' from {}: {}", check.subdomain, client_ip, refusal.message);
+            RegistrationVerdict {
+                subdomain: check.subdomain,
+                available: false,
+                url: None,
+                code: Some(refusal.code),
+                message: Some(refusal.message),
+            }
         }
-    })
+    };
+    Json(verdict).into_response()
 }
 
 // Admin endpoint types
 #[derive(Serialize)]
 struct TunnelInfo {
-    subdomain: String,
+    subdomain: Subdomain,
+    protocol: Protocol,
+    /// The server port a TCP tunnel listens on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_port: Option<u16>,
+    client_ip: IpAddr,
+    /// Absent for clients too old to send it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_version: Option<String>,
+    /// Unix seconds
+    connected_at: u64,
     created_at_secs: u64,
     request_count: u64,
     idle_secs: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// The client's own maintenance window, e.g. "0 2 * * * for 30m"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pause_schedule: Option<String>,
+    /// Unix seconds the maintenance the tunnel is paused for ends; absent when it isn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused_until: Option<u64>,
+    /// Absent when the tunnel's requests aren't limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_requests_per_second: Option<u32>,
+    /// Requests refused with 429 for going over max_requests_per_second
+    requests_throttled: u64,
+    /// Smoothed round trip to the client, in milliseconds; absent until measured, and
+    /// for clients that don't answer echo streams
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_ms: Option<f64>,
+    /// Round-trip probes sent to the client; absent, like the one below, if none have been
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_probes_sent: Option<u64>,
+    /// Probes the client didn't echo in time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rtt_probes_lost: Option<u64>,
+    /// Whether visitors must log in with the client's `--basic-auth` credentials
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    basic_auth: bool,
+    /// Networks visitors must come from, from `expose --allow-ip`; absent when anyone may
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    allow_ips: Vec<String>,
+    /// `path` for tunnels reached under `/t/<subdomain>/` on the base domain; absent
+    /// for ones on their own subdomain
+    #[serde(skip_serializing_if = "TunnelMode::is_subdomain")]
+    mode: TunnelMode,
+    /// Other subdomains visitors reach the tunnel on, its client's and the server's
+    /// configured ones; absent when there are none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<Subdomain>,
 }
 
 #[derive(Serialize)]
 struct TunnelListResponse {
     tunnels: Vec<TunnelInfo>,
     count: usize,
+    /// The registry generation listed, to pass as `since` to wait for the next change
+    generation: u64,
+}
+
+/// Tunnel listing fields that change with time alone, left out of its ETag
+const TUNNEL_CLOCK_FIELDS: &[&str] = &["created_at_secs", "idle_secs", "rtt_ms", "rtt_probes_sent", "rtt_probes_lost"];
+
+/// How long a tunnel listing with `since` waits for a tunnel to register or deregister
+pub const LONG_POLL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct TunnelListQuery {
+    since: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct OwnershipInfo {
+    domain: FullDomain,
+    #[serde(flatten)]
+    owner: Ownership,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    ready: bool,
+    base_certificate: BaseCertState,
+}
+
+#[derive(Serialize)]
+struct PublicHealthResponse {
+    ready: bool,
+    uptime_secs: u64,
+    tunnels: usize,
+    has_base_certificate: bool,
+    /// False once DNS monitoring has found the domain no longer points here
+    dns_ok: bool,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    tunnels: usize,
+    #[serde(flatten)]
+    control: ControlStats,
+    /// Only when DNS is monitored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<DnsReport>,
 }
 
 #[derive(Serialize)]
@@ -285,7 +893,8 @@ struct AdminError {
 }
 
 /// Validate admin authorization header
-fn validate_admin_auth(req: &Request<Body>, config: &Config) -> Result<(), Response> {
+#[allow(clippy::result_large_err)]
+fn validate_admin_auth(req: &Request<Body>, tokens: &TokenStore) -> Result<(), Response> {
     let auth_header = req
         .headers()
         .get(header::AUTHORIZATION)
@@ -299,32 +908,67 @@ fn validate_admin_auth(req: &Request<Body>, config: &Config) -> Result<(), Respo
         (StatusCode::UNAUTHORIZED, Json(AdminError { error: "Invalid authorization format".to_string() })).into_response()
     })?;
     
-    if !config.validate_admin_token(token) {
+    if !tokens.is_admin(token) {
         return Err((StatusCode::UNAUTHORIZED, Json(AdminError { error: "Invalid or non-admin token".to_string() })).into_response());
     }
     
     Ok(())
 }
 
-/// List all active tunnels
+/// List all active tunnels. With `?since=<generation>`, waits up to `LONG_POLL` for
+/// the registry to move past that generation first.
 async fn list_tunnels(
     State(state): State<Arc<ServerState>>,
+    query: Result<Query<TunnelListQuery>, QueryRejection>,
     req: Request<Body>,
 ) -> Response {
-    if let Err(resp) = validate_admin_auth(&req, &state.config) {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
         return resp;
     }
-    
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(e) => {
+            let error = format!("Invalid query: {}", e.body_text());
+            return (StatusCode::BAD_REQUEST, Json(AdminError { error })).into_response();
+        }
+    };
+
+    // Read before listing, so a change made while listing shows up in the next poll
+    let generation = match query.since {
+        Some(since) => state.registry.changed_since(since, LONG_POLL).await,
+        None => state.registry.generation(),
+    };
+
     let subdomains = state.registry.subdomains();
     let mut tunnels = Vec::with_capacity(subdomains.len());
     
     for subdomain in subdomains {
         if let Some(tunnel) = state.registry.get(&subdomain) {
+            let rtt = tunnel.rtt.stats();
+            let probed = rtt.probes_sent > 0;
             tunnels.push(TunnelInfo {
                 subdomain: tunnel.subdomain.clone(),
+                protocol: tunnel.protocol(),
+                tcp_port: tunnel.tcp_port,
+                client_ip: tunnel.client_addr.ip(),
+                client_version: tunnel.client_info.client_version.clone(),
+                connected_at: tunnel.connected_at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
                 created_at_secs: tunnel.created_at.elapsed().as_secs(),
                 request_count: tunnel.request_count.load(std::sync::atomic::Ordering::Relaxed),
-                idle_secs: tunnel.last_activity().elapsed().as_secs(),
+                idle_secs: tunnel.idle_for().as_secs(),
+                bytes_in: tunnel.bytes_in.load(std::sync::atomic::Ordering::Relaxed),
+                bytes_out: tunnel.bytes_out.load(std::sync::atomic::Ordering::Relaxed),
+                pause_schedule: tunnel.pause_schedule.as_ref().map(ToString::to_string),
+                paused_until: tunnel.paused_until().and_then(|until| until.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+                max_requests_per_second: (tunnel.max_requests_per_second > 0).then_some(tunnel.max_requests_per_second),
+                requests_throttled: tunnel.requests_throttled.load(std::sync::atomic::Ordering::Relaxed),
+                rtt_ms: rtt.smoothed.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                rtt_probes_sent: probed.then_some(rtt.probes_sent),
+                rtt_probes_lost: probed.then_some(rtt.probes_lost),
+                basic_auth: tunnel.basic_auth.is_some(),
+                allow_ips: tunnel.allow_ips.iter().map(ToString::to_string).collect(),
+                mode: tunnel.mode,
+                aliases: state.registry.aliases_of(&tunnel),
             });
         }
     }
@@ -332,54 +976,1498 @@ async fn list_tunnels(
     let count = tunnels.len();
     info!("Admin: listed {} tunnels", count);
     
-    Json(TunnelListResponse { tunnels, count }).into_response()
+    admin_json::respond_ignoring(req.headers(), &TunnelListResponse { tunnels, count, generation }, TUNNEL_CLOCK_FIELDS)
 }
 
-/// Force disconnect a tunnel
-async fn delete_tunnel(
+/// Prometheus metrics, behind `metrics.token` when one is set
+async fn get_metrics(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    serve_metrics(&state, req.headers())
+}
+
+fn serve_metrics(state: &ServerState, headers: &header::HeaderMap) -> Response {
+    if let Some(ref token) = state.config.metrics.token {
+        let authorized = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes()));
+        if !authorized {
+            return (StatusCode::UNAUTHORIZED, "Metrics token required").into_response();
+        }
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(&state.registry),
+    )
+        .into_response()
+}
+
+/// Discoverable, public facts about a tunnel, for tooling that consumes it
+#[derive(Serialize)]
+struct Manifest<'a> {
+    subdomain: &'a str,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_version: Option<&'a str>,
+    uptime_secs: u64,
+    /// Whether visitors need the tunnel's `--basic-auth` credentials
+    basic_auth: bool,
+    /// The client's own maintenance window, e.g. "0 2 * * * for 30m"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pause_schedule: Option<String>,
+    /// Unix seconds the maintenance the tunnel is paused for ends; absent when it isn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused_until: Option<u64>,
+}
+
+/// The tunnel's manifest, if its client asked for it to be published. Nothing in it
+/// is secret: the token, client address and the like stay out.
+fn serve_manifest(state: &ServerState, tunnel: &Tunnel) -> Response {
+    let info = &tunnel.client_info;
+    if !info.publish_manifest {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    let manifest = Manifest {
+        subdomain: &tunnel.subdomain,
+        url: state.public_url.http_tunnel_url(&tunnel.subdomain, tunnel.mode),
+        service_name: info.service_name.as_deref(),
+        service_version: info.service_version.as_deref(),
+        uptime_secs: tunnel.created_at.elapsed().as_secs(),
+        basic_auth: tunnel.basic_auth.is_some(),
+        pause_schedule: tunnel.pause_schedule.as_ref().map(ToString::to_string),
+        paused_until: tunnel.paused_until().and_then(|until| until.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+    };
+    ([(header::CACHE_CONTROL, "no-store")], Json(manifest)).into_response()
+}
+
+/// Report the server's build metadata
+async fn get_version(
     State(state): State<Arc<ServerState>>,
-    Path(subdomain): Path<String>,
     req: Request<Body>,
 ) -> Response {
-    if let Err(resp) = validate_admin_auth(&req, &state.config) {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
         return resp;
     }
-    
-    if state.registry.get(&subdomain).is_none() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(AdminError { error: format!("Tunnel '{}' not found", subdomain) }),
-        ).into_response();
+
+    Json(BuildInfo::current()).into_response()
+}
+
+/// Readiness: 503 until the base domain certificate is in place when HTTPS is enabled
+async fn get_health(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
     }
-    
-    state.registry.deregister(&subdomain);
-    info!("Admin: force disconnected tunnel '{}'", subdomain);
-    
-    StatusCode::NO_CONTENT.into_response()
+
+    let base_certificate = state
+        .cert_manager
+        .as_ref()
+        .map(|cm| cm.base_cert_state())
+        .unwrap_or(BaseCertState::Disabled);
+    let dns_ok = !state.dns.is_broken();
+    let ready = matches!(base_certificate, BaseCertState::Ready | BaseCertState::Disabled) && dns_ok;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(HealthResponse { ready, base_certificate })).into_response()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `/_health` always answers 200 while the server runs; `/_health/ready` answers 503
+/// until HTTPS works, as the admin health endpoint does
+fn serve_health(state: &ServerState, path: &str) -> Response {
+    let base_certificate = state
+        .cert_manager
+        .as_ref()
+        .map(|cm| cm.base_cert_state())
+        .unwrap_or(BaseCertState::Disabled);
+    let dns_ok = !state.dns.is_broken();
+    let ready = matches!(base_certificate, BaseCertState::Ready | BaseCertState::Disabled) && dns_ok;
+    let status = if ready || path == HEALTH_PATH {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let health = PublicHealthResponse {
+        ready,
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        tunnels: state.registry.count(),
+        has_base_certificate: base_certificate == BaseCertState::Ready,
+        dns_ok,
+        version: env!("CARGO_PKG_VERSION"),
+    };
+    (status, Json(health)).into_response()
+}
 
-    #[test]
-    fn test_extract_subdomain() {
-        assert_eq!(
-            extract_subdomain("myapp.localhost", "localhost"),
-            Some("myapp".to_string())
-        );
-        assert_eq!(
-            extract_subdomain("myapp.localhost:8080", "localhost"),
-            Some("myapp".to_string())
-        );
-        assert_eq!(
-            extract_subdomain("myapp.tunnel.example.com", "tunnel.example.com"),
-            Some("myapp".to_string())
-        );
-        assert_eq!(extract_subdomain("localhost", "localhost"), None);
-        assert_eq!(
-            extract_subdomain("tunnel.example.com", "tunnel.example.com"),
-            None
-        );
+/// Summarise control connection churn, for spotting reconnect storms
+async fn get_stats(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let stats = StatsResponse {
+        tunnels: state.registry.count(),
+        control: state.metrics.control_stats(),
+        dns: state.dns.report(),
+    };
+    admin_json::respond(req.headers(), &stats)
+}
+
+/// What `GET /_admin/logs` accepts
+#[derive(Debug, Default, Deserialize)]
+struct LogsQuery {
+    /// The least severe level to include, `info` if not given
+    level: Option<String>,
+    /// Keep the response open, sending each new event as it's logged
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Recent log events as NDJSON, oldest first, then with `follow` every new one until
+/// the client goes away or the server shuts down
+async fn get_logs(
+    State(state): State<Arc<ServerState>>,
+    query: Result<Query<LogsQuery>, QueryRejection>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(AdminError { error })).into_response();
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(e) => return bad_request(format!("Invalid query: {}", e.body_text())),
+    };
+    let level = match query.level.as_deref().map(str::parse::<tracing::Level>) {
+        None => tracing::Level::INFO,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => return bad_request("level must be one of error, warn, info, debug or trace".to_string()),
+    };
+
+    let line = |event: &LogEvent| {
+        let mut line = serde_json::to_vec(event).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, std::convert::Infallible>(bytes::Bytes::from(line))
+    };
+    let headers = [(header::CONTENT_TYPE, "application/x-ndjson")];
+    if !query.follow {
+        let lines: Vec<_> = state.logs.recent(level).iter().map(line).collect();
+        return (headers, Body::from_stream(futures::stream::iter(lines))).into_response();
     }
+
+    let (recent, rx) = state.logs.follow(level);
+    let recent = futures::stream::iter(recent.iter().map(line).collect::<Vec<_>>());
+    let shutdown_rx = state.shutdown_tx.subscribe();
+    let live = futures::stream::unfold((rx, shutdown_rx), move |(mut rx, mut shutdown_rx)| async move {
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = shutdown_rx.recv() => return None,
+            };
+            let event = match event {
+                Ok(event) if event.at_least(level) => event,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(count)) => LogEvent::dropped(count),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((line(&event), (rx, shutdown_rx)));
+        }
+    });
+    (headers, Body::from_stream(futures::StreamExt::chain(recent, live))).into_response()
 }
+
+/// List subdomain ownership records
+async fn list_ownership(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let owners: Vec<OwnershipInfo> = state
+        .cert_manager
+        .as_ref()
+        .map(|cm| cm.owners())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(domain, owner)| OwnershipInfo { domain, owner })
+        .collect();
+
+    admin_json::respond(req.headers(), &owners)
+}
+
+/// Release a subdomain so any token can claim it
+async fn release_ownership(
+    State(state): State<Arc<ServerState>>,
+    Path(subdomain): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let subdomain = match Subdomain::new(&subdomain.to_ascii_lowercase()) {
+        Ok(subdomain) => subdomain,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(AdminError { error: e.to_string() })).into_response(),
+    };
+    let domain = subdomain.under(&state.config.server.domain);
+    let released = match state.cert_manager.as_ref() {
+        Some(cm) => cm.release(&domain).await,
+        None => Ok(false),
+    };
+
+    match released {
+        Ok(true) => {
+            info!("Admin: released ownership of {}", domain);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(AdminError { error: format!("No owner recorded for '{}'", subdomain) }),
+        ).into_response(),
+        Err(e) => {
+            error!("Failed to release ownership of {}: {}", domain, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AdminError { error: "Failed to release ownership".to_string() }),
+            ).into_response()
+        }
+    }
+}
+
+/// What `POST /_admin/certificates/prune` accepts
+#[derive(Debug, Default, Deserialize)]
+struct PruneQuery {
+    /// Only list the certificates that would be pruned
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct PruneResponse {
+    dry_run: bool,
+    /// `https.prune_unused_after_days`
+    unused_for_days: u64,
+    /// Pruned, or that would be
+    certificates: Vec<Unused>,
+}
+
+/// Prune the certificates unused for `https.prune_unused_after_days` now, rather than
+/// at the next daily pass, or list them with `?dry_run=true`
+async fn prune_certificates(
+    State(state): State<Arc<ServerState>>,
+    query: Result<Query<PruneQuery>, QueryRejection>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let error = |status: StatusCode, error: String| (status, Json(AdminError { error })).into_response();
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid query: {}", e.body_text())),
+    };
+    let (Some(cert_manager), Some(https)) = (&state.cert_manager, &state.config.https) else {
+        return error(StatusCode::NOT_FOUND, "HTTPS isn't configured".to_string());
+    };
+    let days = https.prune_unused_after_days;
+    if days == 0 {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "Pruning is off; set https.prune_unused_after_days to turn it on".to_string(),
+        );
+    }
+
+    match cert_prune::prune(&state, cert_manager, days, query.dry_run).await {
+        Ok(certificates) => {
+            if !query.dry_run {
+                info!("Admin: pruned {} unused certificate(s)", certificates.len());
+            }
+            admin_json::respond(
+                req.headers(),
+                &PruneResponse { dry_run: query.dry_run, unused_for_days: days, certificates },
+            )
+        }
+        Err(e) => {
+            error!("Failed to prune unused certificates: {:#}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to list certificates".to_string())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReservationInfo {
+    subdomain: String,
+    /// The id of the token it's reserved for
+    owner: TokenId,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NewReservation {
+    /// The token to reserve for, or its id; the caller's token when left out
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CreatedReservation {
+    #[serde(flatten)]
+    reservation: ReservationInfo,
+    /// False when the reservation only lasts until the server restarts
+    saved: bool,
+}
+
+/// List subdomains reserved for a token
+async fn list_reservations(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let reservations: Vec<ReservationInfo> = state
+        .registry
+        .reservations()
+        .list()
+        .into_iter()
+        .map(|(subdomain, owner)| ReservationInfo { subdomain, owner: TokenId::from(owner) })
+        .collect();
+    admin_json::respond(req.headers(), &reservations)
+}
+
+/// Reserve a subdomain for a token, so no other token can register it even while
+/// the owner has no tunnel connected
+async fn reserve_subdomain(
+    State(state): State<Arc<ServerState>>,
+    Path(subdomain): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let error = |status: StatusCode, error: String| (status, Json(AdminError { error })).into_response();
+    let caller = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string();
+    let body = match axum::body::to_bytes(req.into_body(), 64 * 1024).await {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("Failed to read request body: {}", e)),
+    };
+    let new_reservation = if body.is_empty() {
+        NewReservation::default()
+    } else {
+        match serde_json::from_slice::<NewReservation>(&body) {
+            Ok(new_reservation) => new_reservation,
+            Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid reservation: {}", e)),
+        }
+    };
+
+    let subdomain = subdomain.to_ascii_lowercase();
+    if let Err(e) = Registry::validate_subdomain(&subdomain) {
+        return error(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    if state.registry.is_reserved(&subdomain) {
+        return error(StatusCode::BAD_REQUEST, format!("'{}' is reserved for the server", subdomain));
+    }
+
+    let token = new_reservation.token.unwrap_or(caller);
+    let owner = state
+        .tokens
+        .list()
+        .into_iter()
+        .map(|(known, _)| known.id())
+        .find(|id| *id == token || *id == TokenId::of(&token));
+    let Some(owner) = owner else {
+        return error(StatusCode::NOT_FOUND, "Token not found".to_string());
+    };
+    if let Some(tunnel) = state.registry.get(&subdomain) {
+        if tunnel.token.id() != owner {
+            return error(
+                StatusCode::CONFLICT,
+                format!("'{}' is connected with another token; disconnect it first", subdomain),
+            );
+        }
+    }
+
+    let saved = state.registry.reservations().reserve(&subdomain, &owner);
+    info!("Admin: reserved {} for token {}", subdomain, owner);
+    Json(CreatedReservation { reservation: ReservationInfo { subdomain, owner }, saved }).into_response()
+}
+
+/// Drop a subdomain's reservation, so any token can register it again
+async fn release_reservation(
+    State(state): State<Arc<ServerState>>,
+    Path(subdomain): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    match state.registry.reservations().release(&subdomain) {
+        Some(_) => {
+            info!("Admin: released reservation of {}", subdomain);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(AdminError { error: format!("'{}' isn't reserved", subdomain) }),
+        ).into_response(),
+    }
+}
+
+/// Force disconnect a tunnel
+async fn delete_tunnel(
+    State(state): State<Arc<ServerState>>,
+    Path(subdomain): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+    
+    let Some(tunnel) = state.registry.get(&subdomain) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(AdminError { error: format!("Tunnel '{}' not found", subdomain) }),
+        ).into_response();
+    };
+    
+    state.registry.disconnect(&tunnel, "Disconnected by an admin");
+    info!("Admin: force disconnected tunnel '{}'", subdomain);
+    
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Serialize)]
+struct TokenInfo {
+    token: String,
+    /// As in ownership records
+    id: TokenId,
+    #[serde(flatten)]
+    config: TokenConfig,
+    /// Tunnels connected with the token now
+    tunnels: usize,
+}
+
+#[derive(Serialize)]
+struct TokenListResponse {
+    tokens: Vec<TokenInfo>,
+    count: usize,
+}
+
+/// What `POST /_admin/tokens` accepts; every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewToken {
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    keep_alive: bool,
+    max_tunnels: Option<usize>,
+    max_requests_per_second: Option<u32>,
+    weight: Option<u32>,
+    allowed_subdomains: Option<Vec<String>>,
+    #[serde(default)]
+    strip_request_headers: Vec<String>,
+    allowed_methods: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    token: String,
+    #[serde(flatten)]
+    config: TokenConfig,
+    /// False when the token only lasts until the server restarts
+    saved: bool,
+}
+
+#[derive(Serialize)]
+struct RevokedToken {
+    tunnels_disconnected: usize,
+    /// Subdomains that were reserved for the token, and no longer are
+    reservations_released: usize,
+    saved: bool,
+}
+
+/// List the tokens the server accepts, with how many tunnels each has connected
+async fn list_tokens(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let tokens: Vec<TokenInfo> = state
+        .tokens
+        .list()
+        .into_iter()
+        .map(|(token, config)| TokenInfo {
+            id: token.id(),
+            tunnels: state.registry.count_for_token(&token),
+            token: token.expose().to_string(),
+            config,
+        })
+        .collect();
+    let count = tokens.len();
+    admin_json::respond(req.headers(), &TokenListResponse { tokens, count })
+}
+
+/// Create a random token, usable immediately
+async fn create_token(
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(AdminError { error })).into_response();
+    let body = match axum::body::to_bytes(req.into_body(), 64 * 1024).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(format!("Failed to read request body: {}", e)),
+    };
+    let new_token = if body.is_empty() {
+        NewToken::default()
+    } else {
+        match serde_json::from_slice::<NewToken>(&body) {
+            Ok(new_token) => new_token,
+            Err(e) => return bad_request(format!("Invalid token settings: {}", e)),
+        }
+    };
+    if new_token.weight == Some(0) {
+        return bad_request("weight must be at least 1".to_string());
+    }
+
+    let config = TokenConfig {
+        admin: new_token.admin,
+        keep_alive: new_token.keep_alive,
+        max_tunnels: new_token.max_tunnels,
+        max_requests_per_second: new_token.max_requests_per_second,
+        weight: new_token.weight,
+        allowed_subdomains: new_token.allowed_subdomains,
+        strip_request_headers: new_token.strip_request_headers,
+        allowed_methods: new_token.allowed_methods,
+    };
+    if let Err(e) = config.check_allowed_subdomains().and_then(|_| config.check_request_policy()) {
+        return bad_request(e);
+    }
+    let (token, saved) = state.tokens.create(config.clone());
+    info!("Admin: created token {}{}", token.id(), if config.admin { " (admin)" } else { "" });
+
+    (StatusCode::CREATED, Json(CreatedToken { token: token.expose().to_string(), config, saved })).into_response()
+}
+
+/// Revoke a token: refuse it from now on and disconnect its tunnels
+async fn revoke_token(
+    State(state): State<Arc<ServerState>>,
+    Path(token): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let token = TokenSecret::from(token);
+    let saved = match state.tokens.revoke(&token) {
+        Ok(saved) => saved,
+        Err(e) => {
+            let status = match e {
+                TokenError::NotFound => StatusCode::NOT_FOUND,
+                TokenError::LastAdmin => StatusCode::CONFLICT,
+            };
+            return (status, Json(AdminError { error: e.to_string() })).into_response();
+        }
+    };
+
+    let tunnels = state.registry.tunnels_for_token(&token);
+    for tunnel in &tunnels {
+        state.registry.disconnect(tunnel, "The tunnel's token was revoked");
+    }
+    let reservations_released = state.registry.reservations().release_owner(&token.id());
+    info!(
+        "Admin: revoked token {}, disconnecting {} tunnel(s) and releasing {} reservation(s)",
+        token.id(),
+        tunnels.len(),
+        reservations_released
+    );
+
+    Json(RevokedToken { tunnels_disconnected: tunnels.len(), reservations_released, saved }).into_response()
+}
+
+/// What `GET /_admin/tokens/{id}/usage` accepts: unix seconds, from the start of
+/// retention until now by default
+#[derive(Debug, Deserialize)]
+struct UsageRange {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UsageTotals {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    tunnel_hours: f64,
+}
+
+impl From<&Bucket> for UsageTotals {
+    fn from(bucket: &Bucket) -> Self {
+        Self {
+            requests: bucket.requests,
+            bytes_in: bucket.bytes_in,
+            bytes_out: bucket.bytes_out,
+            tunnel_hours: bucket.tunnel_secs as f64 / 3600.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UsageHour {
+    /// Start of the hour, unix seconds
+    start: u64,
+    #[serde(flatten)]
+    usage: UsageTotals,
+}
+
+#[derive(Serialize)]
+struct UsageResponse {
+    id: TokenId,
+    from: u64,
+    to: u64,
+    /// Hours with any usage, oldest first
+    buckets: Vec<UsageHour>,
+    total: UsageTotals,
+}
+
+/// A token's usage by the hour. Takes the token or its id; the id is the only way to
+/// ask about a revoked token, whose usage is kept until it expires.
+async fn get_token_usage(
+    State(state): State<Arc<ServerState>>,
+    Path(token): Path<String>,
+    range: Result<Query<UsageRange>, QueryRejection>,
+    req: Request<Body>,
+) -> Response {
+    if let Err(resp) = validate_admin_auth(&req, &state.tokens) {
+        return resp;
+    }
+
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(AdminError { error })).into_response();
+    let Query(range) = match range {
+        Ok(range) => range,
+        Err(e) => return bad_request(format!("Invalid range: {}", e.body_text())),
+    };
+    let now = now_secs();
+    let to = range.to.unwrap_or(now);
+    let from = range.from.unwrap_or_else(|| state.usage.retained_from(now));
+    if from > to {
+        return bad_request("from must not be after to".to_string());
+    }
+
+    let id = state
+        .tokens
+        .list()
+        .into_iter()
+        .map(|(known, _)| known.id())
+        .find(|id| *id == token || *id == TokenId::of(&token))
+        .or_else(|| state.usage.has(&token).then(|| TokenId::from(token.clone())));
+    let Some(id) = id else {
+        return (StatusCode::NOT_FOUND, Json(AdminError { error: "Token not found".to_string() })).into_response();
+    };
+
+    // Include what connected tunnels have done since the last rollup
+    state.usage.roll_up(&state.registry.tunnels(), now);
+    let hours = state.usage.query(&id, from, to);
+    let mut total = Bucket::default();
+    for (_, bucket) in &hours {
+        total.add(bucket);
+    }
+    let buckets = hours
+        .iter()
+        .map(|(start, bucket)| UsageHour { start: *start, usage: bucket.into() })
+        .collect();
+
+    admin_json::respond(req.headers(), &UsageResponse { id, from, to, buckets, total: (&total).into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::cert_store::{CertStore, FsCertStore};
+    use axum::extract::connect_info::MockConnectInfo;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<ServerState> {
+        Arc::new(test_state_with(|_| {}))
+    }
+
+    /// State for the test config after `configure` has changed it. Tests that need more
+    /// than config (a cert manager, say) set it with struct update syntax
+    fn test_state_with(configure: impl FnOnce(&mut Config)) -> ServerState {
+        let mut config = Config::parse(
+            r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens.tk_admin]
+admin = true
+"#,
+        )
+        .unwrap();
+        configure(&mut config);
+        let metrics = Arc::new(Metrics::new());
+        ServerState {
+            admission: Arc::new(Admission::new(&config.limits)),
+            scheduler: Arc::new(FairScheduler::new(config.limits.fair_queue_threshold, metrics.clone())),
+            public_url: PublicUrlBuilder::from_config(&config),
+            maintenance: Arc::new(Maintenance::new(&config.maintenance).unwrap()),
+            response_headers: Arc::new(ResponseHeaders::default()),
+            started_at: std::time::Instant::now(),
+            motd: Arc::default(),
+            dns: Arc::default(),
+            pages: Arc::default(),
+            proxy_buffers: Arc::default(),
+            logs: Arc::default(),
+            tokens: Arc::new(TokenStore::new(&config)),
+            config: Arc::new(config),
+            registry: Arc::new(Registry::default()),
+            cert_manager: None,
+            acme_probe_limiter: acme_probe_limiter(),
+            metrics,
+            cloudflare: None,
+            shutdown_tx: broadcast::channel(1).0,
+            slow_requests: Arc::new(SlowRequests::default()),
+            churn: Arc::new(Churn::new(0)),
+            usage: Arc::new(Usage::new(90)),
+            tcp_ports: None,
+        }
+    }
+
+    async fn acme_get(router: &Router, from: &str, token: &str) -> Response {
+        let addr: SocketAddr = format!("{}:40000", from).parse().unwrap();
+        router
+            .clone()
+            .layer(MockConnectInfo(addr))
+            .oneshot(
+                Request::get(format!("/.well-known/acme-challenge/{}", token))
+                    .header("host", "myapp.tunnel.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_acme_probing_is_throttled() {
+        let challenge_store = Arc::new(ChallengeStore::new());
+        challenge_store.set("real-token", "real-token.key-auth");
+        let router = create_acme_router(test_state(), challenge_store.clone(), true);
+
+        for i in 0..ACME_PROBE_LIMIT {
+            let response = acme_get(&router, "192.0.2.10", &format!("guess-{}", i)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let response = acme_get(&router, "192.0.2.10", "guess-more").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(challenge_store.unknown_hits(), ACME_PROBE_LIMIT as u64);
+
+        // The validator (a different IP) still gets the pending challenge
+        let response = acme_get(&router, "198.51.100.7", "real-token").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"real-token.key-auth");
+    }
+
+    #[tokio::test]
+    async fn test_acme_valid_lookups_not_throttled() {
+        let challenge_store = Arc::new(ChallengeStore::new());
+        challenge_store.set("real-token", "real-token.key-auth");
+        let router = create_acme_router(test_state(), challenge_store, true);
+
+        for _ in 0..(ACME_PROBE_LIMIT * 2) {
+            let response = acme_get(&router, "198.51.100.7", "real-token").await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_over_http_names_wss_url() {
+        let router = create_acme_router(test_state(), Arc::new(ChallengeStore::new()), true)
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let request = |websocket: bool| {
+            let mut request = Request::get("/socket?room=1").header("host", "myapp.tunnel.example.com");
+            if websocket {
+                request = request
+                    .header("connection", "Upgrade")
+                    .header("upgrade", "websocket")
+                    .header("sec-websocket-version", "13")
+                    .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = router.clone().oneshot(request(true)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.headers()["upgrade"], "TLS/1.2, HTTP/1.1");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("wss://myapp.tunnel.example.com/socket?room=1"), "{}", body);
+
+        // Everything else is still redirected
+        let response = router.oneshot(request(false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        let location = response.headers()["location"].to_str().unwrap();
+        assert!(location.ends_with("://myapp.tunnel.example.com/socket?room=1"), "{}", location);
+    }
+
+    #[tokio::test]
+    async fn test_health_without_https() {
+        let router = create_acme_router(test_state(), Arc::new(ChallengeStore::new()), false);
+        let response = router
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))))
+            .oneshot(
+                Request::get("/_admin/health")
+                    .header("authorization", "Bearer tk_admin")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["ready"], true);
+        assert_eq!(json["base_certificate"]["state"], "disabled");
+    }
+
+    #[tokio::test]
+    async fn test_control_port_hides_control_routes() {
+        let state = Arc::new(test_state_with(|config| config.server.control_port = Some(9443)));
+        let connect_info = MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        let public = [
+            create_router(state.clone()).layer(connect_info),
+            create_acme_router(state.clone(), Arc::new(ChallengeStore::new()), false).layer(connect_info),
+        ];
+        for router in public {
+            for uri in ["/_tunnel/connect", "/_check/registration", "/_admin/health", "/_admin/tunnels"] {
+                let response = router.clone().oneshot(admin_request("GET", uri)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+            }
+        }
+
+        let control = create_control_router(state).layer(connect_info);
+        let response = control.clone().oneshot(admin_request("GET", "/_admin/health")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // No upgrade in the request, but the endpoint is there
+        let response = control.oneshot(admin_request("GET", "/_tunnel/connect")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_public_health() {
+        async fn get(router: &Router, host: &str, path: &str) -> (StatusCode, serde_json::Value) {
+            let response = router
+                .clone()
+                .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))))
+                .oneshot(Request::get(path).header("host", host).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or_default())
+        }
+
+        // HTTP only: ready from the start
+        let router = create_acme_router(test_state(), Arc::new(ChallengeStore::new()), false);
+        let (status, json) = get(&router, "tunnel.example.com", HEALTH_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["ready"], true);
+        assert_eq!(json["tunnels"], 0);
+        assert_eq!(json["has_base_certificate"], false);
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["uptime_secs"].is_u64());
+        // On a load balancer's own address too
+        let (status, _) = get(&router, "10.0.0.5:80", HEALTH_READY_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        // A tunnel's own /_health is its service's
+        let (status, _) = get(&router, "myapp.tunnel.example.com", HEALTH_PATH).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // HTTPS before the base certificate is issued: alive, not ready, and not redirected
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let cert_manager = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("tunnel.example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        let state = Arc::new(ServerState { cert_manager: Some(Arc::new(cert_manager)), ..test_state_with(|_| {}) });
+        let router = create_acme_router(state.clone(), Arc::new(ChallengeStore::new()), true);
+        let (status, json) = get(&router, "tunnel.example.com", HEALTH_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["ready"], false);
+        assert_eq!(json["has_base_certificate"], false);
+        let (status, json) = get(&router, "tunnel.example.com", HEALTH_READY_PATH).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["ready"], false);
+        let (status, _) = get(&router, "myapp.tunnel.example.com", HEALTH_PATH).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        let (status, _) = get(&create_router(state), "tunnel.example.com", HEALTH_READY_PATH).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dns_mismatch_fails_readiness() {
+        let state = test_state();
+        let router = create_acme_router(state.clone(), Arc::new(ChallengeStore::new()), false);
+        let ready = || async {
+            let response = router
+                .clone()
+                .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))))
+                .oneshot(Request::get(HEALTH_READY_PATH).header("host", "tunnel.example.com").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (status, json["dns_ok"].clone())
+        };
+        assert_eq!(ready().await, (StatusCode::OK, serde_json::json!(true)));
+
+        let mismatch = || {
+            vec![crate::server::dns_monitor::Mismatch {
+                name: "tunnel.example.com".to_string(),
+                expected: vec!["203.0.113.10".parse().unwrap()],
+                found: Vec::new(),
+            }]
+        };
+        state.dns.record(mismatch(), 1);
+        assert_eq!(ready().await.0, StatusCode::OK);
+        state.dns.record(mismatch(), 2);
+        assert_eq!(ready().await, (StatusCode::SERVICE_UNAVAILABLE, serde_json::json!(false)));
+        state.dns.record(Vec::new(), 3);
+        assert_eq!(ready().await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_pages_for_browsers() {
+        let router = create_router(test_state()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let visit = |host: &'static str, accept: Option<&'static str>| {
+            let router = router.clone();
+            async move {
+                let mut request = Request::get("/").header("host", host);
+                if let Some(accept) = accept {
+                    request = request.header("accept", accept);
+                }
+                let response = router.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+                let (parts, body) = response.into_parts();
+                let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+                (parts, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (parts, body) = visit("missing.tunnel.example.com", None).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "Tunnel not found");
+
+        let (parts, body) = visit("missing.tunnel.example.com", Some("text/html,*/*;q=0.8")).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+        assert_eq!(parts.headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(parts.headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap().starts_with("default-src 'none'"));
+        let request_id = parts.headers["x-request-id"].to_str().unwrap();
+        assert!(body.contains("<h1>Tunnel not found</h1>") && body.contains(request_id), "{}", body);
+
+        let (_, body) = visit("<script>alert(1)</script>", Some("text/html")).await;
+        assert!(body.contains("&lt;script&gt;alert(1)&lt;/script&gt;") && !body.contains("<script>"), "{}", body);
+    }
+
+    fn admin_request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("authorization", "Bearer tk_admin")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_release_ownership() {
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let cert_manager = Arc::new(
+            CertManager::new(
+                store.clone(),
+                None,
+                Arc::new(ChallengeStore::new()),
+                FullDomain::new("tunnel.example.com"),
+                Arc::new(Metrics::new()),
+            )
+            .await
+            .unwrap(),
+        );
+        let state = Arc::new(ServerState { cert_manager: Some(cert_manager.clone()), ..test_state_with(|_| {}) });
+        let router = create_router(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let domain = &FullDomain::new("app.tunnel.example.com");
+        let expiry = std::time::Duration::from_secs(3600);
+
+        cert_manager.claim(domain, &TokenSecret::new("tk_alice")).await.unwrap();
+        assert!(cert_manager.check_ownership(domain, &TokenSecret::new("tk_mallory"), true, expiry).is_err());
+        assert!(cert_manager.check_ownership(domain, &TokenSecret::new("tk_mallory"), false, expiry).is_ok());
+
+        let response = router.clone().oneshot(admin_request("GET", "/_admin/ownership")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let owners: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(owners[0]["domain"], domain.to_string());
+        assert_eq!(owners[0]["token_id"], TokenId::of("tk_alice").to_string());
+
+        let response = router
+            .clone()
+            .oneshot(admin_request("DELETE", "/_admin/ownership/app"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(cert_manager.check_ownership(domain, &TokenSecret::new("tk_mallory"), true, expiry).is_ok());
+
+        // The release is persisted, not just dropped from memory
+        let reloaded = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("tunnel.example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        assert!(reloaded.owners().is_empty());
+
+        let response = router
+            .clone()
+            .oneshot(admin_request("DELETE", "/_admin/ownership/app"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Nothing can be owned under a name that isn't a subdomain
+        let response = router
+            .oneshot(admin_request("DELETE", "/_admin/ownership/a.b"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_logs() {
+        use http_body_util::BodyExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let state = test_state();
+        let subscriber = tracing_subscriber::registry().with(super::super::logs::LogLayer::new(state.logs.clone()));
+        let _logging = tracing::subscriber::set_default(subscriber);
+        let router = create_router(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let messages = |body: &[u8]| -> Vec<String> {
+            body.split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice::<LogEvent>(line).unwrap().message)
+                .collect()
+        };
+
+        info!("Tunnel registered");
+        warn!(subdomain = "app", "Renewal failed");
+        error!("Bad gateway");
+
+        let response = router.clone().oneshot(admin_request("GET", "/_admin/logs")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(messages(&body), ["Tunnel registered", "Renewal failed", "Bad gateway"]);
+
+        let response = router.clone().oneshot(admin_request("GET", "/_admin/logs?level=warn")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(messages(&body), ["Renewal failed", "Bad gateway"]);
+        let event: LogEvent = serde_json::from_slice(body.split(|&b| b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(event.level, "WARN");
+        assert_eq!(event.fields["subdomain"], "app");
+
+        let response = router.clone().oneshot(admin_request("GET", "/_admin/logs?level=loud")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Following: the kept events, then each new one as it's logged
+        let response = router
+            .clone()
+            .oneshot(admin_request("GET", "/_admin/logs?level=error&follow=true"))
+            .await
+            .unwrap();
+        let mut body = response.into_body();
+        async fn next(body: &mut Body) -> bytes::Bytes {
+            let frame = tokio::time::timeout(Duration::from_secs(5), body.frame()).await.unwrap();
+            frame.unwrap().unwrap().into_data().unwrap()
+        }
+        assert_eq!(messages(&next(&mut body).await), ["Bad gateway"]);
+        warn!("Not severe enough");
+        error!("Tunnel app is gone");
+        assert_eq!(messages(&next(&mut body).await), ["Tunnel app is gone"]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_prunes_unused_certificates() {
+        use crate::server::cert_store::Item;
+        use crate::server::ownership;
+
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        const DAY: u64 = 24 * 3600;
+        let now = now_secs();
+        // Last registered 45 days ago, apart from "fresh"
+        for (domain, last_seen_at) in [
+            ("tunnel.example.com", now - 45 * DAY),
+            ("old.tunnel.example.com", now - 45 * DAY),
+            ("fresh.tunnel.example.com", now - DAY),
+            ("www.tunnel.example.com", now - 45 * DAY),
+            ("provisioned.tunnel.example.com", now - 45 * DAY),
+        ] {
+            store.put(Item::Cert(domain), b"cert").await.unwrap();
+            let owner = Ownership { token_id: TokenId::of("tk_alice"), issued_at: last_seen_at, last_seen_at };
+            ownership::save(store.as_ref(), domain, Some(&owner)).await.unwrap();
+        }
+        // Never registered since tracking started
+        store.put(Item::Cert("quick-fox-123.tunnel.example.com"), b"cert").await.unwrap();
+
+        let cert_manager = Arc::new(
+            CertManager::new(
+                store.clone(),
+                None,
+                Arc::new(ChallengeStore::new()),
+                FullDomain::new("tunnel.example.com"),
+                Arc::new(Metrics::new()),
+            )
+            .await
+            .unwrap(),
+        );
+        let config = Config::parse(
+            r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens.tk_admin]
+admin = true
+
+[tokens.tk_ci]
+allowed_subdomains = ["ci-*", "provisioned"]
+
+[https]
+email = "admin@example.com"
+prune_unused_after_days = 30
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(ServerState {
+            cert_manager: Some(cert_manager.clone()),
+            usage: Arc::new(Usage::new(90).with_tracking_since(now - 60 * DAY)),
+            ..test_state_with(|c| *c = config)
+        });
+        let router = create_router(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let prune = |uri: &'static str| {
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(admin_request("POST", uri)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let domains = |json: &serde_json::Value| -> Vec<String> {
+            json["certificates"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|cert| cert["domain"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let unused = ["old.tunnel.example.com", "quick-fox-123.tunnel.example.com"];
+
+        // A dry run only lists them
+        let json = prune("/_admin/certificates/prune?dry_run=true").await;
+        assert_eq!(json["dry_run"], true);
+        assert_eq!(json["unused_for_days"], 30);
+        assert_eq!(domains(&json), unused);
+        assert_eq!(json["certificates"][0]["last_used_at"], now - 45 * DAY);
+        assert_eq!(store.list().await.unwrap().len(), 6);
+
+        let json = prune("/_admin/certificates/prune").await;
+        assert_eq!(domains(&json), unused);
+        assert_eq!(
+            store.list().await.unwrap(),
+            [
+                "fresh.tunnel.example.com",
+                "provisioned.tunnel.example.com",
+                "tunnel.example.com",
+                "www.tunnel.example.com"
+            ]
+        );
+        assert!(!certs_dir.join("old.tunnel.example.com").exists());
+        assert!(cert_manager.owner("old.tunnel.example.com").is_none());
+        assert!(domains(&prune("/_admin/certificates/prune").await).is_empty());
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    fn cloudflare_state() -> Arc<ServerState> {
+        Arc::new(ServerState { cloudflare: Some(Arc::new(CloudflareRanges::bundled())), ..test_state_with(|_| {}) })
+    }
+
+    #[test]
+    fn test_cloudflare_headers_trusted_only_from_cloudflare_peers() {
+        let state = cloudflare_state();
+        let mut headers = header::HeaderMap::new();
+        headers.insert("cf-connecting-ip", "198.51.100.7".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+
+        let edge: IpAddr = "172.64.0.10".parse().unwrap();
+        assert_eq!(state.client_ip(edge, &headers), "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert!(state.forwarded_https(edge, &headers));
+
+        // Anyone else could be spoofing them
+        let direct: IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(state.client_ip(direct, &headers), direct);
+        assert!(!state.forwarded_https(direct, &headers));
+
+        // Ignored entirely unless behind_cloudflare is set
+        assert_eq!(test_state().client_ip(edge, &headers), edge);
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_https_not_redirected() {
+        let router = create_acme_router(cloudflare_state(), Arc::new(ChallengeStore::new()), true);
+        let get = |from: [u8; 4]| {
+            router
+                .clone()
+                .layer(MockConnectInfo(SocketAddr::from((from, 40000))))
+                .oneshot(
+                    Request::get("/")
+                        .header("host", "myapp.tunnel.example.com")
+                        .header("x-forwarded-proto", "https")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+        };
+
+        // Served directly (no tunnel registered) instead of looping through a redirect
+        let response = get([104, 16, 0, 1]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = get([192, 0, 2, 10]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    /// A server behind a load balancer in 10.0.0.0/8
+    fn trusted_proxy_state() -> Arc<ServerState> {
+        Arc::new(test_state_with(|config| config.server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()]))
+    }
+
+    #[test]
+    fn test_forwarded_headers_trusted_only_from_trusted_proxies() {
+        let state = trusted_proxy_state();
+        let mut headers = header::HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1, 198.51.100.7".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+
+        let balancer: IpAddr = "10.1.2.3".parse().unwrap();
+        assert_eq!(state.client_ip(balancer, &headers), "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert!(state.forwarded_https(balancer, &headers));
+
+        let direct: IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(state.client_ip(direct, &headers), direct);
+        assert!(!state.forwarded_https(direct, &headers));
+
+        // Ignored entirely unless trusted_proxies is set
+        assert_eq!(test_state().client_ip(balancer, &headers), balancer);
+        assert!(!test_state().forwarded_https(balancer, &headers));
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxy_https_not_redirected() {
+        let router = create_acme_router(trusted_proxy_state(), Arc::new(ChallengeStore::new()), true);
+        let get = |from: [u8; 4]| {
+            router
+                .clone()
+                .layer(MockConnectInfo(SocketAddr::from((from, 40000))))
+                .oneshot(
+                    Request::get("/")
+                        .header("host", "myapp.tunnel.example.com")
+                        .header("x-forwarded-proto", "https")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+        };
+
+        let response = get([10, 0, 0, 5]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // A visitor claiming HTTPS itself is still redirected
+        let response = get([192, 0, 2, 10]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn test_control_connections_limited_per_ip_before_upgrade() {
+        let config = Config::parse(
+            r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens.tk_test]
+
+[limits]
+max_connections_per_ip = 3
+registrations_per_minute_per_ip = 0
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(test_state_with(|c| *c = config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), state.config.server.control_path());
+        let app = create_acme_router(state.clone(), Arc::new(ChallengeStore::new()), false);
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+
+        // Connections that haven't registered yet still hold a slot
+        let mut open = Vec::new();
+        for _ in 0..3 {
+            open.push(tokio_tungstenite::connect_async(&url).await.unwrap().0);
+        }
+
+        let attempts = (0..10).map(|_| tokio_tungstenite::connect_async(&url));
+        for result in futures::future::join_all(attempts).await {
+            match result {
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                }
+                other => panic!("expected 429 before the upgrade, got {:?}", other.map(|(_, r)| r.status())),
+            }
+        }
+        assert_eq!(state.admission.connections([127, 0, 0, 1].into()), 3);
+
+        // Closing a connection frees its slot
+        open.pop().unwrap().close(None).await.unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while state.admission.connections([127, 0, 0, 1].into()) > 2 {
+            assert!(std::time::Instant::now() < deadline, "slot never released");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(tokio_tungstenite::connect_async(&url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_uses_public_https_port() {
+        let config = Config::parse(
+            r#"
+[server]
+domain = "tunnel.example.com"
+http_port = 8080
+https_port = 8443
+
+[tokens.tk_test]
+
+[https]
+email = "admin@example.com"
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(test_state_with(|c| *c = config));
+        let response = create_acme_router(state, Arc::new(ChallengeStore::new()), true)
+            .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 10], 40000))))
+            .oneshot(
+                Request::get("/path?q=1")
+                    .header("host", "myapp.tunnel.example.com:8080")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://myapp.tunnel.example.com:8443/path?q=1"
+        );
+    }
+
+    #[test]
+    fn test_extract_subdomain() {
+        assert_eq!(
+            extract_subdomain("myapp.localhost", "localhost"),
+            Some("myapp".to_string())
+        );
+        assert_eq!(
+            extract_subdomain("myapp.localhost:8080", "localhost"),
+            Some("myapp".to_string())
+        );
+        assert_eq!(
+            extract_subdomain("myapp.tunnel.example.com", "tunnel.example.com"),
+            Some("myapp".to_string())
+        );
+        assert_eq!(extract_subdomain("localhost", "localhost"), None);
+        assert_eq!(
+            extract_subdomain("tunnel.example.com", "tunnel.example.com"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics_token() {
+        let config = Config::parse(
+            "[server]\ndomain = \"tunnel.example.com\"\n[tokens.tk_admin]\n[metrics]\nenabled = true\ntoken = \"scrape-me\"\nport = 9090\n",
+        )
+        .unwrap();
+        let state = Arc::new(test_state_with(|c| *c = config));
+        let router = create_metrics_router(state);
+        let scrape = |auth: Option<&str>| {
+            let mut request = Request::get(METRICS_PATH);
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(scrape(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(scrape(Some("Bearer wrong")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let response = scrape(Some("Bearer scrape-me")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain; version=0.0.4"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("loophole_tunnels 0"));
+    }
+}
+
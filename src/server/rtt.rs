@@ -0,0 +1,178 @@
+//! Round-trip time of each tunnel, measured over a yamux stream of its own that the
+//! client echoes back (see [`ECHO_STREAM`]). It covers the hop between the server and
+//! the client only, so a slow tunnel can be told apart from a slow local service.
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::proto::transport::ECHO_STREAM;
+
+/// How often a tunnel is probed
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Probes not echoed within this are counted as lost
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What's been measured of a tunnel's round trip
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RttStats {
+    /// Moved an eighth of the way to each new sample, as TCP smooths its RTT
+    pub smoothed: Option<Duration>,
+    pub latest: Option<Duration>,
+    pub probes_sent: u64,
+    /// Probes not echoed within [`PROBE_TIMEOUT`]
+    pub probes_lost: u64,
+}
+
+/// A tunnel's round trip, updated by its probe
+#[derive(Debug, Default)]
+pub struct TunnelRtt(Mutex<RttStats>);
+
+impl TunnelRtt {
+    pub fn stats(&self) -> RttStats {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn record_sent(&self) {
+        self.0.lock().unwrap().probes_sent += 1;
+    }
+
+    pub fn record_lost(&self) {
+        self.0.lock().unwrap().probes_lost += 1;
+    }
+
+    pub fn record(&self, rtt: Duration) {
+        let mut stats = self.0.lock().unwrap();
+        stats.latest = Some(rtt);
+        stats.smoothed = Some(match stats.smoothed {
+            Some(smoothed) => smoothed - smoothed / 8 + rtt / 8,
+            None => rtt,
+        });
+    }
+}
+
+/// Probe over `stream` every `interval` until it closes, recording into `rtt`. A probe
+/// echoed after `timeout` counts as lost, and its late echo is skipped.
+pub async fn probe<S>(mut stream: S, rtt: &TunnelRtt, interval: Duration, timeout: Duration) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&[ECHO_STREAM]).await?;
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut echoed = Vec::new();
+    let mut buf = [0u8; 64];
+    for sequence in 0u64.. {
+        ticks.tick().await;
+        stream.write_all(&sequence.to_be_bytes()).await?;
+        stream.flush().await?;
+        let sent_at = Instant::now();
+        rtt.record_sent();
+
+        'echo: loop {
+            // Echoes of earlier probes that came back too late are passed over
+            while echoed.len() >= 8 {
+                let echo: Vec<u8> = echoed.drain(..8).collect();
+                if echo == sequence.to_be_bytes() {
+                    rtt.record(sent_at.elapsed());
+                    break 'echo;
+                }
+            }
+            match tokio::time::timeout_at(sent_at + timeout, stream.read(&mut buf)).await {
+                Err(_) => {
+                    rtt.record_lost();
+                    break;
+                }
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(n)) => echoed.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, DuplexStream};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    /// Pass bytes from `from` to `to`, each `delay` after it was read
+    fn delay_line(mut from: tokio::io::ReadHalf<DuplexStream>, mut to: tokio::io::WriteHalf<DuplexStream>, delay: Duration) {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = from.read(&mut buf).await {
+                if n == 0 || tx.send((Instant::now() + delay, buf[..n].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some((due, bytes)) = rx.recv().await {
+                tokio::time::sleep_until(due).await;
+                if to.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// A transport between the server and `crate::expose::echo`, delaying each
+    /// direction by `one_way`
+    fn delayed_echo(one_way: Duration) -> DuplexStream {
+        let (server, server_side) = tokio::io::duplex(1024);
+        let (client_side, client) = tokio::io::duplex(1024);
+        let (server_read, server_write) = tokio::io::split(server_side);
+        let (client_read, client_write) = tokio::io::split(client_side);
+        delay_line(server_read, client_write, one_way);
+        delay_line(client_read, server_write, one_way);
+        tokio::spawn(async move {
+            let _ = crate::expose::echo::dispatch(client.compat()).await;
+        });
+        server
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_measures_delayed_round_trip() {
+        let stream = delayed_echo(Duration::from_millis(40));
+        let rtt = std::sync::Arc::new(TunnelRtt::default());
+        let probing = rtt.clone();
+        tokio::spawn(async move { probe(stream.compat(), &probing, Duration::from_secs(1), PROBE_TIMEOUT).await });
+
+        tokio::time::sleep(Duration::from_millis(4500)).await;
+        let stats = rtt.stats();
+        assert_eq!((stats.probes_sent, stats.probes_lost), (5, 0));
+        let latest = stats.latest.unwrap();
+        assert!(latest >= Duration::from_millis(80) && latest < Duration::from_millis(90), "{:?}", latest);
+        let smoothed = stats.smoothed.unwrap();
+        assert!(smoothed >= Duration::from_millis(80) && smoothed < Duration::from_millis(90), "{:?}", smoothed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_echoes_count_as_lost() {
+        let stream = delayed_echo(Duration::from_millis(1500));
+        let rtt = std::sync::Arc::new(TunnelRtt::default());
+        let probing = rtt.clone();
+        tokio::spawn(async move { probe(stream.compat(), &probing, Duration::from_secs(5), Duration::from_secs(2)).await });
+
+        // Each echo is back after 3s, a second past the timeout, and skipped once it is
+        tokio::time::sleep(Duration::from_millis(14500)).await;
+        let stats = rtt.stats();
+        assert_eq!((stats.probes_sent, stats.probes_lost), (3, 3));
+        assert_eq!(stats.smoothed, None);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let rtt = TunnelRtt::default();
+        rtt.record(Duration::from_millis(80));
+        assert_eq!(rtt.stats().smoothed, Some(Duration::from_millis(80)));
+        rtt.record(Duration::from_millis(160));
+        assert_eq!(rtt.stats().smoothed, Some(Duration::from_millis(90)));
+        assert_eq!(rtt.stats().latest, Some(Duration::from_millis(160)));
+    }
+}
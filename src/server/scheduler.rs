@@ -0,0 +1,263 @@
+//! Fair sharing of stream opens between tunnels (`limits.fair_queue_threshold`).
+//! Below the threshold of requests in flight, requests go straight through. Above it
+//! they wait in a queue per tunnel, and freed slots go to the queues in turn (deficit
+//! round-robin, with each token's `weight` as its quantum), so a tunnel taking a
+//! thousand requests a second can't crowd out one taking a request a second.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+use super::metrics::Metrics;
+
+#[derive(Debug)]
+pub struct FairScheduler {
+    /// Requests allowed in flight before new ones queue (0 = never queue)
+    threshold: usize,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: usize,
+    /// Waiting requests by subdomain; a queue is removed once empty
+    queues: HashMap<String, Queue>,
+    /// Subdomains with waiting requests, in the order they get their turns
+    turns: VecDeque<String>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    weight: u32,
+    /// Requests the tunnel may still start in its current turn
+    deficit: u32,
+    waiting: VecDeque<Waiter>,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    grant: oneshot::Sender<Permit>,
+    queued_at: Instant,
+}
+
+/// A request's slot, given back when dropped
+#[derive(Debug)]
+pub struct Permit {
+    scheduler: Option<Arc<FairScheduler>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
+
+impl FairScheduler {
+    pub fn new(threshold: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            threshold,
+            state: Mutex::new(State::default()),
+            metrics,
+        }
+    }
+
+    /// Wait for a slot for a request to `subdomain`, whose token has `weight`
+    pub async fn admit(self: &Arc<Self>, subdomain: &str, weight: u32) -> Permit {
+        if self.threshold == 0 {
+            return Permit { scheduler: None };
+        }
+
+        let granted = {
+            let mut state = self.lock();
+            // Queued requests go first, so newcomers can't skip past them
+            if state.in_flight < self.threshold && state.turns.is_empty() {
+                state.in_flight += 1;
+                return Permit {
+                    scheduler: Some(self.clone()),
+                };
+            }
+            let (grant, granted) = oneshot::channel();
+            let queue = state.queues.entry(subdomain.to_string()).or_insert_with(|| Queue {
+                weight: weight.max(1),
+                deficit: 0,
+                waiting: VecDeque::new(),
+            });
+            queue.waiting.push_back(Waiter {
+                grant,
+                queued_at: Instant::now(),
+            });
+            let depth = queue.waiting.len();
+            if depth == 1 {
+                state.turns.push_back(subdomain.to_string());
+            }
+            self.metrics.set_queue_depth(subdomain, depth);
+            granted
+        };
+        // Slots are only handed over while the scheduler is alive, and it outlives requests
+        granted.await.expect("scheduler dropped with requests waiting")
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Give a slot back, handing it to the next waiting request if there is one
+    fn release(self: Arc<Self>) {
+        let mut state = self.lock();
+        state.in_flight -= 1;
+        while state.in_flight < self.threshold {
+            let Some((subdomain, waiter, depth)) = state.next_waiter() else {
+                break;
+            };
+            self.metrics.set_queue_depth(&subdomain, depth);
+            self.metrics.record_queue_wait(&subdomain, waiter.queued_at.elapsed());
+            let permit = Permit {
+                scheduler: Some(self.clone()),
+            };
+            state.in_flight += 1;
+            if let Err(mut permit) = waiter.grant.send(permit) {
+                // The request was abandoned while it waited: offer the slot to the next
+                permit.scheduler = None;
+                state.in_flight -= 1;
+            }
+        }
+    }
+}
+
+impl State {
+    /// The request whose turn it is, with its subdomain and how many are left behind it
+    fn next_waiter(&mut self) -> Option<(String, Waiter, usize)> {
+        let subdomain = self.turns.front()?.clone();
+        let queue = self.queues.get_mut(&subdomain).expect("every turn has a queue");
+        if queue.deficit == 0 {
+            queue.deficit = queue.weight;
+        }
+        queue.deficit -= 1;
+        let waiter = queue.waiting.pop_front().expect("queues with turns aren't empty");
+        let depth = queue.waiting.len();
+
+        if depth == 0 {
+            self.queues.remove(&subdomain);
+            self.turns.pop_front();
+        } else if queue.deficit == 0 {
+            // Turn over: to the back of the line
+            self.turns.rotate_left(1);
+        }
+        Some((subdomain, waiter, depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Queue a request without waiting for it to be admitted
+    fn queue(scheduler: &Arc<FairScheduler>, subdomain: &'static str, weight: u32) -> tokio::task::JoinHandle<Permit> {
+        let scheduler = scheduler.clone();
+        tokio::spawn(async move { scheduler.admit(subdomain, weight).await })
+    }
+
+    /// Let spawned requests reach their queues
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    /// Release permits one at a time and record whose request was admitted next
+    async fn admission_order(
+        first: Permit,
+        mut requests: Vec<(&'static str, tokio::task::JoinHandle<Permit>)>,
+    ) -> Vec<&'static str> {
+        let mut order = Vec::new();
+        let mut held = first;
+        while !requests.is_empty() {
+            drop(held);
+            settle().await;
+            let index = requests.iter().position(|(_, request)| request.is_finished()).expect("a request was admitted");
+            let (subdomain, request) = requests.remove(index);
+            assert!(requests.iter().all(|(_, request)| !request.is_finished()), "more than one admitted");
+            order.push(subdomain);
+            held = request.await.unwrap();
+        }
+        order
+    }
+
+    #[tokio::test]
+    async fn test_quiet_tunnel_is_not_starved() {
+        let metrics = Arc::new(Metrics::new());
+        let scheduler = Arc::new(FairScheduler::new(1, metrics.clone()));
+        let first = scheduler.admit("hot", 1).await;
+
+        let mut requests = Vec::new();
+        for _ in 0..50 {
+            requests.push(("hot", queue(&scheduler, "hot", 1)));
+        }
+        settle().await;
+        requests.push(("quiet", queue(&scheduler, "quiet", 1)));
+        settle().await;
+        assert_eq!(metrics.queue_depth("hot"), 50);
+        assert_eq!(metrics.queue_depth("quiet"), 1);
+
+        let order = admission_order(first, requests).await;
+        // Behind one hot request, not fifty
+        assert_eq!(order.iter().position(|s| *s == "quiet"), Some(1), "{:?}", order);
+        assert_eq!(metrics.queue_depth("hot"), 0);
+        assert_eq!(metrics.queue_waits("quiet").0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_weights() {
+        let scheduler = Arc::new(FairScheduler::new(1, Arc::new(Metrics::new())));
+        let first = scheduler.admit("a", 1).await;
+
+        let mut requests = Vec::new();
+        for _ in 0..6 {
+            requests.push(("heavy", queue(&scheduler, "heavy", 2)));
+            settle().await;
+        }
+        for _ in 0..3 {
+            requests.push(("light", queue(&scheduler, "light", 1)));
+            settle().await;
+        }
+
+        let order = admission_order(first, requests).await;
+        assert_eq!(order, ["heavy", "heavy", "light", "heavy", "heavy", "light", "heavy", "heavy", "light"]);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_requests_give_up_their_slot() {
+        let scheduler = Arc::new(FairScheduler::new(1, Arc::new(Metrics::new())));
+        let first = scheduler.admit("app", 1).await;
+        let abandoned = queue(&scheduler, "app", 1);
+        settle().await;
+        let waiting = queue(&scheduler, "app", 1);
+        settle().await;
+        abandoned.abort();
+        settle().await;
+
+        drop(first);
+        let permit = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        drop(permit);
+        // Every slot was given back
+        assert_eq!(scheduler.lock().in_flight, 0);
+        assert!(scheduler.lock().queues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_below_threshold_never_queues() {
+        let scheduler = Arc::new(FairScheduler::new(2, Arc::new(Metrics::new())));
+        let a = scheduler.admit("app", 1).await;
+        let b = scheduler.admit("app", 1).await;
+        assert_eq!(scheduler.lock().in_flight, 2);
+        drop((a, b));
+        assert_eq!(scheduler.lock().in_flight, 0);
+
+        // Off: no limit at all
+        let off = Arc::new(FairScheduler::new(0, Arc::new(Metrics::new())));
+        let _permits: Vec<Permit> = futures::future::join_all((0..100).map(|_| off.admit("app", 1))).await;
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The slow request threshold, which a config reload (SIGHUP) can change while
+/// requests are being proxied
+#[derive(Debug, Default)]
+pub struct SlowRequests {
+    threshold_ms: AtomicU64,
+}
+
+impl SlowRequests {
+    pub fn new(threshold_ms: u64) -> Self {
+        Self {
+            threshold_ms: AtomicU64::new(threshold_ms),
+        }
+    }
+
+    pub fn set_threshold(&self, threshold_ms: u64) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    /// The threshold, or None when slow requests aren't reported
+    pub fn threshold(&self) -> Option<Duration> {
+        match self.threshold_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// The threshold `latency` exceeded, if it's slow
+    pub fn exceeded(&self, latency: Duration) -> Option<Duration> {
+        self.threshold().filter(|threshold| latency > *threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let slow = SlowRequests::new(0);
+        assert_eq!(slow.exceeded(Duration::from_secs(3600)), None);
+
+        slow.set_threshold(500);
+        assert_eq!(slow.exceeded(Duration::from_millis(500)), None);
+        assert_eq!(slow.exceeded(Duration::from_millis(501)), Some(Duration::from_millis(500)));
+
+        slow.set_threshold(0);
+        assert_eq!(slow.threshold(), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Raw TCP tunnels: each one gets its own listener on a port from `[tcp] port_range`,
+//! and every connection to it is copied as-is over a fresh yamux stream. Connectors
+//! (`loophole connect`) reach the same tunnels over a WebSocket on the HTTP(S) port.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{debug, info, warn};
+
+use super::listen;
+use super::tunnel::Tunnel;
+
+/// An inclusive range of ports, written `20000-20100` (or a single port)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        let port = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .ok_or_else(|| format!("invalid port range '{}': expected e.g. \"20000-20100\"", value))
+        };
+        let (start, end) = (port(start)?, port(end)?);
+        if start > end {
+            return Err(format!("invalid port range '{}': {} is greater than {}", value, start, end));
+        }
+        Ok(Self { start, end })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::parse(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PortError {
+    #[error("Port {0} is outside this server's TCP port range ({1})")]
+    OutOfRange(u16, PortRange),
+    #[error("Port {0} is already in use")]
+    InUse(u16),
+    #[error("Every port in this server's TCP port range ({0}) is in use")]
+    Exhausted(PortRange),
+}
+
+/// Hands out listeners on ports in the configured range
+#[derive(Debug)]
+pub struct TcpPorts {
+    range: PortRange,
+    /// `server.bind_address`
+    bind_address: IpAddr,
+}
+
+impl TcpPorts {
+    pub fn new(range: PortRange) -> Self {
+        Self {
+            range,
+            bind_address: Ipv4Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    pub fn with_bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    /// Listen on `requested`, or on the first free port in the range. The port is
+    /// free again once the listener is dropped.
+    pub async fn bind(&self, requested: Option<u16>) -> Result<TcpListener, PortError> {
+        if let Some(port) = requested {
+            if !self.range.contains(port) {
+                return Err(PortError::OutOfRange(port, self.range));
+            }
+            return self.listen(port).ok_or(PortError::InUse(port));
+        }
+
+        for port in self.range.start..=self.range.end {
+            if let Some(listener) = self.listen(port) {
+                return Ok(listener);
+            }
+        }
+        Err(PortError::Exhausted(self.range))
+    }
+
+    fn listen(&self, port: u16) -> Option<TcpListener> {
+        match listen::bind(SocketAddr::new(self.bind_address, port)).and_then(TcpListener::from_std) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                debug!("Can't listen on TCP port {}: {}", port, e);
+                None
+            }
+        }
+    }
+}
+
+/// Accept connections on `listener` and copy each one over its own tunnel stream,
+/// until the task is aborted
+pub async fn serve(listener: TcpListener, tunnel: Arc<Tunnel>) {
+    loop {
+        let (visitor, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Tunnel {} failed to accept a TCP connection: {}", tunnel.subdomain, e);
+                continue;
+            }
+        };
+
+        tokio::spawn(splice(tunnel.clone(), visitor, addr));
+    }
+}
+
+/// Copy a visitor's connection (from the tunnel's port, or a connector's WebSocket)
+/// over a fresh tunnel stream until either side closes
+pub async fn splice<V>(tunnel: Arc<Tunnel>, mut visitor: V, addr: SocketAddr)
+where
+    V: AsyncRead + AsyncWrite + Unpin,
+{
+    // There's no page to show, so visitors are just turned away until it's over
+    if tunnel.paused_until().is_some() {
+        debug!("Tunnel {} is paused for maintenance, closing the connection from {}", tunnel.subdomain, addr);
+        return;
+    }
+    if !tunnel.admits_ip(addr.ip()) {
+        info!("Tunnel {} refused a TCP connection from {}: address not allowed", tunnel.subdomain, addr);
+        return;
+    }
+    tunnel.increment_requests();
+    let _open = tunnel.open_connection();
+    let mut stream = match tunnel.get_stream().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Tunnel {} couldn't take a TCP connection from {}: {}", tunnel.subdomain, addr, e);
+            return;
+        }
+    };
+    // yamux only announces a stream with its first frame, and the visitor may be
+    // waiting for the service to speak first (e.g. a MySQL greeting or SSH banner)
+    if let Err(e) = futures::AsyncWriteExt::write(&mut stream, &[]).await {
+        warn!("Tunnel {} couldn't open a stream for {}: {}", tunnel.subdomain, addr, e);
+        return;
+    }
+    debug!("Tunnel {}: TCP connection from {}", tunnel.subdomain, addr);
+
+    let mut client = stream.compat();
+    match tokio::io::copy_bidirectional(&mut visitor, &mut client).await {
+        Ok((to_client, to_visitor)) => {
+            tunnel.record_bytes_in(to_client as usize);
+            tunnel.record_bytes_out(to_visitor as usize);
+            info!(
+                subdomain = %tunnel.subdomain,
+                peer = %addr,
+                to_client = to_client,
+                to_visitor = to_visitor,
+                "TCP connection closed"
+            );
+        }
+        Err(e) => debug!("Tunnel {}: TCP connection from {} ended: {}", tunnel.subdomain, addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(PortRange::parse("20000-20100"), Ok(PortRange { start: 20000, end: 20100 }));
+        assert_eq!(PortRange::parse(" 20000 - 20000 "), Ok(PortRange { start: 20000, end: 20000 }));
+        assert_eq!(PortRange::parse("2222"), Ok(PortRange { start: 2222, end: 2222 }));
+        assert!(PortRange::parse("20100-20000").unwrap_err().contains("greater than"));
+        for invalid in ["", "0-10", "20000-70000", "a-b", "20000-"] {
+            assert!(PortRange::parse(invalid).is_err(), "{:?}", invalid);
+        }
+        assert_eq!(PortRange::parse("20000-20100").unwrap().to_string(), "20000-20100");
+    }
+
+    #[tokio::test]
+    async fn test_bind_skips_ports_in_use() {
+        // An OS-chosen port, held so the range's only port is busy
+        let held = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = held.local_addr().unwrap().port();
+        let range = PortRange { start: port, end: port };
+        let ports = TcpPorts::new(range);
+
+        assert_eq!(ports.bind(None).await.unwrap_err(), PortError::Exhausted(range));
+        assert_eq!(ports.bind(Some(port)).await.unwrap_err(), PortError::InUse(port));
+        assert_eq!(
+            ports.bind(Some(port.wrapping_add(1))).await.unwrap_err(),
+            PortError::OutOfRange(port.wrapping_add(1), range)
+        );
+
+        drop(held);
+        let listener = ports.bind(None).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+}
@@ -1,84 +1,161 @@
 use anyhow::{Context, Result};
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use rustls::pki_types::CertificateDer;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 use rustls::server::{ClientHello, ResolvesServerCert};
 use rustls::sign::CertifiedKey;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::fs;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::fmt;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
-use super::acme::{AcmeClient, ChallengeStore};
+use super::acme::{AcmeClient, ChallengeStore, TlsAlpnChallenges, ACME_TLS_ALPN};
+use super::cert_store::{CertStore, Item};
+use super::metrics::Metrics;
+use super::names::{FullDomain, Subdomain, TokenSecret};
+use super::ownership::{self, Ownership};
+use crate::units;
+
+/// First retry delay for the base domain certificate, doubled after each failure
+const BOOTSTRAP_BASE_DELAY: Duration = Duration::from_secs(30);
+const BOOTSTRAP_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// How often stored certificates are checked for renewal
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// First retry delay for a failed renewal, doubled after each failure
+const RENEWAL_RETRY_BASE_DELAY: Duration = Duration::from_secs(5 * 60);
+const RENEWAL_RETRY_MAX_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long after a handshake asked for a missing certificate another one may ask again
+const ON_DEMAND_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Certificate requests from handshakes waiting for the on-demand task
+const ON_DEMAND_QUEUE: usize = 64;
+/// Self-signed certificates kept for names waiting on a real one
+const MAX_PLACEHOLDERS: usize = 1000;
+
+/// Progress of the background request for the base domain certificate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BaseCertState {
+    /// HTTPS isn't configured
+    Disabled,
+    /// No attempt has been made yet
+    Pending,
+    Requesting { attempt: u32 },
+    Ready,
+    /// Certificates are managed manually and none in the store covers the base domain
+    Missing,
+    Failed {
+        attempts: u32,
+        error: String,
+        retry_in_secs: u64,
+    },
+}
 
 /// Manages TLS certificates with dynamic loading based on SNI
 #[derive(Debug)]
 pub struct CertManager {
-    certs_dir: PathBuf,
+    store: Arc<dyn CertStore>,
     /// Maps domain -> CertifiedKey
-    certs: DashMap<String, Arc<CertifiedKey>>,
+    certs: DashMap<FullDomain, Arc<CertifiedKey>>,
     /// Domains with pending certificate requests
-    pending: DashMap<String, ()>,
+    pending: DashMap<FullDomain, ()>,
     /// ACME client for requesting certificates
     acme_client: Option<Arc<AcmeClient>>,
     /// Challenge store for HTTP-01 challenges
     challenge_store: Arc<ChallengeStore>,
+    /// Certificates for TLS-ALPN-01 challenges, when the ACME client answers them
+    tls_alpn: Option<Arc<TlsAlpnChallenges>>,
     /// Base domain for the server
-    base_domain: String,
+    base_domain: FullDomain,
+    /// Progress of the base domain certificate bootstrap
+    base_cert_state: RwLock<BaseCertState>,
+    /// Maps domain -> owning token, mirrored in each certificate's meta.json
+    owners: DashMap<FullDomain, Ownership>,
+    /// Counts certificate requests
+    metrics: Arc<Metrics>,
+    /// Requests certificates for handshakes that found none, once enabled
+    on_demand: OnceLock<OnDemand>,
+    /// Maps domain -> when a handshake last asked for its certificate
+    on_demand_asked: DashMap<FullDomain, Instant>,
+    /// Maps domain -> when it was made and the self-signed certificate served until the
+    /// real one is issued
+    placeholders: DashMap<FullDomain, (Instant, Arc<CertifiedKey>)>,
+}
+
+/// Which subdomains handshakes may request certificates for, and where the requests go
+struct OnDemand {
+    has_tunnel: Box<dyn Fn(&Subdomain) -> bool + Send + Sync>,
+    requests: mpsc::Sender<FullDomain>,
+}
+
+impl fmt::Debug for OnDemand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnDemand").finish_non_exhaustive()
+    }
 }
 
 impl CertManager {
     pub async fn new(
-        certs_dir: PathBuf,
+        store: Arc<dyn CertStore>,
         acme_client: Option<Arc<AcmeClient>>,
         challenge_store: Arc<ChallengeStore>,
-        base_domain: String,
+        base_domain: FullDomain,
+        metrics: Arc<Metrics>,
     ) -> Result<Self> {
         let manager = Self {
-            certs_dir: certs_dir.clone(),
+            store,
             certs: DashMap::new(),
             pending: DashMap::new(),
+            tls_alpn: acme_client.as_ref().and_then(|client| client.tls_alpn_challenges()),
             acme_client,
             challenge_store,
             base_domain,
+            base_cert_state: RwLock::new(BaseCertState::Pending),
+            owners: DashMap::new(),
+            metrics,
+            on_demand: OnceLock::new(),
+            on_demand_asked: DashMap::new(),
+            placeholders: DashMap::new(),
         };
 
         // Load existing certificates
         manager.load_existing_certs().await?;
+        if manager.has_cert(&manager.base_domain) {
+            manager.set_base_cert_state(BaseCertState::Ready);
+        } else if manager.acme_client.is_none() {
+            warn!(
+                "No certificate for {} in {} and ACME is disabled",
+                manager.base_domain,
+                manager.store.describe()
+            );
+            manager.set_base_cert_state(BaseCertState::Missing);
+        }
 
         Ok(manager)
     }
 
     async fn load_existing_certs(&self) -> Result<()> {
-        if !self.certs_dir.exists() {
-            fs::create_dir_all(&self.certs_dir).await?;
-            return Ok(());
-        }
-
-        let mut entries = fs::read_dir(&self.certs_dir).await?;
-
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if !path.is_dir() {
-                continue;
-            }
-
-            let domain = match path.file_name().and_then(|n| n.to_str()) {
-                Some(d) => d.to_string(),
-                None => continue,
-            };
-
-            let cert_path = path.join("cert.pem");
-            let key_path = path.join("key.pem");
-
-            if !cert_path.exists() || !key_path.exists() {
-                continue;
+        for domain in self.store.list().await?.into_iter().map(FullDomain::from) {
+            match ownership::load(self.store.as_ref(), &domain).await {
+                Ok(Some(owner)) => {
+                    self.owners.insert(domain.clone(), owner);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to load ownership for {}: {}", domain, e),
             }
 
-            match self.load_cert_from_files(&cert_path, &key_path).await {
-                Ok(certified_key) => {
+            match self.load_cert(&domain).await {
+                Ok(Some(certified_key)) => {
                     info!("Loaded certificate for {}", domain);
                     self.certs.insert(domain, Arc::new(certified_key));
                 }
+                Ok(None) => {}
                 Err(e) => {
                     warn!("Failed to load certificate for {}: {}", domain, e);
                 }
@@ -88,15 +165,15 @@ impl CertManager {
         Ok(())
     }
 
-    async fn load_cert_from_files(
-        &self,
-        cert_path: &PathBuf,
-        key_path: &PathBuf,
-    ) -> Result<CertifiedKey> {
-        let cert_pem = fs::read_to_string(cert_path).await?;
-        let key_pem = fs::read_to_string(key_path).await?;
+    /// The stored certificate for `domain`, if it has both a certificate and a key
+    async fn load_cert(&self, domain: &str) -> Result<Option<CertifiedKey>> {
+        let cert_pem = self.store.get(Item::Cert(domain)).await?;
+        let key_pem = self.store.get(Item::Key(domain)).await?;
+        let (Some(cert_pem), Some(key_pem)) = (cert_pem, key_pem) else {
+            return Ok(None);
+        };
 
-        Self::parse_certificate(&cert_pem, &key_pem)
+        Self::parse_certificate(&String::from_utf8_lossy(&cert_pem), &String::from_utf8_lossy(&key_pem)).map(Some)
     }
 
     pub fn parse_certificate(cert_pem: &str, key_pem: &str) -> Result<CertifiedKey> {
@@ -119,7 +196,12 @@ impl CertManager {
         let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
             .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
 
-        Ok(CertifiedKey::new(certs, signing_key))
+        // A crash between writing a certificate and its key can leave them mismatched
+        let certified_key = CertifiedKey::new(certs, signing_key);
+        match certified_key.keys_match() {
+            Ok(()) | Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::Unknown)) => Ok(certified_key),
+            Err(e) => Err(anyhow::anyhow!("Certificate doesn't match its private key: {}", e)),
+        }
     }
 
     /// Get certificate for a domain, requesting one if not available
@@ -128,27 +210,59 @@ impl CertManager {
         self.certs.get(domain).map(|r| r.clone())
     }
 
-    /// Check if a certificate exists for a domain
+    /// Check if a certificate covering a domain exists. ACME base domain certificates
+    /// don't cover subdomains, but without ACME the certificate served for a subdomain
+    /// (e.g. a Cloudflare origin certificate) is all there is.
     pub fn has_cert(&self, domain: &str) -> bool {
+        if self.acme_client.is_none() {
+            return self.find_cert(domain).is_some();
+        }
         self.certs.contains_key(domain)
+            || self.wildcard_covers(domain) && self.certs.contains_key(&self.base_domain.wildcard())
+    }
+
+    /// Whether the base domain's wildcard covers `name`, which it does for a single
+    /// label only: `*.example.com` doesn't cover `a.b.example.com`
+    fn wildcard_covers(&self, name: &str) -> bool {
+        name.strip_suffix(&*self.base_domain)
+            .and_then(|rest| rest.strip_suffix('.'))
+            .is_some_and(|label| !label.is_empty() && !label.contains('.'))
+    }
+
+    /// The certificate to serve for a name: an exact match, else a wildcard or base
+    /// domain certificate for subdomains of the base domain
+    fn find_cert(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        if let Some(cert) = self.certs.get(server_name) {
+            return Some(cert.clone());
+        }
+
+        if server_name.ends_with(&format!(".{}", self.base_domain)) {
+            // Check for base domain wildcard cert
+            if self.wildcard_covers(server_name) {
+                if let Some(cert) = self.certs.get(&self.base_domain.wildcard()) {
+                    return Some(cert.clone());
+                }
+            }
+
+            // Check for base domain cert (some setups allow this)
+            if let Some(cert) = self.certs.get(&self.base_domain) {
+                return Some(cert.clone());
+            }
+        }
+
+        None
     }
 
     /// Add a certificate for a domain
     #[allow(dead_code)]
-    pub fn add_cert(&self, domain: &str, cert: CertifiedKey) {
-        self.certs.insert(domain.to_string(), Arc::new(cert));
+    pub fn add_cert(&self, domain: &FullDomain, cert: CertifiedKey) {
+        self.certs.insert(domain.clone(), Arc::new(cert));
     }
 
     /// Request a certificate for a domain (async)
-    pub async fn request_cert(&self, domain: &str) -> Result<()> {
-        // Check if already pending
-        if self.pending.contains_key(domain) {
-            debug!("Certificate request already pending for {}", domain);
-            return Ok(());
-        }
-
-        // Check if already have cert
-        if self.certs.contains_key(domain) {
+    pub async fn request_cert(&self, domain: &FullDomain) -> Result<()> {
+        // Check if already have cert, or a wildcard covering it
+        if self.has_cert(domain) {
             debug!("Certificate already exists for {}", domain);
             return Ok(());
         }
@@ -156,25 +270,65 @@ impl CertManager {
         let acme_client = match &self.acme_client {
             Some(c) => c.clone(),
             None => {
-                warn!("ACME not configured, cannot request certificate for {}", domain);
-                return Ok(());
+                anyhow::bail!("ACME not configured, cannot request certificate for {}", domain);
             }
         };
 
-        // Mark as pending
-        self.pending.insert(domain.to_string(), ());
+        // Mark as pending, unless another caller (e.g. the base domain bootstrap) already has
+        match self.pending.entry(domain.clone()) {
+            Entry::Occupied(_) => {
+                debug!("Certificate request already pending for {}", domain);
+                return Ok(());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(());
+            }
+        }
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            domain,
+        };
 
         info!("Requesting certificate for {}", domain);
 
-        let result = acme_client.request_certificate(domain).await;
+        self.issue(&acme_client, domain).await
+    }
+
+    /// Request a fresh certificate for a domain that already has one, replacing it
+    /// once issued
+    pub async fn renew_cert(&self, domain: &FullDomain) -> Result<()> {
+        let acme_client = match &self.acme_client {
+            Some(c) => c.clone(),
+            None => {
+                anyhow::bail!("ACME not configured, cannot renew certificate for {}", domain);
+            }
+        };
+
+        match self.pending.entry(domain.clone()) {
+            Entry::Occupied(_) => {
+                anyhow::bail!("another certificate request for {} is in progress", domain);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(());
+            }
+        }
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            domain,
+        };
+
+        info!("Renewing certificate for {}", domain);
+
+        self.issue(&acme_client, domain).await
+    }
 
-        // Remove pending status
-        self.pending.remove(domain);
+    async fn issue(&self, acme_client: &AcmeClient, domain: &FullDomain) -> Result<()> {
+        let result = acme_client.request_certificate(domain).await;
+        self.metrics.record_certificate_request(result.is_ok());
 
         match result {
             Ok(cert) => {
-                let certified_key = Self::parse_certificate(&cert.cert_pem, &cert.key_pem)?;
-                self.certs.insert(domain.to_string(), Arc::new(certified_key));
+                self.install_cert(domain, &cert.cert_pem, &cert.key_pem)?;
                 info!("Certificate installed for {}", domain);
                 Ok(())
             }
@@ -185,6 +339,135 @@ impl CertManager {
         }
     }
 
+    /// Serve `cert_pem` for a domain from now on. Handshakes already holding the
+    /// previous certificate finish with it.
+    pub fn install_cert(&self, domain: &FullDomain, cert_pem: &str, key_pem: &str) -> Result<()> {
+        let certified_key = Self::parse_certificate(cert_pem, key_pem)?;
+        self.certs.insert(domain.clone(), Arc::new(certified_key));
+        self.placeholders.remove(domain);
+        self.on_demand_asked.remove(domain);
+        Ok(())
+    }
+
+    /// Request certificates for subdomains whose handshakes find none, if `has_tunnel`
+    /// says a tunnel is on the subdomain. The requests arrive on the returned receiver,
+    /// for [`on_demand_task`].
+    pub fn issue_on_demand(
+        &self,
+        has_tunnel: impl Fn(&Subdomain) -> bool + Send + Sync + 'static,
+    ) -> mpsc::Receiver<FullDomain> {
+        let (requests, requests_rx) = mpsc::channel(ON_DEMAND_QUEUE);
+        let on_demand = OnDemand {
+            has_tunnel: Box::new(has_tunnel),
+            requests,
+        };
+        if self.on_demand.set(on_demand).is_err() {
+            warn!("Certificates are already issued on demand");
+        }
+        requests_rx
+    }
+
+    /// A certificate for a name with none yet: asks for the real one at most every
+    /// [`ON_DEMAND_INTERVAL`], serving a self-signed one meanwhile so visitors see a
+    /// certificate warning rather than a reset connection
+    fn on_demand_cert(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let on_demand = self.on_demand.get()?;
+        let domain = FullDomain::new(server_name);
+        if !domain.subdomain_of(&self.base_domain).is_some_and(|subdomain| (on_demand.has_tunnel)(&subdomain)) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let ask = match self.on_demand_asked.entry(domain.clone()) {
+            Entry::Occupied(mut entry) if now.duration_since(*entry.get()) >= ON_DEMAND_INTERVAL => {
+                entry.insert(now);
+                true
+            }
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        };
+        if ask && !self.is_pending(server_name) {
+            debug!("No certificate for {}, requesting one", server_name);
+            if on_demand.requests.try_send(domain.clone()).is_err() {
+                // Handshakes will ask again once the interval is up
+                warn!("Too many certificate requests queued, not requesting one for {}", server_name);
+            }
+        }
+
+        self.placeholder(&domain)
+    }
+
+    /// The self-signed certificate served for `domain` until its real one is issued
+    fn placeholder(&self, domain: &FullDomain) -> Option<Arc<CertifiedKey>> {
+        if let Some(placeholder) = self.placeholders.get(domain) {
+            return Some(placeholder.1.clone());
+        }
+
+        let generated = rcgen::generate_simple_self_signed(vec![domain.to_string()])
+            .map_err(anyhow::Error::from)
+            .and_then(|generated| {
+                let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()));
+                let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
+                    .map_err(|e| anyhow::anyhow!("Failed to create signing key: {:?}", e))?;
+                Ok(CertifiedKey::new(vec![generated.cert.der().clone()], signing_key))
+            });
+        let cert = match generated {
+            Ok(cert) => Arc::new(cert),
+            Err(e) => {
+                error!("Failed to generate a placeholder certificate for {}: {}", domain, e);
+                return None;
+            }
+        };
+
+        // Dropping only the oldest keeps the certificate, and so the browser warning,
+        // every other waiting name is served
+        if self.placeholders.len() >= MAX_PLACEHOLDERS {
+            let oldest = self
+                .placeholders
+                .iter()
+                .min_by_key(|placeholder| placeholder.value().0)
+                .map(|placeholder| placeholder.key().clone());
+            if let Some(oldest) = oldest {
+                self.placeholders.remove(&oldest);
+            }
+        }
+        self.placeholders.insert(domain.clone(), (Instant::now(), cert.clone()));
+        Some(cert)
+    }
+
+    /// Stored certificates that expire soon, or whose expiry can't be read. Wildcard
+    /// certificates are left alone unless DNS-01 is configured, as HTTP-01 can't
+    /// issue them.
+    pub async fn renewals_due(&self, now: SystemTime) -> Result<Vec<FullDomain>> {
+        let mut due = Vec::new();
+        for domain in self.store.list().await?.into_iter().map(FullDomain::from) {
+            if domain.starts_with("*.") && !self.issues_wildcards() {
+                continue;
+            }
+            let Some(cert_pem) = self.store.get(Item::Cert(&domain)).await? else {
+                continue;
+            };
+            if AcmeClient::needs_renewal(&String::from_utf8_lossy(&cert_pem), now) {
+                due.push(domain);
+            }
+        }
+        Ok(due)
+    }
+
+    /// Whether the base domain's wildcard certificate is requested too, which takes
+    /// an ACME client answering DNS-01 challenges
+    pub fn issues_wildcards(&self) -> bool {
+        self.acme_client.as_ref().is_some_and(|client| client.issues_wildcards())
+    }
+
+    /// Whether handshakes asking for `acme-tls/1` are answered, for TLS-ALPN-01
+    pub fn answers_tls_alpn(&self) -> bool {
+        self.tls_alpn.is_some()
+    }
+
     /// Check if a certificate request is pending
     #[allow(dead_code)]
     pub fn is_pending(&self, domain: &str) -> bool {
@@ -199,9 +482,299 @@ impl CertManager {
 
     /// Get base domain
     #[allow(dead_code)]
-    pub fn base_domain(&self) -> &str {
+    pub fn base_domain(&self) -> &FullDomain {
         &self.base_domain
     }
+
+    /// Check whether `token` may register `domain` under the ownership rules
+    pub fn check_ownership(
+        &self,
+        domain: &FullDomain,
+        token: &TokenSecret,
+        strict: bool,
+        expiry: Duration,
+    ) -> Result<(), String> {
+        let owner = self.owners.get(domain).map(|o| o.clone());
+        ownership::check(owner.as_ref(), token, strict, expiry, ownership::now_secs())
+    }
+
+    /// Record `token` as the owner of `domain`
+    pub async fn claim(&self, domain: &FullDomain, token: &TokenSecret) -> Result<()> {
+        let previous = self.owners.get(domain).map(|o| o.clone());
+        let owner = Ownership::claim(previous.as_ref(), token, ownership::now_secs());
+        self.owners.insert(domain.clone(), owner.clone());
+        ownership::save(self.store.as_ref(), domain, Some(&owner)).await
+    }
+
+    /// Drop the owner of `domain`, returning whether it had one
+    pub async fn release(&self, domain: &FullDomain) -> Result<bool> {
+        if self.owners.remove(domain).is_none() {
+            return Ok(false);
+        }
+        ownership::save(self.store.as_ref(), domain, None).await?;
+        Ok(true)
+    }
+
+    /// The ownership record of `domain`, if it has one
+    pub fn owner(&self, domain: &str) -> Option<Ownership> {
+        self.owners.get(domain).map(|o| o.clone())
+    }
+
+    /// Every domain the store keeps anything for, including certificates that
+    /// failed to load
+    pub async fn stored_domains(&self) -> Result<Vec<FullDomain>> {
+        Ok(self.store.list().await?.into_iter().map(FullDomain::from).collect())
+    }
+
+    /// Delete `domain`'s certificate, key and ownership record, from the store and
+    /// from memory
+    pub async fn remove(&self, domain: &FullDomain) -> Result<()> {
+        self.certs.remove(domain);
+        self.placeholders.remove(domain);
+        self.owners.remove(domain);
+        for item in [Item::Cert(domain), Item::Key(domain), Item::Meta(domain)] {
+            self.store.delete(item).await?;
+        }
+        Ok(())
+    }
+
+    /// All ownership records, sorted by domain
+    pub fn owners(&self) -> Vec<(FullDomain, Ownership)> {
+        let mut owners: Vec<_> = self
+            .owners
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        owners.sort_by(|a, b| a.0.cmp(&b.0));
+        owners
+    }
+
+    pub fn base_cert_state(&self) -> BaseCertState {
+        self.base_cert_state
+            .read()
+            .map(|s| s.clone())
+            .unwrap_or(BaseCertState::Pending)
+    }
+
+    fn set_base_cert_state(&self, state: BaseCertState) {
+        if let Ok(mut current) = self.base_cert_state.write() {
+            *current = state;
+        }
+    }
+}
+
+/// Clears a domain's pending mark however the request ends
+struct PendingGuard<'a> {
+    pending: &'a DashMap<FullDomain, ()>,
+    domain: &'a FullDomain,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(self.domain);
+    }
+}
+
+/// Delay after the given number of consecutive failures: 30s, 1m, 2m, ... capped at 10m
+fn bootstrap_delay(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    BOOTSTRAP_BASE_DELAY
+        .saturating_mul(factor)
+        .min(BOOTSTRAP_MAX_DELAY)
+}
+
+/// Keep requesting the base domain certificate, and its wildcard with DNS-01, until
+/// they're installed, backing off between failures. A reload (SIGHUP) retries
+/// immediately.
+pub async fn base_cert_bootstrap_task(
+    cert_manager: Arc<CertManager>,
+    reload_rx: broadcast::Receiver<()>,
+    shutdown_rx: broadcast::Receiver<()>,
+) {
+    let manager = cert_manager.clone();
+    let attempt = move || {
+        let manager = manager.clone();
+        async move {
+            let base_domain = &manager.base_domain;
+            let wildcard = manager.issues_wildcards().then(|| base_domain.wildcard());
+            for domain in std::iter::once(base_domain).chain(&wildcard) {
+                manager.request_cert(domain).await?;
+                if !manager.has_cert(domain) {
+                    // A registration for the base domain holds the pending mark
+                    anyhow::bail!("another certificate request for {} is in progress", domain);
+                }
+            }
+            Ok(())
+        }
+    };
+    retry_until_ready(
+        attempt,
+        |state| cert_manager.set_base_cert_state(state),
+        reload_rx,
+        shutdown_rx,
+    )
+    .await;
+}
+
+async fn retry_until_ready<F, Fut>(
+    mut attempt: F,
+    report: impl Fn(BaseCertState),
+    mut reload_rx: broadcast::Receiver<()>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut failures = 0;
+    loop {
+        report(BaseCertState::Requesting {
+            attempt: failures + 1,
+        });
+        let error = match attempt().await {
+            Ok(()) => {
+                report(BaseCertState::Ready);
+                info!("Base domain certificate ready - clients can now connect via https://");
+                return;
+            }
+            Err(e) => e,
+        };
+
+        failures += 1;
+        let delay = bootstrap_delay(failures);
+        warn!(
+            "Failed to get base domain certificate (attempt {}): {:#}. Retrying in {}; clients should connect via http:// until then.",
+            failures,
+            error,
+            units::format_duration(delay)
+        );
+        report(BaseCertState::Failed {
+            attempts: failures,
+            error: format!("{:#}", error),
+            retry_in_secs: delay.as_secs(),
+        });
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            Ok(()) = reload_rx.recv() => {
+                info!("Reload requested, retrying base domain certificate now");
+            }
+            _ = shutdown_rx.recv() => return,
+        }
+    }
+}
+
+/// Delay after the given number of consecutive failures to renew a certificate:
+/// 5m, 10m, 20m, ... capped at 6h
+fn renewal_retry_delay(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    RENEWAL_RETRY_BASE_DELAY
+        .saturating_mul(factor)
+        .min(RENEWAL_RETRY_MAX_DELAY)
+}
+
+/// Renew certificates as they near expiry, checking the store every
+/// [`RENEWAL_CHECK_INTERVAL`] and retrying failures with backoff
+pub async fn certificate_renewal_task(
+    cert_manager: Arc<CertManager>,
+    shutdown_rx: broadcast::Receiver<()>,
+) {
+    let manager = cert_manager.clone();
+    let due = move || {
+        let manager = manager.clone();
+        async move {
+            info!("Checking certificates for renewal...");
+            match manager.renewals_due(SystemTime::now()).await {
+                Ok(due) => due,
+                Err(e) => {
+                    error!("Failed to list certificates in {}: {}", manager.store.describe(), e);
+                    Vec::new()
+                }
+            }
+        }
+    };
+    let renew = move |domain: FullDomain| {
+        let manager = cert_manager.clone();
+        async move { manager.renew_cert(&domain).await }
+    };
+    renew_when_due(due, renew, shutdown_rx).await;
+}
+
+/// Request the certificates handshakes found missing, each in the background so a
+/// slow order doesn't hold up the rest
+pub async fn on_demand_task(
+    cert_manager: Arc<CertManager>,
+    mut requests: mpsc::Receiver<FullDomain>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    loop {
+        let domain = tokio::select! {
+            Some(domain) = requests.recv() => domain,
+            _ = shutdown_rx.recv() => return,
+            else => return,
+        };
+        let manager = cert_manager.clone();
+        tokio::spawn(async move {
+            info!("A visitor reached {} before its certificate was issued", domain);
+            // Failures are logged as they happen; the next handshake after the interval retries
+            let _ = manager.request_cert(&domain).await;
+        });
+    }
+}
+
+/// A failed renewal, and when to try it again
+struct Retry {
+    failures: u32,
+    at: Instant,
+}
+
+async fn renew_when_due<D, DueFut, R, RenewFut>(
+    mut due: D,
+    mut renew: R,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) where
+    D: FnMut() -> DueFut,
+    DueFut: Future<Output = Vec<FullDomain>>,
+    R: FnMut(FullDomain) -> RenewFut,
+    RenewFut: Future<Output = Result<()>>,
+{
+    let mut retries: HashMap<FullDomain, Retry> = HashMap::new();
+    loop {
+        let mut wake = Instant::now() + RENEWAL_CHECK_INTERVAL;
+        let domains = due().await;
+        retries.retain(|domain, _| domains.contains(domain));
+
+        for domain in domains {
+            if let Some(retry) = retries.get(&domain).filter(|r| r.at > Instant::now()) {
+                wake = wake.min(retry.at);
+                continue;
+            }
+            let error = match renew(domain.clone()).await {
+                Ok(()) => {
+                    retries.remove(&domain);
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let failures = retries.get(&domain).map_or(0, |r| r.failures) + 1;
+            let delay = renewal_retry_delay(failures);
+            warn!(
+                "Failed to renew certificate for {} (attempt {}): {:#}. Retrying in {}.",
+                domain,
+                failures,
+                error,
+                units::format_duration(delay)
+            );
+            let at = Instant::now() + delay;
+            wake = wake.min(at);
+            retries.insert(domain, Retry { failures, at });
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake) => {}
+            _ = shutdown_rx.recv() => return,
+        }
+    }
 }
 
 /// Implements rustls ResolvesServerCert for SNI-based certificate selection
@@ -211,36 +784,521 @@ impl ResolvesServerCert for CertManager {
         
         debug!("SNI resolution for: {}", server_name);
 
-        // Try exact match first
-        if let Some(cert) = self.certs.get(server_name) {
-            return Some(cert.clone());
-        }
-
-        // Try wildcard match for subdomain.base_domain
-        if server_name.ends_with(&format!(".{}", self.base_domain)) {
-            // Check for base domain wildcard cert
-            let wildcard = format!("*.{}", self.base_domain);
-            if let Some(cert) = self.certs.get(&wildcard) {
-                return Some(cert.clone());
-            }
-
-            // Check for base domain cert (some setups allow this)
-            if let Some(cert) = self.certs.get(&self.base_domain) {
-                return Some(cert.clone());
+        // Validators offer only `acme-tls/1`, and get the challenge certificate or nothing
+        let mut protocols = client_hello.alpn().into_iter().flatten();
+        if protocols.next() == Some(ACME_TLS_ALPN) && protocols.next().is_none() {
+            let cert = self.tls_alpn.as_ref().and_then(|challenges| challenges.get(server_name));
+            if cert.is_none() {
+                debug!("No TLS-ALPN-01 challenge for {}", server_name);
             }
+            return cert;
         }
 
-        // No cert found - will trigger certificate request
-        debug!("No certificate found for {}", server_name);
-        None
+        let cert = self.find_cert(server_name).or_else(|| self.on_demand_cert(server_name));
+        if cert.is_none() {
+            debug!("No certificate found for {}", server_name);
+        }
+        cert
     }
 }
 
 /// Create a rustls ServerConfig with the CertManager
 pub fn create_tls_config(cert_manager: Arc<CertManager>) -> Result<rustls::ServerConfig> {
-    let config = rustls::ServerConfig::builder()
+    let answers_tls_alpn = cert_manager.answers_tls_alpn();
+    let mut config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_cert_resolver(cert_manager);
+    // Once any protocol is listed, clients offering none of them are refused, so the
+    // ones the server speaks, as axum-server lists them, go first
+    if answers_tls_alpn {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    }
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::acme::test_certificate;
+    use crate::server::cert_store::FsCertStore;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_bootstrap_delay() {
+        let delays: Vec<u64> = (1..=7).map(|n| bootstrap_delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![30, 60, 120, 240, 480, 600, 600]);
+        assert_eq!(bootstrap_delay(u32::MAX), BOOTSTRAP_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_renewal_retry_delay() {
+        let delays: Vec<u64> = (1..=8).map(|n| renewal_retry_delay(n).as_secs() / 60).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 80, 160, 320, 360]);
+        assert_eq!(renewal_retry_delay(u32::MAX), RENEWAL_RETRY_MAX_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_renewal_retries_with_backoff() {
+        const MINUTE: Duration = Duration::from_secs(60);
+        let started = Instant::now();
+        let renewed = Arc::new(Mutex::new(false));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+
+        let due = {
+            let renewed = renewed.clone();
+            move || {
+                let due = if *renewed.lock().unwrap() { vec![] } else { vec![FullDomain::new("app.example.com")] };
+                async move { due }
+            }
+        };
+        let renew = {
+            let renewed = renewed.clone();
+            let attempts = attempts.clone();
+            move |_domain: FullDomain| {
+                let mut attempts = attempts.lock().unwrap();
+                attempts.push(started.elapsed());
+                let result = if attempts.len() <= 2 {
+                    Err(anyhow::anyhow!("DNS problem: NXDOMAIN"))
+                } else {
+                    *renewed.lock().unwrap() = true;
+                    Ok(())
+                };
+                async move { result }
+            }
+        };
+        let task = tokio::spawn(renew_when_due(due, renew, shutdown_rx));
+
+        // Not a day's wait after a failure, and nothing more once renewed
+        tokio::time::sleep(RENEWAL_CHECK_INTERVAL * 3).await;
+        shutdown_tx.send(()).unwrap();
+        task.await.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), vec![Duration::ZERO, 5 * MINUTE, 15 * MINUTE]);
+    }
+
+    #[tokio::test]
+    async fn test_renewed_certificate_is_served_at_once() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        for (domain, valid_for) in [("app.example.com", 10 * DAY), ("api.example.com", 60 * DAY), ("*.example.com", DAY)] {
+            let cert = test_certificate(domain, valid_for);
+            store.put(Item::Cert(domain), cert.cert_pem.as_bytes()).await.unwrap();
+            store.put(Item::Key(domain), cert.key_pem.as_bytes()).await.unwrap();
+        }
+        let manager = CertManager::new(
+            store.clone(),
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+
+        // Only the short-lived certificate HTTP-01 can reissue
+        let due = manager.renewals_due(SystemTime::now()).await.unwrap();
+        assert_eq!(due, vec![FullDomain::new("app.example.com")]);
+
+        let before = manager.find_cert("app.example.com").unwrap();
+        let renewed = test_certificate("app.example.com", 90 * DAY);
+        store.put(Item::Cert("app.example.com"), renewed.cert_pem.as_bytes()).await.unwrap();
+        store.put(Item::Key("app.example.com"), renewed.key_pem.as_bytes()).await.unwrap();
+        manager.install_cert(&FullDomain::new("app.example.com"), &renewed.cert_pem, &renewed.key_pem).unwrap();
+
+        let after = manager.find_cert("app.example.com").unwrap();
+        assert_ne!(after.cert[0], before.cert[0]);
+        let served = rustls_pemfile::certs(&mut renewed.cert_pem.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(after.cert[0], served);
+        assert!(manager.renewals_due(SystemTime::now()).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_key_is_refused() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let cert = test_certificate("app.example.com", 60 * DAY);
+        let other = test_certificate("app.example.com", 60 * DAY);
+        assert!(CertManager::parse_certificate(&cert.cert_pem, &cert.key_pem).is_ok());
+        let err = CertManager::parse_certificate(&cert.cert_pem, &other.key_pem).err().unwrap();
+        assert!(err.to_string().contains("doesn't match its private key"), "{}", err);
+
+        // Not loaded (so it's requested again), nor installed over a working one
+        store.put(Item::Cert("app.example.com"), cert.cert_pem.as_bytes()).await.unwrap();
+        store.put(Item::Key("app.example.com"), other.key_pem.as_bytes()).await.unwrap();
+        let manager = CertManager::new(
+            store.clone(),
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        assert!(manager.find_cert("app.example.com").is_none());
+        let domain = FullDomain::new("app.example.com");
+        manager.install_cert(&domain, &other.cert_pem, &other.key_pem).unwrap();
+        assert!(manager.install_cert(&domain, &cert.cert_pem, &other.key_pem).is_err());
+        let served = rustls_pemfile::certs(&mut other.cert_pem.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(manager.find_cert("app.example.com").unwrap().cert[0], served);
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missing_certificates_are_requested_on_demand() {
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let manager = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        assert!(manager.on_demand_cert("app.example.com").is_none());
+        let mut requests = manager.issue_on_demand(|subdomain| *subdomain == "app");
+
+        // A burst of handshakes asks once, each getting the same stand-in certificate
+        let placeholder = manager.on_demand_cert("app.example.com").unwrap();
+        for _ in 0..10 {
+            let again = manager.on_demand_cert("app.example.com").unwrap();
+            assert!(Arc::ptr_eq(&again, &placeholder));
+        }
+        assert_eq!(requests.try_recv().unwrap(), "app.example.com");
+        assert!(requests.try_recv().is_err());
+
+        // Only subdomains with a tunnel on them
+        for name in ["api.example.com", "a.app.example.com", "example.com", "app.example.org"] {
+            assert!(manager.on_demand_cert(name).is_none(), "{}", name);
+        }
+        assert!(requests.try_recv().is_err());
+
+        tokio::time::advance(ON_DEMAND_INTERVAL).await;
+        manager.on_demand_cert("app.example.com").unwrap();
+        assert_eq!(requests.try_recv().unwrap(), "app.example.com");
+
+        // The real certificate replaces the stand-in
+        let cert = test_certificate("app.example.com", Duration::from_secs(90 * 24 * 60 * 60));
+        manager.install_cert(&FullDomain::new("app.example.com"), &cert.cert_pem, &cert.key_pem).unwrap();
+        assert!(manager.placeholders.is_empty());
+        let served = manager.find_cert("app.example.com").unwrap();
+        assert_ne!(served.cert[0], placeholder.cert[0]);
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_oldest_placeholder_evicted() {
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let manager = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+
+        let first = manager.placeholder(&FullDomain::new("first.example.com")).unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        for i in 1..MAX_PLACEHOLDERS {
+            let stand_in = (Instant::now(), first.clone());
+            manager.placeholders.insert(FullDomain::from(format!("app{}.example.com", i)), stand_in);
+        }
+
+        manager.placeholder(&FullDomain::new("new.example.com")).unwrap();
+        assert_eq!(manager.placeholders.len(), MAX_PLACEHOLDERS);
+        assert!(!manager.placeholders.contains_key("first.example.com"));
+        assert!(manager.placeholders.contains_key("app1.example.com"));
+        assert!(manager.placeholders.contains_key("new.example.com"));
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_certificate_covers_subdomains() {
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let manager = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        let wildcard = manager.base_domain().wildcard();
+        let cert = test_certificate(&wildcard, Duration::from_secs(90 * 24 * 60 * 60));
+        manager.install_cert(&wildcard, &cert.cert_pem, &cert.key_pem).unwrap();
+        assert!(!manager.issues_wildcards());
+
+        // Subdomains need no certificate of their own, so none is requested
+        let app = FullDomain::new("app.example.com");
+        assert!(manager.has_cert(&app));
+        assert!(!manager.has_cert(&FullDomain::new("a.app.example.com")));
+        manager.request_cert(&app).await.unwrap();
+        assert!(manager.get_cert(&app).is_none());
+        assert!(Arc::ptr_eq(&manager.find_cert(&app).unwrap(), &manager.get_cert(&wildcard).unwrap()));
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    /// Takes whatever certificate the server presents without checking it, as
+    /// webpki refuses the critical acmeIdentifier extension of challenge certificates
+    #[derive(Debug)]
+    struct AcceptAny(Arc<rustls::crypto::CryptoProvider>);
+
+    impl rustls::client::danger::ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &rustls::pki_types::ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: rustls::pki_types::UnixTime,
+        ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    /// Handshake in memory with `server` for `name`, offering `protocols`, returning
+    /// the protocol agreed on and the certificate served
+    fn handshake(
+        server: &Arc<rustls::ServerConfig>,
+        name: &str,
+        protocols: &[&[u8]],
+    ) -> Result<(Option<Vec<u8>>, CertificateDer<'static>), rustls::Error> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny(provider)))
+            .with_no_client_auth();
+        config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        let name = rustls::pki_types::ServerName::try_from(name.to_string()).unwrap();
+        let mut client = rustls::Connection::from(rustls::ClientConnection::new(Arc::new(config), name).unwrap());
+        let mut server = rustls::Connection::from(rustls::ServerConnection::new(server.clone()).unwrap());
+
+        fn transfer(from: &mut rustls::Connection, to: &mut rustls::Connection) -> Result<(), rustls::Error> {
+            let mut buffer = Vec::new();
+            while from.wants_write() {
+                from.write_tls(&mut buffer).unwrap();
+            }
+            let mut rest = &buffer[..];
+            while !rest.is_empty() {
+                to.read_tls(&mut rest).unwrap();
+                to.process_new_packets()?;
+            }
+            Ok(())
+        }
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server)?;
+            transfer(&mut server, &mut client)?;
+        }
+        let cert = client.peer_certificates().unwrap()[0].clone().into_owned();
+        Ok((client.alpn_protocol().map(<[u8]>::to_vec), cert))
+    }
+
+    #[tokio::test]
+    async fn test_tls_alpn_challenges_are_answered() {
+        use crate::server::acme::tls_alpn_certificate;
+
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        // As main does; another test may have got there first
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let mut manager = CertManager::new(
+            store,
+            None,
+            Arc::new(ChallengeStore::new()),
+            FullDomain::new("example.com"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+        let challenges = Arc::new(TlsAlpnChallenges::default());
+        manager.tls_alpn = Some(challenges.clone());
+        let cert = test_certificate("app.example.com", Duration::from_secs(90 * 24 * 60 * 60));
+        manager.install_cert(&FullDomain::new("app.example.com"), &cert.cert_pem, &cert.key_pem).unwrap();
+        let real = manager.find_cert("app.example.com").unwrap().cert[0].clone();
+        let config = Arc::new(create_tls_config(Arc::new(manager)).unwrap());
+
+        // Visitors get the real certificate, whether or not they use ALPN
+        let (protocol, served) = handshake(&config, "app.example.com", &[b"h2", b"http/1.1"]).unwrap();
+        assert_eq!((protocol.as_deref(), &served), (Some(&b"h2"[..]), &real));
+        let (protocol, _) = handshake(&config, "app.example.com", &[b"h2"]).unwrap();
+        assert_eq!(protocol.as_deref(), Some(&b"h2"[..]));
+        let (protocol, _) = handshake(&config, "app.example.com", &[b"http/1.1"]).unwrap();
+        assert_eq!(protocol.as_deref(), Some(&b"http/1.1"[..]));
+        let (protocol, served) = handshake(&config, "app.example.com", &[]).unwrap();
+        assert_eq!((protocol, &served), (None, &real));
+
+        // Validators get the challenge certificate while it's being validated, and
+        // nothing otherwise
+        assert!(handshake(&config, "app.example.com", &[ACME_TLS_ALPN]).is_err());
+        let challenge = tls_alpn_certificate("app.example.com", &[1; 32]).unwrap();
+        let challenge_cert = challenge.cert[0].clone();
+        let guard = challenges.set_guarded("app.example.com", challenge);
+        let (protocol, served) = handshake(&config, "app.example.com", &[ACME_TLS_ALPN]).unwrap();
+        assert_eq!((protocol.as_deref(), &served), (Some(ACME_TLS_ALPN), &challenge_cert));
+        assert!(handshake(&config, "api.example.com", &[ACME_TLS_ALPN]).is_err());
+        let (_, served) = handshake(&config, "app.example.com", &[b"http/1.1"]).unwrap();
+        assert_eq!(served, real);
+
+        drop(guard);
+        assert!(handshake(&config, "app.example.com", &[ACME_TLS_ALPN]).is_err());
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    /// A whole TLS-ALPN-01 order against Pebble, Let's Encrypt's test server. Start it
+    /// and its DNS server, which answers 127.0.0.1 for every name:
+    ///
+    /// ```text
+    /// docker run -d --network host ghcr.io/letsencrypt/pebble-challtestsrv -defaultIPv4 127.0.0.1
+    /// docker run -d --network host -e PEBBLE_VA_NOSLEEP=1 ghcr.io/letsencrypt/pebble -dnsserver 127.0.0.1:8053
+    /// ```
+    ///
+    /// then run it with `PEBBLE_CA` pointing at Pebble's `test/certs/pebble.minica.pem`:
+    /// `cargo test test_pebble_tls_alpn_order -- --ignored`. Pebble validates on port
+    /// 5001, which the test listens on; `PEBBLE_DIRECTORY` overrides where it's found.
+    #[tokio::test]
+    #[ignore = "needs Pebble running"]
+    async fn test_pebble_tls_alpn_order() {
+        let directory = std::env::var("PEBBLE_DIRECTORY").unwrap_or_else(|_| "https://localhost:14000/dir".to_string());
+        let ca = std::fs::read(std::env::var("PEBBLE_CA").expect("PEBBLE_CA should be Pebble's minica certificate")).unwrap();
+        let certs_dir = std::env::temp_dir().join(format!("loophole-certs-{}", uuid::Uuid::new_v4()));
+        let store: Arc<dyn CertStore> = Arc::new(FsCertStore::new(certs_dir.clone()).await.unwrap());
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let challenge_store = Arc::new(ChallengeStore::new());
+        let acme = AcmeClient::new_with_roots("test@example.com", &directory, store.clone(), challenge_store.clone(), Some(&ca))
+            .await
+            .unwrap()
+            .with_tls_alpn();
+        let acme = Arc::new(acme);
+        let manager = CertManager::new(
+            store,
+            Some(acme.clone()),
+            challenge_store,
+            FullDomain::new("loophole.test"),
+            Arc::new(Metrics::new()),
+        )
+        .await
+        .unwrap();
+
+        // The HTTPS listener, as the server runs it, where Pebble comes to validate
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(create_tls_config(Arc::new(manager)).unwrap()));
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:5001").await.unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(stream).await;
+                });
+            }
+        });
+
+        let cert = acme.request_certificate("app.loophole.test").await.unwrap();
+        let pem = rustls_pemfile::certs(&mut cert.cert_pem.as_bytes()).next().unwrap().unwrap();
+        let (_, parsed) = x509_parser::parse_x509_certificate(&pem).unwrap();
+        let names = parsed.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(names.value.general_names, [x509_parser::extensions::GeneralName::DNSName("app.loophole.test")]);
+
+        std::fs::remove_dir_all(certs_dir).unwrap();
+    }
+
+    /// Run the retry loop against an attempt that fails `failures` times, e.g. while DNS
+    /// isn't pointing at the server yet
+    async fn bootstrap(
+        failures: u32,
+        reload_rx: broadcast::Receiver<()>,
+    ) -> Vec<BaseCertState> {
+        let states = Mutex::new(Vec::new());
+        let mut calls = 0;
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        retry_until_ready(
+            || {
+                calls += 1;
+                let result = if calls <= failures {
+                    Err(anyhow::anyhow!("DNS problem: NXDOMAIN"))
+                } else {
+                    Ok(())
+                };
+                async move { result }
+            },
+            |state| states.lock().unwrap().push(state),
+            reload_rx,
+            shutdown_rx,
+        )
+        .await;
+        states.into_inner().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bootstrap_retries_until_issued() {
+        let (_reload_tx, reload_rx) = broadcast::channel(1);
+        let started = tokio::time::Instant::now();
+
+        let states = bootstrap(2, reload_rx).await;
+
+        // Backed off 30s then 60s before the third attempt succeeded
+        assert_eq!(started.elapsed(), Duration::from_secs(90));
+        assert_eq!(states.last(), Some(&BaseCertState::Ready));
+        assert_eq!(
+            states[3],
+            BaseCertState::Failed {
+                attempts: 2,
+                error: "DNS problem: NXDOMAIN".to_string(),
+                retry_in_secs: 60,
+            }
+        );
+        assert_eq!(states[4], BaseCertState::Requesting { attempt: 3 });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bootstrap_retries_on_reload() {
+        let (reload_tx, reload_rx) = broadcast::channel(1);
+        let started = tokio::time::Instant::now();
+
+        let task = tokio::spawn(bootstrap(1, reload_rx));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        reload_tx.send(()).unwrap();
+
+        let states = task.await.unwrap();
+        assert_eq!(states.last(), Some(&BaseCertState::Ready));
+        assert!(started.elapsed() < BOOTSTRAP_BASE_DELAY);
+    }
+}
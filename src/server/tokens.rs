@@ -0,0 +1,305 @@
+//! The tokens the server accepts. They start out as the config's `[tokens]`; admins
+//! can create and revoke tokens at runtime through `/_admin/tokens`, without a restart
+//! that would drop every tunnel. Those changes are kept in `tokens.json` in
+//! `server.state_dir`, or next to the config file when that isn't set, and applied on
+//! top of the config at startup. With neither, they last until the server restarts.
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::config::{Config, TokenConfig};
+use super::names::TokenSecret;
+
+pub const TOKENS_FILE: &str = "tokens.json";
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("Token not found")]
+    NotFound,
+    #[error("Can't revoke the only admin token; create another admin token first")]
+    LastAdmin,
+}
+
+/// Changes made through the admin API, as saved in `tokens.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenChanges {
+    #[serde(default)]
+    created: BTreeMap<String, TokenConfig>,
+    /// Config tokens stay revoked even while they're still in the config
+    #[serde(default)]
+    revoked: BTreeSet<String>,
+}
+
+/// Lookups take a [`TokenSecret`], or the token as it arrived in a request
+pub struct TokenStore {
+    tokens: DashMap<TokenSecret, TokenConfig>,
+    /// limits.max_tunnels_per_token, for tokens without their own max_tunnels
+    default_max_tunnels: usize,
+    /// limits.max_requests_per_second, for tokens without their own
+    default_max_requests_per_second: u32,
+    /// Where changes are saved; None keeps them in memory until the server stops
+    path: Option<PathBuf>,
+    /// Also serializes changes, so two admins can't interleave saves
+    changes: Mutex<TokenChanges>,
+}
+
+impl TokenStore {
+    /// The config's tokens, with changes kept in memory only
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tokens: config.tokens.iter().map(|(token, t)| (TokenSecret::new(token), t.clone())).collect(),
+            default_max_tunnels: config.limits.max_tunnels_per_token,
+            default_max_requests_per_second: config.limits.max_requests_per_second,
+            path: None,
+            changes: Mutex::new(TokenChanges::default()),
+        }
+    }
+
+    /// The config's tokens with the changes saved in `path` applied, saving new ones there
+    pub fn load(config: &Config, path: PathBuf) -> Result<Self> {
+        let changes: TokenChanges = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Invalid {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => TokenChanges::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        if !changes.created.is_empty() || !changes.revoked.is_empty() {
+            info!(
+                "Applying {} created and {} revoked token(s) from {}",
+                changes.created.len(),
+                changes.revoked.len(),
+                path.display()
+            );
+        }
+
+        let store = Self::new(config);
+        for (token, token_config) in &changes.created {
+            store.tokens.insert(TokenSecret::new(token), token_config.clone());
+        }
+        for token in &changes.revoked {
+            store.tokens.remove(token.as_str());
+        }
+        Ok(Self {
+            path: Some(path),
+            changes: Mutex::new(changes),
+            ..store
+        })
+    }
+
+    /// `tokens.json` in `state_dir`
+    pub fn path_in(state_dir: &Path) -> PathBuf {
+        state_dir.join(TOKENS_FILE)
+    }
+
+    pub fn get(&self, token: &(impl Borrow<str> + ?Sized)) -> Option<TokenConfig> {
+        self.tokens.get(token.borrow()).map(|t| t.clone())
+    }
+
+    /// Whether `token` is valid and has admin privileges
+    pub fn is_admin(&self, token: &(impl Borrow<str> + ?Sized)) -> bool {
+        self.tokens.get(token.borrow()).is_some_and(|t| t.admin)
+    }
+
+    /// Most tunnels `token` may have connected at once (0 = no limit)
+    pub fn max_tunnels_for(&self, token: &(impl Borrow<str> + ?Sized)) -> usize {
+        self.tokens
+            .get(token.borrow())
+            .and_then(|t| t.max_tunnels)
+            .unwrap_or(self.default_max_tunnels)
+    }
+
+    /// Requests per second each of `token`'s tunnels may be sent (0 = no limit)
+    pub fn max_requests_per_second_for(&self, token: &(impl Borrow<str> + ?Sized)) -> u32 {
+        self.tokens
+            .get(token.borrow())
+            .and_then(|t| t.max_requests_per_second)
+            .unwrap_or(self.default_max_requests_per_second)
+    }
+
+    /// `token`'s weight in the fair queue
+    pub fn weight_for(&self, token: &(impl Borrow<str> + ?Sized)) -> u32 {
+        self.tokens.get(token.borrow()).and_then(|t| t.weight).unwrap_or(1)
+    }
+
+    /// Every token, sorted
+    pub fn list(&self) -> Vec<(TokenSecret, TokenConfig)> {
+        let mut tokens: Vec<_> = self.tokens.iter().map(|t| (t.key().clone(), t.value().clone())).collect();
+        tokens.sort_by(|a, b| a.0.expose().cmp(b.0.expose()));
+        tokens
+    }
+
+    /// Add a new random token, valid immediately. Also returns whether it was saved: if
+    /// not, it works until the server restarts.
+    pub fn create(&self, token_config: TokenConfig) -> (TokenSecret, bool) {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        let token = TokenSecret::from(crate::init::generate_token("tk"));
+        changes.created.insert(token.expose().to_string(), token_config.clone());
+        self.tokens.insert(token.clone(), token_config);
+        (token, self.save(&changes))
+    }
+
+    /// Stop accepting `token` immediately, returning whether that was saved
+    pub fn revoke(&self, token: &(impl Borrow<str> + ?Sized)) -> Result<bool, TokenError> {
+        let token: &str = token.borrow();
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(revoked) = self.get(token) else {
+            return Err(TokenError::NotFound);
+        };
+        // Losing every admin token would lock admins out until a restart
+        if revoked.admin && self.tokens.iter().filter(|t| t.admin).count() == 1 {
+            return Err(TokenError::LastAdmin);
+        }
+
+        self.tokens.remove(token);
+        if changes.created.remove(token).is_none() {
+            changes.revoked.insert(token.to_string());
+        }
+        Ok(self.save(&changes))
+    }
+
+    /// Replace `tokens.json` atomically, readable only by the server's user. False if
+    /// there's nowhere to save or it failed.
+    fn save(&self, changes: &TokenChanges) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let written = serde_json::to_vec_pretty(changes)
+            .map_err(std::io::Error::from)
+            .and_then(|content| replace_private(path, &content));
+        if let Err(e) = written {
+            warn!("Failed to save tokens to {}, the change lasts until restart: {}", path.display(), e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Replace the file at `path` with `content` atomically, readable only by the server's
+/// user: written beside it under a temporary name, then renamed over it
+pub fn replace_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("state");
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, uuid::Uuid::new_v4()));
+    let written = write_private(&temp, content).and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+pub(super) fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::parse(
+            r#"
+[server]
+domain = "tunnel.example.com"
+
+[tokens.tk_admin]
+admin = true
+[tokens.tk_alice]
+max_tunnels = 2
+max_requests_per_second = 0
+
+[limits]
+max_requests_per_second = 20
+"#,
+        )
+        .unwrap()
+    }
+
+    fn temp_path() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loophole-tokens-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(TOKENS_FILE)
+    }
+
+    #[test]
+    fn test_create_and_revoke() {
+        let store = TokenStore::new(&config());
+        assert!(store.is_admin("tk_admin"));
+        assert_eq!(store.max_tunnels_for("tk_alice"), 2);
+        assert_eq!(store.max_requests_per_second_for("tk_alice"), 0);
+        assert_eq!(store.max_requests_per_second_for("tk_admin"), 20);
+
+        // Nowhere to save them, but they apply all the same
+        let (token, saved) = store.create(TokenConfig { weight: Some(3), ..Default::default() });
+        assert!(!saved);
+        assert!(token.expose().starts_with("tk_"));
+        assert_eq!(store.weight_for(&token), 3);
+        assert!(!store.is_admin(&token));
+
+        assert!(!store.revoke(&token).unwrap());
+        store.revoke("tk_alice").unwrap();
+        assert!(store.get(&token).is_none());
+        assert!(store.get("tk_alice").is_none());
+        assert!(matches!(store.revoke("tk_alice"), Err(TokenError::NotFound)));
+    }
+
+    #[test]
+    fn test_keeps_an_admin_token() {
+        let store = TokenStore::new(&config());
+        assert!(matches!(store.revoke("tk_admin"), Err(TokenError::LastAdmin)));
+        assert!(store.is_admin("tk_admin"));
+
+        // Rotating it is fine
+        let (new_admin, _) = store.create(TokenConfig { admin: true, ..Default::default() });
+        store.revoke("tk_admin").unwrap();
+        assert!(store.is_admin(&new_admin));
+    }
+
+    #[test]
+    fn test_changes_survive_restart() {
+        let path = temp_path();
+        let store = TokenStore::load(&config(), path.clone()).unwrap();
+        let (created, saved) = store.create(TokenConfig::default());
+        assert!(saved);
+        assert!(store.revoke("tk_alice").unwrap());
+
+        // A revoked config token stays revoked although the config still has it
+        let restarted = TokenStore::load(&config(), path.clone()).unwrap();
+        assert!(restarted.get(&created).is_some());
+        assert!(restarted.get("tk_alice").is_none());
+        assert!(restarted.is_admin("tk_admin"));
+
+        // Revoking a created token just forgets it
+        assert!(restarted.revoke(&created).unwrap());
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved, serde_json::json!({ "created": {}, "revoked": ["tk_alice"] }));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_file_is_an_error() {
+        let path = temp_path();
+        fs::write(&path, "{ not json").unwrap();
+        let err = TokenStore::load(&config(), path.clone()).err().unwrap();
+        assert!(format!("{:#}", err).contains("Invalid"), "{:#}", err);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
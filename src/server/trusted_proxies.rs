@@ -0,0 +1,135 @@
+//! Load balancers and reverse proxies in front of the server (`server.trusted_proxies`),
+//! such as Caddy or an AWS load balancer. A request from one of them says who the
+//! visitor is in X-Forwarded-For and how they connected in X-Forwarded-Proto. From any
+//! other peer those headers are ignored, since a visitor can send whatever it likes;
+//! the proxy replaces them before a request reaches a tunnel either way.
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    // IPv4 peers can show up as IPv4-mapped IPv6 on dual-stack listeners
+    let ip = ip.to_canonical();
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// The visitor's address: the rightmost X-Forwarded-For hop that isn't a trusted proxy,
+/// since everything to its left was written by the visitor. The peer itself when it
+/// isn't trusted.
+pub fn client_ip(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !is_trusted(trusted, peer) {
+        return peer;
+    }
+    let hops: Vec<Option<IpAddr>> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .map(parse_hop)
+        .collect();
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        // A hop no trusted proxy would write ends the chain we can believe
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(trusted, ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// An X-Forwarded-For entry, which some proxies give with a port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// Whether a trusted proxy says the visitor connected over HTTPS (it terminated TLS):
+/// the rightmost X-Forwarded-Proto value, the one the proxy appended, since any before
+/// it were sent by the visitor
+pub fn forwarded_https(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> bool {
+    is_trusted(trusted, peer)
+        && headers
+            .get_all("x-forwarded-proto")
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or("").split(','))
+            .last()
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()]
+    }
+
+    fn headers(forwarded_for: &[&str], proto: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        if let Some(proto) = proto {
+            headers.insert("x-forwarded-proto", proto.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignored() {
+        let spoofed = headers(&["198.51.100.7"], Some("https"));
+        let peer = ip("192.0.2.10");
+        assert_eq!(client_ip(&trusted(), peer, &spoofed), peer);
+        assert!(!forwarded_https(&trusted(), peer, &spoofed));
+
+        // And with nothing trusted, even a private address is taken at its word
+        assert_eq!(client_ip(&[], ip("10.0.0.5"), &spoofed), ip("10.0.0.5"));
+        assert!(!forwarded_https(&[], ip("10.0.0.5"), &spoofed));
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let peer = ip("10.0.0.5");
+        let forwarded = headers(&["198.51.100.7"], Some("https"));
+        assert_eq!(client_ip(&trusted(), peer, &forwarded), ip("198.51.100.7"));
+        assert!(forwarded_https(&trusted(), peer, &forwarded));
+        assert!(!forwarded_https(&trusted(), peer, &headers(&[], Some("http"))));
+        assert!(forwarded_https(&trusted(), peer, &headers(&[], Some("http, HTTPS"))));
+        // The visitor's own value comes first, so it can't claim HTTPS
+        assert!(!forwarded_https(&trusted(), peer, &headers(&[], Some("https, http"))));
+
+        // Mapped addresses and ports are understood
+        let mapped = ip("::ffff:10.0.0.5");
+        assert_eq!(client_ip(&trusted(), mapped, &headers(&["198.51.100.7:4711"], None)), ip("198.51.100.7"));
+        assert_eq!(client_ip(&trusted(), peer, &headers(&["[2001:db9::1]:443"], None)), ip("2001:db9::1"));
+
+        // No header leaves the proxy as the client
+        assert_eq!(client_ip(&trusted(), peer, &HeaderMap::new()), peer);
+    }
+
+    #[test]
+    fn test_rightmost_untrusted_hop() {
+        let peer = ip("10.0.0.5");
+        // The visitor put the first address there itself
+        let chain = headers(&["203.0.113.1, 198.51.100.7", "10.0.0.9"], None);
+        assert_eq!(client_ip(&trusted(), peer, &chain), ip("198.51.100.7"));
+
+        // Every hop trusted: the furthest one is all there is
+        assert_eq!(client_ip(&trusted(), peer, &headers(&["10.1.1.1, 10.0.0.9"], None)), ip("10.1.1.1"));
+
+        // Garbage stops the walk at the last hop a trusted proxy wrote
+        assert_eq!(client_ip(&trusted(), peer, &headers(&["198.51.100.7, nonsense, 10.0.0.9"], None)), ip("10.0.0.9"));
+        assert_eq!(client_ip(&trusted(), peer, &headers(&["unknown"], None)), peer);
+    }
+}
@@ -1,9 +1,20 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::RwLock;
-use std::time::Instant;
-use tokio::sync::{mpsc, oneshot};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_util::sync::CancellationToken;
 use yamux::Stream as YamuxStream;
 
+use super::acme::constant_time_eq;
+use super::basic_auth::BasicAuth;
+use super::names::{Subdomain, TokenSecret};
+use super::rate_limit::TokenBucket;
+use super::rtt::TunnelRtt;
+use crate::proto::{Protocol, TunnelMode};
+use crate::schedule::Window;
+
 /// A request to be proxied through the tunnel - now provides a yamux stream for bidirectional I/O
 pub struct ProxyRequest {
     /// Channel to send the opened yamux stream back
@@ -32,38 +43,291 @@ impl std::fmt::Display for ProxyError {
     }
 }
 
+/// What the client declared about itself when it registered
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub service_name: Option<String>,
+    pub service_version: Option<String>,
+    /// Serve the tunnel's manifest on its host
+    pub publish_manifest: bool,
+    /// The client's build, if it said (older clients don't)
+    pub client_version: Option<String>,
+    /// The client answers echo streams, so its round trip can be measured
+    pub echo_streams: bool,
+}
+
 #[allow(dead_code)]
 pub struct Tunnel {
-    pub subdomain: String,
-    pub token: String,
+    pub subdomain: Subdomain,
+    pub token: TokenSecret,
+    /// Where the client connected from; behind Cloudflare, the address it reached Cloudflare from
+    pub client_addr: SocketAddr,
     pub request_tx: mpsc::Sender<ProxyRequest>,
     pub created_at: Instant,
+    /// Wall-clock time of `created_at`, for showing to admins
+    pub connected_at: SystemTime,
     pub request_count: AtomicU64,
+    /// Bytes sent to the client (requests and WebSocket frames from visitors)
+    pub bytes_in: AtomicU64,
+    /// Bytes received from the client (responses and WebSocket frames to visitors)
+    pub bytes_out: AtomicU64,
+    /// The server port a TCP tunnel listens on; None for HTTP tunnels
+    pub tcp_port: Option<u16>,
+    pub client_info: ClientInfo,
+    /// Lets connectors without the tunnel's token reach a TCP tunnel
+    share_key: Option<String>,
+    /// Credentials visitors to an HTTP tunnel must present
+    pub basic_auth: Option<BasicAuth>,
+    /// Networks visitors must come from; anyone may connect when empty
+    pub allow_ips: Vec<IpNet>,
+    /// Where visitors reach an HTTP tunnel: its subdomain, or a path on the base domain
+    pub mode: TunnelMode,
+    /// Other subdomains the client registered for the tunnel, which visitors reach it on too
+    pub aliases: Vec<Subdomain>,
+    /// When the client asked for the tunnel to be paused, on top of the server's windows
+    pub pause_schedule: Option<Window>,
+    /// Unix seconds the maintenance in progress ends at; 0 when not paused
+    paused_until: AtomicU64,
+    /// Requests visitors may send the tunnel per second (0 = no limit)
+    pub max_requests_per_second: u32,
+    /// Holds a second's worth of requests, so short bursts get through
+    request_bucket: Option<TokenBucket<()>>,
+    /// Requests refused for going over `max_requests_per_second`
+    pub requests_throttled: AtomicU64,
+    /// Unix milliseconds the latest refused request may retry at
+    throttled_until_ms: AtomicU64,
+    /// Woken when requests start being refused, so the handler can tell the client
+    throttling_started: Notify,
     last_activity: RwLock<Instant>,
+    /// Set by the registry when the tunnel is registered (0 until then); a later
+    /// tunnel on the same subdomain always has a higher one
+    epoch: AtomicU64,
+    /// Long-lived connections (TCP tunnels) in progress, which keep the tunnel active
+    open_connections: AtomicUsize,
+    /// Cancelled to have the handler tell the client why and close its connection
+    closed: CancellationToken,
+    /// The first reason given to `close`
+    close_reason: OnceLock<String>,
+    /// Round trip to the client, for clients that answer echo streams
+    pub rtt: TunnelRtt,
+}
+
+/// Marks a connection as open until dropped
+pub struct OpenConnection<'a>(&'a Tunnel);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        // Idle time counts from when the connection closed
+        self.0.touch();
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Tunnel {
     pub fn new(
-        subdomain: String,
-        token: String,
+        subdomain: Subdomain,
+        token: TokenSecret,
+        client_addr: SocketAddr,
         request_tx: mpsc::Sender<ProxyRequest>,
     ) -> Self {
         let now = Instant::now();
         Self {
             subdomain,
             token,
+            client_addr,
             request_tx,
             created_at: now,
+            connected_at: SystemTime::now(),
             request_count: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            tcp_port: None,
+            client_info: ClientInfo::default(),
+            share_key: None,
+            basic_auth: None,
+            allow_ips: Vec::new(),
+            mode: TunnelMode::Subdomain,
+            aliases: Vec::new(),
+            pause_schedule: None,
+            paused_until: AtomicU64::new(0),
+            max_requests_per_second: 0,
+            request_bucket: None,
+            requests_throttled: AtomicU64::new(0),
+            throttled_until_ms: AtomicU64::new(0),
+            throttling_started: Notify::new(),
             last_activity: RwLock::new(now),
+            epoch: AtomicU64::new(0),
+            open_connections: AtomicUsize::new(0),
+            closed: CancellationToken::new(),
+            close_reason: OnceLock::new(),
+            rtt: TunnelRtt::default(),
+        }
+    }
+
+    /// Make this a TCP tunnel listening on `port`
+    pub fn with_tcp_port(mut self, port: u16) -> Self {
+        self.tcp_port = Some(port);
+        self
+    }
+
+    pub fn with_share_key(mut self, share_key: Option<String>) -> Self {
+        self.share_key = share_key;
+        self
+    }
+
+    pub fn with_basic_auth(mut self, basic_auth: Option<BasicAuth>) -> Self {
+        self.basic_auth = basic_auth;
+        self
+    }
+
+    pub fn with_allow_ips(mut self, allow_ips: Vec<IpNet>) -> Self {
+        self.allow_ips = allow_ips;
+        self
+    }
+
+    /// Whether a visitor from `ip` may reach the tunnel
+    pub fn admits_ip(&self, ip: IpAddr) -> bool {
+        // A dual-stack listener sees IPv4 visitors as IPv4-mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        self.allow_ips.is_empty() || self.allow_ips.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn with_mode(mut self, mode: TunnelMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_aliases(mut self, aliases: Vec<Subdomain>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    pub fn with_pause_schedule(mut self, pause_schedule: Option<Window>) -> Self {
+        self.pause_schedule = pause_schedule;
+        self
+    }
+
+    /// Limit visitors to `per_second` requests a second (0 = no limit)
+    pub fn with_max_requests_per_second(mut self, per_second: u32) -> Self {
+        self.max_requests_per_second = per_second;
+        self.request_bucket = (per_second > 0).then(|| TokenBucket::new(per_second, Duration::from_secs(1)));
+        self
+    }
+
+    pub fn with_client_info(mut self, client_info: ClientInfo) -> Self {
+        self.client_info = client_info;
+        self
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self.tcp_port {
+            Some(_) => Protocol::Tcp,
+            None => Protocol::Http,
+        }
+    }
+
+    /// Whether a connector presenting `key` may reach this tunnel: with the tunnel's
+    /// token, or its share key if it has one
+    pub fn admits_connector(&self, key: &str) -> bool {
+        let matches = |secret: &str| constant_time_eq(key.as_bytes(), secret.as_bytes());
+        // Both are checked, so the time taken doesn't say which matched
+        let token = matches(self.token.expose());
+        let share_key = self.share_key.as_deref().is_some_and(matches);
+        token | share_key
+    }
+
+    /// When the maintenance the tunnel is paused for ends, if it's paused
+    pub fn paused_until(&self) -> Option<SystemTime> {
+        match self.paused_until.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
         }
     }
 
+    /// Pause the tunnel until `until`, or resume it with None. Returns whether that
+    /// changed anything.
+    pub fn set_paused_until(&self, until: Option<SystemTime>) -> bool {
+        let secs = until.map_or(0, |until| until.duration_since(UNIX_EPOCH).map_or(1, |d| d.as_secs().max(1)));
+        let before = self.paused_until.swap(secs, Ordering::Relaxed);
+        if before != 0 && secs == 0 {
+            // Idle time counts from the end of the maintenance
+            self.touch();
+        }
+        before != secs
+    }
+
+    /// Count a visitor's request against the tunnel's rate. Returns None if it may go
+    /// ahead, or the seconds until it may be retried if the tunnel is over its rate.
+    pub fn throttle(&self) -> Option<u64> {
+        let bucket = self.request_bucket.as_ref()?;
+        if bucket.take(&(), 1) {
+            return None;
+        }
+        self.requests_throttled.fetch_add(1, Ordering::Relaxed);
+        let retry_after = bucket.secs_per_token().max(1);
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        // A refusal after the previous ones' retry time has passed starts a new stretch
+        let previous = self.throttled_until_ms.fetch_max(now_ms + retry_after * 1000, Ordering::Relaxed);
+        if previous < now_ms {
+            self.throttling_started.notify_one();
+        }
+        Some(retry_after)
+    }
+
+    /// Resolves when requests start being refused for going over the tunnel's rate,
+    /// with when the latest refused one may retry (unix milliseconds)
+    pub async fn throttled(&self) -> u64 {
+        self.throttling_started.notified().await;
+        self.throttled_until_ms.load(Ordering::Relaxed)
+    }
+
+    /// Which registration of its subdomain this is
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    /// Given out by the registry when the tunnel is inserted
+    pub(crate) fn set_epoch(&self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::Relaxed);
+    }
+
+    /// Disconnect the client, telling it `reason`. Requests in flight get a few seconds
+    /// to finish first.
+    pub fn close(&self, reason: &str) {
+        let _ = self.close_reason.set(reason.to_string());
+        self.closed.cancel();
+    }
+
+    /// Resolves with the reason once `close` is called
+    pub async fn closed(&self) -> &str {
+        self.closed.cancelled().await;
+        self.close_reason.get().map_or("", |reason| reason.as_str())
+    }
+
+    /// Keep the tunnel from counting as idle while the returned guard is alive
+    pub fn open_connection(&self) -> OpenConnection<'_> {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self)
+    }
+
     pub fn increment_requests(&self) -> u64 {
         self.touch();
         self.request_count.fetch_add(1, Ordering::Relaxed)
     }
 
+    /// Count bytes sent to the client. Traffic is activity, so a long response or
+    /// upload keeps the tunnel from counting as idle however long ago it started.
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.touch();
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count bytes received from the client, which is activity too
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.touch();
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
     /// Update the last activity timestamp
     pub fn touch(&self) {
         if let Ok(mut last) = self.last_activity.write() {
@@ -76,9 +340,18 @@ impl Tunnel {
         self.last_activity.read().map(|t| *t).unwrap_or(self.created_at)
     }
 
+    /// How long the tunnel has been idle: since its last activity, or not at all
+    /// while a connection is open or it's paused for maintenance
+    pub fn idle_for(&self) -> Duration {
+        if self.open_connections.load(Ordering::Relaxed) > 0 || self.paused_until().is_some() {
+            return Duration::ZERO;
+        }
+        self.last_activity().elapsed()
+    }
+
     /// Check if the tunnel has been idle for longer than the given duration
-    pub fn is_idle(&self, timeout: std::time::Duration) -> bool {
-        self.last_activity().elapsed() > timeout
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.idle_for() > timeout
     }
 
     /// Request a yamux stream for proxying
@@ -94,3 +367,23 @@ impl Tunnel {
         stream_rx.await.map_err(|_| ProxyError::ConnectionClosed)?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::names::{Subdomain, TokenSecret};
+
+    #[test]
+    fn test_admits_ip() {
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let tunnel = Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_test"), "127.0.0.1:50000".parse().unwrap(), request_tx);
+        assert!(tunnel.admits_ip("192.0.2.1".parse().unwrap()));
+
+        let tunnel = tunnel.with_allow_ips(vec!["203.0.113.0/24".parse().unwrap(), "2001:db8::/32".parse().unwrap()]);
+        assert!(tunnel.admits_ip("203.0.113.77".parse().unwrap()));
+        assert!(tunnel.admits_ip("2001:db8:1::5".parse().unwrap()));
+        assert!(tunnel.admits_ip("::ffff:203.0.113.77".parse().unwrap()));
+        assert!(!tunnel.admits_ip("198.51.100.7".parse().unwrap()));
+        assert!(!tunnel.admits_ip("2001:db9::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,464 @@
+//! Usage by token, for billing tenants: requests, bytes and tunnel time in hourly
+//! buckets. A rollup every minute adds what each connected tunnel has counted since
+//! the one before, and a tunnel's last stretch is added when it goes away. Buckets are
+//! kept for `usage.retention_days` and saved in `usage.json` in `server.state_dir`,
+//! so a restart doesn't lose them. So is when each subdomain last had a tunnel, which
+//! decides when its certificate is pruned.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use super::names::{Subdomain, TokenId};
+use super::ownership::now_secs;
+use super::registry::Registry;
+use super::tokens::replace_private;
+use super::tunnel::Tunnel;
+
+pub const USAGE_FILE: &str = "usage.json";
+
+/// How often connected tunnels' counters are rolled up into the buckets
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rollups between saves
+const ROLLUPS_PER_SAVE: u32 = 5;
+
+const HOUR: u64 = 3600;
+
+/// One token's usage over an hour, or any other span
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub requests: u64,
+    /// Bytes sent to the token's clients
+    pub bytes_in: u64,
+    /// Bytes received from the token's clients
+    pub bytes_out: u64,
+    /// Seconds its tunnels were connected, added up
+    pub tunnel_secs: u64,
+}
+
+impl Bucket {
+    pub fn add(&mut self, other: &Bucket) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.tunnel_secs += other.tunnel_secs;
+    }
+}
+
+/// What a connected tunnel had counted at the last rollup
+#[derive(Debug)]
+struct Seen {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Unix seconds
+    at: u64,
+    /// The tunnel has gone. Kept a little while so a rollup that listed the tunnel
+    /// just before it went doesn't count it all over again.
+    finished: bool,
+}
+
+/// The buckets, as saved in `usage.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ledger {
+    /// By token id, then by the start of the hour (unix seconds)
+    #[serde(default)]
+    tokens: BTreeMap<TokenId, BTreeMap<u64, Bucket>>,
+    /// When each subdomain last had a tunnel connected (unix seconds)
+    #[serde(default)]
+    subdomains: BTreeMap<Subdomain, u64>,
+    /// When subdomains started being tracked, the most that's known about one not in
+    /// `subdomains` (unix seconds)
+    #[serde(default)]
+    tracking_since: u64,
+}
+
+#[derive(Debug, Default)]
+struct UsageState {
+    ledger: Ledger,
+    /// Connected tunnels by epoch
+    seen: HashMap<u64, Seen>,
+}
+
+pub struct Usage {
+    retention_secs: u64,
+    /// Where buckets are saved; None keeps them in memory until the server stops
+    path: Option<PathBuf>,
+    state: Mutex<UsageState>,
+}
+
+impl Usage {
+    /// Usage kept in memory only
+    pub fn new(retention_days: u64) -> Self {
+        Self {
+            retention_secs: retention_days.saturating_mul(24 * HOUR),
+            path: None,
+            state: Mutex::new(UsageState {
+                ledger: Ledger { tracking_since: now_secs(), ..Ledger::default() },
+                seen: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Usage saved in `path`, starting from what's there
+    pub fn load(retention_days: u64, path: PathBuf) -> Result<Self> {
+        let mut ledger: Ledger = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Invalid {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ledger::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        // Saved before subdomains were tracked, or new
+        if ledger.tracking_since == 0 {
+            ledger.tracking_since = now_secs();
+        }
+        if !ledger.tokens.is_empty() {
+            info!("Loaded usage for {} token(s) from {}", ledger.tokens.len(), path.display());
+        }
+        Ok(Self {
+            path: Some(path),
+            state: Mutex::new(UsageState { ledger, seen: HashMap::new() }),
+            ..Self::new(retention_days)
+        })
+    }
+
+    /// Usage whose subdomains have been tracked since `secs` (unix seconds)
+    #[cfg(test)]
+    pub fn with_tracking_since(self, secs: u64) -> Self {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).ledger.tracking_since = secs;
+        self
+    }
+
+    /// `usage.json` in `state_dir`
+    pub fn path_in(state_dir: &Path) -> PathBuf {
+        state_dir.join(USAGE_FILE)
+    }
+
+    /// Add what each of `tunnels` has counted since it was last rolled up (or since it
+    /// connected) to its token's buckets
+    pub fn roll_up(&self, tunnels: &[Arc<Tunnel>], now: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        for tunnel in tunnels {
+            roll_up_tunnel(&mut state, tunnel, now);
+        }
+    }
+
+    /// Add `tunnel`'s last stretch, now it's gone
+    pub fn finish(&self, tunnel: &Tunnel, now: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        roll_up_tunnel(&mut state, tunnel, now);
+        if let Some(seen) = state.seen.get_mut(&tunnel.epoch()) {
+            seen.finished = true;
+        }
+    }
+
+    /// Drop buckets that ended before the retention period, and forget tunnels that
+    /// went a while ago
+    pub fn prune(&self, now: u64) {
+        let oldest = now.saturating_sub(self.retention_secs);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .seen
+            .retain(|_, seen| !seen.finished || seen.at + ROLLUP_INTERVAL.as_secs() > now);
+        state.ledger.tokens.retain(|_, hours| {
+            hours.retain(|start, _| start + HOUR > oldest);
+            !hours.is_empty()
+        });
+        state.ledger.subdomains.retain(|_, last_used| *last_used > oldest);
+    }
+
+    /// When `subdomain` last had a tunnel connected, as far as is known: never is
+    /// reported as when tracking started
+    pub fn last_used(&self, subdomain: &str) -> u64 {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let ledger = &state.ledger;
+        ledger.subdomains.get(subdomain).copied().unwrap_or(ledger.tracking_since)
+    }
+
+    /// Stop tracking `subdomain`, once its certificate is gone
+    pub fn forget_subdomain(&self, subdomain: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.ledger.subdomains.remove(subdomain);
+    }
+
+    /// The buckets for token `id` overlapping `from..to` (unix seconds), oldest first
+    pub fn query(&self, id: &TokenId, from: u64, to: u64) -> Vec<(u64, Bucket)> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(hours) = state.ledger.tokens.get(id).filter(|_| from < to) else {
+            return Vec::new();
+        };
+        hours
+            .range(hour_start(from)..to)
+            .map(|(start, bucket)| (*start, *bucket))
+            .collect()
+    }
+
+    /// Whether any usage is kept for token `id`
+    pub fn has(&self, id: &str) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.ledger.tokens.contains_key(id)
+    }
+
+    /// Oldest time still covered by the buckets
+    pub fn retained_from(&self, now: u64) -> u64 {
+        hour_start(now.saturating_sub(self.retention_secs))
+    }
+
+    /// Write the buckets to `usage.json`. False if there's nowhere to save or it failed.
+    pub fn save(&self) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let content = {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            serde_json::to_vec(&state.ledger)
+        };
+        let written = content
+            .map_err(std::io::Error::from)
+            .and_then(|content| replace_private(path, &content));
+        if let Err(e) = written {
+            warn!("Failed to save usage to {}: {}", path.display(), e);
+            return false;
+        }
+        true
+    }
+}
+
+fn hour_start(secs: u64) -> u64 {
+    secs - secs % HOUR
+}
+
+fn roll_up_tunnel(state: &mut UsageState, tunnel: &Tunnel, now: u64) {
+    let connected_at = tunnel
+        .connected_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(now);
+    let current = Seen {
+        requests: tunnel.request_count.load(Ordering::Relaxed),
+        bytes_in: tunnel.bytes_in.load(Ordering::Relaxed),
+        bytes_out: tunnel.bytes_out.load(Ordering::Relaxed),
+        at: now,
+        finished: false,
+    };
+    // Nothing counted yet for a tunnel rolled up for the first time
+    let first = Seen {
+        requests: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+        at: connected_at.min(now),
+        finished: false,
+    };
+    let last = state.seen.get(&tunnel.epoch()).unwrap_or(&first);
+    if last.finished {
+        return;
+    }
+    let counted = Bucket {
+        requests: current.requests.saturating_sub(last.requests),
+        bytes_in: current.bytes_in.saturating_sub(last.bytes_in),
+        bytes_out: current.bytes_out.saturating_sub(last.bytes_out),
+        tunnel_secs: 0,
+    };
+    let since = last.at.min(now);
+    // Every name it's reached on stays in use while it's connected, whether or not
+    // anything came through since the last rollup
+    for name in std::iter::once(&tunnel.subdomain).chain(&tunnel.aliases) {
+        state.ledger.subdomains.insert(name.clone(), now);
+    }
+
+    let hours = state.ledger.tokens.entry(tunnel.token.id()).or_default();
+    // Requests and bytes go in the hour they were rolled up in
+    if counted != Bucket::default() {
+        hours.entry(hour_start(now)).or_default().add(&counted);
+    }
+    // Connected time is split between the hours it spans
+    let mut start = since;
+    while start < now {
+        let end = (hour_start(start) + HOUR).min(now);
+        hours.entry(hour_start(start)).or_default().tunnel_secs += end - start;
+        start = end;
+    }
+    state.seen.insert(tunnel.epoch(), current);
+}
+
+/// Roll up connected tunnels every minute, dropping expired buckets and saving now
+/// and then, until the server shuts down
+pub async fn rollup_task(usage: Arc<Usage>, registry: Arc<Registry>, mut shutdown_rx: broadcast::Receiver<()>) {
+    let mut rollups = 0;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(ROLLUP_INTERVAL) => {
+                let now = now_secs();
+                usage.roll_up(&registry.tunnels(), now);
+                usage.prune(now);
+                rollups += 1;
+                if rollups % ROLLUPS_PER_SAVE == 0 {
+                    usage.save();
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                debug!("Usage rollup task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::names::{Subdomain, TokenId, TokenSecret};
+    use std::time::SystemTime;
+
+    /// Noon on some day, an hour boundary
+    const NOON: u64 = 1_792_324_800;
+
+    fn tunnel(token: &str, epoch: u64, connected_at: u64) -> Arc<Tunnel> {
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        let mut tunnel = Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new(token), "127.0.0.1:50000".parse().unwrap(), request_tx);
+        tunnel.connected_at = SystemTime::UNIX_EPOCH + Duration::from_secs(connected_at);
+        tunnel.set_epoch(epoch);
+        Arc::new(tunnel)
+    }
+
+    fn serve(tunnel: &Tunnel, requests: u64, bytes_in: u64, bytes_out: u64) {
+        tunnel.request_count.fetch_add(requests, Ordering::Relaxed);
+        tunnel.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        tunnel.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_rollups_count_each_request_once() {
+        assert_eq!(hour_start(NOON), NOON);
+        let usage = Usage::new(30);
+        let alice = tunnel("tk_alice", 1, NOON);
+        serve(&alice, 3, 300, 3000);
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 60);
+        serve(&alice, 1, 100, 1000);
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 120);
+        usage.finish(&alice, NOON + 150);
+        // A rollup that listed it before it went
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 160);
+
+        let hours = usage.query(&TokenId::of("tk_alice"), NOON, NOON + HOUR);
+        assert_eq!(
+            hours,
+            vec![(NOON, Bucket { requests: 4, bytes_in: 400, bytes_out: 4000, tunnel_secs: 150 })]
+        );
+        // Other tokens have their own
+        assert!(usage.query(&TokenId::of("tk_bob"), NOON, NOON + HOUR).is_empty());
+        assert!(!usage.has(&TokenId::of("tk_bob")));
+    }
+
+    #[test]
+    fn test_bucket_boundaries() {
+        let usage = Usage::new(30);
+        // Connected from half past eleven until one
+        let alice = tunnel("tk_alice", 1, NOON - 1800);
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 10);
+        serve(&alice, 2, 0, 0);
+        usage.finish(&alice, NOON + HOUR);
+        let id = TokenId::of("tk_alice");
+
+        let hours = usage.query(&id, 0, u64::MAX);
+        let secs: Vec<(u64, u64)> = hours.iter().map(|(start, bucket)| (*start, bucket.tunnel_secs)).collect();
+        assert_eq!(secs, vec![(NOON - HOUR, 1800), (NOON, 3600), (NOON + HOUR, 0)]);
+        // Requests count in the hour they were rolled up in, even at its very start
+        let requests: Vec<u64> = hours.iter().map(|(_, bucket)| bucket.requests).collect();
+        assert_eq!(requests, vec![0, 0, 2]);
+
+        // A range takes every hour it overlaps, and none it only touches
+        assert_eq!(usage.query(&id, NOON - 1, NOON).len(), 1);
+        assert_eq!(usage.query(&id, NOON - 1, NOON + 1).len(), 2);
+        assert_eq!(usage.query(&id, NOON, NOON + HOUR).len(), 1);
+        assert!(usage.query(&id, NOON + 10, NOON + 5).is_empty());
+    }
+
+    #[test]
+    fn test_retention() {
+        let usage = Usage::new(1);
+        let old = tunnel("tk_alice", 1, NOON - 2 * 24 * HOUR);
+        usage.finish(&old, NOON - 2 * 24 * HOUR + 60);
+        let recent = tunnel("tk_alice", 2, NOON - HOUR);
+        usage.finish(&recent, NOON - HOUR + 60);
+        let gone = tunnel("tk_bob", 3, NOON - 2 * 24 * HOUR);
+        usage.finish(&gone, NOON - 2 * 24 * HOUR + 60);
+
+        usage.prune(NOON);
+        assert_eq!(usage.query(&TokenId::of("tk_alice"), 0, u64::MAX).len(), 1);
+        // Tokens with nothing left are forgotten
+        assert!(!usage.has(&TokenId::of("tk_bob")));
+        assert_eq!(usage.retained_from(NOON + 600), NOON - 24 * HOUR);
+
+        // However many days, nothing overflows, and everything is kept
+        let forever = Usage::new(u64::MAX);
+        forever.finish(&tunnel("tk_alice", 4, NOON - 2 * 24 * HOUR), NOON - 2 * 24 * HOUR + 60);
+        forever.prune(NOON);
+        assert_eq!(forever.query(&TokenId::of("tk_alice"), 0, u64::MAX).len(), 1);
+    }
+
+    #[test]
+    fn test_subdomains_last_used() {
+        let usage = Usage::new(30);
+        let never = usage.last_used("other");
+        assert!(never > 0);
+
+        let alice = tunnel("tk_alice", 1, NOON);
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 60);
+        assert_eq!(usage.last_used("myapp"), NOON + 60);
+        // Each rollup counts as a use, with no requests since the last
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 90);
+        assert_eq!(usage.last_used("myapp"), NOON + 90);
+        usage.finish(&alice, NOON + 120);
+        // A rollup that listed it before it went
+        usage.roll_up(std::slice::from_ref(&alice), NOON + 180);
+        assert_eq!(usage.last_used("myapp"), NOON + 120);
+
+        // Dropped with the buckets, or when its certificate goes
+        usage.prune(NOON + 31 * 24 * HOUR);
+        assert_eq!(usage.last_used("myapp"), never);
+        usage.roll_up(std::slice::from_ref(&tunnel("tk_alice", 2, NOON)), NOON + 240);
+        usage.forget_subdomain("myapp");
+        assert_eq!(usage.last_used("myapp"), never);
+
+        // Its aliases are used along with it
+        let (request_tx, _) = tokio::sync::mpsc::channel(1);
+        let aliased = Tunnel::new(Subdomain::new("myapp").unwrap(), TokenSecret::new("tk_alice"), "127.0.0.1:50000".parse().unwrap(), request_tx)
+            .with_aliases(vec![Subdomain::new("www-myapp").unwrap()]);
+        aliased.set_epoch(3);
+        usage.roll_up(&[Arc::new(aliased)], NOON + 300);
+        assert_eq!(usage.last_used("www-myapp"), NOON + 300);
+    }
+
+    #[test]
+    fn test_saved_usage_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("loophole-usage-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(USAGE_FILE);
+
+        let usage = Usage::load(30, path.clone()).unwrap();
+        let alice = tunnel("tk_alice", 1, NOON);
+        serve(&alice, 5, 50, 500);
+        usage.finish(&alice, NOON + 60);
+        assert!(usage.save());
+
+        let restarted = Usage::load(30, path.clone()).unwrap();
+        let id = TokenId::of("tk_alice");
+        assert_eq!(restarted.query(&id, 0, u64::MAX), usage.query(&id, 0, u64::MAX));
+        assert_eq!(restarted.last_used("myapp"), NOON + 60);
+        assert_eq!(restarted.last_used("other"), usage.last_used("other"));
+        // Only fingerprints are written, never tokens
+        assert!(!fs::read_to_string(&path).unwrap().contains("tk_alice"));
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(Usage::load(30, path.clone()).is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
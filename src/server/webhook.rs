@@ -0,0 +1,55 @@
+//! Events POSTed as JSON to `monitoring.webhook_url`, for alerting on problems the
+//! server notices by itself, such as DNS no longer pointing at it
+
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use super::ownership::now_secs;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The body of every event: what happened, to which server, and when
+#[derive(Serialize)]
+struct Event<'a, T: Serialize> {
+    event: &'a str,
+    domain: &'a str,
+    /// Unix seconds
+    at: u64,
+    #[serde(flatten)]
+    details: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    /// The server's base domain, so one endpoint can tell servers apart
+    domain: String,
+}
+
+impl Webhook {
+    pub fn new(url: String, domain: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            domain,
+        }
+    }
+
+    /// Send `event` with `details` alongside; failures are logged, as alerting mustn't
+    /// get in the way of the server
+    pub async fn send<T: Serialize>(&self, event: &str, details: T) {
+        let body = Event {
+            event,
+            domain: &self.domain,
+            at: now_secs(),
+            details,
+        };
+        let sent = self.client.post(&self.url).timeout(SEND_TIMEOUT).json(&body).send().await;
+        match sent.and_then(|response| response.error_for_status()) {
+            Ok(_) => debug!("Sent webhook event {}", event),
+            Err(e) => warn!("Failed to send webhook event {}: {}", event, e),
+        }
+    }
+}
@@ -1,23 +1,76 @@
 use anyhow::{Context, Result};
 use colored::Colorize;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::server::Config;
+use crate::admin_client::{self, AdminClient, AdminError};
+use crate::client_config::{ClientConfig, TlsOptions, DEFAULT_PROFILE};
+use crate::proto::Protocol;
+use crate::units;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct TunnelInfo {
     subdomain: String,
+    /// Older servers only have HTTP tunnels
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp_port: Option<u16>,
     created_at_secs: u64,
     request_count: u64,
     idle_secs: u64,
+    /// Missing from older servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes_in: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes_out: Option<u64>,
+    /// Where the client connected from; missing from older servers, like the two below
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_version: Option<String>,
+    /// Unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connected_at: Option<u64>,
+    /// The client's own maintenance window; missing from older servers, like the one below
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pause_schedule: Option<String>,
+    /// Unix seconds the maintenance the tunnel is paused for ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    paused_until: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_requests_per_second: Option<u32>,
+    /// Requests refused for going over max_requests_per_second; missing from older servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requests_throttled: Option<u64>,
+    /// Smoothed round trip to the client in milliseconds; missing until measured, for
+    /// clients that aren't probed, and from older servers, like the two below
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rtt_ms: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rtt_probes_sent: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rtt_probes_lost: Option<u64>,
+    /// Other subdomains leading to the tunnel; missing from older servers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct TunnelListResponse {
     tunnels: Vec<TunnelInfo>,
     count: usize,
 }
 
+/// `http`, or `tcp:<port>` for TCP tunnels
+fn format_protocol(tunnel: &TunnelInfo) -> String {
+    match (tunnel.protocol, tunnel.tcp_port) {
+        (Protocol::Tcp, Some(port)) => format!("tcp:{}", port),
+        (Protocol::Tcp, None) => "tcp".to_string(),
+        (Protocol::Http, _) => "http".to_string(),
+    }
+}
+
 fn format_duration(secs: u64) -> String {
     if secs < 60 {
         format!("{}s", secs)
@@ -40,6 +93,18 @@ fn format_duration(secs: u64) -> String {
     }
 }
 
+/// What's shown under a tunnel paused for maintenance, or with a window of its own
+fn format_maintenance(tunnel: &TunnelInfo, now_secs: u64) -> Option<String> {
+    match (tunnel.paused_until, &tunnel.pause_schedule) {
+        (Some(until), _) => Some(format!(
+            "paused for maintenance, resumes in {}",
+            format_duration(until.saturating_sub(now_secs))
+        )),
+        (None, Some(schedule)) => Some(format!("pauses for maintenance {}", schedule)),
+        (None, None) => None,
+    }
+}
+
 fn format_count(n: u64) -> String {
     if n >= 1_000_000 {
         format!("{:.1}M", n as f64 / 1_000_000.0)
@@ -50,76 +115,119 @@ fn format_count(n: u64) -> String {
     }
 }
 
-pub async fn run(server: Option<String>, token: Option<String>, config_path: String) -> Result<()> {
-    // Try to load from server config first, then fall back to client config
-    let (server, token) = match (server, token) {
-        (Some(s), Some(t)) => (s, t),
-        (server_opt, token_opt) => {
-            // Try server config first
-            if let Ok(config) = Config::load(&config_path) {
-                let server = server_opt.unwrap_or_else(|| format!("https://{}", config.server.domain));
-
-                let token = match token_opt {
-                    Some(t) => t,
-                    None => {
-                        // Find an admin token from config
-                        config
-                            .tokens
-                            .iter()
-                            .find(|(_, t)| t.admin)
-                            .map(|(token, _)| token.clone())
-                            .context("No --token provided and no admin token found in server config")?
-                    }
-                };
-
-                (server, token)
-            } else {
-                // Fall back to client config
-                let client_config = crate::client_config::ClientConfig::load()?
-                    .context("No --server/--token provided and no config found")?;
-
-                let server = server_opt.unwrap_or(client_config.server);
-                let token = token_opt.unwrap_or(client_config.token);
-
-                (server, token)
-            }
-        }
-    };
+/// The round trip for `--wide`, e.g. `42ms`
+fn format_rtt(tunnel: &TunnelInfo) -> String {
+    match tunnel.rtt_ms {
+        Some(ms) if ms < 10.0 => format!("{:.1}ms", ms),
+        Some(ms) => format!("{:.0}ms", ms),
+        None => "-".to_string(),
+    }
+}
 
-    // Use the scheme from the stored server URL
-    let url = if server.starts_with("https://") || server.starts_with("http://") {
-        format!("{}/_admin/tunnels", server)
-    } else {
-        // Legacy: no scheme, default to https
-        format!("https://{}/_admin/tunnels", server)
-    };
+/// The share of round-trip probes lost for `--wide`, e.g. `2.5%`
+fn format_loss(tunnel: &TunnelInfo) -> String {
+    match (tunnel.rtt_probes_sent, tunnel.rtt_probes_lost) {
+        (Some(sent), Some(lost)) if sent > 0 => format!("{:.1}%", lost as f64 * 100.0 / sent as f64),
+        _ => "-".to_string(),
+    }
+}
 
-    let client = reqwest::Client::new();
-    
-    let response = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await
-        .context("Failed to connect to server")?;
+/// Format a byte count for the table, e.g. `1.2GB`, or `-` when the server didn't say
+fn format_bytes(bytes: Option<u64>) -> String {
+    bytes.map_or_else(|| "-".to_string(), units::format_size)
+}
 
-    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
-        anyhow::bail!("Invalid or non-admin token");
-    }
+/// A server to query
+#[derive(Debug, Clone)]
+struct Target {
+    name: String,
+    server: String,
+    token: String,
+    tls: TlsOptions,
+}
 
-    if response.status() == reqwest::StatusCode::NOT_FOUND {
-        anyhow::bail!("Admin API not enabled on server");
-    }
+/// Outcome of querying one server
+struct ServerStatus {
+    target: Target,
+    result: Result<TunnelListResponse>,
+}
 
-    if !response.status().is_success() {
-        anyhow::bail!("Server returned error: {}", response.status());
-    }
+/// Per-server envelope for `--json`
+#[derive(Serialize)]
+struct ServerEnvelope<'a> {
+    name: &'a str,
+    server: &'a str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnels: Option<&'a TunnelListResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-    let data: TunnelListResponse = response
-        .json()
-        .await
-        .context("Failed to parse server response")?;
+/// Resolve the single server to query from flags, the server config or the client config
+fn resolve_target(server: Option<String>, token: Option<String>, config_path: &str, tls: TlsOptions) -> Result<Target> {
+    let (server, token) = admin_client::resolve_credentials(server, token, config_path)?;
+    let tls = admin_client::resolve_tls(&server, tls)?;
+    Ok(Target {
+        name: DEFAULT_PROFILE.to_string(),
+        server,
+        token,
+        tls,
+    })
+}
 
+/// Every server saved in the client config, with `tls` flags applied to each
+fn profile_targets(tls: TlsOptions) -> Result<Vec<Target>> {
+    let config = ClientConfig::load()?
+        .context("No client config found. Run 'loophole login' first.")?;
+    Ok(config
+        .all_profiles()
+        .into_iter()
+        .map(|(name, profile)| Target {
+            name,
+            server: profile.server,
+            token: profile.token,
+            tls: profile.tls.merged(tls.clone()),
+        })
+        .collect())
+}
+
+async fn fetch_tunnels(target: &Target, timeout: Duration) -> Result<TunnelListResponse> {
+    let client = AdminClient::new(&target.server, &target.token, timeout, &target.tls)?;
+    Ok(client.get_json("/_admin/tunnels").await?)
+}
+
+/// Query all targets concurrently; one failing server doesn't affect the others
+async fn query_all(targets: Vec<Target>, timeout: Duration) -> Vec<ServerStatus> {
+    let queries = targets.into_iter().map(|target| async move {
+        let result = fetch_tunnels(&target, timeout).await;
+        ServerStatus { target, result }
+    });
+
+    futures::future::join_all(queries).await
+}
+
+/// Fail if every server failed, or if any failed and `strict` is set
+fn check_results(results: &[ServerStatus], strict: bool) -> Result<()> {
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    if failed == 0 {
+        return Ok(());
+    }
+    if failed == results.len() {
+        if let [only] = results {
+            if let Err(e) = &only.result {
+                anyhow::bail!("{:#}", e);
+            }
+        }
+        anyhow::bail!("All {} servers failed", failed);
+    }
+    if strict {
+        anyhow::bail!("{} of {} servers failed", failed, results.len());
+    }
+    Ok(())
+}
+
+fn print_tunnels(data: &TunnelListResponse, wide: bool) {
     // Print header
     println!(
         "{} {}",
@@ -130,28 +238,413 @@ pub async fn run(server: Option<String>, token: Option<String>, config_path: Str
 
     if data.tunnels.is_empty() {
         println!("{}", "No active tunnels".dimmed());
-        return Ok(());
+        return;
     }
 
     // Print table header
-    println!(
-        "{:<20} {:<12} {:<12} {:<12}",
+    print!(
+        "{:<20} {:<10} {:<16} {:<12} {:<12} {:<12} {:<12} {:<12}",
         "SUBDOMAIN".dimmed(),
+        "TYPE".dimmed(),
+        "IP".dimmed(),
         "AGE".dimmed(),
         "REQUESTS".dimmed(),
-        "IDLE".dimmed()
+        "IDLE".dimmed(),
+        "IN".dimmed(),
+        "OUT".dimmed()
     );
+    if wide {
+        print!(" {:<10} {:<8}", "RTT".dimmed(), "LOSS".dimmed());
+    }
+    println!();
 
     // Print tunnels
-    for tunnel in data.tunnels {
-        println!(
-            "{:<20} {:<12} {:<12} {:<12}",
+    let now_secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    for tunnel in &data.tunnels {
+        print!(
+            "{:<20} {:<10} {:<16} {:<12} {:<12} {:<12} {:<12} {:<12}",
             tunnel.subdomain.green(),
+            format_protocol(tunnel),
+            tunnel.client_ip.as_deref().unwrap_or("-"),
             format_duration(tunnel.created_at_secs),
             format_count(tunnel.request_count),
             format_duration(tunnel.idle_secs),
+            format_bytes(tunnel.bytes_in),
+            format_bytes(tunnel.bytes_out),
         );
+        if wide {
+            print!(" {:<10} {:<8}", format_rtt(tunnel), format_loss(tunnel));
+        }
+        println!();
+        // Under the tunnel they lead to, like its own name
+        for alias in &tunnel.aliases {
+            println!("  {} {}", "↳".dimmed(), alias.green());
+        }
+        if let Some(maintenance) = format_maintenance(tunnel, now_secs) {
+            println!("  {}", maintenance.yellow());
+        }
     }
+}
 
-    Ok(())
+/// Render one table per server, then a summary of the servers that failed
+fn print_grouped(results: &[ServerStatus], wide: bool) {
+    for status in results {
+        if let Ok(data) = &status.result {
+            println!(
+                "{} {}",
+                format!("[{}]", status.target.name).bold(),
+                status.target.server.dimmed()
+            );
+            print_tunnels(data, wide);
+            println!();
+        }
+    }
+
+    let failures: Vec<&ServerStatus> = results.iter().filter(|r| r.result.is_err()).collect();
+    if !failures.is_empty() {
+        println!("{}", "Errors:".red().bold());
+        for status in failures {
+            if let Err(e) = &status.result {
+                println!(
+                    "  {} {} ({}): {:#}",
+                    "✗".red(),
+                    status.target.name,
+                    status.target.server.dimmed(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn to_json(results: &[ServerStatus]) -> Result<String> {
+    let envelopes: Vec<ServerEnvelope> = results
+        .iter()
+        .map(|status| ServerEnvelope {
+            name: &status.target.name,
+            server: &status.target.server,
+            ok: status.result.is_ok(),
+            tunnels: status.result.as_ref().ok(),
+            error: status.result.as_ref().err().map(|e| format!("{:#}", e)),
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&envelopes)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenListResponse {
+    tokens: Vec<TokenId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenId {
+    id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageTotals {
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    tunnel_hours: f64,
+}
+
+/// A token's usage, as `GET /_admin/tokens/{id}/usage` reports it. Only the totals are
+/// shown in the table; `--json` passes the hourly buckets through.
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenUsage {
+    id: String,
+    from: u64,
+    to: u64,
+    buckets: Vec<serde_json::Value>,
+    total: UsageTotals,
+}
+
+/// Usage of every token the server accepts, over all the time it keeps
+async fn fetch_usage(target: &Target, timeout: Duration) -> Result<Vec<TokenUsage>> {
+    let client = AdminClient::new(&target.server, &target.token, timeout, &target.tls)?;
+    let tokens: TokenListResponse = client.get_json("/_admin/tokens").await?;
+    let mut usage = Vec::with_capacity(tokens.tokens.len());
+    for token in tokens.tokens {
+        match client.get_json(&format!("/_admin/tokens/{}/usage", token.id)).await {
+            Ok(token_usage) => usage.push(token_usage),
+            Err(AdminError::NotFound) => anyhow::bail!("The server doesn't report usage; it may need upgrading"),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(usage)
+}
+
+fn print_usage(usage: &[TokenUsage]) {
+    println!("{}", "Usage by token:".bold());
+    println!();
+
+    println!(
+        "{:<18} {:<12} {:<12} {:<12} {:<12}",
+        "ID".dimmed(),
+        "REQUESTS".dimmed(),
+        "IN".dimmed(),
+        "OUT".dimmed(),
+        "TUNNEL HOURS".dimmed()
+    );
+    for token in usage {
+        println!(
+            "{:<18} {:<12} {:<12} {:<12} {:<12.1}",
+            token.id.green(),
+            format_count(token.total.requests),
+            format_bytes(Some(token.total.bytes_in)),
+            format_bytes(Some(token.total.bytes_out)),
+            token.total.tunnel_hours,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    server: Option<String>,
+    token: Option<String>,
+    config_path: String,
+    all_profiles: bool,
+    strict: bool,
+    json: bool,
+    usage: bool,
+    wide: bool,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    if usage {
+        let usage = fetch_usage(&resolve_target(server, token, &config_path, tls)?, timeout).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&usage)?);
+        } else {
+            print_usage(&usage);
+        }
+        return Ok(());
+    }
+
+    let targets = if all_profiles {
+        profile_targets(tls)?
+    } else {
+        vec![resolve_target(server, token, &config_path, tls)?]
+    };
+    let grouped = targets.len() > 1;
+
+    let results = query_all(targets, timeout).await;
+
+    if json {
+        println!("{}", to_json(&results)?);
+    } else if grouped {
+        print_grouped(&results, wide);
+    } else if let Some(Ok(data)) = results.first().map(|r| &r.result) {
+        print_tunnels(data, wide);
+    }
+
+    check_results(&results, strict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::get, Json, Router};
+
+    #[test]
+    fn test_tunnel_bandwidth_fields() {
+        let current: TunnelInfo = serde_json::from_value(serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5,
+            "bytes_in": 2048, "bytes_out": 1288490189
+        }))
+        .unwrap();
+        assert_eq!(format_bytes(current.bytes_in), "2.0KB");
+        assert_eq!(format_bytes(current.bytes_out), "1.2GB");
+
+        // Older servers don't report bandwidth
+        let old: TunnelInfo = serde_json::from_value(serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5
+        }))
+        .unwrap();
+        assert_eq!(old.bytes_in, None);
+        assert_eq!(format_bytes(old.bytes_out), "-");
+        assert!(serde_json::to_value(&old).unwrap().get("bytes_in").is_none());
+    }
+
+    #[test]
+    fn test_tunnel_protocol_fields() {
+        let tunnel = |json: serde_json::Value| -> TunnelInfo { serde_json::from_value(json).unwrap() };
+        let base = serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5
+        });
+
+        // Older servers don't say, and only have HTTP tunnels
+        assert_eq!(format_protocol(&tunnel(base.clone())), "http");
+
+        let mut tcp = base;
+        tcp["protocol"] = "tcp".into();
+        tcp["tcp_port"] = 20042.into();
+        assert_eq!(format_protocol(&tunnel(tcp)), "tcp:20042");
+    }
+
+    #[test]
+    fn test_tunnel_client_fields() {
+        let current: TunnelInfo = serde_json::from_value(serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5,
+            "client_ip": "203.0.113.9", "client_version": "0.1.0 (1a2b3c4d5e6f 2026-10-17)",
+            "connected_at": 1792300000
+        }))
+        .unwrap();
+        assert_eq!(current.client_ip.as_deref(), Some("203.0.113.9"));
+        assert_eq!(current.connected_at, Some(1792300000));
+
+        // Older servers don't say who connected, and --json doesn't make it up
+        let old: TunnelInfo = serde_json::from_value(serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5
+        }))
+        .unwrap();
+        assert_eq!(old.client_ip, None);
+        let json = serde_json::to_value(&old).unwrap();
+        for field in ["client_ip", "client_version", "connected_at"] {
+            assert!(json.get(field).is_none(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn test_tunnel_maintenance_fields() {
+        let tunnel = |json: serde_json::Value| -> TunnelInfo { serde_json::from_value(json).unwrap() };
+        let mut base = serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5
+        });
+        assert_eq!(format_maintenance(&tunnel(base.clone()), 1792300000), None);
+
+        base["pause_schedule"] = "0 2 * * * for 30m".into();
+        assert_eq!(
+            format_maintenance(&tunnel(base.clone()), 1792300000).as_deref(),
+            Some("pauses for maintenance 0 2 * * * for 30m")
+        );
+        base["paused_until"] = 1792300750.into();
+        assert_eq!(
+            format_maintenance(&tunnel(base), 1792300000).as_deref(),
+            Some("paused for maintenance, resumes in 12m 30s")
+        );
+    }
+
+    #[test]
+    fn test_tunnel_rtt_fields() {
+        let tunnel = |json: serde_json::Value| -> TunnelInfo { serde_json::from_value(json).unwrap() };
+        let mut base = serde_json::json!({
+            "subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5
+        });
+        assert_eq!((format_rtt(&tunnel(base.clone())), format_loss(&tunnel(base.clone()))), ("-".into(), "-".into()));
+
+        base["rtt_probes_sent"] = 40.into();
+        base["rtt_probes_lost"] = 1.into();
+        assert_eq!(format_loss(&tunnel(base.clone())), "2.5%");
+        base["rtt_ms"] = 4.31.into();
+        assert_eq!(format_rtt(&tunnel(base.clone())), "4.3ms");
+        base["rtt_ms"] = 83.6.into();
+        assert_eq!(format_rtt(&tunnel(base)), "84ms");
+    }
+
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn target(name: &str, server: String) -> Target {
+        Target {
+            name: name.to_string(),
+            server,
+            token: "tk_admin".to_string(),
+            tls: TlsOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_failures() {
+        let healthy = mock_server(Router::new().route(
+            "/_admin/tunnels",
+            get(|| async {
+                Json(serde_json::json!({
+                    "tunnels": [{"subdomain": "myapp", "created_at_secs": 60, "request_count": 3, "idle_secs": 5}],
+                    "count": 1
+                }))
+            }),
+        ))
+        .await;
+        let broken = mock_server(Router::new().route(
+            "/_admin/tunnels",
+            get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        ))
+        .await;
+
+        let results = query_all(
+            vec![target("default", healthy), target("broken", broken)],
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(results[0].result.as_ref().unwrap().count, 1);
+        let err = results[1].result.as_ref().unwrap_err().to_string();
+        assert!(err.contains("500"), "{}", err);
+
+        // One failure is tolerated unless --strict
+        assert!(check_results(&results, false).is_ok());
+        assert!(check_results(&results, true).is_err());
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&results).unwrap()).unwrap();
+        assert_eq!(json[0]["name"], "default");
+        assert_eq!(json[0]["ok"], true);
+        assert_eq!(json[0]["tunnels"]["tunnels"][0]["subdomain"], "myapp");
+        assert_eq!(json[1]["ok"], false);
+        assert!(json[1]["error"].as_str().unwrap().contains("500"));
+        assert!(json[1].get("tunnels").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_usage_of_every_token() {
+        let server = mock_server(
+            Router::new()
+                .route(
+                    "/_admin/tokens",
+                    get(|| async { Json(serde_json::json!({ "tokens": [{ "token": "tk_alice", "id": "a1" }], "count": 1 })) }),
+                )
+                .route(
+                    "/_admin/tokens/a1/usage",
+                    get(|| async {
+                        Json(serde_json::json!({
+                            "id": "a1", "from": 0, "to": 7200,
+                            "buckets": [{ "start": 3600, "requests": 3, "bytes_in": 10, "bytes_out": 20, "tunnel_hours": 0.5 }],
+                            "total": { "requests": 3, "bytes_in": 10, "bytes_out": 20, "tunnel_hours": 0.5 }
+                        }))
+                    }),
+                ),
+        )
+        .await;
+        let usage = fetch_usage(&target("default", server), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(usage[0].id, "a1");
+        assert_eq!(usage[0].total.requests, 3);
+        assert_eq!(usage[0].buckets.len(), 1);
+
+        // Servers that don't account usage don't have the endpoint
+        let old = mock_server(Router::new().route(
+            "/_admin/tokens",
+            get(|| async { Json(serde_json::json!({ "tokens": [{ "token": "tk_alice", "id": "a1" }], "count": 1 })) }),
+        ))
+        .await;
+        let err = fetch_usage(&target("default", old), Duration::from_secs(5)).await.unwrap_err().to_string();
+        assert!(err.contains("doesn't report usage"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_all_failed() {
+        let broken = mock_server(Router::new().route(
+            "/_admin/tunnels",
+            get(|| async { StatusCode::BAD_GATEWAY }),
+        ))
+        .await;
+
+        let results = query_all(vec![target("default", broken)], Duration::from_secs(5)).await;
+        let err = check_results(&results, false).unwrap_err().to_string();
+        assert!(err.contains("502"), "{}", err);
+    }
 }
@@ -3,9 +3,10 @@ use colored::Colorize;
 
 use crate::client_config::ClientConfig;
 
-/// Check connection to server by attempting to register and immediately disconnect
-pub async fn check_connection(server: &str, token: &str) -> Result<()> {
-    use crate::proto::{ClientMessage, ServerMessage};
+/// Check connection to server by attempting to register and immediately disconnect,
+/// returning the URL the server gave the test tunnel (none if its name was taken)
+pub async fn check_connection(server: &str, token: &str) -> Result<Option<String>> {
+    use crate::proto::{ClientMessage, Protocol, ServerMessage, TunnelMode};
     use futures::{SinkExt, StreamExt};
     use tokio_tungstenite::tungstenite::Message;
     use tracing::debug;
@@ -49,9 +50,22 @@ pub async fn check_connection(server: &str, token: &str) -> Result<()> {
     let register_msg = ClientMessage::Register {
         token: token.to_string(),
         subdomain: test_subdomain,
+        protocol: Protocol::Http,
+        remote_port: None,
+        service_name: None,
+        service_version: None,
+        publish_manifest: false,
+        client_version: Some(crate::build_info::BuildInfo::current().to_string()),
+        share_key: None,
+        pause_schedule: None,
+        basic_auth: None,
+        allow_ips: Vec::new(),
+        mode: TunnelMode::Subdomain,
+        echo_streams: false,
+        aliases: Vec::new(),
     };
     let json = register_msg.to_json()?;
-    write.send(Message::Text(json.into())).await?;
+    write.send(Message::Text(json)).await?;
 
     // Wait for response
     let response = read
@@ -67,10 +81,10 @@ pub async fn check_connection(server: &str, token: &str) -> Result<()> {
 
     let server_msg = ServerMessage::from_json(&response_text)?;
     match server_msg {
-        ServerMessage::Registered { .. } => {
+        ServerMessage::Registered { url, .. } => {
             // Success! Close connection gracefully
             let _ = write.send(Message::Close(None)).await;
-            Ok(())
+            Ok(Some(url))
         }
         ServerMessage::Error { code, message } => {
             use crate::proto::ErrorCode;
@@ -79,7 +93,7 @@ pub async fn check_connection(server: &str, token: &str) -> Result<()> {
                 ErrorCode::SubdomainTaken => {
                     // This actually means auth worked, subdomain just taken
                     let _ = write.send(Message::Close(None)).await;
-                    Ok(())
+                    Ok(None)
                 }
                 _ => Err(anyhow::anyhow!("Server error: {}", message)),
             }
@@ -102,7 +116,7 @@ pub async fn run(server: Option<String>, token: Option<String>) -> Result<()> {
     println!("{} Testing connection to {}...", "→".cyan(), server);
 
     match check_connection(&server, &token).await {
-        Ok(()) => {
+        Ok(_) => {
             println!("{} Connection successful!", "✓".green());
             println!("{} Token is valid", "✓".green());
             println!("{} Server is accepting connections", "✓".green());
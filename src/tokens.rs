@@ -0,0 +1,199 @@
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::admin_client::{self, AdminClient, AdminError};
+use crate::client_config::TlsOptions;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenInfo {
+    token: String,
+    id: String,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    keep_alive: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_tunnels: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    tunnels: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenListResponse {
+    tokens: Vec<TokenInfo>,
+    count: usize,
+}
+
+/// Settings for a new token; the server's defaults apply to those left out
+#[derive(Debug, Default, Serialize)]
+pub struct NewToken {
+    pub admin: bool,
+    pub keep_alive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tunnels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedToken {
+    token: String,
+    saved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokedToken {
+    tunnels_disconnected: usize,
+    /// Older servers don't reserve subdomains
+    #[serde(default)]
+    reservations_released: usize,
+    saved: bool,
+}
+
+pub enum Action {
+    List { json: bool },
+    Create(NewToken),
+    Revoke(String),
+}
+
+/// Manage the server's tokens via the admin API
+pub async fn run(
+    action: Action,
+    server: Option<String>,
+    token: Option<String>,
+    config_path: String,
+    timeout: Duration,
+    tls: TlsOptions,
+) -> Result<()> {
+    let (server, token) = admin_client::resolve_credentials(server, token, &config_path)?;
+    let client = AdminClient::new(&server, &token, timeout, &admin_client::resolve_tls(&server, tls)?)?;
+
+    match action {
+        Action::List { json } => {
+            let data: TokenListResponse = client.get_json("/_admin/tokens").await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&data)?);
+            } else {
+                print_tokens(&data);
+            }
+        }
+        Action::Create(new_token) => {
+            let created = create(&client, &new_token).await?;
+            let kind = if new_token.admin { "admin token" } else { "token" };
+            println!("{} Created {} {}", "✓".green(), kind, created.token.green());
+            warn_unsaved(created.saved);
+        }
+        Action::Revoke(revoked) => {
+            let result = revoke(&client, &revoked).await?;
+            println!(
+                "{} Revoked token, disconnected {} tunnel(s)",
+                "✓".green(),
+                result.tunnels_disconnected
+            );
+            if result.reservations_released > 0 {
+                println!("  Released {} subdomain reservation(s)", result.reservations_released);
+            }
+            warn_unsaved(result.saved);
+        }
+    }
+    Ok(())
+}
+
+async fn create(client: &AdminClient, new_token: &NewToken) -> Result<CreatedToken> {
+    Ok(client.post_json("/_admin/tokens", new_token).await?)
+}
+
+async fn revoke(client: &AdminClient, token: &str) -> Result<RevokedToken> {
+    match client.delete_json(&format!("/_admin/tokens/{}", token)).await {
+        Ok(revoked) => Ok(revoked),
+        // Servers too old to manage tokens say 404 as well
+        Err(AdminError::NotFound) => anyhow::bail!("Token not found, or the server can't manage tokens"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Changes the server couldn't write to its tokens.json are lost when it restarts
+fn warn_unsaved(saved: bool) {
+    if !saved {
+        println!(
+            "{} The server couldn't save this change (it has no config file, or tokens.json isn't writable); it lasts until the server restarts",
+            "!".yellow()
+        );
+    }
+}
+
+fn print_tokens(data: &TokenListResponse) {
+    println!("{} {}", "Tokens:".bold(), data.count.to_string().cyan());
+    println!();
+
+    println!(
+        "{:<36} {:<18} {:<8} {:<8}",
+        "TOKEN".dimmed(),
+        "ID".dimmed(),
+        "ADMIN".dimmed(),
+        "TUNNELS".dimmed()
+    );
+    for token in &data.tokens {
+        println!(
+            "{:<36} {:<18} {:<8} {:<8}",
+            token.token.green(),
+            token.id,
+            if token.admin { "yes" } else { "no" },
+            token.tunnels
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::StatusCode,
+        routing::{delete, post},
+        Json, Router,
+    };
+
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_create_and_revoke() {
+        let server = mock_server(
+            Router::new()
+                .route(
+                    "/_admin/tokens",
+                    post(|Json(body): Json<serde_json::Value>| async move {
+                        // Only the settings that were given are sent
+                        assert_eq!(body, serde_json::json!({ "admin": true, "keep_alive": false, "weight": 2 }));
+                        (StatusCode::CREATED, Json(serde_json::json!({ "token": "tk_new", "admin": true, "saved": true })))
+                    }),
+                )
+                .route("/_admin/tokens/tk_gone", delete(|| async { StatusCode::NOT_FOUND }))
+                .route(
+                    "/_admin/tokens/tk_last",
+                    delete(|| async {
+                        (StatusCode::CONFLICT, Json(serde_json::json!({ "error": "Can't revoke the only admin token" })))
+                    }),
+                ),
+        )
+        .await;
+        let client = AdminClient::new(&server, "tk_admin", Duration::from_secs(5), &TlsOptions::default()).unwrap();
+
+        let new_token = NewToken { admin: true, weight: Some(2), ..Default::default() };
+        let created = create(&client, &new_token).await.unwrap();
+        assert_eq!(created.token, "tk_new");
+        assert!(created.saved);
+
+        let err = revoke(&client, "tk_gone").await.unwrap_err().to_string();
+        assert!(err.contains("Token not found"), "{}", err);
+        let err = revoke(&client, "tk_last").await.unwrap_err().to_string();
+        assert_eq!(err, "Can't revoke the only admin token");
+    }
+}
@@ -0,0 +1,301 @@
+//! Human-friendly byte sizes ("10MB") and durations ("2m30s") for config values and flags.
+//!
+//! Bare numbers are still accepted everywhere so existing configs keep working: sizes are
+//! read as bytes and durations as seconds. Size units are binary (1KB = 1024 bytes).
+
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::time::Duration;
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kb", 1 << 10),
+    ("kib", 1 << 10),
+    ("m", 1 << 20),
+    ("mb", 1 << 20),
+    ("mib", 1 << 20),
+    ("g", 1 << 30),
+    ("gb", 1 << 30),
+    ("gib", 1 << 30),
+];
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("ms", 1),
+    ("s", 1_000),
+    ("m", 60_000),
+    ("h", 3_600_000),
+    ("d", 86_400_000),
+];
+
+/// Parse a byte size such as `10485760`, `512KB` or `10MB`
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let input = s.trim();
+    if input.starts_with('-') {
+        return Err(format!("invalid size '{}': must not be negative", s));
+    }
+
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (digits, unit) = input.split_at(split);
+    if digits.is_empty() {
+        return Err(format!(
+            "invalid size '{}': expected a number with an optional unit (e.g. 10MB)",
+            s
+        ));
+    }
+
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}': number too large", s))?;
+
+    let unit = unit.trim().to_ascii_lowercase();
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        SIZE_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, m)| *m)
+            .ok_or_else(|| format!("invalid size '{}': unknown unit '{}' (use B, KB, MB or GB)", s, unit))?
+    };
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid size '{}': number too large", s))
+}
+
+/// Parse a duration such as `30`, `90s`, `1h` or `2m30s`; bare numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let input = s.trim();
+    if input.starts_with('-') {
+        return Err(format!("invalid duration '{}': must not be negative", s));
+    }
+    if input.is_empty() {
+        return Err(format!(
+            "invalid duration '{}': expected a number with an optional unit (e.g. 30s, 2m30s)",
+            s
+        ));
+    }
+
+    if input.bytes().all(|b| b.is_ascii_digit()) {
+        let secs: u64 = input
+            .parse()
+            .map_err(|_| format!("invalid duration '{}': number too large", s))?;
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total_ms: u64 = 0;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (digits, tail) = rest.split_at(split);
+        if digits.is_empty() {
+            return Err(format!(
+                "invalid duration '{}': expected a number before '{}'",
+                s, tail
+            ));
+        }
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let unit = unit.trim().to_ascii_lowercase();
+        if unit.is_empty() {
+            return Err(format!(
+                "invalid duration '{}': missing unit after '{}' (use ms, s, m, h or d)",
+                s, digits
+            ));
+        }
+
+        let multiplier = DURATION_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, m)| *m)
+            .ok_or_else(|| {
+                format!(
+                    "invalid duration '{}': unknown unit '{}' (use ms, s, m, h or d)",
+                    s, unit
+                )
+            })?;
+
+        let value: u64 = digits
+            .parse()
+            .map_err(|_| format!("invalid duration '{}': number too large", s))?;
+        total_ms = value
+            .checked_mul(multiplier)
+            .and_then(|v| total_ms.checked_add(v))
+            .ok_or_else(|| format!("invalid duration '{}': number too large", s))?;
+        rest = tail.trim_start();
+    }
+
+    Ok(Duration::from_millis(total_ms))
+}
+
+/// Parse a duration for a command-line flag, rejecting zero
+pub fn parse_flag_duration(s: &str) -> Result<Duration, String> {
+    let duration = parse_duration(s)?;
+    if duration.is_zero() {
+        return Err(format!("invalid duration '{}': must be greater than zero", s));
+    }
+    Ok(duration)
+}
+
+/// Parse a duration that must be a whole number of seconds
+pub fn parse_duration_secs(s: &str) -> Result<u64, String> {
+    let duration = parse_duration(s)?;
+    if duration.subsec_nanos() != 0 {
+        return Err(format!(
+            "invalid duration '{}': must be a whole number of seconds",
+            s
+        ));
+    }
+    Ok(duration.as_secs())
+}
+
+/// Format a byte count using the largest unit that divides it exactly
+pub fn format_bytes(bytes: u64) -> String {
+    for (unit, multiplier) in [("GB", 1u64 << 30), ("MB", 1 << 20), ("KB", 1 << 10)] {
+        if bytes >= multiplier && bytes & (multiplier - 1) == 0 {
+            return format!("{}{}", bytes / multiplier, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+/// Format a byte count for reading at a glance, to one decimal place, e.g. `1.5KB`
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KB", "MB", "GB"] {
+        if size < 1024.0 {
+            return match unit {
+                "B" => format!("{}B", bytes),
+                _ => format!("{:.1}{}", size, unit),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}TB", size)
+}
+
+/// Format a duration compactly, e.g. `2m30s`
+pub fn format_duration(duration: Duration) -> String {
+    let mut ms = duration.as_millis() as u64;
+    if ms == 0 {
+        return "0s".to_string();
+    }
+
+    let mut out = String::new();
+    for (unit, multiplier) in [("d", 86_400_000u64), ("h", 3_600_000), ("m", 60_000), ("s", 1_000), ("ms", 1)] {
+        if ms >= multiplier {
+            out.push_str(&format!("{}{}", ms / multiplier, unit));
+            ms %= multiplier;
+        }
+    }
+    out
+}
+
+/// Deserialize a byte size from either an integer or a string like "10MB"
+pub fn deserialize_bytes<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let bytes = deserializer.deserialize_any(UnitVisitor {
+        expecting: "a byte count or a size string like \"10MB\"",
+        parse: parse_bytes,
+    })?;
+    T::try_from(bytes).map_err(|_| de::Error::custom(format!("size {} is too large", bytes)))
+}
+
+/// Deserialize whole seconds from either an integer or a string like "1h"
+pub fn deserialize_secs<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(UnitVisitor {
+        expecting: "a number of seconds or a duration string like \"1h\"",
+        parse: parse_duration_secs,
+    })
+}
+
+struct UnitVisitor {
+    expecting: &'static str,
+    parse: fn(&str) -> Result<u64, String>,
+}
+
+impl Visitor<'_> for UnitVisitor {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+        Ok(v)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+        u64::try_from(v).map_err(|_| E::custom(format!("invalid value {}: must not be negative", v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+        (self.parse)(v).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("10485760"), Ok(10485760));
+        assert_eq!(parse_bytes("10MB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_bytes("512 kb"), Ok(512 * 1024));
+        assert_eq!(parse_bytes("1GiB"), Ok(1 << 30));
+
+        assert!(parse_bytes("-1MB").unwrap_err().contains("negative"));
+        assert!(parse_bytes("10XB").unwrap_err().contains("unknown unit 'xb'"));
+        assert!(parse_bytes("MB").is_err());
+        assert!(parse_bytes("99999999999999GB").unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2m30s"), Ok(Duration::from_secs(150)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1500ms"), Ok(Duration::from_millis(1500)));
+
+        assert!(parse_duration("-5s").unwrap_err().contains("negative"));
+        assert!(parse_duration("5x").unwrap_err().contains("unknown unit 'x'"));
+        assert!(parse_duration("5m30").unwrap_err().contains("missing unit"));
+        assert!(parse_flag_duration("0s").unwrap_err().contains("greater than zero"));
+        assert!(parse_duration_secs("1500ms").unwrap_err().contains("whole number"));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0B");
+        assert_eq!(format_size(1023), "1023B");
+        assert_eq!(format_size(1024), "1.0KB");
+        assert_eq!(format_size(5 * 1024 * 1024 + 512 * 1024), "5.5MB");
+        assert_eq!(format_size(3 << 30), "3.0GB");
+        assert_eq!(format_size(3 << 40), "3.0TB");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for bytes in [1, 1023, 1024, 10 * 1024 * 1024, 3 << 30] {
+            assert_eq!(parse_bytes(&format_bytes(bytes)), Ok(bytes));
+        }
+        for secs in [1, 59, 90, 150, 3600, 3661, 86_400] {
+            let duration = Duration::from_secs(secs);
+            assert_eq!(parse_duration(&format_duration(duration)), Ok(duration));
+        }
+        assert_eq!(format_duration(Duration::from_secs(150)), "2m30s");
+        assert_eq!(format_bytes(10 * 1024 * 1024), "10MB");
+    }
+}